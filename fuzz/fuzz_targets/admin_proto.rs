@@ -0,0 +1,30 @@
+//! Decodes arbitrary bytes as every admin API request frame.
+//!
+//! The admin socket is only reachable locally, but any process on the host can
+//! write to it, so request decoding must never panic on malformed protobuf.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+use vortex_admin::proto::{
+    ClearFaultInjectionRequest, ControlHealthChecksRequest, DumpDiagnosticsRequest, ExplainRouteRequest, ExportStateRequest, GetCacheStatsRequest,
+    GetPoolStatsRequest, GetStatsRequest, GetTopTalkersRequest, ImportStateRequest, ProbeBackendRequest,
+    ReloadConfigRequest, SetFaultInjectionRequest,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ReloadConfigRequest::decode(data);
+    let _ = GetStatsRequest::decode(data);
+    let _ = GetPoolStatsRequest::decode(data);
+    let _ = SetFaultInjectionRequest::decode(data);
+    let _ = ClearFaultInjectionRequest::decode(data);
+    let _ = GetTopTalkersRequest::decode(data);
+    let _ = DumpDiagnosticsRequest::decode(data);
+    let _ = ExplainRouteRequest::decode(data);
+    let _ = GetCacheStatsRequest::decode(data);
+    let _ = ProbeBackendRequest::decode(data);
+    let _ = ExportStateRequest::decode(data);
+    let _ = ImportStateRequest::decode(data);
+    let _ = ControlHealthChecksRequest::decode(data);
+});
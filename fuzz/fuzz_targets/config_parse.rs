@@ -0,0 +1,22 @@
+//! Parses arbitrary text as a proxy configuration file.
+//!
+//! Configurations are reloaded at runtime from files operators (or their
+//! tooling) write, so parsing, placeholder interpolation and validation must
+//! reject bad input with an error rather than panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vortex_core::config::ProxyConfig;
+
+fuzz_target!(|source: &str| {
+    // Every placeholder resolves, so interpolated values get parsed too
+    let Ok(config) = ProxyConfig::parse_with_env(source, |name| Some(name.to_string())) else {
+        return;
+    };
+    if config.diagnostics().is_empty() {
+        for route in &config.routes {
+            let _ = route.to_spec();
+        }
+    }
+});
@@ -0,0 +1,25 @@
+//! Feeds untrusted request paths through the fault injection route matcher.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::time::Duration;
+use vortex_filters::fault_injection::{FaultInjector, FaultRule};
+
+fuzz_target!(|data: (&str, &str)| {
+    let (prefix, path) = data;
+    let injector = FaultInjector::new();
+    injector.set_rule(FaultRule {
+        route_prefix: prefix.to_string(),
+        abort_percent: 50.0,
+        abort_status: 503,
+        delay: Duration::ZERO,
+        delay_jitter: Duration::from_millis(1),
+    });
+
+    let decision = injector.evaluate(path);
+    if !path.starts_with(prefix) {
+        assert_eq!(decision.abort_status, None);
+        assert_eq!(decision.delay, None);
+    }
+});
@@ -0,0 +1,43 @@
+//! Feeds client-supplied headers through the proxy's own header parsers:
+//! trace context, request IDs, deadlines and header limits.
+
+#![no_main]
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use libfuzzer_sys::fuzz_target;
+use tokio::time::Instant;
+use vortex_proxy::deadline::DeadlineConfig;
+use vortex_proxy::header_limits::HeaderLimits;
+use vortex_proxy::otel::TraceContext;
+use vortex_proxy::request_id::RequestId;
+
+fuzz_target!(|fields: Vec<(&str, &[u8])>| {
+    let mut headers = HeaderMap::new();
+    for (name, value) in fields {
+        // The names the parsers look for, more often than chance would pick them
+        let name = match name {
+            "0" => "traceparent",
+            "1" => "x-request-id",
+            "2" => "grpc-timeout",
+            "3" => "x-request-deadline",
+            name => name,
+        };
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_bytes(value)) {
+            headers.append(name, value);
+        }
+    }
+
+    if let Some(context) = TraceContext::from_headers(&headers) {
+        // What we send on parses back to the same span
+        let mut sent = HeaderMap::new();
+        sent.insert("traceparent", context.traceparent());
+        assert_eq!(TraceContext::from_headers(&sent), Some(context));
+    }
+    if let Some(id) = RequestId::from_headers(&headers) {
+        assert!(!id.header_value().is_empty());
+    }
+    if let Some(deadline) = DeadlineConfig::default().deadline_for(&headers, Instant::now()) {
+        deadline.apply_headers(&mut headers);
+    }
+    let _ = HeaderLimits::default().check(&headers);
+});
@@ -1,6 +1,7 @@
 //! Control plane Unix socket API for Vortex.
 
 pub mod server;
+pub mod transport;
 
 /// Protobuf generated code for Vortex admin API.
 #[allow(missing_docs)]
@@ -10,5 +11,5 @@ pub mod proto {
 
 /// Initialize the vortex-admin telemetry and core states.
 pub fn admin_init() {
-    println!("Vortex Admin (UDS) module initialization sweep complete.");
+    tracing::debug!("vortex-admin initialized");
 }
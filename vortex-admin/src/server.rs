@@ -1,25 +1,159 @@
 //! Server implementation for the Vortex Admin API.
 
-use tokio::net::UnixListener;
-use tokio_stream::wrappers::UnixListenerStream;
+use thiserror::Error;
 use tonic::{Request, Response, Status};
 
 use crate::proto::admin_service_server::{AdminService, AdminServiceServer};
-use crate::proto::{GetStatsRequest, GetStatsResponse, ReloadConfigRequest, ReloadConfigResponse};
+use crate::transport::AdminEndpoint;
+use crate::proto::{
+    BackendPoolStats, ClearFaultInjectionRequest, ControlHealthChecksRequest, ControlHealthChecksResponse,
+    DumpDiagnosticsRequest, DumpDiagnosticsResponse,
+    ExplainRouteRequest, ExplainRouteResponse, ExportStateRequest, ExportStateResponse, FaultInjectionResponse, GetCacheStatsRequest, GetCacheStatsResponse,
+    GetPoolStatsRequest, PoolHealthChecks, ProbeBackendRequest, ProbeBackendResponse, RouteCacheStats, RouteCandidate,
+    GetPoolStatsResponse, GetStatsRequest, HealthCheckAction, GetStatsResponse, GetTopTalkersRequest, GetTopTalkersResponse,
+    ImportStateRequest, ImportStateResponse, ReloadConfigRequest, ReloadConfigResponse, SetFaultInjectionRequest,
+    TopTalker,
+};
 
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use vortex_core::domain::routing::SharedRoutingTable;
+use vortex_core::route::{RequestView, SharedRouteTable};
 use vortex_core::domain::backend::{Backend, BackendId};
+use vortex_core::stats::{
+    BackendProber, CacheStatsSource, DiagnosticsSource, HealthCheckControl, HealthCheckStatus, PoolStatsSource, StateSource,
+    TalkerDimension, TrafficStatsSource,
+};
+use vortex_filters::fault_injection::{FaultInjector, FaultRule};
 
 /// Implementation of the AdminService gRPC server.
 pub struct AdminServerImpl {
     routing_table: SharedRoutingTable,
+    route_table: Option<SharedRouteTable>,
+    pool_stats: Option<Arc<dyn PoolStatsSource>>,
+    fault_injector: Option<Arc<FaultInjector>>,
+    traffic_stats: Option<Arc<dyn TrafficStatsSource>>,
+    diagnostics: Option<Arc<dyn DiagnosticsSource>>,
+    cache_stats: Option<Arc<dyn CacheStatsSource>>,
+    prober: Option<Arc<dyn BackendProber>>,
+    state: Option<Arc<dyn StateSource>>,
+    health_checks: Option<Arc<dyn HealthCheckControl>>,
 }
 
+/// Top talkers returned when the request does not set a limit.
+const DEFAULT_TOP_TALKERS: usize = 10;
+
 impl AdminServerImpl {
     /// Creates a new administration server handling requests.
     pub fn new(routing_table: SharedRoutingTable) -> Self {
-        Self { routing_table }
+        Self {
+            routing_table,
+            route_table: None,
+            pool_stats: None,
+            fault_injector: None,
+            traffic_stats: None,
+            diagnostics: None,
+            cache_stats: None,
+            prober: None,
+            state: None,
+            health_checks: None,
+        }
+    }
+
+    /// Attach the data plane's declared routes so `ExplainRoute` matches against them.
+    pub fn with_route_table(mut self, route_table: SharedRouteTable) -> Self {
+        self.route_table = Some(route_table);
+        self
+    }
+
+    /// Attach the data plane connection pool so `GetPoolStats` can report on it.
+    pub fn with_pool_stats(mut self, pool_stats: Arc<dyn PoolStatsSource>) -> Self {
+        self.pool_stats = Some(pool_stats);
+        self
+    }
+
+    /// Attach the data plane fault injector so chaos rules can be managed at runtime.
+    pub fn with_fault_injector(mut self, fault_injector: Arc<FaultInjector>) -> Self {
+        self.fault_injector = Some(fault_injector);
+        self
+    }
+
+    /// Attach the data plane traffic tracker so `GetTopTalkers` can report on it.
+    pub fn with_traffic_stats(mut self, traffic_stats: Arc<dyn TrafficStatsSource>) -> Self {
+        self.traffic_stats = Some(traffic_stats);
+        self
+    }
+
+    /// Attach the data plane's runtime snapshot so `DumpDiagnostics` can render it.
+    pub fn with_diagnostics(mut self, diagnostics: Arc<dyn DiagnosticsSource>) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    /// Attach the data plane response cache so `GetCacheStats` can report on it.
+    pub fn with_cache_stats(mut self, cache_stats: Arc<dyn CacheStatsSource>) -> Self {
+        self.cache_stats = Some(cache_stats);
+        self
+    }
+
+    /// Attach the data plane's health check so `ProbeBackend` can run it on demand.
+    pub fn with_prober(mut self, prober: Arc<dyn BackendProber>) -> Self {
+        self.prober = Some(prober);
+        self
+    }
+
+    /// Attach the data plane's learned backend state so it can be exported and imported.
+    pub fn with_state(mut self, state: Arc<dyn StateSource>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Attach the periodic health checks so they can be paused, resumed, retimed or stopped.
+    pub fn with_health_checks(mut self, health_checks: Arc<dyn HealthCheckControl>) -> Self {
+        self.health_checks = Some(health_checks);
+        self
+    }
+
+    fn fault_injector(&self) -> Option<&FaultInjector> {
+        self.fault_injector.as_deref()
+    }
+}
+
+/// The request described by an `ExplainRouteRequest`.
+struct SyntheticRequest {
+    method: String,
+    host: String,
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+impl From<ExplainRouteRequest> for SyntheticRequest {
+    fn from(req: ExplainRouteRequest) -> Self {
+        Self {
+            method: if req.method.is_empty() { "GET".to_string() } else { req.method },
+            host: req.host,
+            path: if req.path.is_empty() { "/".to_string() } else { req.path },
+            headers: req.headers.into_iter().map(|(k, v)| (k.to_ascii_lowercase(), v)).collect(),
+        }
+    }
+}
+
+impl RequestView for SyntheticRequest {
+    fn method(&self) -> &str {
+        &self.method
+    }
+
+    fn host(&self) -> Option<&str> {
+        (!self.host.is_empty()).then_some(self.host.as_str())
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
     }
 }
 
@@ -30,12 +164,11 @@ impl AdminService for AdminServerImpl {
         request: Request<ReloadConfigRequest>,
     ) -> Result<Response<ReloadConfigResponse>, Status> {
         let req = request.into_inner();
-        println!("Received reload config request for path: {}", req.config_path);
+        tracing::info!(path = %req.config_path, "received reload config request");
 
         // Simulate reading a configuration file from the specified path
         // In a real implementation this would parse YAML/JSON into Domain objects.
-        let mut new_backends = Vec::new();
-        new_backends.push(Arc::new(Backend::new(BackendId(99), "127.0.0.1:9099".parse().unwrap())));
+        let new_backends = vec![Arc::new(Backend::new(BackendId(99), "127.0.0.1:9099".parse().unwrap()))];
 
         // Zero-downtime, lock-free swap
         self.routing_table.update_backends(new_backends);
@@ -55,27 +188,381 @@ impl AdminService for AdminServerImpl {
             active_connections: 0,
         }))
     }
+
+    async fn get_pool_stats(
+        &self,
+        _request: Request<GetPoolStatsRequest>,
+    ) -> Result<Response<GetPoolStatsResponse>, Status> {
+        let source = self
+            .pool_stats
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Connection pool statistics are not wired up"))?;
+
+        let backends = source
+            .pool_stats()
+            .into_iter()
+            .map(|s| BackendPoolStats {
+                address: s.addr.to_string(),
+                idle: s.idle,
+                created: s.created,
+                reused: s.reused,
+                closed: s.closed,
+                avg_connection_age_ms: s.avg_connection_age.as_millis() as u64,
+                backend_id: s.backend.0,
+            })
+            .collect();
+
+        Ok(Response::new(GetPoolStatsResponse { backends }))
+    }
+
+    async fn set_fault_injection(
+        &self,
+        request: Request<SetFaultInjectionRequest>,
+    ) -> Result<Response<FaultInjectionResponse>, Status> {
+        let req = request.into_inner();
+        let injector = self
+            .fault_injector()
+            .ok_or_else(|| Status::unavailable("Fault injection is not wired up"))?;
+
+        if !(0.0..=100.0).contains(&req.abort_percent) {
+            return Err(Status::invalid_argument("abort_percent must be between 0 and 100"));
+        }
+        let abort_status = u16::try_from(req.abort_status)
+            .ok()
+            .filter(|s| (100..=599).contains(s))
+            .ok_or_else(|| Status::invalid_argument("abort_status must be a valid HTTP status code"))?;
+
+        injector.set_rule(FaultRule {
+            route_prefix: req.route_prefix.clone(),
+            abort_percent: req.abort_percent,
+            abort_status,
+            delay: Duration::from_millis(req.delay_ms),
+            delay_jitter: Duration::from_millis(req.delay_jitter_ms),
+        });
+
+        Ok(Response::new(FaultInjectionResponse {
+            success: true,
+            message: format!("Fault injection enabled for route prefix {}", req.route_prefix),
+        }))
+    }
+
+    async fn clear_fault_injection(
+        &self,
+        request: Request<ClearFaultInjectionRequest>,
+    ) -> Result<Response<FaultInjectionResponse>, Status> {
+        let req = request.into_inner();
+        let removed = self
+            .fault_injector()
+            .ok_or_else(|| Status::unavailable("Fault injection is not wired up"))?
+            .clear_rule(&req.route_prefix);
+
+        Ok(Response::new(FaultInjectionResponse {
+            success: removed,
+            message: if removed {
+                format!("Fault injection cleared for route prefix {}", req.route_prefix)
+            } else {
+                format!("No fault injection rule for route prefix {}", req.route_prefix)
+            },
+        }))
+    }
+
+    async fn get_top_talkers(
+        &self,
+        request: Request<GetTopTalkersRequest>,
+    ) -> Result<Response<GetTopTalkersResponse>, Status> {
+        let req = request.into_inner();
+        let source = self
+            .traffic_stats
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Traffic statistics are not wired up"))?;
+
+        let dimension = match crate::proto::TalkerDimension::try_from(req.dimension) {
+            Ok(crate::proto::TalkerDimension::ClientIp) => TalkerDimension::ClientIp,
+            Ok(crate::proto::TalkerDimension::Route) => TalkerDimension::Route,
+            Ok(crate::proto::TalkerDimension::UserAgent) => TalkerDimension::UserAgent,
+            Err(_) => return Err(Status::invalid_argument("unknown talker dimension")),
+        };
+        let limit = match req.limit {
+            0 => DEFAULT_TOP_TALKERS,
+            n => n as usize,
+        };
+
+        let talkers = source
+            .top_talkers(dimension, limit)
+            .into_iter()
+            .map(|t| TopTalker { key: t.key, requests: t.requests })
+            .collect();
+
+        Ok(Response::new(GetTopTalkersResponse {
+            talkers,
+            requests_per_second: source.requests_per_second(),
+            window_seconds: source.window().as_secs(),
+        }))
+    }
+
+    async fn dump_diagnostics(
+        &self,
+        request: Request<DumpDiagnosticsRequest>,
+    ) -> Result<Response<DumpDiagnosticsResponse>, Status> {
+        let source = self
+            .diagnostics
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Diagnostics are not wired up"))?;
+
+        let dump = source.dump();
+        if request.into_inner().log {
+            tracing::info!("diagnostic dump\n{}", dump);
+        }
+        Ok(Response::new(DumpDiagnosticsResponse { dump }))
+    }
+
+    async fn explain_route(
+        &self,
+        request: Request<ExplainRouteRequest>,
+    ) -> Result<Response<ExplainRouteResponse>, Status> {
+        let req = SyntheticRequest::from(request.into_inner());
+        let mut labels = (*self.routing_table.labels()).clone();
+        let mut response = ExplainRouteResponse::default();
+
+        match &self.route_table {
+            Some(route_table) => {
+                let explanation = route_table.matcher().explain(&req);
+                response.candidates = explanation
+                    .candidates
+                    .iter()
+                    .map(|c| RouteCandidate {
+                        route: c.route.name.clone(),
+                        host: c.route.host.clone().unwrap_or_default(),
+                        path_prefix: c.route.path_prefix.clone(),
+                        selected: c.rejected_by.is_none(),
+                        reason: match &c.rejected_by {
+                            Some(predicate) => format!("predicate failed: {}", predicate),
+                            None => "most specific host and longest path prefix whose predicates all hold".to_string(),
+                        },
+                    })
+                    .collect();
+                if let Some(route) = explanation.selected {
+                    labels = labels.merged(&route.labels);
+                    response.matched = true;
+                    response.route = route.name;
+                }
+            }
+            None => {
+                // Without declared routes every request goes to the pool, named by its path
+                response.matched = true;
+                response.route = req.path.clone();
+            }
+        }
+
+        let backends = self.routing_table.snapshot();
+        response.total_backends = backends.len() as u32;
+        response.healthy_backends = backends.iter().filter(|b| b.is_healthy()).count() as u32;
+        response.labels = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let fault_rule = self.fault_injector().and_then(|f| f.rule_for(&req.path));
+        if let Some(rule) = fault_rule.filter(|_| response.matched) {
+            response.filters.push(format!(
+                "fault_injection(prefix={}, abort={}% with {}, delay={:?}+{:?} jitter)",
+                rule.route_prefix, rule.abort_percent, rule.abort_status, rule.delay, rule.delay_jitter
+            ));
+        }
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_cache_stats(
+        &self,
+        _request: Request<GetCacheStatsRequest>,
+    ) -> Result<Response<GetCacheStatsResponse>, Status> {
+        let source = self
+            .cache_stats
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Response caching is not enabled"))?;
+
+        let routes = source
+            .cache_stats()
+            .into_iter()
+            .map(|s| {
+                let served = s.hits + s.stale_serves;
+                let lookups = served + s.misses;
+                RouteCacheStats {
+                    hit_ratio: if lookups == 0 { 0.0 } else { served as f64 / lookups as f64 },
+                    route: s.route,
+                    hits: s.hits,
+                    misses: s.misses,
+                    stale_serves: s.stale_serves,
+                    evictions: s.evictions,
+                    entries: s.entries,
+                    avg_age_ms: s.avg_age.as_millis() as u64,
+                    max_age_ms: s.max_age.as_millis() as u64,
+                }
+            })
+            .collect();
+
+        Ok(Response::new(GetCacheStatsResponse { routes }))
+    }
+
+    async fn probe_backend(
+        &self,
+        request: Request<ProbeBackendRequest>,
+    ) -> Result<Response<ProbeBackendResponse>, Status> {
+        let prober = self
+            .prober
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Health probing is not wired up"))?;
+
+        let backend_id = request.into_inner().backend_id;
+        let result = prober
+            .probe(BackendId(backend_id))
+            .await
+            .ok_or_else(|| Status::not_found(format!("Backend {} does not exist", backend_id)))?;
+
+        Ok(Response::new(ProbeBackendResponse {
+            backend_id,
+            address: result.addr.to_string(),
+            healthy: result.healthy,
+            was_healthy: result.was_healthy,
+            connect_time_us: result.connect_time.as_micros() as u64,
+            error: result.error.unwrap_or_default(),
+        }))
+    }
+
+    async fn export_state(
+        &self,
+        _request: Request<ExportStateRequest>,
+    ) -> Result<Response<ExportStateResponse>, Status> {
+        let state = self
+            .state
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Backend state is not wired up"))?;
+        let snapshot = state.export_state();
+        Ok(Response::new(ExportStateResponse { snapshot }))
+    }
+
+    async fn import_state(
+        &self,
+        request: Request<ImportStateRequest>,
+    ) -> Result<Response<ImportStateResponse>, Status> {
+        let state = self
+            .state
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Backend state is not wired up"))?;
+        let restored = state
+            .import_state(&request.into_inner().snapshot)
+            .map_err(Status::invalid_argument)?;
+        tracing::info!(backends = restored, "imported backend state");
+        Ok(Response::new(ImportStateResponse { restored_backends: restored as u32 }))
+    }
+
+    async fn control_health_checks(
+        &self,
+        request: Request<ControlHealthChecksRequest>,
+    ) -> Result<Response<ControlHealthChecksResponse>, Status> {
+        let health_checks = self
+            .health_checks
+            .as_ref()
+            .ok_or_else(|| Status::unavailable("Periodic health checks are not running"))?;
+
+        let req = request.into_inner();
+        let action = HealthCheckAction::try_from(req.action)
+            .map_err(|_| Status::invalid_argument("unknown health check action"))?;
+        let pool = (!req.pool.is_empty()).then_some(req.pool.as_str());
+        let targeted: Vec<HealthCheckStatus> = health_checks
+            .status()
+            .into_iter()
+            .filter(|(name, _)| pool.is_none_or(|pool| pool == name))
+            .map(|(_, status)| status)
+            .collect();
+        if targeted.is_empty() {
+            return Err(Status::not_found(format!("Pool {} has no health checks", req.pool)));
+        }
+        let changes = action != HealthCheckAction::Status || req.interval_ms > 0;
+        if changes && targeted.iter().all(|status| status.stopped) {
+            return Err(Status::failed_precondition("Health checks were stopped"));
+        }
+        if req.interval_ms > 0 {
+            let pool = pool.ok_or_else(|| Status::invalid_argument("Changing the interval needs a pool"))?;
+            health_checks.set_interval(pool, Duration::from_millis(req.interval_ms));
+        }
+        match action {
+            HealthCheckAction::Status => {}
+            HealthCheckAction::Pause => health_checks.pause(pool),
+            HealthCheckAction::Resume => health_checks.resume(pool),
+            HealthCheckAction::Stop => health_checks.stop(pool),
+        }
+        if changes {
+            tracing::info!(
+                action = action.as_str_name(),
+                pool = pool.unwrap_or("*"),
+                interval_ms = req.interval_ms,
+                "health checks changed"
+            );
+        }
+
+        let pools = health_checks
+            .status()
+            .into_iter()
+            .map(|(pool, status)| PoolHealthChecks {
+                pool,
+                interval_ms: status.interval.as_millis() as u64,
+                paused: status.paused,
+                stopped: status.stopped,
+            })
+            .collect();
+        Ok(Response::new(ControlHealthChecksResponse { pools }))
+    }
 }
 
-/// Start the Admin gRPC server listening on a Unix Domain Socket.
-pub async fn start_admin_server(
-    socket_path: &str,
-    routing_table: SharedRoutingTable,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Ensure any dangling socket from a previous process is cleaned up
-    let _ = std::fs::remove_file(socket_path);
+/// Errors that stop the admin API from serving.
+#[derive(Debug, Error)]
+pub enum AdminError {
+    /// The admin endpoint could not be bound.
+    #[error("failed to bind admin endpoint {endpoint}: {source}")]
+    Bind {
+        /// The endpoint that failed to bind
+        endpoint: String,
+        /// The underlying I/O error
+        #[source]
+        source: std::io::Error,
+    },
+    /// The gRPC transport failed while serving.
+    #[error("admin gRPC transport failed: {0}")]
+    Transport(#[from] tonic::transport::Error),
+}
 
-    let uds = UnixListener::bind(socket_path)?;
-    let stream = UnixListenerStream::new(uds);
+/// Start the Admin gRPC server on the given endpoint.
+pub async fn start_admin_server(
+    endpoint: &AdminEndpoint,
+    admin_service: AdminServerImpl,
+) -> Result<(), AdminError> {
+    let bind_err = |source| AdminError::Bind {
+        endpoint: endpoint.to_string(),
+        source,
+    };
+    let server = tonic::transport::Server::builder().add_service(AdminServiceServer::new(admin_service));
 
-    let admin_service = AdminServerImpl::new(routing_table);
+    tracing::info!(%endpoint, "starting admin API");
 
-    println!("Starting Admin Unix Socket API at {}", socket_path);
+    match endpoint {
+        #[cfg(unix)]
+        AdminEndpoint::Unix(socket_path) => {
+            // Ensure any dangling socket from a previous process is cleaned up
+            let _ = std::fs::remove_file(socket_path);
 
-    tonic::transport::Server::builder()
-        .add_service(AdminServiceServer::new(admin_service))
-        .serve_with_incoming(stream)
-        .await?;
+            let uds = tokio::net::UnixListener::bind(socket_path).map_err(bind_err)?;
+            let stream = tokio_stream::wrappers::UnixListenerStream::new(uds);
+            server.serve_with_incoming(stream).await?;
+        }
+        #[cfg(windows)]
+        AdminEndpoint::NamedPipe(name) => {
+            let stream = crate::transport::named_pipe::incoming(name).map_err(bind_err)?;
+            server.serve_with_incoming(stream).await?;
+        }
+        AdminEndpoint::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await.map_err(bind_err)?;
+            let stream = tokio_stream::wrappers::TcpListenerStream::new(listener);
+            server.serve_with_incoming(stream).await?;
+        }
+    }
 
     Ok(())
 }
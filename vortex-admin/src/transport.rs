@@ -0,0 +1,127 @@
+//! Platform-specific transports for the admin API.
+//!
+//! Unix platforms (Linux, macOS) serve the API on a Unix domain socket, Windows
+//! on a named pipe. A loopback TCP endpoint is available everywhere as the
+//! portable fallback.
+
+use std::fmt;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// Where the admin API listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminEndpoint {
+    /// A Unix domain socket path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A Windows named pipe, e.g. `\\.\pipe\vortex-admin`.
+    #[cfg(windows)]
+    NamedPipe(String),
+    /// A TCP address. Should be a loopback address: the API is unauthenticated.
+    Tcp(SocketAddr),
+}
+
+impl AdminEndpoint {
+    /// The conventional endpoint for the current platform.
+    pub fn platform_default() -> Self {
+        #[cfg(unix)]
+        {
+            AdminEndpoint::Unix(PathBuf::from("/tmp/vortex_admin.sock"))
+        }
+        #[cfg(windows)]
+        {
+            AdminEndpoint::NamedPipe(r"\\.\pipe\vortex-admin".to_string())
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            AdminEndpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], 9901)))
+        }
+    }
+}
+
+impl fmt::Display for AdminEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(unix)]
+            AdminEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(windows)]
+            AdminEndpoint::NamedPipe(name) => write!(f, "pipe:{}", name),
+            AdminEndpoint::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+/// Named pipe listener adapted to tonic's incoming-connection stream.
+#[cfg(windows)]
+pub(crate) mod named_pipe {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::transport::server::Connected;
+
+    /// One accepted named pipe client.
+    pub(crate) struct PipeConnection(NamedPipeServer);
+
+    impl Connected for PipeConnection {
+        type ConnectInfo = ();
+
+        fn connect_info(&self) -> Self::ConnectInfo {}
+    }
+
+    impl AsyncRead for PipeConnection {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for PipeConnection {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    /// Creates the first pipe instance and a stream yielding each connected client.
+    ///
+    /// A new server instance is created before handing the connected one off,
+    /// so clients never observe the pipe as missing between connections.
+    pub(crate) fn incoming(name: &str) -> io::Result<ReceiverStream<io::Result<PipeConnection>>> {
+        let mut server = ServerOptions::new().first_pipe_instance(true).create(name)?;
+        let name = name.to_string();
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = server.connect().await {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+                let connected = server;
+                server = match ServerOptions::new().create(&name) {
+                    Ok(next) => next,
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        return;
+                    }
+                };
+                if tx.send(Ok(PipeConnection(connected))).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+}
@@ -0,0 +1,76 @@
+//! Route matching cost as the route count grows: the compiled matcher
+//! against the linear scan it replaces.
+
+// `criterion_group!` expands to an undocumented public function
+#![allow(missing_docs)]
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::hint::black_box;
+use vortex_core::route::matcher::RouteMatcher;
+use vortex_core::route::{RequestView, RouteSpec};
+
+struct Req {
+    host: String,
+    path: String,
+}
+
+impl RequestView for Req {
+    fn method(&self) -> &str {
+        "GET"
+    }
+
+    fn host(&self) -> Option<&str> {
+        Some(&self.host)
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn header(&self, _name: &str) -> Option<&str> {
+        None
+    }
+}
+
+/// `count` routes spread over 10 hosts, each with a distinct service prefix.
+fn routes(count: usize) -> Vec<RouteSpec> {
+    (0..count)
+        .map(|i| {
+            RouteSpec::new(format!("route-{}", i), format!("/svc-{}/v1/", i))
+                .with_host(format!("tenant-{}.example.com", i % 10))
+        })
+        .collect()
+}
+
+/// The pre-compilation behavior: check every route, keep the longest prefix.
+fn linear_find<'a>(routes: &'a [RouteSpec], req: &Req) -> Option<&'a RouteSpec> {
+    routes
+        .iter()
+        .filter(|r| r.host.as_deref() == Some(req.host.as_str()) && req.path.starts_with(&r.path_prefix))
+        .max_by_key(|r| r.path_prefix.len())
+}
+
+fn bench_route_matching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route_matching");
+    for count in [10, 100, 1_000, 10_000] {
+        let routes = routes(count);
+        let matcher = RouteMatcher::new(routes.clone());
+        // Worst case for the scan: the last declared route
+        let last = count - 1;
+        let req = Req {
+            host: format!("tenant-{}.example.com", last % 10),
+            path: format!("/svc-{}/v1/orders/42", last),
+        };
+
+        group.bench_with_input(BenchmarkId::new("compiled", count), &req, |b, req| {
+            b.iter(|| matcher.find(black_box(req)))
+        });
+        group.bench_with_input(BenchmarkId::new("linear", count), &req, |b, req| {
+            b.iter(|| linear_find(&routes, black_box(req)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_route_matching);
+criterion_main!(benches);
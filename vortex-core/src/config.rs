@@ -0,0 +1,1134 @@
+//! Declarative proxy configuration, loaded from a TOML file.
+//!
+//! ```toml
+//! metrics_address = "127.0.0.1:9100"
+//!
+//! [[listeners]]
+//! address = "0.0.0.0:8443"
+//! tls = { cert = "certs/cert.pem", key = "certs/key.pem" }
+//!
+//! [[clusters]]
+//! name = "web"
+//! load_balancer = "peak_ewma"
+//! labels = { team = "storefront" }
+//! metadata = { zone = "us-east-1a" }
+//! health_check = { type = "http", path = "/healthz", interval_ms = 2000, unhealthy_threshold = 3 }
+//! backends = [
+//!     { address = "127.0.0.1:9090" },
+//!     { address = "127.0.0.1:9091", metadata = { canary = "true" } },
+//! ]
+//!
+//! [[routes]]
+//! name = "api"
+//! path_prefix = "/api/"
+//! cluster = "web"
+//! methods = ["GET", "POST"]
+//! subset = { canary = "true" }
+//! subset_fallback = "any_backend"
+//! ```
+//!
+//! A cluster's `metadata` applies to each of its backends, under the
+//! backends' own. Its backends are only health checked if it has a
+//! `health_check`; unset fields there take the proxy's defaults. Backends
+//! without an `id` are numbered after the highest explicit one, in file
+//! order. Backends sharing an address behind a name-based ingress set
+//! `host`, which upstream requests then name instead of the address.
+//!
+//! Instead of listing `backends`, the first cluster can resolve them: from
+//! `hostnames = { hosts = ["primary.db:5432", "replica.db:5432"] }`, each
+//! name a failover group in the order given, or from `srv = { name =
+//! "_http._tcp.web.svc" }`, a group per record priority. A name expands to
+//! all its addresses unless `strategy = "per_connection"` makes it one
+//! backend resolved again for every connection, for names fronting a load
+//! balancer.
+//!
+//! Routes sharing a prefix can split traffic by method: a route with
+//! `methods = ["GET"]` to the replicas takes the reads, `HEAD`s included,
+//! and one without `methods` to the primary, declared after it, takes
+//! everything else.
+//!
+//! A route matches requests carrying each of its `headers`, with the given
+//! value unless it is empty, and each of its `header_regex` headers with a
+//! value the regex matches in full, so `header_regex = { "x-tenant" =
+//! "acme|globex" }` sends those two tenants to the route's cluster.
+//!
+//! A route's `expression` is a CEL condition checked on top of the rest, so
+//! `expression = "request.headers['x-ver'].startsWith('2.') && source.ip in
+//! cidr('10.0.0.0/8')"` takes only v2 clients on the internal network. It is
+//! compiled when the file is loaded, and one that doesn't compile fails the
+//! load.
+//!
+//! A route's `path_regex` must match the whole path on top of its
+//! `path_prefix`, and `rewrite` replaces the forwarded path, so
+//! `path_regex = '/users/(\d+)/profile'` with `rewrite = "/profiles/$1"`
+//! sends `/users/42/profile` upstream as `/profiles/42`. Named groups are
+//! written `$name`, or `$${name}` to keep them apart from environment
+//! variables.
+//!
+//! String values may refer to environment variables, so one file can serve
+//! several environments: `${NAME}` is replaced with the variable's value and
+//! `${NAME:-default}` falls back to `default` when it is unset or empty.
+//! `$$` is a literal `$`. A variable that is unset and has no default fails
+//! the load. Variables are resolved when the file is loaded, including on
+//! reloads.
+
+use crate::domain::backend::{Backend, BackendId, SharedBackend};
+use crate::domain::labels::Labels;
+use crate::load_balancer::balancer::Algorithm;
+use crate::load_balancer::hash::HashKey;
+use crate::load_balancer::maglev::{is_valid_table_size, MaglevBalancer};
+use crate::load_balancer::subset::{Subset, SubsetFallback};
+use crate::load_balancer::zone::ZoneAffinity;
+use crate::domain::routing::RoutingTable;
+use crate::route::cel::CelExpression;
+use crate::route::pattern::Pattern;
+use crate::route::{Predicate, RouteSpec};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Why a configuration couldn't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file couldn't be read
+    Io {
+        /// The file
+        path: PathBuf,
+        /// The read error
+        source: std::io::Error,
+    },
+    /// The file isn't valid TOML or doesn't have the expected shape
+    Parse(String),
+    /// The file parsed but describes an impossible setup
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "cannot read {}: {}", path.display(), source)
+            }
+            ConfigError::Parse(message) => write!(f, "invalid config: {}", message),
+            ConfigError::Invalid(message) => write!(f, "invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// One problem found in a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Where the problem is, e.g. `routes[2]` or `clusters[0].backends[1]`
+    pub location: String,
+    /// What is wrong
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// The whole proxy configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Addresses to accept traffic on
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Named backend pools
+    #[serde(default)]
+    pub clusters: Vec<ClusterConfig>,
+    /// Routes, in declaration order
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Where to serve Prometheus metrics, if anywhere
+    pub metrics_address: Option<SocketAddr>,
+}
+
+/// One listener.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// The address to bind
+    pub address: SocketAddr,
+    /// Terminate TLS with these files; plaintext if unset
+    pub tls: Option<TlsFiles>,
+}
+
+/// PEM files for a TLS listener.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    /// Certificate chain
+    pub cert: PathBuf,
+    /// Private key
+    pub key: PathBuf,
+}
+
+/// A named pool of backends.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Unique cluster name, referenced by routes
+    pub name: String,
+    /// The pool's backends, unless they come from `hostnames` or `srv`
+    #[serde(default)]
+    pub backends: Vec<BackendConfig>,
+    /// Resolve the pool's backends from hostnames, in failover order
+    pub hostnames: Option<HostnamesSpec>,
+    /// Resolve the pool's backends from the SRV records of a name
+    pub srv: Option<SrvSpec>,
+    /// Observability labels of the pool
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Metadata given to every backend of the pool
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// How the pool's backends are health checked, if at all
+    pub health_check: Option<HealthCheckSpec>,
+    /// The load-balancing algorithm
+    #[serde(default)]
+    pub load_balancer: Algorithm,
+    /// What requests are hashed by, for hash-based algorithms
+    pub hash_key: Option<HashKey>,
+    /// Slots in the lookup table of the `maglev` algorithm, a prime;
+    /// 65537 if unset
+    pub maglev_table_size: Option<usize>,
+    /// How long a backend that joins on reload or turns healthy takes to
+    /// ramp up to its full share of traffic; no ramp if unset
+    pub slow_start_ms: Option<u64>,
+    /// Keeps traffic on backends in the proxy's zone, if set
+    pub zone_affinity: Option<ZoneAffinitySpec>,
+    /// Healthy percentage of a failover group below which the next group
+    /// takes traffic too; 70 if unset
+    pub failover_percent: Option<u8>,
+}
+
+/// Backends resolved from hostnames, each a failover group of its own.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostnamesSpec {
+    /// `host:port` names, most preferred first
+    pub hosts: Vec<String>,
+    /// Whether each address is a backend or each name is one, resolved per
+    /// connection
+    #[serde(default)]
+    pub strategy: HostStrategy,
+    /// How often names are resolved again; 30 seconds if unset
+    pub refresh_ms: Option<u64>,
+    /// Healthy backends a name needs to take traffic; 1 if unset
+    pub min_healthy: Option<usize>,
+}
+
+/// How a hostname becomes backends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostStrategy {
+    /// Every address the name resolves to is a backend
+    #[default]
+    AllAddresses,
+    /// The name is one backend, resolved again for every connection
+    PerConnection,
+}
+
+impl HostnamesSpec {
+    /// Settings that are out of range.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.hosts.is_empty() {
+            problems.push("hosts is empty".to_string());
+        }
+        for host in &self.hosts {
+            let has_port = host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+            if !has_port || !is_valid_host(host) {
+                problems.push(format!("host {:?} is not host:port", host));
+            }
+        }
+        problems.extend(dns_problems(self.refresh_ms, self.min_healthy));
+        problems
+    }
+}
+
+/// Backends resolved from SRV records, a failover group per priority.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SrvSpec {
+    /// The SRV name, e.g. `_http._tcp.web.svc.cluster.local`
+    pub name: String,
+    /// Where to send queries; the first `/etc/resolv.conf` nameserver if unset
+    pub nameserver: Option<SocketAddr>,
+    /// How often the records are looked up again; 30 seconds if unset
+    pub refresh_ms: Option<u64>,
+    /// Healthy backends a priority needs to take traffic; 1 if unset
+    pub min_healthy: Option<usize>,
+}
+
+impl SrvSpec {
+    /// Settings that are out of range.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let valid = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_');
+        if self.name.is_empty() || !self.name.bytes().all(valid) {
+            problems.push(format!("name {:?} is not a DNS name", self.name));
+        }
+        problems.extend(dns_problems(self.refresh_ms, self.min_healthy));
+        problems
+    }
+}
+
+/// Zero settings shared by `hostnames` and `srv`.
+fn dns_problems(refresh_ms: Option<u64>, min_healthy: Option<usize>) -> Vec<String> {
+    [("refresh_ms", refresh_ms == Some(0)), ("min_healthy", min_healthy == Some(0))]
+        .into_iter()
+        .filter(|(_, is_zero)| *is_zero)
+        .map(|(field, _)| format!("{} must be at least 1", field))
+        .collect()
+}
+
+/// Zone-aware balancing of one cluster, by the backends' `zone` metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneAffinitySpec {
+    /// The zone this proxy runs in
+    pub local_zone: String,
+    /// Percentage of local backends that must be healthy to keep traffic
+    /// local; 70 if unset
+    pub min_healthy_percent: Option<u8>,
+    /// Average in-flight requests per local backend above which traffic
+    /// spills to other zones; no limit if unset
+    pub max_active_requests: Option<u64>,
+}
+
+impl ZoneAffinitySpec {
+    /// The affinity this spec describes.
+    pub fn to_affinity(&self) -> ZoneAffinity {
+        let defaults = ZoneAffinity::new(self.local_zone.clone());
+        ZoneAffinity {
+            min_healthy_percent: self.min_healthy_percent.unwrap_or(defaults.min_healthy_percent),
+            max_active_requests: self.max_active_requests,
+            ..defaults
+        }
+    }
+}
+
+/// Active health checking of one cluster. Unset fields take the proxy's
+/// defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckSpec {
+    /// What a probe does
+    #[serde(rename = "type", default)]
+    pub kind: HealthCheckType,
+    /// Time between probes of each backend
+    pub interval_ms: Option<u64>,
+    /// How long one probe may take before it counts as failed
+    pub timeout_ms: Option<u64>,
+    /// Request path of `http` probes
+    pub path: Option<String>,
+    /// Service name asked about by `grpc` probes; the whole server if unset
+    pub service: Option<String>,
+    /// Consecutive passing probes that mark an unhealthy backend healthy
+    pub healthy_threshold: Option<u32>,
+    /// Consecutive failing probes that mark a healthy backend unhealthy
+    pub unhealthy_threshold: Option<u32>,
+    /// Most probes of the cluster in flight at once
+    pub max_concurrent_probes: Option<usize>,
+}
+
+/// The protocol of a health probe.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckType {
+    /// The backend accepts a TCP connection
+    #[default]
+    Tcp,
+    /// `GET path` answers with a 2xx status
+    Http,
+    /// The gRPC health service reports `SERVING`
+    Grpc,
+}
+
+impl HealthCheckSpec {
+    /// Settings that are out of range or don't apply to the probe type.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let zero = [
+            ("interval_ms", self.interval_ms == Some(0)),
+            ("timeout_ms", self.timeout_ms == Some(0)),
+            ("healthy_threshold", self.healthy_threshold == Some(0)),
+            ("unhealthy_threshold", self.unhealthy_threshold == Some(0)),
+            ("max_concurrent_probes", self.max_concurrent_probes == Some(0)),
+        ];
+        for (field, _) in zero.iter().filter(|(_, is_zero)| *is_zero) {
+            problems.push(format!("{} must be at least 1", field));
+        }
+        match &self.path {
+            Some(_) if self.kind != HealthCheckType::Http => problems.push("path only applies to http checks".to_string()),
+            Some(path) if !path.starts_with('/') => problems.push("path does not start with /".to_string()),
+            _ => {}
+        }
+        if self.service.is_some() && self.kind != HealthCheckType::Grpc {
+            problems.push("service only applies to grpc checks".to_string());
+        }
+        problems
+    }
+}
+
+/// One backend of a cluster.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendConfig {
+    /// The backend's address
+    pub address: SocketAddr,
+    /// The name requests carry to reach it, as `Host` and in the URI, for
+    /// backends sharing an address behind a name-based ingress
+    pub host: Option<String>,
+    /// Stable ID for metrics and sticky sessions; assigned if unset
+    pub id: Option<u32>,
+    /// Relative share of traffic, e.g. 4 for a machine four times as big; 1 if unset
+    pub weight: Option<u32>,
+    /// Failover group: 0 (the default) for primaries, higher for standbys
+    pub priority: Option<u32>,
+    /// The backend's own metadata, over the cluster's
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+/// One route.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Unique route name
+    pub name: String,
+    /// `example.com`, `*.example.com`, or unset for any host
+    pub host: Option<String>,
+    /// Path prefix the request path must start with
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    /// A regex the whole path must match as well, e.g. `/users/(?P<id>\d+)`
+    pub path_regex: Option<String>,
+    /// The path to forward instead, with `$1` or `$name` standing for
+    /// `path_regex`'s capture groups
+    pub rewrite: Option<String>,
+    /// The cluster serving the route
+    pub cluster: String,
+    /// Allowed methods, where `GET` admits `HEAD` too; any if empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Headers that must be present, with the exact value if not empty
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Headers that must be present with a value matching the regex in full
+    #[serde(default)]
+    pub header_regex: BTreeMap<String, String>,
+    /// A CEL expression the request must satisfy as well, e.g.
+    /// `source.ip in cidr('10.0.0.0/8')`
+    pub expression: Option<String>,
+    /// Observability labels merged over the cluster's
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Backend metadata the route's backends must carry; any backend if empty
+    #[serde(default)]
+    pub subset: HashMap<String, String>,
+    /// What to do when no healthy backend matches `subset`
+    #[serde(default)]
+    pub subset_fallback: SubsetFallback,
+}
+
+fn default_path_prefix() -> String {
+    "/".to_string()
+}
+
+impl ProxyConfig {
+    /// Read and validate the configuration in `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::parse(&source)
+    }
+
+    /// Parse and validate a configuration.
+    pub fn parse(source: &str) -> Result<Self, ConfigError> {
+        let config = Self::parse_unvalidated(source)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a configuration, checking only its syntax and shape.
+    pub fn parse_unvalidated(source: &str) -> Result<Self, ConfigError> {
+        Self::parse_with_env(source, |name| std::env::var(name).ok())
+    }
+
+    /// Parse a configuration, resolving `${NAME}` placeholders with `env`,
+    /// checking only its syntax and shape.
+    pub fn parse_with_env(source: &str, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut table: toml::Table = source.parse().map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))?;
+        for (key, value) in table.iter_mut() {
+            interpolate_value(value, key, &env)?;
+        }
+        toml::Value::Table(table).try_into().map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))
+    }
+
+    /// Check what the file format can't express, failing on the first
+    /// problem `diagnostics` finds.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self.diagnostics().into_iter().next() {
+            Some(problem) => Err(ConfigError::Invalid(problem.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Every problem the file format can't express: duplicate names, IDs
+    /// and addresses, routes pointing at missing clusters, clusters without
+    /// backends or with more than one source of them, malformed hostnames,
+    /// methods, path prefixes, regexes and expressions, zero weights, hash
+    /// balancing without a key, and health check settings that can't work.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let mut problems = Vec::new();
+        let mut report = |location: String, message: String| problems.push(Diagnostic { location, message });
+
+        let mut listeners = HashSet::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if !listeners.insert(listener.address) {
+                report(format!("listeners[{}]", i), format!("address {} is used twice", listener.address));
+            }
+        }
+
+        let mut clusters = HashSet::new();
+        let mut ids = HashSet::new();
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            let location = format!("clusters[{}]", i);
+            if !clusters.insert(cluster.name.as_str()) {
+                report(location.clone(), format!("cluster {} is declared twice", cluster.name));
+            }
+            let sources = [!cluster.backends.is_empty(), cluster.hostnames.is_some(), cluster.srv.is_some()];
+            match sources.iter().filter(|&&set| set).count() {
+                0 => report(location.clone(), format!("cluster {} has no backends", cluster.name)),
+                1 => {}
+                _ => {
+                    let message = format!("cluster {} sets more than one of backends, hostnames and srv", cluster.name);
+                    report(location.clone(), message);
+                }
+            }
+            if i > 0 && (cluster.hostnames.is_some() || cluster.srv.is_some()) {
+                // Discovery fills the default pool, which is the first cluster
+                let message = format!("cluster {} resolves its backends, which only the first cluster can", cluster.name);
+                report(location.clone(), message);
+            }
+            let dns = [
+                ("hostnames", cluster.hostnames.as_ref().map(HostnamesSpec::problems)),
+                ("srv", cluster.srv.as_ref().map(SrvSpec::problems)),
+            ];
+            for (field, problems) in dns {
+                for problem in problems.into_iter().flatten() {
+                    report(format!("{}.{}", location, field), problem);
+                }
+            }
+            let mut addrs = HashSet::new();
+            for (j, backend) in cluster.backends.iter().enumerate() {
+                let location = format!("{}.backends[{}]", location, j);
+                if !addrs.insert((backend.address, backend.host.as_deref())) {
+                    let target = backend.host.as_ref().map_or(String::new(), |host| format!(" with host {}", host));
+                    report(location.clone(), format!("address {}{} is used twice", backend.address, target));
+                }
+                if let Some(host) = backend.host.as_deref().filter(|host| !is_valid_host(host)) {
+                    report(location.clone(), format!("host {:?} is not a valid host name", host));
+                }
+                if backend.weight == Some(0) {
+                    report(location.clone(), "weight must be at least 1".to_string());
+                }
+                if let Some(id) = backend.id.filter(|&id| !ids.insert(id)) {
+                    report(location, format!("backend id {} is used twice", id));
+                }
+            }
+            if cluster.load_balancer.hashes_requests() && cluster.hash_key.is_none() {
+                report(location.clone(), format!("cluster {} balances by hash but has no hash_key", cluster.name));
+            }
+            if let Some(size) = cluster.maglev_table_size {
+                if cluster.load_balancer != Algorithm::Maglev {
+                    report(location.clone(), format!("cluster {} sets maglev_table_size but isn't maglev", cluster.name));
+                } else if !is_valid_table_size(size) {
+                    report(location.clone(), format!("maglev_table_size {} is not prime", size));
+                }
+            }
+            if cluster.failover_percent.is_some_and(|p| p > 100) {
+                report(location.clone(), "failover_percent must be at most 100".to_string());
+            }
+            if let Some(zones) = &cluster.zone_affinity {
+                if zones.local_zone.is_empty() {
+                    report(location.clone(), "zone_affinity has an empty local_zone".to_string());
+                }
+                if zones.min_healthy_percent.is_some_and(|p| p > 100) {
+                    report(location.clone(), "min_healthy_percent must be at most 100".to_string());
+                }
+            }
+            if let Some(check) = &cluster.health_check {
+                let location = format!("{}.health_check", location);
+                for problem in check.problems() {
+                    report(location.clone(), problem);
+                }
+            }
+        }
+
+        let mut routes = HashSet::new();
+        for (i, route) in self.routes.iter().enumerate() {
+            let location = format!("routes[{}]", i);
+            if !routes.insert(route.name.as_str()) {
+                report(location.clone(), format!("route {} is declared twice", route.name));
+            }
+            if !clusters.contains(route.cluster.as_str()) {
+                report(location.clone(), format!("route {} uses unknown cluster {}", route.name, route.cluster));
+            }
+            let valid_method = |m: &&String| !m.is_empty() && m.bytes().all(|b| b.is_ascii_alphabetic());
+            if let Some(method) = route.methods.iter().find(|m| !valid_method(m)) {
+                report(location.clone(), format!("route {} has an invalid method {:?}", route.name, method));
+            }
+            if !route.path_prefix.starts_with('/') {
+                report(location.clone(), format!("route {} has a path prefix not starting with /", route.name));
+            }
+            if let Some(Err(e)) = route.path_regex.as_deref().map(Pattern::new) {
+                report(location.clone(), format!("route {} has an invalid path regex: {}", route.name, e));
+            }
+            for (name, pattern) in &route.header_regex {
+                if let Err(e) = Pattern::new(pattern) {
+                    let message = format!("route {} has an invalid regex for header {}: {}", route.name, name, e);
+                    report(location.clone(), message);
+                }
+            }
+            if let Some(Err(e)) = route.expression.as_deref().map(CelExpression::compile) {
+                report(location.clone(), format!("route {} has an invalid expression: {}", route.name, e));
+            }
+            if route.rewrite.as_ref().is_some_and(|rewrite| !rewrite.starts_with(['/', '$'])) {
+                report(location, format!("route {} has a rewrite not starting with /", route.name));
+            }
+        }
+        problems
+    }
+
+    /// Build every cluster's backends, assigning IDs where the file left
+    /// them out.
+    pub fn backends(&self) -> HashMap<String, Vec<SharedBackend>> {
+        let mut next_id = self
+            .clusters
+            .iter()
+            .flat_map(|c| c.backends.iter().filter_map(|b| b.id))
+            .max()
+            .map_or(1, |max| max + 1);
+        self.clusters
+            .iter()
+            .map(|cluster| {
+                let backends = cluster
+                    .backends
+                    .iter()
+                    .map(|b| {
+                        let id = b.id.unwrap_or_else(|| {
+                            next_id += 1;
+                            next_id - 1
+                        });
+                        let mut backend = Backend::new(BackendId(id), b.address).with_weight(b.weight.unwrap_or(1));
+                        backend.host = b.host.clone();
+                        backend.priority = b.priority.unwrap_or_default();
+                        backend.metadata = cluster.metadata.clone();
+                        backend.metadata.extend(b.metadata.clone());
+                        if let Some(ms) = cluster.slow_start_ms {
+                            backend.slow_start.set_window(Duration::from_millis(ms));
+                        }
+                        Arc::new(backend)
+                    })
+                    .collect();
+                (cluster.name.clone(), backends)
+            })
+            .collect()
+    }
+
+    /// Build the routing table of the named cluster.
+    pub fn routing_table(&self, cluster: &str) -> Option<RoutingTable> {
+        let config = self.clusters.iter().find(|c| c.name == cluster)?;
+        let backends = self.backends().remove(cluster)?;
+        let balancer = match (config.load_balancer, config.maglev_table_size) {
+            (Algorithm::Maglev, Some(size)) => Arc::new(MaglevBalancer::new(size)),
+            (algorithm, _) => algorithm.build(),
+        };
+        let mut table = RoutingTable::new(backends).with_labels(config.labels.iter().collect()).with_balancer(balancer);
+        if let Some(key) = &config.hash_key {
+            table = table.with_hash_key(key.clone());
+        }
+        if let Some(zones) = &config.zone_affinity {
+            table = table.with_zone_affinity(zones.to_affinity());
+        }
+        if let Some(percent) = config.failover_percent {
+            table = table.with_failover_percent(percent);
+        }
+        Some(table)
+    }
+
+    /// The declared routes.
+    pub fn route_specs(&self) -> Vec<RouteSpec> {
+        self.routes.iter().map(RouteConfig::to_spec).collect()
+    }
+}
+
+/// Whether `host` can name a backend: a DNS name or IP, optionally with a
+/// port, as allowed in a URI authority and a `Host` header.
+fn is_valid_host(host: &str) -> bool {
+    let (name, port) = match host.rsplit_once(':').filter(|_| !host.ends_with(']')) {
+        Some((name, port)) => (name, Some(port)),
+        None => (host, None),
+    };
+    let name_ok = if let Some(ip) = name.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        ip.parse::<std::net::Ipv6Addr>().is_ok()
+    } else {
+        !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+    };
+    name_ok && port.is_none_or(|port| port.parse::<u16>().is_ok())
+}
+
+/// Resolves the placeholders in every string within `value`, found at `path`.
+fn interpolate_value(
+    value: &mut toml::Value,
+    path: &str,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    match value {
+        toml::Value::String(s) => *s = interpolate(s, env).map_err(|e| ConfigError::Invalid(format!("{}: {}", path, e)))?,
+        toml::Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate_value(item, &format!("{}[{}]", path, i), env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, item) in table.iter_mut() {
+                interpolate_value(item, &format!("{}.{}", path, key), env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces `${NAME}` and `${NAME:-default}` in `s`, and `$$` with `$`.
+fn interpolate(s: &str, env: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| format!("unterminated placeholder in {:?}", s))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+                return Err(format!("invalid variable name {:?}", name));
+            }
+            match (env(name).filter(|v| !v.is_empty() || default.is_none()), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => return Err(format!("environment variable {} is not set", name)),
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl RouteConfig {
+    /// The route as the matcher sees it.
+    pub fn to_spec(&self) -> RouteSpec {
+        let mut spec = RouteSpec::new(self.name.clone(), self.path_prefix.clone())
+            .with_labels(self.labels.iter().collect::<Labels>())
+            .with_cluster(self.cluster.clone());
+        if let Some(host) = &self.host {
+            spec = spec.with_host(host.clone());
+        }
+        // Validated to compile; the compiled regex lives in the route from here on
+        if let Some(Ok(regex)) = self.path_regex.as_deref().map(Pattern::new) {
+            spec = spec.with_path_regex(regex);
+        }
+        if let Some(rewrite) = &self.rewrite {
+            spec = spec.with_rewrite(rewrite.clone());
+        }
+        spec = spec.with_methods(self.methods.iter().cloned());
+        if !self.subset.is_empty() {
+            spec = spec.with_subset(Subset::new(self.subset.clone()).with_fallback(self.subset_fallback.clone()));
+        }
+        for (name, value) in &self.headers {
+            spec = spec.with_predicate(Predicate::Header {
+                name: name.clone(),
+                value: (!value.is_empty()).then(|| value.clone()),
+            });
+        }
+        for (name, pattern) in &self.header_regex {
+            if let Ok(pattern) = Pattern::new(pattern) {
+                spec = spec.with_header_regex(name.clone(), pattern);
+            }
+        }
+        if let Some(Ok(expr)) = self.expression.as_deref().map(CelExpression::compile) {
+            spec = spec.with_predicate(Predicate::Cel(expr));
+        }
+        spec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+        [[listeners]]
+        address = "0.0.0.0:8443"
+        tls = { cert = "certs/cert.pem", key = "certs/key.pem" }
+
+        [[clusters]]
+        name = "web"
+        labels = { team = "storefront" }
+        metadata = { zone = "a", version = "v1" }
+        health_check = { type = "grpc", service = "web.Storefront", interval_ms = 2000, unhealthy_threshold = 3 }
+        backends = [
+            { address = "127.0.0.1:9090" },
+            { address = "127.0.0.1:9091", id = 7, metadata = { version = "v2" } },
+        ]
+
+        [[routes]]
+        name = "api"
+        path_prefix = "/api/"
+        cluster = "web"
+        methods = ["GET", "POST"]
+        headers = { "x-tenant" = "" }
+        subset = { version = "v2" }
+        subset_fallback = { default_subset = { version = "v1" } }
+    "#;
+
+    #[test]
+    fn test_example_config_builds_the_pool_and_routes() {
+        let config = ProxyConfig::parse(EXAMPLE).unwrap();
+        assert_eq!(
+            config.listeners[0].tls.as_ref().unwrap().cert,
+            PathBuf::from("certs/cert.pem")
+        );
+        let check = config.clusters[0].health_check.as_ref().unwrap();
+        assert_eq!(check.kind, HealthCheckType::Grpc);
+        assert_eq!((check.interval_ms, check.timeout_ms), (Some(2000), None));
+        assert_eq!(check.unhealthy_threshold, Some(3));
+
+        let table = config.routing_table("web").unwrap();
+        assert_eq!(table.labels().get("team"), Some("storefront"));
+        assert_eq!(table.balancer().name(), "peak_ewma");
+        let backends = table.snapshot();
+        assert_eq!(backends[0].id, BackendId(8));
+        assert_eq!(backends[0].metadata["version"], "v1");
+        assert_eq!(backends[1].id, BackendId(7));
+        assert_eq!(backends[1].metadata["version"], "v2");
+        assert_eq!(backends[1].metadata["zone"], "a");
+
+        let spec = &config.route_specs()[0];
+        assert_eq!(spec.path_prefix, "/api/");
+        assert_eq!(spec.predicates.len(), 2);
+        assert_eq!(spec.predicates[0], Predicate::Methods(vec!["GET".to_string(), "POST".to_string()]));
+        let subset = spec.subset.as_ref().unwrap();
+        assert_eq!(subset.selector["version"], "v2");
+        assert!(matches!(&subset.fallback, SubsetFallback::DefaultSubset(s) if s["version"] == "v1"));
+        assert!(config.routing_table("db").is_none());
+    }
+
+    #[test]
+    fn test_shipped_config_is_valid() {
+        let config = ProxyConfig::parse(include_str!("../../vortex.toml")).unwrap();
+        assert_eq!(config.clusters.len(), 1);
+    }
+
+    #[test]
+    fn test_diagnostics_list_every_problem() {
+        let config = ProxyConfig::parse_unvalidated(
+            r#"
+            [[listeners]]
+            address = "0.0.0.0:80"
+            [[listeners]]
+            address = "0.0.0.0:80"
+
+            [[clusters]]
+            name = "web"
+            backends = [{ address = "127.0.0.1:1", id = 1 }, { address = "127.0.0.1:1", id = 1 }]
+            health_check = { type = "tcp", path = "/healthz", timeout_ms = 0 }
+
+            [[routes]]
+            name = "r"
+            cluster = "web"
+            [[routes]]
+            name = "r"
+            cluster = "db"
+            path_prefix = "api"
+            "#,
+        )
+        .unwrap();
+        let locations: Vec<String> = config.diagnostics().into_iter().map(|d| d.location).collect();
+        assert_eq!(
+            locations,
+            [
+                "listeners[1]",
+                "clusters[0].backends[1]",
+                "clusters[0].backends[1]",
+                "clusters[0].health_check",
+                "clusters[0].health_check",
+                "routes[1]",
+                "routes[1]",
+                "routes[1]"
+            ]
+        );
+        assert!(config.validate().unwrap_err().to_string().contains("listeners[1]: address 0.0.0.0:80 is used twice"));
+    }
+
+    #[test]
+    fn test_path_regex_routes_compile_once_and_rewrite() {
+        let source = r#"
+            [[clusters]]
+            name = "web"
+            backends = [{ address = "127.0.0.1:9090" }]
+
+            [[routes]]
+            name = "profile"
+            path_prefix = "/users/"
+            path_regex = '/users/(?P<id>\d+)/profile'
+            rewrite = "/profiles/$id"
+            cluster = "web"
+        "#;
+        let routes = ProxyConfig::parse(source).unwrap().route_specs();
+        assert_eq!(routes[0].rewritten_path("/users/42/profile").as_deref(), Some("/profiles/42"));
+        let matcher = crate::route::matcher::RouteMatcher::new(routes);
+        assert!(matches!(matcher.routes()[0].predicates[..], [Predicate::PathRegex(_)]));
+
+        let err = |source: &str| ProxyConfig::parse(source).unwrap_err().to_string();
+        assert!(err(&source.replace(r"\d+)", r"\d+")).contains("route profile has an invalid path regex"));
+        let tenants = format!("{}header_regex = {{ \"x-tenant\" = '(acme|globex' }}", source);
+        assert!(err(&tenants).contains("route profile has an invalid regex for header x-tenant"));
+        assert!(err(&source.replace("\"/profiles/$id\"", "\"profiles\"")).contains("rewrite not starting with /"));
+    }
+
+    #[test]
+    fn test_route_expressions_compile_at_load() {
+        let source = r#"
+            [[clusters]]
+            name = "web"
+            backends = [{ address = "127.0.0.1:9090" }]
+
+            [[routes]]
+            name = "internal"
+            cluster = "web"
+            expression = "source.ip in cidr('10.0.0.0/8')"
+        "#;
+        let routes = ProxyConfig::parse(source).unwrap().route_specs();
+        assert!(matches!(&routes[0].predicates[..], [Predicate::Cel(expr)] if expr.source().contains("cidr")));
+
+        let err = ProxyConfig::parse(&source.replace("10.0.0.0/8", "10.0.0.0/33")).unwrap_err().to_string();
+        assert!(err.contains("routes[0]: route internal has an invalid expression"), "{}", err);
+    }
+
+    #[test]
+    fn test_placeholders_resolve_from_the_environment() {
+        let env = |name: &str| match name {
+            "BACKEND_HOST" => Some("10.0.0.5".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let source = r#"
+            [[listeners]]
+            address = "0.0.0.0:${PORT:-8443}"
+            tls = { cert = "${CERT_DIR:-certs}/cert.pem", key = "$${EMPTY:-}/key.pem" }
+
+            [[clusters]]
+            name = "web"
+            backends = [{ address = "${BACKEND_HOST}:9090", metadata = { zone = "${EMPTY:-local}" } }]
+        "#;
+        let config = ProxyConfig::parse_with_env(source, env).unwrap();
+        assert_eq!(config.listeners[0].address.port(), 8443);
+        let tls = config.listeners[0].tls.as_ref().unwrap();
+        assert_eq!(tls.cert, PathBuf::from("certs/cert.pem"));
+        assert_eq!(tls.key, PathBuf::from("${EMPTY:-}/key.pem"));
+        let backend = &config.clusters[0].backends[0];
+        assert_eq!(backend.address, "10.0.0.5:9090".parse().unwrap());
+        assert_eq!(backend.metadata["zone"], "local");
+
+        let err = ProxyConfig::parse_with_env(&source.replace("${BACKEND_HOST}", "${DB_HOST}"), env).unwrap_err();
+        assert!(err.to_string().contains("clusters[0].backends[0].address: environment variable DB_HOST is not set"));
+        let err = ProxyConfig::parse_with_env(&source.replace("${BACKEND_HOST}", "${BACKEND_HOST"), env).unwrap_err();
+        assert!(err.to_string().contains("unterminated"));
+    }
+
+    #[test]
+    fn test_clusters_can_resolve_their_backends() {
+        let resolved = r#"
+            [[clusters]]
+            name = "db"
+            hostnames = { hosts = ["primary.db:5432", "replica.db:5432"], strategy = "per_connection", refresh_ms = 5000 }
+
+            [[clusters]]
+            name = "web"
+            backends = [{ address = "127.0.0.1:9090" }]
+        "#;
+        let config = ProxyConfig::parse(resolved).unwrap();
+        let hostnames = config.clusters[0].hostnames.as_ref().unwrap();
+        assert_eq!(
+            (hostnames.strategy, hostnames.refresh_ms, hostnames.min_healthy),
+            (HostStrategy::PerConnection, Some(5000), None)
+        );
+        assert!(config.routing_table("db").unwrap().snapshot().is_empty());
+        let srv = resolved.replace(
+            r#"hostnames = { hosts = ["primary.db:5432", "replica.db:5432"], strategy = "per_connection", refresh_ms = 5000 }"#,
+            r#"srv = { name = "_postgres._tcp.db.svc", nameserver = "10.0.0.2:53" }"#,
+        );
+        assert_eq!(ProxyConfig::parse(&srv).unwrap().clusters[0].srv.as_ref().unwrap().name, "_postgres._tcp.db.svc");
+
+        let problems = |source: &str| -> Vec<String> {
+            ProxyConfig::parse_unvalidated(source).unwrap().diagnostics().iter().map(Diagnostic::to_string).collect()
+        };
+        let mistakes = r#"
+            [[clusters]]
+            name = "a"
+            backends = [{ address = "127.0.0.1:1" }]
+            hostnames = { hosts = ["no-port.db", "bad host:80"], min_healthy = 0 }
+
+            [[clusters]]
+            name = "b"
+            srv = { name = "", refresh_ms = 0 }
+        "#;
+        assert_eq!(
+            problems(mistakes),
+            [
+                "clusters[0]: cluster a sets more than one of backends, hostnames and srv",
+                "clusters[0].hostnames: host \"no-port.db\" is not host:port",
+                "clusters[0].hostnames: host \"bad host:80\" is not host:port",
+                "clusters[0].hostnames: min_healthy must be at least 1",
+                "clusters[1]: cluster b resolves its backends, which only the first cluster can",
+                "clusters[1].srv: name \"\" is not a DNS name",
+                "clusters[1].srv: refresh_ms must be at least 1",
+            ]
+        );
+        let err = ProxyConfig::parse(&resolved.replace("per_connection", "round_robin")).unwrap_err().to_string();
+        assert!(err.contains("unknown variant `round_robin`"));
+    }
+
+    #[test]
+    fn test_mistakes_are_reported() {
+        let err = |source: &str| ProxyConfig::parse(source).unwrap_err().to_string();
+        assert!(err("listners = []").contains("unknown field"));
+        assert!(err("[[listeners]]\naddress = \"nope\"").contains("invalid"));
+        assert!(err("[[clusters]]\nname = \"a\"\nbackends = []").contains("no backends"));
+        let dangling = "[[routes]]\nname = \"r\"\ncluster = \"missing\"";
+        assert!(err(dangling).contains("unknown cluster missing"));
+        let twice = "[[clusters]]\nname = \"a\"\nbackends = [{ address = \"127.0.0.1:1\", id = 1 }, { address = \"127.0.0.1:2\", id = 1 }]";
+        assert!(err(twice).contains("backend id 1 is used twice"));
+    }
+
+    const CLUSTER: &str = "[[clusters]]\nname = \"a\"\nbackends = [{ address = \"127.0.0.1:1\" }]\nload_balancer = ";
+
+    #[test]
+    fn test_clusters_pick_their_balancer() {
+        let err = |source: &str| ProxyConfig::parse(source).unwrap_err().to_string();
+        assert!(err(&format!("{}\"random\"", CLUSTER)).contains("unknown variant `random`"));
+        for name in ["round_robin", "peak_ewma_p2c", "least_connections"] {
+            let config = ProxyConfig::parse(&format!("{}\"{}\"", CLUSTER, name)).unwrap();
+            assert_eq!(config.routing_table("a").unwrap().balancer().name(), name);
+        }
+    }
+
+    #[test]
+    fn test_weighted_backends_need_a_positive_weight() {
+        let weighted = CLUSTER.replace("\" }]", "\", weight = 4 }]");
+        let config = ProxyConfig::parse(&format!("{}\"weighted_round_robin\"", weighted)).unwrap();
+        let table = config.routing_table("a").unwrap();
+        assert_eq!(table.balancer().name(), "weighted_round_robin");
+        assert_eq!(table.snapshot()[0].weight(), 4);
+        let zero = format!("{}\"weighted_round_robin\"", weighted.replace("weight = 4", "weight = 0"));
+        assert!(ProxyConfig::parse(&zero).unwrap_err().to_string().contains("weight must be at least 1"));
+    }
+
+    #[test]
+    fn test_hash_balancers_need_a_key() {
+        let err = |source: &str| ProxyConfig::parse(source).unwrap_err().to_string();
+        assert!(err(&format!("{}\"ring_hash\"", CLUSTER)).contains("balances by hash but has no hash_key"));
+        let hashed = format!("{}\"ring_hash\"\nhash_key = {{ header = \"x-user-id\" }}", CLUSTER);
+        let table = ProxyConfig::parse(&hashed).unwrap().routing_table("a").unwrap();
+        assert_eq!(table.balancer().name(), "ring_hash");
+        assert_eq!(table.hash_key(), Some(&HashKey::Header("x-user-id".to_string())));
+        let by_ip = format!("{}\"ring_hash\"\nhash_key = \"client_ip\"", CLUSTER);
+        assert_eq!(ProxyConfig::parse(&by_ip).unwrap().clusters[0].hash_key, Some(HashKey::ClientIp));
+    }
+
+    #[test]
+    fn test_maglev_table_size_must_be_prime() {
+        let err = |source: &str| ProxyConfig::parse(source).unwrap_err().to_string();
+        let maglev = format!("{}\"maglev\"\nhash_key = \"client_ip\"\nmaglev_table_size = 1009", CLUSTER);
+        assert_eq!(ProxyConfig::parse(&maglev).unwrap().routing_table("a").unwrap().balancer().name(), "maglev");
+        assert!(err(&maglev.replace("1009", "1000")).contains("maglev_table_size 1000 is not prime"));
+        assert!(err(&maglev.replace("\"maglev\"", "\"ring_hash\"")).contains("sets maglev_table_size but isn't maglev"));
+    }
+
+    #[test]
+    fn test_backends_sharing_an_address_name_their_host() {
+        let err = |source: &str| ProxyConfig::parse(source).unwrap_err().to_string();
+        let shared = "[[clusters]]\nname = \"a\"\nbackends = [{ address = \"127.0.0.1:1\", host = \"orders.internal\" }, { address = \"127.0.0.1:1\", host = \"users.internal:8080\" }]";
+        let backends = ProxyConfig::parse(shared).unwrap().backends().remove("a").unwrap();
+        assert_eq!(backends[1].host.as_deref(), Some("users.internal:8080"));
+        assert!(err(&shared.replace("users.internal:8080", "orders.internal")).contains("address 127.0.0.1:1 with host orders.internal is used twice"));
+        assert!(err(&shared.replace("users.internal:8080", "users internal")).contains("\"users internal\" is not a valid host name"));
+        assert!(is_valid_host("[::1]:443") && is_valid_host("10.0.0.1") && !is_valid_host("a:b") && !is_valid_host("a/b"));
+    }
+
+    #[test]
+    fn test_slow_start_window_comes_from_the_cluster() {
+        let slow = format!("{}\"peak_ewma\"\nslow_start_ms = 30000", CLUSTER);
+        let table = ProxyConfig::parse(&slow).unwrap().routing_table("a").unwrap();
+        assert_eq!(table.snapshot()[0].slow_start.window(), Duration::from_secs(30));
+        // Backends present from the start don't ramp
+        assert!(!table.snapshot()[0].slow_start.is_ramping());
+    }
+
+    #[test]
+    fn test_zone_affinity_is_configured_per_cluster() {
+        let zoned = format!(
+            "{}\"peak_ewma\"\nzone_affinity = {{ local_zone = \"us-east-1a\", max_active_requests = 20 }}",
+            CLUSTER.replace("\" }]", "\", metadata = { zone = \"us-east-1a\" } }]")
+        );
+        let table = ProxyConfig::parse(&zoned).unwrap().routing_table("a").unwrap();
+        let affinity = table.zone_affinity().unwrap();
+        assert_eq!((affinity.min_healthy_percent, affinity.max_active_requests), (70, Some(20)));
+        assert_eq!(table.snapshot()[0].zone(), Some("us-east-1a"));
+        let excessive = zoned.replace("max_active_requests = 20", "min_healthy_percent = 101");
+        assert!(ProxyConfig::parse(&excessive).unwrap_err().to_string().contains("min_healthy_percent must be at most 100"));
+    }
+
+    #[test]
+    fn test_failover_percent_is_bounded() {
+        let standby = format!("{}\"peak_ewma\"\nfailover_percent = 50", CLUSTER.replace("\" }]", "\", priority = 1 }]"));
+        let table = ProxyConfig::parse(&standby).unwrap().routing_table("a").unwrap();
+        assert_eq!((table.failover_percent(), table.snapshot()[0].priority), (50, 1));
+        let excessive = standby.replace("= 50", "= 150");
+        assert!(ProxyConfig::parse(&excessive).unwrap_err().to_string().contains("failover_percent must be at most 100"));
+    }
+}
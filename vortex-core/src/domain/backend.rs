@@ -1,9 +1,13 @@
 //! Backend server models.
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use crate::load_balancer::ewma::PeakEwma;
+use crate::load_balancer::slow_start::SlowStart;
+use crate::load_balancer::zone::ZONE_KEY;
 
 /// A unique identifier for a backend server.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -16,10 +20,27 @@ pub struct Backend {
     pub id: BackendId,
     /// The socket address of the backend
     pub addr: SocketAddr,
+    /// The name requests must carry to reach this backend, for backends
+    /// sharing an address behind a name-based ingress; the address if unset
+    pub host: Option<String>,
+    /// A `host:port` name resolved afresh for every new connection, which
+    /// goes to the next of its addresses in turn; `addr` is then only what it
+    /// last resolved to in discovery, for health checks, pool keys and logs
+    pub dns_name: Option<String>,
+    /// Failover group: 0 for the primary backends, higher for standbys that
+    /// only take traffic when the groups before them are unhealthy
+    pub priority: u32,
     /// Whether the backend is currently considered healthy
     healthy: AtomicBool,
+    /// Relative share of traffic, at least 1
+    weight: AtomicU32,
     /// The Peak EWMA tracker for this specific backend
     pub ewma: PeakEwma,
+    /// Ramps the backend's share of traffic up after it joins or recovers
+    pub slow_start: SlowStart,
+    /// Free-form attributes such as `version`, `zone` or `canary`, for
+    /// routing, filters and logs to refer to
+    pub metadata: HashMap<String, String>,
 }
 
 impl Backend {
@@ -28,23 +49,141 @@ impl Backend {
         Self {
             id,
             addr,
+            host: None,
+            dns_name: None,
+            priority: 0,
             healthy: AtomicBool::new(true), // assume healthy initially
+            weight: AtomicU32::new(1),
 
             // Initialize EWMA with 50.0ms baseline and 0.5 balanced decay
             ewma: PeakEwma::new(50.0, 0.5),
+            slow_start: SlowStart::new(),
+            metadata: HashMap::new(),
         }
     }
 
+    /// Add or replace a metadata attribute, builder style.
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Place the backend in an availability zone, builder style.
+    pub fn with_zone(self, zone: impl Into<String>) -> Self {
+        self.with_metadata(ZONE_KEY, zone)
+    }
+
+    /// The availability zone the backend runs in, from its `zone` metadata.
+    pub fn zone(&self) -> Option<&str> {
+        self.metadata.get(ZONE_KEY).map(String::as_str)
+    }
+
+    /// Set the name requests to the backend carry, builder style.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Resolve `name` on every new connection to the backend, builder style.
+    pub fn with_dns_name(mut self, name: impl Into<String>) -> Self {
+        self.dns_name = Some(name.into());
+        self
+    }
+
+    /// Put the backend in a failover group, builder style.
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// What upstream requests name as their host: `host` if set, or the address.
+    pub fn authority(&self) -> String {
+        self.host.clone().unwrap_or_else(|| self.addr.to_string())
+    }
+
+    /// Set the backend's weight, builder style.
+    pub fn with_weight(self, weight: u32) -> Self {
+        self.set_weight(weight);
+        self
+    }
+
+    /// Ramp the backend up over `window` whenever it turns healthy, builder style.
+    pub fn with_slow_start(self, window: Duration) -> Self {
+        self.slow_start.set_window(window);
+        self
+    }
+
+    /// Relative share of traffic the backend should get; 1 unless set.
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    /// Change the backend's weight; 0 counts as 1.
+    pub fn set_weight(&self, weight: u32) {
+        self.weight.store(weight.max(1), Ordering::Relaxed);
+    }
+
+    /// Whether the backend carries every attribute in `required` with the
+    /// same value. An empty `required` matches every backend.
+    pub fn matches_metadata(&self, required: &HashMap<String, String>) -> bool {
+        required.iter().all(|(key, value)| self.metadata.get(key) == Some(value))
+    }
+
     /// Check if the backend is marked healthy
     pub fn is_healthy(&self) -> bool {
         self.healthy.load(Ordering::Acquire)
     }
 
-    /// Update the health status of the backend
+    /// Update the health status of the backend. A backend turning healthy
+    /// starts its slow-start ramp.
     pub fn set_healthy(&self, is_healthy: bool) {
-        self.healthy.store(is_healthy, Ordering::Release);
+        let was_healthy = self.healthy.swap(is_healthy, Ordering::AcqRel);
+        if is_healthy && !was_healthy {
+            self.slow_start.begin();
+        }
+    }
+
+    /// The backend's Peak EWMA score per unit of weight, raised while it
+    /// slow-starts. Lower is better.
+    pub fn load_score(&self) -> f64 {
+        self.ewma.calculate_score() / (f64::from(self.weight()) * self.slow_start.share())
     }
 }
 
 /// A thread-safe reference to a Backend.
 pub type SharedBackend = Arc<Backend>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_match_requires_every_attribute() {
+        let backend = Backend::new(BackendId(1), "10.0.0.1:80".parse().unwrap())
+            .with_metadata("version", "v2")
+            .with_metadata("zone", "us-east-1a");
+        let required = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+
+        assert!(backend.matches_metadata(&HashMap::new()));
+        assert!(backend.matches_metadata(&required(&[("version", "v2")])));
+        assert!(!backend.matches_metadata(&required(&[("version", "v2"), ("canary", "true")])));
+        assert!(!backend.matches_metadata(&required(&[("version", "v1")])));
+    }
+
+    #[test]
+    fn test_turning_healthy_starts_the_slow_start_ramp() {
+        let backend = Backend::new(BackendId(1), "10.0.0.1:80".parse().unwrap())
+            .with_weight(2)
+            .with_slow_start(Duration::from_secs(30));
+        let full = backend.load_score();
+        assert_eq!(full, backend.ewma.calculate_score() / 2.0);
+        // Already healthy, so no ramp
+        backend.set_healthy(true);
+        assert!(!backend.slow_start.is_ramping());
+
+        backend.set_healthy(false);
+        backend.set_healthy(true);
+        assert!(backend.slow_start.is_ramping());
+        assert!(backend.load_score() > full * 5.0);
+    }
+}
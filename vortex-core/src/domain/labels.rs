@@ -0,0 +1,76 @@
+//! Static observability labels attached to pools and routes.
+//!
+//! Operators tag traffic with ownership dimensions (team, service, environment)
+//! that the data plane copies onto metrics, traces, and access logs.
+
+use std::collections::BTreeMap;
+
+/// An ordered set of `key=value` observability labels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels(BTreeMap<String, String>);
+
+impl Labels {
+    /// Create an empty label set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a label, builder style.
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    /// Add or replace a label.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.0.insert(key.into(), value.into());
+    }
+
+    /// Look up a label value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Whether no labels are set.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over labels in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Returns a copy of `self` overlaid with `more_specific`, whose values win.
+    ///
+    /// Used to layer route labels on top of pool labels.
+    pub fn merged(&self, more_specific: &Labels) -> Labels {
+        let mut merged = self.clone();
+        for (k, v) in more_specific.iter() {
+            merged.insert(k, v);
+        }
+        merged
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Labels {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Labels(iter.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_more_specific_labels_win() {
+        let pool = Labels::new().with("team", "payments").with("environment", "prod");
+        let route = Labels::new().with("team", "checkout");
+
+        let merged = pool.merged(&route);
+        assert_eq!(merged.get("team"), Some("checkout"));
+        assert_eq!(merged.get("environment"), Some("prod"));
+        assert_eq!(merged.get("service"), None);
+    }
+}
@@ -1,4 +1,5 @@
 //! Domain models for Vortex.
 
 pub mod backend;
+pub mod labels;
 pub mod routing;
@@ -1,16 +1,54 @@
 //! Routing module for defining active traffic targets.
 
 use arc_swap::ArcSwap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use crate::domain::backend::SharedBackend;
+use std::time::{Duration, Instant};
+use crate::domain::backend::{BackendId, SharedBackend};
+use crate::domain::labels::Labels;
+use crate::load_balancer::balancer::{Balancer, PeakEwmaBalancer};
+use crate::load_balancer::hash::HashKey;
+use crate::load_balancer::priority::DEFAULT_FAILOVER_PERCENT;
+use crate::load_balancer::zone::ZoneAffinity;
+
+/// How long a removed backend keeps serving sticky traffic by default.
+pub const DEFAULT_DRAIN_GRACE: Duration = Duration::from_secs(30);
+
+/// A backend removed from the table that still serves traffic pinned to it.
+#[derive(Debug, Clone)]
+pub struct DrainingBackend {
+    /// The removed backend
+    pub backend: SharedBackend,
+    /// When draining ends and its connections may be closed
+    pub until: Instant,
+}
 
 /// A lock-free routing table mapping traffic to backends.
 ///
 /// Uses `ArcSwap` to allow atomic, zero-downtime hot reloads of the backend
 /// topology without acquiring read locks on the hot path (like `RwLock` would).
+///
+/// Backends dropped by a reload are not forgotten at once: they drain for a
+/// grace period during which load balancing skips them but lookups by id
+/// (sticky sessions, pinned retries) still find them. Every replacement bumps
+/// the table's generation, so background work walking a snapshot (like a
+/// health check sweep) can tell when it went stale.
+///
+/// The table also owns the pool's `Balancer`, Peak EWMA by default, the
+/// `HashKey` requests are hashed by for hash-based balancers, the pool's
+/// `ZoneAffinity`, if it keeps traffic in the proxy's zone, and the healthy
+/// share at which a failover group brings in the next one.
 #[derive(Debug)]
 pub struct RoutingTable {
     backends: ArcSwap<Vec<SharedBackend>>,
+    draining: ArcSwap<Vec<DrainingBackend>>,
+    generation: AtomicU64,
+    drain_grace: Duration,
+    labels: ArcSwap<Labels>,
+    balancer: Arc<dyn Balancer>,
+    hash_key: Option<HashKey>,
+    zone_affinity: Option<ZoneAffinity>,
+    failover_percent: u8,
 }
 
 impl RoutingTable {
@@ -18,12 +56,132 @@ impl RoutingTable {
     pub fn new(initial_backends: Vec<SharedBackend>) -> Self {
         Self {
             backends: ArcSwap::from_pointee(initial_backends),
+            draining: ArcSwap::from_pointee(Vec::new()),
+            generation: AtomicU64::new(0),
+            drain_grace: DEFAULT_DRAIN_GRACE,
+            labels: ArcSwap::from_pointee(Labels::new()),
+            balancer: Arc::new(PeakEwmaBalancer),
+            hash_key: None,
+            zone_affinity: None,
+            failover_percent: DEFAULT_FAILOVER_PERCENT,
         }
     }
 
+    /// Balance load with `balancer`, builder style.
+    pub fn with_balancer(mut self, balancer: Arc<dyn Balancer>) -> Self {
+        self.balancer = balancer;
+        self
+    }
+
+    /// The pool's load-balancing algorithm.
+    pub fn balancer(&self) -> &dyn Balancer {
+        &*self.balancer
+    }
+
+    /// Hash requests by `key` for the balancer, builder style.
+    pub fn with_hash_key(mut self, key: HashKey) -> Self {
+        self.hash_key = Some(key);
+        self
+    }
+
+    /// The request property the pool hashes requests by, if any.
+    pub fn hash_key(&self) -> Option<&HashKey> {
+        self.hash_key.as_ref()
+    }
+
+    /// Prefer backends in the proxy's zone, builder style.
+    pub fn with_zone_affinity(mut self, affinity: ZoneAffinity) -> Self {
+        self.zone_affinity = Some(affinity);
+        self
+    }
+
+    /// How the pool keeps traffic in the proxy's zone, if it does.
+    pub fn zone_affinity(&self) -> Option<&ZoneAffinity> {
+        self.zone_affinity.as_ref()
+    }
+
+    /// Bring in the next failover group once fewer than `percent` of a
+    /// group's backends are healthy, builder style.
+    pub fn with_failover_percent(mut self, percent: u8) -> Self {
+        self.failover_percent = percent;
+        self
+    }
+
+    /// The healthy percentage below which a failover group brings in the next.
+    pub fn failover_percent(&self) -> u8 {
+        self.failover_percent
+    }
+
+    /// Set how long removed backends drain, builder style.
+    pub fn with_drain_grace(mut self, grace: Duration) -> Self {
+        self.drain_grace = grace;
+        self
+    }
+
+    /// Attach static observability labels to this pool, builder style.
+    pub fn with_labels(self, labels: Labels) -> Self {
+        self.labels.store(Arc::new(labels));
+        self
+    }
+
+    /// The pool's observability labels.
+    pub fn labels(&self) -> Arc<Labels> {
+        self.labels.load_full()
+    }
+
+    /// Atomically replace the pool's observability labels (e.g. during config hot-reload).
+    pub fn update_labels(&self, labels: Labels) {
+        self.labels.store(Arc::new(labels));
+    }
+
     /// Atomically replace the entire set of backends (e.g., during config hot-reload).
+    ///
+    /// Backends missing from `new_backends` start draining; backends that
+    /// come back while draining are active again.
     pub fn update_backends(&self, new_backends: Vec<SharedBackend>) {
-        self.backends.store(Arc::new(new_backends));
+        let until = Instant::now() + self.drain_grace;
+        let new_backends = Arc::new(new_backends);
+        let old_backends = self.backends.swap(new_backends.clone());
+        self.generation.fetch_add(1, Ordering::Release);
+
+        let is_active = |b: &SharedBackend| new_backends.iter().any(|n| n.id == b.id);
+        self.draining.rcu(|draining| {
+            let mut next: Vec<DrainingBackend> = draining.iter().filter(|d| !is_active(&d.backend)).cloned().collect();
+            for backend in old_backends.iter().filter(|b| !is_active(b)) {
+                if !next.iter().any(|d| d.backend.id == backend.id) {
+                    next.push(DrainingBackend { backend: backend.clone(), until });
+                }
+            }
+            next
+        });
+    }
+
+    /// How many times the set of backends has been replaced.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Backends that were removed and are still draining.
+    pub fn draining(&self) -> Arc<Vec<DrainingBackend>> {
+        self.draining.load_full()
+    }
+
+    /// Forget draining backends whose grace period ended by `now`, returning
+    /// them so their connections can be closed.
+    pub fn take_drained(&self, now: Instant) -> Vec<SharedBackend> {
+        if self.draining.load().iter().all(|d| d.until > now) {
+            return Vec::new();
+        }
+        let previous = self.draining.rcu(|draining| {
+            draining.iter().filter(|d| d.until > now).cloned().collect::<Vec<_>>()
+        });
+        previous.iter().filter(|d| d.until <= now).map(|d| d.backend.clone()).collect()
+    }
+
+    /// Look up a backend by id among the active and the still draining ones.
+    pub fn backend(&self, id: BackendId) -> Option<SharedBackend> {
+        let active = self.backends.load().iter().find(|b| b.id == id).cloned();
+        active.or_else(|| self.draining.load().iter().find(|d| d.backend.id == id).map(|d| d.backend.clone()))
     }
 
     /// Selects the first available healthy backend.
@@ -3,10 +3,14 @@
 //! This crate contains the domain models, configuration definitions, and routing primitives
 //! that power//! the `vortex-proxy` Tokio adapters.
 
+pub mod config;
 pub mod domain;
 pub mod load_balancer;
+pub mod pipeline;
+pub mod route;
+pub mod stats;
 
 /// A placeholder function to start.
 pub fn core_init() {
-    println!("vortex-core initialized");
+    tracing::debug!("vortex-core initialized");
 }
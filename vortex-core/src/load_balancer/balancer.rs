@@ -0,0 +1,141 @@
+//! The pluggable load-balancing strategy of a pool.
+//!
+//! Every `RoutingTable` carries a `Balancer`, Peak EWMA unless the pool is
+//! configured otherwise, and every selection goes through it: routed
+//! requests, retries, subsets and raw TCP tunnels alike. The selection
+//! functions in `selector` decide which backends are usable (healthy, not
+//! excluded, in the subset); the balancer only decides among them.
+
+use serde::Deserialize;
+use std::fmt;
+use std::sync::Arc;
+
+use super::least_connections::LeastConnectionsBalancer;
+use super::maglev::MaglevBalancer;
+use super::power_of_two::PowerOfTwoBalancer;
+use super::ring_hash::RingHashBalancer;
+use super::round_robin::RoundRobinBalancer;
+use super::weighted_round_robin::WeightedRoundRobinBalancer;
+use crate::domain::backend::{Backend, SharedBackend};
+
+/// A load-balancing algorithm.
+pub trait Balancer: fmt::Debug + Send + Sync {
+    /// Picks one of the `backends` that `usable` accepts, or `None` if it
+    /// accepts none. `backends` is the pool's whole list in its configured
+    /// order, so algorithms that keep state per backend or position see the
+    /// same list from call to call. `hash` is the request's hash (see
+    /// `hash`), if the pool hashes requests and the request had the key;
+    /// algorithms that don't hash ignore it.
+    fn pick(&self, backends: &[SharedBackend], usable: &dyn Fn(&Backend) -> bool, hash: Option<u64>)
+        -> Option<SharedBackend>;
+
+    /// The algorithm's name as configured, e.g. `peak_ewma`.
+    fn name(&self) -> &'static str;
+}
+
+/// The algorithms a pool can be configured with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Lowest Peak EWMA latency times in-flight requests, scaled by weight
+    #[default]
+    PeakEwma,
+    /// The lower Peak EWMA score of two random usable backends
+    PeakEwmaP2c,
+    /// Each usable backend in turn, regardless of latency or weight
+    RoundRobin,
+    /// Usable backends in turn, each as often as its weight says
+    WeightedRoundRobin,
+    /// Fewest in-flight requests per unit of weight, regardless of latency
+    LeastConnections,
+    /// The backend owning the request's hash on a consistent hash ring
+    RingHash,
+    /// The backend owning the request's hash in a Maglev lookup table
+    Maglev,
+}
+
+impl Algorithm {
+    /// A fresh balancer running this algorithm.
+    pub fn build(self) -> Arc<dyn Balancer> {
+        match self {
+            Algorithm::PeakEwma => Arc::new(PeakEwmaBalancer),
+            Algorithm::PeakEwmaP2c => Arc::new(PowerOfTwoBalancer::new()),
+            Algorithm::RoundRobin => Arc::new(RoundRobinBalancer::new()),
+            Algorithm::WeightedRoundRobin => Arc::new(WeightedRoundRobinBalancer::new()),
+            Algorithm::LeastConnections => Arc::new(LeastConnectionsBalancer::new()),
+            Algorithm::RingHash => Arc::new(RingHashBalancer::default()),
+            Algorithm::Maglev => Arc::new(MaglevBalancer::default()),
+        }
+    }
+
+    /// Whether the algorithm picks by the request's hash, so the pool needs
+    /// a `HashKey`.
+    pub fn hashes_requests(self) -> bool {
+        matches!(self, Algorithm::RingHash | Algorithm::Maglev)
+    }
+}
+
+/// Picks the backend with the lowest Peak EWMA score divided by its
+/// weight, so a backend of weight 2 is picked over one of weight 1 until its
+/// score is twice as high. Slow-starting backends count as proportionally
+/// lighter.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeakEwmaBalancer;
+
+impl Balancer for PeakEwmaBalancer {
+    fn pick(
+        &self,
+        backends: &[SharedBackend],
+        usable: &dyn Fn(&Backend) -> bool,
+        _hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        backends
+            .iter()
+            .filter(|b| usable(b))
+            .map(|b| (rank(b.load_score()), b))
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, b)| b.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "peak_ewma"
+    }
+}
+
+/// Orders scores totally: NaN, which a sane score never is, ranks last
+/// instead of comparing equal to everything.
+fn rank(score: f64) -> f64 {
+    if score.is_nan() {
+        f64::INFINITY
+    } else {
+        score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::BackendId;
+
+    #[test]
+    fn test_peak_ewma_skips_unusable_backends() {
+        let backends: Vec<SharedBackend> = (1..=3)
+            .map(|i| {
+                let backend = Backend::new(BackendId(i), ([127, 0, 0, 1], 8000 + i as u16).into());
+                backend.ewma.restore(f64::from(i) * 10.0);
+                Arc::new(backend)
+            })
+            .collect();
+        let balancer = Algorithm::default().build();
+        assert_eq!(balancer.name(), "peak_ewma");
+        assert_eq!(balancer.pick(&backends, &|_| true, None).unwrap().id, BackendId(1));
+        assert_eq!(balancer.pick(&backends, &|b| b.id != BackendId(1), None).unwrap().id, BackendId(2));
+        assert!(balancer.pick(&backends, &|_| false, None).is_none());
+    }
+
+    #[test]
+    fn test_rank_puts_nan_last() {
+        assert_eq!(rank(f64::NAN), f64::INFINITY);
+        assert!(rank(1.0).total_cmp(&rank(f64::NAN)).is_lt());
+    }
+}
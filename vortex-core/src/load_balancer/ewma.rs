@@ -5,7 +5,16 @@
 //! gracefully decaying back to the historical average over time.
 
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+
+/// Latency samples are clamped to this many milliseconds (one hour), so a
+/// clock glitch can't park a node at an absurd score for good.
+pub const MAX_LATENCY_MS: f64 = 3_600_000.0;
+
+/// Maps a latency to the tracked range, or `None` for NaN, infinite and
+/// negative values, which no real measurement produces.
+fn sanitize_latency(ms: f64) -> Option<f64> {
+    (ms.is_finite() && ms >= 0.0).then(|| ms.min(MAX_LATENCY_MS))
+}
 
 /// The mathematical representation of a node's latency characteristics over time.
 #[derive(Debug)]
@@ -27,10 +36,12 @@ pub struct PeakEwma {
 impl PeakEwma {
     /// Create a new Peak EWMA tracker with a specified decay alpha.
     ///
-    /// Typically, an alpha of `0.5` represents a balanced decay.
+    /// Typically, an alpha of `0.5` represents a balanced decay. Invalid
+    /// inputs fall back to a zero baseline and a balanced decay.
     pub fn new(initial_latency_ms: f64, decay_alpha: f64) -> Self {
+        let decay_alpha = if decay_alpha.is_nan() { 0.5 } else { decay_alpha.clamp(0.0, 1.0) };
         Self {
-            ewma: AtomicU64::new(initial_latency_ms.to_bits()),
+            ewma: AtomicU64::new(sanitize_latency(initial_latency_ms).unwrap_or(0.0).to_bits()),
             decay_alpha,
             active_requests: AtomicU64::new(0),
         }
@@ -41,8 +52,27 @@ impl PeakEwma {
         f64::from_bits(self.ewma.load(Ordering::Relaxed))
     }
 
+    /// Read the number of in-flight requests.
+    pub fn active_requests(&self) -> u64 {
+        self.active_requests.load(Ordering::Relaxed)
+    }
+
+    /// Overwrite the moving average, e.g. with one saved before a restart.
+    /// Negative and non-finite values are ignored.
+    pub fn restore(&self, ewma_ms: f64) {
+        if let Some(ewma_ms) = sanitize_latency(ewma_ms) {
+            self.ewma.store(ewma_ms.to_bits(), Ordering::Release);
+        }
+    }
+
     /// Update the moving average with a newly observed latency sample.
+    ///
+    /// NaN, infinite and negative samples are dropped; samples above
+    /// `MAX_LATENCY_MS` count as `MAX_LATENCY_MS`.
     pub fn observe_latency(&self, rtt_ms: f64) {
+        let Some(rtt_ms) = sanitize_latency(rtt_ms) else {
+            return;
+        };
         let mut current_bits = self.ewma.load(Ordering::Acquire);
 
         loop {
@@ -55,7 +85,8 @@ impl PeakEwma {
             let next_ewma = if rtt_ms > current_ewma {
                 rtt_ms
             } else {
-                (rtt_ms * (1.0 - self.decay_alpha)) + (current_ewma * self.decay_alpha)
+                // Rounding can land a hair outside the samples' range
+                ((rtt_ms * (1.0 - self.decay_alpha)) + (current_ewma * self.decay_alpha)).min(MAX_LATENCY_MS)
             };
 
             let next_bits = next_ewma.to_bits();
@@ -78,6 +109,12 @@ impl PeakEwma {
 
     /// Increment the active request counter and return a guard
     /// that will decrement it when dropped.
+    ///
+    /// The increment and the guard are created together, with nothing in
+    /// between that could be cancelled or panic, so every increment is
+    /// matched by exactly one decrement: when the request completes, when its
+    /// future is dropped mid-flight, or while a panic unwinds through it.
+    #[must_use = "the request stops counting as active as soon as the guard is dropped"]
     pub fn increment_active(&self) -> ActiveRequestGuard<'_> {
         self.active_requests.fetch_add(1, Ordering::Relaxed);
         ActiveRequestGuard { ewma: self }
@@ -87,6 +124,9 @@ impl PeakEwma {
     /// A lower score is better.
     ///
     /// Score = (EWMA Latency + 1) * (Active Requests + 1)
+    ///
+    /// The score is always finite and positive, since the EWMA stays
+    /// within `0..=MAX_LATENCY_MS`.
     pub fn calculate_score(&self) -> f64 {
         let ewma = self.get_ewma();
         let active = self.active_requests.load(Ordering::Relaxed) as f64;
@@ -98,13 +138,19 @@ impl PeakEwma {
 
 /// A RAII guard that automatically decrements the active request pool for a node
 /// when the request finishes and drops the guard.
+#[must_use = "the request stops counting as active as soon as the guard is dropped"]
 pub struct ActiveRequestGuard<'a> {
     ewma: &'a PeakEwma,
 }
 
 impl<'a> Drop for ActiveRequestGuard<'a> {
     fn drop(&mut self) {
-        self.ewma.active_requests.fetch_sub(1, Ordering::Relaxed);
+        // Saturating, so an accounting bug elsewhere can never wrap the
+        // counter around and make the node look infinitely busy
+        let _ = self
+            .ewma
+            .active_requests
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| active.checked_sub(1));
     }
 }
 
@@ -158,6 +204,33 @@ mod tests {
         assert_eq!(ewma.calculate_score(), 11.0);
     }
 
+    #[test]
+    fn test_active_requests_settle_after_panics_and_threads() {
+        let ewma = std::sync::Arc::new(PeakEwma::new(10.0, 0.5));
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = ewma.increment_active();
+            panic!("request handler blew up");
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(ewma.active_requests(), 0);
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let ewma = ewma.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let _guard = ewma.increment_active();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(ewma.active_requests(), 0);
+    }
+
     proptest! {
         #[test]
         fn prop_ewma_never_exceeds_bounds(
@@ -183,5 +256,32 @@ mod tests {
                 prop_assert!(current <= max_observed);
             }
         }
+
+        #[test]
+        fn prop_adversarial_samples_keep_the_ewma_sane(
+            initial in prop::num::f64::ANY,
+            alpha in prop::num::f64::ANY,
+            samples in prop::collection::vec(prop::num::f64::ANY, 1..100),
+        ) {
+            let ewma = PeakEwma::new(initial, alpha);
+            for sample in samples {
+                ewma.observe_latency(sample);
+                let current = ewma.get_ewma();
+                prop_assert!((0.0..=MAX_LATENCY_MS).contains(&current), "ewma {} out of bounds", current);
+                prop_assert!(ewma.calculate_score().is_finite());
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_samples_are_ignored() {
+        let ewma = PeakEwma::new(f64::NAN, f64::INFINITY);
+        assert_eq!(ewma.get_ewma(), 0.0);
+        for sample in [f64::NAN, -1.0, f64::INFINITY, f64::NEG_INFINITY] {
+            ewma.observe_latency(sample);
+            assert_eq!(ewma.get_ewma(), 0.0);
+        }
+        ewma.observe_latency(1e300);
+        assert_eq!(ewma.get_ewma(), MAX_LATENCY_MS);
     }
 }
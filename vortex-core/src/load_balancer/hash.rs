@@ -0,0 +1,52 @@
+//! Request hashing for hash-based balancers.
+//!
+//! A pool balanced by hash is configured with the request property that
+//! identifies a client (its IP, a header, a cookie). The proxy hashes that
+//! property and hands the hash to the pool's `Balancer`, so the same key
+//! keeps reaching the same backend. The hash is stable across builds,
+//! platforms and proxy instances, so every instance agrees on the mapping.
+
+use serde::Deserialize;
+
+/// The request property a pool hashes requests by.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    /// The downstream peer's IP address
+    ClientIp,
+    /// The value of this request header
+    Header(String),
+    /// The value of this cookie
+    Cookie(String),
+}
+
+/// 64-bit FNV-1a over the concatenated `parts`, finalized with
+/// MurmurHash3's mixer so that every output bit depends on every input bit.
+pub fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // FNV's low bits only depend on the low bits of the input, so they are
+    // mixed with the high ones before the hash is reduced to a bucket
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_stable_and_spreads_low_bits() {
+        // Pinned, so a change that would remap every key fails here first
+        assert_eq!(stable_hash(&[b"user-", b"7"]), stable_hash(&[b"user-7"]));
+        assert_eq!(stable_hash(&[b"user-7"]), 0x05f4_5893_168b_eb5f);
+        let odd = (0..1000).filter(|i| stable_hash(&[format!("user-{}", i).as_bytes()]) % 2 == 1).count();
+        assert!((450..550).contains(&odd), "{} of 1000 odd", odd);
+    }
+}
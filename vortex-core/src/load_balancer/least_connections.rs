@@ -0,0 +1,103 @@
+//! Least-connections load balancing.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::balancer::Balancer;
+use crate::domain::backend::{Backend, SharedBackend};
+
+/// Picks the usable backend with the fewest in-flight requests per unit of
+/// weight, ignoring latency.
+///
+/// Suits backends whose cost is the work they hold rather than how fast
+/// they answer, such as long polls or streaming. Ties, which are the norm
+/// while traffic is light, go to the backends in turn rather than always to
+/// the first one.
+#[derive(Debug, Default)]
+pub struct LeastConnectionsBalancer {
+    next: AtomicUsize,
+}
+
+impl LeastConnectionsBalancer {
+    /// A balancer breaking its first tie in favor of the first backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Balancer for LeastConnectionsBalancer {
+    fn pick(
+        &self,
+        backends: &[SharedBackend],
+        usable: &dyn Fn(&Backend) -> bool,
+        _hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        if backends.is_empty() {
+            return None;
+        }
+        // Scanning from a rotating start lets the first of the tied win in turn
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % backends.len();
+        let (wrapped, ahead) = backends.split_at(start);
+        let mut best: Option<(&SharedBackend, f64)> = None;
+        for backend in ahead.iter().chain(wrapped).filter(|b| usable(b)) {
+            let load = backend.ewma.active_requests() as f64 / f64::from(backend.weight());
+            if best.is_none_or(|(_, least)| load < least) {
+                best = Some((backend, load));
+            }
+        }
+        best.map(|(backend, _)| backend.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "least_connections"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::BackendId;
+    use std::sync::Arc;
+
+    fn pool(weights: &[u32]) -> Vec<SharedBackend> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| {
+                let id = i as u32 + 1;
+                Arc::new(Backend::new(BackendId(id), ([127, 0, 0, 1], 8000 + id as u16).into()).with_weight(weight))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_picks_the_least_loaded_per_unit_of_weight() {
+        let backends = pool(&[1, 1, 2]);
+        let balancer = LeastConnectionsBalancer::new();
+        // Idle backends share requests in turn
+        let picks: Vec<_> = (0..3).map(|_| balancer.pick(&backends, &|_| true, None).unwrap().id.0).collect();
+        assert_eq!(picks, [1, 2, 3]);
+
+        // Latency plays no part, however slow a backend looks
+        backends[0].ewma.restore(5000.0);
+        let _first = backends[1].ewma.increment_active();
+        let _third = [backends[2].ewma.increment_active(), backends[2].ewma.increment_active()];
+        assert!((0..6).all(|_| balancer.pick(&backends, &|_| true, None).unwrap().id == BackendId(1)));
+
+        // Two requests on weight 2 count as one on weight 1
+        let _busy = backends[0].ewma.increment_active();
+        let mut picks: Vec<_> = (0..6).map(|_| balancer.pick(&backends, &|_| true, None).unwrap().id.0).collect();
+        picks.sort();
+        picks.dedup();
+        assert_eq!(picks, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_unusable_backends_are_skipped() {
+        let backends = pool(&[1, 1]);
+        let balancer = LeastConnectionsBalancer::new();
+        let _busy = backends[1].ewma.increment_active();
+        assert_eq!(balancer.pick(&backends, &|b| b.id == BackendId(2), None).unwrap().id, BackendId(2));
+        assert!(balancer.pick(&backends, &|_| false, None).is_none());
+        assert!(balancer.pick(&[], &|_| true, None).is_none());
+    }
+}
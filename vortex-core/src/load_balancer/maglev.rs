@@ -0,0 +1,215 @@
+//! Maglev consistent hashing load balancing.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use super::balancer::Balancer;
+use super::hash::stable_hash;
+use crate::domain::backend::{Backend, BackendId, SharedBackend};
+
+/// Lookup table size used unless configured otherwise.
+pub const DEFAULT_TABLE_SIZE: usize = 65_537;
+
+/// Maps each request hash to a backend through a fixed-size lookup table
+/// (Google's Maglev).
+///
+/// Every backend walks its own permutation of the table's slots, derived
+/// from its id, and the backends take turns claiming the next free slot in
+/// theirs, as many per turn as their weight. Lookups are a single index
+/// whatever the pool size, keys spread almost perfectly evenly, and a pool
+/// change only moves a little more than the minimal share of keys. That
+/// makes it a better fit than ring hash for large pools, at the cost of
+/// rebuilding the whole table when the pool changes.
+///
+/// The table size must be a prime, and well above the number of backends
+/// for the spread to be even (100 times is plenty). A key whose backend
+/// isn't usable moves on to the backends of the following slots.
+#[derive(Debug)]
+pub struct MaglevBalancer {
+    table_size: usize,
+    table: RwLock<Arc<Table>>,
+    unkeyed: AtomicU64,
+}
+
+/// The lookup table of one backend list.
+#[derive(Debug, Default)]
+struct Table {
+    /// The ids and weights the table was built from, in pool order
+    members: Vec<(BackendId, u32)>,
+    /// The index in the pool of each slot's backend
+    slots: Vec<u32>,
+}
+
+impl Table {
+    fn build(backends: &[SharedBackend], size: usize) -> Self {
+        let members: Vec<_> = backends.iter().map(|b| (b.id, b.weight())).collect();
+        if members.is_empty() {
+            return Self::default();
+        }
+        let size_u64 = size as u64;
+        let permutations: Vec<(u64, u64)> = members
+            .iter()
+            .map(|(id, _)| {
+                let offset = stable_hash(&[&id.0.to_be_bytes(), b"offset"]) % size_u64;
+                let skip = stable_hash(&[&id.0.to_be_bytes(), b"skip"]) % (size_u64 - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+
+        let mut slots = vec![u32::MAX; size];
+        let mut next = vec![0u64; members.len()];
+        let mut filled = 0;
+        'fill: loop {
+            for (index, &(_, weight)) in members.iter().enumerate() {
+                let (offset, skip) = permutations[index];
+                for _ in 0..weight {
+                    // Walk this backend's permutation to its next free slot
+                    let slot = loop {
+                        let slot = ((offset + next[index] * skip) % size_u64) as usize;
+                        next[index] += 1;
+                        if slots[slot] == u32::MAX {
+                            break slot;
+                        }
+                    };
+                    slots[slot] = index as u32;
+                    filled += 1;
+                    if filled == size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+        Self { members, slots }
+    }
+
+    fn is_for(&self, backends: &[SharedBackend]) -> bool {
+        self.members.len() == backends.len()
+            && self.members.iter().zip(backends).all(|(&(id, weight), b)| id == b.id && weight == b.weight())
+    }
+}
+
+/// Whether `n` is prime, as a Maglev table size must be.
+pub fn is_valid_table_size(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d))
+}
+
+impl MaglevBalancer {
+    /// A balancer with a table of `table_size` slots, which must be prime.
+    ///
+    /// # Panics
+    ///
+    /// If `table_size` is not prime.
+    pub fn new(table_size: usize) -> Self {
+        assert!(is_valid_table_size(table_size), "Maglev table size {} is not prime", table_size);
+        Self {
+            table_size,
+            table: RwLock::default(),
+            unkeyed: AtomicU64::new(0),
+        }
+    }
+
+    /// The table for `backends`, rebuilt if the pool changed since the last pick.
+    fn table(&self, backends: &[SharedBackend]) -> Arc<Table> {
+        let table = self.table.read().expect("table lock poisoned").clone();
+        if table.is_for(backends) {
+            return table;
+        }
+        let table = Arc::new(Table::build(backends, self.table_size));
+        *self.table.write().expect("table lock poisoned") = table.clone();
+        table
+    }
+}
+
+impl Default for MaglevBalancer {
+    fn default() -> Self {
+        Self::new(DEFAULT_TABLE_SIZE)
+    }
+}
+
+impl Balancer for MaglevBalancer {
+    fn pick(
+        &self,
+        backends: &[SharedBackend],
+        usable: &dyn Fn(&Backend) -> bool,
+        hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        // Without this, a pool with nothing usable would scan the whole table
+        if !backends.iter().any(|b| usable(b)) {
+            return None;
+        }
+        let table = self.table(backends);
+        let hash = hash.unwrap_or_else(|| {
+            let n = self.unkeyed.fetch_add(1, Ordering::Relaxed);
+            stable_hash(&[&n.to_be_bytes()])
+        });
+        let start = (hash % self.table_size as u64) as usize;
+        let (wrapped, ahead) = table.slots.split_at(start);
+        ahead
+            .iter()
+            .chain(wrapped)
+            .map(|&index| &backends[index as usize])
+            .find(|b| usable(b))
+            .cloned()
+    }
+
+    fn name(&self) -> &'static str {
+        "maglev"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(ids: impl IntoIterator<Item = u32>) -> Vec<SharedBackend> {
+        ids.into_iter()
+            .map(|id| Arc::new(Backend::new(BackendId(id), ([127, 0, 0, 1], 8000 + id as u16).into())))
+            .collect()
+    }
+
+    fn assign(balancer: &MaglevBalancer, backends: &[SharedBackend]) -> Vec<u32> {
+        (0..5000u64)
+            .map(|key| balancer.pick(backends, &|_| true, Some(stable_hash(&[&key.to_be_bytes()]))).unwrap().id.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_table_is_balanced_and_weighted() {
+        let table = Table::build(&pool(1..=10), 1009);
+        for index in 0..10 {
+            let share = table.slots.iter().filter(|&&i| i == index).count();
+            assert!((100..=101).contains(&share), "backend {} has {} slots", index, share);
+        }
+
+        let mut backends = pool(1..=2);
+        backends.push(Arc::new(Backend::new(BackendId(3), ([127, 0, 0, 1], 8003).into()).with_weight(2)));
+        let table = Table::build(&backends, 1009);
+        let heavy = table.slots.iter().filter(|&&i| i == 2).count();
+        assert!((500..=506).contains(&heavy), "weight 2 of 4 got {} of 1009 slots", heavy);
+    }
+
+    #[test]
+    fn test_keys_stick_and_few_move_when_the_pool_changes() {
+        let balancer = MaglevBalancer::new(10_007);
+        let before = assign(&balancer, &pool(1..=10));
+        assert_eq!(before, assign(&balancer, &pool(1..=10)));
+
+        // Removing a backend moves its own keys and barely any others
+        let after = assign(&balancer, &pool((1..=10).filter(|&id| id != 4)));
+        let collateral = before.iter().zip(&after).filter(|(&a, &b)| a != b && a != 4).count();
+        assert!(collateral < 250, "{} of 5000 keys moved needlessly", collateral);
+
+        let backends = pool(1..=3);
+        let owner = balancer.pick(&backends, &|_| true, Some(42)).unwrap();
+        assert_ne!(balancer.pick(&backends, &|b| b.id != owner.id, Some(42)).unwrap().id, owner.id);
+        assert!(balancer.pick(&backends, &|_| false, Some(42)).is_none());
+    }
+
+    #[test]
+    fn test_table_sizes_must_be_prime() {
+        assert!(is_valid_table_size(65_537));
+        assert!(is_valid_table_size(2));
+        assert!(!is_valid_table_size(65_536));
+        assert!(!is_valid_table_size(1));
+    }
+}
@@ -1,4 +1,16 @@
 //! Load balancing algorithms and node selection strategies.
 
+pub mod balancer;
 pub mod ewma;
+pub mod hash;
+pub mod least_connections;
+pub mod maglev;
+pub mod power_of_two;
+pub mod priority;
+pub mod ring_hash;
+pub mod round_robin;
 pub mod selector;
+pub mod slow_start;
+pub mod subset;
+pub mod weighted_round_robin;
+pub mod zone;
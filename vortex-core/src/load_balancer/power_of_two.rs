@@ -0,0 +1,118 @@
+//! Power-of-two-choices Peak EWMA load balancing.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::balancer::{Balancer, PeakEwmaBalancer};
+use super::hash::stable_hash;
+use crate::domain::backend::{Backend, SharedBackend};
+
+/// Draws made to find two usable backends before scanning the whole pool.
+const SAMPLE_ATTEMPTS: usize = 8;
+
+/// Picks two distinct usable backends at random and sends the request to
+/// the one with the lower Peak EWMA score per unit of weight, slow start
+/// included.
+///
+/// A full scan sends every request to the single best backend until its
+/// score catches up, so a burst herds onto it; comparing two random ones
+/// spreads the burst while still steering away from slow backends, and only
+/// looks at two backends however large the pool. Pools where most backends
+/// aren't usable fall back to a full scan.
+#[derive(Debug)]
+pub struct PowerOfTwoBalancer {
+    draws: AtomicU64,
+}
+
+impl PowerOfTwoBalancer {
+    /// A balancer seeded from the clock, so proxy instances draw differently.
+    pub fn new() -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        Self { draws: AtomicU64::new(seed) }
+    }
+
+    /// Two distinct indices below `n`, which is at least 2.
+    fn draw(&self, n: usize) -> (usize, usize) {
+        let draw = self.draws.fetch_add(1, Ordering::Relaxed);
+        let random = stable_hash(&[&draw.to_be_bytes()]);
+        let n = n as u64;
+        let first = random % n;
+        let second = (first + 1 + (random >> 32) % (n - 1)) % n;
+        (first as usize, second as usize)
+    }
+}
+
+impl Default for PowerOfTwoBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Balancer for PowerOfTwoBalancer {
+    fn pick(
+        &self,
+        backends: &[SharedBackend],
+        usable: &dyn Fn(&Backend) -> bool,
+        hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        if backends.len() >= 2 {
+            for _ in 0..SAMPLE_ATTEMPTS {
+                let (first, second) = self.draw(backends.len());
+                let (a, b) = (&backends[first], &backends[second]);
+                if usable(a) && usable(b) {
+                    // NaN compares false, so a sane score wins over it
+                    return Some(if b.load_score() < a.load_score() { b.clone() } else { a.clone() });
+                }
+            }
+        }
+        PeakEwmaBalancer.pick(backends, usable, hash)
+    }
+
+    fn name(&self) -> &'static str {
+        "peak_ewma_p2c"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::BackendId;
+    use std::sync::Arc;
+
+    fn pool(n: u32) -> Vec<SharedBackend> {
+        (1..=n)
+            .map(|i| {
+                let backend = Backend::new(BackendId(i), ([127, 0, 0, 1], 8000 + i as u16).into());
+                backend.ewma.restore(f64::from(i) * 10.0);
+                Arc::new(backend)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_picks_the_better_of_two_without_herding() {
+        let backends = pool(100);
+        let balancer = PowerOfTwoBalancer::new();
+        let picks: Vec<_> = (0..5000).map(|_| balancer.pick(&backends, &|_| true, None).unwrap().id.0).collect();
+        // The best backend wins every draw it's in (about 2%), not every request
+        let best = picks.iter().filter(|&&id| id == 1).count();
+        assert!((50..200).contains(&best), "best backend got {} of 5000", best);
+        // The worst one loses every draw
+        assert!(!picks.contains(&100));
+
+        let two = pool(2);
+        assert!((0..100).all(|_| balancer.pick(&two, &|_| true, None).unwrap().id == BackendId(1)));
+    }
+
+    #[test]
+    fn test_unusable_backends_are_never_picked() {
+        let backends = pool(10);
+        let balancer = PowerOfTwoBalancer::new();
+        let only = |b: &Backend| b.id == BackendId(7);
+        assert!((0..100).all(|_| balancer.pick(&backends, &only, None).unwrap().id == BackendId(7)));
+        let some = |b: &Backend| b.id.0.is_multiple_of(2);
+        assert!((0..100).all(|_| balancer.pick(&backends, &some, None).unwrap().id.0.is_multiple_of(2)));
+        assert!(balancer.pick(&backends, &|_| false, None).is_none());
+        assert!(balancer.pick(&backends[..1], &|_| true, None).is_some());
+    }
+}
@@ -0,0 +1,92 @@
+//! Priority failover: standby groups of backends that only take traffic
+//! when the groups ahead of them can't.
+//!
+//! Every backend has a priority, 0 (the primary group) unless set. Traffic
+//! goes to the primary group while enough of it is healthy. Once the
+//! healthy share of the primary group drops below the threshold, the group
+//! with the next priority joins in, and so on. The healthy primaries keep
+//! their share of the traffic, so the standby only takes what they can't.
+//! If no group is healthy enough, every group takes traffic.
+
+use crate::domain::backend::{Backend, SharedBackend};
+
+/// Healthy percentage a group needs to carry its traffic without the next
+/// group, unless the pool sets its own.
+pub const DEFAULT_FAILOVER_PERCENT: u8 = 70;
+
+/// The lowest-priority group that takes traffic: every backend at this
+/// priority or a higher one (a smaller number) is in play. Only backends
+/// `eligible` accepts count. `u32::MAX`, so every group, if no group has
+/// `min_healthy_percent` of its backends healthy.
+pub fn active_priority(
+    backends: &[SharedBackend],
+    eligible: &dyn Fn(&Backend) -> bool,
+    min_healthy_percent: u8,
+) -> u32 {
+    let mut level = 0;
+    loop {
+        // Groups are few and usually just one, so a scan per group is cheapest
+        let (mut total, mut healthy, mut next) = (0u64, 0u64, None::<u32>);
+        for backend in backends.iter().filter(|b| eligible(b)) {
+            if backend.priority == level {
+                total += 1;
+                healthy += u64::from(backend.is_healthy());
+            } else if backend.priority > level {
+                next = Some(next.map_or(backend.priority, |n| n.min(backend.priority)));
+            }
+        }
+        if healthy > 0 && healthy * 100 >= total * u64::from(min_healthy_percent) {
+            return level;
+        }
+        match next {
+            Some(priority) => level = priority,
+            None => return u32::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::BackendId;
+    use std::sync::Arc;
+
+    fn pool(priorities: &[u32]) -> Vec<SharedBackend> {
+        priorities
+            .iter()
+            .enumerate()
+            .map(|(i, &priority)| {
+                let id = i as u32 + 1;
+                Arc::new(Backend::new(BackendId(id), ([10, 0, 0, id as u8], 80).into()).with_priority(priority))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_standby_groups_join_as_the_primary_group_fails() {
+        let backends = pool(&[0, 0, 0, 0, 1, 1, 5]);
+        assert_eq!(active_priority(&backends, &|_| true, 70), 0);
+        backends[0].set_healthy(false);
+        // Three of four is still 75%
+        assert_eq!(active_priority(&backends, &|_| true, 70), 0);
+        backends[1].set_healthy(false);
+        assert_eq!(active_priority(&backends, &|_| true, 70), 1);
+        backends[4].set_healthy(false);
+        // Groups are skipped over by priority, not counted
+        assert_eq!(active_priority(&backends, &|_| true, 70), 5);
+        backends[6].set_healthy(false);
+        assert_eq!(active_priority(&backends, &|_| true, 70), u32::MAX);
+        assert_eq!(active_priority(&backends, &|_| true, 0), 0);
+    }
+
+    #[test]
+    fn test_only_eligible_backends_make_up_a_group() {
+        let backends = pool(&[0, 0, 1]);
+        backends[0].set_healthy(false);
+        assert_eq!(active_priority(&backends, &|_| true, 70), 1);
+        assert_eq!(active_priority(&backends, &|b| b.id != BackendId(1), 70), 0);
+        // A subset with no primaries starts at its own first group
+        assert_eq!(active_priority(&backends, &|b| b.id == BackendId(3), 70), 1);
+        assert_eq!(active_priority(&[], &|_| true, 70), u32::MAX);
+    }
+}
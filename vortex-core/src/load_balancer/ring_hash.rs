@@ -0,0 +1,171 @@
+//! Consistent hashing (ring hash) load balancing.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use super::balancer::Balancer;
+use super::hash::stable_hash;
+use crate::domain::backend::{Backend, BackendId, SharedBackend};
+
+/// Points each unit of weight puts on the ring by default.
+pub const DEFAULT_POINTS_PER_WEIGHT: u32 = 160;
+
+/// Most points on a ring; pools with large weights get fewer per unit.
+pub const MAX_RING_POINTS: u64 = 1 << 20;
+
+/// Maps each request hash to the backend owning the next point clockwise on
+/// a hash ring.
+///
+/// Every backend puts `points_per_weight` points per unit of weight on the
+/// ring, at positions derived from its id alone, so adding or removing a
+/// backend only moves the keys landing next to its own points (about `1/n`
+/// of them) and leaves the rest where they were. A key whose backend isn't
+/// usable goes on clockwise to the next usable one. Requests without a hash
+/// are spread over the ring.
+#[derive(Debug)]
+pub struct RingHashBalancer {
+    points_per_weight: u32,
+    ring: RwLock<Arc<Ring>>,
+    unkeyed: AtomicU64,
+}
+
+/// The ring of one backend list.
+#[derive(Debug, Default)]
+struct Ring {
+    /// The ids and weights the ring was built from, in pool order
+    members: Vec<(BackendId, u32)>,
+    /// Points by position, each with the index of its backend in the pool
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn build(backends: &[SharedBackend], points_per_weight: u32) -> Self {
+        let members: Vec<_> = backends.iter().map(|b| (b.id, b.weight())).collect();
+        let total_weight: u64 = members.iter().map(|&(_, weight)| u64::from(weight)).sum();
+        let points_per_weight = u64::from(points_per_weight).min(MAX_RING_POINTS / total_weight.max(1)).max(1);
+        let mut points: Vec<(u64, usize)> = members
+            .iter()
+            .enumerate()
+            .flat_map(|(index, &(id, weight))| {
+                (0..u64::from(weight) * points_per_weight)
+                    .map(move |point| (stable_hash(&[&id.0.to_be_bytes(), &point.to_be_bytes()]), index))
+            })
+            .collect();
+        points.sort_unstable();
+        Self { members, points }
+    }
+
+    fn is_for(&self, backends: &[SharedBackend]) -> bool {
+        self.members.len() == backends.len()
+            && self.members.iter().zip(backends).all(|(&(id, weight), b)| id == b.id && weight == b.weight())
+    }
+}
+
+impl RingHashBalancer {
+    /// A balancer putting `points_per_weight` points per unit of weight on
+    /// the ring. More points spread keys more evenly at the cost of memory.
+    pub fn new(points_per_weight: u32) -> Self {
+        Self {
+            points_per_weight: points_per_weight.max(1),
+            ring: RwLock::default(),
+            unkeyed: AtomicU64::new(0),
+        }
+    }
+
+    /// The ring for `backends`, rebuilt if the pool changed since the last pick.
+    fn ring(&self, backends: &[SharedBackend]) -> Arc<Ring> {
+        let ring = self.ring.read().expect("ring lock poisoned").clone();
+        if ring.is_for(backends) {
+            return ring;
+        }
+        let ring = Arc::new(Ring::build(backends, self.points_per_weight));
+        *self.ring.write().expect("ring lock poisoned") = ring.clone();
+        ring
+    }
+}
+
+impl Default for RingHashBalancer {
+    fn default() -> Self {
+        Self::new(DEFAULT_POINTS_PER_WEIGHT)
+    }
+}
+
+impl Balancer for RingHashBalancer {
+    fn pick(
+        &self,
+        backends: &[SharedBackend],
+        usable: &dyn Fn(&Backend) -> bool,
+        hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        let ring = self.ring(backends);
+        let hash = hash.unwrap_or_else(|| {
+            let n = self.unkeyed.fetch_add(1, Ordering::Relaxed);
+            stable_hash(&[&n.to_be_bytes()])
+        });
+        let start = ring.points.partition_point(|&(point, _)| point < hash);
+        let (wrapped, ahead) = ring.points.split_at(start);
+        ahead
+            .iter()
+            .chain(wrapped)
+            .map(|&(_, index)| &backends[index])
+            .find(|b| usable(b))
+            .cloned()
+    }
+
+    fn name(&self) -> &'static str {
+        "ring_hash"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(ids: impl IntoIterator<Item = u32>) -> Vec<SharedBackend> {
+        ids.into_iter()
+            .map(|id| Arc::new(Backend::new(BackendId(id), ([127, 0, 0, 1], 8000 + id as u16).into())))
+            .collect()
+    }
+
+    fn assign(balancer: &RingHashBalancer, backends: &[SharedBackend]) -> Vec<u32> {
+        (0..2000u64)
+            .map(|key| balancer.pick(backends, &|_| true, Some(stable_hash(&[&key.to_be_bytes()]))).unwrap().id.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_keys_stick_and_few_move_when_the_pool_changes() {
+        let balancer = RingHashBalancer::default();
+        let four = pool(1..=4);
+        let before = assign(&balancer, &four);
+        assert_eq!(before, assign(&balancer, &four));
+        for id in 1..=4 {
+            let share = before.iter().filter(|&&b| b == id).count();
+            assert!((350..650).contains(&share), "backend {} got {} of 2000", id, share);
+        }
+
+        // Adding a fifth backend only moves keys onto it
+        let after = assign(&balancer, &pool(1..=5));
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(a, b)| a != b).collect();
+        assert!(moved.iter().all(|(_, &to)| to == 5));
+        assert!((250..550).contains(&moved.len()), "{} of 2000 keys moved", moved.len());
+
+        // Removing one only moves the keys it had
+        let after = assign(&balancer, &pool([1, 2, 4]));
+        assert!(before.iter().zip(&after).all(|(&a, &b)| a == b || a == 3));
+    }
+
+    #[test]
+    fn test_unusable_owners_pass_keys_clockwise() {
+        let balancer = RingHashBalancer::default();
+        let backends = pool(1..=3);
+        for key in 0..100u64 {
+            let hash = Some(stable_hash(&[&key.to_be_bytes()]));
+            let owner = balancer.pick(&backends, &|_| true, hash).unwrap();
+            let next = balancer.pick(&backends, &|b| b.id != owner.id, hash).unwrap();
+            assert_ne!(next.id, owner.id);
+        }
+        assert!(balancer.pick(&backends, &|_| false, Some(1)).is_none());
+        assert!(balancer.pick(&backends, &|_| true, None).is_some());
+    }
+}
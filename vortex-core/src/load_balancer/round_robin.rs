@@ -0,0 +1,65 @@
+//! Round-robin load balancing.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::balancer::Balancer;
+use crate::domain::backend::{Backend, SharedBackend};
+
+/// Hands requests to the usable backends in turn.
+///
+/// A single atomic counter indexes into the usable backends of the current
+/// snapshot, so every one gets the same share no matter how fast it is,
+/// and a backend leaving or rejoining only shifts the rotation.
+#[derive(Debug, Default)]
+pub struct RoundRobinBalancer {
+    next: AtomicUsize,
+}
+
+impl RoundRobinBalancer {
+    /// A balancer starting at the first backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Balancer for RoundRobinBalancer {
+    fn pick(
+        &self,
+        backends: &[SharedBackend],
+        usable: &dyn Fn(&Backend) -> bool,
+        _hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        let count = backends.iter().filter(|b| usable(b)).count();
+        if count == 0 {
+            return None;
+        }
+        let turn = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        backends.iter().filter(|b| usable(b)).nth(turn).cloned()
+    }
+
+    fn name(&self) -> &'static str {
+        "round_robin"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::BackendId;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rotates_over_usable_backends() {
+        let backends: Vec<SharedBackend> = (1..=3)
+            .map(|i| Arc::new(Backend::new(BackendId(i), ([127, 0, 0, 1], 8000 + i as u16).into())))
+            .collect();
+        let balancer = RoundRobinBalancer::new();
+        let picks = |usable: &dyn Fn(&Backend) -> bool| {
+            (0..4).map(|_| balancer.pick(&backends, usable, None).unwrap().id.0).collect::<Vec<_>>()
+        };
+        assert_eq!(picks(&|_| true), [1, 2, 3, 1]);
+        // Backend 2 is skipped without giving backend 3 a double share
+        assert_eq!(picks(&|b| b.id != BackendId(2)), [1, 3, 1, 3]);
+        assert!(balancer.pick(&backends, &|_| false, None).is_none());
+    }
+}
@@ -1,21 +1,141 @@
 //! Load Balancing Selector logic
+//!
+//! These functions narrow the pool down to the backends a request may use;
+//! the pool's `Balancer` picks among them.
 
-use std::sync::Arc;
-use crate::domain::backend::{Backend, SharedBackend};
+use crate::domain::backend::{Backend, BackendId, SharedBackend};
 use crate::domain::routing::SharedRoutingTable;
-use crate::load_balancer::ewma::PeakEwma;
+use crate::load_balancer::priority::active_priority;
 
-/// Selects the optimal backend using the Peak EWMA algorithm.
+/// Selects a healthy backend with the pool's balancer.
 pub fn select_best_backend(routing_table: &SharedRoutingTable) -> Option<SharedBackend> {
+    select_best_backend_excluding(routing_table, &[])
+}
+
+/// Selects a healthy backend with the pool's balancer, skipping the
+/// `excluded` ones (e.g. backends a retry has already tried).
+pub fn select_best_backend_excluding(
+    routing_table: &SharedRoutingTable,
+    excluded: &[BackendId],
+) -> Option<SharedBackend> {
+    select_best_backend_where(routing_table, excluded, None, |_| true)
+}
+
+/// Selects a healthy backend with the pool's balancer among those
+/// `eligible` accepts, skipping the `excluded` ones. `hash` is the request's
+/// hash for hash-based balancers, if it has one.
+///
+/// Only the failover groups currently taking traffic are considered. A pool
+/// with zone affinity picks among their local backends while they can take
+/// the traffic, and among all of them otherwise.
+pub fn select_best_backend_where(
+    routing_table: &SharedRoutingTable,
+    excluded: &[BackendId],
+    hash: Option<u64>,
+    eligible: impl Fn(&Backend) -> bool,
+) -> Option<SharedBackend> {
     let backends = routing_table.snapshot();
+    let priority = active_priority(&backends, &eligible, routing_table.failover_percent());
+    let eligible = |b: &Backend| b.priority <= priority && eligible(b);
+    let usable = |b: &Backend| b.is_healthy() && !excluded.contains(&b.id) && eligible(b);
+    if let Some(affinity) = routing_table.zone_affinity() {
+        // Judged on the eligible backends, so a subset is kept local on its own terms, and
+        // without the exclusions, so a retry stays local too
+        if affinity.keeps_local(&backends, &eligible, &|b| b.is_healthy()) {
+            let local = routing_table.balancer().pick(&backends, &|b| usable(b) && affinity.is_local(b), hash);
+            if local.is_some() {
+                return local;
+            }
+        }
+    }
+    routing_table.balancer().pick(&backends, &usable, hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::Backend;
+    use crate::domain::routing::RoutingTable;
+    use crate::load_balancer::zone::ZoneAffinity;
+    use proptest::prelude::*;
+    use std::sync::Arc;
+
+    proptest! {
+        #[test]
+        fn prop_selection_picks_a_lowest_score(
+            latencies in prop::collection::vec(prop::collection::vec(prop::num::f64::ANY, 0..5), 1..8),
+        ) {
+            let backends: Vec<SharedBackend> = latencies
+                .iter()
+                .enumerate()
+                .map(|(i, samples)| {
+                    let backend = Backend::new(BackendId(i as u32), ([127, 0, 0, 1], 8000 + i as u16).into());
+                    for &sample in samples {
+                        backend.ewma.observe_latency(sample);
+                    }
+                    Arc::new(backend)
+                })
+                .collect();
+            let routing_table = Arc::new(RoutingTable::new(backends.clone()));
+
+            let best = select_best_backend(&routing_table).unwrap();
+            let best_score = best.ewma.calculate_score();
+            for backend in &backends {
+                prop_assert!(best_score <= backend.ewma.calculate_score());
+            }
+        }
+    }
+
+    #[test]
+    fn test_weight_scales_the_score() {
+        let light = Backend::new(BackendId(1), ([127, 0, 0, 1], 8001).into());
+        let heavy = Backend::new(BackendId(2), ([127, 0, 0, 1], 8002).into()).with_weight(3);
+        light.ewma.restore(10.0);
+        heavy.ewma.restore(20.0);
+        let routing_table = Arc::new(RoutingTable::new(vec![Arc::new(light), Arc::new(heavy)]));
+        assert_eq!(select_best_backend(&routing_table).unwrap().id, BackendId(2));
+    }
+
+    #[test]
+    fn test_zone_affinity_keeps_traffic_local_until_it_spills_over() {
+        let backends: Vec<SharedBackend> = [(1, "a"), (2, "a"), (3, "b")]
+            .into_iter()
+            .map(|(id, zone)| Arc::new(Backend::new(BackendId(id), ([127, 0, 0, 1], 8000 + id as u16).into()).with_zone(zone)))
+            .collect();
+        // The remote backend looks best, but stays unused while the local zone is fine
+        backends[2].ewma.restore(1.0);
+        let routing_table = Arc::new(RoutingTable::new(backends.clone()).with_zone_affinity(ZoneAffinity::new("a")));
+        assert!((0..10).all(|_| select_best_backend(&routing_table).unwrap().zone() == Some("a")));
+        // Retries don't shrink the local zone
+        assert_eq!(select_best_backend_excluding(&routing_table, &[BackendId(1)]).unwrap().id, BackendId(2));
+
+        backends[0].set_healthy(false);
+        assert_eq!(select_best_backend(&routing_table).unwrap().id, BackendId(3));
+        // A subset of the local zone is judged on its own
+        let only_second = |b: &Backend| b.id != BackendId(1);
+        assert_eq!(select_best_backend_where(&routing_table, &[], None, only_second).unwrap().id, BackendId(2));
+    }
+
+    #[test]
+    fn test_standby_group_takes_traffic_only_on_failover() {
+        let backends: Vec<SharedBackend> = [(1, 0), (2, 0), (3, 1)]
+            .into_iter()
+            .map(|(id, priority)| {
+                let backend = Backend::new(BackendId(id), ([127, 0, 0, 1], 8000 + id as u16).into());
+                Arc::new(backend.with_priority(priority))
+            })
+            .collect();
+        // The standby looks best, but only the primaries take traffic while they're healthy
+        backends[2].ewma.restore(1.0);
+        let routing_table = Arc::new(RoutingTable::new(backends.clone()));
+        assert!((0..10).all(|_| select_best_backend(&routing_table).unwrap().priority == 0));
+        // A retry excluding a healthy primary doesn't fail over
+        assert_eq!(select_best_backend_excluding(&routing_table, &[BackendId(1)]).unwrap().id, BackendId(2));
 
-    backends
-        .iter()
-        .filter(|b| b.is_healthy())
-        .min_by(|a, b| {
-            let score_a = a.ewma.calculate_score();
-            let score_b = b.ewma.calculate_score();
-            score_a.partial_cmp(&score_b).unwrap_or(std::cmp::Ordering::Equal)
-        })
-        .cloned()
+        backends[0].set_healthy(false);
+        assert_eq!(select_best_backend(&routing_table).unwrap().id, BackendId(3));
+        // Half healthy is enough for a lenient pool
+        let lenient = Arc::new(RoutingTable::new(backends.clone()).with_failover_percent(50));
+        assert_eq!(select_best_backend(&lenient).unwrap().id, BackendId(2));
+    }
 }
@@ -0,0 +1,116 @@
+//! Slow start: ramping a backend up to its full share of traffic.
+//!
+//! A backend that just joined the pool or came back from being unhealthy
+//! has cold caches and no pooled connections, yet its Peak EWMA score is
+//! the lowest around: it has no requests in flight, and either the baseline
+//! or a stale latency. Without slow start it gets a flood of traffic at
+//! once. With it, the backend's score is divided by a share that grows
+//! linearly from `MIN_SHARE` to 1 over the window, so it gets a small but
+//! growing slice of the traffic meanwhile.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+/// The share a backend starts its ramp at, so it still sees some traffic.
+pub const MIN_SHARE: f64 = 0.1;
+
+/// Ramp start times are kept as nanoseconds since this instant.
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Nanoseconds since `EPOCH`, plus one so that 0 can mean "not ramping".
+fn stamp(now: Instant) -> u64 {
+    now.saturating_duration_since(*EPOCH).as_nanos() as u64 + 1
+}
+
+/// A backend's slow-start window and the ramp in progress, if any.
+#[derive(Debug, Default)]
+pub struct SlowStart {
+    /// The window in nanoseconds; 0 turns slow start off
+    window: AtomicU64,
+    /// When the ramp began, as a `stamp`; 0 if not ramping
+    started: AtomicU64,
+}
+
+impl SlowStart {
+    /// Slow start that is off until a window is set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long a ramp lasts; zero if slow start is off.
+    pub fn window(&self) -> Duration {
+        Duration::from_nanos(self.window.load(Ordering::Relaxed))
+    }
+
+    /// Changes how long ramps last, including one in progress.
+    pub fn set_window(&self, window: Duration) {
+        self.window.store(window.as_nanos().min(u128::from(u64::MAX)) as u64, Ordering::Relaxed);
+    }
+
+    /// Starts a ramp from `MIN_SHARE`, unless slow start is off.
+    pub fn begin(&self) {
+        if !self.window().is_zero() {
+            self.started.store(stamp(Instant::now()), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether a ramp is in progress.
+    pub fn is_ramping(&self) -> bool {
+        self.share() < 1.0
+    }
+
+    /// The fraction of its full share of traffic the backend should get now.
+    pub fn share(&self) -> f64 {
+        self.share_at(Instant::now())
+    }
+
+    fn share_at(&self, now: Instant) -> f64 {
+        let started = self.started.load(Ordering::Relaxed);
+        let window = self.window.load(Ordering::Relaxed);
+        if started == 0 || window == 0 {
+            return 1.0;
+        }
+        let elapsed = stamp(now).saturating_sub(started);
+        if elapsed >= window {
+            // Done; later calls skip the arithmetic
+            let _ = self.started.compare_exchange(started, 0, Ordering::Relaxed, Ordering::Relaxed);
+            return 1.0;
+        }
+        MIN_SHARE + (1.0 - MIN_SHARE) * (elapsed as f64 / window as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_ramps_linearly_over_the_window() {
+        let slow_start = SlowStart::new();
+        // Off without a window
+        slow_start.begin();
+        assert_eq!(slow_start.share(), 1.0);
+
+        slow_start.set_window(Duration::from_secs(10));
+        assert_eq!(slow_start.share(), 1.0);
+        let start = Instant::now();
+        slow_start.begin();
+        assert!(slow_start.is_ramping());
+        assert!((slow_start.share_at(start) - MIN_SHARE).abs() < 0.01);
+        let halfway = slow_start.share_at(start + Duration::from_secs(5));
+        assert!((halfway - 0.55).abs() < 0.01, "{}", halfway);
+        assert_eq!(slow_start.share_at(start + Duration::from_secs(11)), 1.0);
+        assert!(!slow_start.is_ramping());
+    }
+
+    #[test]
+    fn test_window_changes_apply_to_the_ramp_in_progress() {
+        let slow_start = SlowStart::new();
+        slow_start.set_window(Duration::from_secs(60));
+        slow_start.begin();
+        assert!(slow_start.is_ramping());
+        slow_start.set_window(Duration::ZERO);
+        assert_eq!(slow_start.share(), 1.0);
+    }
+}
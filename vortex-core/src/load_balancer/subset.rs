@@ -0,0 +1,111 @@
+//! Subset load balancing: restricting a route to backends with given metadata.
+//!
+//! A route requiring `{version: v2}` only balances across backends whose
+//! metadata has `version=v2`. When none of them is healthy (or none exists),
+//! the subset's fallback policy decides what happens to the request.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::selector::select_best_backend_where;
+use crate::domain::backend::{BackendId, SharedBackend};
+use crate::domain::routing::SharedRoutingTable;
+
+/// What to do when no healthy backend is in the subset.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubsetFallback {
+    /// Fail the request as if the pool had no healthy backend
+    #[default]
+    NoFallback,
+    /// Balance across the whole pool
+    AnyBackend,
+    /// Balance across the backends matching this other selector
+    DefaultSubset(HashMap<String, String>),
+}
+
+/// The backends a route may use.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subset {
+    /// Metadata a backend must carry, with these exact values
+    pub selector: HashMap<String, String>,
+    /// Policy when no healthy backend matches `selector`
+    pub fallback: SubsetFallback,
+}
+
+impl Subset {
+    /// A subset of the backends matching `selector`, without fallback.
+    pub fn new(selector: HashMap<String, String>) -> Self {
+        Self {
+            selector,
+            fallback: SubsetFallback::default(),
+        }
+    }
+
+    /// Set the fallback policy, builder style.
+    pub fn with_fallback(mut self, fallback: SubsetFallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Selects a backend of the subset with the pool's balancer, skipping
+    /// the `excluded` ones, or one allowed by the fallback policy. `hash` is
+    /// the request's hash for hash-based balancers, if it has one.
+    pub fn select(
+        &self,
+        routing_table: &SharedRoutingTable,
+        excluded: &[BackendId],
+        hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        select_best_backend_where(routing_table, excluded, hash, |b| b.matches_metadata(&self.selector)).or_else(|| {
+            match &self.fallback {
+                SubsetFallback::NoFallback => None,
+                SubsetFallback::AnyBackend => select_best_backend_where(routing_table, excluded, hash, |_| true),
+                SubsetFallback::DefaultSubset(selector) => {
+                    select_best_backend_where(routing_table, excluded, hash, |b| b.matches_metadata(selector))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::Backend;
+    use crate::domain::routing::RoutingTable;
+    use std::sync::Arc;
+
+    fn selector(version: &str) -> HashMap<String, String> {
+        [("version".to_string(), version.to_string())].into()
+    }
+
+    #[test]
+    fn test_select_stays_in_the_subset_until_it_is_empty() {
+        let backends: Vec<SharedBackend> = ["v1", "v2", "v2"]
+            .iter()
+            .enumerate()
+            .map(|(i, version)| {
+                let addr = ([127, 0, 0, 1], 8000 + i as u16).into();
+                Arc::new(Backend::new(BackendId(i as u32), addr).with_metadata("version", *version))
+            })
+            .collect();
+        let routing_table = Arc::new(RoutingTable::new(backends.clone()));
+
+        let v2 = Subset::new(selector("v2"));
+        for _ in 0..5 {
+            assert_eq!(v2.select(&routing_table, &[], None).unwrap().metadata["version"], "v2");
+        }
+        assert_eq!(v2.select(&routing_table, &[BackendId(1)], None).unwrap().id, BackendId(2));
+
+        backends[1].set_healthy(false);
+        backends[2].set_healthy(false);
+        assert!(v2.select(&routing_table, &[], None).is_none());
+        let v2 = v2.with_fallback(SubsetFallback::AnyBackend);
+        assert_eq!(v2.select(&routing_table, &[], None).unwrap().id, BackendId(0));
+        let v3 = Subset::new(selector("v3")).with_fallback(SubsetFallback::DefaultSubset(selector("v2")));
+        assert!(v3.select(&routing_table, &[], None).is_none());
+        backends[2].set_healthy(true);
+        assert_eq!(v3.select(&routing_table, &[], None).unwrap().id, BackendId(2));
+    }
+}
@@ -0,0 +1,102 @@
+//! Smooth weighted round-robin load balancing.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::balancer::Balancer;
+use crate::domain::backend::{Backend, BackendId, SharedBackend};
+
+/// Hands requests to the usable backends in proportion to their weights,
+/// interleaving them as evenly as possible (nginx's smooth weighted
+/// round-robin).
+///
+/// On every pick each usable backend's running score grows by its weight,
+/// the highest score wins, and the winner's score drops by the total weight.
+/// Weights 5, 1, 1 thus give `a a b a c a a` rather than five `a`s in a
+/// row. Backends that aren't usable keep their score until they are again.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobinBalancer {
+    scores: Mutex<HashMap<BackendId, i64>>,
+}
+
+impl WeightedRoundRobinBalancer {
+    /// A balancer with every score at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Balancer for WeightedRoundRobinBalancer {
+    fn pick(
+        &self,
+        backends: &[SharedBackend],
+        usable: &dyn Fn(&Backend) -> bool,
+        _hash: Option<u64>,
+    ) -> Option<SharedBackend> {
+        let mut scores = self.scores.lock().expect("balancer lock poisoned");
+        // Backends that left the pool don't linger
+        if scores.len() > backends.len() {
+            scores.retain(|id, _| backends.iter().any(|b| b.id == *id));
+        }
+
+        let mut total = 0;
+        let mut best: Option<(&SharedBackend, i64)> = None;
+        for backend in backends.iter().filter(|b| usable(b)) {
+            let weight = i64::from(backend.weight());
+            total += weight;
+            let score = scores.entry(backend.id).or_insert(0);
+            *score += weight;
+            if best.is_none_or(|(_, top)| *score > top) {
+                best = Some((backend, *score));
+            }
+        }
+        let (backend, _) = best?;
+        *scores.get_mut(&backend.id).expect("scored above") -= total;
+        Some(backend.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "weighted_round_robin"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn pool(weights: &[u32]) -> Vec<SharedBackend> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| {
+                let id = i as u32 + 1;
+                Arc::new(Backend::new(BackendId(id), ([127, 0, 0, 1], 8000 + id as u16).into()).with_weight(weight))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_picks_are_proportional_and_interleaved() {
+        let backends = pool(&[5, 1, 1]);
+        let balancer = WeightedRoundRobinBalancer::new();
+        let picks: Vec<_> = (0..7).map(|_| balancer.pick(&backends, &|_| true, None).unwrap().id.0).collect();
+        assert_eq!(picks, [1, 1, 2, 1, 3, 1, 1]);
+
+        // A 16-core and a 4-core machine split traffic 4:1
+        let backends = pool(&[16, 4]);
+        let balancer = WeightedRoundRobinBalancer::new();
+        let big = (0..1000).filter(|_| balancer.pick(&backends, &|_| true, None).unwrap().id == BackendId(1)).count();
+        assert_eq!(big, 800);
+    }
+
+    #[test]
+    fn test_unusable_backends_are_skipped() {
+        let backends = pool(&[2, 1]);
+        let balancer = WeightedRoundRobinBalancer::new();
+        let usable = |b: &Backend| b.id != BackendId(1);
+        let picks: Vec<_> = (0..3).map(|_| balancer.pick(&backends, &usable, None).unwrap().id.0).collect();
+        assert_eq!(picks, [2, 2, 2]);
+        assert!(balancer.pick(&backends, &|_| false, None).is_none());
+    }
+}
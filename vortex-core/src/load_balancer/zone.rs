@@ -0,0 +1,110 @@
+//! Zone-aware load balancing: keeping traffic in the proxy's own zone.
+//!
+//! Traffic between availability zones costs money and latency, so a pool
+//! with zone affinity balances only across the backends whose `zone`
+//! metadata matches the proxy's, as long as the local zone can take the
+//! load. It spills over to the whole pool when too few local backends are
+//! healthy, or when the healthy ones are overloaded. Backends without a
+//! zone count as remote.
+
+use crate::domain::backend::{Backend, SharedBackend};
+
+/// The metadata key naming a backend's zone.
+pub const ZONE_KEY: &str = "zone";
+
+/// Prefers backends in the local zone while it has the capacity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneAffinity {
+    /// The zone the proxy runs in
+    pub local_zone: String,
+    /// Percentage of local backends that must be usable to keep traffic local
+    pub min_healthy_percent: u8,
+    /// Average in-flight requests per usable local backend above which
+    /// traffic spills over; no limit if unset
+    pub max_active_requests: Option<u64>,
+}
+
+impl ZoneAffinity {
+    /// Affinity to `local_zone`, spilling over once fewer than 70% of its
+    /// backends are usable.
+    pub fn new(local_zone: impl Into<String>) -> Self {
+        Self {
+            local_zone: local_zone.into(),
+            min_healthy_percent: 70,
+            max_active_requests: None,
+        }
+    }
+
+    /// Whether `backend` is in the local zone.
+    pub fn is_local(&self, backend: &Backend) -> bool {
+        backend.zone() == Some(self.local_zone.as_str())
+    }
+
+    /// Whether the local backends among the `eligible` ones can take the
+    /// traffic: enough of them are usable, and those aren't overloaded.
+    pub fn keeps_local(
+        &self,
+        backends: &[SharedBackend],
+        eligible: &dyn Fn(&Backend) -> bool,
+        usable: &dyn Fn(&Backend) -> bool,
+    ) -> bool {
+        let (mut local, mut healthy, mut active) = (0u64, 0u64, 0u64);
+        for backend in backends.iter().filter(|b| self.is_local(b) && eligible(b)) {
+            local += 1;
+            if usable(backend) {
+                healthy += 1;
+                active += backend.ewma.active_requests();
+            }
+        }
+        if healthy == 0 || healthy * 100 < local * u64::from(self.min_healthy_percent) {
+            return false;
+        }
+        self.max_active_requests.is_none_or(|max| active <= max * healthy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::backend::BackendId;
+    use std::sync::Arc;
+
+    fn pool(zones: &[&str]) -> Vec<SharedBackend> {
+        zones
+            .iter()
+            .enumerate()
+            .map(|(i, zone)| {
+                let id = i as u32 + 1;
+                Arc::new(Backend::new(BackendId(id), ([10, 0, 0, id as u8], 80).into()).with_zone(*zone))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spills_over_when_the_local_zone_is_unhealthy() {
+        let backends = pool(&["a", "a", "a", "b"]);
+        let affinity = ZoneAffinity::new("a");
+        assert!(affinity.is_local(&backends[0]) && !affinity.is_local(&backends[3]));
+        assert!(affinity.keeps_local(&backends, &|_| true, &|_| true));
+        // Two of three is under 70%
+        assert!(!affinity.keeps_local(&backends, &|_| true, &|b| b.id != BackendId(1)));
+        assert!(!ZoneAffinity::new("c").keeps_local(&backends, &|_| true, &|_| true));
+
+        let lenient = ZoneAffinity { min_healthy_percent: 50, ..affinity };
+        assert!(lenient.keeps_local(&backends, &|_| true, &|b| b.id != BackendId(1)));
+    }
+
+    #[test]
+    fn test_spills_over_when_the_local_zone_is_overloaded() {
+        let backends = pool(&["a", "a", "b"]);
+        let affinity = ZoneAffinity { max_active_requests: Some(2), ..ZoneAffinity::new("a") };
+        let _busy: Vec<_> = (0..4).map(|_| backends[0].ewma.increment_active()).collect();
+        // Four across two local backends is at the limit
+        assert!(affinity.keeps_local(&backends, &|_| true, &|_| true));
+        let _more = backends[1].ewma.increment_active();
+        assert!(!affinity.keeps_local(&backends, &|_| true, &|_| true));
+        // Remote load doesn't matter
+        let remote = ZoneAffinity { max_active_requests: Some(2), ..ZoneAffinity::new("b") };
+        assert!(remote.keeps_local(&backends, &|_| true, &|_| true));
+    }
+}
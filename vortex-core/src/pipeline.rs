@@ -0,0 +1,152 @@
+//! Composable request pipeline built from tower layers.
+//!
+//! The proxy's request path is a fixed sequence of stages, outermost first:
+//! route → filters → limits → retry → pool → upstream. Each stage holds zero or
+//! more tower layers; the data plane installs its own and third parties can
+//! insert additional layers at any stage without touching the proxy code.
+//!
+//! The builder is generic over the request, response, and error types so it has
+//! no dependency on a particular HTTP stack.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use tower::util::BoxCloneService;
+use tower::{Layer, Service};
+
+/// Ordered stages of the request pipeline, outermost first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    /// Route matching and backend selection
+    Route,
+    /// L7 filters (Wasm plugins, fault injection, transformations)
+    Filters,
+    /// Admission control (rate limits, concurrency caps, size limits)
+    Limits,
+    /// Retries and hedging
+    Retry,
+    /// Connection acquisition, right before the upstream exchange
+    Pool,
+}
+
+/// A type-erased, cloneable service produced by the pipeline.
+pub type BoxService<Req, Res, E> = BoxCloneService<Req, Res, E>;
+
+type BoxLayerFn<Req, Res, E> = Box<dyn FnOnce(BoxService<Req, Res, E>) -> BoxService<Req, Res, E> + Send>;
+
+/// Collects layers per stage and assembles them around an upstream service.
+pub struct PipelineBuilder<Req, Res, E> {
+    stages: BTreeMap<Stage, Vec<BoxLayerFn<Req, Res, E>>>,
+}
+
+impl<Req, Res, E> Default for PipelineBuilder<Req, Res, E> {
+    fn default() -> Self {
+        Self {
+            stages: BTreeMap::new(),
+        }
+    }
+}
+
+impl<Req, Res, E> fmt::Debug for PipelineBuilder<Req, Res, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.stages.iter().map(|(stage, layers)| (stage, layers.len())))
+            .finish()
+    }
+}
+
+impl<Req, Res, E> PipelineBuilder<Req, Res, E>
+where
+    Req: 'static,
+    Res: 'static,
+    E: 'static,
+{
+    /// Create an empty pipeline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a layer to a stage.
+    ///
+    /// Within a stage, layers run in insertion order: the first layer added
+    /// sees the request first.
+    pub fn layer<L>(mut self, stage: Stage, layer: L) -> Self
+    where
+        L: Layer<BoxService<Req, Res, E>> + Send + 'static,
+        L::Service: Service<Req, Response = Res, Error = E> + Clone + Send + 'static,
+        <L::Service as Service<Req>>::Future: Send + 'static,
+    {
+        self.stages
+            .entry(stage)
+            .or_default()
+            .push(Box::new(move |inner| BoxCloneService::new(layer.layer(inner))));
+        self
+    }
+
+    /// Number of layers registered at a stage.
+    pub fn stage_len(&self, stage: Stage) -> usize {
+        self.stages.get(&stage).map_or(0, Vec::len)
+    }
+
+    /// Wrap the upstream service in every registered layer.
+    pub fn build<S>(self, upstream: S) -> BoxService<Req, Res, E>
+    where
+        S: Service<Req, Response = Res, Error = E> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+    {
+        let mut service = BoxCloneService::new(upstream);
+
+        // Wrap innermost-first so the `Route` stage ends up outermost.
+        for layers in self.stages.into_values().rev() {
+            for layer in layers.into_iter().rev() {
+                service = layer(service);
+            }
+        }
+        service
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::layer::layer_fn;
+    use tower::{service_fn, ServiceExt};
+
+    type Trace = Vec<&'static str>;
+
+    fn tag(name: &'static str) -> impl Layer<BoxService<Trace, Trace, Infallible>, Service = BoxService<Trace, Trace, Infallible>> {
+        layer_fn(move |inner: BoxService<Trace, Trace, Infallible>| {
+            BoxCloneService::new(inner.map_request(move |mut trace: Trace| {
+                trace.push(name);
+                trace
+            }))
+        })
+    }
+
+    #[tokio::test]
+    async fn test_stages_run_outermost_first() {
+        let upstream = service_fn(|mut trace: Trace| async move {
+            trace.push("upstream");
+            Ok::<_, Infallible>(trace)
+        });
+
+        // Registered out of order on purpose: stage order must win.
+        let service = PipelineBuilder::new()
+            .layer(Stage::Pool, tag("pool"))
+            .layer(Stage::Route, tag("route"))
+            .layer(Stage::Filters, tag("filter-a"))
+            .layer(Stage::Filters, tag("filter-b"))
+            .build(upstream);
+
+        let trace = service.oneshot(Vec::new()).await.unwrap();
+        assert_eq!(trace, vec!["route", "filter-a", "filter-b", "pool", "upstream"]);
+    }
+
+    #[test]
+    fn test_stage_len_counts_layers() {
+        let builder = PipelineBuilder::<Trace, Trace, Infallible>::new()
+            .layer(Stage::Limits, tag("limit"));
+        assert_eq!(builder.stage_len(Stage::Limits), 1);
+        assert_eq!(builder.stage_len(Stage::Retry), 0);
+    }
+}
@@ -0,0 +1,705 @@
+//! A compiled subset of CEL (Common Expression Language) for route
+//! predicates and policy conditions.
+//!
+//! Expressions are parsed and checked once, up front: a route's
+//! `expression` when the config file is loaded, so a typo in a field name or
+//! a malformed CIDR fails the load instead of silently never matching.
+//! Supported:
+//!
+//! - `request.method`, `request.path`, `request.host`, `request.headers['name']`,
+//!   `'name' in request.headers`, and `source.ip`
+//! - string, integer, boolean, `null`, and list literals
+//! - `!`, `&&`, `||`, `==`, `!=`, `<`, `<=`, `>`, `>=`, `in`, and unary `-`
+//! - `s.startsWith(x)`, `s.endsWith(x)`, `s.contains(x)`, `size(x)`, and
+//!   `cidr('10.0.0.0/8')` (for `source.ip in cidr(...)`)
+//!
+//! As in CEL, reading a header the request doesn't have is an error rather
+//! than an empty string; an expression that errors doesn't match. Expressions
+//! nested more than 64 levels deep are rejected.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use super::RequestView;
+
+/// Deepest an expression may nest, so that parsing, checking and evaluating
+/// it can't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// An expression that failed to compile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CelError {
+    /// Byte offset into the expression where the problem was found
+    pub offset: usize,
+    /// What was wrong
+    pub message: String,
+}
+
+impl fmt::Display for CelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid expression at offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for CelError {}
+
+/// A compiled CEL expression.
+#[derive(Debug, Clone)]
+pub struct CelExpression {
+    source: String,
+    root: Expr,
+}
+
+impl CelExpression {
+    /// Parse and check `source`.
+    pub fn compile(source: &str) -> Result<Self, CelError> {
+        let tokens = lex(source)?;
+        let mut parser = Parser { tokens, pos: 0, end: source.len(), depth: 0 };
+        let root = parser.expr()?;
+        if let Some((offset, token)) = parser.tokens.get(parser.pos) {
+            return Err(error(*offset, format!("unexpected {}", token)));
+        }
+        root.check()?;
+        Ok(Self {
+            source: source.to_string(),
+            root,
+        })
+    }
+
+    /// The expression as written.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluate against `req`, describing the failure if evaluation errors
+    /// or the result isn't a boolean.
+    pub fn evaluate(&self, req: &dyn RequestView) -> Result<bool, String> {
+        match self.root.eval(req)? {
+            Value::Bool(b) => Ok(b),
+            other => Err(format!("expression evaluated to {}, not a boolean", other.type_name())),
+        }
+    }
+
+    /// Whether the expression holds for `req`. Errors count as no match.
+    pub fn matches(&self, req: &dyn RequestView) -> bool {
+        self.evaluate(req).unwrap_or(false)
+    }
+}
+
+impl PartialEq for CelExpression {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for CelExpression {}
+
+impl fmt::Display for CelExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn error(offset: usize, message: impl Into<String>) -> CelError {
+    CelError {
+        offset,
+        message: message.into(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Int(n) => write!(f, "number {}", n),
+            Token::Str(s) => write!(f, "string {:?}", s),
+            Token::Ident(name) => write!(f, "`{}`", name),
+            Token::Punct(p) => write!(f, "`{}`", p),
+        }
+    }
+}
+
+const PUNCTUATION: [&str; 16] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "-", "(", ")", "[", "]", ",", "."];
+
+fn lex(source: &str) -> Result<Vec<(usize, Token)>, CelError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            let n = source[start..i].parse().map_err(|_| error(start, "integer out of range"))?;
+            tokens.push((start, Token::Int(n)));
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push((start, Token::Ident(source[start..i].to_string())));
+        } else if c == b'\'' || c == b'"' {
+            let start = i;
+            let mut value = String::new();
+            let mut chars = source[i + 1..].char_indices();
+            loop {
+                let Some((at, ch)) = chars.next() else {
+                    return Err(error(start, "unterminated string"));
+                };
+                match ch {
+                    '\\' => match chars.next() {
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 't')) => value.push('\t'),
+                        Some((_, escaped @ ('\\' | '\'' | '"'))) => value.push(escaped),
+                        _ => return Err(error(i + 1 + at, "unsupported escape")),
+                    },
+                    ch if ch as u32 == u32::from(c) => {
+                        i += 1 + at + 1;
+                        break;
+                    }
+                    ch => value.push(ch),
+                }
+            }
+            tokens.push((start, Token::Str(value)));
+        } else {
+            let Some(p) = PUNCTUATION.iter().find(|p| source[i..].starts_with(**p)) else {
+                return Err(error(i, format!("unexpected character {:?}", source[i..].chars().next().unwrap_or('?'))));
+            };
+            tokens.push((i, Token::Punct(p)));
+            i += p.len();
+        }
+    }
+    Ok(tokens)
+}
+
+/// A request attribute an expression can read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Attr {
+    Method,
+    Path,
+    Host,
+    SourceIp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Func {
+    Size,
+    Cidr,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Lit(Value),
+    Attr(Attr),
+    /// `request.headers` on its own, only valid as an index or `in` target
+    Headers(usize),
+    Header(Box<Expr>),
+    HasHeader(Box<Expr>),
+    List(Vec<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cmp(CmpOp, Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+    /// How deep the expression being parsed is nested
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(o, _)| *o)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), CelError> {
+        if self.eat(punct) {
+            return Ok(());
+        }
+        let found = self.peek().map_or("end of expression".to_string(), Token::to_string);
+        Err(error(self.offset(), format!("expected `{}`, found {}", punct, found)))
+    }
+
+    /// Goes a level deeper into the expression, until `MAX_DEPTH`.
+    fn descend(&mut self) -> Result<(), CelError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(error(self.offset(), format!("expression is nested more than {} levels deep", MAX_DEPTH)));
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Expr, CelError> {
+        let depth = self.depth;
+        self.descend()?;
+        let mut lhs = self.and()?;
+        // Each operator in a chain nests the ones before it a level deeper
+        while self.eat("||") {
+            self.descend()?;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, CelError> {
+        let depth = self.depth;
+        let mut lhs = self.relation()?;
+        while self.eat("&&") {
+            self.descend()?;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.relation()?));
+        }
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    fn relation(&mut self) -> Result<Expr, CelError> {
+        let lhs = self.unary()?;
+        if self.peek() == Some(&Token::Ident("in".into())) {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            return Ok(match rhs {
+                Expr::Headers(_) => Expr::HasHeader(Box::new(lhs)),
+                rhs => Expr::In(Box::new(lhs), Box::new(rhs)),
+            });
+        }
+        let op = match self.peek() {
+            Some(Token::Punct("==")) => CmpOp::Eq,
+            Some(Token::Punct("!=")) => CmpOp::Ne,
+            Some(Token::Punct("<")) => CmpOp::Lt,
+            Some(Token::Punct("<=")) => CmpOp::Le,
+            Some(Token::Punct(">")) => CmpOp::Gt,
+            Some(Token::Punct(">=")) => CmpOp::Ge,
+            _ => return Ok(lhs),
+        };
+        self.pos += 1;
+        Ok(Expr::Cmp(op, Box::new(lhs), Box::new(self.unary()?)))
+    }
+
+    fn unary(&mut self) -> Result<Expr, CelError> {
+        let negation: fn(Box<Expr>) -> Expr = if self.eat("!") {
+            Expr::Not
+        } else if self.eat("-") {
+            Expr::Neg
+        } else {
+            return self.postfix();
+        };
+        self.descend()?;
+        let operand = self.unary()?;
+        self.depth -= 1;
+        Ok(negation(Box::new(operand)))
+    }
+
+    fn postfix(&mut self) -> Result<Expr, CelError> {
+        let depth = self.depth;
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat("[") {
+                let key = self.expr()?;
+                self.expect("]")?;
+                expr = match expr {
+                    Expr::Headers(_) => Expr::Header(Box::new(key)),
+                    _ => return Err(error(self.offset(), "only request.headers can be indexed")),
+                };
+            } else if self.eat(".") {
+                self.descend()?;
+                let offset = self.offset();
+                let func = match self.peek() {
+                    Some(Token::Ident(name)) if name == "startsWith" => Func::StartsWith,
+                    Some(Token::Ident(name)) if name == "endsWith" => Func::EndsWith,
+                    Some(Token::Ident(name)) if name == "contains" => Func::Contains,
+                    Some(Token::Ident(name)) if name == "size" => Func::Size,
+                    Some(token) => return Err(error(offset, format!("unknown method {}", token))),
+                    None => return Err(error(offset, "expected a method name")),
+                };
+                self.pos += 1;
+                let mut args = vec![expr];
+                args.extend(self.args()?);
+                expr = call(func, args, offset)?;
+            } else {
+                self.depth = depth;
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>, CelError> {
+        self.expect("(")?;
+        let mut args = Vec::new();
+        if self.eat(")") {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expr()?);
+            if self.eat(")") {
+                return Ok(args);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, CelError> {
+        let offset = self.offset();
+        let Some(token) = self.peek().cloned() else {
+            return Err(error(offset, "unexpected end of expression"));
+        };
+        self.pos += 1;
+        match token {
+            Token::Int(n) => Ok(Expr::Lit(Value::Int(n))),
+            Token::Str(s) => Ok(Expr::Lit(Value::Str(s))),
+            Token::Punct("(") => {
+                let inner = self.expr()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Punct("[") => {
+                let mut items = Vec::new();
+                if !self.eat("]") {
+                    loop {
+                        items.push(self.expr()?);
+                        if self.eat("]") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Expr::List(items))
+            }
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Expr::Lit(Value::Bool(true))),
+                "false" => Ok(Expr::Lit(Value::Bool(false))),
+                "null" => Ok(Expr::Lit(Value::Null)),
+                "size" => call(Func::Size, self.args()?, offset),
+                "cidr" => call(Func::Cidr, self.args()?, offset),
+                "request" | "source" => {
+                    self.expect(".")?;
+                    let field_offset = self.offset();
+                    let field = match self.peek() {
+                        Some(Token::Ident(field)) => format!("{}.{}", name, field),
+                        _ => return Err(error(field_offset, format!("expected a field of `{}`", name))),
+                    };
+                    self.pos += 1;
+                    match field.as_str() {
+                        "request.method" => Ok(Expr::Attr(Attr::Method)),
+                        "request.path" => Ok(Expr::Attr(Attr::Path)),
+                        "request.host" => Ok(Expr::Attr(Attr::Host)),
+                        "request.headers" => Ok(Expr::Headers(offset)),
+                        "source.ip" => Ok(Expr::Attr(Attr::SourceIp)),
+                        _ => Err(error(field_offset, format!("unknown field `{}`", field))),
+                    }
+                }
+                _ => Err(error(offset, format!("unknown identifier `{}`", name))),
+            },
+            token => Err(error(offset, format!("unexpected {}", token))),
+        }
+    }
+}
+
+/// Builds a call, checking its arity and folding literal CIDRs.
+fn call(func: Func, args: Vec<Expr>, offset: usize) -> Result<Expr, CelError> {
+    let arity = match func {
+        Func::Size | Func::Cidr => 1,
+        Func::StartsWith | Func::EndsWith | Func::Contains => 2,
+    };
+    if args.len() != arity {
+        return Err(error(offset, format!("{:?} takes {} argument(s)", func, arity)));
+    }
+    if func == Func::Cidr {
+        if let Expr::Lit(Value::Str(net)) = &args[0] {
+            return parse_cidr(net).map(Expr::Lit).map_err(|e| error(offset, e));
+        }
+    }
+    Ok(Expr::Call(func, args))
+}
+
+fn parse_cidr(net: &str) -> Result<Value, String> {
+    let invalid = || format!("invalid CIDR {:?}", net);
+    let (addr, prefix) = net.split_once('/').ok_or_else(invalid)?;
+    let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+    let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        return Err(invalid());
+    }
+    Ok(Value::Net(addr, prefix))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    List(Vec<Value>),
+    Net(IpAddr, u8),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Net(..) => "cidr",
+        }
+    }
+}
+
+fn net_contains(net: IpAddr, prefix: u8, addr: IpAddr) -> bool {
+    match (net, addr) {
+        (IpAddr::V4(net), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(net) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(net) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+impl Expr {
+    /// Rejects `request.headers` anywhere but an index or `in` target.
+    fn check(&self) -> Result<(), CelError> {
+        match self {
+            Expr::Headers(offset) => Err(error(*offset, "request.headers must be indexed or used with `in`")),
+            Expr::Lit(_) | Expr::Attr(_) => Ok(()),
+            Expr::Header(e) | Expr::HasHeader(e) | Expr::Not(e) | Expr::Neg(e) => e.check(),
+            Expr::And(a, b) | Expr::Or(a, b) | Expr::Cmp(_, a, b) | Expr::In(a, b) => {
+                a.check()?;
+                b.check()
+            }
+            Expr::List(items) | Expr::Call(_, items) => items.iter().try_for_each(Expr::check),
+        }
+    }
+
+    fn eval(&self, req: &dyn RequestView) -> Result<Value, String> {
+        Ok(match self {
+            Expr::Lit(value) => value.clone(),
+            Expr::Attr(Attr::Method) => Value::Str(req.method().to_string()),
+            Expr::Attr(Attr::Path) => Value::Str(req.path().to_string()),
+            Expr::Attr(Attr::Host) => Value::Str(req.host().unwrap_or_default().to_string()),
+            Expr::Attr(Attr::SourceIp) => match req.client_ip() {
+                Some(ip) => Value::Str(ip.to_string()),
+                None => return Err("source address is unknown".into()),
+            },
+            Expr::Headers(_) => unreachable!("rejected at compile time"),
+            Expr::Header(key) => {
+                let key = expect_str(key.eval(req)?)?;
+                match req.header(&key) {
+                    Some(value) => Value::Str(value.to_string()),
+                    None => return Err(format!("no such header: {}", key)),
+                }
+            }
+            Expr::HasHeader(key) => Value::Bool(req.header(&expect_str(key.eval(req)?)?).is_some()),
+            Expr::List(items) => Value::List(items.iter().map(|e| e.eval(req)).collect::<Result<_, _>>()?),
+            Expr::Not(e) => Value::Bool(!expect_bool(e.eval(req)?)?),
+            Expr::Neg(e) => match e.eval(req)? {
+                Value::Int(n) => Value::Int(n.checked_neg().ok_or("integer overflow")?),
+                other => return Err(format!("cannot negate {}", other.type_name())),
+            },
+            Expr::And(a, b) => Value::Bool(expect_bool(a.eval(req)?)? && expect_bool(b.eval(req)?)?),
+            Expr::Or(a, b) => Value::Bool(expect_bool(a.eval(req)?)? || expect_bool(b.eval(req)?)?),
+            Expr::Cmp(op, a, b) => Value::Bool(compare(*op, a.eval(req)?, b.eval(req)?)?),
+            Expr::In(item, target) => {
+                let item = item.eval(req)?;
+                match target.eval(req)? {
+                    Value::List(items) => Value::Bool(items.contains(&item)),
+                    Value::Net(net, prefix) => {
+                        let addr = expect_str(item)?;
+                        let addr: IpAddr = addr.parse().map_err(|_| format!("{:?} is not an IP address", addr))?;
+                        Value::Bool(net_contains(net, prefix, addr))
+                    }
+                    other => return Err(format!("`in` is not supported on {}", other.type_name())),
+                }
+            }
+            Expr::Call(func, args) => {
+                let mut args = args.iter().map(|e| e.eval(req)).collect::<Result<Vec<_>, _>>()?.into_iter();
+                let first = args.next().unwrap_or(Value::Null);
+                match func {
+                    Func::Size => match first {
+                        Value::Str(s) => Value::Int(s.chars().count() as i64),
+                        Value::List(items) => Value::Int(items.len() as i64),
+                        other => return Err(format!("size() is not supported on {}", other.type_name())),
+                    },
+                    Func::Cidr => parse_cidr(&expect_str(first)?)?,
+                    Func::StartsWith | Func::EndsWith | Func::Contains => {
+                        let (s, x) = (expect_str(first)?, expect_str(args.next().unwrap_or(Value::Null))?);
+                        Value::Bool(match func {
+                            Func::StartsWith => s.starts_with(&x),
+                            Func::EndsWith => s.ends_with(&x),
+                            _ => s.contains(&x),
+                        })
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn expect_bool(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        other => Err(format!("expected bool, found {}", other.type_name())),
+    }
+}
+
+fn expect_str(value: Value) -> Result<String, String> {
+    match value {
+        Value::Str(s) => Ok(s),
+        other => Err(format!("expected string, found {}", other.type_name())),
+    }
+}
+
+fn compare(op: CmpOp, a: Value, b: Value) -> Result<bool, String> {
+    let ordering = match (&a, &b) {
+        (Value::Int(x), Value::Int(y)) => x.cmp(y),
+        (Value::Str(x), Value::Str(y)) => x.cmp(y),
+        _ => {
+            return match op {
+                CmpOp::Eq => Ok(a == b),
+                CmpOp::Ne => Ok(a != b),
+                _ => Err(format!("cannot order {} and {}", a.type_name(), b.type_name())),
+            }
+        }
+    };
+    Ok(match op {
+        CmpOp::Eq => ordering.is_eq(),
+        CmpOp::Ne => ordering.is_ne(),
+        CmpOp::Lt => ordering.is_lt(),
+        CmpOp::Le => ordering.is_le(),
+        CmpOp::Gt => ordering.is_gt(),
+        CmpOp::Ge => ordering.is_ge(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Req {
+        headers: HashMap<&'static str, &'static str>,
+        ip: Option<IpAddr>,
+    }
+
+    impl RequestView for Req {
+        fn method(&self) -> &str {
+            "GET"
+        }
+        fn host(&self) -> Option<&str> {
+            Some("api.example.com")
+        }
+        fn path(&self) -> &str {
+            "/v2/users"
+        }
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.get(name).copied()
+        }
+        fn client_ip(&self) -> Option<IpAddr> {
+            self.ip
+        }
+    }
+
+    fn req(ver: &'static str, ip: &str) -> Req {
+        Req {
+            headers: [("x-ver", ver)].into(),
+            ip: Some(ip.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_expressions_evaluate_against_the_request() {
+        let expr = CelExpression::compile("request.headers['x-ver'].startsWith('2.') && source.ip in cidr('10.0.0.0/8')").unwrap();
+        assert!(expr.matches(&req("2.1", "10.1.2.3")));
+        assert!(!expr.matches(&req("1.9", "10.1.2.3")));
+        assert!(!expr.matches(&req("2.1", "192.168.0.1")));
+
+        let expr = CelExpression::compile(r#"request.method in ["GET", "HEAD"] && !('x-debug' in request.headers) && size(request.path) > 3"#).unwrap();
+        assert!(expr.matches(&req("1", "::1")));
+
+        // A missing header is an error, which doesn't match
+        let expr = CelExpression::compile("request.headers['x-missing'] == 'a' || true").unwrap();
+        assert!(expr.evaluate(&req("1", "::1")).is_err());
+        assert!(!expr.matches(&req("1", "::1")));
+    }
+
+    #[test]
+    fn test_mistakes_fail_at_compile_time() {
+        for (source, message) in [
+            ("request.verb == 'GET'", "unknown field `request.verb`"),
+            ("source.ip in cidr('10.0.0.0/33')", "invalid CIDR"),
+            ("request.path.startsWith()", "takes 2 argument(s)"),
+            ("request.headers == 'x'", "must be indexed"),
+            ("request.method == 'GET' &&", "unexpected end"),
+            ("request.method = 'GET'", "unexpected character"),
+        ] {
+            let err = CelExpression::compile(source).unwrap_err();
+            assert!(err.message.contains(message), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_nesting_is_limited() {
+        let nested = |depth: usize| format!("{}true{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(CelExpression::compile(&nested(MAX_DEPTH - 1)).is_ok());
+        // Deep enough to overflow the stack if parsed, checked or evaluated
+        for source in [
+            nested(50_000),
+            format!("{}true", "!".repeat(50_000)),
+            format!("{}1 == 1", "-".repeat(50_000)),
+            format!("{}true", "true || ".repeat(50_000)),
+            format!("{}true", "true && ".repeat(50_000)),
+            format!("request.path{}", ".size()".repeat(50_000)),
+            format!("{}1{}", "[".repeat(50_000), "]".repeat(50_000)),
+        ] {
+            let err = CelExpression::compile(&source).unwrap_err();
+            assert!(err.message.contains("nested more than 64 levels"), "{}", err);
+        }
+    }
+}
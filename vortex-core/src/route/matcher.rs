@@ -0,0 +1,415 @@
+//! The compiled, multi-level route matcher.
+//!
+//! Matching is split into three levels so its cost depends on the request,
+//! not on how many routes are configured:
+//!
+//! 1. A trie over the host's labels, stored right to left, picks the exact
+//!    host and every wildcard domain enclosing it.
+//! 2. Each host owns a radix tree over path bytes; walking it yields the
+//!    routes registered at every prefix of the path.
+//! 3. Only the routes at those nodes have their predicates evaluated,
+//!    deepest prefix first, in declaration order.
+//!
+//! When every candidate at one level is rejected, matching falls back to the
+//! next shorter prefix, then to the next less specific host.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use super::{Predicate, RequestView, RouteSpec};
+
+/// Routes compiled into a host trie of path radix trees.
+#[derive(Debug, Default)]
+pub struct RouteMatcher {
+    routes: Vec<RouteSpec>,
+    hosts: HostNode,
+    any_host: PathNode,
+}
+
+impl RouteMatcher {
+    /// Compile `routes`; their order breaks ties between equally specific routes.
+    pub fn new(routes: Vec<RouteSpec>) -> Self {
+        let mut hosts = HostNode::default();
+        let mut any_host = PathNode::default();
+
+        for (index, route) in routes.iter().enumerate() {
+            let tree = match route.host.as_deref().map(str::to_ascii_lowercase) {
+                None => &mut any_host,
+                Some(host) if host == "*" => &mut any_host,
+                Some(host) => hosts.tree_for(&host),
+            };
+            tree.insert(route.path_prefix.as_bytes(), index);
+        }
+
+        Self { routes, hosts, any_host }
+    }
+
+    /// The routes in declaration order.
+    pub fn routes(&self) -> &[RouteSpec] {
+        &self.routes
+    }
+
+    /// Whether no routes are configured.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// The most specific route matching `req`, if any.
+    pub fn find(&self, req: &dyn RequestView) -> Option<&RouteSpec> {
+        let index = self.find_index(req, &mut |index| self.routes[index].predicates_match(req))?;
+        Some(&self.routes[index])
+    }
+
+    /// Matches `req` like `find`, recording every route whose host and path
+    /// matched along the way and why it was passed over.
+    pub fn explain(&self, req: &dyn RequestView) -> RouteExplanation {
+        let mut candidates = Vec::new();
+        let selected = self.find_index(req, &mut |index| {
+            let route = &self.routes[index];
+            let rejected_by = route.predicates.iter().find(|p| !p.matches(req)).cloned();
+            let accepted = rejected_by.is_none();
+            candidates.push(CandidateRoute { route: route.clone(), rejected_by });
+            accepted
+        });
+
+        RouteExplanation {
+            selected: selected.map(|index| self.routes[index].clone()),
+            candidates,
+        }
+    }
+
+    fn find_index(&self, req: &dyn RequestView, accept: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        let path = req.path().as_bytes();
+        let from_host = req
+            .host()
+            .map(normalize_host)
+            .and_then(|host| self.hosts.find(host.rsplit('.'), path, accept));
+        from_host.or_else(|| self.any_host.find(path, accept))
+    }
+}
+
+/// The outcome of `RouteMatcher::explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteExplanation {
+    /// The route that would serve the request
+    pub selected: Option<RouteSpec>,
+    /// Routes whose host and path matched, in the order they were tried
+    pub candidates: Vec<CandidateRoute>,
+}
+
+/// A route considered while matching a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandidateRoute {
+    /// The route
+    pub route: RouteSpec,
+    /// The first predicate the request failed, if any
+    pub rejected_by: Option<Predicate>,
+}
+
+/// Strips the port and trailing dot from a request host and lowercases it.
+fn normalize_host(host: &str) -> Cow<'_, str> {
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal: keep the brackets, drop any port after them
+        Some(rest) => rest.find(']').map_or(host, |end| &host[..end + 2]),
+        None => match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        },
+    };
+    let host = host.strip_suffix('.').unwrap_or(host);
+
+    if host.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(host.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(host)
+    }
+}
+
+/// One domain label in the host trie, e.g. `example` under `com`.
+#[derive(Debug, Default)]
+struct HostNode {
+    children: HashMap<Box<str>, HostNode>,
+    /// Routes for exactly this domain
+    exact: Option<PathNode>,
+    /// Routes for `*.` this domain
+    wildcard: Option<PathNode>,
+}
+
+impl HostNode {
+    fn tree_for(&mut self, host: &str) -> &mut PathNode {
+        let (wildcard, domain) = match host.strip_prefix("*.") {
+            Some(domain) => (true, domain),
+            None => (false, host),
+        };
+        let node = domain
+            .rsplit('.')
+            .fold(self, |node, label| node.children.entry(label.into()).or_default());
+        if wildcard {
+            node.wildcard.get_or_insert_with(PathNode::default)
+        } else {
+            node.exact.get_or_insert_with(PathNode::default)
+        }
+    }
+
+    /// Searches the exact host first, then enclosing wildcards from the innermost out.
+    fn find<'a>(
+        &self,
+        mut labels: impl Iterator<Item = &'a str>,
+        path: &[u8],
+        accept: &mut dyn FnMut(usize) -> bool,
+    ) -> Option<usize> {
+        let Some(label) = labels.next() else {
+            return self.exact.as_ref()?.find(path, accept);
+        };
+        let deeper = self
+            .children
+            .get(label)
+            .and_then(|child| child.find(labels, path, accept));
+        // A wildcard matches only when at least one label remains below this domain
+        deeper.or_else(|| self.wildcard.as_ref()?.find(path, accept))
+    }
+}
+
+/// A radix tree node over path bytes.
+#[derive(Debug, Default)]
+struct PathNode {
+    /// The bytes on the edge leading to this node
+    label: Box<[u8]>,
+    /// Routes whose prefix ends exactly here, in declaration order
+    routes: Vec<usize>,
+    /// Children, sorted by the first byte of their label
+    children: Vec<PathNode>,
+}
+
+impl PathNode {
+    fn insert(&mut self, path: &[u8], route: usize) {
+        let Some(&first) = path.first() else {
+            self.routes.push(route);
+            return;
+        };
+
+        match self.children.binary_search_by_key(&first, |c| c.label[0]) {
+            Ok(i) => {
+                let child = &mut self.children[i];
+                let common = child.label.iter().zip(path).take_while(|(a, b)| a == b).count();
+                if common < child.label.len() {
+                    // Split the edge so the shared prefix gets its own node
+                    let tail = PathNode {
+                        label: child.label[common..].into(),
+                        routes: std::mem::take(&mut child.routes),
+                        children: std::mem::take(&mut child.children),
+                    };
+                    child.label = child.label[..common].into();
+                    child.children = vec![tail];
+                }
+                child.insert(&path[common..], route);
+            }
+            Err(i) => self.children.insert(
+                i,
+                PathNode {
+                    label: path.into(),
+                    routes: vec![route],
+                    children: Vec::new(),
+                },
+            ),
+        }
+    }
+
+    /// The first accepted route along `path`, trying the longest prefix first.
+    fn find(&self, path: &[u8], accept: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        let deeper = path.first().and_then(|first| {
+            let i = self.children.binary_search_by_key(first, |c| c.label[0]).ok()?;
+            let child = &self.children[i];
+            child.find(path.strip_prefix(&*child.label)?, accept)
+        });
+        deeper.or_else(|| self.routes.iter().copied().find(|&route| accept(route)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::Predicate;
+    use proptest::prelude::*;
+
+    struct Req {
+        method: &'static str,
+        host: Option<String>,
+        path: String,
+        headers: Vec<(&'static str, &'static str)>,
+    }
+
+    impl Req {
+        fn get(host: &str, path: &str) -> Self {
+            Req { method: "GET", host: Some(host.to_string()), path: path.to_string(), headers: Vec::new() }
+        }
+    }
+
+    impl RequestView for Req {
+        fn method(&self) -> &str {
+            self.method
+        }
+
+        fn host(&self) -> Option<&str> {
+            self.host.as_deref()
+        }
+
+        fn path(&self) -> &str {
+            &self.path
+        }
+
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| *v)
+        }
+    }
+
+    fn matched<'a>(matcher: &'a RouteMatcher, req: &Req) -> Option<&'a str> {
+        matcher.find(req).map(|r| r.name.as_str())
+    }
+
+    #[test]
+    fn test_longest_prefix_and_radix_splits() {
+        let matcher = RouteMatcher::new(vec![
+            RouteSpec::new("apples", "/apples"),
+            RouteSpec::new("api", "/api"),
+            RouteSpec::new("ap", "/ap"),
+            RouteSpec::new("root", "/"),
+        ]);
+
+        assert_eq!(matched(&matcher, &Req::get("x", "/api/v1")), Some("api"));
+        assert_eq!(matched(&matcher, &Req::get("x", "/apples/red")), Some("apples"));
+        assert_eq!(matched(&matcher, &Req::get("x", "/apricot")), Some("ap"));
+        assert_eq!(matched(&matcher, &Req::get("x", "/b")), Some("root"));
+        assert_eq!(matched(&matcher, &Req::get("x", "")), None);
+    }
+
+    #[test]
+    fn test_host_specificity_and_normalization() {
+        let matcher = RouteMatcher::new(vec![
+            RouteSpec::new("any", "/"),
+            RouteSpec::new("wild", "/").with_host("*.example.com"),
+            RouteSpec::new("deep-wild", "/").with_host("*.eu.example.com"),
+            RouteSpec::new("exact", "/").with_host("API.example.com"),
+        ]);
+
+        assert_eq!(matched(&matcher, &Req::get("api.example.com:8443", "/")), Some("exact"));
+        assert_eq!(matched(&matcher, &Req::get("Shop.Example.com.", "/")), Some("wild"));
+        assert_eq!(matched(&matcher, &Req::get("a.eu.example.com", "/")), Some("deep-wild"));
+        assert_eq!(matched(&matcher, &Req::get("example.com", "/")), Some("any"));
+        assert_eq!(matched(&matcher, &Req::get("[::1]:80", "/")), Some("any"));
+    }
+
+    #[test]
+    fn test_predicates_fall_back_to_less_specific_routes() {
+        let matcher = RouteMatcher::new(vec![
+            RouteSpec::new("api-fallback", "/api"),
+            RouteSpec::new("canary", "/api/users")
+                .with_host("example.com")
+                .with_predicate(Predicate::Header { name: "x-canary".into(), value: Some("1".into()) }),
+            RouteSpec::new("writes", "/api/users")
+                .with_host("example.com")
+                .with_predicate(Predicate::Method("POST".into())),
+        ]);
+
+        let mut req = Req::get("example.com", "/api/users/7");
+        assert_eq!(matched(&matcher, &req), Some("api-fallback"));
+
+        req.method = "POST";
+        assert_eq!(matched(&matcher, &req), Some("writes"));
+
+        // Declaration order breaks the tie between two satisfied routes
+        req.headers.push(("X-Canary", "1"));
+        assert_eq!(matched(&matcher, &req), Some("canary"));
+    }
+
+    #[test]
+    fn test_reads_and_writes_split_by_method() {
+        let matcher = RouteMatcher::new(vec![
+            RouteSpec::new("replica", "/orders").with_methods(["GET", "OPTIONS"]),
+            RouteSpec::new("primary", "/orders"),
+            RouteSpec::new("reports", "/reports").with_methods(["GET"]),
+        ]);
+
+        let mut req = Req::get("example.com", "/orders/7");
+        assert_eq!(matched(&matcher, &req), Some("replica"));
+        req.method = "HEAD";
+        assert_eq!(matched(&matcher, &req), Some("replica"));
+        req.method = "DELETE";
+        assert_eq!(matched(&matcher, &req), Some("primary"));
+
+        req.path = "/reports/daily".into();
+        req.method = "HEAD";
+        assert_eq!(matched(&matcher, &req), Some("reports"));
+        req.method = "POST";
+        assert_eq!(matched(&matcher, &req), None);
+        assert_eq!(Predicate::Methods(vec!["GET".into(), "HEAD".into()]).to_string(), "method is one of GET, HEAD");
+    }
+
+    #[test]
+    fn test_header_patterns_compose_with_host_and_path() {
+        let tenants = crate::route::pattern::Pattern::new("acme|globex").unwrap();
+        let matcher = RouteMatcher::new(vec![
+            RouteSpec::new("shared", "/api"),
+            RouteSpec::new("dedicated", "/api").with_host("example.com").with_header_regex("x-tenant", tenants),
+        ]);
+
+        let mut req = Req::get("example.com", "/api/orders");
+        assert_eq!(matched(&matcher, &req), Some("shared"));
+        req.headers.push(("X-Tenant", "globex"));
+        assert_eq!(matched(&matcher, &req), Some("dedicated"));
+
+        // The whole value must match, and only on the route's host
+        req.headers[0].1 = "acme-staging";
+        assert_eq!(matched(&matcher, &req), Some("shared"));
+        let mut elsewhere = Req::get("example.org", "/api/orders");
+        elsewhere.headers.push(("x-tenant", "acme"));
+        assert_eq!(matched(&matcher, &elsewhere), Some("shared"));
+    }
+
+    #[test]
+    fn test_explain_lists_rejected_candidates() {
+        let matcher = RouteMatcher::new(vec![
+            RouteSpec::new("root", "/"),
+            RouteSpec::new("writes", "/api").with_predicate(Predicate::Method("POST".into())),
+            RouteSpec::new("other-host", "/api").with_host("example.org"),
+        ]);
+
+        let explanation = matcher.explain(&Req::get("example.com", "/api/x"));
+        assert_eq!(explanation.selected.map(|r| r.name), Some("root".to_string()));
+        let tried: Vec<_> = explanation
+            .candidates
+            .iter()
+            .map(|c| (c.route.name.as_str(), c.rejected_by.clone()))
+            .collect();
+        assert_eq!(tried, [("writes", Some(Predicate::Method("POST".into()))), ("root", None)]);
+    }
+
+    /// The behavior the compiled matcher must preserve, as a plain scan.
+    fn linear_find<'a>(routes: &'a [RouteSpec], path: &str) -> Option<&'a str> {
+        routes
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| path.starts_with(&r.path_prefix))
+            .max_by_key(|(i, r)| (r.path_prefix.len(), std::cmp::Reverse(*i)))
+            .map(|(_, r)| r.name.as_str())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_matches_linear_scan(
+            prefixes in proptest::collection::vec("/[ab/]{0,6}", 1..40),
+            paths in proptest::collection::vec("/[ab/]{0,8}", 1..20),
+        ) {
+            let routes: Vec<RouteSpec> = prefixes
+                .iter()
+                .enumerate()
+                .map(|(i, prefix)| RouteSpec::new(format!("r{}", i), prefix.clone()))
+                .collect();
+            let matcher = RouteMatcher::new(routes.clone());
+
+            for path in paths {
+                let req = Req::get("h", &path);
+                prop_assert_eq!(matched(&matcher, &req), linear_find(&routes, &path));
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Request-to-route matching.
+//!
+//! Routes are declared as `RouteSpec`s and compiled into a `RouteMatcher`
+//! whenever the route set changes, so the hot path never scans the whole list:
+//! the request host selects a path tree, the path walks that tree, and only
+//! the handful of routes sharing the longest matching prefix have their
+//! predicates evaluated.
+//!
+//! A route may also require its path or a header to match a regex (see
+//! `pattern`), checked like its other predicates, and rewrite the forwarded
+//! path from the path regex's capture groups.
+
+pub mod cel;
+pub mod matcher;
+pub mod pattern;
+
+use arc_swap::ArcSwap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use crate::domain::labels::Labels;
+use crate::load_balancer::subset::Subset;
+use self::cel::CelExpression;
+use self::matcher::RouteMatcher;
+use self::pattern::Pattern;
+
+/// The parts of a request that route matching looks at.
+///
+/// Implemented by the data plane for live requests and by the admin API for
+/// synthetic ones, so core stays independent of any HTTP library.
+pub trait RequestView {
+    /// The request method, e.g. `GET`
+    fn method(&self) -> &str;
+    /// The requested host (`Host` header or URI authority), port included if sent
+    fn host(&self) -> Option<&str>;
+    /// The request path, without the query string
+    fn path(&self) -> &str;
+    /// The value of a request header, looked up case-insensitively
+    fn header(&self, name: &str) -> Option<&str>;
+    /// The client's address, if known
+    fn client_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// An extra condition a request must meet after its host and path matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// The request method equals this one (case-sensitive, as in HTTP);
+    /// `GET` admits `HEAD` too
+    Method(String),
+    /// The request method is one of these, as for `Method`
+    Methods(Vec<String>),
+    /// The header is present and, if a value is given, equals it exactly
+    Header {
+        /// Header name
+        name: String,
+        /// Required value, or `None` for presence only
+        value: Option<String>,
+    },
+    /// The header is present and its whole value matches the regex
+    HeaderRegex {
+        /// Header name
+        name: String,
+        /// What the value must match
+        pattern: Pattern,
+    },
+    /// The CEL expression evaluates to true (see `cel` for what's supported)
+    Cel(CelExpression),
+    /// The whole request path matches the regex
+    PathRegex(Pattern),
+}
+
+impl Predicate {
+    /// Whether `req` satisfies this predicate.
+    pub fn matches(&self, req: &dyn RequestView) -> bool {
+        match self {
+            Predicate::Method(method) => method_admits(method, req.method()),
+            Predicate::Methods(methods) => methods.iter().any(|method| method_admits(method, req.method())),
+            Predicate::Header { name, value } => match (req.header(name), value) {
+                (Some(actual), Some(expected)) => actual == expected,
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+            Predicate::HeaderRegex { name, pattern } => req.header(name).is_some_and(|value| pattern.is_match(value)),
+            Predicate::Cel(expr) => expr.matches(req),
+            Predicate::PathRegex(regex) => regex.is_match(req.path()),
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Predicate::Method(method) => write!(f, "method is {}", method),
+            Predicate::Methods(methods) => write!(f, "method is one of {}", methods.join(", ")),
+            Predicate::Header { name, value: Some(value) } => write!(f, "header {} is {:?}", name, value),
+            Predicate::Header { name, value: None } => write!(f, "header {} is present", name),
+            Predicate::HeaderRegex { name, pattern } => write!(f, "header {} matches {}", name, pattern),
+            Predicate::Cel(expr) => write!(f, "expression {} holds", expr),
+            Predicate::PathRegex(regex) => write!(f, "path matches {}", regex),
+        }
+    }
+}
+
+/// Whether a route allowing `allowed` takes a `method` request. A `HEAD` is
+/// a `GET` without the body, so wherever reads go, it goes too.
+fn method_admits(allowed: &str, method: &str) -> bool {
+    method == allowed || (method == "HEAD" && allowed == "GET")
+}
+
+/// A declared route.
+///
+/// Among routes matching a request, an exact host beats a wildcard host,
+/// which beats routes without a host; then the longest path prefix wins;
+/// then declaration order decides. So `/api/` can go to one cluster and
+/// `/api/static/` to another, whatever order they're declared in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteSpec {
+    /// Unique route name, used in metrics, logs, and the admin API
+    pub name: String,
+    /// `example.com`, `*.example.com`, or `None` for any host
+    pub host: Option<String>,
+    /// Path prefix the request path must start with
+    pub path_prefix: String,
+    /// Additional conditions, all of which must hold
+    pub predicates: Vec<Predicate>,
+    /// Observability labels merged over the pool's for matching requests
+    pub labels: Labels,
+    /// Backends the route may use; the whole pool if `None`
+    pub subset: Option<Arc<Subset>>,
+    /// The cluster serving the route; the default pool if `None`
+    pub cluster: Option<String>,
+    /// The path forwarded upstream in place of the request's, where `$1` or
+    /// `$name` stand for the path regex's capture groups; unchanged if `None`
+    pub rewrite: Option<String>,
+}
+
+impl RouteSpec {
+    /// Create a route matching any host under `path_prefix`.
+    pub fn new(name: impl Into<String>, path_prefix: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            host: None,
+            path_prefix: path_prefix.into(),
+            predicates: Vec::new(),
+            labels: Labels::new(),
+            subset: None,
+            cluster: None,
+            rewrite: None,
+        }
+    }
+
+    /// Restrict the route to a host or `*.`-prefixed wildcard domain, builder style.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Add a predicate, builder style.
+    pub fn with_predicate(mut self, predicate: Predicate) -> Self {
+        self.predicates.push(predicate);
+        self
+    }
+
+    /// Attach observability labels, builder style.
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Restrict the route to a subset of the pool, builder style.
+    pub fn with_subset(mut self, subset: Subset) -> Self {
+        self.subset = Some(Arc::new(subset));
+        self
+    }
+
+    /// Serve the route from the named cluster instead of the default pool, builder style.
+    pub fn with_cluster(mut self, cluster: impl Into<String>) -> Self {
+        self.cluster = Some(cluster.into());
+        self
+    }
+
+    /// Restrict the route to `methods`, builder style; no restriction if empty.
+    pub fn with_methods<I, S>(self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut methods: Vec<String> = methods.into_iter().map(Into::into).collect();
+        match methods.len() {
+            0 => self,
+            1 => self.with_predicate(Predicate::Method(methods.remove(0))),
+            _ => self.with_predicate(Predicate::Methods(methods)),
+        }
+    }
+
+    /// Require header `name` to be present with a value matching `pattern`,
+    /// builder style.
+    pub fn with_header_regex(self, name: impl Into<String>, pattern: Pattern) -> Self {
+        self.with_predicate(Predicate::HeaderRegex { name: name.into(), pattern })
+    }
+
+    /// Require the whole path to match `regex`, builder style.
+    pub fn with_path_regex(self, regex: Pattern) -> Self {
+        self.with_predicate(Predicate::PathRegex(regex))
+    }
+
+    /// Forward requests with their path rewritten from `template`, builder style.
+    pub fn with_rewrite(mut self, template: impl Into<String>) -> Self {
+        self.rewrite = Some(template.into());
+        self
+    }
+
+    /// The path to forward in place of `path`, if the route rewrites it.
+    /// Capture groups refer to the first path regex; without one, the
+    /// template is the path.
+    pub fn rewritten_path(&self, path: &str) -> Option<String> {
+        let template = self.rewrite.as_deref()?;
+        let regex = self.predicates.iter().find_map(|p| match p {
+            Predicate::PathRegex(regex) => Some(regex),
+            _ => None,
+        });
+        match regex {
+            Some(regex) => regex.expand(path, template),
+            None => Some(template.to_string()),
+        }
+    }
+
+    /// Whether all of the route's predicates hold for `req`.
+    pub fn predicates_match(&self, req: &dyn RequestView) -> bool {
+        self.predicates.iter().all(|p| p.matches(req))
+    }
+}
+
+/// A lock-free, hot-reloadable set of compiled routes.
+#[derive(Debug)]
+pub struct RouteTable {
+    matcher: ArcSwap<RouteMatcher>,
+}
+
+impl RouteTable {
+    /// Compile the initial route set.
+    pub fn new(routes: Vec<RouteSpec>) -> Self {
+        Self {
+            matcher: ArcSwap::from_pointee(RouteMatcher::new(routes)),
+        }
+    }
+
+    /// The current compiled routes.
+    pub fn matcher(&self) -> Arc<RouteMatcher> {
+        self.matcher.load_full()
+    }
+
+    /// Recompile and atomically swap in a new route set (e.g. during config hot-reload).
+    pub fn update_routes(&self, routes: Vec<RouteSpec>) {
+        self.matcher.store(Arc::new(RouteMatcher::new(routes)));
+    }
+}
+
+/// A shared reference to the lock-free route table.
+pub type SharedRouteTable = Arc<RouteTable>;
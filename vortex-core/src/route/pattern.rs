@@ -0,0 +1,93 @@
+//! Regular expressions over request paths and header values.
+//!
+//! A route can require its path to match a regex, on top of its path prefix,
+//! and rewrite the path it forwards from the regex's capture groups, e.g.
+//! `/users/(?P<id>\d+)/profile` to `/profiles/$id`. It can likewise require
+//! a header's value to match one, such as `acme|globex` for `X-Tenant`.
+//! Patterns are compiled once, when the route set is, and kept in the
+//! compiled routes.
+
+use regex::Regex;
+use std::fmt;
+
+/// A compiled regex that a path or header value must match in full.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    /// Compile `source`. It is anchored at both ends, so it must match the
+    /// whole value; for paths, that is without the query string.
+    pub fn new(source: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            source: source.to_string(),
+            regex: Regex::new(&format!("^(?:{})$", source))?,
+        })
+    }
+
+    /// The pattern as written.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether `value` matches.
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+
+    /// `template` with `$1`, `$name` or `${name}` replaced by what the
+    /// groups of the match against `path` captured, or `None` if it doesn't
+    /// match. Groups that captured nothing expand to nothing.
+    pub fn expand(&self, path: &str, template: &str) -> Option<String> {
+        let captures = self.regex.captures(path)?;
+        let mut expanded = String::with_capacity(template.len());
+        captures.expand(template, &mut expanded);
+        Some(expanded)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Pattern {}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns_match_whole_paths() {
+        let regex = Pattern::new(r"/users/\d+").unwrap();
+        assert!(regex.is_match("/users/42"));
+        assert!(!regex.is_match("/users/42/orders"));
+        assert!(!regex.is_match("/v1/users/42"));
+
+        // Alternations stay inside the anchors
+        let regex = Pattern::new("/a|/b").unwrap();
+        assert!(regex.is_match("/b"));
+        assert!(!regex.is_match("/b/c"));
+    }
+
+    #[test]
+    fn test_expand_fills_in_groups() {
+        let regex = Pattern::new(r"/users/(?P<id>\d+)/(\w+)").unwrap();
+        assert_eq!(regex.expand("/users/42/orders", "/${2}/by-user/$id").as_deref(), Some("/orders/by-user/42"));
+        assert_eq!(regex.expand("/users/x/orders", "/$id"), None);
+    }
+
+    #[test]
+    fn test_invalid_patterns_are_rejected() {
+        assert!(Pattern::new("/users/(").is_err());
+    }
+}
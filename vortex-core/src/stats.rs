@@ -0,0 +1,173 @@
+//! Runtime statistics shared between the data plane and the control plane.
+//!
+//! The data plane owns the live counters; these plain snapshot types let the
+//! admin API and metrics exporters read them without depending on `hyper`.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+use crate::domain::backend::BackendId;
+
+/// A point-in-time snapshot of the connection pool for a single backend address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+    /// The backend the connections belong to
+    pub backend: BackendId,
+    /// The upstream address the connections point at
+    pub addr: SocketAddr,
+    /// Number of idle connections currently waiting in the pool
+    pub idle: u64,
+    /// Total connections established to this upstream
+    pub created: u64,
+    /// Total times an idle connection was handed out instead of dialing
+    pub reused: u64,
+    /// Total connections retired (peer closed, `Connection: close`, or evicted)
+    pub closed: u64,
+    /// Mean time since the connections currently idle in the pool were
+    /// established
+    pub avg_connection_age: Duration,
+}
+
+/// Anything that can report connection pool statistics.
+pub trait PoolStatsSource: Send + Sync {
+    /// Returns a snapshot of every upstream address the pool has seen.
+    fn pool_stats(&self) -> Vec<PoolStats>;
+}
+
+/// A request attribute that live traffic can be broken down by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TalkerDimension {
+    /// The downstream client's IP address
+    ClientIp,
+    /// The route the request matched, `default` without declared routes
+    Route,
+    /// The `User-Agent` header
+    UserAgent,
+}
+
+impl TalkerDimension {
+    /// Every dimension, in a stable order.
+    pub const ALL: [TalkerDimension; 3] = [TalkerDimension::ClientIp, TalkerDimension::Route, TalkerDimension::UserAgent];
+}
+
+/// One of the heaviest senders along some `TalkerDimension`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopTalker {
+    /// The client IP, route, or user agent
+    pub key: String,
+    /// Approximate requests seen from `key` within the window
+    pub requests: u64,
+}
+
+/// Anything that can report live request rates and the heaviest talkers.
+pub trait TrafficStatsSource: Send + Sync {
+    /// The sliding window the figures are aggregated over.
+    fn window(&self) -> Duration;
+
+    /// Requests per second averaged over the window.
+    fn requests_per_second(&self) -> f64;
+
+    /// The `limit` busiest keys along `dimension`, busiest first.
+    fn top_talkers(&self, dimension: TalkerDimension, limit: usize) -> Vec<TopTalker>;
+}
+
+/// Anything that can render a human-readable snapshot of runtime state for
+/// postmortem triage.
+pub trait DiagnosticsSource: Send + Sync {
+    /// Renders the snapshot as multi-line text.
+    fn dump(&self) -> String;
+}
+
+/// A point-in-time snapshot of the response cache for a single route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheStats {
+    /// The route the responses were cached for
+    pub route: String,
+    /// Total requests answered with a fresh cached response
+    pub hits: u64,
+    /// Total cacheable requests sent upstream because nothing fresh was stored
+    pub misses: u64,
+    /// Total requests answered with an expired response because the upstream failed
+    pub stale_serves: u64,
+    /// Total entries dropped at capacity to make room for others
+    pub evictions: u64,
+    /// Responses currently stored
+    pub entries: u64,
+    /// Mean age of the responses currently stored
+    pub avg_age: Duration,
+    /// Age of the oldest response currently stored
+    pub max_age: Duration,
+}
+
+/// Anything that can report response cache statistics.
+pub trait CacheStatsSource: Send + Sync {
+    /// Returns a snapshot of every route the cache has seen, sorted by route.
+    fn cache_stats(&self) -> Vec<CacheStats>;
+}
+
+/// The outcome of probing one backend on demand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeResult {
+    /// The backend that was probed
+    pub backend: BackendId,
+    /// The address the probe connected to
+    pub addr: SocketAddr,
+    /// Whether the probe passed
+    pub healthy: bool,
+    /// Whether the backend was considered healthy before the probe
+    pub was_healthy: bool,
+    /// How long the probe took to connect, or to give up
+    pub connect_time: Duration,
+    /// Why the probe failed, if it did
+    pub error: Option<String>,
+}
+
+/// Anything that can run the configured health check against one backend right away.
+pub trait BackendProber: Send + Sync {
+    /// Probes `backend`, or returns `None` if no such backend exists.
+    fn probe(&self, backend: BackendId) -> Pin<Box<dyn Future<Output = Option<ProbeResult>> + Send + '_>>;
+}
+
+/// Anything that can export the learned backend and breaker state, and seed
+/// itself from another instance's export.
+pub trait StateSource: Send + Sync {
+    /// The current state as a JSON snapshot.
+    fn export_state(&self) -> String;
+
+    /// Applies a snapshot from `export_state`, returning how many backends
+    /// it matched, or why it couldn't be applied.
+    fn import_state(&self, snapshot: &str) -> Result<usize, String>;
+}
+
+/// What the periodic health checks are doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthCheckStatus {
+    /// Time between sweeps
+    pub interval: Duration,
+    /// Whether sweeps are paused until resumed
+    pub paused: bool,
+    /// Whether the checks were stopped for good
+    pub stopped: bool,
+}
+
+/// Anything that can pause, resume, retime or stop the periodic health
+/// checks of one or more named pools. Pools are left alone by calls naming
+/// another pool, and a call naming no pool reaches all of them.
+pub trait HealthCheckControl: Send + Sync {
+    /// Skips sweeps until resumed; backends keep the health they have.
+    fn pause(&self, pool: Option<&str>);
+
+    /// Resumes paused sweeps.
+    fn resume(&self, pool: Option<&str>);
+
+    /// Changes the time between sweeps of `pool`, starting from now. Pools
+    /// have intervals of their own, so this only ever retimes one.
+    fn set_interval(&self, pool: &str, interval: Duration);
+
+    /// Stops the checks for good, abandoning a sweep in progress.
+    fn stop(&self, pool: Option<&str>);
+
+    /// What the checks of each pool are doing now, by pool name.
+    fn status(&self) -> Vec<(String, HealthCheckStatus)>;
+}
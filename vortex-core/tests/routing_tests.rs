@@ -5,6 +5,7 @@ use std::time::Duration;
 use tokio::time::sleep;
 use vortex_core::domain::backend::{Backend, BackendId};
 use vortex_core::domain::routing::RoutingTable;
+use vortex_core::load_balancer::selector::select_best_backend;
 
 #[tokio::test]
 async fn test_zero_downtime_config_swap_draining() {
@@ -30,3 +31,24 @@ async fn test_zero_downtime_config_swap_draining() {
     let active_backend_for_req2 = routing_table.get_healthy_backend().expect("Expected healthy backend");
     assert_eq!(active_backend_for_req2.id.0, 2);
 }
+
+#[test]
+fn test_removed_backends_drain_before_being_forgotten() {
+    let backend = |id| Arc::new(Backend::new(BackendId(id), format!("127.0.0.1:{}", 9000 + id).parse().unwrap()));
+    let routing_table = Arc::new(RoutingTable::new(vec![backend(1), backend(2)]).with_drain_grace(Duration::from_secs(30)));
+
+    assert_eq!(routing_table.generation(), 0);
+    routing_table.update_backends(vec![backend(2)]);
+    assert_eq!(routing_table.generation(), 1);
+
+    // Backend 1 gets no new traffic but is still reachable by id for pinned requests
+    assert_eq!(select_best_backend(&routing_table).unwrap().id, BackendId(2));
+    assert_eq!(routing_table.backend(BackendId(1)).unwrap().id, BackendId(1));
+    assert!(routing_table.take_drained(std::time::Instant::now()).is_empty());
+
+    let later = std::time::Instant::now() + Duration::from_secs(31);
+    let drained = routing_table.take_drained(later);
+    assert_eq!(drained.iter().map(|b| b.id).collect::<Vec<_>>(), [BackendId(1)]);
+    assert!(routing_table.backend(BackendId(1)).is_none());
+    assert!(routing_table.draining().is_empty());
+}
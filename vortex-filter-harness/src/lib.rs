@@ -0,0 +1,226 @@
+//! Vortex Filter Harness
+//!
+//! Runs a Wasm filter against synthetic requests and responses, with a
+//! scripted host environment (key-value store, callout responses, clock), so
+//! plugin authors can test filters without running the proxy.
+//!
+//! ```
+//! use vortex_filter_harness::{FilterHarness, Message, Mutation};
+//!
+//! let filter = r#"(module
+//!     (import "vortex" "header_set" (func $set (param i32 i32 i32 i32)))
+//!     (memory (export "memory") 1)
+//!     (data (i32.const 0) "x-filteredyes")
+//!     (func (export "execute") (result i32)
+//!         (call $set (i32.const 0) (i32.const 10) (i32.const 10) (i32.const 3))
+//!         i32.const 200))"#;
+//!
+//! let outcome = FilterHarness::new(filter).run(Message::request("GET", "/")).unwrap();
+//! assert_eq!(outcome.code, 200);
+//! assert_eq!(outcome.message.header("x-filtered"), Some("yes"));
+//! assert_eq!(outcome.mutations, [Mutation::SetHeader { name: "x-filtered".into(), value: "yes".into() }]);
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use vortex_filters::host::FilterHost;
+use vortex_filters::wasm_engine::{FilterError, WasmEngine};
+
+/// A synthetic HTTP message: headers, including `:method` and `:path` for
+/// requests and `:status` for responses. Names are lowercase.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    /// Header values by name
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Message {
+    /// A request for `path`.
+    pub fn request(method: &str, path: &str) -> Self {
+        Self::default().with_header(":method", method).with_header(":path", path)
+    }
+
+    /// A response with `status`.
+    pub fn response(status: u16) -> Self {
+        Self::default().with_header(":status", &status.to_string())
+    }
+
+    /// Add a header, builder style.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_ascii_lowercase(), value.to_string());
+        self
+    }
+
+    /// The value of header `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+}
+
+/// A change a filter made through the host, in call order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    /// A header was set
+    SetHeader {
+        /// Header name, lowercase
+        name: String,
+        /// New value
+        value: String,
+    },
+    /// A header was removed
+    RemoveHeader {
+        /// Header name, lowercase
+        name: String,
+    },
+    /// A key-value entry was written
+    KvSet {
+        /// The key
+        key: String,
+        /// The value written
+        value: Vec<u8>,
+    },
+    /// A callout was made
+    Callout {
+        /// The callout target
+        target: String,
+    },
+}
+
+/// The result of running a filter once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The filter's exit code (2xx to pass the message on)
+    pub code: i32,
+    /// The message after the filter's changes
+    pub message: Message,
+    /// Every change the filter made, in order
+    pub mutations: Vec<Mutation>,
+}
+
+/// The scripted environment a filter runs in during one `run`.
+struct ScriptedHost {
+    message: Message,
+    kv: HashMap<String, Vec<u8>>,
+    callouts: HashMap<String, i32>,
+    clock_ms: i64,
+    mutations: Vec<Mutation>,
+}
+
+impl FilterHost for ScriptedHost {
+    fn header(&self, name: &str) -> Option<String> {
+        self.message.header(name).map(str::to_string)
+    }
+
+    fn set_header(&mut self, name: &str, value: &str) {
+        let name = name.to_ascii_lowercase();
+        self.message.headers.insert(name.clone(), value.to_string());
+        self.mutations.push(Mutation::SetHeader {
+            name,
+            value: value.to_string(),
+        });
+    }
+
+    fn remove_header(&mut self, name: &str) {
+        let name = name.to_ascii_lowercase();
+        self.message.headers.remove(&name);
+        self.mutations.push(Mutation::RemoveHeader { name });
+    }
+
+    fn kv_get(&self, key: &str) -> Option<Vec<u8>> {
+        self.kv.get(key).cloned()
+    }
+
+    fn kv_set(&mut self, key: &str, value: &[u8]) {
+        self.kv.insert(key.to_string(), value.to_vec());
+        self.mutations.push(Mutation::KvSet {
+            key: key.to_string(),
+            value: value.to_vec(),
+        });
+    }
+
+    fn now_ms(&self) -> i64 {
+        self.clock_ms
+    }
+
+    fn callout(&mut self, target: &str) -> Result<i32, String> {
+        self.mutations.push(Mutation::Callout {
+            target: target.to_string(),
+        });
+        self.callouts
+            .get(target)
+            .copied()
+            .ok_or_else(|| format!("no response scripted for callout to {}", target))
+    }
+}
+
+/// Loads one filter and runs it against synthetic messages.
+///
+/// The key-value store and clock persist across runs, so multi-request
+/// behavior (e.g. a rate limiter) can be tested step by step.
+pub struct FilterHarness {
+    engine: WasmEngine,
+    module: Vec<u8>,
+    kv: HashMap<String, Vec<u8>>,
+    callouts: HashMap<String, i32>,
+    clock_ms: i64,
+}
+
+impl FilterHarness {
+    /// Load a filter from its Wasm binary or text.
+    pub fn new(module: impl Into<Vec<u8>>) -> Self {
+        Self {
+            engine: WasmEngine::new(),
+            module: module.into(),
+            kv: HashMap::new(),
+            callouts: HashMap::new(),
+            clock_ms: 0,
+        }
+    }
+
+    /// Seed the key-value store, builder style.
+    pub fn with_kv(mut self, key: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.kv.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Answer callouts to `target` with `status`, builder style. Callouts
+    /// to targets without a scripted status trap the filter.
+    pub fn with_callout(mut self, target: &str, status: i32) -> Self {
+        self.callouts.insert(target.to_string(), status);
+        self
+    }
+
+    /// Set the clock, in milliseconds since the Unix epoch, builder style.
+    pub fn with_clock(mut self, now_ms: i64) -> Self {
+        self.clock_ms = now_ms;
+        self
+    }
+
+    /// Move the clock forward by `ms`.
+    pub fn advance_clock(&mut self, ms: i64) {
+        self.clock_ms += ms;
+    }
+
+    /// The current key-value store contents.
+    pub fn kv(&self) -> &HashMap<String, Vec<u8>> {
+        &self.kv
+    }
+
+    /// Run the filter once against `message`. A run that fails leaves the
+    /// key-value store as it was.
+    pub fn run(&mut self, message: Message) -> Result<Outcome, FilterError> {
+        let host = ScriptedHost {
+            message,
+            kv: self.kv.clone(),
+            callouts: self.callouts.clone(),
+            clock_ms: self.clock_ms,
+            mutations: Vec::new(),
+        };
+        let (code, host) = self.engine.execute_with_host(&self.module, host)?;
+        self.kv = host.kv;
+        Ok(Outcome {
+            code,
+            message: host.message,
+            mutations: host.mutations,
+        })
+    }
+}
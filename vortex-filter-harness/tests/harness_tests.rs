@@ -0,0 +1,56 @@
+//! Integration tests for running filters in the harness.
+
+use vortex_filter_harness::{FilterHarness, Message, Mutation};
+use vortex_filters::wasm_engine::FilterError;
+
+/// Returns the status of a callout to `authz`, after recording the time under key `seen`.
+const AUTHZ_FILTER: &str = r#"(module
+    (import "vortex" "callout" (func $callout (param i32 i32) (result i32)))
+    (import "vortex" "now_ms" (func $now (result i64)))
+    (import "vortex" "kv_set" (func $kv_set (param i32 i32 i32 i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 0) "authzseen")
+    (func (export "execute") (result i32)
+        (i64.store (i32.const 16) (call $now))
+        (call $kv_set (i32.const 5) (i32.const 4) (i32.const 16) (i32.const 8))
+        (call $callout (i32.const 0) (i32.const 5))))"#;
+
+#[test]
+fn test_scripted_callouts_clock_and_kv() {
+    let mut harness = FilterHarness::new(AUTHZ_FILTER).with_clock(1_000).with_callout("authz", 403);
+    let outcome = harness.run(Message::request("GET", "/admin")).unwrap();
+    assert_eq!(outcome.code, 403);
+    assert_eq!(
+        outcome.mutations,
+        [
+            Mutation::KvSet { key: "seen".into(), value: 1_000i64.to_le_bytes().to_vec() },
+            Mutation::Callout { target: "authz".into() },
+        ]
+    );
+
+    harness.advance_clock(500);
+    harness.run(Message::request("GET", "/admin")).unwrap();
+    assert_eq!(harness.kv()["seen"], 1_500i64.to_le_bytes());
+}
+
+#[test]
+fn test_unscripted_callouts_trap() {
+    let mut harness = FilterHarness::new(AUTHZ_FILTER);
+    let err = harness.run(Message::request("GET", "/")).unwrap_err();
+    assert!(matches!(err, FilterError::Trap(_)));
+    assert!(harness.kv().is_empty());
+}
+
+#[test]
+fn test_filters_read_the_message_headers() {
+    // Returns the length of the `:path` pseudo-header, or -1 without one
+    let filter = r#"(module
+        (import "vortex" "header_get" (func $get (param i32 i32 i32 i32) (result i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 0) ":path")
+        (func (export "execute") (result i32)
+            (call $get (i32.const 0) (i32.const 5) (i32.const 64) (i32.const 64))))"#;
+    let mut harness = FilterHarness::new(filter);
+    assert_eq!(harness.run(Message::request("GET", "/users")).unwrap().code, 6);
+    assert_eq!(harness.run(Message::response(200)).unwrap().code, -1);
+}
@@ -0,0 +1,786 @@
+//! Body codecs for protocol gateways.
+//!
+//! A codec converts message bodies between the format a route's clients
+//! speak and the one its upstream speaks, in both directions, so clients
+//! can move to (or stay on) a format the backend doesn't know: MessagePack
+//! clients in front of a JSON API, say, while the API is modernized behind
+//! them, or JSON clients in front of a legacy XML service. Codecs are fed a
+//! body chunk by chunk through a `Transcoder` and emit output as soon as
+//! they have it: `JsonXml` converts as the body arrives, while one that
+//! needs the whole document, like `MessagePackJson`, holds what it was fed
+//! until the end.
+//!
+//! The proxy picks the codec per route and enforces size limits; codecs only
+//! convert.
+
+use std::borrow::Cow;
+use std::fmt;
+
+/// Why a body could not be converted.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CodecError {
+    /// The input is not valid in the format it claims to be
+    #[error("malformed {format} body: {reason}")]
+    Malformed {
+        /// The format the input was read as
+        format: &'static str,
+        /// What was wrong with it
+        reason: String,
+    },
+    /// The input has a value the output format can't represent
+    #[error("{format} cannot represent {what}")]
+    Unrepresentable {
+        /// The format being written
+        format: &'static str,
+        /// The value that didn't fit
+        what: String,
+    },
+}
+
+/// Which way a body travels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// A request body, from the client format to the upstream's
+    ToUpstream,
+    /// A response body, from the upstream format to the client's
+    ToClient,
+}
+
+impl Direction {
+    /// `request` or `response`, for metrics and logs.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::ToUpstream => "request",
+            Direction::ToClient => "response",
+        }
+    }
+}
+
+/// A pair of body formats a route converts between.
+pub trait BodyCodec: Send + Sync + fmt::Debug {
+    /// Short name for metrics and logs, e.g. `msgpack_json`
+    fn name(&self) -> &str;
+
+    /// The media type of bodies on the client side
+    fn client_media_type(&self) -> &str;
+
+    /// The media type of bodies on the upstream side
+    fn upstream_media_type(&self) -> &str;
+
+    /// Whether `mime`, without parameters, names the client format; codecs
+    /// with several registered names override this.
+    fn is_client_media_type(&self, mime: &str) -> bool {
+        mime.eq_ignore_ascii_case(self.client_media_type())
+    }
+
+    /// Whether `mime`, without parameters, names the upstream format.
+    fn is_upstream_media_type(&self, mime: &str) -> bool {
+        mime.eq_ignore_ascii_case(self.upstream_media_type())
+    }
+
+    /// Whether transcoders emit output as input arrives rather than all at
+    /// the end, so it can be forwarded before the body is complete.
+    fn is_streaming(&self) -> bool {
+        false
+    }
+
+    /// A fresh converter for one body travelling `direction`.
+    fn transcoder(&self, direction: Direction) -> Box<dyn Transcoder>;
+}
+
+/// Converts one body, chunk by chunk.
+pub trait Transcoder: Send + Sync {
+    /// Feed the next chunk of input, appending whatever output is ready to `out`.
+    fn push(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError>;
+
+    /// Signal the end of input, appending the rest of the output to `out`.
+    fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), CodecError>;
+}
+
+/// MessagePack clients in front of a JSON upstream.
+///
+/// Documents are converted whole, as a MessagePack value says nothing about
+/// where it ends until it does. Map keys must be strings and binary values
+/// become arrays of byte values, as JSON has nothing better for either.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackJson;
+
+impl BodyCodec for MessagePackJson {
+    fn name(&self) -> &str {
+        "msgpack_json"
+    }
+
+    fn client_media_type(&self) -> &str {
+        "application/msgpack"
+    }
+
+    fn upstream_media_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn is_client_media_type(&self, mime: &str) -> bool {
+        ["application/msgpack", "application/x-msgpack", "application/vnd.msgpack"]
+            .iter()
+            .any(|name| mime.eq_ignore_ascii_case(name))
+    }
+
+    fn transcoder(&self, direction: Direction) -> Box<dyn Transcoder> {
+        Box::new(WholeDocument { direction, input: Vec::new() })
+    }
+}
+
+/// Buffers a document until its end, then converts it in one go.
+struct WholeDocument {
+    direction: Direction,
+    input: Vec<u8>,
+}
+
+impl Transcoder for WholeDocument {
+    fn push(&mut self, input: &[u8], _out: &mut Vec<u8>) -> Result<(), CodecError> {
+        self.input.extend_from_slice(input);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), CodecError> {
+        let input = std::mem::take(&mut self.input);
+        if input.is_empty() {
+            // No body stays no body
+            return Ok(());
+        }
+        match self.direction {
+            Direction::ToUpstream => msgpack_to_json(&input, out),
+            Direction::ToClient => json_to_msgpack(&input, out),
+        }
+    }
+}
+
+fn msgpack_to_json(input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+    let malformed = |reason: String| CodecError::Malformed { format: "MessagePack", reason };
+    let mut rest = input;
+    let value: serde_json::Value = rmp_serde::from_read(&mut rest).map_err(|e| malformed(e.to_string()))?;
+    if !rest.is_empty() {
+        return Err(malformed(format!("{} bytes after the document", rest.len())));
+    }
+    serde_json::to_writer(out, &value).map_err(|e| CodecError::Unrepresentable { format: "JSON", what: e.to_string() })
+}
+
+fn json_to_msgpack(input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+    let value: serde_json::Value = serde_json::from_slice(input)
+        .map_err(|e| CodecError::Malformed { format: "JSON", reason: e.to_string() })?;
+    rmp_serde::encode::write(out, &value)
+        .map_err(|e| CodecError::Unrepresentable { format: "MessagePack", what: e.to_string() })
+}
+
+/// JSON clients in front of an XML upstream.
+///
+/// Documents map to JsonML: an element is an array of its name, an object of
+/// its attributes if it has any, then its children, and text is a string,
+/// so `<a id="1">hi<b/></a>` is `["a",{"id":"1"},"hi",["b"]]`. Unlike
+/// mappings that gather repeated elements into arrays, this one needs no
+/// lookahead, so both directions convert as the body arrives. Comments,
+/// processing instructions, the doctype and whitespace between elements are
+/// dropped. XML must be UTF-8 and can't declare entities of its own.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonXml;
+
+impl BodyCodec for JsonXml {
+    fn name(&self) -> &str {
+        "json_xml"
+    }
+
+    fn client_media_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn upstream_media_type(&self) -> &str {
+        "application/xml"
+    }
+
+    fn is_client_media_type(&self, mime: &str) -> bool {
+        ["application/json", "application/jsonml+json"]
+            .iter()
+            .any(|name| mime.eq_ignore_ascii_case(name))
+    }
+
+    fn is_upstream_media_type(&self, mime: &str) -> bool {
+        ["application/xml", "text/xml"].iter().any(|name| mime.eq_ignore_ascii_case(name))
+    }
+
+    fn is_streaming(&self) -> bool {
+        true
+    }
+
+    fn transcoder(&self, direction: Direction) -> Box<dyn Transcoder> {
+        match direction {
+            Direction::ToUpstream => Box::<JsonToXml>::default(),
+            Direction::ToClient => Box::<XmlToJson>::default(),
+        }
+    }
+}
+
+fn malformed_xml(reason: impl Into<String>) -> CodecError {
+    CodecError::Malformed { format: "XML", reason: reason.into() }
+}
+
+fn malformed_json(reason: impl Into<String>) -> CodecError {
+    CodecError::Malformed { format: "JsonML", reason: reason.into() }
+}
+
+/// Whether `name` can name an XML element or attribute.
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | ':' | '-' | '.'))
+}
+
+fn write_json_string(out: &mut Vec<u8>, s: &str) {
+    serde_json::to_writer(out, s).expect("strings always serialize");
+}
+
+/// Reads XML as it arrives, writing JsonML.
+#[derive(Default)]
+struct XmlToJson {
+    /// Input not converted yet: an unfinished tag or text run
+    pending: Vec<u8>,
+    /// Names of the open elements, innermost last
+    open: Vec<String>,
+    /// Whether the root element has started
+    seen_root: bool,
+    /// Whether anything but whitespace has been read
+    started: bool,
+}
+
+impl XmlToJson {
+    /// Converts the complete tags and text runs at the start of `input`,
+    /// returning how many bytes were used.
+    fn convert(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<usize, CodecError> {
+        let mut at = 0;
+        while let Some(lt) = input[at..].iter().position(|&b| b == b'<') {
+            self.text(&input[at..at + lt], out)?;
+            at += lt;
+            let markup = &input[at..];
+            let end = if markup.starts_with(b"<!--") {
+                find(markup, b"-->", 4).map(|end| end + 3)
+            } else if markup.starts_with(b"<![CDATA[") {
+                find(markup, b"]]>", 9).map(|end| end + 3)
+            } else if markup.starts_with(b"<?") {
+                find(markup, b"?>", 2).map(|end| end + 2)
+            } else if markup.starts_with(b"<!") {
+                // A `[` before the `>` of a complete declaration opens a DTD
+                let end = find(markup, b">", 2);
+                if end.is_some_and(|end| markup[..end].contains(&b'[')) {
+                    return Err(malformed_xml("internal DTD subsets are not supported"));
+                }
+                end.map(|end| end + 1)
+            } else {
+                tag_end(markup)
+            };
+            // The rest of the markup is still to come
+            let Some(end) = end else { return Ok(at) };
+            let markup = &markup[..end];
+            if let Some(cdata) = markup.strip_prefix(b"<![CDATA[") {
+                self.text_node(utf8(&cdata[..cdata.len() - 3])?, out)?;
+            } else if !markup.starts_with(b"<!") && !markup.starts_with(b"<?") {
+                self.tag(utf8(&markup[1..end - 1])?, out)?;
+            }
+            at += end;
+        }
+        if self.open.is_empty() {
+            // Only whitespace may follow the root, so there is nothing to hold back
+            self.text(&input[at..], out)?;
+            return Ok(input.len());
+        }
+        Ok(at)
+    }
+
+    /// Converts a run of character data.
+    fn text(&mut self, text: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+        if text.iter().all(u8::is_ascii_whitespace) {
+            return Ok(());
+        }
+        let text = unescape(utf8(text)?)?;
+        self.text_node(&text, out)
+    }
+
+    /// Writes `text` as a child of the innermost open element.
+    fn text_node(&mut self, text: &str, out: &mut Vec<u8>) -> Result<(), CodecError> {
+        if self.open.is_empty() {
+            if text.trim().is_empty() {
+                return Ok(());
+            }
+            return Err(malformed_xml("text outside the root element"));
+        }
+        if !text.is_empty() {
+            out.push(b',');
+            write_json_string(out, text);
+        }
+        Ok(())
+    }
+
+    /// Converts a start, end or empty-element tag, given what is between
+    /// its angle brackets.
+    fn tag(&mut self, tag: &str, out: &mut Vec<u8>) -> Result<(), CodecError> {
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim_end();
+            return match self.open.pop() {
+                Some(open) if open == name => {
+                    out.push(b']');
+                    Ok(())
+                }
+                Some(open) => Err(malformed_xml(format!("</{name}> closes <{open}>"))),
+                None => Err(malformed_xml(format!("</{name}> closes nothing"))),
+            };
+        }
+        let (tag, empty) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+        let (name, attributes) = start_tag(tag)?;
+        if self.open.is_empty() {
+            if self.seen_root {
+                return Err(malformed_xml("more than one root element"));
+            }
+            self.seen_root = true;
+        } else {
+            out.push(b',');
+        }
+        out.push(b'[');
+        write_json_string(out, name);
+        if !attributes.is_empty() {
+            out.extend_from_slice(b",{");
+            for (i, (name, value)) in attributes.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_json_string(out, name);
+                out.push(b':');
+                write_json_string(out, value);
+            }
+            out.push(b'}');
+        }
+        if empty {
+            out.push(b']');
+        } else {
+            self.open.push(name.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Transcoder for XmlToJson {
+    fn push(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+        self.started |= input.iter().any(|b| !b.is_ascii_whitespace());
+        self.pending.extend_from_slice(input);
+        let pending = std::mem::take(&mut self.pending);
+        let used = self.convert(&pending, out)?;
+        self.pending = pending[used..].to_vec();
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> Result<(), CodecError> {
+        if !self.pending.is_empty() {
+            return Err(malformed_xml("the document ends inside a tag or element"));
+        }
+        if let Some(open) = self.open.last() {
+            return Err(malformed_xml(format!("<{open}> is never closed")));
+        }
+        if self.started && !self.seen_root {
+            return Err(malformed_xml("no root element"));
+        }
+        Ok(())
+    }
+}
+
+/// Where `needle` starts in `haystack`, looking from `from`.
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?.windows(needle.len()).position(|w| w == needle).map(|at| at + from)
+}
+
+/// Where the tag `markup` starts ends, just past its `>`, skipping quoted
+/// attribute values.
+fn tag_end(markup: &[u8]) -> Option<usize> {
+    let mut quote = None;
+    for (i, &b) in markup.iter().enumerate() {
+        match (quote, b) {
+            (None, b'"' | b'\'') => quote = Some(b),
+            (Some(q), _) if q == b => quote = None,
+            (None, b'>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn utf8(bytes: &[u8]) -> Result<&str, CodecError> {
+    std::str::from_utf8(bytes).map_err(|e| malformed_xml(e.to_string()))
+}
+
+/// An attribute's name and its value, with references replaced.
+type Attribute<'a> = (&'a str, Cow<'a, str>);
+
+/// The name and attributes of a start tag, given what is between its angle
+/// brackets.
+fn start_tag(tag: &str) -> Result<(&str, Vec<Attribute<'_>>), CodecError> {
+    let split = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+    let (name, mut rest) = tag.split_at(split);
+    if !is_xml_name(name) {
+        return Err(malformed_xml(format!("bad element name {name:?}")));
+    }
+    let mut attributes: Vec<Attribute<'_>> = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok((name, attributes));
+        }
+        let (attribute, after) = rest.split_once('=').ok_or_else(|| malformed_xml(format!("<{name}> has a bad attribute")))?;
+        let attribute = attribute.trim_end();
+        if !is_xml_name(attribute) || attributes.iter().any(|(seen, _)| *seen == attribute) {
+            return Err(malformed_xml(format!("<{name}> has a bad or repeated attribute {attribute:?}")));
+        }
+        let after = after.trim_start();
+        let quote = after.chars().next().filter(|c| matches!(c, '"' | '\''));
+        let (value, after) = quote
+            .and_then(|quote| after[1..].split_once(quote))
+            .ok_or_else(|| malformed_xml(format!("attribute {attribute:?} of <{name}> is not quoted")))?;
+        attributes.push((attribute, unescape(value)?));
+        rest = after;
+    }
+}
+
+/// Replaces the predefined entities and character references in `text`.
+fn unescape(text: &str) -> Result<Cow<'_, str>, CodecError> {
+    if !text.contains('&') {
+        return Ok(Cow::Borrowed(text));
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        let len = rest[amp..].find(';').ok_or_else(|| malformed_xml("an unterminated entity reference"))?;
+        let entity = &rest[amp + 1..amp + len];
+        let c = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        out.push(c.ok_or_else(|| malformed_xml(format!("unknown entity &{entity};")))?);
+        rest = &rest[amp + len + 1..];
+    }
+    out.push_str(rest);
+    Ok(Cow::Owned(out))
+}
+
+/// A JSON token JsonML is made of.
+enum Token {
+    Open,
+    Close,
+    OpenObject,
+    CloseObject,
+    Comma,
+    Colon,
+    Str(String),
+}
+
+impl Token {
+    /// The token as an error message names it.
+    fn describe(&self) -> &'static str {
+        match self {
+            Token::Open => "`[`",
+            Token::Close => "`]`",
+            Token::OpenObject => "`{`",
+            Token::CloseObject => "`}`",
+            Token::Comma => "`,`",
+            Token::Colon => "`:`",
+            Token::Str(_) => "a string",
+        }
+    }
+}
+
+/// Where a `JsonToXml` is in the document.
+#[derive(Default)]
+enum Expect {
+    /// The root element
+    #[default]
+    Root,
+    /// An element's name, after its `[`
+    Name,
+    /// The `,` or `]` after an element's name
+    AfterName,
+    /// An element's attributes or its first child
+    FirstItem,
+    /// An attribute's name, or the end of the attributes if there are none
+    Attribute { first: bool },
+    /// The `:` after an attribute's name
+    Colon(String),
+    /// An attribute's value
+    Value(String),
+    /// The `,` or `}` after an attribute
+    AfterAttribute,
+    /// The `,` or `]` after the attributes
+    AfterAttributes,
+    /// A child: text or an element
+    Child,
+    /// The `,` or `]` after a child
+    AfterChild,
+    /// Nothing more; the root element is closed
+    End,
+}
+
+/// Reads JsonML as it arrives, writing XML.
+#[derive(Default)]
+struct JsonToXml {
+    /// Input not converted yet: an unfinished token
+    pending: Vec<u8>,
+    /// Names of the open elements, innermost last
+    open: Vec<String>,
+    expect: Expect,
+}
+
+impl JsonToXml {
+    /// Writes the XML for the next token of the document.
+    fn token(&mut self, token: Token, out: &mut Vec<u8>) -> Result<(), CodecError> {
+        self.expect = match (std::mem::take(&mut self.expect), token) {
+            (Expect::Root, Token::Open) | (Expect::Child, Token::Open) => Expect::Name,
+            (Expect::FirstItem, Token::Open) => {
+                out.push(b'>');
+                Expect::Name
+            }
+            (Expect::Name, Token::Str(name)) => {
+                check_name(&name)?;
+                out.push(b'<');
+                out.extend_from_slice(name.as_bytes());
+                self.open.push(name);
+                Expect::AfterName
+            }
+            (Expect::AfterName, Token::Comma) => Expect::FirstItem,
+            (Expect::AfterName | Expect::AfterAttributes, Token::Close) => {
+                out.extend_from_slice(b"/>");
+                self.open.pop();
+                self.after_element()
+            }
+            (Expect::FirstItem, Token::OpenObject) => Expect::Attribute { first: true },
+            (Expect::FirstItem, Token::Str(text)) => {
+                out.push(b'>');
+                escape(&text, false, out)?;
+                Expect::AfterChild
+            }
+            (Expect::Attribute { first: true }, Token::CloseObject) => Expect::AfterAttributes,
+            (Expect::Attribute { .. }, Token::Str(name)) => {
+                check_name(&name)?;
+                Expect::Colon(name)
+            }
+            (Expect::Colon(name), Token::Colon) => Expect::Value(name),
+            (Expect::Value(name), Token::Str(value)) => {
+                out.push(b' ');
+                out.extend_from_slice(name.as_bytes());
+                out.extend_from_slice(b"=\"");
+                escape(&value, true, out)?;
+                out.push(b'"');
+                Expect::AfterAttribute
+            }
+            (Expect::AfterAttribute, Token::Comma) => Expect::Attribute { first: false },
+            (Expect::AfterAttribute, Token::CloseObject) => Expect::AfterAttributes,
+            (Expect::AfterAttributes, Token::Comma) => {
+                out.push(b'>');
+                Expect::Child
+            }
+            (Expect::Child, Token::Str(text)) => {
+                escape(&text, false, out)?;
+                Expect::AfterChild
+            }
+            (Expect::AfterChild, Token::Comma) => Expect::Child,
+            (Expect::AfterChild, Token::Close) => {
+                let name = self.open.pop().expect("a child is inside an element");
+                out.extend_from_slice(b"</");
+                out.extend_from_slice(name.as_bytes());
+                out.push(b'>');
+                self.after_element()
+            }
+            (Expect::End, token) => return Err(malformed_json(format!("{} after the document", token.describe()))),
+            (_, token) => return Err(malformed_json(format!("unexpected {}", token.describe()))),
+        };
+        Ok(())
+    }
+
+    fn after_element(&self) -> Expect {
+        if self.open.is_empty() { Expect::End } else { Expect::AfterChild }
+    }
+}
+
+impl Transcoder for JsonToXml {
+    fn push(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), CodecError> {
+        self.pending.extend_from_slice(input);
+        let pending = std::mem::take(&mut self.pending);
+        let mut at = 0;
+        while let Some((token, len)) = next_token(&pending[at..])? {
+            self.token(token, out)?;
+            at += len;
+        }
+        self.pending = pending[at..].to_vec();
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> Result<(), CodecError> {
+        if !self.pending.iter().all(u8::is_ascii_whitespace) {
+            return Err(malformed_json("the document ends inside a string"));
+        }
+        match self.expect {
+            // No body stays no body
+            Expect::Root | Expect::End => Ok(()),
+            _ => Err(malformed_json("the document ends early")),
+        }
+    }
+}
+
+/// The next token in `input` and the bytes it takes up, or `None` if it is
+/// still to come.
+fn next_token(input: &[u8]) -> Result<Option<(Token, usize)>, CodecError> {
+    let Some(start) = input.iter().position(|b| !b.is_ascii_whitespace()) else {
+        return Ok(None);
+    };
+    let token = match input[start] {
+        b'[' => Token::Open,
+        b']' => Token::Close,
+        b'{' => Token::OpenObject,
+        b'}' => Token::CloseObject,
+        b',' => Token::Comma,
+        b':' => Token::Colon,
+        b'"' => {
+            let mut i = start + 1;
+            loop {
+                match input.get(i) {
+                    None => return Ok(None),
+                    Some(b'\\') => i += 2,
+                    Some(b'"') => break,
+                    Some(_) => i += 1,
+                }
+            }
+            let text = serde_json::from_slice(&input[start..=i]).map_err(|e| malformed_json(e.to_string()))?;
+            return Ok(Some((Token::Str(text), i + 1)));
+        }
+        other => {
+            let other = char::from(other).escape_default();
+            return Err(malformed_json(format!("only arrays, objects and strings are allowed, not `{other}`")));
+        }
+    };
+    Ok(Some((token, start + 1)))
+}
+
+fn check_name(name: &str) -> Result<(), CodecError> {
+    if is_xml_name(name) {
+        Ok(())
+    } else {
+        Err(CodecError::Unrepresentable { format: "XML", what: format!("the name {name:?}") })
+    }
+}
+
+/// Writes `text` as XML character data, or as an attribute value if `attribute`.
+fn escape(text: &str, attribute: bool, out: &mut Vec<u8>) -> Result<(), CodecError> {
+    for c in text.chars() {
+        match c {
+            '&' => out.extend_from_slice(b"&amp;"),
+            '<' => out.extend_from_slice(b"&lt;"),
+            '>' => out.extend_from_slice(b"&gt;"),
+            '"' if attribute => out.extend_from_slice(b"&quot;"),
+            // Attribute values would have these normalized to spaces
+            '\t' | '\n' | '\r' if attribute => out.extend_from_slice(format!("&#{};", u32::from(c)).as_bytes()),
+            '\t' | '\n' | '\r' => out.push(c as u8),
+            c if c < ' ' => {
+                return Err(CodecError::Unrepresentable { format: "XML", what: format!("the character {c:?}") });
+            }
+            c => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(direction: Direction, chunks: &[&[u8]]) -> Result<Vec<u8>, CodecError> {
+        convert_with(&MessagePackJson, direction, chunks)
+    }
+
+    fn convert_with(codec: &dyn BodyCodec, direction: Direction, chunks: &[&[u8]]) -> Result<Vec<u8>, CodecError> {
+        let mut transcoder = codec.transcoder(direction);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            transcoder.push(chunk, &mut out)?;
+        }
+        transcoder.finish(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_documents_round_trip_across_chunks() {
+        let json = br#"{"id":7,"name":"acme","tags":["a","b"],"ratio":0.5,"active":true,"parent":null}"#;
+        let packed = convert(Direction::ToClient, &[&json[..10], &json[10..]]).unwrap();
+        assert_eq!(packed[0], 0x86, "a fixmap of six entries");
+        let (head, tail) = packed.split_at(3);
+        let back = convert(Direction::ToUpstream, &[head, tail]).unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&back).unwrap();
+        assert_eq!(parsed, serde_json::from_slice::<serde_json::Value>(json).unwrap());
+    }
+
+    #[test]
+    fn test_bad_input_is_reported() {
+        assert_eq!(convert(Direction::ToClient, &[]), Ok(Vec::new()));
+        let truncated = convert(Direction::ToClient, &[b"{\"a\":"]);
+        assert!(matches!(truncated, Err(CodecError::Malformed { format: "JSON", .. })));
+        // A lone fixmap header promising one entry, then nothing
+        assert!(matches!(convert(Direction::ToUpstream, &[&[0x81]]), Err(CodecError::Malformed { .. })));
+        // Two documents where one is expected
+        assert!(matches!(convert(Direction::ToUpstream, &[&[0xc3, 0xc2]]), Err(CodecError::Malformed { .. })));
+        assert!(MessagePackJson.is_client_media_type("Application/X-MsgPack"));
+        assert!(!MessagePackJson.is_client_media_type("application/json"));
+    }
+
+    #[test]
+    fn test_xml_converts_as_it_arrives() {
+        let xml = concat!(
+            r#"<?xml version="1.0"?><!DOCTYPE order><order id="7" note='a &amp; "b"'>"#,
+            "\n  <item sku=\"x-1\">2 &lt; 3</item><!-- gift -->\n  <empty/><![CDATA[<raw>]]>&#x263A;</order>\n",
+        );
+        let json = r#"["order",{"id":"7","note":"a & \"b\""},["item",{"sku":"x-1"},"2 < 3"],["empty"],"<raw>","☺"]"#;
+        let bytes: Vec<&[u8]> = xml.as_bytes().chunks(1).collect();
+        assert_eq!(String::from_utf8(convert_with(&JsonXml, Direction::ToClient, &bytes).unwrap()).unwrap(), json);
+
+        // The start of the document is out before the rest is in
+        let mut transcoder = JsonXml.transcoder(Direction::ToClient);
+        let mut out = Vec::new();
+        transcoder.push(br#"<order id="7"><it"#, &mut out).unwrap();
+        assert_eq!(out, br#"["order",{"id":"7"}"#);
+
+        let back = convert_with(&JsonXml, Direction::ToUpstream, &json.as_bytes().chunks(3).collect::<Vec<_>>());
+        assert_eq!(
+            String::from_utf8(back.unwrap()).unwrap(),
+            r#"<order id="7" note="a &amp; &quot;b&quot;"><item sku="x-1">2 &lt; 3</item><empty/>&lt;raw&gt;☺</order>"#
+        );
+    }
+
+    #[test]
+    fn test_bad_xml_and_jsonml_are_reported() {
+        let to_json = |xml: &str| convert_with(&JsonXml, Direction::ToClient, &[xml.as_bytes()]);
+        assert_eq!(to_json(" \n"), Ok(Vec::new()));
+        for xml in ["<a><b></a>", "<a>", "<a/><b/>", "text", "<a x=1/>", "<a>&nbsp;</a>", "<!DOCTYPE a [<!ENTITY e 'x'>]><a/>"] {
+            assert!(matches!(to_json(xml), Err(CodecError::Malformed { format: "XML", .. })), "{xml}");
+        }
+
+        let to_xml = |json: &str| convert_with(&JsonXml, Direction::ToUpstream, &[json.as_bytes()]);
+        assert_eq!(to_xml(""), Ok(Vec::new()));
+        for json in [r#"["a""#, r#"{"a":1}"#, r#"["a",1]"#, r#"["a",{"x":"1"},{"y":"2"}]"#, r#"["a"]["b"]"#, r#"["a","#] {
+            assert!(matches!(to_xml(json), Err(CodecError::Malformed { format: "JsonML", .. })), "{json}");
+        }
+        assert!(matches!(to_xml(r#"["a b"]"#), Err(CodecError::Unrepresentable { format: "XML", .. })));
+        assert!(JsonXml.is_upstream_media_type("Text/XML"));
+    }
+}
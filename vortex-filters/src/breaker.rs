@@ -0,0 +1,237 @@
+//! Circuit breaking for misbehaving Wasm filters.
+//!
+//! A filter that keeps trapping, or keeps running past its time budget, is
+//! taken out of the chain for a cool-down period instead of failing or
+//! slowing down every request. What happens to requests meanwhile is up to
+//! the policy: skip the filter, or reject them as the filter would have on
+//! failure. After the cool-down the filter gets one trial run; success closes
+//! the breaker, another failure reopens it.
+//!
+//! Wasmtime calls can't be preempted here, so a slow filter still finishes
+//! the call that tripped the breaker; later requests are spared.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What to do with requests while a filter's breaker is open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenAction {
+    /// Skip the filter, as if it weren't in the chain
+    Bypass,
+    /// Reject requests the filter would have seen
+    FailClosed,
+}
+
+/// When a filter's breaker opens and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerPolicy {
+    /// Consecutive failures that open the breaker
+    pub failure_threshold: u32,
+    /// Runs taking longer than this count as failures
+    pub timeout: Duration,
+    /// How long the breaker stays open
+    pub cooldown: Duration,
+    /// What open breakers do with requests
+    pub when_open: OpenAction,
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            timeout: Duration::from_millis(50),
+            cooldown: Duration::from_secs(30),
+            when_open: OpenAction::Bypass,
+        }
+    }
+}
+
+/// A breaker changing state, for logs and metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerEvent {
+    /// The filter failed too often and is out of the chain
+    Opened,
+    /// A trial run succeeded and the filter is back in the chain
+    Closed,
+}
+
+/// One filter's breaker state, detached from the clock so it can be saved
+/// and restored across restarts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakerSnapshot {
+    /// The filter's name
+    pub filter: String,
+    /// Failures since its last success
+    pub consecutive_failures: u32,
+    /// How much longer the breaker stays open, if it is open
+    pub open_for: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    half_open: bool,
+}
+
+/// Breaker state for every filter, keyed by filter name.
+///
+/// Filters sharing a name across route chains share a breaker.
+#[derive(Debug, Default)]
+pub struct FilterBreakers {
+    policy: BreakerPolicy,
+    states: Mutex<HashMap<String, BreakerState>>,
+}
+
+impl FilterBreakers {
+    /// Create breakers following `policy`.
+    pub fn new(policy: BreakerPolicy) -> Self {
+        Self {
+            policy,
+            states: Mutex::default(),
+        }
+    }
+
+    /// The policy the breakers follow.
+    pub fn policy(&self) -> &BreakerPolicy {
+        &self.policy
+    }
+
+    /// Whether `filter` should run at `now`. `false` means its breaker is
+    /// open and `policy().when_open` applies instead.
+    pub fn allows(&self, filter: &str, now: Instant) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(filter) else {
+            return true;
+        };
+        match state.open_until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                // Half-open until the trial run is recorded
+                state.open_until = None;
+                state.half_open = true;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Record a run of `filter` finishing at `now` after `elapsed`, which
+    /// failed if `ok` is false. Returns the state change it caused, if any.
+    pub fn record(&self, filter: &str, ok: bool, elapsed: Duration, now: Instant) -> Option<BreakerEvent> {
+        let failed = !ok || elapsed > self.policy.timeout;
+        let mut states = self.states.lock().unwrap();
+        if !failed {
+            let state = states.remove(filter)?;
+            return state.half_open.then_some(BreakerEvent::Closed);
+        }
+
+        let state = states.entry(filter.to_string()).or_default();
+        state.consecutive_failures += 1;
+        if state.open_until.is_none() && (state.half_open || state.consecutive_failures >= self.policy.failure_threshold) {
+            state.open_until = Some(now + self.policy.cooldown);
+            state.half_open = false;
+            return Some(BreakerEvent::Opened);
+        }
+        None
+    }
+
+    /// Every filter's breaker state as of `now`. A breaker waiting on its
+    /// trial run is reported as open with no time left.
+    pub fn snapshot(&self, now: Instant) -> Vec<BreakerSnapshot> {
+        let states = self.states.lock().unwrap();
+        states
+            .iter()
+            .map(|(filter, state)| BreakerSnapshot {
+                filter: filter.clone(),
+                consecutive_failures: state.consecutive_failures,
+                open_for: match state.open_until {
+                    Some(until) => Some(until.saturating_duration_since(now)),
+                    None => state.half_open.then_some(Duration::ZERO),
+                },
+            })
+            .collect()
+    }
+
+    /// Load breaker state saved by `snapshot`, as of `now`. Open times are
+    /// capped at the policy's cool-down.
+    pub fn restore(&self, snapshots: &[BreakerSnapshot], now: Instant) {
+        let mut states = self.states.lock().unwrap();
+        for snapshot in snapshots {
+            states.insert(
+                snapshot.filter.clone(),
+                BreakerState {
+                    consecutive_failures: snapshot.consecutive_failures,
+                    open_until: snapshot.open_for.map(|left| now + left.min(self.policy.cooldown)),
+                    half_open: false,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_cools_down_and_recovers() {
+        let breakers = FilterBreakers::new(BreakerPolicy {
+            failure_threshold: 2,
+            timeout: Duration::from_millis(10),
+            cooldown: Duration::from_secs(5),
+            when_open: OpenAction::Bypass,
+        });
+        let start = Instant::now();
+        let fast = Duration::from_millis(1);
+
+        assert_eq!(breakers.record("auth", false, fast, start), None);
+        // A slow success is a failure too
+        assert_eq!(breakers.record("auth", true, Duration::from_secs(1), start), Some(BreakerEvent::Opened));
+        assert!(!breakers.allows("auth", start + Duration::from_secs(1)));
+        assert!(breakers.allows("log", start));
+
+        // The trial run fails and reopens it at once
+        let later = start + Duration::from_secs(6);
+        assert!(breakers.allows("auth", later));
+        assert_eq!(breakers.record("auth", false, fast, later), Some(BreakerEvent::Opened));
+        assert!(!breakers.allows("auth", later));
+
+        let much_later = later + Duration::from_secs(6);
+        assert!(breakers.allows("auth", much_later));
+        assert_eq!(breakers.record("auth", true, fast, much_later), Some(BreakerEvent::Closed));
+        assert!(breakers.allows("auth", much_later));
+    }
+
+    #[test]
+    fn test_restored_breakers_stay_open() {
+        let policy = BreakerPolicy {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(5),
+            ..BreakerPolicy::default()
+        };
+        let breakers = FilterBreakers::new(policy);
+        let start = Instant::now();
+        breakers.record("auth", false, Duration::ZERO, start);
+        breakers.record("log", false, Duration::ZERO, start);
+        breakers.record("log", true, Duration::ZERO, start);
+
+        let saved = breakers.snapshot(start + Duration::from_secs(2));
+        assert_eq!(
+            saved,
+            vec![BreakerSnapshot {
+                filter: "auth".into(),
+                consecutive_failures: 1,
+                open_for: Some(Duration::from_secs(3)),
+            }]
+        );
+
+        let restarted = FilterBreakers::new(policy);
+        let later = Instant::now();
+        restarted.restore(&saved, later);
+        assert!(!restarted.allows("auth", later + Duration::from_secs(2)));
+        assert!(restarted.allows("auth", later + Duration::from_secs(3)));
+        assert!(restarted.allows("log", later));
+    }
+}
@@ -0,0 +1,112 @@
+//! Fault injection filter for chaos testing downstream resilience.
+//!
+//! Rules are keyed by a route path prefix and can abort a percentage of requests
+//! with a chosen status, and/or delay requests by a fixed amount plus jitter.
+//! The rule set lives behind an `ArcSwap` so the admin plane can change it at
+//! runtime without locking the request path.
+
+use arc_swap::ArcSwap;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A single fault injection rule applied to requests under a path prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    /// The route path prefix this rule applies to (e.g. `/api/`)
+    pub route_prefix: String,
+    /// Percentage (0-100) of matching requests to abort locally
+    pub abort_percent: f64,
+    /// HTTP status returned for aborted requests
+    pub abort_status: u16,
+    /// Fixed delay added before forwarding matching requests
+    pub delay: Duration,
+    /// Upper bound of the random jitter added on top of `delay`
+    pub delay_jitter: Duration,
+}
+
+/// The outcome of evaluating the fault rules for one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FaultDecision {
+    /// Delay to apply before continuing
+    pub delay: Option<Duration>,
+    /// Status to abort the request with instead of proxying it
+    pub abort_status: Option<u16>,
+}
+
+/// A lock-free, hot-swappable set of fault injection rules.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    rules: ArcSwap<Vec<FaultRule>>,
+}
+
+impl FaultInjector {
+    /// Create an injector with no active rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Install or replace the rule for `rule.route_prefix`.
+    pub fn set_rule(&self, rule: FaultRule) {
+        self.rules.rcu(|rules| {
+            let mut next: Vec<FaultRule> = rules
+                .iter()
+                .filter(|r| r.route_prefix != rule.route_prefix)
+                .cloned()
+                .collect();
+            next.push(rule.clone());
+            next
+        });
+    }
+
+    /// Remove the rule for a route prefix. Returns whether a rule was removed.
+    pub fn clear_rule(&self, route_prefix: &str) -> bool {
+        let previous = self.rules.rcu(|rules| {
+            rules
+                .iter()
+                .filter(|r| r.route_prefix != route_prefix)
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+        previous.iter().any(|r| r.route_prefix == route_prefix)
+    }
+
+    /// Snapshot of the currently active rules.
+    pub fn rules(&self) -> Arc<Vec<FaultRule>> {
+        self.rules.load_full()
+    }
+
+    /// The rule that applies to a request for `path`: the longest matching prefix.
+    pub fn rule_for(&self, path: &str) -> Option<FaultRule> {
+        self.rules
+            .load()
+            .iter()
+            .filter(|r| path.starts_with(&r.route_prefix))
+            .max_by_key(|r| r.route_prefix.len())
+            .cloned()
+    }
+
+    /// Decide which faults, if any, apply to a request for `path`.
+    ///
+    /// When several rules match, the longest prefix wins.
+    pub fn evaluate(&self, path: &str) -> FaultDecision {
+        let Some(rule) = self.rule_for(path) else {
+            return FaultDecision::default();
+        };
+
+        let mut rng = rand::thread_rng();
+
+        let delay = if rule.delay.is_zero() && rule.delay_jitter.is_zero() {
+            None
+        } else {
+            let jitter_ms = rule.delay_jitter.as_millis() as u64;
+            let jitter = if jitter_ms == 0 { 0 } else { rng.gen_range(0..=jitter_ms) };
+            Some(rule.delay.saturating_add(Duration::from_millis(jitter)))
+        };
+
+        let abort_status = (rule.abort_percent > 0.0 && rng.gen_range(0.0..100.0) < rule.abort_percent)
+            .then_some(rule.abort_status);
+
+        FaultDecision { delay, abort_status }
+    }
+}
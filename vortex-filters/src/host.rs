@@ -0,0 +1,126 @@
+//! The host environment Wasm filters can import.
+//!
+//! Filters see one HTTP message (a request or a response) through the
+//! functions below, imported from the `vortex` module. Strings cross the
+//! boundary as `(ptr, len)` pairs into the filter's exported `memory`; reads
+//! copy into a caller-supplied `(out_ptr, out_cap)` buffer and return the
+//! full length, or -1 if there is nothing to read, so a filter can retry with
+//! a bigger buffer.
+//!
+//! | import | signature |
+//! |---|---|
+//! | `header_get` | `(name_ptr, name_len, out_ptr, out_cap) -> i32` |
+//! | `header_set` | `(name_ptr, name_len, value_ptr, value_len)` |
+//! | `header_remove` | `(name_ptr, name_len)` |
+//! | `kv_get` | `(key_ptr, key_len, out_ptr, out_cap) -> i32` |
+//! | `kv_set` | `(key_ptr, key_len, value_ptr, value_len)` |
+//! | `now_ms` | `() -> i64` |
+//! | `callout` | `(target_ptr, target_len) -> i32` (the response status) |
+//!
+//! Request pseudo-headers (`:method`, `:path`, `:status` on responses) are
+//! readable like any other header.
+
+use wasmtime::{Caller, Extern, Linker, Memory};
+
+/// What a filter's imports are served by.
+pub trait FilterHost: Send {
+    /// The value of header `name` of the message being filtered.
+    fn header(&self, name: &str) -> Option<String>;
+    /// Set header `name`, replacing any existing values.
+    fn set_header(&mut self, name: &str, value: &str);
+    /// Remove header `name`.
+    fn remove_header(&mut self, name: &str);
+    /// The value stored under `key` in the filter's key-value store.
+    fn kv_get(&self, key: &str) -> Option<Vec<u8>>;
+    /// Store `value` under `key`.
+    fn kv_set(&mut self, key: &str, value: &[u8]);
+    /// Wall-clock time in milliseconds since the Unix epoch.
+    fn now_ms(&self) -> i64;
+    /// Call out to `target`, returning the response status.
+    fn callout(&mut self, target: &str) -> Result<i32, String>;
+}
+
+/// Define the `vortex` imports on `linker`, served by the store's host.
+pub fn link<H: FilterHost + 'static>(linker: &mut Linker<H>) -> wasmtime::Result<()> {
+    linker.func_wrap(
+        "vortex",
+        "header_get",
+        |mut caller: Caller<'_, H>, ptr: i32, len: i32, out: i32, cap: i32| {
+            let name = read_str(&mut caller, ptr, len)?;
+            let value = caller.data().header(&name).map(String::into_bytes);
+            write_out(&mut caller, value, out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "vortex",
+        "header_set",
+        |mut caller: Caller<'_, H>, ptr: i32, len: i32, value_ptr: i32, value_len: i32| {
+            let name = read_str(&mut caller, ptr, len)?;
+            let value = read_str(&mut caller, value_ptr, value_len)?;
+            caller.data_mut().set_header(&name, &value);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap("vortex", "header_remove", |mut caller: Caller<'_, H>, ptr: i32, len: i32| {
+        let name = read_str(&mut caller, ptr, len)?;
+        caller.data_mut().remove_header(&name);
+        Ok(())
+    })?;
+    linker.func_wrap(
+        "vortex",
+        "kv_get",
+        |mut caller: Caller<'_, H>, ptr: i32, len: i32, out: i32, cap: i32| {
+            let key = read_str(&mut caller, ptr, len)?;
+            let value = caller.data().kv_get(&key);
+            write_out(&mut caller, value, out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "vortex",
+        "kv_set",
+        |mut caller: Caller<'_, H>, ptr: i32, len: i32, value_ptr: i32, value_len: i32| {
+            let key = read_str(&mut caller, ptr, len)?;
+            let value = read_bytes(&mut caller, value_ptr, value_len)?;
+            caller.data_mut().kv_set(&key, &value);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap("vortex", "now_ms", |caller: Caller<'_, H>| caller.data().now_ms())?;
+    linker.func_wrap("vortex", "callout", |mut caller: Caller<'_, H>, ptr: i32, len: i32| {
+        let target = read_str(&mut caller, ptr, len)?;
+        caller.data_mut().callout(&target).map_err(wasmtime::Error::msg)
+    })?;
+    Ok(())
+}
+
+fn memory<H>(caller: &mut Caller<'_, H>) -> wasmtime::Result<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        _ => Err(wasmtime::Error::msg("filter does not export `memory`")),
+    }
+}
+
+fn read_bytes<H>(caller: &mut Caller<'_, H>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let memory = memory(caller)?;
+    let start = ptr as u32 as usize;
+    let end = start + len as u32 as usize;
+    memory
+        .data(&caller)
+        .get(start..end)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| wasmtime::Error::msg("host call read out of bounds"))
+}
+
+fn read_str<H>(caller: &mut Caller<'_, H>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    String::from_utf8(read_bytes(caller, ptr, len)?).map_err(|_| wasmtime::Error::msg("host call string is not UTF-8"))
+}
+
+fn write_out<H>(caller: &mut Caller<'_, H>, value: Option<Vec<u8>>, out: i32, cap: i32) -> wasmtime::Result<i32> {
+    let Some(value) = value else {
+        return Ok(-1);
+    };
+    let memory = memory(caller)?;
+    let n = value.len().min(cap.max(0) as usize);
+    memory.write(&mut *caller, out as u32 as usize, &value[..n])?;
+    Ok(value.len() as i32)
+}
@@ -0,0 +1,440 @@
+//! Streaming redaction of JSON object fields.
+//!
+//! Bodies are rewritten chunk by chunk as they pass through, so memory stays
+//! bounded by the nesting depth and the longest configured field name no
+//! matter how large the document is. Matching fields are looked up at any
+//! depth by their raw (still escaped) key, and either have their value masked
+//! or are removed from the object altogether.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// The string a masked value is replaced with.
+pub const MASK: &str = "\"[REDACTED]\"";
+
+/// Deepest nesting a redactor tracks before giving up on a document.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// What happens to a matching field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedactionMode {
+    /// Keep the key but replace its value with `"[REDACTED]"`
+    Mask,
+    /// Remove the key and its value from the object
+    Strip,
+}
+
+/// Why a document could not be redacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum RedactionError {
+    /// The input is not valid JSON
+    #[error("malformed JSON at byte {offset}")]
+    Malformed {
+        /// Offset of the offending byte in the whole document
+        offset: usize,
+    },
+    /// The input ended in the middle of a value
+    #[error("truncated JSON document")]
+    Truncated,
+    /// The document nests deeper than the configured maximum
+    #[error("JSON nested deeper than {max_depth} levels")]
+    TooDeep {
+        /// The configured maximum
+        max_depth: usize,
+    },
+}
+
+/// Which fields to redact, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonRedaction {
+    fields: HashSet<Vec<u8>>,
+    longest_field: usize,
+    mode: RedactionMode,
+    max_depth: usize,
+}
+
+impl JsonRedaction {
+    /// Redact the given field names wherever they occur.
+    pub fn new<I, S>(fields: I, mode: RedactionMode) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let fields: HashSet<Vec<u8>> = fields.into_iter().map(|f| f.into().into_bytes()).collect();
+        Self {
+            longest_field: fields.iter().map(Vec::len).max().unwrap_or(0),
+            fields,
+            mode,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Set the deepest nesting accepted, builder style.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Start redacting a new document.
+    pub fn redactor(self: &Arc<Self>) -> JsonRedactor {
+        JsonRedactor {
+            config: self.clone(),
+            stack: Vec::new(),
+            expect: Expect::Value,
+            token: Token::None,
+            key: Vec::new(),
+            member: Member::Keep,
+            offset: 0,
+        }
+    }
+}
+
+/// An open container.
+#[derive(Debug, Clone, Copy)]
+enum Frame {
+    /// `wrote_member` decides whether the next kept member needs a comma
+    Object { wrote_member: bool },
+    Array,
+}
+
+/// What the next significant byte may be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    /// Any value
+    Value,
+    /// Any value or `]`, right after `[`
+    ValueOrEnd,
+    /// A key or `}`, right after `{`
+    KeyOrEnd,
+    /// A key, after a comma
+    Key,
+    /// `:` after a key
+    Colon,
+    /// `,` or the end of the enclosing container
+    CommaOrEnd,
+    /// Nothing but whitespace, after the top-level value
+    Done,
+}
+
+/// What the current member's value is turned into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Member {
+    Keep,
+    Mask,
+    Strip,
+}
+
+/// The multi-byte token being read, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    None,
+    /// A string, `emit` telling whether it is written out
+    String { emit: bool, escaped: bool },
+    /// A key still being buffered to see whether it matches
+    Key { escaped: bool },
+    /// A key too long to match anything, written out as it arrives
+    LongKey { escaped: bool },
+    /// A number or literal
+    Scalar { emit: bool },
+    /// A masked or stripped object or array, `depth` levels deep
+    Skip { depth: usize, in_string: bool, escaped: bool },
+}
+
+/// Redacts one document incrementally.
+#[derive(Debug)]
+pub struct JsonRedactor {
+    config: Arc<JsonRedaction>,
+    stack: Vec<Frame>,
+    expect: Expect,
+    token: Token,
+    key: Vec<u8>,
+    member: Member,
+    offset: usize,
+}
+
+impl JsonRedactor {
+    /// Redact the next chunk of the document, appending the output to `out`.
+    pub fn push(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> Result<(), RedactionError> {
+        for &b in chunk {
+            self.byte(b, out)?;
+            self.offset += 1;
+        }
+        Ok(())
+    }
+
+    /// Check that the document is complete once the input has ended.
+    pub fn finish(&mut self) -> Result<(), RedactionError> {
+        // A top-level number has no delimiter to end it
+        if let Token::Scalar { .. } = self.token {
+            self.token = Token::None;
+            self.after_value();
+        }
+        if self.expect == Expect::Done && self.token == Token::None {
+            Ok(())
+        } else {
+            Err(RedactionError::Truncated)
+        }
+    }
+
+    fn malformed(&self) -> RedactionError {
+        RedactionError::Malformed { offset: self.offset }
+    }
+
+    fn byte(&mut self, b: u8, out: &mut Vec<u8>) -> Result<(), RedactionError> {
+        match self.token {
+            Token::String { emit, escaped } => {
+                if emit {
+                    out.push(b);
+                }
+                self.token = match (escaped, b) {
+                    (false, b'"') => {
+                        self.after_value();
+                        Token::None
+                    }
+                    (false, b'\\') => Token::String { emit, escaped: true },
+                    _ => Token::String { emit, escaped: false },
+                };
+                return Ok(());
+            }
+            Token::Key { escaped } => {
+                if !escaped && b == b'"' {
+                    self.token = Token::None;
+                    self.end_key(out);
+                    return Ok(());
+                }
+                self.key.push(b);
+                self.token = Token::Key { escaped: !escaped && b == b'\\' };
+                if self.key.len() > self.config.longest_field {
+                    // Too long to match anything: stop buffering
+                    self.write_key(out);
+                    self.token = Token::LongKey { escaped: !escaped && b == b'\\' };
+                }
+                return Ok(());
+            }
+            Token::LongKey { escaped } => {
+                out.push(b);
+                self.token = match (escaped, b) {
+                    (false, b'"') => {
+                        self.expect = Expect::Colon;
+                        Token::None
+                    }
+                    (false, b'\\') => Token::LongKey { escaped: true },
+                    _ => Token::LongKey { escaped: false },
+                };
+                return Ok(());
+            }
+            Token::Skip { depth, in_string, escaped } => {
+                self.token = match (in_string, escaped, b) {
+                    (true, false, b'"') => Token::Skip { depth, in_string: false, escaped: false },
+                    (true, false, b'\\') => Token::Skip { depth, in_string: true, escaped: true },
+                    (true, _, _) => Token::Skip { depth, in_string: true, escaped: false },
+                    (false, _, b'"') => Token::Skip { depth, in_string: true, escaped: false },
+                    (false, _, b'{' | b'[') => {
+                        if self.stack.len() + depth >= self.config.max_depth {
+                            return Err(RedactionError::TooDeep { max_depth: self.config.max_depth });
+                        }
+                        Token::Skip { depth: depth + 1, in_string: false, escaped: false }
+                    }
+                    (false, _, b'}' | b']') if depth == 1 => {
+                        self.after_value();
+                        Token::None
+                    }
+                    (false, _, b'}' | b']') => Token::Skip { depth: depth - 1, in_string: false, escaped: false },
+                    _ => self.token,
+                };
+                return Ok(());
+            }
+            Token::Scalar { emit } => {
+                if is_scalar_byte(b) {
+                    if emit {
+                        out.push(b);
+                    }
+                    return Ok(());
+                }
+                self.token = Token::None;
+                self.after_value();
+            }
+            Token::None => {}
+        }
+
+        if b.is_ascii_whitespace() {
+            // Whitespace inside a stripped member or before a deferred comma is dropped
+            let stripping = self.config.mode == RedactionMode::Strip;
+            if self.member != Member::Strip && !(stripping && self.expect == Expect::Key) {
+                out.push(b);
+            }
+            return Ok(());
+        }
+
+        match self.expect {
+            Expect::Value | Expect::ValueOrEnd if b == b']' && self.expect == Expect::ValueOrEnd => {
+                self.close(b, out);
+            }
+            Expect::Value | Expect::ValueOrEnd => self.start_value(b, out)?,
+            Expect::KeyOrEnd | Expect::Key if b == b'"' => {
+                self.key.clear();
+                self.token = Token::Key { escaped: false };
+            }
+            Expect::KeyOrEnd if b == b'}' => self.close(b, out),
+            Expect::Colon if b == b':' => {
+                if self.member != Member::Strip {
+                    out.push(b);
+                }
+                self.expect = Expect::Value;
+            }
+            Expect::CommaOrEnd => match (self.stack.last(), b) {
+                // When stripping, object commas are written before the next kept member instead
+                (Some(Frame::Object { .. }), b',') => {
+                    if self.config.mode == RedactionMode::Mask {
+                        out.push(b);
+                    }
+                    self.expect = Expect::Key;
+                }
+                (Some(Frame::Array), b',') => {
+                    out.push(b);
+                    self.expect = Expect::Value;
+                }
+                (Some(Frame::Object { .. }), b'}') | (Some(Frame::Array), b']') => self.close(b, out),
+                _ => return Err(self.malformed()),
+            },
+            _ => return Err(self.malformed()),
+        }
+        Ok(())
+    }
+
+    fn start_value(&mut self, b: u8, out: &mut Vec<u8>) -> Result<(), RedactionError> {
+        if !(matches!(b, b'{' | b'[' | b'"' | b'-') || b.is_ascii_alphanumeric()) {
+            return Err(self.malformed());
+        }
+        let member = std::mem::replace(&mut self.member, Member::Keep);
+        if member != Member::Keep {
+            if member == Member::Mask {
+                out.extend_from_slice(MASK.as_bytes());
+            }
+            // Restore the mode until the skipped value ends
+            self.member = member;
+            self.token = match b {
+                b'{' | b'[' => Token::Skip { depth: 1, in_string: false, escaped: false },
+                b'"' => Token::String { emit: false, escaped: false },
+                _ => Token::Scalar { emit: false },
+            };
+            return Ok(());
+        }
+
+        match b {
+            b'{' | b'[' => {
+                if self.stack.len() >= self.config.max_depth {
+                    return Err(RedactionError::TooDeep { max_depth: self.config.max_depth });
+                }
+                out.push(b);
+                if b == b'{' {
+                    self.stack.push(Frame::Object { wrote_member: false });
+                    self.expect = Expect::KeyOrEnd;
+                } else {
+                    self.stack.push(Frame::Array);
+                    self.expect = Expect::ValueOrEnd;
+                }
+            }
+            b'"' => {
+                out.push(b);
+                self.token = Token::String { emit: true, escaped: false };
+            }
+            _ => {
+                out.push(b);
+                self.token = Token::Scalar { emit: true };
+            }
+        }
+        Ok(())
+    }
+
+    /// Decides the fate of the member whose key was just read.
+    fn end_key(&mut self, out: &mut Vec<u8>) {
+        self.member = match (self.config.fields.contains(&self.key), self.config.mode) {
+            (false, _) => Member::Keep,
+            (true, RedactionMode::Mask) => Member::Mask,
+            (true, RedactionMode::Strip) => Member::Strip,
+        };
+        if self.member != Member::Strip {
+            self.write_key(out);
+            out.push(b'"');
+        }
+        self.expect = Expect::Colon;
+    }
+
+    /// Writes the comma owed to the previous member and the buffered key,
+    /// without its closing quote.
+    fn write_key(&mut self, out: &mut Vec<u8>) {
+        if let Some(Frame::Object { wrote_member }) = self.stack.last_mut() {
+            if *wrote_member && self.config.mode == RedactionMode::Strip {
+                out.push(b',');
+            }
+            *wrote_member = true;
+        }
+        out.push(b'"');
+        out.extend_from_slice(&self.key);
+    }
+
+    fn close(&mut self, b: u8, out: &mut Vec<u8>) {
+        out.push(b);
+        self.stack.pop();
+        self.after_value();
+    }
+
+    fn after_value(&mut self) {
+        self.member = Member::Keep;
+        self.expect = if self.stack.is_empty() { Expect::Done } else { Expect::CommaOrEnd };
+    }
+}
+
+fn is_scalar_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'+' | b'.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(config: &Arc<JsonRedaction>, input: &str, chunk: usize) -> Result<String, RedactionError> {
+        let mut redactor = config.redactor();
+        let mut out = Vec::new();
+        for piece in input.as_bytes().chunks(chunk) {
+            redactor.push(piece, &mut out)?;
+        }
+        redactor.finish()?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    const DOC: &str = r#"{"user": {"name": "ada", "password": "hunter2", "ssn": {"a": [1, "}"]}}, "ok": true, "password": 12}"#;
+
+    #[test]
+    fn test_mask_keeps_keys() {
+        let config = Arc::new(JsonRedaction::new(["password", "ssn"], RedactionMode::Mask));
+        for chunk in [1, 3, DOC.len()] {
+            assert_eq!(
+                redact(&config, DOC, chunk).unwrap(),
+                r#"{"user": {"name": "ada", "password": "[REDACTED]", "ssn": "[REDACTED]"}, "ok": true, "password": "[REDACTED]"}"#
+            );
+        }
+    }
+
+    #[test]
+    fn test_strip_removes_members_and_their_commas() {
+        let config = Arc::new(JsonRedaction::new(["password", "ssn", "name"], RedactionMode::Strip));
+        for chunk in [1, 5, DOC.len()] {
+            assert_eq!(redact(&config, DOC, chunk).unwrap(), r#"{"user": {},"ok": true}"#);
+        }
+        assert_eq!(
+            redact(&config, r#"[{"name":1},{"a_much_longer_key":"x","ssn":0}]"#, 2).unwrap(),
+            r#"[{},{"a_much_longer_key":"x"}]"#
+        );
+    }
+
+    #[test]
+    fn test_bad_documents_are_rejected() {
+        let config = Arc::new(JsonRedaction::new(["password"], RedactionMode::Mask).with_max_depth(3));
+        assert_eq!(redact(&config, r#"{"a" 1}"#, 4), Err(RedactionError::Malformed { offset: 5 }));
+        assert_eq!(redact(&config, r#"{"a": [1, 2"#, 4), Err(RedactionError::Truncated));
+        assert_eq!(redact(&config, "[[[[1]]]]", 4), Err(RedactionError::TooDeep { max_depth: 3 }));
+        assert_eq!(redact(&config, "42", 1).unwrap(), "42");
+    }
+}
@@ -2,9 +2,17 @@
 //!
 //! Exposes WebAssembly plugin execution via Wasmtime for dynamic proxy filters.
 
+pub mod body_codec;
+pub mod breaker;
+pub mod chain;
+pub mod fault_injection;
+pub mod host;
+pub mod json_redaction;
+#[cfg(feature = "lua")]
+pub mod lua;
 pub mod wasm_engine;
 
 /// Initializes the WebAssembly filters runtime.
 pub fn filters_init() {
-    println!("vortex-filters initialized");
+    tracing::debug!("vortex-filters initialized");
 }
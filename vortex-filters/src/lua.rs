@@ -0,0 +1,173 @@
+//! Lua scripting filters, for header and routing logic too small to be worth
+//! compiling to Wasm.
+//!
+//! Each run gets a fresh interpreter with only the `string`, `table`, and
+//! `math` libraries (no `io`, `os`, or module loading), an instruction
+//! budget, and a memory cap. The script sees a global `request` table:
+//!
+//! ```lua
+//! -- request.method, request.path, request.headers["x-api-key"]
+//! if request.headers["x-api-key"] == nil then return 401 end
+//! request.headers["x-tenant"] = string.lower(request.headers["x-api-key"])
+//! if request.backend["canary"] == "true" then request.headers["x-canary"] = "1" end
+//! ```
+//!
+//! `request.backend` holds the metadata of the backend the request was
+//! routed to; changes to it are ignored. Header names are lowercase. Changes to `request.headers` are applied to
+//! the request (a header set to `nil` is removed). The script returns a
+//! status code like a Wasm filter's `execute`, or nothing to pass.
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::wasm_engine::FilterError;
+
+/// How many instructions run between budget checks.
+const BUDGET_CHECK_INTERVAL: u32 = 1000;
+
+/// The parts of a request a script can read and change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptRequest {
+    /// Request method
+    pub method: String,
+    /// Request path and query
+    pub path: String,
+    /// Header values by lowercase name (repeated headers joined with `, `)
+    pub headers: BTreeMap<String, String>,
+    /// Metadata of the backend the request was routed to, read-only
+    pub backend: BTreeMap<String, String>,
+}
+
+/// A sandboxed Lua filter script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LuaFilter {
+    source: Arc<str>,
+    instruction_budget: u64,
+    memory_limit: usize,
+}
+
+impl LuaFilter {
+    /// Wrap `source`, allowing 1M instructions and 8 MiB per run.
+    pub fn new(source: impl Into<Arc<str>>) -> Self {
+        Self {
+            source: source.into(),
+            instruction_budget: 1_000_000,
+            memory_limit: 8 * 1024 * 1024,
+        }
+    }
+
+    /// Set the instruction budget per run, builder style. Checked every
+    /// 1000 instructions, so a script may overrun it slightly.
+    pub fn with_instruction_budget(mut self, instructions: u64) -> Self {
+        self.instruction_budget = instructions;
+        self
+    }
+
+    /// Set the memory cap per run, builder style.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Run the script against `request`, applying its header changes.
+    /// Returns the script's status code, 200 if it returned nothing.
+    pub fn run(&self, request: &mut ScriptRequest) -> Result<i32, FilterError> {
+        let lua = Lua::new_with(StdLib::STRING | StdLib::TABLE | StdLib::MATH, LuaOptions::default()).map_err(script_error)?;
+        lua.set_memory_limit(self.memory_limit).map_err(script_error)?;
+        let budget = self.instruction_budget;
+        let executed = AtomicU64::new(0);
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(BUDGET_CHECK_INTERVAL),
+            move |_, _| {
+                let total = executed.fetch_add(BUDGET_CHECK_INTERVAL.into(), Ordering::Relaxed) + u64::from(BUDGET_CHECK_INTERVAL);
+                if total > budget {
+                    return Err(mlua::Error::runtime("instruction budget exhausted"));
+                }
+                Ok(())
+            },
+        );
+
+        let headers = lua.create_table_from(request.headers.clone()).map_err(script_error)?;
+        let table = lua.create_table().map_err(script_error)?;
+        table.set("method", request.method.as_str()).map_err(script_error)?;
+        table.set("path", request.path.as_str()).map_err(script_error)?;
+        table.set("headers", headers).map_err(script_error)?;
+        let backend = lua.create_table_from(request.backend.clone()).map_err(script_error)?;
+        table.set("backend", backend).map_err(script_error)?;
+        lua.globals().set("request", table.clone()).map_err(script_error)?;
+
+        let status: Option<i32> = lua.load(&*self.source).set_name("filter").eval().map_err(script_error)?;
+
+        let headers: Table = table.get("headers").map_err(script_error)?;
+        request.headers = headers
+            .pairs::<String, String>()
+            .map(|pair| pair.map(|(name, value)| (name.to_ascii_lowercase(), value)))
+            .collect::<mlua::Result<_>>()
+            .map_err(script_error)?;
+        Ok(status.unwrap_or(200))
+    }
+}
+
+fn script_error(err: mlua::Error) -> FilterError {
+    FilterError::Script(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> ScriptRequest {
+        ScriptRequest {
+            method: "GET".into(),
+            path: "/".into(),
+            headers: [("x-api-key".to_string(), "ACME".to_string()), ("cookie".to_string(), "a=b".to_string())].into(),
+            backend: [("canary".to_string(), "true".to_string())].into(),
+        }
+    }
+
+    #[test]
+    fn test_scripts_change_headers_and_pick_status() {
+        let filter = LuaFilter::new(
+            r#"
+            if request.headers["x-api-key"] == nil then return 401 end
+            request.headers["x-tenant"] = string.lower(request.headers["x-api-key"])
+            request.headers["cookie"] = nil
+            "#,
+        );
+        let mut req = request();
+        assert_eq!(filter.run(&mut req).unwrap(), 200);
+        assert_eq!(req.headers["x-tenant"], "acme");
+        assert!(!req.headers.contains_key("cookie"));
+
+        req.headers.clear();
+        assert_eq!(filter.run(&mut req).unwrap(), 401);
+    }
+
+    #[test]
+    fn test_scripts_read_backend_metadata() {
+        let filter = LuaFilter::new(
+            r#"
+            if request.backend["canary"] == "true" then request.headers["x-canary"] = "1" end
+            request.backend["canary"] = "false"
+            "#,
+        );
+        let mut req = request();
+        filter.run(&mut req).unwrap();
+        assert_eq!(req.headers["x-canary"], "1");
+        assert_eq!(req.backend["canary"], "true");
+    }
+
+    #[test]
+    fn test_scripts_are_sandboxed() {
+        let spin = LuaFilter::new("while true do end").with_instruction_budget(10_000);
+        assert!(spin.run(&mut request()).unwrap_err().to_string().contains("budget"));
+
+        let escape = LuaFilter::new("return os.exit(1)");
+        assert!(matches!(escape.run(&mut request()), Err(FilterError::Script(_))));
+
+        let hog = LuaFilter::new("local s = string.rep('x', 64 * 1024 * 1024)").with_memory_limit(1024 * 1024);
+        assert!(hog.run(&mut request()).is_err());
+    }
+}
@@ -1,7 +1,33 @@
 //! Wasmtime Engine integration for WebAssembly proxy plugins.
 
+use thiserror::Error;
 use wasmtime::*;
 
+use crate::host::{self, FilterHost};
+
+/// Errors raised while compiling or running a WebAssembly filter.
+///
+/// `wasmtime::Error` is an `anyhow` error, so it is rendered into the message
+/// rather than exposed as a `source`.
+#[derive(Debug, Error)]
+pub enum FilterError {
+    /// The module bytes could not be compiled.
+    #[error("failed to compile Wasm filter: {0:#}")]
+    Compile(wasmtime::Error),
+    /// The module could not be instantiated (e.g. missing imports).
+    #[error("failed to instantiate Wasm filter: {0:#}")]
+    Instantiate(wasmtime::Error),
+    /// The module does not export a compatible `execute` function.
+    #[error("Wasm filter is missing a `() -> i32` `execute` export: {0:#}")]
+    MissingExport(wasmtime::Error),
+    /// The filter trapped while executing.
+    #[error("Wasm filter trapped: {0:#}")]
+    Trap(wasmtime::Error),
+    /// A script filter failed to load or run.
+    #[error("script filter failed: {0}")]
+    Script(String),
+}
+
 /// Manages the WebAssembly engine, configuration, and module instantiation.
 pub struct WasmEngine {
     engine: Engine,
@@ -23,12 +49,30 @@ impl WasmEngine {
     }
 
     /// Executes a simple WebAssembly module by executing 'execute' export.
-    pub fn execute_filter(&self, wasm_bytes: &[u8]) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
-        let module = Module::new(&self.engine, wasm_bytes)?;
+    pub fn execute_filter(&self, wasm_bytes: &[u8]) -> Result<i32, FilterError> {
+        let module = Module::new(&self.engine, wasm_bytes).map_err(FilterError::Compile)?;
         let mut store = Store::new(&self.engine, ());
-        let instance = Instance::new(&mut store, &module, &[])?;
-        let execute = instance.get_typed_func::<(), i32>(&mut store, "execute")?;
-        let result = execute.call(&mut store, ())?;
+        let instance = Instance::new(&mut store, &module, &[]).map_err(FilterError::Instantiate)?;
+        let execute = instance
+            .get_typed_func::<(), i32>(&mut store, "execute")
+            .map_err(FilterError::MissingExport)?;
+        let result = execute.call(&mut store, ()).map_err(FilterError::Trap)?;
         Ok(result)
     }
+
+    /// Executes a filter that may import the `vortex` host functions (see
+    /// `crate::host`), served by `host`. Returns the exit code and the host,
+    /// with whatever the filter changed.
+    pub fn execute_with_host<H: FilterHost + 'static>(&self, wasm_bytes: &[u8], host: H) -> Result<(i32, H), FilterError> {
+        let module = Module::new(&self.engine, wasm_bytes).map_err(FilterError::Compile)?;
+        let mut linker = Linker::new(&self.engine);
+        host::link(&mut linker).map_err(FilterError::Instantiate)?;
+        let mut store = Store::new(&self.engine, host);
+        let instance = linker.instantiate(&mut store, &module).map_err(FilterError::Instantiate)?;
+        let execute = instance
+            .get_typed_func::<(), i32>(&mut store, "execute")
+            .map_err(FilterError::MissingExport)?;
+        let result = execute.call(&mut store, ()).map_err(FilterError::Trap)?;
+        Ok((result, store.into_data()))
+    }
 }
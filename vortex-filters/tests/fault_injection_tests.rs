@@ -0,0 +1,45 @@
+//! Integration tests for the fault injection filter.
+
+use std::time::Duration;
+use vortex_filters::fault_injection::{FaultDecision, FaultInjector, FaultRule};
+
+fn rule(prefix: &str, abort_percent: f64, delay_ms: u64) -> FaultRule {
+    FaultRule {
+        route_prefix: prefix.to_string(),
+        abort_percent,
+        abort_status: 503,
+        delay: Duration::from_millis(delay_ms),
+        delay_jitter: Duration::ZERO,
+    }
+}
+
+#[test]
+fn test_no_rules_means_no_faults() {
+    let injector = FaultInjector::new();
+    assert_eq!(injector.evaluate("/api/users"), FaultDecision::default());
+}
+
+#[test]
+fn test_longest_prefix_rule_wins() {
+    let injector = FaultInjector::new();
+    injector.set_rule(rule("/", 0.0, 10));
+    injector.set_rule(rule("/api/", 100.0, 0));
+
+    let api = injector.evaluate("/api/users");
+    assert_eq!(api.abort_status, Some(503));
+    assert_eq!(api.delay, None);
+
+    let other = injector.evaluate("/static/logo.png");
+    assert_eq!(other.abort_status, None);
+    assert_eq!(other.delay, Some(Duration::from_millis(10)));
+}
+
+#[test]
+fn test_clear_rule_restores_traffic() {
+    let injector = FaultInjector::new();
+    injector.set_rule(rule("/api/", 100.0, 0));
+
+    assert!(injector.clear_rule("/api/"));
+    assert!(!injector.clear_rule("/api/"));
+    assert_eq!(injector.evaluate("/api/users").abort_status, None);
+}
@@ -1,6 +1,6 @@
 //! Integration tests for executing WebAssembly (Wasm) filter plugins natively.
 
-use vortex_filters::wasm_engine::WasmEngine;
+use vortex_filters::wasm_engine::{FilterError, WasmEngine};
 
 #[test]
 fn test_execute_wat_filter() {
@@ -19,3 +19,12 @@ fn test_execute_wat_filter() {
     let result = engine.execute_filter(wat.as_bytes()).expect("Failed to execute WASM module");
     assert_eq!(result, 200);
 }
+
+#[test]
+fn test_missing_export_is_classified() {
+    let engine = WasmEngine::new();
+    let wat = r#"(module (func (export "other") (result i32) i32.const 1))"#;
+
+    let err = engine.execute_filter(wat.as_bytes()).unwrap_err();
+    assert!(matches!(err, FilterError::MissingExport(_)));
+}
@@ -0,0 +1,48 @@
+//! Cost of pointing a request at its backend: the interned targets against
+//! formatting and re-parsing the URI and `Host` header per request.
+
+// `criterion_group!` expands to an undocumented public function
+#![allow(missing_docs)]
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use hyper::header::HOST;
+use hyper::Request;
+use std::hint::black_box;
+use std::net::SocketAddr;
+use vortex_core::domain::backend::{Backend, BackendId};
+use vortex_proxy::upstream_target::UpstreamTargets;
+
+fn request() -> Request<()> {
+    Request::builder()
+        .uri("/api/v2/orders/8f14e45f?expand=items&currency=EUR")
+        .header(HOST, "shop.example.com")
+        .header("user-agent", "bench/1.0")
+        .header("accept", "application/json")
+        .body(())
+        .unwrap()
+}
+
+/// The previous behavior: format the absolute URI and the address, then parse both.
+fn format_and_parse(req: &mut Request<()>, addr: SocketAddr) {
+    let uri = format!("http://{}{}", addr, req.uri().path_and_query().map(|x| x.as_str()).unwrap_or("/"));
+    *req.uri_mut() = uri.parse().unwrap();
+    req.headers_mut().insert(HOST, addr.to_string().parse().unwrap());
+}
+
+fn bench_point_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("point_request");
+    let addr: SocketAddr = "10.20.30.40:8080".parse().unwrap();
+    let backend = Backend::new(BackendId(1), addr);
+    let targets = UpstreamTargets::new();
+
+    group.bench_function("interned", |b| {
+        b.iter_batched_ref(request, |req| targets.point(req, black_box(&backend)), BatchSize::SmallInput)
+    });
+    group.bench_function("format_and_parse", |b| {
+        b.iter_batched_ref(request, |req| format_and_parse(req, black_box(addr)), BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_point_request);
+criterion_main!(benches);
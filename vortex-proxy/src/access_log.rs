@@ -0,0 +1,599 @@
+//! Per-request access and error log records.
+//!
+//! `AccessLogLayer` sits at the very start of the pipeline and hands one
+//! access record per request, plus an error record for requests that failed
+//! inside the proxy, to a `LogShipper`. Access records are rendered with an
+//! `AccessLogFormat`, by default:
+//!
+//! ```text
+//! 10.0.0.7 "GET /items?page=2 HTTP/1.1" 200 12ms 5f0c6e1a-8d3b-4c2e-9a41-7b6d2e0f9c13
+//! ```
+//!
+//! The access record is shipped once the response body has been sent (or
+//! abandoned), so it can report bytes transferred and the full duration.
+//! Shipping only queues the record; sinks write it from their own tasks, so
+//! a slow disk or collector never holds up a request.
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::HeaderName;
+use http_body_util::BodyExt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tower::{Layer, Service};
+use vortex_core::domain::labels::Labels;
+
+use crate::civil::UtcTime;
+use crate::error::ProxyError;
+use crate::log_sink::{LogKind, LogRecord, LogSeverity, LogShipper};
+use crate::pipeline::{
+    take_inner, ClientAddr, ProxyBody, ProxyFuture, ProxyRequest, ProxyResponse, RouteLabels, UpstreamExchange,
+};
+use crate::request_id::RequestId;
+use crate::server::ListenerPolicy;
+
+/// The Apache combined log format.
+pub const COMBINED: &str = r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-Agent}i""#;
+
+/// The format used unless another is configured.
+pub const DEFAULT_FORMAT: &str = r#"%h "%r" %>s %{ms}Tms %{request_id}x"#;
+
+/// A format string that could not be parsed.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid access log format at byte {offset}: {reason}")]
+pub struct AccessLogFormatError {
+    /// Where in the format string the problem is
+    pub offset: usize,
+    /// What is wrong
+    pub reason: String,
+}
+
+/// One piece of a parsed format string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Directive {
+    Literal(String),
+    /// `%h`, `%a`: the client IP
+    Client,
+    /// `%l`, `%u`: identd and auth user, which the proxy never knows
+    Unknown,
+    /// `%t`: when the request arrived
+    Time,
+    /// `%r`: the request line
+    RequestLine,
+    /// `%s`, `%>s`: the response status
+    Status,
+    /// `%b` (`-` for none), `%B`, `%O`: response body bytes sent
+    BytesSent { clf: bool },
+    /// `%I`: request body bytes received
+    BytesReceived,
+    /// `%m`
+    Method,
+    /// `%U`: the path, without the query
+    Path,
+    /// `%q`: `?` and the query, or nothing
+    Query,
+    /// `%H`: the protocol version
+    Protocol,
+    /// `%D`, `%T`, `%{ms}T`, `%{us}T`, `%{s}T`: time to serve the request
+    Duration(TimeUnit),
+    /// `%{Name}i`: a request header
+    RequestHeader(HeaderName),
+    /// `%{upstream_addr}x`: the backend that answered
+    UpstreamAddr,
+    /// `%{upstream_rtt}x`: milliseconds until the backend's response headers
+    UpstreamRtt,
+    /// `%{request_id}x`: the request's `X-Request-ID`
+    RequestId,
+    /// `%{label:<key>}x`: an observability label of the matched route or its pool
+    Label(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeUnit {
+    Seconds,
+    Millis,
+    Micros,
+}
+
+/// How access records are written: Apache `mod_log_config` directives plus
+/// `%{upstream_addr}x`, `%{upstream_rtt}x`, `%{request_id}x` and
+/// `%{label:<key>}x`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogFormat {
+    directives: Vec<Directive>,
+}
+
+impl Default for AccessLogFormat {
+    fn default() -> Self {
+        Self::parse(DEFAULT_FORMAT).expect("the default format parses")
+    }
+}
+
+impl AccessLogFormat {
+    /// The Apache combined log format.
+    pub fn combined() -> Self {
+        Self::parse(COMBINED).expect("the combined format parses")
+    }
+
+    /// Parses a format string such as `%h "%r" %>s %b %{upstream_rtt}x`.
+    pub fn parse(format: &str) -> Result<Self, AccessLogFormatError> {
+        let mut directives = Vec::new();
+        let mut literal = String::new();
+        let mut chars = format.char_indices().peekable();
+        while let Some((offset, c)) = chars.next() {
+            if c != '%' {
+                literal.push(c);
+                continue;
+            }
+            let error = |reason: &str| AccessLogFormatError {
+                offset,
+                reason: reason.to_string(),
+            };
+            let mut argument = None;
+            if chars.next_if(|(_, c)| *c == '{').is_some() {
+                let mut arg = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '}')) => break,
+                        Some((_, c)) => arg.push(c),
+                        None => return Err(error("unterminated `%{`")),
+                    }
+                }
+                argument = Some(arg);
+            }
+            // `%>s` reports the final status, which is the only one the proxy has
+            chars.next_if(|(_, c)| *c == '>');
+            let Some((_, code)) = chars.next() else {
+                return Err(error("`%` at the end of the format"));
+            };
+            let directive = match (code, argument.as_deref()) {
+                ('%', None) => {
+                    literal.push('%');
+                    continue;
+                }
+                ('h' | 'a', None) => Directive::Client,
+                ('l' | 'u', None) => Directive::Unknown,
+                ('t', None) => Directive::Time,
+                ('r', None) => Directive::RequestLine,
+                ('s', None) => Directive::Status,
+                ('b', None) => Directive::BytesSent { clf: true },
+                ('B' | 'O', None) => Directive::BytesSent { clf: false },
+                ('I', None) => Directive::BytesReceived,
+                ('m', None) => Directive::Method,
+                ('U', None) => Directive::Path,
+                ('q', None) => Directive::Query,
+                ('H', None) => Directive::Protocol,
+                ('D', None) | ('T', Some("us")) => Directive::Duration(TimeUnit::Micros),
+                ('T', None | Some("s")) => Directive::Duration(TimeUnit::Seconds),
+                ('T', Some("ms")) => Directive::Duration(TimeUnit::Millis),
+                ('i', Some(name)) => Directive::RequestHeader(
+                    HeaderName::from_bytes(name.as_bytes()).map_err(|_| error("invalid header name"))?,
+                ),
+                ('x', Some("upstream_addr")) => Directive::UpstreamAddr,
+                ('x', Some("upstream_rtt")) => Directive::UpstreamRtt,
+                ('x', Some("request_id")) => Directive::RequestId,
+                ('x', Some(arg)) if arg.starts_with("label:") => Directive::Label(arg["label:".len()..].to_string()),
+                _ => return Err(error(&format!("unknown directive `%{}`", code))),
+            };
+            if !literal.is_empty() {
+                directives.push(Directive::Literal(std::mem::take(&mut literal)));
+            }
+            directives.push(directive);
+        }
+        if !literal.is_empty() {
+            directives.push(Directive::Literal(literal));
+        }
+        Ok(Self { directives })
+    }
+
+    fn counts_received(&self) -> bool {
+        self.directives.contains(&Directive::BytesReceived)
+    }
+
+    fn headers(&self) -> impl Iterator<Item = &HeaderName> {
+        self.directives.iter().filter_map(|d| match d {
+            Directive::RequestHeader(name) => Some(name),
+            _ => None,
+        })
+    }
+
+    fn render(&self, entry: &AccessEntry) -> String {
+        let mut out = String::new();
+        let mut headers = entry.headers.iter();
+        for directive in &self.directives {
+            match directive {
+                Directive::Literal(text) => out.push_str(text),
+                Directive::Client => out.push_str(&entry.client),
+                Directive::Unknown => out.push('-'),
+                Directive::Time => {
+                    out.push('[');
+                    out.push_str(&UtcTime::from_system_time(entry.timestamp).clf());
+                    out.push(']');
+                }
+                Directive::RequestLine => {
+                    let query = entry.query.as_deref().map_or(String::new(), |q| format!("?{}", q));
+                    push_escaped(&mut out, &format!("{} {}{} {}", entry.method, entry.path, query, entry.protocol));
+                }
+                Directive::Status => out.push_str(&entry.status.to_string()),
+                Directive::BytesSent { clf: true } if entry.bytes_sent == 0 => out.push('-'),
+                Directive::BytesSent { .. } => out.push_str(&entry.bytes_sent.to_string()),
+                Directive::BytesReceived => out.push_str(&entry.bytes_received.to_string()),
+                Directive::Method => out.push_str(&entry.method),
+                Directive::Path => push_escaped(&mut out, &entry.path),
+                Directive::Query => {
+                    if let Some(query) = &entry.query {
+                        out.push('?');
+                        push_escaped(&mut out, query);
+                    }
+                }
+                Directive::Protocol => out.push_str(&entry.protocol),
+                Directive::Duration(unit) => out.push_str(&match unit {
+                    TimeUnit::Seconds => entry.duration.as_secs().to_string(),
+                    TimeUnit::Millis => entry.duration.as_millis().to_string(),
+                    TimeUnit::Micros => entry.duration.as_micros().to_string(),
+                }),
+                Directive::RequestHeader(_) => match headers.next().and_then(Option::as_deref) {
+                    Some(value) => push_escaped(&mut out, value),
+                    None => out.push('-'),
+                },
+                Directive::UpstreamAddr => match entry.upstream {
+                    Some(exchange) => out.push_str(&exchange.addr.to_string()),
+                    None => out.push('-'),
+                },
+                Directive::UpstreamRtt => match entry.upstream {
+                    Some(exchange) => out.push_str(&format!("{:.3}", exchange.rtt.as_secs_f64() * 1000.0)),
+                    None => out.push('-'),
+                },
+                Directive::RequestId => out.push_str(entry.request_id.as_deref().unwrap_or("-")),
+                Directive::Label(key) => match entry.labels.as_ref().and_then(|labels| labels.get(key)) {
+                    Some(value) => push_escaped(&mut out, value),
+                    None => out.push('-'),
+                },
+            }
+        }
+        out
+    }
+}
+
+/// Appends `value` with quotes, backslashes and control characters escaped,
+/// so client-supplied text can't forge log lines or break quoting.
+fn push_escaped(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Everything an access record can report about one request.
+#[derive(Debug, Clone)]
+struct AccessEntry {
+    client: String,
+    timestamp: SystemTime,
+    method: String,
+    path: String,
+    query: Option<String>,
+    protocol: String,
+    request_id: Option<String>,
+    /// Values of the headers the format references, in order
+    headers: Vec<Option<String>>,
+    status: u16,
+    upstream: Option<UpstreamExchange>,
+    /// Labels of the matched route, once it is known
+    labels: Option<Arc<Labels>>,
+    duration: Duration,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+/// Logs every request to a `LogShipper`.
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    shipper: Arc<LogShipper>,
+    format: Arc<AccessLogFormat>,
+}
+
+impl AccessLogLayer {
+    /// Create a layer logging to `shipper` in the default format.
+    pub fn new(shipper: Arc<LogShipper>) -> Self {
+        Self {
+            shipper,
+            format: Arc::default(),
+        }
+    }
+
+    /// Render access records in `format`, builder style.
+    pub fn with_format(mut self, format: AccessLogFormat) -> Self {
+        self.format = Arc::new(format);
+        self
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogService {
+            inner,
+            shipper: self.shipper.clone(),
+            format: self.format.clone(),
+        }
+    }
+}
+
+/// Service produced by `AccessLogLayer`.
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+    shipper: Arc<LogShipper>,
+    format: Arc<AccessLogFormat>,
+}
+
+impl<S> Service<ProxyRequest> for AccessLogService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let start = Instant::now();
+        let format = req
+            .extensions()
+            .get::<Arc<ListenerPolicy>>()
+            .and_then(|policy| policy.access_log_format.clone())
+            .unwrap_or_else(|| self.format.clone());
+        let mut entry = AccessEntry {
+            client: req.extensions().get::<ClientAddr>().map_or("-".to_string(), |c| c.0.ip().to_string()),
+            timestamp: SystemTime::now(),
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            query: req.uri().query().map(str::to_string),
+            protocol: format!("{:?}", req.version()),
+            request_id: req.extensions().get::<RequestId>().map(|id| id.to_string()),
+            headers: format
+                .headers()
+                .map(|name| req.headers().get(name).map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned()))
+                .collect(),
+            status: 0,
+            upstream: None,
+            labels: None,
+            duration: Duration::ZERO,
+            bytes_received: 0,
+            bytes_sent: 0,
+        };
+        let received = Arc::new(AtomicU64::new(0));
+        let req = if format.counts_received() {
+            let received = received.clone();
+            req.map(|body| Counted::new(body, received, None).boxed())
+        } else {
+            req
+        };
+
+        let shipper = self.shipper.clone();
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let result = inner.call(req).await;
+            match result {
+                Ok(res) => {
+                    entry.status = res.status().as_u16();
+                    entry.upstream = res.extensions().get::<UpstreamExchange>().copied();
+                    entry.labels = res.extensions().get::<RouteLabels>().map(|labels| labels.0.clone());
+                    // Logged once the body is done, to report its size and the full duration
+                    let on_done = Box::new(move |sent: u64| {
+                        entry.duration = start.elapsed();
+                        entry.bytes_sent = sent;
+                        entry.bytes_received = received.load(Ordering::Relaxed);
+                        shipper.ship(access_record(&format, &entry));
+                    });
+                    Ok(res.map(|body| Counted::new(body, Arc::new(AtomicU64::new(0)), Some(on_done)).boxed()))
+                }
+                Err(e) => {
+                    let request_line = format!(
+                        "{} {}{} {}",
+                        entry.method,
+                        entry.path,
+                        entry.query.as_deref().map_or(String::new(), |q| format!("?{}", q)),
+                        entry.protocol
+                    );
+                    let request_id = entry.request_id.as_deref().map_or(String::new(), |id| format!(" ({})", id));
+                    shipper.ship(LogRecord {
+                        kind: LogKind::Error,
+                        severity: LogSeverity::Error,
+                        timestamp: entry.timestamp,
+                        message: format!(
+                            "{} \"{}\"{} failed [{}]: {}",
+                            entry.client,
+                            request_line,
+                            request_id,
+                            e.kind(),
+                            e
+                        ),
+                    });
+                    entry.status = e.status_code().as_u16();
+                    entry.duration = start.elapsed();
+                    entry.bytes_received = received.load(Ordering::Relaxed);
+                    shipper.ship(access_record(&format, &entry));
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+fn access_record(format: &AccessLogFormat, entry: &AccessEntry) -> LogRecord {
+    LogRecord {
+        kind: LogKind::Access,
+        severity: LogSeverity::Info,
+        timestamp: entry.timestamp,
+        message: format.render(entry),
+    }
+}
+
+type OnDone = Box<dyn FnOnce(u64) + Send + Sync>;
+
+/// Counts the data bytes passing through a body, calling `on_done` with the
+/// total once it ends or is dropped.
+struct Counted {
+    inner: ProxyBody,
+    bytes: Arc<AtomicU64>,
+    on_done: Option<OnDone>,
+}
+
+impl Counted {
+    fn new(inner: ProxyBody, bytes: Arc<AtomicU64>, on_done: Option<OnDone>) -> Self {
+        Self { inner, bytes, on_done }
+    }
+
+    fn done(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.bytes.load(Ordering::Relaxed));
+        }
+    }
+}
+
+impl Body for Counted {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+                if this.inner.is_end_stream() {
+                    this.done();
+                }
+            }
+            Poll::Ready(_) => this.done(),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> AccessEntry {
+        AccessEntry {
+            client: "10.0.0.7".to_string(),
+            timestamp: UNIX_EPOCH + Duration::from_secs(1_440_938_160),
+            method: "GET".to_string(),
+            path: "/items".to_string(),
+            query: Some("page=2".to_string()),
+            protocol: "HTTP/1.1".to_string(),
+            request_id: Some("5f0c6e1a-8d3b-4c2e-9a41-7b6d2e0f9c13".to_string()),
+            headers: vec![None, Some("curl/8.0 \"quoted\"".to_string())],
+            status: 200,
+            upstream: Some(UpstreamExchange {
+                addr: "127.0.0.1:9090".parse().unwrap(),
+                rtt: Duration::from_micros(4_250),
+            }),
+            labels: Some(Arc::new(Labels::new().with("team", "payments"))),
+            duration: Duration::from_millis(12),
+            bytes_received: 0,
+            bytes_sent: 1234,
+        }
+    }
+
+    #[test]
+    fn test_default_and_combined_formats() {
+        let entry = entry();
+        assert_eq!(
+            AccessLogFormat::default().render(&entry),
+            r#"10.0.0.7 "GET /items?page=2 HTTP/1.1" 200 12ms 5f0c6e1a-8d3b-4c2e-9a41-7b6d2e0f9c13"#
+        );
+        assert_eq!(
+            AccessLogFormat::combined().render(&entry),
+            r#"10.0.0.7 - - [30/Aug/2015:12:36:00 +0000] "GET /items?page=2 HTTP/1.1" 200 1234 "-" "curl/8.0 \"quoted\"""#
+        );
+    }
+
+    #[test]
+    fn test_custom_format_directives() {
+        let format = AccessLogFormat::parse("%m %U%q %>s %B/%I %D %{upstream_addr}x %{upstream_rtt}x 100%%").unwrap();
+        let mut entry = entry();
+        assert_eq!(format.render(&entry), "GET /items?page=2 200 1234/0 12000 127.0.0.1:9090 4.250 100%");
+        entry.upstream = None;
+        entry.path = "/a\nb".to_string();
+        assert_eq!(format.render(&entry), "GET /a\\x0ab?page=2 200 1234/0 12000 - - 100%");
+        entry.request_id = None;
+        assert_eq!(AccessLogFormat::parse("[%{request_id}x]").unwrap().render(&entry), "[-]");
+
+        assert_eq!(AccessLogFormat::parse("%h %Z").unwrap_err().offset, 3);
+        assert!(AccessLogFormat::parse("%{Referer").is_err());
+        assert!(AccessLogFormat::parse("%{nope}x").is_err());
+        assert!(AccessLogFormat::parse("trailing %").is_err());
+
+        let labels = AccessLogFormat::parse("%{label:team}x %{label:service}x").unwrap();
+        assert_eq!(labels.render(&entry), "payments -");
+        entry.labels = None;
+        assert_eq!(labels.render(&entry), "- -");
+    }
+
+    #[tokio::test]
+    async fn test_records_carry_the_route_labels() {
+        use crate::lifecycle::CloseSignal;
+        use crate::log_sink::{LogShipping, LogSink};
+        use crate::pipeline::{full_body, local_response};
+        use tower::ServiceExt;
+
+        let path = std::env::temp_dir().join(format!("vortex-access-labels-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = LogShipping {
+            sinks: vec![LogSink::File(path.clone())],
+            ..LogShipping::default()
+        };
+        let (shipper, tasks) = LogShipper::spawn(config, CloseSignal::new());
+        let service = AccessLogLayer::new(Arc::new(shipper))
+            .with_format(AccessLogFormat::parse("%>s %{label:team}x").unwrap())
+            .layer(tower::service_fn(|_req: ProxyRequest| async {
+                let mut res = local_response(hyper::StatusCode::OK, "ok");
+                res.extensions_mut().insert(RouteLabels(Arc::new(Labels::new().with("team", "checkout"))));
+                Ok::<_, ProxyError>(res)
+            }));
+
+        let req = hyper::Request::new(full_body(Bytes::new()));
+        drop(service.oneshot(req).await.unwrap());
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "200 checkout\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
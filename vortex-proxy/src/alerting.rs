@@ -0,0 +1,301 @@
+//! Anomaly alerts delivered to webhooks.
+//!
+//! An `Alerter` posts `Alert`s to Slack- or PagerDuty-compatible webhooks,
+//! suppressing repeats of the same alert key within a cooldown. The alert
+//! monitor periodically checks the data plane for pools without a healthy
+//! backend and for elevated 5xx rates; other subsystems (e.g. certificate
+//! monitoring) can fire through the same `Alerter`.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time;
+use vortex_core::domain::routing::SharedRoutingTable;
+
+use crate::metrics::RequestMetrics;
+
+/// How urgent an alert is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Degraded but still serving
+    Warning,
+    /// Traffic is failing
+    Critical,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// A single anomaly notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    /// Stable identity used for deduplication, e.g. `no_healthy_backends:default`
+    pub key: String,
+    /// How urgent the alert is
+    pub severity: Severity,
+    /// Human-readable description
+    pub summary: String,
+}
+
+/// The JSON dialect a webhook expects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebhookFormat {
+    /// Slack incoming webhook (`{"text": ...}`), also accepted by most chat tools
+    Slack,
+    /// PagerDuty Events API v2
+    PagerDuty {
+        /// The integration's routing key
+        routing_key: String,
+    },
+}
+
+/// A webhook receiving alerts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    /// Where alerts are POSTed
+    pub url: String,
+    /// The payload format
+    pub format: WebhookFormat,
+}
+
+impl Webhook {
+    /// A Slack-compatible webhook.
+    pub fn slack(url: impl Into<String>) -> Self {
+        Self { url: url.into(), format: WebhookFormat::Slack }
+    }
+
+    /// A PagerDuty Events API v2 webhook.
+    pub fn pagerduty(url: impl Into<String>, routing_key: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            format: WebhookFormat::PagerDuty { routing_key: routing_key.into() },
+        }
+    }
+
+    fn payload(&self, alert: &Alert) -> Value {
+        match &self.format {
+            WebhookFormat::Slack => json!({
+                "text": format!("[vortex] {}: {}", alert.severity.as_str(), alert.summary),
+            }),
+            WebhookFormat::PagerDuty { routing_key } => json!({
+                "routing_key": routing_key,
+                "event_action": "trigger",
+                "dedup_key": alert.key,
+                "payload": {
+                    "summary": alert.summary,
+                    "source": "vortex-proxy",
+                    "severity": alert.severity.as_str(),
+                },
+            }),
+        }
+    }
+}
+
+/// What to alert on and where to send it.
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    /// Destinations for every alert
+    pub webhooks: Vec<Webhook>,
+    /// Minimum time between two notifications with the same key
+    pub cooldown: Duration,
+    /// How often the monitor evaluates its rules
+    pub check_interval: Duration,
+    /// Alert when the 5xx share of requests in one interval exceeds this percentage
+    pub error_rate_percent: Option<f64>,
+    /// Ignore the error rate for intervals with fewer requests than this
+    pub min_requests: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            webhooks: Vec::new(),
+            cooldown: Duration::from_secs(300),
+            check_interval: Duration::from_secs(10),
+            error_rate_percent: Some(5.0),
+            min_requests: 20,
+        }
+    }
+}
+
+/// Delivers alerts to webhooks, at most once per key per cooldown.
+#[derive(Debug)]
+pub struct Alerter {
+    webhooks: Vec<Webhook>,
+    cooldown: Duration,
+    client: reqwest::Client,
+    last_fired: Mutex<HashMap<String, Instant>>,
+}
+
+impl Alerter {
+    /// Create an alerter posting to `webhooks`.
+    pub fn new(webhooks: Vec<Webhook>, cooldown: Duration) -> Self {
+        Self {
+            webhooks,
+            cooldown,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap_or_default(),
+            last_fired: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends `alert` unless the same key fired within the cooldown.
+    /// Returns whether it was sent.
+    pub async fn fire(&self, alert: Alert) -> bool {
+        if !self.should_fire(&alert.key, Instant::now()) {
+            return false;
+        }
+
+        tracing::warn!(severity = alert.severity.as_str(), key = %alert.key, "{}", alert.summary);
+        for webhook in &self.webhooks {
+            let result = self
+                .client
+                .post(&webhook.url)
+                .json(&webhook.payload(&alert))
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+            if let Err(e) = result {
+                tracing::warn!(key = %alert.key, error = %e.without_url(), "failed to deliver alert to webhook");
+            }
+        }
+        true
+    }
+
+    fn should_fire(&self, key: &str, now: Instant) -> bool {
+        let mut last_fired = self.last_fired.lock().unwrap_or_else(|e| e.into_inner());
+        match last_fired.get(key) {
+            Some(at) if now.duration_since(*at) < self.cooldown => false,
+            _ => {
+                last_fired.insert(key.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// Spawns a task that evaluates the alert rules every `config.check_interval`.
+pub fn spawn_alert_monitor(
+    alerter: Arc<Alerter>,
+    config: AlertConfig,
+    routing_table: SharedRoutingTable,
+    request_metrics: RequestMetrics,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(config.check_interval);
+        let mut previous = request_metrics.outcome_totals();
+
+        // Prevent immediately ticking when spawned
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let pool = routing_table.labels().get("service").unwrap_or("default").to_string();
+            if let Some(alert) = no_healthy_backends_alert(&pool, &routing_table) {
+                alerter.fire(alert).await;
+            }
+
+            let current = request_metrics.outcome_totals();
+            if let Some(alert) = error_rate_alert(&pool, previous, current, &config) {
+                alerter.fire(alert).await;
+            }
+            previous = current;
+        }
+    })
+}
+
+fn no_healthy_backends_alert(pool: &str, routing_table: &SharedRoutingTable) -> Option<Alert> {
+    let backends = routing_table.snapshot();
+    if backends.is_empty() || backends.iter().any(|b| b.is_healthy()) {
+        return None;
+    }
+    Some(Alert {
+        key: format!("no_healthy_backends:{}", pool),
+        severity: Severity::Critical,
+        summary: format!("pool {} has zero healthy backends ({} configured)", pool, backends.len()),
+    })
+}
+
+/// Compares two `(requests, server_errors)` samples against the configured threshold.
+fn error_rate_alert(pool: &str, previous: (u64, u64), current: (u64, u64), config: &AlertConfig) -> Option<Alert> {
+    let threshold = config.error_rate_percent?;
+    let requests = current.0.saturating_sub(previous.0);
+    let errors = current.1.saturating_sub(previous.1);
+    if requests == 0 || requests < config.min_requests {
+        return None;
+    }
+
+    let rate = errors as f64 * 100.0 / requests as f64;
+    (rate > threshold).then(|| Alert {
+        key: format!("error_rate:{}", pool),
+        severity: Severity::Warning,
+        summary: format!(
+            "pool {} 5xx rate {:.1}% exceeds {:.1}% ({} of {} requests)",
+            pool, rate, threshold, errors, requests
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::routing::RoutingTable;
+
+    fn alert(key: &str) -> Alert {
+        Alert { key: key.into(), severity: Severity::Critical, summary: "down".into() }
+    }
+
+    #[test]
+    fn test_cooldown_suppresses_repeats_per_key() {
+        let alerter = Alerter::new(Vec::new(), Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(alerter.should_fire("a", now));
+        assert!(!alerter.should_fire("a", now + Duration::from_secs(30)));
+        assert!(alerter.should_fire("b", now + Duration::from_secs(30)));
+        assert!(alerter.should_fire("a", now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_pagerduty_payload_carries_dedup_key() {
+        let webhook = Webhook::pagerduty("https://events.pagerduty.com/v2/enqueue", "rk");
+        let payload = webhook.payload(&alert("no_healthy_backends:api"));
+        assert_eq!(payload["dedup_key"], "no_healthy_backends:api");
+        assert_eq!(payload["payload"]["severity"], "critical");
+
+        let slack = Webhook::slack("https://hooks.slack.com/x").payload(&alert("k"));
+        assert_eq!(slack["text"], "[vortex] critical: down");
+    }
+
+    #[test]
+    fn test_error_rate_threshold() {
+        let config = AlertConfig { error_rate_percent: Some(5.0), min_requests: 20, ..AlertConfig::default() };
+
+        assert!(error_rate_alert("api", (100, 0), (200, 5), &config).is_none());
+        assert!(error_rate_alert("api", (100, 0), (200, 6), &config).is_some());
+        // Too few requests in the interval to judge
+        assert!(error_rate_alert("api", (0, 0), (10, 10), &config).is_none());
+    }
+
+    #[test]
+    fn test_no_healthy_backends() {
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap()));
+        let routing_table = Arc::new(RoutingTable::new(vec![backend.clone()]));
+        assert!(no_healthy_backends_alert("api", &routing_table).is_none());
+
+        backend.set_healthy(false);
+        assert_eq!(no_healthy_backends_alert("api", &routing_table).unwrap().key, "no_healthy_backends:api");
+    }
+}
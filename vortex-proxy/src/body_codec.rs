@@ -0,0 +1,510 @@
+//! Body format conversion between clients and upstreams, per route.
+//!
+//! A route with a codec (see `vortex_filters::body_codec`) accepts request
+//! bodies in the client format and forwards them in the upstream's, and
+//! converts responses back for clients that asked for the client format.
+//! Clients already speaking the upstream's format pass through untouched, so
+//! a route can serve both while its clients migrate.
+//!
+//! A client asks for the client format in its `Accept` header, or by sending
+//! a body in it without an `Accept` header at all; a client accepting
+//! anything else gets the upstream's response as it is.
+//!
+//! Request bodies are converted as they are read, but the result is buffered
+//! before it is forwarded: a document that turns out malformed or oversized
+//! ends in a clean error status rather than a body that breaks off halfway.
+//! Responses are buffered the same way unless the codec is streaming (see
+//! `BodyCodec::is_streaming`), in which case each piece is forwarded as soon
+//! as it is converted and a failure cuts the body short instead. Input and
+//! output are both capped at the route's `max_bytes`.
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use vortex_filters::body_codec::{BodyCodec, Direction, Transcoder};
+
+use crate::error::ProxyError;
+use crate::pipeline::{buffered_body, take_inner, ProxyBody, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// Bodies run through a codec, by `codec`, `direction` and `outcome`:
+/// `converted`, `malformed` or `too_large`.
+static TRANSCODES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_body_transcodes_total",
+        "Bodies converted between client and upstream formats",
+        &["codec", "direction", "outcome"]
+    )
+    .expect("metric registers once")
+});
+
+/// The codec of one route.
+#[derive(Debug, Clone)]
+pub struct RouteCodec {
+    /// Converts between the route's client and upstream formats
+    pub codec: Arc<dyn BodyCodec>,
+    /// Largest body read or produced, in bytes, either way
+    pub max_bytes: usize,
+}
+
+/// Body codecs by route name.
+#[derive(Debug, Clone, Default)]
+pub struct BodyCodecs {
+    /// Codecs for individual routes, by route name
+    pub routes: HashMap<String, RouteCodec>,
+}
+
+/// Why a body was not converted, before it is mapped to a `ProxyError`.
+enum Failure {
+    /// Reading the body failed
+    Read(hyper::Error),
+    /// The input or output passed the limit
+    TooLarge,
+    /// The codec rejected the input
+    Malformed(String),
+}
+
+/// The media type of `headers`' `Content-Type`, without parameters.
+fn media_type(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    Some(value.split(';').next().unwrap_or_default().trim())
+}
+
+/// Whether a message carries an uncompressed body the codec reads as `direction`.
+fn is_convertible(headers: &HeaderMap, codec: &dyn BodyCodec, direction: Direction) -> bool {
+    let convertible = media_type(headers).is_some_and(|mime| match direction {
+        Direction::ToUpstream => codec.is_client_media_type(mime),
+        Direction::ToClient => codec.is_upstream_media_type(mime),
+    });
+    convertible && !headers.contains_key(CONTENT_ENCODING)
+}
+
+/// Whether the request's `Accept` names the client format.
+fn accepts_client_format(headers: &HeaderMap, codec: &dyn BodyCodec) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|range| codec.is_client_media_type(range.split(';').next().unwrap_or_default().trim()))
+}
+
+/// Runs `body` through a fresh transcoder, buffering the output.
+async fn transcode(
+    body: ProxyBody,
+    route: &RouteCodec,
+    direction: Direction,
+) -> Result<(Vec<u8>, Option<HeaderMap>), Failure> {
+    let mut body = body;
+    let mut transcoder = route.codec.transcoder(direction);
+    let mut read = 0;
+    let mut out = Vec::new();
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        let data = match frame.map_err(Failure::Read)?.into_data() {
+            Ok(data) => data,
+            Err(frame) => {
+                trailers = frame.into_trailers().ok();
+                continue;
+            }
+        };
+        read += data.len();
+        if read > route.max_bytes {
+            return Err(Failure::TooLarge);
+        }
+        transcoder.push(&data, &mut out).map_err(|e| Failure::Malformed(e.to_string()))?;
+        if out.len() > route.max_bytes {
+            return Err(Failure::TooLarge);
+        }
+    }
+    transcoder.finish(&mut out).map_err(|e| Failure::Malformed(e.to_string()))?;
+    if out.len() > route.max_bytes {
+        return Err(Failure::TooLarge);
+    }
+    Ok((out, trailers))
+}
+
+/// Counts the outcome of a conversion.
+fn record<T>(route: &RouteCodec, direction: Direction, result: &Result<T, Failure>) {
+    let outcome = match result {
+        Ok(_) => "converted",
+        Err(Failure::TooLarge) => "too_large",
+        Err(Failure::Malformed(_)) => "malformed",
+        // Not the codec's doing
+        Err(Failure::Read(_)) => return,
+    };
+    TRANSCODES.with_label_values(&[route.codec.name(), direction.as_str(), outcome]).inc();
+}
+
+/// A response body converted by a streaming codec as it passes through.
+struct TranscodedBody {
+    inner: ProxyBody,
+    transcoder: Box<dyn Transcoder>,
+    codec: RouteCodec,
+    /// The route, for logs
+    route: String,
+    read: usize,
+    written: usize,
+    done: bool,
+}
+
+impl TranscodedBody {
+    fn wrap(inner: ProxyBody, codec: RouteCodec, route: String) -> ProxyBody {
+        let transcoder = codec.codec.transcoder(Direction::ToClient);
+        TranscodedBody { inner, transcoder, codec, route, read: 0, written: 0, done: false }.boxed()
+    }
+
+    /// Converts `data` (or finishes, if `None`), returning the output so far.
+    fn convert(&mut self, data: Option<&[u8]>) -> Result<Vec<u8>, Failure> {
+        let mut out = Vec::new();
+        if let Some(data) = data {
+            self.read += data.len();
+            if self.read > self.codec.max_bytes {
+                return Err(Failure::TooLarge);
+            }
+        }
+        match data {
+            Some(data) => self.transcoder.push(data, &mut out),
+            None => self.transcoder.finish(&mut out),
+        }
+        .map_err(|e| Failure::Malformed(e.to_string()))?;
+        self.written += out.len();
+        if self.written > self.codec.max_bytes {
+            return Err(Failure::TooLarge);
+        }
+        Ok(out)
+    }
+
+    /// Stops the body after a failed conversion; the client sees it end early.
+    fn fail(&mut self, failure: Failure) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let reason = match &failure {
+            Failure::TooLarge => format!("larger than {} bytes", self.codec.max_bytes),
+            Failure::Malformed(reason) => reason.clone(),
+            Failure::Read(e) => e.to_string(),
+        };
+        tracing::warn!(route = %self.route, error = %reason, "truncating response that could not be converted");
+        record::<()>(&self.codec, Direction::ToClient, &Err(failure));
+        self.done = true;
+        Poll::Ready(None)
+    }
+}
+
+impl Body for TranscodedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+            return match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => match this.convert(Some(&data)) {
+                        // Held back until later input completes it
+                        Ok(out) if out.is_empty() => continue,
+                        Ok(out) => Poll::Ready(Some(Ok(Frame::data(Bytes::from(out))))),
+                        Err(failure) => this.fail(failure),
+                    },
+                    Err(frame) => Poll::Ready(Some(Ok(frame))),
+                },
+                Poll::Ready(None) => match this.convert(None) {
+                    Ok(out) => {
+                        this.done = true;
+                        record(&this.codec, Direction::ToClient, &Ok(()));
+                        if out.is_empty() {
+                            Poll::Ready(None)
+                        } else {
+                            Poll::Ready(Some(Ok(Frame::data(Bytes::from(out)))))
+                        }
+                    }
+                    Err(failure) => this.fail(failure),
+                },
+                other => other,
+            };
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+/// Replaces a message's body with converted bytes, labelled as `media_type`.
+fn replace_body(headers: &mut HeaderMap, media_type: &str, bytes: Vec<u8>, trailers: Option<HeaderMap>) -> ProxyBody {
+    if let Ok(value) = HeaderValue::from_str(media_type) {
+        headers.insert(CONTENT_TYPE, value);
+    }
+    if trailers.is_some() {
+        // HTTP/1.1 only carries trailers on a chunked body
+        headers.remove(CONTENT_LENGTH);
+    } else {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    }
+    buffered_body(Bytes::from(bytes), trailers)
+}
+
+/// Converts bodies of routes with a codec per `BodyCodecs`.
+#[derive(Debug, Clone)]
+pub struct BodyCodecLayer {
+    codecs: Arc<BodyCodecs>,
+}
+
+impl BodyCodecLayer {
+    /// Create a layer converting per `codecs`.
+    pub fn new(codecs: BodyCodecs) -> Self {
+        Self { codecs: Arc::new(codecs) }
+    }
+}
+
+impl<S> Layer<S> for BodyCodecLayer {
+    type Service = BodyCodecService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BodyCodecService {
+            inner,
+            codecs: self.codecs.clone(),
+        }
+    }
+}
+
+/// Service produced by `BodyCodecLayer`.
+#[derive(Debug, Clone)]
+pub struct BodyCodecService<S> {
+    inner: S,
+    codecs: Arc<BodyCodecs>,
+}
+
+impl<S> Service<ProxyRequest> for BodyCodecService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let context = req.extensions().get::<RouteContext>().cloned();
+        let route = context.as_ref().and_then(|ctx| self.codecs.routes.get(&ctx.route)).cloned();
+        let Some((context, route)) = context.zip(route) else {
+            return Box::pin(self.inner.call(req));
+        };
+
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let codec = route.codec.as_ref();
+            let (mut parts, body) = req.into_parts();
+            let converts_request = is_convertible(&parts.headers, codec, Direction::ToUpstream);
+            // Clients that don't say what they accept get what they sent
+            let wants_client_format = accepts_client_format(&parts.headers, codec)
+                || (converts_request && !parts.headers.contains_key(ACCEPT));
+            let body = if converts_request {
+                let result = transcode(body, &route, Direction::ToUpstream).await;
+                record(&route, Direction::ToUpstream, &result);
+                let (bytes, trailers) = result.map_err(|failure| match failure {
+                    Failure::TooLarge => ProxyError::RequestTooLarge {
+                        route: context.route.clone(),
+                        limit: route.max_bytes as u64,
+                    },
+                    Failure::Malformed(reason) => ProxyError::InvalidRequest { route: context.route.clone(), reason },
+                    Failure::Read(e) => ProxyError::InvalidRequest {
+                        route: context.route.clone(),
+                        reason: format!("request body could not be read: {e}"),
+                    },
+                })?;
+                replace_body(&mut parts.headers, codec.upstream_media_type(), bytes, trailers)
+            } else {
+                body
+            };
+            if wants_client_format {
+                // The upstream only speaks its own format; ours is restored on the way back
+                if let Ok(value) = HeaderValue::from_str(codec.upstream_media_type()) {
+                    parts.headers.insert(ACCEPT, value);
+                }
+            }
+
+            let res = inner.call(ProxyRequest::from_parts(parts, body)).await?;
+            if !wants_client_format || !is_convertible(res.headers(), codec, Direction::ToClient) {
+                return Ok(res);
+            }
+            let (mut parts, body) = res.into_parts();
+            if codec.is_streaming() {
+                if let Ok(value) = HeaderValue::from_str(codec.client_media_type()) {
+                    parts.headers.insert(CONTENT_TYPE, value);
+                }
+                parts.headers.remove(CONTENT_LENGTH);
+                let body = TranscodedBody::wrap(body, route, context.route);
+                return Ok(ProxyResponse::from_parts(parts, body));
+            }
+            let result = transcode(body, &route, Direction::ToClient).await;
+            record(&route, Direction::ToClient, &result);
+            let (bytes, trailers) = result.map_err(|failure| match failure {
+                Failure::TooLarge => ProxyError::ResponseTooLarge {
+                    route: context.route.clone(),
+                    limit: route.max_bytes as u64,
+                },
+                Failure::Malformed(reason) => ProxyError::InvalidUpstreamResponse {
+                    backend: context.backend.id,
+                    addr: context.backend.addr,
+                    reason,
+                },
+                Failure::Read(source) => ProxyError::UpstreamProtocol {
+                    backend: context.backend.id,
+                    addr: context.backend.addr,
+                    source,
+                },
+            })?;
+            let body = replace_body(&mut parts.headers, codec.client_media_type(), bytes, trailers);
+            Ok(ProxyResponse::from_parts(parts, body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{full_body, Chunks};
+    use hyper::{Request, Response};
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_filters::body_codec::{JsonXml, MessagePackJson};
+
+    fn codecs(max_bytes: usize) -> BodyCodecs {
+        let route = RouteCodec { codec: Arc::new(MessagePackJson), max_bytes };
+        BodyCodecs { routes: HashMap::from([("api".to_string(), route)]) }
+    }
+
+    fn request(content_type: &str, accept: &str, body: &'static [u8]) -> ProxyRequest {
+        let mut req = Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header(ACCEPT, accept)
+            .body(full_body(Bytes::from_static(body)))
+            .unwrap();
+        req.extensions_mut().insert(RouteContext {
+            route: "api".to_string(),
+            backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9000".parse().unwrap())),
+            labels: Arc::default(),
+        });
+        req
+    }
+
+    /// An upstream that echoes JSON request bodies, recording what it saw.
+    fn echo() -> impl Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError, Future: Send> + Clone + Send {
+        tower::service_fn(|req: ProxyRequest| async move {
+            assert_eq!(req.headers()[CONTENT_TYPE], "application/json");
+            assert_eq!(req.headers()[ACCEPT], "application/json");
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            let res = Response::builder()
+                .header(CONTENT_TYPE, "application/json; charset=utf-8")
+                .body(full_body(body))
+                .unwrap();
+            Ok::<_, ProxyError>(res)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_msgpack_clients_reach_a_json_upstream() {
+        let service = BodyCodecLayer::new(codecs(1024)).layer(echo());
+        // {"a": 1} as MessagePack
+        let req = request("application/x-msgpack", "application/msgpack", &[0x81, 0xa1, b'a', 0x01]);
+        let res = service.oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/msgpack");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "4");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], &[0x81, 0xa1, b'a', 0x01]);
+
+        // JSON clients on the same route are left alone
+        let service = BodyCodecLayer::new(codecs(1024)).layer(echo());
+        let res = service.oneshot(request("application/json", "application/json", br#"{"a":1}"#)).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json; charset=utf-8");
+    }
+
+    #[tokio::test]
+    async fn test_malformed_and_oversized_requests_are_rejected() {
+        let service = BodyCodecLayer::new(codecs(1024)).layer(echo());
+        let err = service.oneshot(request("application/msgpack", "*/*", &[0x81])).await.unwrap_err();
+        assert_eq!(err.kind(), "invalid_request");
+
+        let service = BodyCodecLayer::new(codecs(3)).layer(echo());
+        let err = service.oneshot(request("application/msgpack", "*/*", &[0x81, 0xa1, b'a', 0x01])).await.unwrap_err();
+        assert_eq!(err.kind(), "request_too_large");
+        assert_eq!(err.status_code(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_responses_follow_the_accept_header() {
+        // Clients accepting only JSON get JSON back, whatever they sent
+        let service = BodyCodecLayer::new(codecs(1024)).layer(echo());
+        let req = request("application/msgpack", "application/json", &[0x81, 0xa1, b'a', 0x01]);
+        let res = service.oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json; charset=utf-8");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], br#"{"a":1}"#);
+
+        // Without an Accept header, they get what they sent
+        let service = BodyCodecLayer::new(codecs(1024)).layer(echo());
+        let mut req = request("application/msgpack", "*/*", &[0x81, 0xa1, b'a', 0x01]);
+        req.headers_mut().remove(ACCEPT);
+        let res = service.oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/msgpack");
+    }
+
+    #[tokio::test]
+    async fn test_streaming_codecs_forward_responses_as_they_convert() {
+        let upstream = |chunks: Vec<&'static [u8]>| {
+            tower::service_fn(move |req: ProxyRequest| {
+                let chunks = chunks.clone();
+                async move {
+                    assert_eq!(req.headers()[CONTENT_TYPE], "application/xml");
+                    assert_eq!(req.headers()[ACCEPT], "application/xml");
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    assert_eq!(&body[..], b"<q n=\"1\"/>");
+                    let res = Response::builder()
+                        .header(CONTENT_TYPE, "text/xml")
+                        .header(CONTENT_LENGTH, "17")
+                        .body(Chunks(chunks.into()).boxed())
+                        .unwrap();
+                    Ok::<_, ProxyError>(res)
+                }
+            })
+        };
+        let codecs = || {
+            let route = RouteCodec { codec: Arc::new(JsonXml), max_bytes: 1024 };
+            BodyCodecs { routes: HashMap::from([("api".to_string(), route)]) }
+        };
+
+        let service = BodyCodecLayer::new(codecs()).layer(upstream(vec![b"<a id=\"1\">", b"hi</a>"]));
+        let res = service.oneshot(request("application/json", "application/json", br#"["q",{"n":"1"}]"#)).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        let mut body = res.into_body();
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(&first[..], br#"["a",{"id":"1"}"#);
+        let rest = body.collect().await.unwrap().to_bytes();
+        assert_eq!(&rest[..], br#","hi"]"#);
+
+        // A document that goes wrong halfway is cut short
+        let service = BodyCodecLayer::new(codecs()).layer(upstream(vec![b"<a>", b"</b>"]));
+        let res = service.oneshot(request("application/json", "application/json", br#"["q",{"n":"1"}]"#)).await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], br#"["a""#);
+    }
+}
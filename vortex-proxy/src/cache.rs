@@ -0,0 +1,704 @@
+//! An in-memory cache of upstream responses, with per-route cache keys.
+//!
+//! Keying on the URL alone is wrong for most APIs: it either fragments the
+//! cache (a tracking parameter, a reordered query string) or poisons it (a
+//! response that depends on a tenant header served to another tenant). Each
+//! route therefore declares what goes into its key with a
+//! `CacheKeyTemplate`: selected headers and cookies, a normalized subset of
+//! the query string, and pool or route labels such as a tenant ID.
+//!
+//! Only `GET` and `HEAD` requests without credentials (unless the key
+//! includes `Authorization`) are served from cache, and only complete `200`
+//! responses the upstream allows to be shared are stored: no `no-store`,
+//! `private`, or `no-cache`, no `Set-Cookie`, and any `Vary` headers must be
+//! part of the key.
+//!
+//! Hits, misses, stale serves, and evictions are counted per route, both as
+//! Prometheus metrics and through `CacheStatsSource` for the admin API, so
+//! operators can see whether a route's key template actually gets reuse.
+//! Without declared routes, requests are counted under one `default` route
+//! rather than by path.
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use hyper::header::{HeaderMap, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, COOKIE, SET_COOKIE, VARY};
+use hyper::{Method, StatusCode};
+use std::collections::{HashMap, VecDeque};
+use prometheus::{HistogramVec, IntCounterVec};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+use vortex_core::stats::{CacheStats, CacheStatsSource};
+
+use crate::error::ProxyError;
+use crate::pipeline::{full_body, route_label, take_inner, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// Which query parameters a cache key includes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryParams {
+    /// Every parameter
+    All,
+    /// Only these parameters
+    Only(Vec<String>),
+    /// Every parameter except these (e.g. `utm_source`)
+    Except(Vec<String>),
+}
+
+/// One component of a cache key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyPart {
+    /// The request method
+    Method,
+    /// The requested host
+    Host,
+    /// The request path
+    Path,
+    /// The query string, filtered
+    Query(QueryParams),
+    /// A request header's value
+    Header(String),
+    /// A cookie's value
+    Cookie(String),
+    /// A label of the pool or route, e.g. `tenant`
+    Label(String),
+}
+
+/// What goes into a route's cache keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKeyTemplate {
+    /// Key components, in order
+    pub parts: Vec<KeyPart>,
+    /// Sort query parameters by name, so `?a=1&b=2` and `?b=2&a=1` share an entry
+    pub sort_query: bool,
+}
+
+impl Default for CacheKeyTemplate {
+    fn default() -> Self {
+        Self {
+            parts: vec![KeyPart::Method, KeyPart::Host, KeyPart::Path, KeyPart::Query(QueryParams::All)],
+            sort_query: true,
+        }
+    }
+}
+
+impl CacheKeyTemplate {
+    /// The key for `req` on the route in `ctx`.
+    pub fn key(&self, req: &ProxyRequest, ctx: &RouteContext) -> String {
+        let mut key = ctx.route.clone();
+        for part in &self.parts {
+            // Unit separators keep parts from running into each other
+            key.push('\u{1f}');
+            match part {
+                KeyPart::Method => key.push_str(req.method().as_str()),
+                KeyPart::Host => {
+                    let host = req.uri().host().or_else(|| req.headers().get("host").and_then(|v| v.to_str().ok()));
+                    key.push_str(&host.unwrap_or_default().to_ascii_lowercase());
+                }
+                KeyPart::Path => key.push_str(req.uri().path()),
+                KeyPart::Query(params) => key.push_str(&self.query(req.uri().query().unwrap_or(""), params)),
+                KeyPart::Header(name) => {
+                    // Absent and empty headers must not share a key
+                    let values: Vec<_> = req.headers().get_all(name.as_str()).iter().map(HeaderValue::as_bytes).collect();
+                    if values.is_empty() {
+                        key.push('-');
+                    } else {
+                        key.push('=');
+                        for value in values {
+                            key.push_str(&String::from_utf8_lossy(value));
+                            key.push(',');
+                        }
+                    }
+                }
+                KeyPart::Cookie(name) => match cookie(req.headers(), name) {
+                    Some(value) => {
+                        key.push('=');
+                        key.push_str(value);
+                    }
+                    None => key.push('-'),
+                },
+                KeyPart::Label(name) => key.push_str(ctx.labels.get(name).unwrap_or_default()),
+            }
+        }
+        key
+    }
+
+    fn query(&self, query: &str, params: &QueryParams) -> String {
+        let mut pairs: Vec<&str> = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let name = pair.split('=').next().unwrap_or("");
+                match params {
+                    QueryParams::All => true,
+                    QueryParams::Only(names) => names.iter().any(|n| n == name),
+                    QueryParams::Except(names) => !names.iter().any(|n| n == name),
+                }
+            })
+            .collect();
+        if self.sort_query {
+            pairs.sort_by_key(|pair| pair.split('=').next().unwrap_or(""));
+        }
+        pairs.join("&")
+    }
+
+    fn includes_header(&self, name: &str) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, KeyPart::Header(h) if h.eq_ignore_ascii_case(name)))
+    }
+}
+
+/// The value of cookie `name` in the request's `Cookie` headers.
+pub(crate) fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (k, v) = pair.trim().split_once('=')?;
+            (k == name).then_some(v)
+        })
+}
+
+/// How a route's responses are cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteCachePolicy {
+    /// Lifetime of responses without `max-age` or `s-maxage`
+    pub default_ttl: Duration,
+    /// What the route's cache keys are made of
+    pub key: CacheKeyTemplate,
+    /// How long past expiry a response may still be served when the upstream
+    /// fails or returns a 5xx; zero disables stale serving
+    pub stale_if_error: Duration,
+}
+
+/// Response caching, by route name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseCaching {
+    /// Most responses held at once; the oldest is evicted first
+    pub max_entries: usize,
+    /// Larger responses (or those of unknown length) aren't stored
+    pub max_body_bytes: u64,
+    /// Policy for routes without their own, if they are cached at all
+    pub default: Option<RouteCachePolicy>,
+    /// Policies for individual routes, by route name
+    pub routes: HashMap<String, RouteCachePolicy>,
+}
+
+impl Default for ResponseCaching {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_body_bytes: 1024 * 1024,
+            default: None,
+            routes: HashMap::new(),
+        }
+    }
+}
+
+impl ResponseCaching {
+    fn for_route(&self, route: &str) -> Option<&RouteCachePolicy> {
+        self.routes.get(route).or(self.default.as_ref())
+    }
+}
+
+/// Responses served from cache, fetched on a miss, or served stale because
+/// the upstream failed, by route.
+static LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_cache_lookups_total",
+        "Cacheable requests by route and result: hit, miss, or stale",
+        &["route", "result"]
+    )
+    .expect("metric registers once")
+});
+
+/// Entries dropped to make room for newer ones, by the route that stored them.
+static EVICTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_cache_evictions_total",
+        "Cache entries evicted at capacity, by route",
+        &["route"]
+    )
+    .expect("metric registers once")
+});
+
+/// How old cached responses are when served, by route.
+static OBJECT_AGE: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "vortex_cache_object_age_seconds",
+        "Age of responses served from cache, by route",
+        &["route"],
+        vec![1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14400.0, 86400.0]
+    )
+    .expect("metric registers once")
+});
+
+/// A stored response.
+struct Entry {
+    /// The route it's counted under
+    route: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    expires_at: Instant,
+}
+
+impl Entry {
+    fn response(&self, now: Instant, head_only: bool) -> ProxyResponse {
+        let body = if head_only { Bytes::new() } else { self.body.clone() };
+        let mut res = ProxyResponse::new(full_body(body));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res.headers_mut().insert(AGE, HeaderValue::from(now.duration_since(self.stored_at).as_secs()));
+        res
+    }
+}
+
+/// Lookup results for a route since startup.
+#[derive(Default)]
+struct RouteCounters {
+    hits: u64,
+    misses: u64,
+    stale_serves: u64,
+    evictions: u64,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    /// Keys in insertion order, for eviction; may hold keys since replaced or removed
+    order: VecDeque<String>,
+    counters: HashMap<String, RouteCounters>,
+}
+
+impl Store {
+    fn counters(&mut self, route: &str) -> &mut RouteCounters {
+        if !self.counters.contains_key(route) {
+            self.counters.insert(route.to_string(), RouteCounters::default());
+        }
+        self.counters.get_mut(route).expect("inserted above")
+    }
+}
+
+/// The stored responses and their statistics, shared by every service a
+/// `CacheLayer` produces.
+pub struct ResponseCache {
+    config: ResponseCaching,
+    store: Mutex<Store>,
+}
+
+impl ResponseCache {
+    /// Create an empty cache governed by `config`.
+    pub fn new(config: ResponseCaching) -> Self {
+        Self {
+            config,
+            store: Mutex::default(),
+        }
+    }
+
+    /// The fresh response stored under `key`, recording a hit or a miss.
+    fn fresh(&self, route: &str, key: &str, now: Instant, head_only: bool) -> Option<ProxyResponse> {
+        let mut store = self.store.lock().unwrap();
+        let Some(entry) = store.entries.get(key).filter(|e| now < e.expires_at) else {
+            store.counters(route).misses += 1;
+            LOOKUPS.with_label_values(&[route, "miss"]).inc();
+            return None;
+        };
+        let res = entry.response(now, head_only);
+        OBJECT_AGE.with_label_values(&[route]).observe(now.duration_since(entry.stored_at).as_secs_f64());
+        store.counters(route).hits += 1;
+        LOOKUPS.with_label_values(&[route, "hit"]).inc();
+        Some(res)
+    }
+
+    /// The response stored under `key` if it expired less than `grace` ago,
+    /// recording a stale serve.
+    fn stale(&self, route: &str, key: &str, now: Instant, grace: Duration, head_only: bool) -> Option<ProxyResponse> {
+        let mut store = self.store.lock().unwrap();
+        let entry = store.entries.get(key).filter(|e| now < e.expires_at + grace)?;
+        let res = entry.response(now, head_only);
+        OBJECT_AGE.with_label_values(&[route]).observe(now.duration_since(entry.stored_at).as_secs_f64());
+        store.counters(route).stale_serves += 1;
+        LOOKUPS.with_label_values(&[route, "stale"]).inc();
+        Some(res)
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        let mut store = self.store.lock().unwrap();
+        if store.entries.insert(key.clone(), entry).is_none() {
+            store.order.push_back(key);
+        }
+        while store.entries.len() > self.config.max_entries {
+            let Some(oldest) = store.order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = store.entries.remove(&oldest) {
+                store.counters(&evicted.route).evictions += 1;
+                EVICTIONS.with_label_values(&[&evicted.route]).inc();
+            }
+        }
+    }
+}
+
+impl CacheStatsSource for ResponseCache {
+    fn cache_stats(&self) -> Vec<CacheStats> {
+        let now = Instant::now();
+        let store = self.store.lock().unwrap();
+        let mut stats: HashMap<&str, CacheStats> = store
+            .counters
+            .iter()
+            .map(|(route, c)| {
+                let stats = CacheStats {
+                    route: route.clone(),
+                    hits: c.hits,
+                    misses: c.misses,
+                    stale_serves: c.stale_serves,
+                    evictions: c.evictions,
+                    entries: 0,
+                    avg_age: Duration::ZERO,
+                    max_age: Duration::ZERO,
+                };
+                (route.as_str(), stats)
+            })
+            .collect();
+        let mut total_age: HashMap<&str, Duration> = HashMap::new();
+        for entry in store.entries.values() {
+            let age = now.duration_since(entry.stored_at);
+            // Every stored entry was looked up (and missed) first
+            let Some(route) = stats.get_mut(entry.route.as_str()) else {
+                continue;
+            };
+            route.entries += 1;
+            route.max_age = route.max_age.max(age);
+            *total_age.entry(entry.route.as_str()).or_default() += age;
+        }
+        let mut stats: Vec<CacheStats> = stats
+            .into_values()
+            .map(|mut s| {
+                if s.entries > 0 {
+                    s.avg_age = total_age[s.route.as_str()] / s.entries as u32;
+                }
+                s
+            })
+            .collect();
+        stats.sort_by(|a, b| a.route.cmp(&b.route));
+        stats
+    }
+}
+
+/// Serves cacheable responses from memory.
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: Arc<ResponseCache>,
+}
+
+impl CacheLayer {
+    /// Create a layer storing responses in `cache`.
+    pub fn new(cache: Arc<ResponseCache>) -> Self {
+        Self { cache }
+    }
+}
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = CacheService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CacheService {
+            inner,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+/// Service produced by `CacheLayer`.
+#[derive(Clone)]
+pub struct CacheService<S> {
+    inner: S,
+    cache: Arc<ResponseCache>,
+}
+
+impl<S> Service<ProxyRequest> for CacheService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let ctx = req.extensions().get::<RouteContext>().cloned();
+        let policy = ctx.as_ref().and_then(|ctx| self.cache.config.for_route(&ctx.route)).cloned();
+        let (Some(ctx), Some(policy)) = (ctx, policy) else {
+            return Box::pin(self.inner.call(req));
+        };
+        if !cacheable_request(&req, &policy.key) {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let key = policy.key.key(&req, &ctx);
+        let route = route_label(&req).unwrap_or_default().to_string();
+        let head_only = req.method() == Method::HEAD;
+        if let Some(res) = self.cache.fresh(&route, &key, Instant::now(), head_only) {
+            return Box::pin(std::future::ready(Ok(res)));
+        }
+
+        let cache = self.cache.clone();
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let result = inner.call(req).await;
+            let failed = result.as_ref().map_or(true, |res| res.status().is_server_error());
+            if failed && !policy.stale_if_error.is_zero() {
+                if let Some(res) = cache.stale(&route, &key, Instant::now(), policy.stale_if_error, head_only) {
+                    return Ok(res);
+                }
+            }
+            let res = result?;
+            // HEAD responses have no body to store
+            if head_only {
+                return Ok(res);
+            }
+            let Some(ttl) = shared_ttl(&res, &policy) else {
+                return Ok(res);
+            };
+            if res.body().size_hint().exact().is_none_or(|len| len > cache.config.max_body_bytes) {
+                return Ok(res);
+            }
+
+            let (parts, body) = res.into_parts();
+            let body = body
+                .collect()
+                .await
+                .map_err(|source| ProxyError::UpstreamProtocol {
+                    backend: ctx.backend.id,
+                    addr: ctx.backend.addr,
+                    source,
+                })?
+                .to_bytes();
+            let stored_at = Instant::now();
+            let entry = Entry {
+                route,
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                stored_at,
+                expires_at: stored_at + ttl,
+            };
+            cache.insert(key, entry);
+            Ok(ProxyResponse::from_parts(parts, full_body(body)))
+        })
+    }
+}
+
+/// Whether `req` may be answered from (and stored in) the cache.
+fn cacheable_request(req: &ProxyRequest, key: &CacheKeyTemplate) -> bool {
+    let no_cache = req
+        .headers()
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|d| matches!(d.trim(), "no-cache" | "no-store")));
+    matches!(*req.method(), Method::GET | Method::HEAD)
+        && !no_cache
+        && (!req.headers().contains_key(AUTHORIZATION) || key.includes_header(AUTHORIZATION.as_str()))
+}
+
+/// How long `res` may be shared for, or `None` if it must not be stored.
+fn shared_ttl(res: &ProxyResponse, policy: &RouteCachePolicy) -> Option<Duration> {
+    let headers = res.headers();
+    if res.status() != StatusCode::OK || headers.contains_key(SET_COOKIE) {
+        return None;
+    }
+    let varies_outside_key = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|name| name == "*" || !policy.key.includes_header(name));
+    if varies_outside_key {
+        return None;
+    }
+
+    let mut ttl = policy.default_ttl;
+    let mut shared_max_age = None;
+    for directive in headers.get_all(CACHE_CONTROL).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+        let directive = directive.trim().to_ascii_lowercase();
+        match directive.split_once('=') {
+            None if matches!(directive.as_str(), "no-store" | "private" | "no-cache") => return None,
+            Some(("max-age", secs)) => ttl = Duration::from_secs(secs.trim_matches('"').parse().ok()?),
+            Some(("s-maxage", secs)) => shared_max_age = Some(Duration::from_secs(secs.trim_matches('"').parse().ok()?)),
+            _ => {}
+        }
+    }
+    let ttl = shared_max_age.unwrap_or(ttl);
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Request, Response};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::labels::Labels;
+
+    fn routed(uri: &str, headers: &[(&str, &str)]) -> ProxyRequest {
+        let mut req = Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let mut req = req.body(full_body(Bytes::new())).unwrap();
+        req.extensions_mut().insert(RouteContext {
+            route: "api".into(),
+            backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap())),
+            labels: Arc::new(Labels::new().with("tenant", "acme")),
+        });
+        req
+    }
+
+    #[test]
+    fn test_key_templates_select_and_normalize() {
+        let template = CacheKeyTemplate {
+            parts: vec![
+                KeyPart::Path,
+                KeyPart::Query(QueryParams::Except(vec!["utm_source".into()])),
+                KeyPart::Cookie("region".into()),
+                KeyPart::Label("tenant".into()),
+            ],
+            sort_query: true,
+        };
+        let key = |req: ProxyRequest| {
+            let ctx = req.extensions().get::<RouteContext>().cloned().unwrap();
+            template.key(&req, &ctx)
+        };
+
+        let a = key(routed("/items?b=2&a=1&utm_source=mail", &[("cookie", "session=x; region=eu")]));
+        let b = key(routed("/items?a=1&b=2", &[("cookie", "region=eu; session=y")]));
+        assert_eq!(a, b);
+        assert!(a.ends_with("acme"));
+        assert_ne!(a, key(routed("/items?a=1&b=2", &[("cookie", "region=us")])));
+        assert_ne!(a, key(routed("/items?a=1&b=2", &[])));
+    }
+
+    #[tokio::test]
+    async fn test_shareable_responses_are_served_from_cache() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let upstream = tower::service_fn(move |req: ProxyRequest| {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let cache_control = if req.uri().path() == "/private" { "private" } else { "max-age=60" };
+                let res = Response::builder()
+                    .header(CACHE_CONTROL, cache_control)
+                    .body(full_body(Bytes::from(n.to_string())))
+                    .unwrap();
+                Ok::<_, ProxyError>(res)
+            }
+        });
+        let config = ResponseCaching {
+            default: Some(RouteCachePolicy {
+                default_ttl: Duration::from_secs(10),
+                key: CacheKeyTemplate::default(),
+                stale_if_error: Duration::ZERO,
+            }),
+            ..ResponseCaching::default()
+        };
+        let service = CacheLayer::new(Arc::new(ResponseCache::new(config))).layer(upstream);
+
+        for _ in 0..2 {
+            let res = service.clone().oneshot(routed("/items", &[])).await.unwrap();
+            assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "0");
+        }
+        service.clone().oneshot(routed("/items", &[("authorization", "Bearer t")])).await.unwrap();
+        service.clone().oneshot(routed("/private", &[])).await.unwrap();
+        service.clone().oneshot(routed("/private", &[])).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_hits_stale_serves_and_evictions() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let fail = failing.clone();
+        let upstream = tower::service_fn(move |_req: ProxyRequest| {
+            let fail = fail.load(Ordering::SeqCst);
+            async move {
+                let status = if fail { StatusCode::BAD_GATEWAY } else { StatusCode::OK };
+                let res = Response::builder()
+                    .status(status)
+                    .body(full_body(Bytes::from_static(b"ok")))
+                    .unwrap();
+                Ok::<_, ProxyError>(res)
+            }
+        });
+        let config = ResponseCaching {
+            max_entries: 1,
+            default: Some(RouteCachePolicy {
+                default_ttl: Duration::from_secs(10),
+                key: CacheKeyTemplate::default(),
+                stale_if_error: Duration::from_secs(60),
+            }),
+            ..ResponseCaching::default()
+        };
+        let cache = Arc::new(ResponseCache::new(config));
+        let service = CacheLayer::new(cache.clone()).layer(upstream);
+
+        service.clone().oneshot(routed("/a", &[])).await.unwrap();
+        service.clone().oneshot(routed("/a", &[])).await.unwrap();
+        // Storing /b evicts /a
+        service.clone().oneshot(routed("/b", &[])).await.unwrap();
+        cache.store.lock().unwrap().entries.values_mut().for_each(|e| e.expires_at = Instant::now());
+        failing.store(true, Ordering::SeqCst);
+        let res = service.clone().oneshot(routed("/b", &[])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = service.clone().oneshot(routed("/a", &[])).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+
+        let stats = cache.cache_stats();
+        assert_eq!(stats.len(), 1);
+        let api = &stats[0];
+        assert_eq!(api.route, "api");
+        assert_eq!((api.hits, api.misses, api.stale_serves, api.evictions, api.entries), (1, 4, 1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_undeclared_routes_share_one_stats_entry() {
+        use crate::pipeline::{UndeclaredRoute, UNDECLARED_ROUTE};
+
+        let upstream = tower::service_fn(|_req: ProxyRequest| async {
+            Ok::<_, ProxyError>(Response::new(full_body(Bytes::from_static(b"ok"))))
+        });
+        let config = ResponseCaching {
+            default: Some(RouteCachePolicy {
+                default_ttl: Duration::from_secs(10),
+                key: CacheKeyTemplate::default(),
+                stale_if_error: Duration::ZERO,
+            }),
+            ..ResponseCaching::default()
+        };
+        let cache = Arc::new(ResponseCache::new(config));
+        let service = CacheLayer::new(cache.clone()).layer(upstream);
+
+        // Without declared routes, the route stage names each request's route by its path
+        for path in ["/a", "/b", "/c"] {
+            let mut req = routed(path, &[]);
+            req.extensions_mut().get_mut::<RouteContext>().unwrap().route = path.to_string();
+            req.extensions_mut().insert(UndeclaredRoute);
+            service.clone().oneshot(req).await.unwrap();
+        }
+
+        let stats = cache.cache_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].route.as_str(), stats[0].misses, stats[0].entries), (UNDECLARED_ROUTE, 3, 3));
+        assert_eq!(LOOKUPS.with_label_values(&["/a", "miss"]).get(), 0);
+    }
+}
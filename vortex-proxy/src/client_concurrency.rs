@@ -0,0 +1,321 @@
+//! Per-client caps on requests in flight.
+//!
+//! A single client (a runaway batch job, a retry storm behind one NAT
+//! address) can otherwise hold most of the backends' concurrency with
+//! slow requests, queueing everyone else behind it. With a cap, each
+//! client, identified by its address or by an API key header, may have at
+//! most `max_in_flight` requests in the proxy at once, counting until its
+//! response body has been sent; requests over the cap are answered with
+//! `429 Too Many Requests` without reaching a backend. Rejections are
+//! counted per offender, with API keys reduced to a fingerprint so the
+//! metrics don't leak them. Only the first `MAX_LABELLED_CLIENTS` offenders
+//! get a label of their own, as API keys are unbounded; later ones count as
+//! `other` and are only named in logs.
+
+use dashmap::DashMap;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::HeaderName;
+use prometheus::IntCounterVec;
+use std::fmt;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use vortex_core::load_balancer::hash::stable_hash;
+
+use crate::error::ProxyError;
+use crate::pipeline::{take_inner, ClientAddr, ProxyBody, ProxyFuture, ProxyRequest, ProxyResponse};
+
+/// Requests rejected for going over their client's cap, by client, or
+/// `other` past the first `MAX_LABELLED_CLIENTS` offenders.
+static REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_client_concurrency_rejections_total",
+        "Requests rejected because their client had too many in flight, by client address or API key fingerprint",
+        &["client"]
+    )
+    .expect("metric registers once")
+});
+
+/// Offenders counted under their own `REJECTIONS` label; more would let
+/// clients minting API keys grow the metric without bound.
+pub const MAX_LABELLED_CLIENTS: usize = 100;
+
+/// The `REJECTIONS` label of offenders past `MAX_LABELLED_CLIENTS`.
+pub const OTHER_CLIENTS: &str = "other";
+
+/// Offenders with a `REJECTIONS` label of their own.
+static LABELLED: LazyLock<Mutex<HashSet<Client>>> = LazyLock::new(Mutex::default);
+
+/// The `REJECTIONS` label `client` is counted under.
+fn rejection_label(client: Client) -> String {
+    let mut labelled = LABELLED.lock().expect("labelled clients lock poisoned");
+    if labelled.len() < MAX_LABELLED_CLIENTS || labelled.contains(&client) {
+        labelled.insert(client);
+        client.to_string()
+    } else {
+        OTHER_CLIENTS.to_string()
+    }
+}
+
+/// What tells clients apart.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ClientIdentity {
+    /// The client's IP address
+    #[default]
+    Ip,
+    /// An API key carried in this header; requests without it are told
+    /// apart by address
+    Header(HeaderName),
+}
+
+/// How many requests each client may have in flight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientConcurrency {
+    /// Requests one client may have in flight at once
+    pub max_in_flight: u32,
+    /// What identifies a client
+    pub identity: ClientIdentity,
+}
+
+impl Default for ClientConcurrency {
+    fn default() -> Self {
+        Self { max_in_flight: 64, identity: ClientIdentity::Ip }
+    }
+}
+
+/// One client, as counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Client {
+    Ip(IpAddr),
+    /// An API key, kept only as a hash
+    Key(u64),
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::Ip(ip) => write!(f, "{}", ip),
+            Client::Key(hash) => write!(f, "key:{:08x}", hash >> 32),
+        }
+    }
+}
+
+impl ClientIdentity {
+    /// The client `req` came from, if it can be told.
+    pub(crate) fn client(&self, req: &ProxyRequest) -> Option<Client> {
+        if let ClientIdentity::Header(name) = self {
+            if let Some(key) = req.headers().get(name) {
+                return Some(Client::Key(stable_hash(&[key.as_bytes()])));
+            }
+        }
+        req.extensions().get::<ClientAddr>().map(|ClientAddr(addr)| Client::Ip(addr.ip().to_canonical()))
+    }
+}
+
+impl ClientConcurrency {
+    fn client(&self, req: &ProxyRequest) -> Option<Client> {
+        self.identity.client(req)
+    }
+}
+
+/// In-flight counts of every client with requests in flight.
+#[derive(Debug, Default)]
+struct InFlight(DashMap<Client, u32>);
+
+impl InFlight {
+    /// Counts a request for `client`, unless it is at `max` already.
+    fn acquire(self: &Arc<Self>, client: Client, max: u32) -> Option<Slot> {
+        let mut count = self.0.entry(client).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot { in_flight: self.clone(), client })
+    }
+}
+
+/// A request counted against its client until dropped.
+#[derive(Debug)]
+struct Slot {
+    in_flight: Arc<InFlight>,
+    client: Client,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.in_flight.0.get_mut(&self.client) {
+            *count = count.saturating_sub(1);
+        }
+        // Idle clients are forgotten, so the map only holds busy ones
+        self.in_flight.0.remove_if(&self.client, |_, count| *count == 0);
+    }
+}
+
+/// A response body holding its request's slot until it is done.
+struct HeldBody {
+    inner: ProxyBody,
+    _slot: Slot,
+}
+
+impl Body for HeldBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Caps each client's requests in flight, per `ClientConcurrency`.
+#[derive(Debug, Clone)]
+pub struct ClientConcurrencyLayer {
+    config: Arc<ClientConcurrency>,
+    in_flight: Arc<InFlight>,
+}
+
+impl ClientConcurrencyLayer {
+    /// Create a layer capping clients per `config`.
+    pub fn new(config: ClientConcurrency) -> Self {
+        Self { config: Arc::new(config), in_flight: Arc::default() }
+    }
+}
+
+impl<S> Layer<S> for ClientConcurrencyLayer {
+    type Service = ClientConcurrencyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientConcurrencyService { inner, config: self.config.clone(), in_flight: self.in_flight.clone() }
+    }
+}
+
+/// Service produced by `ClientConcurrencyLayer`.
+#[derive(Clone)]
+pub struct ClientConcurrencyService<S> {
+    inner: S,
+    config: Arc<ClientConcurrency>,
+    in_flight: Arc<InFlight>,
+}
+
+impl<S> Service<ProxyRequest> for ClientConcurrencyService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let Some(client) = self.config.client(&req) else {
+            return Box::pin(self.inner.call(req));
+        };
+        let limit = self.config.max_in_flight;
+        let Some(slot) = self.in_flight.acquire(client, limit) else {
+            tracing::debug!(%client, limit, "client over its concurrency cap");
+            REJECTIONS.with_label_values(&[&rejection_label(client)]).inc();
+            let client = client.to_string();
+            return Box::pin(async move { Err(ProxyError::ClientConcurrency { client, limit }) });
+        };
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let res = inner.call(req).await?;
+            Ok(res.map(|inner| HeldBody { inner, _slot: slot }.boxed()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Request, Response, StatusCode};
+    use tower::ServiceExt;
+
+    fn request(ip: [u8; 4], key: Option<&str>) -> ProxyRequest {
+        let mut req = Request::builder().uri("/").body(crate::pipeline::full_body(Bytes::new())).unwrap();
+        if let Some(key) = key {
+            req.headers_mut().insert("x-api-key", key.parse().unwrap());
+        }
+        req.extensions_mut().insert(ClientAddr((ip, 40000).into()));
+        req
+    }
+
+    #[test]
+    fn test_clients_are_capped_separately() {
+        let in_flight = Arc::new(InFlight::default());
+        let (a, b) = (Client::Ip([10, 0, 0, 1].into()), Client::Ip([10, 0, 0, 2].into()));
+        let first = in_flight.acquire(a, 2).unwrap();
+        let _second = in_flight.acquire(a, 2).unwrap();
+        assert!(in_flight.acquire(a, 2).is_none());
+        assert!(in_flight.acquire(b, 2).is_some());
+
+        drop(first);
+        assert!(in_flight.acquire(a, 2).is_some());
+        // Clients with nothing in flight aren't kept around
+        assert!(!in_flight.0.contains_key(&b));
+    }
+
+    #[test]
+    fn test_api_keys_identify_clients_behind_one_address() {
+        let config = ClientConcurrency {
+            identity: ClientIdentity::Header(HeaderName::from_static("x-api-key")),
+            ..ClientConcurrency::default()
+        };
+        let alice = config.client(&request([10, 0, 0, 1], Some("alice-secret"))).unwrap();
+        let bob = config.client(&request([10, 0, 0, 1], Some("bob-secret"))).unwrap();
+        assert_ne!(alice, bob);
+        assert!(alice.to_string().starts_with("key:") && !alice.to_string().contains("alice"));
+        // Without a key, the address tells clients apart
+        assert_eq!(config.client(&request([10, 0, 0, 1], None)), Some(Client::Ip([10, 0, 0, 1].into())));
+    }
+
+    #[tokio::test]
+    async fn test_slots_are_held_until_the_body_is_dropped() {
+        let upstream = tower::service_fn(|_req: ProxyRequest| async {
+            Ok::<_, ProxyError>(Response::new(crate::pipeline::full_body(Bytes::from_static(b"done"))))
+        });
+        let config = ClientConcurrency { max_in_flight: 1, ..ClientConcurrency::default() };
+        let service = ClientConcurrencyLayer::new(config).layer(upstream);
+
+        let res = service.clone().oneshot(request([10, 0, 0, 1], None)).await.unwrap();
+        // The headers are back, but the body is still to be sent
+        let err = service.clone().oneshot(request([10, 0, 0, 1], None)).await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(matches!(err, ProxyError::ClientConcurrency { ref client, limit: 1 } if client == "10.0.0.1"));
+        // Other clients are unaffected
+        assert!(service.clone().oneshot(request([10, 0, 0, 2], None)).await.is_ok());
+
+        drop(res);
+        assert!(service.oneshot(request([10, 0, 0, 1], None)).await.is_ok());
+    }
+
+    #[test]
+    fn test_offender_labels_are_capped() {
+        let labels: Vec<(Client, String)> = (0..=MAX_LABELLED_CLIENTS as u64)
+            .map(|i| Client::Key((i + 1) << 40))
+            .map(|client| (client, rejection_label(client)))
+            .collect();
+        assert_eq!(labels.last().unwrap().1, OTHER_CLIENTS);
+        // Offenders with a label keep it
+        let (client, label) = labels.iter().find(|(_, label)| label != OTHER_CLIENTS).unwrap();
+        assert_eq!(&rejection_label(*client), label);
+    }
+}
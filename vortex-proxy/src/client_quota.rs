@@ -0,0 +1,284 @@
+//! Per-client request quotas, weighted by what requests cost.
+//!
+//! Each client, identified as for concurrency caps, has a token bucket that
+//! refills at `rate` per second up to `burst`. Every request spends its
+//! route's cost from it, so a heavy search spends its share of the budget
+//! faster than a health ping does. A cost is either fixed per route or
+//! reported by the upstream in a response header such as `X-Request-Cost`.
+//! Reported costs are estimated when the request is admitted, then settled
+//! once the response says what it really cost: an expensive request can put
+//! its client into debt, and a cheap one is refunded the difference. Clients
+//! out of budget are answered with `429 Too Many Requests` and a
+//! `Retry-After` of when their next request would be admitted.
+
+use hyper::header::HeaderName;
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower::{Layer, Service};
+
+use crate::client_concurrency::{Client, ClientIdentity};
+use crate::error::ProxyError;
+use crate::pipeline::{route_label, take_inner, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+use crate::token_bucket::TokenBuckets;
+
+/// Requests rejected for going over their client's quota, by route; by
+/// client would grow without bound, so offenders are only named in logs. So
+/// would by path, so without declared routes every request counts under one
+/// `default` route.
+static REJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_client_quota_rejections_total",
+        "Requests rejected because their client was out of quota, by route",
+        &["route"]
+    )
+    .expect("metric registers once")
+});
+
+/// Quota spent by admitted requests, by route as for `REJECTIONS`.
+static SPENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_client_quota_spent_total",
+        "Quota spent by admitted requests, by route",
+        &["route"]
+    )
+    .expect("metric registers once")
+});
+
+/// What a request on a route costs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteCost {
+    /// The same for every request
+    Fixed(u32),
+    /// Reported by the upstream in this response header, as a whole number;
+    /// requests are admitted on `estimate` and charged the difference once
+    /// the response arrives, or keep the estimate if it never says
+    Reported {
+        /// The response header carrying the cost
+        header: HeaderName,
+        /// What a request is assumed to cost until its response is in
+        estimate: u32,
+    },
+}
+
+impl RouteCost {
+    /// The cost charged when a request is admitted.
+    fn upfront(&self) -> u32 {
+        match self {
+            RouteCost::Fixed(cost) => *cost,
+            RouteCost::Reported { estimate, .. } => *estimate,
+        }
+    }
+}
+
+/// How much each client may spend, and what requests cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientQuota {
+    /// Quota refilled per second, per client
+    pub rate: f64,
+    /// Quota a client may spend at once
+    pub burst: u32,
+    /// What identifies a client
+    pub identity: ClientIdentity,
+    /// Cost of a request on a route without its own
+    pub default_cost: u32,
+    /// Costs for individual routes, by route name
+    pub routes: HashMap<String, RouteCost>,
+}
+
+impl Default for ClientQuota {
+    fn default() -> Self {
+        Self {
+            rate: 100.0,
+            burst: 200,
+            identity: ClientIdentity::Ip,
+            default_cost: 1,
+            routes: HashMap::new(),
+        }
+    }
+}
+
+/// Charges each client's requests against its quota, per `ClientQuota`.
+#[derive(Debug, Clone)]
+pub struct ClientQuotaLayer {
+    config: Arc<ClientQuota>,
+    buckets: Arc<TokenBuckets<Client>>,
+}
+
+impl ClientQuotaLayer {
+    /// Create a layer enforcing `config`.
+    pub fn new(config: ClientQuota) -> Self {
+        let buckets = Arc::new(TokenBuckets::new(config.rate, config.burst));
+        Self { config: Arc::new(config), buckets }
+    }
+}
+
+impl<S> Layer<S> for ClientQuotaLayer {
+    type Service = ClientQuotaService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientQuotaService { inner, config: self.config.clone(), buckets: self.buckets.clone() }
+    }
+}
+
+/// Service produced by `ClientQuotaLayer`.
+#[derive(Clone)]
+pub struct ClientQuotaService<S> {
+    inner: S,
+    config: Arc<ClientQuota>,
+    buckets: Arc<TokenBuckets<Client>>,
+}
+
+impl<S> Service<ProxyRequest> for ClientQuotaService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let Some(client) = self.config.identity.client(&req) else {
+            return Box::pin(self.inner.call(req));
+        };
+        let route = req.extensions().get::<RouteContext>().map(|ctx| ctx.route.clone()).unwrap_or_default();
+        let cost = self.config.routes.get(&route).cloned().unwrap_or(RouteCost::Fixed(self.config.default_cost));
+        let upfront = cost.upfront();
+        let label = route_label(&req).unwrap_or_default().to_string();
+        if let Err(retry_after) = self.buckets.try_take_n(client, f64::from(upfront), Instant::now()) {
+            let client = client.to_string();
+            REJECTIONS.with_label_values(&[&label]).inc();
+            tracing::debug!(%client, %route, ?retry_after, "client out of quota");
+            return Box::pin(async move { Err(ProxyError::QuotaExceeded { client, retry_after }) });
+        }
+
+        let RouteCost::Reported { header, .. } = cost else {
+            SPENT.with_label_values(&[&label]).inc_by(u64::from(upfront));
+            return Box::pin(self.inner.call(req));
+        };
+        let buckets = self.buckets.clone();
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let res = inner.call(req).await;
+            let reported = res.as_ref().ok().and_then(|res| res.headers().get(&header));
+            let actual = reported.and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse::<u32>().ok());
+            let spent = match actual {
+                Some(actual) => {
+                    buckets.charge(client, f64::from(actual) - f64::from(upfront), Instant::now());
+                    actual
+                }
+                None => upfront,
+            };
+            SPENT.with_label_values(&[&label]).inc_by(u64::from(spent));
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{full_body, ClientAddr};
+    use hyper::body::Bytes;
+    use hyper::{Request, Response};
+    use std::time::Duration;
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::{Backend, BackendId};
+
+    fn request(route: &str) -> ProxyRequest {
+        let mut req = Request::builder().uri("/").body(full_body(Bytes::new())).unwrap();
+        req.extensions_mut().insert(ClientAddr(([10, 0, 0, 1], 40000).into()));
+        req.extensions_mut().insert(RouteContext {
+            route: route.to_string(),
+            backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9000".parse().unwrap())),
+            labels: Arc::default(),
+        });
+        req
+    }
+
+    fn quota() -> ClientQuota {
+        let reported = RouteCost::Reported { header: HeaderName::from_static("x-request-cost"), estimate: 5 };
+        ClientQuota {
+            rate: 1.0,
+            burst: 20,
+            routes: HashMap::from([("search".to_string(), RouteCost::Fixed(10)), ("report".to_string(), reported)]),
+            ..ClientQuota::default()
+        }
+    }
+
+    /// An upstream reporting `cost` on every response.
+    fn upstream(
+        cost: &'static str,
+    ) -> impl Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError, Future: Send> + Clone + Send {
+        tower::service_fn(move |_req: ProxyRequest| async move {
+            let res = Response::builder().header("x-request-cost", cost).body(full_body(Bytes::new())).unwrap();
+            Ok::<_, ProxyError>(res)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_expensive_routes_spend_the_quota_faster() {
+        let layer = ClientQuotaLayer::new(quota());
+        for _ in 0..2 {
+            layer.layer(upstream("0")).oneshot(request("search")).await.unwrap();
+        }
+        let err = layer.layer(upstream("0")).oneshot(request("search")).await.unwrap_err();
+        assert_eq!(err.kind(), "quota_exceeded");
+        assert_eq!(err.status_code(), hyper::StatusCode::TOO_MANY_REQUESTS);
+        let ProxyError::QuotaExceeded { retry_after, .. } = err else { unreachable!() };
+        assert!(retry_after > Duration::from_secs(9) && retry_after <= Duration::from_secs(10), "{:?}", retry_after);
+
+        // A search spends what ten pings do
+        let layer = ClientQuotaLayer::new(quota());
+        layer.layer(upstream("0")).oneshot(request("search")).await.unwrap();
+        for _ in 0..10 {
+            layer.layer(upstream("0")).oneshot(request("health")).await.unwrap();
+        }
+        assert!(layer.layer(upstream("0")).oneshot(request("health")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reported_costs_settle_the_estimate() {
+        // Admitted on an estimate of 5 each, but charged 1: ten fit in a burst of 20
+        let layer = ClientQuotaLayer::new(quota());
+        for _ in 0..10 {
+            layer.layer(upstream("1")).oneshot(request("report")).await.unwrap();
+        }
+
+        // Estimated at 5, but one costs 30, leaving the client in debt
+        let layer = ClientQuotaLayer::new(quota());
+        layer.layer(upstream("30")).oneshot(request("report")).await.unwrap();
+        let err = layer.layer(upstream("1")).oneshot(request("report")).await.unwrap_err();
+        assert_eq!(err.kind(), "quota_exceeded");
+    }
+
+    #[tokio::test]
+    async fn test_undeclared_routes_count_under_one_label() {
+        use crate::pipeline::{UndeclaredRoute, UNDECLARED_ROUTE};
+
+        // Without declared routes, the route stage names each request's route by its path
+        let undeclared = |path: &str| {
+            let mut req = request(path);
+            req.extensions_mut().insert(UndeclaredRoute);
+            req
+        };
+        let layer = ClientQuotaLayer::new(ClientQuota { rate: 1.0, burst: 2, ..ClientQuota::default() });
+        let spent = SPENT.with_label_values(&[UNDECLARED_ROUTE]).get();
+        let rejected = REJECTIONS.with_label_values(&[UNDECLARED_ROUTE]).get();
+        for path in ["/users/1", "/users/2"] {
+            layer.layer(upstream("0")).oneshot(undeclared(path)).await.unwrap();
+        }
+        layer.layer(upstream("0")).oneshot(undeclared("/users/3")).await.unwrap_err();
+
+        assert_eq!(SPENT.with_label_values(&[UNDECLARED_ROUTE]).get() - spent, 2);
+        assert_eq!(REJECTIONS.with_label_values(&[UNDECLARED_ROUTE]).get() - rejected, 1);
+        assert_eq!(SPENT.with_label_values(&["/users/1"]).get(), 0);
+    }
+}
@@ -0,0 +1,305 @@
+//! Compression of request bodies sent to upstreams.
+//!
+//! Chatty JSON APIs behind a WAN link benefit from compressing what the proxy
+//! forwards, but only a backend that can decode it may receive it. Backends
+//! advertise the request encodings they accept with an `Accept-Encoding`
+//! response header (RFC 7694); the proxy remembers the latest advertisement
+//! per backend and compresses eligible bodies with the first configured
+//! encoding it lists. A `415 Unsupported Media Type` reply to a compressed
+//! request withdraws that encoding for the backend.
+
+use dashmap::DashMap;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::StatusCode;
+use std::io::Write;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use vortex_core::domain::backend::BackendId;
+
+use crate::error::ProxyError;
+use crate::pipeline::{buffered_body, take_inner, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// A content coding the proxy can apply to request bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `gzip`
+    Gzip,
+    /// `zstd`
+    Zstd,
+}
+
+impl Encoding {
+    /// The `Content-Encoding` token.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(data, 3),
+        }
+    }
+}
+
+/// Which request bodies a pool compresses, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestCompression {
+    /// Encodings in order of preference
+    pub encodings: Vec<Encoding>,
+    /// Bodies smaller than this are sent as-is
+    pub min_bytes: u64,
+    /// Bodies larger than this (or of unknown length) are streamed as-is
+    pub max_bytes: u64,
+    /// `Content-Type` prefixes eligible for compression
+    pub content_types: Vec<String>,
+}
+
+impl Default for RequestCompression {
+    fn default() -> Self {
+        Self {
+            encodings: vec![Encoding::Zstd, Encoding::Gzip],
+            min_bytes: 1024,
+            max_bytes: 1024 * 1024,
+            content_types: vec!["application/json".into(), "text/".into()],
+        }
+    }
+}
+
+impl RequestCompression {
+    fn eligible(&self, req: &ProxyRequest) -> bool {
+        let headers = req.headers();
+        let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+        !headers.contains_key(CONTENT_ENCODING)
+            && self.content_types.iter().any(|prefix| content_type.starts_with(prefix.as_str()))
+            && req
+                .body()
+                .size_hint()
+                .exact()
+                .is_some_and(|len| (self.min_bytes..=self.max_bytes).contains(&len))
+    }
+}
+
+/// Parses the encodings a backend accepts from its `Accept-Encoding` response header.
+fn advertised_encodings(headers: &HeaderMap) -> Option<Vec<Encoding>> {
+    let mut encodings = Vec::new();
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        for token in value.to_str().ok()?.split(',') {
+            let mut params = token.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let refused = params.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+            let encoding = match name.to_ascii_lowercase().as_str() {
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                "zstd" => Encoding::Zstd,
+                _ => continue,
+            };
+            if !refused {
+                encodings.push(encoding);
+            }
+        }
+    }
+    headers.contains_key(ACCEPT_ENCODING).then_some(encodings)
+}
+
+/// Compresses eligible request bodies for backends that accept it.
+#[derive(Debug, Clone)]
+pub struct CompressionLayer {
+    config: Arc<RequestCompression>,
+    advertised: Arc<DashMap<BackendId, Vec<Encoding>>>,
+}
+
+impl CompressionLayer {
+    /// Create a layer compressing per `config`.
+    pub fn new(config: RequestCompression) -> Self {
+        Self {
+            config: Arc::new(config),
+            advertised: Arc::default(),
+        }
+    }
+}
+
+impl<S> Layer<S> for CompressionLayer {
+    type Service = CompressionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CompressionService {
+            inner,
+            config: self.config.clone(),
+            advertised: self.advertised.clone(),
+        }
+    }
+}
+
+/// Service produced by `CompressionLayer`.
+#[derive(Debug, Clone)]
+pub struct CompressionService<S> {
+    inner: S,
+    config: Arc<RequestCompression>,
+    advertised: Arc<DashMap<BackendId, Vec<Encoding>>>,
+}
+
+impl<S> Service<ProxyRequest> for CompressionService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let Some(RouteContext { route, backend, .. }) = req.extensions().get::<RouteContext>().cloned() else {
+            return Box::pin(self.inner.call(req));
+        };
+        let encoding = self.config.eligible(&req).then(|| {
+            let advertised = self.advertised.get(&backend.id)?;
+            self.config.encodings.iter().copied().find(|e| advertised.contains(e))
+        });
+        let encoding = encoding.flatten();
+
+        let mut inner = take_inner(&mut self.inner);
+        let advertised = self.advertised.clone();
+        Box::pin(async move {
+            let req = match encoding {
+                Some(encoding) => compress_request(req, encoding, &route).await?,
+                None => req,
+            };
+            let res = inner.call(req).await?;
+
+            if res.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE {
+                if let Some(encoding) = encoding {
+                    if let Some(mut accepted) = advertised.get_mut(&backend.id) {
+                        accepted.retain(|e| *e != encoding);
+                    }
+                }
+            }
+            if let Some(encodings) = advertised_encodings(res.headers()) {
+                advertised.insert(backend.id, encodings);
+            }
+            Ok(res)
+        })
+    }
+}
+
+async fn compress_request(req: ProxyRequest, encoding: Encoding, route: &str) -> Result<ProxyRequest, ProxyError> {
+    let (mut parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(|e| ProxyError::InvalidRequest {
+        route: route.to_string(),
+        reason: format!("failed to read request body: {}", e),
+    })?;
+    let trailers = body.trailers().cloned();
+    let body = body.to_bytes();
+
+    match encoding.compress(&body) {
+        Ok(compressed) if compressed.len() < body.len() => {
+            parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+            if trailers.is_some() {
+                // HTTP/1.1 only carries trailers on a chunked body
+                parts.headers.remove(CONTENT_LENGTH);
+            } else {
+                parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+            }
+            Ok(ProxyRequest::from_parts(parts, buffered_body(Bytes::from(compressed), trailers)))
+        }
+        // Incompressible payloads (or an encoder failure) go out unchanged
+        _ => Ok(ProxyRequest::from_parts(parts, buffered_body(body, trailers))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use hyper::Request;
+    use std::sync::Mutex;
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::Backend;
+
+    /// Echoes the request's `Content-Encoding`, advertising the given encodings.
+    #[derive(Clone)]
+    struct Upstream {
+        advertise: &'static str,
+        seen: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl Service<ProxyRequest> for Upstream {
+        type Response = ProxyResponse;
+        type Error = ProxyError;
+        type Future = std::future::Ready<Result<ProxyResponse, ProxyError>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: ProxyRequest) -> Self::Future {
+            let encoding = req.headers().get(CONTENT_ENCODING).map(|v| v.to_str().unwrap().to_string());
+            self.seen.lock().unwrap().push(encoding);
+            let mut res = ProxyResponse::new(full_body(Bytes::new()));
+            res.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static(self.advertise));
+            std::future::ready(Ok(res))
+        }
+    }
+
+    fn json_request(backend: &Arc<Backend>) -> ProxyRequest {
+        let body = Bytes::from(format!("[{}]", vec!["{\"k\":\"value\"}"; 200].join(",")));
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/api")
+            .header(CONTENT_TYPE, "application/json")
+            .body(full_body(body))
+            .unwrap();
+        req.extensions_mut().insert(RouteContext {
+            route: "/api".into(),
+            backend: backend.clone(),
+            labels: Arc::default(),
+        });
+        req
+    }
+
+    #[tokio::test]
+    async fn test_compresses_only_after_backend_advertises() {
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap()));
+        let upstream = Upstream { advertise: "gzip", seen: Arc::default() };
+        let service = CompressionLayer::new(RequestCompression::default()).layer(upstream.clone());
+
+        service.clone().oneshot(json_request(&backend)).await.unwrap();
+        service.oneshot(json_request(&backend)).await.unwrap();
+
+        assert_eq!(*upstream.seen.lock().unwrap(), [None, Some("gzip".to_string())]);
+    }
+
+    #[test]
+    fn test_parse_advertised_encodings() {
+        let headers: HeaderMap = [(ACCEPT_ENCODING, HeaderValue::from_static("gzip;q=0.5, br, zstd;q=0"))]
+            .into_iter()
+            .collect();
+        assert_eq!(advertised_encodings(&headers), Some(vec![Encoding::Gzip]));
+        assert_eq!(advertised_encodings(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"{\"hello\":\"world\"}".repeat(100);
+        let gz = Encoding::Gzip.compress(&data).unwrap();
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&gz[..]), &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        let zst = Encoding::Zstd.compress(&data).unwrap();
+        assert_eq!(zstd::decode_all(&zst[..]).unwrap(), data);
+    }
+}
@@ -0,0 +1,81 @@
+//! Dry-run validation of a configuration file, for gating deploys.
+//!
+//! `vortex-proxy --validate-config` runs everything the proxy would check
+//! when starting from the file, without binding anything: the TOML syntax,
+//! the semantic checks of `ProxyConfig::diagnostics`, what this binary
+//! supports (at least one cluster and one listener) and whether every TLS
+//! listener's certificate and key load. Backend and listener addresses must
+//! be IP literals, so an unresolvable address already fails the syntax check;
+//! names to resolve go in a cluster's `hostnames` or `srv` instead.
+
+use std::path::Path;
+use vortex_core::config::{ConfigError, Diagnostic, ProxyConfig};
+
+use crate::tls;
+
+/// Every problem in the configuration file at `path`; empty if the proxy
+/// would start from it.
+pub fn check_config(path: &Path) -> Vec<Diagnostic> {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => return vec![diagnostic("file", e.to_string())],
+    };
+    let config = match ProxyConfig::parse_unvalidated(&source) {
+        Ok(config) => config,
+        Err(ConfigError::Parse(message)) => return vec![diagnostic("file", message.trim_end().to_string())],
+        Err(e) => return vec![diagnostic("file", e.to_string())],
+    };
+
+    let mut problems = config.diagnostics();
+    if config.listeners.is_empty() {
+        problems.push(diagnostic("listeners", "no listeners are configured".to_string()));
+    }
+    if config.clusters.is_empty() {
+        problems.push(diagnostic("clusters", "no clusters are configured".to_string()));
+    }
+    for (i, listener) in config.listeners.iter().enumerate() {
+        if let Some(files) = &listener.tls {
+            if let Err(e) = tls::load_tls_config(&files.cert, &files.key) {
+                problems.push(diagnostic(&format!("listeners[{}].tls", i), e.to_string()));
+            }
+        }
+    }
+    problems
+}
+
+fn diagnostic(location: &str, message: String) -> Diagnostic {
+    Diagnostic {
+        location: location.to_string(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_reports_missing_files() {
+        let path = std::env::temp_dir().join(format!("vortex-check-{}.toml", std::process::id()));
+        assert_eq!(check_config(&path)[0].location, "file");
+
+        let config = r#"
+            [[listeners]]
+            address = "0.0.0.0:8443"
+            tls = { cert = "/nonexistent/cert.pem", key = "/nonexistent/key.pem" }
+
+            [[clusters]]
+            name = "web"
+            backends = [{ address = "127.0.0.1:9090" }]
+        "#;
+        std::fs::write(&path, config).unwrap();
+        let problems = check_config(&path);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].location, "listeners[0].tls");
+        assert!(problems[0].message.contains("/nonexistent/cert.pem"));
+
+        std::fs::write(&path, config.replace("address = \"127.0.0.1:9090\"", "address = \"db.internal:5432\"")).unwrap();
+        assert!(check_config(&path)[0].message.contains("invalid socket address"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
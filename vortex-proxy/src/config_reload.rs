@@ -0,0 +1,360 @@
+//! Reloading the configuration file while the proxy runs.
+//!
+//! On `SIGHUP`, or when the file's modification time or size changes, the
+//! file is parsed and validated again and the differences applied in place:
+//! each cluster's routing table swaps to its new backends (removed ones
+//! drain, so in-flight requests finish), pool labels are replaced and the
+//! route table is swapped. Backends whose address, ID and metadata are unchanged are kept
+//! as they are, with their EWMA, health and active requests.
+//!
+//! A file that fails to parse or validate is rejected as a whole and the
+//! running configuration stays in place, as it does when clusters are added,
+//! removed or renamed. Listeners, the metrics address and each cluster's
+//! health check and load balancer are only read at startup; changing them
+//! logs a warning and needs a restart.
+
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use vortex_core::config::{ConfigError, ProxyConfig};
+use vortex_core::domain::backend::SharedBackend;
+use vortex_core::domain::routing::SharedRoutingTable;
+use vortex_core::route::SharedRouteTable;
+
+/// Reload attempts, by `result`: `applied`, `unchanged` or `rejected`
+static RELOADS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_config_reloads_total",
+        "Configuration reload attempts by result",
+        &["result"]
+    )
+    .expect("metric registers once")
+});
+
+/// Where the configuration comes from and what is running now.
+#[derive(Debug, Clone)]
+pub struct ConfigReload {
+    /// The configuration file
+    pub path: PathBuf,
+    /// The configuration the proxy was started with
+    pub loaded: ProxyConfig,
+    /// How often the file is checked for changes; `None` to reload on `SIGHUP` only
+    pub poll_interval: Option<Duration>,
+}
+
+impl ConfigReload {
+    /// Watch `path`, which the proxy was started from as `loaded`, checking
+    /// for changes every 2 seconds.
+    pub fn new(path: impl Into<PathBuf>, loaded: ProxyConfig) -> Self {
+        Self {
+            path: path.into(),
+            loaded,
+            poll_interval: Some(Duration::from_secs(2)),
+        }
+    }
+}
+
+/// Applies a changed configuration file to the running proxy.
+pub(crate) struct ConfigReloader {
+    path: PathBuf,
+    current: ProxyConfig,
+    routing_table: SharedRoutingTable,
+    clusters: HashMap<String, SharedRoutingTable>,
+    route_table: Option<SharedRouteTable>,
+}
+
+impl ConfigReloader {
+    /// `routing_table` is the first cluster's, the default pool; `clusters`
+    /// hold every other cluster's by name.
+    pub(crate) fn new(
+        config: ConfigReload,
+        routing_table: SharedRoutingTable,
+        clusters: HashMap<String, SharedRoutingTable>,
+        route_table: Option<SharedRouteTable>,
+    ) -> Self {
+        Self {
+            path: config.path,
+            current: config.loaded,
+            routing_table,
+            clusters,
+            route_table,
+        }
+    }
+
+    /// Reload the file, returning whether anything was applied. On error
+    /// nothing is.
+    pub(crate) fn reload(&mut self) -> Result<bool, ConfigError> {
+        let result = ProxyConfig::load(&self.path).and_then(|next| self.apply(next));
+        let outcome = match &result {
+            Ok(true) => "applied",
+            Ok(false) => "unchanged",
+            Err(_) => "rejected",
+        };
+        RELOADS.with_label_values(&[outcome]).inc();
+        result
+    }
+
+    fn apply(&mut self, next: ProxyConfig) -> Result<bool, ConfigError> {
+        if next == self.current {
+            return Ok(false);
+        }
+        // Everything that can be rejected is checked before anything changes
+        let names = |config: &ProxyConfig| config.clusters.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        if names(&next) != names(&self.current) {
+            return Err(ConfigError::Invalid(
+                "adding, removing, renaming or reordering clusters requires a restart".to_string(),
+            ));
+        }
+        let mut pools = Vec::with_capacity(next.clusters.len());
+        for (i, cluster) in next.clusters.iter().enumerate() {
+            let routing_table = match i {
+                0 => &self.routing_table,
+                _ => self.clusters.get(&cluster.name).ok_or_else(|| {
+                    ConfigError::Invalid(format!("cluster {} isn't running; it needs a restart", cluster.name))
+                })?,
+            };
+            pools.push((cluster, routing_table));
+        }
+        if next.clusters.iter().any(|c| c.hostnames.is_some() || c.srv.is_some()) {
+            return Err(ConfigError::Invalid("switching to resolved backends requires a restart".to_string()));
+        }
+        if self.route_table.is_some() == next.routes.is_empty() {
+            return Err(ConfigError::Invalid(
+                "adding the first route or removing the last one requires a restart".to_string(),
+            ));
+        }
+
+        let restart_only = [
+            ("listeners", next.listeners != self.current.listeners),
+            ("metrics_address", next.metrics_address != self.current.metrics_address),
+        ];
+        for (field, _) in restart_only.iter().filter(|(_, changed)| *changed) {
+            tracing::warn!(field, path = %self.path.display(), "setting changed but only applies after a restart");
+        }
+        for (cluster, running) in next.clusters.iter().zip(&self.current.clusters) {
+            let restart_only = [
+                ("clusters.health_check", cluster.health_check != running.health_check),
+                ("clusters.load_balancer", cluster.load_balancer != running.load_balancer),
+                ("clusters.hash_key", cluster.hash_key != running.hash_key),
+                ("clusters.maglev_table_size", cluster.maglev_table_size != running.maglev_table_size),
+                ("clusters.failover_percent", cluster.failover_percent != running.failover_percent),
+                ("clusters.zone_affinity", cluster.zone_affinity != running.zone_affinity),
+            ];
+            for (field, _) in restart_only.iter().filter(|(_, changed)| *changed) {
+                tracing::warn!(
+                    field,
+                    cluster = %cluster.name,
+                    path = %self.path.display(),
+                    "setting changed but only applies after a restart"
+                );
+            }
+        }
+
+        let mut fresh = next.backends();
+        for (cluster, routing_table) in pools {
+            reload_backends(&cluster.name, routing_table, fresh.remove(&cluster.name).unwrap_or_default());
+            routing_table.update_labels(cluster.labels.iter().collect());
+        }
+        if let Some(route_table) = &self.route_table {
+            if next.routes != self.current.routes {
+                tracing::info!(routes = next.routes.len(), "reloaded routes");
+                route_table.update_routes(next.route_specs());
+            }
+        }
+
+        self.current = next;
+        Ok(true)
+    }
+}
+
+/// Swaps `routing_table` to the backends `fresh` describes, keeping the
+/// running ones that are unchanged.
+fn reload_backends(cluster: &str, routing_table: &SharedRoutingTable, fresh: Vec<SharedBackend>) {
+    let running = routing_table.snapshot();
+    let backends: Vec<SharedBackend> = fresh
+        .into_iter()
+        .map(|fresh| {
+            let same = |b: &&SharedBackend| {
+                b.id == fresh.id
+                    && b.addr == fresh.addr
+                    && b.host == fresh.host
+                    && b.priority == fresh.priority
+                    && b.metadata == fresh.metadata
+            };
+            match running.iter().find(same) {
+                // Weights change in place, keeping the backend's latency history
+                Some(backend) => {
+                    backend.set_weight(fresh.weight());
+                    backend.slow_start.set_window(fresh.slow_start.window());
+                    backend.clone()
+                }
+                // New to a running pool, so it ramps up rather than taking full load at once
+                None => {
+                    fresh.slow_start.begin();
+                    fresh
+                }
+            }
+        })
+        .collect();
+    let unchanged = running.len() == backends.len() && running.iter().zip(&backends).all(|(a, b)| Arc::ptr_eq(a, b));
+    if !unchanged {
+        let added = backends.iter().filter(|b| !running.iter().any(|r| Arc::ptr_eq(r, b))).count();
+        let removed = running.iter().filter(|r| !backends.iter().any(|b| Arc::ptr_eq(r, b))).count();
+        tracing::info!(cluster, added, removed, "reloaded backends");
+        routing_table.update_backends(backends);
+    }
+}
+
+/// The file's modification time and size, to notice it changing.
+fn stamp(path: &std::path::Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Spawns a task reloading the configuration on `SIGHUP` and, if polling,
+/// whenever the file changes.
+pub fn spawn_config_reload(
+    config: ConfigReload,
+    routing_table: SharedRoutingTable,
+    clusters: HashMap<String, SharedRoutingTable>,
+    route_table: Option<SharedRouteTable>,
+) -> std::io::Result<JoinHandle<()>> {
+    #[cfg(unix)]
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let poll_interval = config.poll_interval;
+    let mut reloader = ConfigReloader::new(config, routing_table, clusters, route_table);
+
+    Ok(tokio::spawn(async move {
+        let mut last_stamp = stamp(&reloader.path);
+        let mut ticker = poll_interval.map(tokio::time::interval);
+        loop {
+            let tick = async {
+                match &mut ticker {
+                    Some(ticker) => {
+                        ticker.tick().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(unix)]
+            let hangup = sighup.recv();
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = tick => {
+                    let next_stamp = stamp(&reloader.path);
+                    if next_stamp == last_stamp {
+                        continue;
+                    }
+                    last_stamp = next_stamp;
+                }
+                signal = hangup => {
+                    if signal.is_none() {
+                        return;
+                    }
+                    last_stamp = stamp(&reloader.path);
+                }
+            }
+            match reloader.reload() {
+                Ok(true) => tracing::info!(path = %reloader.path.display(), "reloaded configuration"),
+                Ok(false) => {}
+                Err(e) => tracing::error!(error = %e, "kept the running configuration"),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vortex_core::domain::backend::BackendId;
+    use vortex_core::route::RouteTable;
+
+    const CONFIG: &str = r#"
+        [[clusters]]
+        name = "web"
+        backends = [{ address = "127.0.0.1:9090", id = 1 }, { address = "127.0.0.1:9091", id = 2 }]
+
+        [[routes]]
+        name = "all"
+        cluster = "web"
+    "#;
+
+    #[test]
+    fn test_reload_applies_changes_and_rejects_broken_files() {
+        let path = std::env::temp_dir().join(format!("vortex-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let loaded = ProxyConfig::load(&path).unwrap();
+        let routing_table = Arc::new(loaded.routing_table("web").unwrap());
+        let route_table = Arc::new(RouteTable::new(loaded.route_specs()));
+        let mut reloader = ConfigReloader::new(
+            ConfigReload::new(&path, loaded),
+            routing_table.clone(),
+            HashMap::new(),
+            Some(route_table.clone()),
+        );
+        assert!(!reloader.reload().unwrap());
+        let kept = routing_table.snapshot()[0].clone();
+
+        let changed = CONFIG
+            .replace(r#"{ address = "127.0.0.1:9091", id = 2 }"#, r#"{ address = "127.0.0.1:9092", id = 3 }"#)
+            .replace(r#"name = "all""#, "name = \"api\"\npath_prefix = \"/api/\"")
+            .replace(r#"name = "web""#, "name = \"web\"\nslow_start_ms = 10000");
+        std::fs::write(&path, &changed).unwrap();
+        assert!(reloader.reload().unwrap());
+        let backends = routing_table.snapshot();
+        assert!(Arc::ptr_eq(&backends[0], &kept));
+        assert_eq!(backends[1].id, BackendId(3));
+        // Only the new backend ramps up, though both take the new window
+        assert!(backends[1].slow_start.is_ramping() && !backends[0].slow_start.is_ramping());
+        assert_eq!(backends[0].slow_start.window(), std::time::Duration::from_secs(10));
+        assert_eq!(routing_table.draining()[0].backend.id, BackendId(2));
+        assert_eq!(route_table.matcher().routes()[0].name, "api");
+
+        // A broken file leaves everything as it was
+        std::fs::write(&path, changed.replace("cluster = \"web\"", "cluster = \"gone\"")).unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(routing_table.snapshot().len(), 2);
+        assert_eq!(route_table.matcher().routes()[0].name, "api");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(reloader.reload(), Err(ConfigError::Io { .. })));
+    }
+
+    #[test]
+    fn test_reload_reaches_every_cluster() {
+        let config = format!(
+            "{}\n[[clusters]]\nname = \"api\"\nbackends = [{{ address = \"127.0.0.1:9190\", id = 11 }}]\n\n\
+             [[routes]]\nname = \"api\"\npath_prefix = \"/api/\"\ncluster = \"api\"",
+            CONFIG
+        );
+        let path = std::env::temp_dir().join(format!("vortex-reload-clusters-{}.toml", std::process::id()));
+        std::fs::write(&path, &config).unwrap();
+        let loaded = ProxyConfig::load(&path).unwrap();
+        let routing_table = Arc::new(loaded.routing_table("web").unwrap());
+        let api = Arc::new(loaded.routing_table("api").unwrap());
+        let route_table = Arc::new(RouteTable::new(loaded.route_specs()));
+        let clusters = HashMap::from([("api".to_string(), api.clone())]);
+        let mut reloader =
+            ConfigReloader::new(ConfigReload::new(&path, loaded), routing_table.clone(), clusters, Some(route_table));
+
+        let changed =
+            config.replace(r#"{ address = "127.0.0.1:9190", id = 11 }"#, r#"{ address = "127.0.0.1:9191", id = 12 }"#);
+        std::fs::write(&path, &changed).unwrap();
+        assert!(reloader.reload().unwrap());
+        assert_eq!(api.snapshot()[0].id, BackendId(12));
+        assert_eq!(api.draining()[0].backend.id, BackendId(11));
+        assert_eq!(routing_table.snapshot().len(), 2);
+
+        // Clusters the proxy didn't start with would have no routing table to fill
+        let renamed = changed.replace("name = \"api\"\nbackends", "name = \"orders\"\nbackends");
+        std::fs::write(&path, renamed.replace(r#"cluster = "api""#, r#"cluster = "orders""#)).unwrap();
+        assert!(matches!(reloader.reload(), Err(ConfigError::Invalid(e)) if e.contains("renaming")));
+        assert_eq!(api.snapshot()[0].id, BackendId(12));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
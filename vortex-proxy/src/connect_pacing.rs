@@ -0,0 +1,139 @@
+//! Pacing of new upstream connections per backend.
+//!
+//! When a burst of requests misses the connection pool for the same backend
+//! (a traffic spike, or a backend that just came back and has no warm
+//! connections), each of them would otherwise open its own connection at
+//! once, flooding the backend with SYNs just as it recovers. With pacing,
+//! new connections to a backend draw from a token bucket. A request that
+//! finds the bucket empty waits a little, picking up any connection that
+//! goes back to the pool in the meantime (typically one another request has
+//! just opened and used), or dialing once a token refills. Requests still
+//! waiting after `max_wait` fail with `ConnectPaced`, which retries treat as
+//! never sent.
+
+use prometheus::IntCounterVec;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use crate::connection_pool::pool::{ConnectionPool, PoolKey, PooledConnection};
+use crate::token_bucket::TokenBuckets;
+
+/// How often a waiting request looks for a pooled connection.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Requests that found a backend's connect bucket empty, by outcome:
+/// `pooled`, `dialed`, or `rejected`.
+static WAITS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_connect_pacing_waits_total",
+        "Requests held back by upstream connect pacing, by outcome: pooled, dialed, or rejected",
+        &["outcome"]
+    )
+    .expect("metric registers once")
+});
+
+/// How fast new connections to one backend may be opened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectPacing {
+    /// New connections per second, per backend
+    pub rate: f64,
+    /// Connections that may be opened at once after a quiet period
+    pub burst: u32,
+    /// How long a request waits for a connection before giving up
+    pub max_wait: Duration,
+}
+
+impl Default for ConnectPacing {
+    fn default() -> Self {
+        Self {
+            rate: 50.0,
+            burst: 10,
+            max_wait: Duration::from_millis(250),
+        }
+    }
+}
+
+/// How a paced request gets its connection.
+#[derive(Debug)]
+pub enum Paced {
+    /// A connection went back to the pool while it waited
+    Pooled(PooledConnection),
+    /// It may open a new connection
+    Dial,
+}
+
+/// Paces new connections to every backend; clones share the buckets.
+#[derive(Debug, Clone)]
+pub struct ConnectPacer {
+    pacing: ConnectPacing,
+    buckets: Arc<TokenBuckets<PoolKey>>,
+}
+
+impl ConnectPacer {
+    /// A pacer with every backend's bucket full.
+    pub fn new(pacing: ConnectPacing) -> Self {
+        Self {
+            pacing,
+            buckets: Arc::new(TokenBuckets::new(pacing.rate, pacing.burst)),
+        }
+    }
+
+    /// Waits until the request may open a connection to `key`, or another
+    /// one is pooled, for at most `max_wait` or `limit`, whichever is
+    /// shorter. `None` if neither happened in time.
+    pub async fn wait_for_turn(&self, pool: &ConnectionPool, key: PoolKey, limit: Duration) -> Option<Paced> {
+        let start = Instant::now();
+        let mut wait = match self.buckets.try_take(key, start) {
+            Ok(()) => return Some(Paced::Dial),
+            Err(wait) => wait,
+        };
+        let give_up = start + self.pacing.max_wait.min(limit);
+        loop {
+            let now = Instant::now();
+            if now >= give_up {
+                WAITS.with_label_values(&["rejected"]).inc();
+                return None;
+            }
+            tokio::time::sleep(wait.min(POLL_INTERVAL).min(give_up - now)).await;
+            while let Some(mut conn) = pool.try_pop(&key) {
+                if conn.sender.ready().await.is_ok() {
+                    WAITS.with_label_values(&["pooled"]).inc();
+                    return Some(Paced::Pooled(conn));
+                }
+                pool.retire(key, conn);
+            }
+            match self.buckets.try_take(key, Instant::now()) {
+                Ok(()) => {
+                    WAITS.with_label_values(&["dialed"]).inc();
+                    return Some(Paced::Dial);
+                }
+                Err(next) => wait = next,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vortex_core::domain::backend::BackendId;
+
+    fn key(id: u32) -> PoolKey {
+        PoolKey { backend: BackendId(id), addr: ([127, 0, 0, 1], 9000).into() }
+    }
+
+    #[tokio::test]
+    async fn test_waiters_dial_when_a_token_refills_or_give_up() {
+        let pacing = ConnectPacing { rate: 50.0, burst: 1, max_wait: Duration::from_millis(100) };
+        let pacer = ConnectPacer::new(pacing);
+        let pool = ConnectionPool::new();
+        assert!(matches!(pacer.wait_for_turn(&pool, key(1), Duration::MAX).await, Some(Paced::Dial)));
+
+        let start = Instant::now();
+        assert!(matches!(pacer.wait_for_turn(&pool, key(1), Duration::MAX).await, Some(Paced::Dial)));
+        assert!(start.elapsed() >= Duration::from_millis(15), "dialed after {:?}", start.elapsed());
+
+        // Bounded by the caller's limit as well as its own
+        assert!(pacer.wait_for_turn(&pool, key(1), Duration::from_millis(5)).await.is_none());
+    }
+}
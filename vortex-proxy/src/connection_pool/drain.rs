@@ -0,0 +1,101 @@
+//! Closing pooled connections whose backend is gone: backends that finished
+//! draining, and old addresses of backends that were re-resolved.
+
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use vortex_core::domain::routing::SharedRoutingTable;
+
+use super::pool::{ConnectionPool, PoolKey};
+
+/// Spawns a task that, every `interval`, closes the pooled connections of
+/// removed backends whose drain grace period has ended and of addresses no
+/// backend uses any more.
+pub fn spawn_drain_reaper(routing_table: SharedRoutingTable, pool: ConnectionPool, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            reap(&routing_table, &pool, Instant::now());
+        }
+    })
+}
+
+fn reap(routing_table: &SharedRoutingTable, pool: &ConnectionPool, now: Instant) {
+    for backend in routing_table.take_drained(now) {
+        let closed = pool.close_backend(&PoolKey::from(&*backend));
+        tracing::info!(
+            backend = backend.id.0,
+            addr = %backend.addr,
+            in_flight = backend.ewma.active_requests(),
+            closed,
+            "backend finished draining"
+        );
+    }
+
+    // A backend that kept its id but moved must not keep connections to its old address
+    let active = routing_table.snapshot();
+    let draining = routing_table.draining();
+    let closed = pool.retain(|key| {
+        active.iter().chain(draining.iter().map(|d| &d.backend)).any(|b| PoolKey::from(&**b) == *key)
+    });
+    if closed > 0 {
+        tracing::debug!(closed, "closed idle connections to addresses no backend uses any more");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper_util::rt::TokioIo;
+    use std::sync::Arc;
+    use tokio::net::{TcpListener, TcpStream};
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::routing::RoutingTable;
+
+    #[tokio::test]
+    async fn test_drained_backends_lose_their_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let _server_side = listener.accept().await.unwrap();
+        let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+
+        let pool = ConnectionPool::new();
+        let key = PoolKey { backend: BackendId(1), addr };
+        let pooled = pool.register_new(key, sender);
+        pool.push(key, pooled);
+        let routing_table = Arc::new(RoutingTable::new(vec![Arc::new(Backend::new(BackendId(1), addr))]));
+        routing_table.update_backends(Vec::new());
+
+        reap(&routing_table, &pool, Instant::now());
+        assert_eq!(pool.stats()[0].idle, 1, "still draining");
+
+        reap(&routing_table, &pool, Instant::now() + Duration::from_secs(60));
+        assert!(pool.stats().is_empty());
+        assert!(routing_table.draining().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_readdressed_backends_drop_old_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let _server_side = listener.accept().await.unwrap();
+        let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+
+        let pool = ConnectionPool::new();
+        let old = PoolKey { backend: BackendId(1), addr };
+        let pooled = pool.register_new(old, sender);
+        pool.push(old, pooled);
+
+        // Same backend id, new address: the old connection must not be reused
+        let moved = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap()));
+        let routing_table = Arc::new(RoutingTable::new(vec![moved.clone()]));
+        assert!(pool.try_pop(&PoolKey::from(&*moved)).is_none());
+
+        reap(&routing_table, &pool, Instant::now());
+        assert!(pool.stats().is_empty());
+    }
+}
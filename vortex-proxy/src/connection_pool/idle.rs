@@ -0,0 +1,58 @@
+//! Evicting pooled connections that have been idle for too long.
+
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use super::pool::ConnectionPool;
+
+/// Spawns a task that closes pooled connections once they have been idle for
+/// `max_idle`, checking a few times per period so none lingers much longer.
+pub fn spawn_idle_reaper(pool: ConnectionPool, max_idle: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval((max_idle / 4).max(Duration::from_millis(100)));
+        loop {
+            ticker.tick().await;
+            let Some(cutoff) = Instant::now().checked_sub(max_idle) else {
+                continue;
+            };
+            let evicted = pool.evict_idle(cutoff);
+            if evicted > 0 {
+                tracing::debug!(evicted, ?max_idle, "closed idle upstream connections");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_pool::pool::PoolKey;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::{TcpListener, TcpStream};
+    use vortex_core::domain::backend::BackendId;
+
+    #[tokio::test]
+    async fn test_only_long_idle_connections_are_evicted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ConnectionPool::new();
+        let key = PoolKey { backend: BackendId(1), addr };
+        let mut server_sides = Vec::new();
+        for _ in 0..2 {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            server_sides.push(listener.accept().await.unwrap());
+            let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+            tokio::spawn(conn);
+            let pooled = pool.register_new(key, sender);
+            pool.push(key, pooled);
+        }
+        let pushed = Instant::now();
+
+        assert_eq!(pool.evict_idle(pushed - Duration::from_secs(1)), 0);
+        assert_eq!(pool.stats()[0].idle, 2);
+
+        assert_eq!(pool.evict_idle(pushed + Duration::from_secs(1)), 2);
+        let stats = &pool.stats()[0];
+        assert_eq!((stats.idle, stats.closed), (0, 2));
+    }
+}
@@ -1,3 +1,5 @@
 //! Two-stage lock-free connection pool for reusing HTTP/1.1 connections.
 
+pub mod drain;
+pub mod idle;
 pub mod pool;
@@ -1,17 +1,88 @@
 //! Lock-free hot pool implementation using DashMap and SegQueue.
 
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use dashmap::DashMap;
 use crossbeam_queue::SegQueue;
 use hyper::client::conn::http1::SendRequest;
-use hyper::body::Incoming;
+use hyper::header::{HeaderMap, CONNECTION};
+use hyper::Version;
+use vortex_core::domain::backend::{Backend, BackendId};
+use vortex_core::stats::{PoolStats, PoolStatsSource};
+
+use crate::pipeline::ProxyBody;
+
+/// Identifies the upstream a pooled connection belongs to.
+///
+/// Keying by backend identity as well as address means a backend whose
+/// address changes (e.g. after DNS re-resolution) never inherits connections
+/// to its old address, and connections to an address that a different backend
+/// now owns are never handed to it. Backends sharing an address behind a
+/// name-based ingress are told apart by their id, so each keeps its own pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    /// The backend the connections were opened for
+    pub backend: BackendId,
+    /// The address they were opened to
+    pub addr: SocketAddr,
+}
+
+impl From<&Backend> for PoolKey {
+    fn from(backend: &Backend) -> Self {
+        Self { backend: backend.id, addr: backend.addr }
+    }
+}
+
+/// An idle upstream sender together with the moment its connection was established.
+#[derive(Debug)]
+pub struct PooledConnection {
+    /// The HTTP/1.1 sender half of the upstream connection
+    pub sender: SendRequest<ProxyBody>,
+    /// When the underlying TCP connection was established
+    pub created_at: Instant,
+    /// When the connection last went back to the pool
+    pub idle_since: Instant,
+}
+
+/// Per-backend idle queue plus the lifetime counters reported by `stats()`.
+#[derive(Debug)]
+struct BackendPool {
+    idle: SegQueue<PooledConnection>,
+    created: AtomicU64,
+    reused: AtomicU64,
+    closed: AtomicU64,
+    /// Sum of the creation offsets (micros since the pool epoch) of idle connections,
+    /// so their mean age can be derived without walking the queue.
+    idle_created_sum_us: AtomicU64,
+}
+
+impl BackendPool {
+    fn new() -> Self {
+        Self {
+            idle: SegQueue::new(),
+            created: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+            idle_created_sum_us: AtomicU64::new(0),
+        }
+    }
+}
 
 /// A lock-free two-stage hot pool for caching backend TCP connections.
 #[derive(Debug, Clone)]
 pub struct ConnectionPool {
-    /// Maps a backend address to a lock-free queue of idle HTTP/1.1 senders.
-    idle_connections: Arc<DashMap<SocketAddr, Arc<SegQueue<SendRequest<Incoming>>>>>,
+    /// Maps a backend to a lock-free queue of idle HTTP/1.1 senders.
+    idle_connections: Arc<DashMap<PoolKey, Arc<BackendPool>>>,
+    /// Reference point for the connection age bookkeeping.
+    epoch: Instant,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConnectionPool {
@@ -19,36 +90,262 @@ impl ConnectionPool {
     pub fn new() -> Self {
         Self {
             idle_connections: Arc::new(DashMap::new()),
+            epoch: Instant::now(),
+        }
+    }
+
+    fn backend(&self, key: PoolKey) -> Arc<BackendPool> {
+        self.idle_connections
+            .entry(key)
+            .or_insert_with(|| Arc::new(BackendPool::new()))
+            .value()
+            .clone()
+    }
+
+    fn offset_us(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.epoch).as_micros() as u64
+    }
+
+    /// Wraps a freshly established sender, counting it as a newly created connection.
+    pub fn register_new(&self, key: PoolKey, sender: SendRequest<ProxyBody>) -> PooledConnection {
+        self.backend(key).created.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        PooledConnection {
+            sender,
+            created_at: now,
+            idle_since: now,
         }
     }
 
     /// Tries to pop an existing, connection sender to the given backend.
-    pub fn try_pop(&self, addr: &SocketAddr) -> Option<SendRequest<Incoming>> {
-        if let Some(queue_ref) = self.idle_connections.get(addr) {
-            let queue = queue_ref.value();
-            while let Some(sender) = queue.pop() {
+    pub fn try_pop(&self, key: &PoolKey) -> Option<PooledConnection> {
+        if let Some(queue_ref) = self.idle_connections.get(key) {
+            let backend = queue_ref.value();
+            while let Some(conn) = backend.idle.pop() {
+                let offset = self.offset_us(conn.created_at);
+                backend.idle_created_sum_us.fetch_sub(offset, Ordering::Relaxed);
+
                 // Return if the sender is not explicitly closed.
                 // It still requires caller to verify `ready().await` before use.
-                if !sender.is_closed() {
-                    return Some(sender);
+                if !conn.sender.is_closed() {
+                    backend.reused.fetch_add(1, Ordering::Relaxed);
+                    return Some(conn);
                 }
+                backend.closed.fetch_add(1, Ordering::Relaxed);
             }
         }
         None
     }
 
     /// Pushes an active sender back into the pool for reuse.
-    pub fn push(&self, addr: SocketAddr, sender: SendRequest<Incoming>) {
-        if sender.is_closed() {
+    ///
+    /// Senders whose connection has already been closed by the peer are dropped
+    /// instead of being handed to the next request.
+    pub fn push(&self, key: PoolKey, mut conn: PooledConnection) {
+        conn.idle_since = Instant::now();
+        let backend = self.backend(key);
+        if conn.sender.is_closed() {
+            backend.closed.fetch_add(1, Ordering::Relaxed);
             return;
         }
 
-        let queue = self.idle_connections
-            .entry(addr)
-            .or_insert_with(|| Arc::new(SegQueue::new()))
-            .value()
-            .clone();
+        backend
+            .idle_created_sum_us
+            .fetch_add(self.offset_us(conn.created_at), Ordering::Relaxed);
+        backend.idle.push(conn);
+    }
+
+    /// Drops a connection that must not be reused, counting it as closed.
+    pub fn retire(&self, key: PoolKey, conn: PooledConnection) {
+        self.backend(key).closed.fetch_add(1, Ordering::Relaxed);
+        drop(conn);
+    }
+
+    /// Proactively drops every idle sender to `key` whose connection has closed.
+    ///
+    /// Returns the number of senders that were evicted.
+    pub fn evict_closed(&self, key: &PoolKey) -> usize {
+        let Some(queue_ref) = self.idle_connections.get(key) else {
+            return 0;
+        };
+        let backend = queue_ref.value();
+
+        // Drain a bounded number of entries so concurrent pushes can't keep us spinning.
+        let mut evicted = 0;
+        for _ in 0..backend.idle.len() {
+            match backend.idle.pop() {
+                Some(conn) if conn.sender.is_closed() => {
+                    let offset = self.offset_us(conn.created_at);
+                    backend.idle_created_sum_us.fetch_sub(offset, Ordering::Relaxed);
+                    backend.closed.fetch_add(1, Ordering::Relaxed);
+                    evicted += 1;
+                }
+                Some(conn) => backend.idle.push(conn),
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Drops every idle sender that has been idle since before `cutoff`, or
+    /// whose connection has closed, across all upstreams.
+    ///
+    /// HTTP/1.1 has no ping, so a connection silently dropped by a NAT or
+    /// firewall can't be told apart from a live one; bounding how long a
+    /// connection may sit idle keeps the next request from stalling on it.
+    /// Returns the number of senders that were evicted.
+    pub fn evict_idle(&self, cutoff: Instant) -> usize {
+        let mut evicted = 0;
+        for entry in self.idle_connections.iter() {
+            let backend = entry.value();
+            for _ in 0..backend.idle.len() {
+                match backend.idle.pop() {
+                    Some(conn) if conn.sender.is_closed() || conn.idle_since < cutoff => {
+                        let offset = self.offset_us(conn.created_at);
+                        backend.idle_created_sum_us.fetch_sub(offset, Ordering::Relaxed);
+                        backend.closed.fetch_add(1, Ordering::Relaxed);
+                        evicted += 1;
+                    }
+                    Some(conn) => backend.idle.push(conn),
+                    None => break,
+                }
+            }
+        }
+        evicted
+    }
+
+    /// Closes every idle connection to `key` and forgets its counters, e.g.
+    /// once a removed backend has finished draining.
+    ///
+    /// Returns the number of connections that were closed.
+    pub fn close_backend(&self, key: &PoolKey) -> usize {
+        self.idle_connections
+            .remove(key)
+            .map_or(0, |(_, backend)| backend.idle.len())
+    }
+
+    /// Closes the connections of every upstream for which `keep` is false,
+    /// e.g. the old address of a backend that was re-resolved.
+    ///
+    /// Returns the number of idle connections that were closed.
+    pub fn retain(&self, mut keep: impl FnMut(&PoolKey) -> bool) -> usize {
+        let mut closed = 0;
+        self.idle_connections.retain(|key, backend| {
+            let kept = keep(key);
+            if !kept {
+                closed += backend.idle.len();
+            }
+            kept
+        });
+        closed
+    }
+
+    /// Returns a snapshot of idle counts, lifetime counters, and the mean age
+    /// of the idle connections, since they were established, per backend.
+    pub fn stats(&self) -> Vec<PoolStats> {
+        let now_us = self.offset_us(Instant::now());
+
+        self.idle_connections
+            .iter()
+            .map(|entry| {
+                let backend = entry.value();
+                let idle = backend.idle.len() as u64;
+                let sum = backend.idle_created_sum_us.load(Ordering::Relaxed);
+                let avg_connection_age = match sum.checked_div(idle) {
+                    Some(mean_created) => Duration::from_micros(now_us.saturating_sub(mean_created)),
+                    None => Duration::ZERO,
+                };
+
+                PoolStats {
+                    backend: entry.key().backend,
+                    addr: entry.key().addr,
+                    idle,
+                    created: backend.created.load(Ordering::Relaxed),
+                    reused: backend.reused.load(Ordering::Relaxed),
+                    closed: backend.closed.load(Ordering::Relaxed),
+                    avg_connection_age,
+                }
+            })
+            .collect()
+    }
+}
+
+impl PoolStatsSource for ConnectionPool {
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        self.stats()
+    }
+}
+
+/// Returns whether an upstream HTTP/1.x connection may be reused after an exchange
+/// whose request asked to close it (`client_closes`, see `asks_to_close`) or not,
+/// given the response headers.
+///
+/// A `Connection: close` token on either side, or an HTTP/1.0 peer that did not
+/// explicitly opt into keep-alive, means the upstream is about to tear the
+/// connection down. Handing such a sender back to the pool would only make the
+/// next request fail on a dying socket.
+pub fn is_reusable(version: Version, client_closes: bool, response_headers: &HeaderMap) -> bool {
+    if client_closes || asks_to_close(response_headers) {
+        return false;
+    }
+
+    match version {
+        Version::HTTP_10 => has_connection_token(response_headers, "keep-alive"),
+        _ => true,
+    }
+}
+
+/// Whether a message's `Connection` header asks to close the connection.
+pub fn asks_to_close(headers: &HeaderMap) -> bool {
+    has_connection_token(headers, "close")
+}
+
+fn has_connection_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(connection: Option<&'static str>) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        if let Some(value) = connection {
+            map.insert(CONNECTION, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    #[test]
+    fn test_keep_alive_by_default_on_http11() {
+        assert!(is_reusable(Version::HTTP_11, asks_to_close(&headers(None)), &headers(None)));
+    }
+
+    #[test]
+    fn test_connection_close_retires_sender() {
+        assert!(!is_reusable(Version::HTTP_11, asks_to_close(&headers(None)), &headers(Some("close"))));
+        assert!(!is_reusable(Version::HTTP_11, asks_to_close(&headers(Some("Upgrade, Close"))), &headers(None)));
+    }
+
+    #[test]
+    fn test_stats_start_empty() {
+        let pool = ConnectionPool::new();
+        let key = PoolKey { backend: BackendId(1), addr: "127.0.0.1:9090".parse().unwrap() };
+
+        assert!(pool.try_pop(&key).is_none());
+        assert!(pool.stats().is_empty());
+        assert_eq!(pool.evict_closed(&key), 0);
+    }
 
-        queue.push(sender);
+    #[test]
+    fn test_http10_requires_explicit_keep_alive() {
+        assert!(!is_reusable(Version::HTTP_10, asks_to_close(&headers(None)), &headers(None)));
+        assert!(is_reusable(Version::HTTP_10, asks_to_close(&headers(None)), &headers(Some("keep-alive"))));
     }
 }
@@ -0,0 +1,254 @@
+//! Client deadline propagation.
+//!
+//! Clients can bound a request with gRPC's `grpc-timeout` or with a relative
+//! deadline header (`X-Request-Deadline: <milliseconds>` by default). The
+//! proxy turns either into an absolute `Deadline` when the request arrives,
+//! optionally clamped to its own maximum, rejects requests that have already
+//! run out of time, aborts work once the deadline passes, and rewrites the
+//! headers with the remaining budget before forwarding so backends see how
+//! much time is actually left.
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::pipeline::{ProxyFuture, ProxyRequest, ProxyResponse};
+
+/// The default relative deadline header, in milliseconds.
+pub const DEFAULT_DEADLINE_HEADER: &str = "x-request-deadline";
+
+const GRPC_TIMEOUT: HeaderName = HeaderName::from_static("grpc-timeout");
+
+/// How client deadlines are honored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlineConfig {
+    /// Relative deadline header (milliseconds remaining), if honored
+    pub header: Option<HeaderName>,
+    /// Honor gRPC's `grpc-timeout`
+    pub grpc_timeout: bool,
+    /// Upper bound on any request's time in the proxy, even without a client deadline
+    pub max_timeout: Option<Duration>,
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        Self {
+            header: Some(HeaderName::from_static(DEFAULT_DEADLINE_HEADER)),
+            grpc_timeout: true,
+            max_timeout: None,
+        }
+    }
+}
+
+/// A request's absolute deadline, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deadline {
+    at: Instant,
+    forward_grpc: bool,
+    forward_header: Option<HeaderName>,
+}
+
+impl Deadline {
+    /// Time left before the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    /// Rewrites the deadline headers the client sent with the remaining budget.
+    pub fn apply_headers(&self, headers: &mut HeaderMap) {
+        let remaining = self.remaining();
+        if self.forward_grpc {
+            headers.insert(GRPC_TIMEOUT, encode_grpc_timeout(remaining));
+        }
+        if let Some(header) = &self.forward_header {
+            headers.insert(header.clone(), HeaderValue::from(remaining.as_millis() as u64));
+        }
+    }
+}
+
+impl DeadlineConfig {
+    /// Derives the request's deadline from its headers and the proxy maximum.
+    /// Returns `None` when nothing bounds the request.
+    pub fn deadline_for(&self, headers: &HeaderMap, now: Instant) -> Option<Deadline> {
+        let grpc = self
+            .grpc_timeout
+            .then(|| headers.get(GRPC_TIMEOUT).and_then(|v| parse_grpc_timeout(v.to_str().ok()?)))
+            .flatten();
+        let relative = self.header.as_ref().and_then(|name| {
+            let ms = headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok()?;
+            Some((name.clone(), Duration::from_millis(ms)))
+        });
+
+        let timeout = [grpc, relative.as_ref().map(|(_, d)| *d), self.max_timeout]
+            .into_iter()
+            .flatten()
+            .min()?;
+        Some(Deadline {
+            at: now + timeout,
+            forward_grpc: grpc.is_some(),
+            forward_header: relative.map(|(name, _)| name),
+        })
+    }
+}
+
+/// Parses a `grpc-timeout` value: up to 8 digits followed by a unit.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let (digits, unit) = value.split_at(value.len().checked_sub(1)?);
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(n * 3600),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+/// Encodes a duration as a `grpc-timeout` value in the finest unit that fits.
+fn encode_grpc_timeout(timeout: Duration) -> HeaderValue {
+    const MAX: u128 = 99_999_999;
+    let value = if timeout.as_micros() <= MAX {
+        format!("{}u", timeout.as_micros())
+    } else if timeout.as_millis() <= MAX {
+        format!("{}m", timeout.as_millis())
+    } else if u128::from(timeout.as_secs()) <= MAX {
+        format!("{}S", timeout.as_secs())
+    } else {
+        format!("{}H", (timeout.as_secs() / 3600).min(MAX as u64))
+    };
+    HeaderValue::from_str(&value).expect("digits and a unit are a valid header value")
+}
+
+/// Enforces client deadlines and records them for the upstream stage.
+#[derive(Debug, Clone)]
+pub struct DeadlineLayer {
+    config: Arc<DeadlineConfig>,
+}
+
+impl DeadlineLayer {
+    /// Create a layer honoring deadlines as configured.
+    pub fn new(config: DeadlineConfig) -> Self {
+        Self { config: Arc::new(config) }
+    }
+}
+
+impl<S> Layer<S> for DeadlineLayer {
+    type Service = DeadlineService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DeadlineService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Service produced by `DeadlineLayer`.
+#[derive(Debug, Clone)]
+pub struct DeadlineService<S> {
+    inner: S,
+    config: Arc<DeadlineConfig>,
+}
+
+impl<S> Service<ProxyRequest> for DeadlineService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let Some(deadline) = self.config.deadline_for(req.headers(), Instant::now()) else {
+            return Box::pin(self.inner.call(req));
+        };
+
+        let route = req.uri().path().to_string();
+        if deadline.remaining().is_zero() {
+            // Don't spend a backend's time on a request the client has given up on
+            return Box::pin(std::future::ready(Err(ProxyError::DeadlineExceeded { route })));
+        }
+
+        let at = deadline.at;
+        req.extensions_mut().insert(deadline);
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            match tokio::time::timeout_at(at, fut).await {
+                Ok(result) => result,
+                Err(_) => Err(ProxyError::DeadlineExceeded { route }),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (HeaderName::from_static(k), v.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_grpc_timeout_round_trip() {
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("123456789m"), None);
+        assert_eq!(parse_grpc_timeout("10x"), None);
+        assert_eq!(parse_grpc_timeout("m"), None);
+
+        let encoded = encode_grpc_timeout(Duration::from_millis(1500));
+        assert_eq!(parse_grpc_timeout(encoded.to_str().unwrap()), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_tightest_deadline_wins() {
+        let config = DeadlineConfig { max_timeout: Some(Duration::from_secs(30)), ..DeadlineConfig::default() };
+        let now = Instant::now();
+
+        let deadline = config
+            .deadline_for(&headers(&[("grpc-timeout", "5S"), ("x-request-deadline", "800")]), now)
+            .unwrap();
+        assert_eq!(deadline.at, now + Duration::from_millis(800));
+        assert!(deadline.forward_grpc);
+
+        // Only the proxy maximum applies; nothing to forward
+        let deadline = config.deadline_for(&HeaderMap::new(), now).unwrap();
+        assert_eq!(deadline.at, now + Duration::from_secs(30));
+        assert!(!deadline.forward_grpc && deadline.forward_header.is_none());
+
+        assert!(DeadlineConfig::default().deadline_for(&HeaderMap::new(), now).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forwarded_headers_carry_the_remaining_budget() {
+        let config = DeadlineConfig::default();
+        let deadline = config
+            .deadline_for(&headers(&[("x-request-deadline", "1000")]), Instant::now())
+            .unwrap();
+        tokio::time::advance(Duration::from_millis(400)).await;
+
+        let mut forwarded = HeaderMap::new();
+        deadline.apply_headers(&mut forwarded);
+        assert_eq!(forwarded.get(DEFAULT_DEADLINE_HEADER).unwrap(), "600");
+        assert!(forwarded.get("grpc-timeout").is_none());
+    }
+}
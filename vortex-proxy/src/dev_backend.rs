@@ -0,0 +1,164 @@
+//! Throwaway HTTP backends for local development.
+//!
+//! `vortex-proxy dev-backend` starts one small HTTP/1.1 server per port
+//! (9090 and 9091 by default, matching the demo proxy), so the proxy can be
+//! tried out without installing anything else. Each server echoes the
+//! request back, and its status code, delay and body can be set for all
+//! requests on the command line or for one request through its query:
+//!
+//! ```text
+//! curl 'http://127.0.0.1:9090/slow?delay_ms=250&status=503'
+//! ```
+
+use clap::Args;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Options for the `dev-backend` subcommand.
+#[derive(Debug, Clone, Args)]
+pub struct DevBackendArgs {
+    /// Ports to serve on, one backend each
+    #[arg(long = "port", default_values_t = [9090, 9091])]
+    pub ports: Vec<u16>,
+    /// Address to bind
+    #[arg(long, default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+    /// Status code of every response, unless a request asks for another
+    #[arg(long, default_value_t = 200)]
+    pub status: u16,
+    /// Milliseconds to wait before answering, unless a request asks for another delay
+    #[arg(long, default_value_t = 0)]
+    pub delay_ms: u64,
+    /// Fixed response body, instead of echoing the request
+    #[arg(long)]
+    pub body: Option<String>,
+}
+
+impl Default for DevBackendArgs {
+    fn default() -> Self {
+        Self {
+            ports: vec![9090, 9091],
+            bind: IpAddr::from([127, 0, 0, 1]),
+            status: 200,
+            delay_ms: 0,
+            body: None,
+        }
+    }
+}
+
+/// Runs the backends until interrupted.
+pub async fn run(args: DevBackendArgs) -> Result<(), BoxError> {
+    let args = Arc::new(args);
+    for &port in &args.ports {
+        let addr = spawn_dev_backend(SocketAddr::new(args.bind, port), args.clone()).await?;
+        tracing::info!(%addr, "dev backend listening");
+    }
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Starts one backend on `addr`, returning the address it bound.
+pub async fn spawn_dev_backend(addr: SocketAddr, args: Arc<DevBackendArgs>) -> Result<SocketAddr, BoxError> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let args = args.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| respond(req, addr, args.clone()));
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn respond(
+    req: Request<Incoming>,
+    addr: SocketAddr,
+    args: Arc<DevBackendArgs>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let query = |key: &str| {
+        req.uri()
+            .query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, v)| v.parse::<u64>().ok())
+    };
+    let status = query("status")
+        .and_then(|code| u16::try_from(code).ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .or_else(|| StatusCode::from_u16(args.status).ok())
+        .unwrap_or(StatusCode::OK);
+    let delay = Duration::from_millis(query("delay_ms").unwrap_or(args.delay_ms));
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    let body = match &args.body {
+        Some(body) => Bytes::from(body.clone()),
+        None => echo(req, addr).await,
+    };
+    let mut res = Response::new(Full::new(body));
+    *res.status_mut() = status;
+    res.headers_mut().insert("x-dev-backend", addr.port().into());
+    Ok(res)
+}
+
+/// Describes the request: its line, headers and body.
+async fn echo(req: Request<Incoming>, addr: SocketAddr) -> Bytes {
+    let (parts, body) = req.into_parts();
+    // Writing into a String cannot fail
+    let mut out = String::new();
+    let _ = writeln!(out, "served by {}", addr);
+    let _ = writeln!(out, "{} {} {:?}", parts.method, parts.uri, parts.version);
+    for (name, value) in &parts.headers {
+        let _ = writeln!(out, "{}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+    }
+    let body = body.collect().await.map(|b| b.to_bytes()).unwrap_or_default();
+    if !body.is_empty() {
+        let _ = writeln!(out);
+        out.push_str(&String::from_utf8_lossy(&body));
+    }
+    Bytes::from(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dev_backend_echoes_and_honors_overrides() {
+        let args = DevBackendArgs {
+            status: 202,
+            ..DevBackendArgs::default()
+        };
+        let addr = spawn_dev_backend("127.0.0.1:0".parse().unwrap(), Arc::new(args)).await.unwrap();
+        let client = reqwest::Client::new();
+
+        let res = client.post(format!("http://{}/items?page=2", addr)).body("hello").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(res.headers()["x-dev-backend"], addr.port().to_string().as_str());
+        let text = res.text().await.unwrap();
+        assert!(text.contains("POST /items?page=2 HTTP/1.1"));
+        assert!(text.ends_with("\nhello"));
+
+        let res = client.get(format!("http://{}/?status=503&delay_ms=20", addr)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,245 @@
+//! Human-readable runtime snapshots for postmortem triage.
+//!
+//! A dump lists the pool's labels and backends with their live scores, the
+//! connection pools, fault rules, served certificates, request totals, and
+//! (when tracked) the current top talkers. It can be pulled through the admin
+//! API or, on Unix, triggered with `SIGQUIT` in the spirit of a JVM thread dump.
+
+use std::fmt::Write as _;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use vortex_core::domain::routing::SharedRoutingTable;
+use vortex_core::stats::{DiagnosticsSource, PoolStatsSource, TalkerDimension, TrafficStatsSource};
+use vortex_filters::fault_injection::FaultInjector;
+
+use crate::connection_pool::pool::ConnectionPool;
+use crate::metrics::{self, RequestMetrics};
+use crate::tls;
+use crate::traffic::TrafficTracker;
+
+/// Top talkers listed per dimension in a dump.
+const DUMP_TOP_TALKERS: usize = 5;
+
+/// Where a signal-triggered dump is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpTarget {
+    /// Write the dump to stderr
+    Log,
+    /// Write each dump to a new timestamped file in this directory
+    Dir(PathBuf),
+}
+
+/// Collects runtime state from the proxy's subsystems.
+pub struct Diagnostics {
+    started_at: SystemTime,
+    routing_table: SharedRoutingTable,
+    pool: ConnectionPool,
+    fault_injector: Arc<FaultInjector>,
+    request_metrics: RequestMetrics,
+    traffic: Option<Arc<TrafficTracker>>,
+}
+
+impl Diagnostics {
+    /// Create a snapshot source over the given subsystems.
+    pub fn new(
+        routing_table: SharedRoutingTable,
+        pool: ConnectionPool,
+        fault_injector: Arc<FaultInjector>,
+        request_metrics: RequestMetrics,
+    ) -> Self {
+        Self {
+            started_at: SystemTime::now(),
+            routing_table,
+            pool,
+            fault_injector,
+            request_metrics,
+            traffic: None,
+        }
+    }
+
+    /// Include the live top talkers in dumps.
+    pub fn with_traffic(mut self, traffic: Arc<TrafficTracker>) -> Self {
+        self.traffic = Some(traffic);
+        self
+    }
+}
+
+impl DiagnosticsSource for Diagnostics {
+    fn dump(&self) -> String {
+        // Writing into a String cannot fail
+        let mut out = String::new();
+        let now = SystemTime::now();
+        let _ = writeln!(out, "=== vortex diagnostic dump ===");
+        let _ = writeln!(out, "time: {}s since epoch", unix_secs(now));
+        let _ = writeln!(
+            out,
+            "uptime: {}s",
+            now.duration_since(self.started_at).unwrap_or_default().as_secs()
+        );
+        let _ = writeln!(out, "active downstream connections: {}", metrics::ACTIVE_CONNECTIONS.get());
+        let (requests, server_errors) = self.request_metrics.outcome_totals();
+        let _ = writeln!(out, "requests: {} total, {} 5xx", requests, server_errors);
+
+        let labels: Vec<String> = self.routing_table.labels().iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        let _ = writeln!(out, "\n[pool] labels: {}", if labels.is_empty() { "-".to_string() } else { labels.join(",") });
+        for backend in self.routing_table.snapshot().iter() {
+            let mut metadata: Vec<String> = backend.metadata.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            metadata.sort();
+            let _ = writeln!(
+                out,
+                "  backend {} {} healthy={} active={} ewma_ms={:.2} score={:.2} metadata={}",
+                backend.id.0,
+                backend.addr,
+                backend.is_healthy(),
+                backend.ewma.active_requests(),
+                backend.ewma.get_ewma(),
+                backend.ewma.calculate_score(),
+                if metadata.is_empty() { "-".to_string() } else { metadata.join(",") },
+            );
+        }
+        for draining in self.routing_table.draining().iter() {
+            let _ = writeln!(
+                out,
+                "  backend {} {} draining active={} remaining_s={}",
+                draining.backend.id.0,
+                draining.backend.addr,
+                draining.backend.ewma.active_requests(),
+                draining.until.saturating_duration_since(std::time::Instant::now()).as_secs(),
+            );
+        }
+
+        let _ = writeln!(out, "\n[connection pools]");
+        for stats in self.pool.pool_stats() {
+            let _ = writeln!(
+                out,
+                "  backend {} {} idle={} created={} reused={} closed={} avg_connection_age_ms={}",
+                stats.backend.0,
+                stats.addr,
+                stats.idle,
+                stats.created,
+                stats.reused,
+                stats.closed,
+                stats.avg_connection_age.as_millis()
+            );
+        }
+
+        let _ = writeln!(out, "\n[fault rules]");
+        for rule in self.fault_injector.rules().iter() {
+            let _ = writeln!(
+                out,
+                "  {} abort={}%/{} delay={:?}±{:?}",
+                rule.route_prefix, rule.abort_percent, rule.abort_status, rule.delay, rule.delay_jitter
+            );
+        }
+
+        let _ = writeln!(out, "\n[certificates]");
+        for cert in tls::loaded_certificates() {
+            let _ = writeln!(
+                out,
+                "  {} subject=\"{}\" not_after={}s since epoch",
+                cert.source,
+                cert.subject,
+                unix_secs(cert.not_after)
+            );
+        }
+
+        if let Some(traffic) = &self.traffic {
+            let _ = writeln!(
+                out,
+                "\n[traffic] {:.1} rps over {}s",
+                traffic.requests_per_second(),
+                traffic.window().as_secs()
+            );
+            for dimension in TalkerDimension::ALL {
+                for talker in traffic.top_talkers(dimension, DUMP_TOP_TALKERS) {
+                    let _ = writeln!(out, "  {:?} {} {}", dimension, talker.key, talker.requests);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Writes a dump to `target`, returning the file it went to, if any.
+pub fn write_dump(source: &dyn DiagnosticsSource, target: &DumpTarget) -> io::Result<Option<PathBuf>> {
+    let dump = source.dump();
+    match target {
+        DumpTarget::Log => {
+            tracing::info!("diagnostic dump\n{}", dump);
+            Ok(None)
+        }
+        DumpTarget::Dir(dir) => {
+            let path = dir.join(format!("vortex-dump-{}-{}.txt", unix_secs(SystemTime::now()), std::process::id()));
+            std::fs::write(&path, dump)?;
+            Ok(Some(path))
+        }
+    }
+}
+
+/// Writes a dump to `target` every time the process receives `SIGQUIT`.
+#[cfg(unix)]
+pub fn spawn_dump_on_sigquit(
+    source: Arc<dyn DiagnosticsSource>,
+    target: DumpTarget,
+) -> io::Result<tokio::task::JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigquit = signal(SignalKind::quit())?;
+    Ok(tokio::spawn(async move {
+        while sigquit.recv().await.is_some() {
+            match write_dump(source.as_ref(), &target) {
+                Ok(Some(path)) => tracing::info!(path = %path.display(), "wrote diagnostic dump"),
+                Ok(None) => {}
+                Err(e) => tracing::error!(error = %e, "failed to write diagnostic dump"),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::labels::Labels;
+    use vortex_core::domain::routing::RoutingTable;
+
+    #[test]
+    fn test_dump_lists_backends_and_labels() {
+        let backend = Arc::new(Backend::new(BackendId(7), "127.0.0.1:9090".parse().unwrap()));
+        let routing_table =
+            Arc::new(RoutingTable::new(vec![backend]).with_labels(Labels::new().with("team", "edge")));
+        let diagnostics = Diagnostics::new(
+            routing_table,
+            ConnectionPool::new(),
+            Arc::default(),
+            RequestMetrics::new(Vec::<String>::new()).unwrap(),
+        );
+
+        let dump = diagnostics.dump();
+        assert!(dump.contains("labels: team=edge"));
+        assert!(dump.contains("backend 7 127.0.0.1:9090 healthy=true active=0"));
+    }
+
+    #[test]
+    fn test_dump_to_dir_writes_a_file() {
+        let dir = std::env::temp_dir().join(format!("vortex-dump-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let diagnostics = Diagnostics::new(
+            Arc::new(RoutingTable::new(Vec::new())),
+            ConnectionPool::new(),
+            Arc::default(),
+            RequestMetrics::new(Vec::<String>::new()).unwrap(),
+        );
+
+        let path = write_dump(&diagnostics, &DumpTarget::Dir(dir.clone())).unwrap().unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().starts_with("=== vortex diagnostic dump ==="));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
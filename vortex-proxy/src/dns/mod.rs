@@ -0,0 +1,569 @@
+//! Backend pools defined by hostnames or SRV records, with ordered failover.
+//!
+//! A pool such as `primary.db.svc:5432, replica.db.svc:5432` is not one flat
+//! set of addresses: the replica should only take traffic while the primary
+//! can't. Each hostname resolves into its own group of backends, and the
+//! routing table serves the first group, in declaration order, that has
+//! enough healthy backends. Traffic fails back once an earlier group
+//! recovers.
+//!
+//! An `SrvPool` gets the same from one SRV name: records are grouped by
+//! priority, lowest first, and each record's weight becomes its backends'
+//! weight.
+//!
+//! Groups in the routing table are kept healthy or not by the regular health
+//! checker; standby groups are probed here on every refresh so a failover
+//! never lands on a group that is down too. A hostname that fails to resolve
+//! keeps its last known addresses, as does an SRV pool whose lookup fails.
+//!
+//! By default every address a hostname resolves to becomes a backend, which
+//! suits headless services whose records list the pods themselves. Behind a
+//! managed load balancer, whose addresses come and go and must be looked up
+//! again for every connection, `DnsStrategy::PerConnection` keeps each
+//! hostname a single backend that is resolved on every dial instead, taking
+//! its addresses in turn.
+
+pub mod srv;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use dashmap::DashMap;
+use prometheus::IntCounterVec;
+use tokio::task::JoinHandle;
+use vortex_core::config::{HostStrategy, HostnamesSpec, SrvSpec};
+use vortex_core::domain::backend::{Backend, BackendId, SharedBackend};
+use vortex_core::domain::routing::SharedRoutingTable;
+
+use crate::health_check::prober;
+
+/// Failed lookups of pool hostnames, by `host`
+static RESOLUTION_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_dns_resolution_failures_total",
+        "Failed DNS lookups of pool hostnames",
+        &["host"]
+    )
+    .expect("metric registers once")
+});
+
+/// Where the rotation of each name resolved per connection stands
+static ROTATION: LazyLock<DashMap<String, usize>> = LazyLock::new(DashMap::new);
+
+/// How a pool turns a hostname into backends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DnsStrategy {
+    /// Every resolved address is a backend of its own, balanced and health
+    /// checked apart; for names listing the instances themselves
+    #[default]
+    AllAddresses,
+    /// The hostname is one backend, resolved again for every new connection,
+    /// which goes to the next of its addresses; for names fronting a load
+    /// balancer that moves
+    PerConnection,
+}
+
+/// A pool built from hostnames, in failover order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostnamePool {
+    /// `host:port` names, most preferred first
+    pub hosts: Vec<String>,
+    /// How often names are re-resolved and standby groups probed
+    pub refresh_interval: Duration,
+    /// Healthy backends a group needs to take traffic; groups with fewer
+    /// addresses than this need all of them healthy
+    pub min_healthy: usize,
+    /// Whether a hostname expands to all its addresses or is resolved per connection
+    pub strategy: DnsStrategy,
+}
+
+impl HostnamePool {
+    /// A pool over `hosts` in failover order, refreshed every 30 seconds.
+    pub fn new<I, S>(hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            hosts: hosts.into_iter().map(Into::into).collect(),
+            refresh_interval: Duration::from_secs(30),
+            min_healthy: 1,
+            strategy: DnsStrategy::default(),
+        }
+    }
+}
+
+impl From<&HostnamesSpec> for HostnamePool {
+    fn from(spec: &HostnamesSpec) -> Self {
+        let defaults = Self::new(spec.hosts.clone());
+        Self {
+            refresh_interval: spec.refresh_ms.map_or(defaults.refresh_interval, Duration::from_millis),
+            min_healthy: spec.min_healthy.unwrap_or(defaults.min_healthy),
+            strategy: match spec.strategy {
+                HostStrategy::AllAddresses => DnsStrategy::AllAddresses,
+                HostStrategy::PerConnection => DnsStrategy::PerConnection,
+            },
+            ..defaults
+        }
+    }
+}
+
+/// A pool built from the SRV records of one name, failing over by priority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvPool {
+    /// The SRV name, e.g. `_http._tcp.web.svc.cluster.local`
+    pub name: String,
+    /// Where to send the query; the first `/etc/resolv.conf` nameserver if unset
+    pub nameserver: Option<SocketAddr>,
+    /// How often the records are looked up again and standby groups probed
+    pub refresh_interval: Duration,
+    /// Healthy backends a priority needs to take traffic; priorities with
+    /// fewer addresses than this need all of them healthy
+    pub min_healthy: usize,
+}
+
+impl SrvPool {
+    /// A pool over the records of `name`, refreshed every 30 seconds.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            nameserver: None,
+            refresh_interval: Duration::from_secs(30),
+            min_healthy: 1,
+        }
+    }
+}
+
+impl From<&SrvSpec> for SrvPool {
+    fn from(spec: &SrvSpec) -> Self {
+        let defaults = Self::new(spec.name.clone());
+        Self {
+            nameserver: spec.nameserver,
+            refresh_interval: spec.refresh_ms.map_or(defaults.refresh_interval, Duration::from_millis),
+            min_healthy: spec.min_healthy.unwrap_or(defaults.min_healthy),
+            ..defaults
+        }
+    }
+}
+
+/// Where a pool's groups come from.
+#[derive(Debug)]
+enum Source {
+    /// One group per hostname
+    Hosts(Vec<String>),
+    /// One group per SRV priority
+    Srv {
+        name: String,
+        nameserver: Option<SocketAddr>,
+    },
+}
+
+/// The backends one hostname, or one SRV priority, resolved to.
+#[derive(Debug)]
+struct HostGroup {
+    host: String,
+    backends: Vec<SharedBackend>,
+}
+
+/// What a lookup found for one group: its addresses with their weights, or
+/// `None` to keep the last ones.
+type Resolved = (String, Option<Vec<(SocketAddr, u32)>>);
+
+/// Resolves a `HostnamePool` or `SrvPool` and keeps a routing table on its
+/// active group.
+pub(crate) struct DnsFailover {
+    source: Source,
+    strategy: DnsStrategy,
+    min_healthy: usize,
+    routing_table: SharedRoutingTable,
+    groups: Vec<HostGroup>,
+    active: Option<String>,
+    next_id: u32,
+}
+
+impl DnsFailover {
+    pub(crate) fn new(config: HostnamePool, routing_table: SharedRoutingTable) -> Self {
+        Self {
+            strategy: config.strategy,
+            ..Self::with_source(Source::Hosts(config.hosts), config.min_healthy, routing_table)
+        }
+    }
+
+    pub(crate) fn srv(config: SrvPool, routing_table: SharedRoutingTable) -> Self {
+        let source = Source::Srv {
+            name: config.name,
+            nameserver: config.nameserver,
+        };
+        Self::with_source(source, config.min_healthy, routing_table)
+    }
+
+    fn with_source(source: Source, min_healthy: usize, routing_table: SharedRoutingTable) -> Self {
+        Self {
+            source,
+            strategy: DnsStrategy::AllAddresses,
+            min_healthy,
+            routing_table,
+            groups: Vec::new(),
+            active: None,
+            next_id: 1,
+        }
+    }
+
+    /// Re-resolve the pool, probe the standby groups and switch the routing
+    /// table to the group that should serve now.
+    pub(crate) async fn refresh(&mut self) {
+        if let Some(resolved) = self.resolve().await {
+            // Backends keep their identity, and so their EWMA and health, across lookups
+            let mut known: HashMap<SocketAddr, SharedBackend> = self
+                .groups
+                .iter()
+                .flat_map(|g| g.backends.iter())
+                .map(|b| (b.addr, b.clone()))
+                .collect();
+            let mut previous: HashMap<String, Vec<SharedBackend>> =
+                self.groups.drain(..).map(|g| (g.host, g.backends)).collect();
+            for (host, addrs) in resolved {
+                let backends = match addrs {
+                    Some(addrs) if self.strategy == DnsStrategy::PerConnection => {
+                        let previous = previous.remove(&host).and_then(|backends| backends.into_iter().next());
+                        self.resolved_per_connection(&host, previous, &addrs)
+                    }
+                    Some(addrs) => {
+                        let mut backends: Vec<SharedBackend> = Vec::with_capacity(addrs.len());
+                        for (addr, weight) in addrs {
+                            if backends.iter().any(|b| b.addr == addr) {
+                                continue;
+                            }
+                            let backend = known.remove(&addr).unwrap_or_else(|| {
+                                let id = BackendId(self.next_id);
+                                self.next_id += 1;
+                                Arc::new(Backend::new(id, addr))
+                            });
+                            backend.set_weight(weight);
+                            backends.push(backend);
+                        }
+                        backends
+                    }
+                    None => previous.remove(&host).unwrap_or_default(),
+                };
+                self.groups.push(HostGroup { host, backends });
+            }
+        }
+
+        for group in &self.groups {
+            if Some(&group.host) == self.active.as_ref() {
+                continue;
+            }
+            for backend in &group.backends {
+                backend.set_healthy(prober::probe(backend.addr, prober::PROBE_TIMEOUT).await);
+            }
+        }
+
+        let Some(next) = select_group(&self.groups, self.min_healthy) else {
+            return;
+        };
+        let next_host = &self.groups[next].host;
+        if let Some(previous) = self.active.as_ref().filter(|&active| active != next_host) {
+            tracing::warn!(from = %previous, to = %next_host, "pool failing over");
+        }
+        let backends = self.groups[next].backends.clone();
+        let current = self.routing_table.snapshot();
+        let unchanged = current.len() == backends.len() && current.iter().zip(&backends).all(|(a, b)| a.id == b.id);
+        if !unchanged {
+            self.routing_table.update_backends(backends);
+        }
+        self.active = Some(next_host.clone());
+    }
+
+    /// The single backend of a hostname resolved per connection. It keeps its
+    /// identity while the name resolves; only its address, which health
+    /// checks probe, moves when the old one is gone.
+    fn resolved_per_connection(
+        &mut self,
+        host: &str,
+        previous: Option<SharedBackend>,
+        addrs: &[(SocketAddr, u32)],
+    ) -> Vec<SharedBackend> {
+        let Some(&(first, _)) = addrs.first() else {
+            return previous.into_iter().collect();
+        };
+        let id = match previous {
+            Some(backend) if addrs.iter().any(|&(addr, _)| addr == backend.addr) => return vec![backend],
+            Some(backend) => backend.id,
+            None => {
+                self.next_id += 1;
+                BackendId(self.next_id - 1)
+            }
+        };
+        vec![Arc::new(Backend::new(id, first).with_dns_name(host))]
+    }
+
+    /// Looks up every group, or `None` if nothing should change.
+    async fn resolve(&self) -> Option<Vec<Resolved>> {
+        match &self.source {
+            Source::Hosts(hosts) => {
+                let mut resolved = Vec::with_capacity(hosts.len());
+                for host in hosts {
+                    let addrs = match tokio::net::lookup_host(host.as_str()).await {
+                        Ok(addrs) => Some(addrs.map(|addr| (addr, 1)).collect()),
+                        Err(e) => {
+                            RESOLUTION_FAILURES.with_label_values(&[host]).inc();
+                            tracing::warn!(host = %host, error = %e, "failed to resolve pool hostname");
+                            None
+                        }
+                    };
+                    resolved.push((host.clone(), addrs));
+                }
+                Some(resolved)
+            }
+            Source::Srv { name, nameserver } => {
+                let lookup = async {
+                    let nameserver = match nameserver {
+                        Some(nameserver) => *nameserver,
+                        None => srv::system_nameserver()?,
+                    };
+                    srv::lookup_srv(name, nameserver).await
+                };
+                let mut records = match lookup.await {
+                    Ok(records) if !records.is_empty() => records,
+                    Ok(_) => {
+                        tracing::warn!(name = %name, "SRV lookup returned no records");
+                        return None;
+                    }
+                    Err(e) => {
+                        RESOLUTION_FAILURES.with_label_values(&[name]).inc();
+                        tracing::warn!(name = %name, error = %e, "failed to look up pool SRV records");
+                        return None;
+                    }
+                };
+                records.sort_by_key(|r| r.priority);
+                let mut resolved: Vec<Resolved> = Vec::new();
+                for record in records {
+                    let host = format!("{} priority {}", name, record.priority);
+                    let target = (record.target.as_str(), record.port);
+                    let addrs = match tokio::net::lookup_host(target).await {
+                        Ok(addrs) => addrs.collect(),
+                        Err(e) => {
+                            RESOLUTION_FAILURES.with_label_values(&[&record.target]).inc();
+                            tracing::warn!(host = %record.target, error = %e, "failed to resolve SRV target");
+                            Vec::new()
+                        }
+                    };
+                    let weighted = addrs.into_iter().map(|addr| (addr, u32::from(record.weight)));
+                    match resolved.last_mut() {
+                        Some((last, Some(group))) if *last == host => group.extend(weighted),
+                        _ => resolved.push((host, Some(weighted.collect()))),
+                    }
+                }
+                Some(resolved)
+            }
+        }
+    }
+}
+
+/// The first group with enough healthy backends; failing that, the one with
+/// the most, preferring earlier groups. `None` if nothing resolved.
+fn select_group(groups: &[HostGroup], min_healthy: usize) -> Option<usize> {
+    let healthy = |g: &HostGroup| g.backends.iter().filter(|b| b.is_healthy()).count();
+    let eligible = groups
+        .iter()
+        .position(|g| !g.backends.is_empty() && healthy(g) >= min_healthy.min(g.backends.len()));
+    eligible.or_else(|| {
+        groups
+            .iter()
+            .enumerate()
+            .filter(|(_, g)| !g.backends.is_empty())
+            .max_by_key(|(i, g)| (healthy(g), std::cmp::Reverse(*i)))
+            .map(|(i, _)| i)
+    })
+}
+
+/// Resolves `name` and picks the next of its addresses, so consecutive
+/// connections to a name resolved per connection spread over all of them.
+pub(crate) async fn next_address(name: &str) -> io::Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = match tokio::net::lookup_host(name).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            RESOLUTION_FAILURES.with_label_values(&[name]).inc();
+            return Err(e);
+        }
+    };
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} resolved to no addresses", name)));
+    }
+    let mut turn = ROTATION.entry(name.to_string()).or_insert(0);
+    let addr = addrs[*turn % addrs.len()];
+    *turn = turn.wrapping_add(1);
+    Ok(addr)
+}
+
+/// Spawns a task keeping `routing_table` on the active group of `config`.
+pub fn spawn_hostname_pool(config: HostnamePool, routing_table: SharedRoutingTable) -> JoinHandle<()> {
+    let interval = config.refresh_interval;
+    spawn_failover(DnsFailover::new(config, routing_table), interval)
+}
+
+/// Spawns a task keeping `routing_table` on the lowest priority of `config`
+/// with enough healthy backends.
+pub fn spawn_srv_pool(config: SrvPool, routing_table: SharedRoutingTable) -> JoinHandle<()> {
+    let interval = config.refresh_interval;
+    spawn_failover(DnsFailover::srv(config, routing_table), interval)
+}
+
+fn spawn_failover(mut failover: DnsFailover, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            failover.refresh().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use vortex_core::domain::routing::RoutingTable;
+
+    fn group(host: &str, healthy: &[bool]) -> HostGroup {
+        let backends = healthy
+            .iter()
+            .enumerate()
+            .map(|(i, &up)| {
+                let backend = Backend::new(BackendId(i as u32), SocketAddr::from(([10, 0, 0, i as u8], 80)));
+                backend.set_healthy(up);
+                Arc::new(backend)
+            })
+            .collect();
+        HostGroup {
+            host: host.into(),
+            backends,
+        }
+    }
+
+    #[test]
+    fn test_select_group_follows_declaration_order() {
+        let groups = [group("primary", &[true, false]), group("replica", &[true, true])];
+        assert_eq!(select_group(&groups, 1), Some(0));
+        assert_eq!(select_group(&groups, 2), Some(1));
+
+        // Nothing qualifies: the healthiest group still serves
+        let groups = [group("primary", &[false]), group("replica", &[true, false]), group("dr", &[])];
+        assert_eq!(select_group(&groups, 2), Some(1));
+        let groups = [group("primary", &[false]), group("replica", &[false])];
+        assert_eq!(select_group(&groups, 1), Some(0));
+        assert_eq!(select_group(&[group("primary", &[])], 1), None);
+    }
+
+    #[test]
+    fn test_pools_follow_the_config_file() {
+        let spec = HostnamesSpec {
+            hosts: vec!["lb.internal:443".to_string()],
+            strategy: HostStrategy::PerConnection,
+            refresh_ms: Some(5000),
+            min_healthy: None,
+        };
+        let pool = HostnamePool::from(&spec);
+        assert_eq!(pool.strategy, DnsStrategy::PerConnection);
+        assert_eq!((pool.refresh_interval, pool.min_healthy), (Duration::from_secs(5), 1));
+        let spec = SrvSpec { name: "_http._tcp.web".to_string(), nameserver: None, refresh_ms: None, min_healthy: Some(2) };
+        let pool = SrvPool::from(&spec);
+        assert_eq!((pool.refresh_interval, pool.min_healthy), (Duration::from_secs(30), 2));
+    }
+
+    #[tokio::test]
+    async fn test_pool_fails_over_and_back() {
+        let replica = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replica_addr = replica.local_addr().unwrap();
+        let primary_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let routing_table = Arc::new(RoutingTable::new(Vec::new()));
+        let config = HostnamePool::new([primary_addr.to_string(), replica_addr.to_string()]);
+        let mut failover = DnsFailover::new(config, routing_table.clone());
+
+        // The primary's port is closed
+        failover.refresh().await;
+        assert_eq!(routing_table.snapshot()[0].addr, replica_addr);
+
+        // It comes back and, probed as a standby group, takes over again
+        let _primary = TcpListener::bind(primary_addr).await.unwrap();
+        failover.refresh().await;
+        let active = routing_table.snapshot();
+        assert_eq!(active[0].addr, primary_addr);
+        assert_eq!(active[0].id, BackendId(1));
+
+        // Once active, the health checker decides when it goes down
+        active[0].set_healthy(false);
+        failover.refresh().await;
+        assert_eq!(routing_table.snapshot()[0].addr, replica_addr);
+    }
+
+    #[tokio::test]
+    async fn test_per_connection_hosts_stay_one_backend_that_rotates() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let routing_table = Arc::new(RoutingTable::new(Vec::new()));
+        let config = HostnamePool {
+            strategy: DnsStrategy::PerConnection,
+            ..HostnamePool::new([host.clone()])
+        };
+        let mut failover = DnsFailover::new(config, routing_table.clone());
+        failover.refresh().await;
+        failover.refresh().await;
+        let active = routing_table.snapshot();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, BackendId(1));
+        assert_eq!(active[0].dns_name.as_deref(), Some(host.as_str()));
+
+        // Each connection takes the next address the name resolves to
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(host.as_str()).await.unwrap().collect();
+        let mut picked = Vec::new();
+        for _ in 0..addrs.len() * 2 {
+            picked.push(next_address(&host).await.unwrap());
+        }
+        assert_eq!(picked, [addrs.clone(), addrs].concat());
+    }
+
+    #[tokio::test]
+    async fn test_srv_priorities_fail_over_and_weights_carry() {
+        let preferred = [TcpListener::bind("127.0.0.1:0").await.unwrap(), TcpListener::bind("127.0.0.1:0").await.unwrap()];
+        let [heavy, light] = preferred.each_ref().map(|l| l.local_addr().unwrap().port());
+        let standby = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let standby_port = standby.local_addr().unwrap().port();
+
+        let nameserver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let nameserver_addr = nameserver.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = nameserver.recv_from(&mut buf).await {
+                let records = [(20, 1, standby_port, "127.0.0.1"), (10, 3, heavy, "127.0.0.1"), (10, 0, light, "127.0.0.1")];
+                let _ = nameserver.send_to(&srv::tests::response(&buf[..n], &records), from).await;
+            }
+        });
+
+        let routing_table = Arc::new(RoutingTable::new(Vec::new()));
+        let config = SrvPool {
+            nameserver: Some(nameserver_addr),
+            ..SrvPool::new("_http._tcp.web.svc")
+        };
+        let mut failover = DnsFailover::srv(config, routing_table.clone());
+        failover.refresh().await;
+        let active = routing_table.snapshot();
+        let weights: Vec<(u16, u32)> = active.iter().map(|b| (b.addr.port(), b.weight())).collect();
+        assert_eq!(weights, [(heavy, 3), (light, 1)]);
+
+        // Priority 10 is down, so priority 20 takes over
+        for backend in active.iter() {
+            backend.set_healthy(false);
+        }
+        drop(preferred);
+        failover.refresh().await;
+        assert_eq!(routing_table.snapshot()[0].addr.port(), standby_port);
+    }
+}
+
@@ -0,0 +1,277 @@
+//! A minimal DNS client for SRV lookups.
+//!
+//! The system resolver behind `tokio::net::lookup_host` only answers address
+//! queries, so SRV records are asked for directly: one recursive query over
+//! UDP to a nameserver (the first in `/etc/resolv.conf` by default), retried
+//! over TCP when the answer is truncated. Only the answer section is read;
+//! targets are resolved to addresses separately.
+
+use rand::Rng;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// How long one query may take, over each transport.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// One SRV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower values are preferred; higher ones are failover targets
+    pub priority: u16,
+    /// Relative share of traffic among records of the same priority
+    pub weight: u16,
+    /// The service's port on the target
+    pub port: u16,
+    /// The host providing the service, without the trailing dot
+    pub target: String,
+}
+
+/// The first nameserver in `/etc/resolv.conf`.
+pub fn system_nameserver() -> io::Result<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|ip| ip.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf"))
+}
+
+/// Looks up the SRV records of `name` (e.g. `_http._tcp.web.svc`) at `nameserver`.
+pub async fn lookup_srv(name: &str, nameserver: SocketAddr) -> io::Result<Vec<SrvRecord>> {
+    let id: u16 = rand::thread_rng().gen();
+    let query = build_query(id, name)?;
+
+    let socket = UdpSocket::bind(if nameserver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(nameserver).await?;
+    let response = tokio::time::timeout(QUERY_TIMEOUT, async {
+        socket.send(&query).await?;
+        let mut buf = vec![0; 4096];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // Stray datagrams for other queries are ignored
+            if n >= 2 && buf[..2] == id.to_be_bytes() {
+                buf.truncate(n);
+                return Ok::<_, io::Error>(buf);
+            }
+        }
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no answer from nameserver"))??;
+
+    match parse_response(id, &response) {
+        Err(ParseError::Truncated) => {
+            let response = tokio::time::timeout(QUERY_TIMEOUT, query_tcp(&query, nameserver))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no answer from nameserver"))??;
+            parse_response(id, &response).map_err(Into::into)
+        }
+        result => result.map_err(Into::into),
+    }
+}
+
+async fn query_tcp(query: &[u8], nameserver: SocketAddr) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await?;
+    let mut response = vec![0; len as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// A recursive query for the SRV records of `name`.
+fn build_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", name, reason));
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired; one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid("invalid label length"));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    if query.len() > 12 + 255 {
+        return Err(invalid("name too long"));
+    }
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Why a response couldn't be used.
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    /// The answer didn't fit in a datagram; ask again over TCP
+    Truncated,
+    /// The nameserver answered with this RCODE
+    Rcode(u8),
+    /// The response is not a well-formed answer to the query
+    Malformed,
+}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> Self {
+        let message = match err {
+            ParseError::Truncated => "truncated response".to_string(),
+            ParseError::Rcode(3) => "no such name".to_string(),
+            ParseError::Rcode(code) => format!("nameserver answered with RCODE {}", code),
+            ParseError::Malformed => "malformed response".to_string(),
+        };
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+}
+
+/// The SRV records in the answer section of the response to query `id`.
+fn parse_response(id: u16, msg: &[u8]) -> Result<Vec<SrvRecord>, ParseError> {
+    let u16_at = |pos: usize| msg.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or(ParseError::Malformed);
+    let flags = u16_at(2)?;
+    if u16_at(0)? != id || flags & 0x8000 == 0 {
+        return Err(ParseError::Malformed);
+    }
+    if flags & 0x0200 != 0 {
+        return Err(ParseError::Truncated);
+    }
+    match (flags & 0x000f) as u8 {
+        0 => {}
+        rcode => return Err(ParseError::Rcode(rcode)),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let (kind, class, len) = (u16_at(pos)?, u16_at(pos + 2)?, u16_at(pos + 8)? as usize);
+        let data = pos + 10;
+        if msg.len() < data + len {
+            return Err(ParseError::Malformed);
+        }
+        // Answers may also hold the CNAMEs that led to the records
+        if kind == TYPE_SRV && class == CLASS_IN {
+            records.push(SrvRecord {
+                priority: u16_at(data)?,
+                weight: u16_at(data + 2)?,
+                port: u16_at(data + 4)?,
+                target: read_name(msg, data + 6)?.0,
+            });
+        }
+        pos = data + len;
+    }
+    Ok(records)
+}
+
+/// Reads the possibly compressed name at `pos`, returning it and the
+/// position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), ParseError> {
+    let mut name = String::new();
+    let mut end = None;
+    // Each pointer must go backwards, so this bounds the loop
+    let mut limit = pos;
+    loop {
+        let len = *msg.get(pos).ok_or(ParseError::Malformed)? as usize;
+        match len {
+            0 => return Ok((name, end.unwrap_or(pos + 1))),
+            len if len & 0xc0 == 0xc0 => {
+                let target = ((len & 0x3f) << 8) | *msg.get(pos + 1).ok_or(ParseError::Malformed)? as usize;
+                if target >= limit {
+                    return Err(ParseError::Malformed);
+                }
+                end.get_or_insert(pos + 2);
+                limit = target;
+                pos = target;
+            }
+            len if len <= 63 => {
+                let label = msg.get(pos + 1..pos + 1 + len).ok_or(ParseError::Malformed)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + len;
+            }
+            _ => return Err(ParseError::Malformed),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A response to `query` answering with `records`, targets compressed
+    /// where they repeat the question's name.
+    pub(crate) fn response(query: &[u8], records: &[(u16, u16, u16, &str)]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] |= 0x80;
+        msg[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for &(priority, weight, port, target) in records {
+            // The owner name points back at the question
+            msg.extend_from_slice(&[0xc0, 12]);
+            msg.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+            msg.extend_from_slice(&300u32.to_be_bytes());
+            let mut data = Vec::new();
+            for field in [priority, weight, port] {
+                data.extend_from_slice(&field.to_be_bytes());
+            }
+            for label in target.split('.') {
+                data.push(label.len() as u8);
+                data.extend_from_slice(label.as_bytes());
+            }
+            data.push(0);
+            msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            msg.extend(data);
+        }
+        msg
+    }
+
+    #[test]
+    fn test_query_encodes_the_name() {
+        let query = build_query(0xbeef, "_http._tcp.web.").unwrap();
+        assert_eq!(&query[..4], [0xbe, 0xef, 0x01, 0x00]);
+        assert_eq!(&query[12..], b"\x05_http\x04_tcp\x03web\x00\x00\x21\x00\x01");
+        assert!(build_query(1, "a..b").is_err());
+    }
+
+    #[test]
+    fn test_response_yields_srv_records() {
+        let query = build_query(7, "_http._tcp.web").unwrap();
+        let msg = response(&query, &[(10, 60, 8080, "a.web"), (20, 0, 8081, "b.web")]);
+        let records = parse_response(7, &msg).unwrap();
+        assert_eq!(
+            records,
+            [
+                SrvRecord { priority: 10, weight: 60, port: 8080, target: "a.web".to_string() },
+                SrvRecord { priority: 20, weight: 0, port: 8081, target: "b.web".to_string() },
+            ]
+        );
+
+        assert_eq!(parse_response(8, &msg), Err(ParseError::Malformed));
+        let mut truncated = msg.clone();
+        truncated[2] |= 0x02;
+        assert_eq!(parse_response(7, &truncated), Err(ParseError::Truncated));
+        let mut nxdomain = msg.clone();
+        nxdomain[3] |= 0x03;
+        assert_eq!(parse_response(7, &nxdomain), Err(ParseError::Rcode(3)));
+        assert_eq!(parse_response(7, &msg[..msg.len() - 3]), Err(ParseError::Malformed));
+    }
+
+    #[test]
+    fn test_compression_loops_are_rejected() {
+        let msg = [0xc0, 0];
+        assert_eq!(read_name(&msg, 0), Err(ParseError::Malformed));
+    }
+}
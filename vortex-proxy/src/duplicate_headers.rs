@@ -0,0 +1,151 @@
+//! Policy for upstream responses repeating framing headers.
+//!
+//! A response with two `Content-Length` fields, or a `Content-Encoding` that
+//! lists the same coding twice, is ambiguous: a downstream client or cache
+//! may frame or decode it differently than the proxy did, which is how
+//! response splitting and smuggling start. Such responses are replaced with
+//! a 502 by default. Backends known to send them harmlessly can be set to
+//! have them repaired instead: identical `Content-Length` fields are
+//! collapsed into one and repeated codings dropped. Conflicting
+//! `Content-Length` values are never repaired.
+
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH};
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+
+/// Responses with duplicate framing headers, by `header` and the `action` taken
+static DUPLICATES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_upstream_duplicate_headers_total",
+        "Upstream responses with duplicate framing headers",
+        &["header", "action"]
+    )
+    .expect("metric registers once")
+});
+
+/// What to do with a response repeating framing headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateHeaderAction {
+    /// Replace it with a 502
+    #[default]
+    Reject,
+    /// Collapse the duplicates where that is unambiguous, reject otherwise
+    Repair,
+}
+
+/// Duplicate header handling, by backend address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DuplicateHeaders {
+    /// Action for backends without their own
+    pub default: DuplicateHeaderAction,
+    /// Actions for individual backends, e.g. known-broken ones
+    pub backends: HashMap<SocketAddr, DuplicateHeaderAction>,
+}
+
+impl DuplicateHeaders {
+    fn for_backend(&self, addr: SocketAddr) -> DuplicateHeaderAction {
+        self.backends.get(&addr).copied().unwrap_or(self.default)
+    }
+
+    /// Apply the policy for the backend at `addr` to its response headers,
+    /// returning why the response must be rejected, if it must.
+    pub(crate) fn enforce(&self, addr: SocketAddr, headers: &mut HeaderMap) -> Result<(), String> {
+        let action = self.for_backend(addr);
+        let result = enforce_content_length(action, headers).and_then(|()| enforce_content_encoding(action, headers));
+        if let Err((header, _)) = &result {
+            DUPLICATES.with_label_values(&[header, "rejected"]).inc();
+        }
+        result.map_err(|(_, reason)| reason)
+    }
+}
+
+type Violation = (&'static str, String);
+
+fn enforce_content_length(action: DuplicateHeaderAction, headers: &mut HeaderMap) -> Result<(), Violation> {
+    let values: Vec<HeaderValue> = headers.get_all(CONTENT_LENGTH).iter().cloned().collect();
+    if values.len() < 2 {
+        return Ok(());
+    }
+    let first = values[0].as_bytes().trim_ascii();
+    if !values.iter().all(|v| v.as_bytes().trim_ascii() == first) {
+        return Err(("content-length", "conflicting Content-Length headers".to_string()));
+    }
+    match action {
+        DuplicateHeaderAction::Reject => {
+            Err(("content-length", format!("{} Content-Length headers", values.len())))
+        }
+        DuplicateHeaderAction::Repair => {
+            headers.insert(CONTENT_LENGTH, values[0].clone());
+            DUPLICATES.with_label_values(&["content-length", "repaired"]).inc();
+            Ok(())
+        }
+    }
+}
+
+fn enforce_content_encoding(action: DuplicateHeaderAction, headers: &mut HeaderMap) -> Result<(), Violation> {
+    let mut codings: Vec<String> = Vec::new();
+    let mut repeated = None;
+    for value in headers.get_all(CONTENT_ENCODING) {
+        let value = value
+            .to_str()
+            .map_err(|_| ("content-encoding", "Content-Encoding is not valid text".to_string()))?;
+        for coding in value.split(',').map(|c| c.trim().to_ascii_lowercase()).filter(|c| !c.is_empty()) {
+            if codings.contains(&coding) {
+                repeated.get_or_insert_with(|| coding.clone());
+            } else {
+                codings.push(coding);
+            }
+        }
+    }
+    let Some(repeated) = repeated else {
+        return Ok(());
+    };
+    match action {
+        DuplicateHeaderAction::Reject => Err(("content-encoding", format!("Content-Encoding lists {} twice", repeated))),
+        DuplicateHeaderAction::Repair => {
+            let joined = HeaderValue::from_str(&codings.join(", ")).expect("codings were valid header text");
+            headers.insert(CONTENT_ENCODING, joined);
+            DUPLICATES.with_label_values(&["content-encoding", "repaired"]).inc();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_duplicates_are_rejected_unless_the_backend_is_repaired() {
+        let broken: SocketAddr = "127.0.0.1:9091".parse().unwrap();
+        let healthy: SocketAddr = "127.0.0.1:9090".parse().unwrap();
+        let policy = DuplicateHeaders {
+            backends: [(broken, DuplicateHeaderAction::Repair)].into(),
+            ..DuplicateHeaders::default()
+        };
+
+        let mut single = headers(&[("content-length", "5"), ("content-encoding", "gzip"), ("content-encoding", "br")]);
+        assert_eq!(policy.enforce(healthy, &mut single), Ok(()));
+        assert_eq!(single.get_all(CONTENT_ENCODING).iter().count(), 2);
+
+        let twice = headers(&[("content-length", "5"), ("content-length", " 5"), ("content-encoding", "gzip, GZIP")]);
+        assert!(policy.enforce(healthy, &mut twice.clone()).unwrap_err().contains("2 Content-Length"));
+        let mut repaired = twice;
+        assert_eq!(policy.enforce(broken, &mut repaired), Ok(()));
+        assert_eq!(repaired.get_all(CONTENT_LENGTH).iter().count(), 1);
+        assert_eq!(repaired[CONTENT_ENCODING], "gzip");
+
+        let mut conflicting = headers(&[("content-length", "5"), ("content-length", "6")]);
+        assert!(policy.enforce(broken, &mut conflicting).unwrap_err().contains("conflicting"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Structured error types for the proxy data plane.
+//!
+//! Every failure on the request path is classified into a `ProxyError` variant
+//! carrying the backend and route involved, so error pages, retries, metrics,
+//! and logs can key off the error kind instead of parsing strings.
+
+use hyper::{Method, StatusCode};
+use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
+use vortex_core::domain::backend::BackendId;
+
+/// Errors produced while accepting, routing, or proxying a request.
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    /// Routes are configured but none matches the request.
+    #[error("no route matches {path}")]
+    NoRouteMatch {
+        /// The request path
+        path: String,
+    },
+
+    /// No backend in the routing table is currently healthy.
+    #[error("no healthy backend available for route {route}")]
+    NoHealthyBackend {
+        /// The route the request was matched against
+        route: String,
+    },
+
+    /// The TCP connection to the upstream could not be established.
+    #[error("failed to connect to backend {} ({addr}): {source}", backend.0)]
+    UpstreamConnect {
+        /// The backend that was selected
+        backend: BackendId,
+        /// The address that was dialed
+        addr: SocketAddr,
+        /// The underlying socket error
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The upstream did not answer within the allotted time.
+    #[error("backend {} ({addr}) timed out during {phase}", backend.0)]
+    UpstreamTimeout {
+        /// The backend that was selected
+        backend: BackendId,
+        /// The address that was dialed
+        addr: SocketAddr,
+        /// Which phase of the exchange timed out
+        phase: &'static str,
+    },
+
+    /// Connect pacing held the request back and no connection to the
+    /// backend became available in time.
+    #[error("backend {} ({addr}) is paced and had no connection available", backend.0)]
+    ConnectPaced {
+        /// The backend that was selected
+        backend: BackendId,
+        /// The address that would have been dialed
+        addr: SocketAddr,
+    },
+
+    /// The client's deadline passed before the request completed.
+    #[error("deadline exceeded for route {route}")]
+    DeadlineExceeded {
+        /// The request path
+        route: String,
+    },
+
+    /// The HTTP/1.1 handshake or exchange with the upstream failed.
+    #[error("HTTP exchange with backend {} ({addr}) failed: {source}", backend.0)]
+    UpstreamProtocol {
+        /// The backend that was selected
+        backend: BackendId,
+        /// The address that was dialed
+        addr: SocketAddr,
+        /// The underlying hyper error
+        #[source]
+        source: hyper::Error,
+    },
+
+    /// The upstream's response was malformed in a way that can't be forwarded safely.
+    #[error("invalid response from backend {} ({addr}): {reason}", backend.0)]
+    InvalidUpstreamResponse {
+        /// The backend that was selected
+        backend: BackendId,
+        /// The address that was dialed
+        addr: SocketAddr,
+        /// What was wrong with it
+        reason: String,
+    },
+
+    /// The downstream TLS handshake failed.
+    #[error("TLS handshake with {peer} failed: {source}")]
+    TlsHandshake {
+        /// The client address
+        peer: SocketAddr,
+        /// The underlying TLS/socket error
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The request could not be rewritten for the upstream.
+    #[error("invalid request for route {route}: {reason}")]
+    InvalidRequest {
+        /// The route the request was matched against
+        route: String,
+        /// What was wrong with it
+        reason: String,
+    },
+
+    /// The request URI is longer than its route allows.
+    #[error("URI for route {route} exceeds the {limit} byte limit")]
+    UriTooLong {
+        /// The route the request was matched against
+        route: String,
+        /// The route's limit in bytes
+        limit: usize,
+    },
+
+    /// The request method isn't on its route's allow-list.
+    #[error("method {method} is not allowed on route {route}")]
+    MethodNotAllowed {
+        /// The route the request was matched against
+        route: String,
+        /// The request method
+        method: Method,
+        /// The methods the route accepts
+        allowed: Vec<Method>,
+    },
+
+    /// The request matched its route's `deny` condition.
+    #[error("request denied on route {route} by {condition}")]
+    PolicyDenied {
+        /// The route the request was matched against
+        route: String,
+        /// The condition it matched
+        condition: String,
+    },
+
+    /// The request body is larger than its route allows.
+    #[error("request body for route {route} exceeds the {limit} byte limit")]
+    RequestTooLarge {
+        /// The route the request was matched against
+        route: String,
+        /// The route's limit in bytes
+        limit: u64,
+    },
+
+    /// The request's headers exceed the listener's header limits.
+    #[error("request headers too large: {reason}")]
+    RequestHeadersTooLarge {
+        /// Which limit was exceeded
+        reason: String,
+    },
+
+    /// The upstream response's headers exceed the listener's header limits.
+    #[error("response headers too large: {reason}")]
+    ResponseHeadersTooLarge {
+        /// Which limit was exceeded
+        reason: String,
+    },
+
+    /// The upstream response is larger than its route allows.
+    #[error("response for route {route} exceeds the {limit} byte limit")]
+    ResponseTooLarge {
+        /// The route the request was matched against
+        route: String,
+        /// The route's limit in bytes
+        limit: u64,
+    },
+
+    /// The client already has as many requests in flight as it may.
+    #[error("client {client} already has {limit} requests in flight")]
+    ClientConcurrency {
+        /// The client's address, or its API key's fingerprint
+        client: String,
+        /// The client's cap
+        limit: u32,
+    },
+
+    /// The client has spent its request quota for now.
+    #[error("client {client} is out of quota for {retry_after:?}")]
+    QuotaExceeded {
+        /// The client's address, or its API key's fingerprint
+        client: String,
+        /// How long until the request would be admitted
+        retry_after: Duration,
+    },
+
+    /// Credentials for the upstream could not be obtained or applied.
+    #[error("upstream authentication failed: {reason}")]
+    UpstreamAuth {
+        /// What went wrong
+        reason: String,
+    },
+
+    /// The listening socket failed to bind or accept.
+    #[error("listener error: {0}")]
+    Listener(#[from] std::io::Error),
+
+    /// A metric could not be created, e.g. an invalid dimension name.
+    #[error("metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
+}
+
+impl ProxyError {
+    /// A short, stable identifier for metrics labels and structured logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProxyError::NoRouteMatch { .. } => "no_route_match",
+            ProxyError::NoHealthyBackend { .. } => "no_healthy_backend",
+            ProxyError::UpstreamConnect { .. } => "upstream_connect",
+            ProxyError::UpstreamTimeout { .. } => "upstream_timeout",
+            ProxyError::ConnectPaced { .. } => "connect_paced",
+            ProxyError::DeadlineExceeded { .. } => "deadline_exceeded",
+            ProxyError::UpstreamProtocol { .. } => "upstream_protocol",
+            ProxyError::InvalidUpstreamResponse { .. } => "invalid_upstream_response",
+            ProxyError::TlsHandshake { .. } => "tls_handshake",
+            ProxyError::InvalidRequest { .. } => "invalid_request",
+            ProxyError::UriTooLong { .. } => "uri_too_long",
+            ProxyError::MethodNotAllowed { .. } => "method_not_allowed",
+            ProxyError::PolicyDenied { .. } => "policy_denied",
+            ProxyError::RequestTooLarge { .. } => "request_too_large",
+            ProxyError::RequestHeadersTooLarge { .. } => "request_headers_too_large",
+            ProxyError::ResponseHeadersTooLarge { .. } => "response_headers_too_large",
+            ProxyError::ResponseTooLarge { .. } => "response_too_large",
+            ProxyError::ClientConcurrency { .. } => "client_concurrency",
+            ProxyError::QuotaExceeded { .. } => "quota_exceeded",
+            ProxyError::UpstreamAuth { .. } => "upstream_auth",
+            ProxyError::Listener(_) => "listener",
+            ProxyError::Metrics(_) => "metrics",
+        }
+    }
+
+    /// The backend involved in the failure, if one had been selected.
+    pub fn backend(&self) -> Option<BackendId> {
+        match self {
+            ProxyError::UpstreamConnect { backend, .. }
+            | ProxyError::UpstreamTimeout { backend, .. }
+            | ProxyError::ConnectPaced { backend, .. }
+            | ProxyError::UpstreamProtocol { backend, .. }
+            | ProxyError::InvalidUpstreamResponse { backend, .. } => Some(*backend),
+            _ => None,
+        }
+    }
+
+    /// The status code sent downstream when this error ends a request.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProxyError::NoRouteMatch { .. } => StatusCode::NOT_FOUND,
+            ProxyError::NoHealthyBackend { .. } | ProxyError::ConnectPaced { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::UpstreamTimeout { .. } | ProxyError::DeadlineExceeded { .. } => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            ProxyError::UriTooLong { .. } => StatusCode::URI_TOO_LONG,
+            ProxyError::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            ProxyError::PolicyDenied { .. } => StatusCode::FORBIDDEN,
+            ProxyError::RequestTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ProxyError::RequestHeadersTooLarge { .. } => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ProxyError::ClientConcurrency { .. } | ProxyError::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_kinds_map_to_statuses() {
+        let addr: SocketAddr = "127.0.0.1:9090".parse().unwrap();
+
+        let err = ProxyError::NoHealthyBackend { route: "/".to_string() };
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.backend(), None);
+
+        let err = ProxyError::NoRouteMatch { path: "/missing".to_string() };
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(err.kind(), "no_route_match");
+
+        let err = ProxyError::UpstreamTimeout { backend: BackendId(7), addr, phase: "connect" };
+        assert_eq!(err.status_code(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(err.kind(), "upstream_timeout");
+        assert_eq!(err.backend(), Some(BackendId(7)));
+
+        let err = ProxyError::ConnectPaced { backend: BackendId(7), addr };
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.backend(), Some(BackendId(7)));
+
+        let err = ProxyError::DeadlineExceeded { route: "/".to_string() };
+        assert_eq!(err.status_code(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(err.kind(), "deadline_exceeded");
+
+        let err = ProxyError::UpstreamConnect {
+            backend: BackendId(1),
+            addr,
+            source: std::io::Error::from(std::io::ErrorKind::ConnectionRefused),
+        };
+        assert_eq!(err.status_code(), StatusCode::BAD_GATEWAY);
+        assert!(err.to_string().contains("backend 1"));
+
+        let err = ProxyError::InvalidUpstreamResponse { backend: BackendId(2), addr, reason: "2 Content-Length headers".to_string() };
+        assert_eq!(err.status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(err.backend(), Some(BackendId(2)));
+
+        let err = ProxyError::RequestHeadersTooLarge { reason: "101 headers".to_string() };
+        assert_eq!(err.status_code(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        let err = ProxyError::ResponseHeadersTooLarge { reason: "101 headers".to_string() };
+        assert_eq!(err.status_code(), StatusCode::BAD_GATEWAY);
+    }
+}
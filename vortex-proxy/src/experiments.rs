@@ -0,0 +1,352 @@
+//! Route-level A/B experiment assignment.
+//!
+//! An `Experiment` splits a route's traffic between named variants by
+//! weight. A request is bucketed by hashing the experiment's name with a key
+//! taken from the request (a header, a cookie or the client IP), so the same
+//! user lands in the same variant on every request and on every proxy
+//! instance, and separate experiments bucket independently. Requests
+//! without the key are not enrolled.
+//!
+//! The variant is sent upstream in the experiment's header, so backends can
+//! branch on it without bucketing themselves. Whatever the client sent in
+//! that header is dropped, enrolled or not. Every assignment is an exposure: it is counted in
+//! `vortex_experiment_exposures_total`, logged as a `vortex::exposure`
+//! event and, when log shipping is enabled, shipped as an `exposure` record.
+//! Exposures carry the request's `X-Request-ID`, which analytics can join
+//! against access logs and the application's own outcome events.
+
+use hyper::header::{HeaderName, HeaderValue};
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tower::{Layer, Service};
+use vortex_core::load_balancer::hash::stable_hash;
+
+use crate::cache::cookie;
+use crate::error::ProxyError;
+use crate::log_sink::{LogKind, LogRecord, LogSeverity, LogShipper};
+use crate::pipeline::{take_inner, ClientAddr, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+use crate::request_id::RequestId;
+
+/// Assignments, by `experiment` and `variant`
+static EXPOSURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_experiment_exposures_total",
+        "Requests assigned to an experiment variant",
+        &["experiment", "variant"]
+    )
+    .expect("metric registers once")
+});
+
+/// What identifies the unit being bucketed, usually a user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssignmentKey {
+    /// The value of a request header, e.g. `x-user-id`
+    Header(HeaderName),
+    /// The value of a cookie, e.g. a session ID
+    Cookie(String),
+    /// The client's IP address
+    ClientIp,
+}
+
+/// One arm of an experiment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    /// Name sent upstream and in exposures, e.g. `control`
+    pub name: String,
+    /// Share of traffic relative to the other variants
+    pub weight: u32,
+}
+
+impl Variant {
+    /// A variant getting `weight` shares of the traffic.
+    pub fn new(name: impl Into<String>, weight: u32) -> Self {
+        Self {
+            name: name.into(),
+            weight,
+        }
+    }
+}
+
+/// A split of one route's traffic between variants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Experiment {
+    /// Name in exposures; also seeds the bucketing
+    pub name: String,
+    /// What requests are bucketed by
+    pub key: AssignmentKey,
+    /// The variants and their weights
+    pub variants: Vec<Variant>,
+    /// Header carrying the variant upstream
+    pub header: HeaderName,
+}
+
+impl Experiment {
+    /// An experiment sending its variant upstream in `x-experiment-<name>`.
+    ///
+    /// # Panics
+    ///
+    /// If `name` can't be part of a header name.
+    pub fn new(name: impl Into<String>, key: AssignmentKey, variants: Vec<Variant>) -> Self {
+        let name = name.into();
+        let header = HeaderName::try_from(format!("x-experiment-{}", name.to_ascii_lowercase()))
+            .expect("experiment names are header-safe");
+        Self {
+            name,
+            key,
+            variants,
+            header,
+        }
+    }
+
+    /// Send the variant in `header` instead, builder style.
+    pub fn with_header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// The variant for a unit identified by `key`, or `None` if no variant
+    /// has any weight.
+    pub fn assign(&self, key: &[u8]) -> Option<&Variant> {
+        let total: u64 = self.variants.iter().map(|v| u64::from(v.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut bucket = stable_hash(&[self.name.as_bytes(), b"\0", key]) % total;
+        self.variants.iter().find(|v| {
+            let weight = u64::from(v.weight);
+            if bucket < weight {
+                return true;
+            }
+            bucket -= weight;
+            false
+        })
+    }
+
+    fn key_of(&self, req: &ProxyRequest) -> Option<Vec<u8>> {
+        match &self.key {
+            AssignmentKey::Header(name) => req.headers().get(name).map(|v| v.as_bytes().to_vec()),
+            AssignmentKey::Cookie(name) => cookie(req.headers(), name).map(|v| v.as_bytes().to_vec()),
+            AssignmentKey::ClientIp => req.extensions().get::<ClientAddr>().map(|c| c.0.ip().to_string().into_bytes()),
+        }
+    }
+}
+
+/// The variants a request was assigned, by experiment name, in its
+/// extensions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Assignments(pub Vec<(String, String)>);
+
+/// Experiments by route name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteExperiments {
+    /// The experiments running on each route
+    pub routes: HashMap<String, Vec<Experiment>>,
+}
+
+impl RouteExperiments {
+    /// Run `experiment` on `route`, builder style.
+    pub fn with(mut self, route: impl Into<String>, experiment: Experiment) -> Self {
+        self.routes.entry(route.into()).or_default().push(experiment);
+        self
+    }
+}
+
+/// Assigns requests to the variants of their route's experiments.
+#[derive(Clone)]
+pub struct ExperimentLayer {
+    experiments: Arc<RouteExperiments>,
+    shipper: Option<Arc<LogShipper>>,
+}
+
+impl ExperimentLayer {
+    /// Create a layer running `experiments`.
+    pub fn new(experiments: RouteExperiments) -> Self {
+        Self {
+            experiments: Arc::new(experiments),
+            shipper: None,
+        }
+    }
+
+    /// Also ship exposure records to `shipper`, builder style.
+    pub fn with_shipper(mut self, shipper: Arc<LogShipper>) -> Self {
+        self.shipper = Some(shipper);
+        self
+    }
+}
+
+impl<S> Layer<S> for ExperimentLayer {
+    type Service = ExperimentService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ExperimentService {
+            inner,
+            experiments: self.experiments.clone(),
+            shipper: self.shipper.clone(),
+        }
+    }
+}
+
+/// Service produced by `ExperimentLayer`.
+#[derive(Clone)]
+pub struct ExperimentService<S> {
+    inner: S,
+    experiments: Arc<RouteExperiments>,
+    shipper: Option<Arc<LogShipper>>,
+}
+
+impl<S> ExperimentService<S> {
+    fn enroll(&self, req: &mut ProxyRequest) {
+        let Some(route) = req.extensions().get::<RouteContext>().map(|c| c.route.clone()) else {
+            return;
+        };
+        let Some(experiments) = self.experiments.routes.get(&route) else {
+            return;
+        };
+        let request_id = req.extensions().get::<RequestId>().map(RequestId::to_string);
+        let mut assignments = Assignments::default();
+        for experiment in experiments {
+            req.headers_mut().remove(&experiment.header);
+            let Some(variant) = experiment.key_of(req).and_then(|key| experiment.assign(&key)) else {
+                continue;
+            };
+            let Ok(value) = HeaderValue::from_str(&variant.name) else {
+                continue;
+            };
+            req.headers_mut().insert(experiment.header.clone(), value);
+            EXPOSURES.with_label_values(&[&experiment.name, &variant.name]).inc();
+            tracing::info!(
+                target: "vortex::exposure",
+                experiment = %experiment.name,
+                variant = %variant.name,
+                route = %route,
+                request_id = request_id.as_deref(),
+                "experiment exposure"
+            );
+            if let Some(shipper) = &self.shipper {
+                shipper.ship(LogRecord {
+                    kind: LogKind::Exposure,
+                    severity: LogSeverity::Info,
+                    timestamp: SystemTime::now(),
+                    message: format!(
+                        "experiment={} variant={} route={} request_id={}",
+                        experiment.name,
+                        variant.name,
+                        route,
+                        request_id.as_deref().unwrap_or("-")
+                    ),
+                });
+            }
+            assignments.0.push((experiment.name.clone(), variant.name.clone()));
+        }
+        if !assignments.0.is_empty() {
+            req.extensions_mut().insert(assignments);
+        }
+    }
+}
+
+impl<S> Service<ProxyRequest> for ExperimentService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        self.enroll(&mut req);
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move { inner.call(req).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use hyper::body::Bytes;
+    use hyper::Request;
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::{Backend, BackendId};
+
+    fn experiment() -> Experiment {
+        Experiment::new(
+            "checkout",
+            AssignmentKey::Header(HeaderName::from_static("x-user-id")),
+            vec![Variant::new("control", 3), Variant::new("one-click", 1)],
+        )
+    }
+
+    #[test]
+    fn test_assignment_is_sticky_and_follows_the_weights() {
+        let experiment = experiment();
+        let assign = |key: &str| experiment.assign(key.as_bytes()).unwrap().name.as_str();
+        assert_eq!(assign("user-7"), assign("user-7"));
+
+        let control = (0..4000).filter(|i| assign(&format!("user-{}", i)) == "control").count();
+        assert!((2850..3150).contains(&control), "{} of 4000 in control", control);
+
+        // Another experiment over the same users buckets them independently
+        let other = Experiment { name: "search".to_string(), ..experiment.clone() };
+        let same = (0..4000)
+            .filter(|i| {
+                let key = format!("user-{}", i);
+                other.assign(key.as_bytes()).unwrap().name == assign(&key)
+            })
+            .count();
+        assert!(same < 3000, "{} of 4000 bucketed alike", same);
+
+        let off = Experiment { variants: vec![Variant::new("control", 0)], ..experiment };
+        assert!(off.assign(b"user-7").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_variant_replaces_the_client_header() {
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:1".parse().unwrap()));
+        let layer = ExperimentLayer::new(RouteExperiments::default().with("shop", experiment()));
+        let service = layer.layer(tower::service_fn(|req: ProxyRequest| async move {
+            let variant = req.headers().get("x-experiment-checkout").cloned();
+            let assignments = req.extensions().get::<Assignments>().cloned();
+            let mut res = ProxyResponse::new(full_body(Bytes::new()));
+            res.extensions_mut().insert((variant, assignments));
+            Ok::<_, ProxyError>(res)
+        }));
+
+        let request = |route: &str, user: Option<&str>| {
+            let mut builder = Request::builder().header("x-experiment-checkout", "forged");
+            if let Some(user) = user {
+                builder = builder.header("x-user-id", user);
+            }
+            let mut req = builder.body(full_body(Bytes::new())).unwrap();
+            req.extensions_mut().insert(RouteContext {
+                route: route.to_string(),
+                backend: backend.clone(),
+                labels: Default::default(),
+            });
+            req
+        };
+        type Seen = (Option<HeaderValue>, Option<Assignments>);
+        let seen = |res: ProxyResponse| res.extensions().get::<Seen>().cloned().unwrap();
+
+        let expected = experiment().assign(b"user-7").unwrap().name.clone();
+        let (variant, assignments) = seen(service.clone().oneshot(request("shop", Some("user-7"))).await.unwrap());
+        assert_eq!(variant.unwrap(), expected.as_str());
+        assert_eq!(assignments.unwrap().0, [("checkout".to_string(), expected)]);
+
+        // Without the key nothing is assigned, and the forged variant is dropped
+        let (variant, assignments) = seen(service.clone().oneshot(request("shop", None)).await.unwrap());
+        assert!(variant.is_none() && assignments.is_none());
+
+        // Routes without experiments are left alone
+        let (variant, assignments) = seen(service.oneshot(request("blog", Some("user-7"))).await.unwrap());
+        assert_eq!(variant.unwrap(), "forged");
+        assert!(assignments.is_none());
+    }
+}
@@ -0,0 +1,185 @@
+//! File descriptor guardrails.
+//!
+//! Every downstream connection, upstream connection and log file costs a
+//! descriptor, and once the process reaches `RLIMIT_NOFILE` everything that
+//! opens one fails at once: `accept()`, upstream connects, health probes. A
+//! guardrail samples descriptor usage and, past a high-water mark below the
+//! limit, has listeners turn new connections away with a 503 so requests
+//! already in flight keep the descriptors they need.
+//!
+//! Usage is read from `/proc/self`, so guardrails only work on Linux.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::metrics;
+
+/// When to start refusing connections.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FdGuardrail {
+    /// Fraction of the soft `RLIMIT_NOFILE` above which new connections are refused
+    pub high_water: f64,
+    /// How often descriptor usage is sampled
+    pub check_interval: Duration,
+}
+
+impl Default for FdGuardrail {
+    fn default() -> Self {
+        Self {
+            high_water: 0.9,
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Tracks descriptor usage against the process limit.
+#[derive(Debug)]
+pub struct FdMonitor {
+    limit: u64,
+    high_water: u64,
+    open: AtomicU64,
+    saturated: AtomicBool,
+}
+
+impl FdMonitor {
+    /// Create a monitor for a process allowed `limit` descriptors.
+    pub fn new(config: FdGuardrail, limit: u64) -> Self {
+        Self {
+            limit,
+            high_water: (limit as f64 * config.high_water.clamp(0.0, 1.0)) as u64,
+            open: AtomicU64::new(0),
+            saturated: AtomicBool::new(false),
+        }
+    }
+
+    /// Read the process limit and start sampling usage every
+    /// `config.check_interval`.
+    pub fn spawn(config: FdGuardrail) -> io::Result<(Arc<Self>, JoinHandle<()>)> {
+        let monitor = Arc::new(Self::new(config, soft_limit()?));
+        monitor.observe(open_fds()?);
+        metrics::FD_LIMIT.set(monitor.limit as i64);
+
+        let sampler = monitor.clone();
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(config.check_interval);
+            loop {
+                ticker.tick().await;
+                match open_fds() {
+                    Ok(open) => sampler.observe(open),
+                    Err(e) => tracing::warn!(error = %e, "failed to count open file descriptors"),
+                }
+            }
+        });
+        Ok((monitor, task))
+    }
+
+    /// Record a usage sample, logging when usage crosses the high-water mark
+    /// either way.
+    pub fn observe(&self, open: u64) {
+        self.open.store(open, Ordering::Relaxed);
+        metrics::OPEN_FDS.set(open as i64);
+        let saturated = open >= self.high_water;
+        if self.saturated.swap(saturated, Ordering::Relaxed) != saturated {
+            if saturated {
+                tracing::warn!(
+                    open,
+                    limit = self.limit,
+                    high_water = self.high_water,
+                    "file descriptors nearly exhausted, refusing new connections"
+                );
+            } else {
+                tracing::info!(open, limit = self.limit, "file descriptor usage recovered, accepting new connections");
+            }
+        }
+    }
+
+    /// Whether new connections should be refused.
+    pub fn saturated(&self) -> bool {
+        self.saturated.load(Ordering::Relaxed)
+    }
+
+    /// Descriptors open at the last sample.
+    pub fn open(&self) -> u64 {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// The soft descriptor limit.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
+
+/// Whether an `accept()` error means the process or system ran out of
+/// descriptors, rather than the listener being broken.
+pub(crate) fn is_exhaustion(e: &io::Error) -> bool {
+    // EMFILE and ENFILE
+    matches!(e.raw_os_error(), Some(23 | 24))
+}
+
+#[cfg(target_os = "linux")]
+fn soft_limit() -> io::Result<u64> {
+    let limits = std::fs::read_to_string("/proc/self/limits")?;
+    parse_soft_limit(&limits).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no open files limit"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn soft_limit() -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "file descriptor guardrails require Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn open_fds() -> io::Result<u64> {
+    Ok(std::fs::read_dir("/proc/self/fd")?.count() as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds() -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "file descriptor guardrails require Linux"))
+}
+
+/// Finds the soft limit on the `Max open files` line of `/proc/self/limits`.
+/// An unlimited soft limit is reported as `u64::MAX`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_soft_limit(limits: &str) -> Option<u64> {
+    let line = limits.lines().find(|l| l.starts_with("Max open files"))?;
+    match line["Max open files".len()..].split_whitespace().next()? {
+        "unlimited" => Some(u64::MAX),
+        soft => soft.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_soft_limit() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max processes             63304                63304                processes \n\
+                      Max open files            1024                 524288               files     \n";
+        assert_eq!(parse_soft_limit(limits), Some(1024));
+        assert_eq!(parse_soft_limit("Max open files unlimited unlimited files"), Some(u64::MAX));
+        assert_eq!(parse_soft_limit("Max processes 10 10 processes"), None);
+    }
+
+    #[test]
+    fn test_saturation_follows_the_high_water_mark() {
+        let monitor = FdMonitor::new(
+            FdGuardrail {
+                high_water: 0.8,
+                ..FdGuardrail::default()
+            },
+            100,
+        );
+        monitor.observe(79);
+        assert!(!monitor.saturated());
+        monitor.observe(80);
+        assert!(monitor.saturated());
+        monitor.observe(60);
+        assert!(!monitor.saturated());
+        assert_eq!(monitor.open(), 60);
+    }
+}
@@ -0,0 +1,139 @@
+//! Debug override that pins a request to one backend.
+//!
+//! Reproducing a bug that only one node exhibits is hard when the load
+//! balancer keeps spreading requests. With the override enabled, a trusted
+//! client can send `X-Vortex-Force-Backend: <backend id>` to skip backend
+//! selection. Only clients from the configured networks (and, if a token is
+//! set, presenting it) are trusted; everyone else has the headers silently
+//! dropped. The headers are never forwarded upstream.
+
+use hyper::header::HeaderName;
+use ipnet::IpNet;
+use vortex_core::domain::backend::BackendId;
+
+use crate::error::ProxyError;
+use crate::pipeline::{ClientAddr, ProxyRequest};
+
+/// The header naming the backend id to target, unless configured otherwise.
+pub const DEFAULT_FORCE_BACKEND_HEADER: &str = "x-vortex-force-backend";
+
+/// The header carrying the shared debug token, unless configured otherwise.
+pub const DEFAULT_DEBUG_TOKEN_HEADER: &str = "x-vortex-debug-token";
+
+/// Who may bypass load balancing, and how they ask for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForceBackend {
+    /// Header naming the backend id to target
+    pub header: HeaderName,
+    /// Client networks allowed to use the override
+    pub trusted_networks: Vec<IpNet>,
+    /// Shared secret trusted clients must also present, if set
+    pub token: Option<String>,
+    /// Header carrying the shared secret
+    pub token_header: HeaderName,
+}
+
+impl Default for ForceBackend {
+    fn default() -> Self {
+        Self {
+            header: HeaderName::from_static(DEFAULT_FORCE_BACKEND_HEADER),
+            trusted_networks: vec![
+                "127.0.0.0/8".parse().expect("valid network"),
+                "::1/128".parse().expect("valid network"),
+            ],
+            token: None,
+            token_header: HeaderName::from_static(DEFAULT_DEBUG_TOKEN_HEADER),
+        }
+    }
+}
+
+/// Marks a request whose backend was chosen by the override, so later
+/// stages (e.g. retries) don't move it to another backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForcedBackend(pub BackendId);
+
+impl ForceBackend {
+    /// Strips the override headers from `req` and returns the backend id it
+    /// asks for, if the client is allowed to ask.
+    pub fn take_requested(&self, req: &mut ProxyRequest, route: &str) -> Result<Option<BackendId>, ProxyError> {
+        let requested = req.headers_mut().remove(&self.header);
+        let token = req.headers_mut().remove(&self.token_header);
+        let Some(requested) = requested else {
+            return Ok(None);
+        };
+
+        let client = req.extensions().get::<ClientAddr>().map(|ClientAddr(addr)| addr.ip());
+        let trusted_network = client.is_some_and(|ip| self.trusted_networks.iter().any(|net| net.contains(&ip)));
+        let token_ok = match &self.token {
+            Some(expected) => token.is_some_and(|t| constant_time_eq(t.as_bytes(), expected.as_bytes())),
+            None => true,
+        };
+        if !trusted_network || !token_ok {
+            tracing::warn!(header = %self.header, ?client, "ignoring backend override from untrusted client");
+            return Ok(None);
+        }
+
+        let id = requested
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .ok_or_else(|| ProxyError::InvalidRequest {
+                route: route.to_string(),
+                reason: format!("{} must be a backend id", self.header),
+            })?;
+        Ok(Some(BackendId(id)))
+    }
+}
+
+/// Compares secrets without leaking the length of the matching prefix through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use hyper::body::Bytes;
+    use hyper::Request;
+
+    fn request(client: &str, headers: &[(&'static str, &'static str)]) -> ProxyRequest {
+        let mut req = Request::builder().uri("/").body(full_body(Bytes::new())).unwrap();
+        for (name, value) in headers {
+            req.headers_mut().insert(*name, value.parse().unwrap());
+        }
+        req.extensions_mut().insert(ClientAddr(client.parse().unwrap()));
+        req
+    }
+
+    #[test]
+    fn test_only_trusted_clients_can_force_a_backend() {
+        let config = ForceBackend::default();
+
+        let mut req = request("127.0.0.1:5000", &[(DEFAULT_FORCE_BACKEND_HEADER, "2")]);
+        assert_eq!(config.take_requested(&mut req, "/").unwrap(), Some(BackendId(2)));
+        assert!(req.headers().get(DEFAULT_FORCE_BACKEND_HEADER).is_none());
+
+        let mut req = request("203.0.113.9:5000", &[(DEFAULT_FORCE_BACKEND_HEADER, "2")]);
+        assert_eq!(config.take_requested(&mut req, "/").unwrap(), None);
+        assert!(req.headers().get(DEFAULT_FORCE_BACKEND_HEADER).is_none());
+
+        let mut req = request("127.0.0.1:5000", &[(DEFAULT_FORCE_BACKEND_HEADER, "two")]);
+        assert_eq!(config.take_requested(&mut req, "/").unwrap_err().kind(), "invalid_request");
+    }
+
+    #[test]
+    fn test_token_is_required_when_configured() {
+        let config = ForceBackend { token: Some("s3cret".into()), ..ForceBackend::default() };
+
+        let mut req = request("127.0.0.1:5000", &[(DEFAULT_FORCE_BACKEND_HEADER, "2")]);
+        assert_eq!(config.take_requested(&mut req, "/").unwrap(), None);
+
+        let mut req = request(
+            "127.0.0.1:5000",
+            &[(DEFAULT_FORCE_BACKEND_HEADER, "2"), (DEFAULT_DEBUG_TOKEN_HEADER, "s3cret")],
+        );
+        assert_eq!(config.take_requested(&mut req, "/").unwrap(), Some(BackendId(2)));
+        assert!(req.headers().get(DEFAULT_DEBUG_TOKEN_HEADER).is_none());
+    }
+}
@@ -0,0 +1,86 @@
+//! Limits on header size and count, enforced in both directions.
+//!
+//! hyper has its own (connection-level, version-specific) parsing limits;
+//! these are the proxy's policy on top, the same for HTTP/1 and HTTP/2 and
+//! applied to upstream responses as well, so an oversized cookie jar or a
+//! misbehaving backend is rejected consistently.
+
+use hyper::header::HeaderMap;
+
+/// Maximum header sizes and count for a listener.
+///
+/// Sizes count each header's name plus value, without separators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderLimits {
+    /// All headers of one message together
+    pub max_total_bytes: usize,
+    /// Any single header
+    pub max_header_bytes: usize,
+    /// Number of header fields (repeated names count once per field)
+    pub max_count: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_total_bytes: 64 * 1024,
+            max_header_bytes: 16 * 1024,
+            max_count: 100,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Check `headers` against the limits, describing the first violation.
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), String> {
+        if headers.len() > self.max_count {
+            return Err(format!("{} headers exceed the limit of {}", headers.len(), self.max_count));
+        }
+        let mut total = 0;
+        for (name, value) in headers {
+            let size = name.as_str().len() + value.len();
+            if size > self.max_header_bytes {
+                return Err(format!("header {} is {} bytes, over the limit of {}", name, size, self.max_header_bytes));
+            }
+            total += size;
+        }
+        if total > self.max_total_bytes {
+            return Err(format!("headers total {} bytes, over the limit of {}", total, self.max_total_bytes));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_limit_is_enforced() {
+        let limits = HeaderLimits {
+            max_total_bytes: 64,
+            max_header_bytes: 32,
+            max_count: 4,
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "example.com".parse().unwrap());
+        headers.append("accept", "*/*".parse().unwrap());
+        assert!(limits.check(&headers).is_ok());
+
+        let mut big = headers.clone();
+        big.insert("cookie", "x".repeat(40).parse().unwrap());
+        assert!(limits.check(&big).unwrap_err().contains("header cookie"));
+
+        let mut many = headers.clone();
+        many.append("accept", "text/html".parse().unwrap());
+        many.append("accept", "text/plain".parse().unwrap());
+        many.append("accept", "text/css".parse().unwrap());
+        assert!(limits.check(&many).unwrap_err().contains("5 headers"));
+
+        let mut total = headers;
+        total.insert("x-a", "a".repeat(25).parse().unwrap());
+        total.insert("x-b", "b".repeat(25).parse().unwrap());
+        assert!(limits.check(&total).unwrap_err().contains("total"));
+    }
+}
@@ -1,47 +1,807 @@
-//! Background prober for active TCP health checks.
+//! Background prober for active health checks.
+//!
+//! Every pool carries its own `HealthCheckConfig`: how often, how long to
+//! wait, what a probe does (a TCP connect, an HTTP `GET` or a gRPC health
+//! check) and how many consecutive results flip a backend's health, since a
+//! database, a gRPC service and a static file server want very different
+//! probing.
+//!
+//! Each sweep probes every backend concurrently, at most
+//! `HealthCheckConfig::max_concurrent_probes` at a time, with start times
+//! spread evenly across the interval so a large pool doesn't open hundreds of
+//! connections at once. A sweep that still overruns the interval delays the
+//! next one rather than overlapping it; `vortex_health_check_sweep_seconds`
+//! shows how close sweeps come. A sweep keeps an eye on the routing table's
+//! generation, so probes of backends removed meanwhile are abandoned at once
+//! instead of flipping the health of something no longer in the pool.
+//!
+//! The `HealthChecker` a checker is spawned with pauses, resumes, retimes or
+//! stops it while it runs, e.g. from the admin API during a maintenance
+//! window; `HealthCheckers` does so for the checkers of several named pools,
+//! one pool or all of them at once.
+//!
+//! `OnDemandProber` runs the same check against a single backend when the
+//! admin API asks for it, instead of waiting for the next interval.
 
-use std::time::Duration;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HOST, TE, USER_AGENT};
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use prometheus::Histogram;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::time;
 
-use vortex_core::domain::routing::SharedRoutingTable;
+use vortex_core::config::{HealthCheckSpec, HealthCheckType};
+use vortex_core::domain::backend::{Backend, BackendId, SharedBackend};
+use vortex_core::domain::routing::{RoutingTable, SharedRoutingTable};
+use vortex_core::stats::{BackendProber, HealthCheckControl, HealthCheckStatus, ProbeResult};
+
+/// How long a probe waits for the TCP connection.
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// How often a sweep checks whether the routing table changed under it.
+const GENERATION_POLL: Duration = Duration::from_millis(100);
+
+/// Time to probe every backend once
+static SWEEP_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    prometheus::register_histogram!(
+        "vortex_health_check_sweep_seconds",
+        "Time taken to probe every backend once",
+        vec![0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .expect("metric registers once")
+});
+
+/// What a probe does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheckKind {
+    /// Pass if a TCP connection opens
+    Tcp,
+    /// Pass if `GET path` over HTTP/1.1 answers with a 2xx status
+    Http {
+        /// The request path
+        path: String,
+    },
+    /// Pass if `grpc.health.v1.Health/Check` reports `SERVING`
+    Grpc {
+        /// The service asked about; empty for the server as a whole
+        service: String,
+    },
+}
+
+/// How one pool's backends are probed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    /// Time between sweeps
+    pub interval: Duration,
+    /// How long one probe may take before it counts as failed
+    pub timeout: Duration,
+    /// What a probe does
+    pub kind: HealthCheckKind,
+    /// Consecutive passing probes that mark an unhealthy backend healthy
+    pub healthy_threshold: u32,
+    /// Consecutive failing probes that mark a healthy backend unhealthy
+    pub unhealthy_threshold: u32,
+    /// Probes that may be in flight at once
+    pub max_concurrent_probes: usize,
+    /// Start probes evenly across the interval instead of all at once
+    pub spread: bool,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: PROBE_TIMEOUT,
+            kind: HealthCheckKind::Tcp,
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+            max_concurrent_probes: 16,
+            spread: true,
+        }
+    }
+}
+
+impl From<&HealthCheckSpec> for HealthCheckConfig {
+    fn from(spec: &HealthCheckSpec) -> Self {
+        let defaults = Self::default();
+        Self {
+            interval: spec.interval_ms.map_or(defaults.interval, Duration::from_millis),
+            timeout: spec.timeout_ms.map_or(defaults.timeout, Duration::from_millis),
+            kind: match spec.kind {
+                HealthCheckType::Tcp => HealthCheckKind::Tcp,
+                HealthCheckType::Http => HealthCheckKind::Http {
+                    path: spec.path.clone().unwrap_or_else(|| "/".to_string()),
+                },
+                HealthCheckType::Grpc => HealthCheckKind::Grpc {
+                    service: spec.service.clone().unwrap_or_default(),
+                },
+            },
+            healthy_threshold: spec.healthy_threshold.unwrap_or(defaults.healthy_threshold),
+            unhealthy_threshold: spec.unhealthy_threshold.unwrap_or(defaults.unhealthy_threshold),
+            max_concurrent_probes: spec.max_concurrent_probes.unwrap_or(defaults.max_concurrent_probes),
+            ..defaults
+        }
+    }
+}
+
+/// Whether a TCP connection to `addr` succeeds within `timeout`.
+pub async fn probe(addr: SocketAddr, timeout: Duration) -> bool {
+    matches!(time::timeout(timeout, TcpStream::connect(addr)).await, Ok(Ok(_stream)))
+}
+
+/// Runs one `kind` probe against `addr`, failing with the reason if it
+/// doesn't pass within `timeout`.
+pub async fn check(addr: SocketAddr, kind: &HealthCheckKind, timeout: Duration) -> Result<(), String> {
+    check_named(addr, None, kind, timeout).await
+}
+
+/// Like `check`, with HTTP and gRPC probes naming `host` rather than the
+/// address, for backends sharing an address behind a name-based ingress.
+pub async fn check_named(
+    addr: SocketAddr,
+    host: Option<&str>,
+    kind: &HealthCheckKind,
+    timeout: Duration,
+) -> Result<(), String> {
+    let authority = host.map_or_else(|| addr.to_string(), str::to_string);
+    let attempt = async {
+        match kind {
+            HealthCheckKind::Tcp => TcpStream::connect(addr).await.map(drop).map_err(|e| e.to_string()),
+            HealthCheckKind::Http { path } => check_http(addr, &authority, path).await,
+            HealthCheckKind::Grpc { service } => check_grpc(addr, &authority, service).await,
+        }
+    };
+    match time::timeout(timeout, attempt).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no answer within {:?}", timeout)),
+    }
+}
+
+async fn check_http(addr: SocketAddr, authority: &str, path: &str) -> Result<(), String> {
+    let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let request = Request::get(path)
+        .header(HOST, authority)
+        .header(USER_AGENT, "vortex-health-check")
+        .body(Empty::<Bytes>::new())
+        .map_err(|e| e.to_string())?;
+    let response = sender.send_request(request).await.map_err(|e| e.to_string())?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("status {}", status.as_u16())),
+    }
+}
+
+async fn check_grpc(addr: SocketAddr, authority: &str, service: &str) -> Result<(), String> {
+    let stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+    let (mut sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    let request = Request::post(format!("http://{}/grpc.health.v1.Health/Check", authority))
+        .header(CONTENT_TYPE, "application/grpc")
+        .header(TE, "trailers")
+        .body(Full::new(grpc_health_request(service)))
+        .map_err(|e| e.to_string())?;
+    let response = sender.send_request(request).await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status().as_u16()));
+    }
+    // A failed call may answer with headers only
+    let headers_status = response.headers().get("grpc-status").cloned();
+    let body = response.into_body().collect().await.map_err(|e| e.to_string())?;
+    let status = headers_status.or_else(|| body.trailers().and_then(|t| t.get("grpc-status")).cloned());
+    match status.as_ref().map(|s| s.to_str().unwrap_or("?")) {
+        Some("0") => grpc_serving(&body.to_bytes()),
+        Some(code) => Err(format!("grpc-status {}", code)),
+        None => Err("no grpc-status".to_string()),
+    }
+}
+
+/// A length-prefixed `HealthCheckRequest { service }` message.
+fn grpc_health_request(service: &str) -> Bytes {
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        // Field 1, length-delimited
+        message.push(0x0a);
+        let mut len = service.len() as u64;
+        while len >= 0x80 {
+            message.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        message.push(len as u8);
+        message.extend_from_slice(service.as_bytes());
+    }
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend(message);
+    frame.into()
+}
+
+/// Whether a length-prefixed `HealthCheckResponse` says `SERVING`.
+fn grpc_serving(frame: &[u8]) -> Result<(), String> {
+    let malformed = || "malformed health response".to_string();
+    let (&[0, a, b, c, d], rest) = frame.split_first_chunk::<5>().ok_or_else(malformed)? else {
+        return Err("compressed health response".to_string());
+    };
+    let mut message = rest.get(..u32::from_be_bytes([a, b, c, d]) as usize).ok_or_else(malformed)?;
+    // Status is field 1; an absent field is UNKNOWN
+    let mut status = 0;
+    while !message.is_empty() {
+        let tag = read_varint(&mut message).ok_or_else(malformed)?;
+        match tag & 7 {
+            0 => {
+                let value = read_varint(&mut message).ok_or_else(malformed)?;
+                if tag >> 3 == 1 {
+                    status = value;
+                }
+            }
+            1 | 5 => {
+                let width = if tag & 7 == 1 { 8 } else { 4 };
+                message = message.get(width..).ok_or_else(malformed)?;
+            }
+            2 => {
+                let len = read_varint(&mut message).ok_or_else(malformed)? as usize;
+                message = message.get(len..).ok_or_else(malformed)?;
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    match status {
+        1 => Ok(()),
+        2 => Err("NOT_SERVING".to_string()),
+        3 => Err("SERVICE_UNKNOWN".to_string()),
+        _ => Err("UNKNOWN".to_string()),
+    }
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Records a probe result on `backend`, logging when its health flips.
+fn apply(backend: &Backend, is_healthy: bool, error: Option<&str>) {
+    if is_healthy != backend.is_healthy() {
+        tracing::warn!(
+            backend = backend.id.0,
+            addr = %backend.addr,
+            healthy = is_healthy,
+            error,
+            "backend health changed"
+        );
+        backend.set_healthy(is_healthy);
+    }
+}
+
+/// Consecutive passing or failing probes of each backend, checked against
+/// the thresholds.
+#[derive(Debug, Default)]
+struct Streaks(HashMap<BackendId, (bool, u32)>);
+
+impl Streaks {
+    /// Counts one probe result, returning whether the backend should now be
+    /// healthy.
+    fn record(&mut self, backend: &Backend, passed: bool, config: &HealthCheckConfig) -> bool {
+        let streak = self.0.entry(backend.id).or_insert((passed, 0));
+        if streak.0 != passed {
+            *streak = (passed, 0);
+        }
+        streak.1 = streak.1.saturating_add(1);
+        let threshold = if passed { config.healthy_threshold } else { config.unhealthy_threshold };
+        if streak.1 >= threshold {
+            passed
+        } else {
+            backend.is_healthy()
+        }
+    }
+
+    /// Forgets backends no longer in the pool.
+    fn retain(&mut self, backends: &[SharedBackend]) {
+        self.0.retain(|id, _| backends.iter().any(|b| b.id == *id));
+    }
+}
+
+/// Controls a running health checker; clones control the same one.
+#[derive(Debug, Clone)]
+pub struct HealthChecker {
+    schedule: Arc<watch::Sender<HealthCheckStatus>>,
+}
+
+impl HealthChecker {
+    fn new(interval: Duration) -> Self {
+        let status = HealthCheckStatus { interval, paused: false, stopped: false };
+        Self { schedule: Arc::new(watch::Sender::new(status)) }
+    }
+
+    /// Skips sweeps until resumed; backends keep the health they have.
+    pub fn pause(&self) {
+        self.schedule.send_if_modified(|s| !std::mem::replace(&mut s.paused, true));
+    }
+
+    /// Resumes paused sweeps.
+    pub fn resume(&self) {
+        self.schedule.send_if_modified(|s| std::mem::replace(&mut s.paused, false));
+    }
+
+    /// Changes the time between sweeps, starting from now.
+    pub fn set_interval(&self, interval: Duration) {
+        // A zero interval would sweep back to back
+        let interval = interval.max(Duration::from_millis(1));
+        self.schedule.send_modify(|s| s.interval = interval);
+    }
+
+    /// Stops the checks for good, abandoning a sweep in progress.
+    pub fn stop(&self) {
+        self.schedule.send_if_modified(|s| !std::mem::replace(&mut s.stopped, true));
+    }
+
+    /// What the checks are doing now.
+    pub fn status(&self) -> HealthCheckStatus {
+        *self.schedule.borrow()
+    }
+}
+
+/// The name the default pool's checker goes by among `HealthCheckers`;
+/// clusters go by their own names.
+pub const DEFAULT_POOL: &str = "default";
+
+/// Controls the health checkers of several pools by name, e.g. the default
+/// pool's and those of the clusters.
+#[derive(Debug, Clone, Default)]
+pub struct HealthCheckers(Vec<(String, HealthChecker)>);
+
+impl HealthCheckers {
+    /// Adds the checker of the pool named `pool`.
+    pub fn push(&mut self, pool: impl Into<String>, checker: HealthChecker) {
+        self.0.push((pool.into(), checker));
+    }
+
+    /// Whether no pool has health checks.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The checkers of `pool`, or of every pool if `None`.
+    fn matching<'a>(&'a self, pool: Option<&'a str>) -> impl Iterator<Item = &'a HealthChecker> {
+        self.0.iter().filter(move |(name, _)| pool.is_none_or(|pool| pool == name)).map(|(_, checker)| checker)
+    }
+}
+
+impl HealthCheckControl for HealthCheckers {
+    fn pause(&self, pool: Option<&str>) {
+        self.matching(pool).for_each(HealthChecker::pause);
+    }
+
+    fn resume(&self, pool: Option<&str>) {
+        self.matching(pool).for_each(HealthChecker::resume);
+    }
+
+    fn set_interval(&self, pool: &str, interval: Duration) {
+        self.matching(Some(pool)).for_each(|checker| checker.set_interval(interval));
+    }
+
+    fn stop(&self, pool: Option<&str>) {
+        self.matching(pool).for_each(HealthChecker::stop);
+    }
+
+    fn status(&self) -> Vec<(String, HealthCheckStatus)> {
+        self.0.iter().map(|(name, checker)| (name.clone(), checker.status())).collect()
+    }
+}
 
 /// Spawns a background Tokio task that periodically probes a list of backends
 /// and updates their internal atomic health state.
-pub fn spawn_health_checker(routing_table: SharedRoutingTable, interval_ms: u64) {
-    let check_interval = Duration::from_millis(interval_ms);
+pub fn spawn_health_checker(routing_table: SharedRoutingTable, interval_ms: u64) -> (HealthChecker, JoinHandle<()>) {
+    spawn_health_checker_with(
+        routing_table,
+        HealthCheckConfig {
+            interval: Duration::from_millis(interval_ms),
+            ..HealthCheckConfig::default()
+        },
+    )
+}
 
-    tokio::spawn(async move {
-        let mut interval = time::interval(check_interval);
-
-        // Prevent immediately ticking when spawned
-        interval.tick().await;
-
-        loop {
-            interval.tick().await;
-
-            let backends = routing_table.snapshot();
-            for backend in backends.iter() {
-                // Perform a simple and fast TCP connect to check health
-                // In Phase 3, we can extend this to L7 HTTP probes or gRPC Ping checks
-                let is_healthy = match time::timeout(
-                    Duration::from_millis(1500),
-                    TcpStream::connect(backend.addr)
-                ).await {
-                    Ok(Ok(_stream)) => true, // Successfully connected
-                    _ => false,              // Timeout or Connection Refused
-                };
-
-                let was_healthy = backend.is_healthy();
-
-                if is_healthy != was_healthy {
-                    println!(
-                        "[HEALTH-CHECK] Backend {} ({}) state changed: {} -> {}",
-                        backend.id.0, backend.addr, was_healthy, is_healthy
-                    );
-                    backend.set_healthy(is_healthy);
+/// Like `spawn_health_checker`, with the pool's own probe type, timeout,
+/// thresholds and concurrency.
+pub fn spawn_health_checker_with(
+    routing_table: SharedRoutingTable,
+    config: HealthCheckConfig,
+) -> (HealthChecker, JoinHandle<()>) {
+    let checker = HealthChecker::new(config.interval);
+    let (kind, timeout) = (Arc::new(config.kind.clone()), config.timeout);
+    let task = tokio::spawn(run(routing_table, config, checker.clone(), move |backend| {
+        let (kind, addr, host) = (kind.clone(), backend.addr, backend.host.clone());
+        async move { check_named(addr, host.as_deref(), &kind, timeout).await }
+    }));
+    (checker, task)
+}
+
+/// Sweeps `routing_table` on the schedule `checker` sets, until it stops.
+async fn run<F, Fut>(routing_table: SharedRoutingTable, config: HealthCheckConfig, checker: HealthChecker, probe: F)
+where
+    F: Fn(&Backend) -> Fut,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let mut schedule = checker.schedule.subscribe();
+    let mut config = config;
+    let mut interval = ticker(config.interval);
+    let mut streaks = Streaks::default();
+
+    loop {
+        let status = *schedule.borrow_and_update();
+        if status.stopped {
+            tracing::info!("health checks stopped");
+            return;
+        }
+        if status.interval != config.interval {
+            config.interval = status.interval;
+            interval = ticker(config.interval);
+        }
+        // Any change of schedule is looked at afresh; the checker holds the
+        // sender, so `changed` only fails if it's gone too
+        if status.paused {
+            let _ = schedule.changed().await;
+            continue;
+        }
+        tokio::select! {
+            _ = schedule.changed() => continue,
+            _ = interval.tick() => {}
+        }
+
+        let started = Instant::now();
+        streaks.retain(&routing_table.snapshot());
+        tokio::select! {
+            _ = sweep(&routing_table, &config, &mut streaks, &probe) => {
+                SWEEP_SECONDS.observe(started.elapsed().as_secs_f64());
+            }
+            _ = schedule.wait_for(|s| s.stopped) => {}
+        }
+    }
+}
+
+/// A sweep every `period`, the first one `period` from now.
+fn ticker(period: Duration) -> time::Interval {
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    interval
+}
+
+/// Probes every backend in `routing_table` once, returning when all probes
+/// are done. Probes of backends the table drops meanwhile are abandoned.
+async fn sweep<F, Fut>(routing_table: &RoutingTable, config: &HealthCheckConfig, streaks: &mut Streaks, probe: F)
+where
+    F: Fn(&Backend) -> Fut,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(config.max_concurrent_probes.max(1)));
+    let start = time::Instant::now();
+    let mut generation = routing_table.generation();
+    let backends = routing_table.snapshot();
+    let count = backends.len() as u32;
+    let mut probes = JoinSet::new();
+    let mut in_flight = HashMap::new();
+    for (i, backend) in (0..).zip(backends.iter().cloned()) {
+        let offset = if config.spread { config.interval * i / count } else { Duration::ZERO };
+        let permits = permits.clone();
+        let result = probe(&backend);
+        let id = backend.id;
+        let handle = probes.spawn(async move {
+            time::sleep_until(start + offset).await;
+            let _permit = permits.acquire_owned().await.expect("the semaphore is never closed");
+            (backend, result.await)
+        });
+        in_flight.insert(id, handle);
+    }
+
+    let mut poll = time::interval(GENERATION_POLL);
+    poll.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        let done = tokio::select! {
+            done = probes.join_next() => match done {
+                Some(done) => Some(done),
+                None => break,
+            },
+            _ = poll.tick() => None,
+        };
+        if routing_table.generation() != generation {
+            generation = routing_table.generation();
+            let current = routing_table.snapshot();
+            in_flight.retain(|id, handle| {
+                let kept = current.iter().any(|b| b.id == *id);
+                if !kept {
+                    tracing::debug!(backend = id.0, "backend removed mid-sweep; probe abandoned");
+                    handle.abort();
                 }
+                kept
+            });
+        }
+        let Some(Ok((backend, result))) = done else { continue };
+        // Gone if its backend was removed after the probe finished
+        if in_flight.remove(&backend.id).is_none() {
+            continue;
+        }
+        let healthy = streaks.record(&backend, result.is_ok(), config);
+        apply(&backend, healthy, result.err().as_deref());
+    }
+}
+
+/// Probes single backends on demand for the admin API.
+pub struct OnDemandProber {
+    pools: Vec<(SharedRoutingTable, Option<HealthCheckConfig>)>,
+}
+
+impl OnDemandProber {
+    /// Probes backends in `routing_table` with the pool's `check`. When
+    /// there is one (i.e. periodic health checks run), the result is applied
+    /// to the backend at once, regardless of thresholds; otherwise a TCP
+    /// connect is only reported.
+    pub fn new(routing_table: SharedRoutingTable, check: Option<HealthCheckConfig>) -> Self {
+        Self { pools: vec![(routing_table, check)] }
+    }
+
+    /// Also probes backends in `routing_table`, e.g. a cluster's pool, with its own `check`.
+    pub fn with_pool(mut self, routing_table: SharedRoutingTable, check: Option<HealthCheckConfig>) -> Self {
+        self.pools.push((routing_table, check));
+        self
+    }
+}
+
+impl BackendProber for OnDemandProber {
+    fn probe(&self, id: BackendId) -> Pin<Box<dyn Future<Output = Option<ProbeResult>> + Send + '_>> {
+        Box::pin(async move {
+            let (backend, check) =
+                self.pools.iter().find_map(|(table, check)| Some((table.backend(id)?, check)))?;
+            let was_healthy = backend.is_healthy();
+            let (kind, timeout) = match check {
+                Some(check) => (&check.kind, check.timeout),
+                None => (&HealthCheckKind::Tcp, PROBE_TIMEOUT),
+            };
+            let started = Instant::now();
+            let result = check_named(backend.addr, backend.host.as_deref(), kind, timeout).await;
+            let connect_time = started.elapsed();
+            if check.is_some() {
+                apply(&backend, result.is_ok(), result.as_ref().err().map(String::as_str));
             }
+            Some(ProbeResult {
+                backend: id,
+                addr: backend.addr,
+                healthy: result.is_ok(),
+                was_healthy,
+                connect_time,
+                error: result.err(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vortex_core::domain::routing::RoutingTable;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sweep_bounds_concurrency_and_spreads_probes() {
+        let backends: Vec<SharedBackend> = (0..8)
+            .map(|i| Arc::new(Backend::new(BackendId(i), SocketAddr::from(([127, 0, 0, 1], 9000 + i as u16)))))
+            .collect();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let started = time::Instant::now();
+        let probe = |backend: &Backend| {
+            let (in_flight, peak, addr) = (in_flight.clone(), peak.clone(), backend.addr);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                time::sleep(Duration::from_millis(500)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                if addr.port().is_multiple_of(2) { Ok(()) } else { Err("odd port".to_string()) }
+            }
+        };
+
+        let bursty = HealthCheckConfig {
+            interval: Duration::from_secs(8),
+            max_concurrent_probes: 3,
+            spread: false,
+            ..HealthCheckConfig::default()
+        };
+        let mut streaks = Streaks::default();
+        sweep(&RoutingTable::new(backends.clone()), &bursty, &mut streaks, probe).await;
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        // 8 half-second probes, 3 at a time
+        assert_eq!(started.elapsed(), Duration::from_millis(1500));
+        assert!(backends[0].is_healthy() && !backends[1].is_healthy());
+
+        // Spread one second apart, the probes never overlap
+        peak.store(0, Ordering::SeqCst);
+        let spread = HealthCheckConfig { spread: true, ..bursty };
+        let started = time::Instant::now();
+        sweep(&RoutingTable::new(backends), &spread, &mut streaks, probe).await;
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+        assert_eq!(started.elapsed(), Duration::from_millis(7500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sweep_abandons_probes_of_removed_backends() {
+        let backend = |id: u16| Arc::new(Backend::new(BackendId(id.into()), SocketAddr::from(([127, 0, 0, 1], 9000 + id))));
+        let (kept, removed) = (backend(1), backend(2));
+        let routing_table = Arc::new(RoutingTable::new(vec![kept.clone(), removed.clone()]));
+        // Every probe fails after a second
+        let probe = |_: &Backend| async {
+            time::sleep(Duration::from_secs(1)).await;
+            Err("down".to_string())
+        };
+        let table = routing_table.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(500)).await;
+            table.update_backends(vec![kept]);
+        });
+
+        let config = HealthCheckConfig { spread: false, ..HealthCheckConfig::default() };
+        sweep(&routing_table, &config, &mut Streaks::default(), probe).await;
+        assert!(!routing_table.backend(BackendId(1)).unwrap().is_healthy());
+        assert!(removed.is_healthy());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_checker_pauses_resumes_retimes_and_stops() {
+        let backends = vec![Arc::new(Backend::new(BackendId(1), SocketAddr::from(([127, 0, 0, 1], 9000))))];
+        let routing_table = Arc::new(RoutingTable::new(backends));
+        let probes = Arc::new(AtomicUsize::new(0));
+        let counted = probes.clone();
+        let probe = move |_: &Backend| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async { Ok(()) }
+        };
+        let config = HealthCheckConfig { interval: Duration::from_secs(1), spread: false, ..HealthCheckConfig::default() };
+        let checker = HealthChecker::new(config.interval);
+        let task = tokio::spawn(run(routing_table, config, checker.clone(), probe));
+
+        time::sleep(Duration::from_millis(3500)).await;
+        assert_eq!(probes.load(Ordering::SeqCst), 3);
+        checker.pause();
+        time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(probes.load(Ordering::SeqCst), 3);
+        assert!(checker.status().paused);
+
+        checker.set_interval(Duration::from_millis(100));
+        checker.resume();
+        time::sleep(Duration::from_millis(1050)).await;
+        assert_eq!(probes.load(Ordering::SeqCst), 13);
+
+        checker.stop();
+        task.await.unwrap();
+        let status = checker.status();
+        assert_eq!(status, HealthCheckStatus { interval: Duration::from_millis(100), paused: false, stopped: true });
+    }
+
+    #[test]
+    fn test_checkers_act_on_the_named_pool() {
+        let mut checkers = HealthCheckers::default();
+        checkers.push(DEFAULT_POOL, HealthChecker::new(Duration::from_secs(5)));
+        checkers.push("reports", HealthChecker::new(Duration::from_secs(30)));
+
+        checkers.set_interval("reports", Duration::from_secs(1));
+        checkers.pause(Some("reports"));
+        let status = checkers.status();
+        assert_eq!(status[0], (DEFAULT_POOL.to_string(), HealthCheckStatus {
+            interval: Duration::from_secs(5),
+            paused: false,
+            stopped: false,
+        }));
+        assert_eq!(status[1], ("reports".to_string(), HealthCheckStatus {
+            interval: Duration::from_secs(1),
+            paused: true,
+            stopped: false,
+        }));
+
+        checkers.stop(None);
+        assert!(checkers.status().iter().all(|(_, status)| status.stopped));
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_probe_reports_and_applies_result() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let up = listener.local_addr().unwrap();
+        let down = {
+            let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            closed.local_addr().unwrap()
+        };
+        let routing_table = Arc::new(RoutingTable::new(vec![
+            Arc::new(Backend::new(BackendId(1), up)),
+            Arc::new(Backend::new(BackendId(2), down)),
+        ]));
+        let cluster = Arc::new(RoutingTable::new(vec![Arc::new(Backend::new(BackendId(4), up))]));
+        let prober = OnDemandProber::new(routing_table.clone(), Some(HealthCheckConfig::default()))
+            .with_pool(cluster, None);
+
+        let result = prober.probe(BackendId(1)).await.unwrap();
+        assert!(result.healthy && result.was_healthy && result.error.is_none());
+        assert_eq!(result.addr, up);
+
+        let result = prober.probe(BackendId(2)).await.unwrap();
+        assert!(!result.healthy && result.was_healthy && result.error.is_some());
+        assert!(!routing_table.backend(BackendId(2)).unwrap().is_healthy());
+
+        assert!(prober.probe(BackendId(3)).await.is_none());
+        // Cluster pools are probed too
+        assert!(prober.probe(BackendId(4)).await.unwrap().healthy);
+    }
+
+    #[test]
+    fn test_thresholds_need_consecutive_results() {
+        let backend = Backend::new(BackendId(1), SocketAddr::from(([127, 0, 0, 1], 9000)));
+        let config = HealthCheckConfig { healthy_threshold: 2, unhealthy_threshold: 3, ..HealthCheckConfig::default() };
+        let mut streaks = Streaks::default();
+        let mut probe = |passed| {
+            let healthy = streaks.record(&backend, passed, &config);
+            apply(&backend, healthy, None);
+            healthy
+        };
+        // A pass resets the failure streak
+        assert_eq!([false, false, true, false, false].map(&mut probe), [true; 5]);
+        assert!(!probe(false));
+        assert_eq!([true, false, true].map(&mut probe), [false; 3]);
+        assert!(probe(true));
+    }
+
+    #[tokio::test]
+    async fn test_http_check_needs_a_2xx_status() {
+        async fn answer(status_line: &'static str) -> SocketAddr {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let n = stream.read(&mut request).await.unwrap();
+                assert!(request[..n].starts_with(b"GET /healthz HTTP/1.1"));
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status_line);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+            addr
         }
-    });
+        let kind = HealthCheckKind::Http { path: "/healthz".to_string() };
+        assert_eq!(check(answer("204 No Content").await, &kind, PROBE_TIMEOUT).await, Ok(()));
+        let err = check(answer("503 Service Unavailable").await, &kind, PROBE_TIMEOUT).await;
+        assert_eq!(err, Err("status 503".to_string()));
+    }
+
+    #[test]
+    fn test_grpc_health_messages() {
+        assert_eq!(&grpc_health_request("")[..], [0, 0, 0, 0, 0]);
+        assert_eq!(&grpc_health_request("db")[..], [0, 0, 0, 0, 4, 0x0a, 2, b'd', b'b']);
+        assert_eq!(grpc_serving(&[0, 0, 0, 0, 2, 0x08, 1]), Ok(()));
+        assert_eq!(grpc_serving(&[0, 0, 0, 0, 2, 0x08, 2]), Err("NOT_SERVING".to_string()));
+        // Unknown fields are skipped, and a missing status is UNKNOWN
+        assert_eq!(grpc_serving(&[0, 0, 0, 0, 3, 0x12, 1, b'x']), Err("UNKNOWN".to_string()));
+        assert!(grpc_serving(&[0, 0, 0, 0, 2, 0x08]).is_err());
+    }
 }
@@ -0,0 +1,257 @@
+//! Request hedging for tail latency.
+//!
+//! When a safe request has not been answered within the hedge delay, a second
+//! copy is sent to the next best backend and whichever answers first wins.
+//! The losing attempt is cancelled by dropping it: its upstream connection is
+//! mid-exchange, so the upstream stage retires it instead of returning it to
+//! the pool (see `vortex_upstream_requests_cancelled_total`).
+
+use http_body_util::BodyExt;
+use hyper::body::Body;
+use hyper::{Method, Request};
+use prometheus::IntCounterVec;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service, ServiceExt};
+use vortex_core::domain::routing::SharedRoutingTable;
+
+use crate::error::ProxyError;
+use crate::force_backend::ForcedBackend;
+use crate::pipeline::{buffered_body, select_backend, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+use crate::retry::clone_parts;
+
+/// Hedge lifecycle events: `issued`, `won` (the hedge answered first), and
+/// `cancelled` (an attempt was dropped because the other one won).
+static HEDGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_hedges_total",
+        "Hedged upstream requests, by event",
+        &["event"]
+    )
+    .expect("metric registers once")
+});
+
+/// When to hedge a slow request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HedgePolicy {
+    /// How long the first attempt may run before a hedge is sent
+    pub delay: Duration,
+    /// Larger (or streamed) bodies are not buffered, so their requests are never hedged
+    pub max_body_bytes: u64,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(100),
+            max_body_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Only safe methods are hedged: two copies of even an idempotent write may
+/// race each other on different backends.
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Hedges slow safe requests per `HedgePolicy`.
+#[derive(Debug, Clone)]
+pub struct HedgeLayer {
+    policy: Arc<HedgePolicy>,
+    routing_table: SharedRoutingTable,
+}
+
+impl HedgeLayer {
+    /// Create a hedging layer picking hedge backends from `routing_table`.
+    pub fn new(policy: HedgePolicy, routing_table: SharedRoutingTable) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            routing_table,
+        }
+    }
+}
+
+impl<S> Layer<S> for HedgeLayer {
+    type Service = HedgeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HedgeService {
+            inner,
+            policy: self.policy.clone(),
+            routing_table: self.routing_table.clone(),
+        }
+    }
+}
+
+/// Service produced by `HedgeLayer`.
+#[derive(Debug, Clone)]
+pub struct HedgeService<S> {
+    inner: S,
+    policy: Arc<HedgePolicy>,
+    routing_table: SharedRoutingTable,
+}
+
+impl<S> Service<ProxyRequest> for HedgeService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let replayable = req
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|len| len <= self.policy.max_body_bytes);
+        let routed = req.extensions().get::<RouteContext>().is_some();
+        let forced = req.extensions().get::<ForcedBackend>().is_some();
+        if !is_safe(req.method()) || !replayable || !routed || forced {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let routing_table = self.routing_table.clone();
+        Box::pin(hedge_request(req, inner, policy, routing_table))
+    }
+}
+
+async fn hedge_request<S>(
+    req: ProxyRequest,
+    inner: S,
+    policy: Arc<HedgePolicy>,
+    routing_table: SharedRoutingTable,
+) -> Result<ProxyResponse, ProxyError>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone,
+{
+    let (parts, body) = req.into_parts();
+    let context = parts.extensions.get::<RouteContext>().cloned().expect("checked by the caller");
+    let body = body.collect().await.map_err(|e| ProxyError::InvalidRequest {
+        route: context.route.clone(),
+        reason: format!("failed to read request body: {}", e),
+    })?;
+    let trailers = body.trailers().cloned();
+    let body = body.to_bytes();
+
+    let primary = inner
+        .clone()
+        .oneshot(Request::from_parts(clone_parts(&parts), buffered_body(body.clone(), trailers.clone())));
+    tokio::pin!(primary);
+    tokio::select! {
+        result = &mut primary => return result,
+        _ = tokio::time::sleep(policy.delay) => {}
+    }
+
+    let Some(backend) = select_backend(&routing_table, &parts.extensions, &[context.backend.id]) else {
+        return primary.await;
+    };
+    let mut req = Request::from_parts(clone_parts(&parts), buffered_body(body, trailers));
+    req.extensions_mut().insert(RouteContext { backend, ..context });
+    let hedge = inner.oneshot(req);
+    tokio::pin!(hedge);
+    HEDGES.with_label_values(&["issued"]).inc();
+
+    // The first success wins; a failure waits for the other attempt instead
+    tokio::select! {
+        result = &mut primary => match result {
+            Ok(res) => {
+                HEDGES.with_label_values(&["cancelled"]).inc();
+                Ok(res)
+            }
+            Err(_) => hedge.await.inspect(|_| HEDGES.with_label_values(&["won"]).inc()),
+        },
+        result = &mut hedge => match result {
+            Ok(res) => {
+                HEDGES.with_label_values(&["won"]).inc();
+                HEDGES.with_label_values(&["cancelled"]).inc();
+                Ok(res)
+            }
+            Err(_) => primary.await,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use crate::pipeline::local_response;
+    use hyper::body::Bytes;
+    use hyper::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::routing::RoutingTable;
+
+    /// Backend 1 answers after a second, backend 2 at once; counts dropped attempts.
+    #[derive(Clone)]
+    struct Upstream {
+        dropped: Arc<AtomicUsize>,
+    }
+
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Service<ProxyRequest> for Upstream {
+        type Response = ProxyResponse;
+        type Error = ProxyError;
+        type Future = ProxyFuture;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: ProxyRequest) -> Self::Future {
+            let backend = req.extensions().get::<RouteContext>().unwrap().backend.id;
+            let guard = DropCounter(self.dropped.clone());
+            Box::pin(async move {
+                if backend == BackendId(1) {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                std::mem::forget(guard);
+                Ok(local_response(StatusCode::OK, if backend == BackendId(1) { "slow" } else { "fast" }))
+            })
+        }
+    }
+
+    fn request(method: Method, backend: &Arc<Backend>) -> ProxyRequest {
+        let mut req = Request::builder().method(method).uri("/").body(full_body(Bytes::new())).unwrap();
+        req.extensions_mut().insert(RouteContext {
+            route: "/".into(),
+            backend: backend.clone(),
+            labels: Arc::default(),
+        });
+        req
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hedge_wins_and_cancels_the_slow_attempt() {
+        let backends = [1, 2].map(|id| Arc::new(Backend::new(BackendId(id), "127.0.0.1:9".parse().unwrap())));
+        let routing_table = Arc::new(RoutingTable::new(backends.to_vec()));
+        let upstream = Upstream { dropped: Arc::default() };
+        let service = HedgeLayer::new(HedgePolicy::default(), routing_table).layer(upstream.clone());
+
+        let res = service.clone().oneshot(request(Method::GET, &backends[0])).await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "fast");
+        assert_eq!(upstream.dropped.load(Ordering::SeqCst), 1, "the slow attempt was cancelled");
+
+        // Writes are never hedged
+        let res = service.oneshot(request(Method::POST, &backends[0])).await.unwrap();
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "slow");
+    }
+}
@@ -0,0 +1,488 @@
+//! Rewriting of internal URLs in proxied HTML and CSS.
+//!
+//! Legacy apps behind the proxy often emit their own internal origin
+//! (`http://app.internal:8080/...`) in links, redirects and stylesheets,
+//! which breaks once they are served from the external domain. Routes with
+//! rewrites get those prefixes replaced as the response streams through:
+//! HTML attribute values and inline `<style>` blocks via `lol_html`, CSS
+//! bodies and the `Location` header as plain text. Matching is literal and
+//! case-sensitive, so each spelling the app uses (`http://`, `//`, a bare
+//! host) needs its own rewrite.
+//!
+//! Compressed bodies can't be rewritten, so requests on these routes ask the
+//! backend for an uncompressed response.
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use hyper::{Method, StatusCode};
+use http_body_util::BodyExt;
+use lol_html::html_content::ContentType;
+use lol_html::{element, send, text, OutputSink};
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::pipeline::{take_inner, ProxyBody, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// Bodies cut short because they could not be rewritten, by `route`.
+static REWRITE_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_html_rewrite_failures_total",
+        "HTML bodies truncated because they could not be rewritten",
+        &["route"]
+    )
+    .expect("metric registers once")
+});
+
+/// One prefix replaced in responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlRewrite {
+    /// What the backend emits, e.g. `http://app.internal:8080`
+    pub from: String,
+    /// What clients should see instead, e.g. `https://www.example.com`
+    pub to: String,
+}
+
+impl UrlRewrite {
+    /// Replace `from` with `to`.
+    pub fn new(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self { from: from.into(), to: to.into() }
+    }
+}
+
+/// URL rewrites by route name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UrlRewrites {
+    /// The rewrites applied to each route's responses, tried in order
+    pub routes: HashMap<String, Vec<UrlRewrite>>,
+}
+
+impl UrlRewrites {
+    /// Replace `from` with `to` in `route`'s responses, builder style.
+    pub fn with(mut self, route: impl Into<String>, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.routes.entry(route.into()).or_default().push(UrlRewrite::new(from, to));
+        self
+    }
+}
+
+/// Literal prefix replacement over a stream of bytes.
+///
+/// Input that could still turn out to be the start of a match is held back
+/// until the next chunk decides it, so matches split across chunks are found.
+#[derive(Debug)]
+struct Replacer {
+    rewrites: Arc<[UrlRewrite]>,
+    pending: Vec<u8>,
+}
+
+impl Replacer {
+    fn new(rewrites: Arc<[UrlRewrite]>) -> Self {
+        Self { rewrites, pending: Vec::new() }
+    }
+
+    /// Rewrites `data`, appending what is settled to `out`.
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.pending.extend_from_slice(data);
+        let consumed = self.replace(out, false);
+        self.pending.drain(..consumed);
+    }
+
+    /// Flushes the input held back at the end of the stream.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        self.replace(out, true);
+        self.pending.clear();
+    }
+
+    /// Rewrites as much of `pending` as can be decided, returning how much.
+    fn replace(&self, out: &mut Vec<u8>, last: bool) -> usize {
+        let input = &self.pending;
+        let mut pos = 0;
+        'scan: while pos < input.len() {
+            let rest = &input[pos..];
+            for rewrite in self.rewrites.iter().filter(|r| !r.from.is_empty()) {
+                let from = rewrite.from.as_bytes();
+                if rest.starts_with(from) {
+                    out.extend_from_slice(rewrite.to.as_bytes());
+                    pos += from.len();
+                    continue 'scan;
+                }
+                if !last && rest.len() < from.len() && from.starts_with(rest) {
+                    return pos;
+                }
+            }
+            out.push(input[pos]);
+            pos += 1;
+        }
+        pos
+    }
+
+    /// `text` with every match replaced.
+    fn rewrite_str(rewrites: &Arc<[UrlRewrite]>, text: &str) -> String {
+        let mut replacer = Self::new(rewrites.clone());
+        let mut out = Vec::with_capacity(text.len());
+        replacer.push(text.as_bytes(), &mut out);
+        replacer.finish(&mut out);
+        // Whole UTF-8 strings are only ever swapped for other UTF-8 strings
+        String::from_utf8(out).expect("rewriting keeps UTF-8 valid")
+    }
+}
+
+/// The kind of body a response carries, if it is rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Document {
+    Html,
+    Css,
+}
+
+impl Document {
+    /// The rewritable document in a response with `headers`, if any.
+    /// Compressed bodies and charsets other than UTF-8 are left alone.
+    fn of(headers: &HeaderMap) -> Option<Self> {
+        if headers.contains_key(CONTENT_ENCODING) {
+            return None;
+        }
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?.to_ascii_lowercase();
+        let mut params = content_type.split(';').map(str::trim);
+        let document = match params.next()? {
+            "text/html" | "application/xhtml+xml" => Document::Html,
+            "text/css" => Document::Css,
+            _ => return None,
+        };
+        let utf8 = params
+            .filter_map(|p| p.strip_prefix("charset="))
+            .all(|charset| matches!(charset.trim_matches('"'), "utf-8" | "utf8" | "us-ascii"));
+        utf8.then_some(document)
+    }
+}
+
+/// Rewrites internal URLs in responses per `UrlRewrites`.
+#[derive(Debug, Clone)]
+pub struct HtmlRewriteLayer {
+    rewrites: Arc<HashMap<String, Arc<[UrlRewrite]>>>,
+}
+
+impl HtmlRewriteLayer {
+    /// Create a layer applying `rewrites`.
+    pub fn new(rewrites: UrlRewrites) -> Self {
+        let routes = rewrites.routes.into_iter().map(|(route, list)| (route, list.into())).collect();
+        Self { rewrites: Arc::new(routes) }
+    }
+}
+
+impl<S> Layer<S> for HtmlRewriteLayer {
+    type Service = HtmlRewriteService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HtmlRewriteService {
+            inner,
+            rewrites: self.rewrites.clone(),
+        }
+    }
+}
+
+/// Service produced by `HtmlRewriteLayer`.
+#[derive(Debug, Clone)]
+pub struct HtmlRewriteService<S> {
+    inner: S,
+    rewrites: Arc<HashMap<String, Arc<[UrlRewrite]>>>,
+}
+
+impl<S> Service<ProxyRequest> for HtmlRewriteService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let route = req.extensions().get::<RouteContext>().map(|ctx| ctx.route.clone());
+        let Some((route, rewrites)) = route.and_then(|route| Some((route.clone(), self.rewrites.get(&route)?.clone())))
+        else {
+            return Box::pin(self.inner.call(req));
+        };
+        req.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        let head = req.method() == Method::HEAD;
+
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let mut res = inner.call(req).await?;
+            if let Some(location) = res.headers().get(LOCATION).and_then(|v| v.to_str().ok()) {
+                let rewritten = Replacer::rewrite_str(&rewrites, location);
+                if let Ok(value) = HeaderValue::from_str(&rewritten) {
+                    res.headers_mut().insert(LOCATION, value);
+                }
+            }
+            let bodiless = head || matches!(res.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+            if let Some(document) = Document::of(res.headers()).filter(|_| !bodiless) {
+                res.headers_mut().remove(CONTENT_LENGTH);
+                res = res.map(|body| RewrittenBody::wrap(body, document, rewrites, route));
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Where the HTML rewriter writes its output, for the body to pick up.
+type Output = Arc<Mutex<Vec<u8>>>;
+
+/// Collects the HTML rewriter's output into an `Output`.
+struct Sink(Output);
+
+impl OutputSink for Sink {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        self.0.lock().expect("rewriter output lock poisoned").extend_from_slice(chunk);
+    }
+}
+
+/// The rewriter for one body.
+enum Rewriter {
+    Html(Box<send::HtmlRewriter<'static, Sink>>, Output),
+    Css(Replacer),
+}
+
+impl Rewriter {
+    fn html(rewrites: Arc<[UrlRewrite]>) -> Self {
+        let output = Output::default();
+        let sink = output.clone();
+        let attributes = rewrites.clone();
+        let mut style = String::new();
+        let settings = send::Settings {
+            element_content_handlers: vec![
+                element!("*", move |el: &mut send::Element<'_, '_>| {
+                    let changed: Vec<_> = el
+                        .attributes()
+                        .iter()
+                        .filter_map(|attr| {
+                            let value = attr.value();
+                            let rewritten = Replacer::rewrite_str(&attributes, &value);
+                            (rewritten != value).then(|| (attr.name(), rewritten))
+                        })
+                        .collect();
+                    for (name, value) in changed {
+                        el.set_attribute(&name, &value)?;
+                    }
+                    Ok(())
+                }),
+                // A stylesheet may arrive in several chunks; it is rewritten whole
+                text!("style", move |chunk| {
+                    style.push_str(chunk.as_str());
+                    if chunk.last_in_text_node() {
+                        chunk.replace(&Replacer::rewrite_str(&rewrites, &style), ContentType::Html);
+                        style.clear();
+                    } else {
+                        chunk.remove();
+                    }
+                    Ok(())
+                }),
+            ],
+            ..send::Settings::new_send()
+        };
+        Rewriter::Html(Box::new(send::HtmlRewriter::new(settings, Sink(sink))), output)
+    }
+}
+
+/// A body whose data frames are passed through a `Rewriter`.
+struct RewrittenBody {
+    inner: ProxyBody,
+    // Only ever used through `&mut`; the lock just makes the body `Sync`
+    rewriter: Mutex<Option<Rewriter>>,
+    route: String,
+    done: bool,
+}
+
+impl RewrittenBody {
+    fn wrap(inner: ProxyBody, document: Document, rewrites: Arc<[UrlRewrite]>, route: String) -> ProxyBody {
+        let rewriter = match document {
+            Document::Html => Rewriter::html(rewrites),
+            Document::Css => Rewriter::Css(Replacer::new(rewrites)),
+        };
+        RewrittenBody {
+            inner,
+            rewriter: Mutex::new(Some(rewriter)),
+            route,
+            done: false,
+        }
+        .boxed()
+    }
+
+    /// Feeds `data` (or the end of the body, if `None`) to the rewriter,
+    /// returning its output so far.
+    fn rewrite(&mut self, data: Option<&[u8]>) -> Result<Vec<u8>, lol_html::errors::RewritingError> {
+        let slot = self.rewriter.get_mut().expect("rewriter lock poisoned");
+        let mut out = Vec::new();
+        match (slot, data) {
+            (Some(Rewriter::Html(rewriter, output)), Some(data)) => {
+                rewriter.write(data)?;
+                out = std::mem::take(&mut *output.lock().expect("rewriter output lock poisoned"));
+            }
+            (slot @ Some(Rewriter::Html(..)), None) => {
+                let Some(Rewriter::Html(rewriter, output)) = slot.take() else {
+                    unreachable!("matched an HTML rewriter");
+                };
+                rewriter.end()?;
+                out = std::mem::take(&mut *output.lock().expect("rewriter output lock poisoned"));
+            }
+            (Some(Rewriter::Css(replacer)), Some(data)) => replacer.push(data, &mut out),
+            (Some(Rewriter::Css(replacer)), None) => replacer.finish(&mut out),
+            (None, _) => {}
+        }
+        Ok(out)
+    }
+
+    /// Stops the body after a rewriting failure.
+    fn fail(&mut self, err: impl std::fmt::Display) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        tracing::warn!(route = %self.route, error = %err, "truncating body that could not be rewritten");
+        REWRITE_FAILURES.with_label_values(&[&self.route]).inc();
+        self.done = true;
+        Poll::Ready(None)
+    }
+}
+
+impl Body for RewrittenBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            if this.done {
+                return Poll::Ready(None);
+            }
+            return match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => match this.rewrite(Some(&data)) {
+                        // Held back until later input decides it
+                        Ok(out) if out.is_empty() => continue,
+                        Ok(out) => Poll::Ready(Some(Ok(Frame::data(Bytes::from(out))))),
+                        Err(e) => this.fail(e),
+                    },
+                    Err(frame) => Poll::Ready(Some(Ok(frame))),
+                },
+                Poll::Ready(None) => {
+                    this.done = true;
+                    match this.rewrite(None) {
+                        Ok(out) if out.is_empty() => Poll::Ready(None),
+                        Ok(out) => Poll::Ready(Some(Ok(Frame::data(Bytes::from(out))))),
+                        Err(e) => this.fail(e),
+                    }
+                }
+                other => other,
+            };
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{full_body, Chunks};
+    use hyper::{Request, Response};
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::{Backend, BackendId};
+
+    fn rewrites() -> Arc<[UrlRewrite]> {
+        vec![
+            UrlRewrite::new("http://app.internal:8080", "https://www.example.com"),
+            UrlRewrite::new("//app.internal:8080", "//www.example.com"),
+        ]
+        .into()
+    }
+
+    #[test]
+    fn test_matches_split_across_chunks_are_replaced() {
+        let mut replacer = Replacer::new(rewrites());
+        let mut out = Vec::new();
+        for chunk in ["a{background:url(http://app.in", "ternal:8080/bg.png)} /* http://app */"] {
+            replacer.push(chunk.as_bytes(), &mut out);
+        }
+        replacer.finish(&mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a{background:url(https://www.example.com/bg.png)} /* http://app */"
+        );
+    }
+
+    #[test]
+    fn test_only_utf8_html_and_css_are_rewritten() {
+        let headers = |pairs: &[(&str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in pairs {
+                map.insert(hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+            }
+            map
+        };
+        assert_eq!(Document::of(&headers(&[("content-type", "text/HTML; charset=UTF-8")])), Some(Document::Html));
+        assert_eq!(Document::of(&headers(&[("content-type", "text/css")])), Some(Document::Css));
+        assert_eq!(Document::of(&headers(&[("content-type", "text/html; charset=iso-8859-1")])), None);
+        assert_eq!(Document::of(&headers(&[("content-type", "text/html"), ("content-encoding", "gzip")])), None);
+        assert_eq!(Document::of(&headers(&[("content-type", "application/json")])), None);
+    }
+
+    #[tokio::test]
+    async fn test_html_links_styles_and_redirects_are_rewritten() {
+        let upstream = tower::service_fn(|req: ProxyRequest| async move {
+            assert_eq!(req.headers()[ACCEPT_ENCODING], "identity");
+            let html = concat!(
+                r#"<a href="http://app.internal:8080/login?next=/">in</a>"#,
+                r#"<img src="//app.internal:8080/logo.png" alt="http://elsewhere">"#,
+                "<style>body{background:url(http://app.internal:8080/bg.png)}</style>",
+                "<p>http://app.internal:8080 in text stays</p>"
+            );
+            let (head, tail) = html.split_at(20);
+            let body = Chunks([head.as_bytes(), tail.as_bytes()].into());
+            let res = Response::builder()
+                .status(StatusCode::FOUND)
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .header(CONTENT_LENGTH, html.len())
+                .header(LOCATION, "http://app.internal:8080/home")
+                .body(body.boxed())
+                .unwrap();
+            Ok::<_, ProxyError>(res)
+        });
+        let rewrites = UrlRewrites::default()
+            .with("app", "http://app.internal:8080", "https://www.example.com")
+            .with("app", "//app.internal:8080", "//www.example.com");
+        let service = HtmlRewriteLayer::new(rewrites).layer(upstream);
+
+        let mut req = Request::builder().header(ACCEPT_ENCODING, "gzip").body(full_body(Bytes::new())).unwrap();
+        req.extensions_mut().insert(RouteContext {
+            route: "app".to_string(),
+            backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap())),
+            labels: Arc::default(),
+        });
+        let res = service.oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[LOCATION], "https://www.example.com/home");
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            concat!(
+                r#"<a href="https://www.example.com/login?next=/">in</a>"#,
+                r#"<img src="//www.example.com/logo.png" alt="http://elsewhere">"#,
+                "<style>body{background:url(https://www.example.com/bg.png)}</style>",
+                "<p>http://app.internal:8080 in text stays</p>"
+            )
+        );
+    }
+}
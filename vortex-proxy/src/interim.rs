@@ -0,0 +1,211 @@
+//! Pass-through of upstream 1xx interim responses, chiefly 103 Early Hints.
+//!
+//! By default the upstream client swallows interim responses, so the
+//! `Link: <...>; rel=preload` hints an application sends ahead of a slow
+//! page never reach the browser. Routes that opt in have their upstream's
+//! interim responses collected and attached to the final response as
+//! `InterimResponses`.
+//!
+//! hyper's server side has no way to write an interim response yet, so the
+//! `Link` headers of any 103s are also folded into the final response, where
+//! browsers still act on preload hints, just later than they could.
+
+use hyper::header::{HeaderMap, LINK};
+use hyper::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::pipeline::{take_inner, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// Which routes forward interim responses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterimForwarding {
+    /// Whether routes without their own setting forward them
+    pub default: bool,
+    /// Settings for individual routes, by route name
+    pub routes: HashMap<String, bool>,
+}
+
+impl InterimForwarding {
+    fn for_route(&self, route: &str) -> bool {
+        self.routes.get(route).copied().unwrap_or(self.default)
+    }
+}
+
+/// One interim response received from the upstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterimResponse {
+    /// Its 1xx status
+    pub status: StatusCode,
+    /// Its headers
+    pub headers: HeaderMap,
+}
+
+/// Response extension holding the interim responses the upstream sent
+/// before the final one, in the order received.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterimResponses(pub Vec<InterimResponse>);
+
+/// Request extension asking the upstream client to record interim responses.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InterimCollector(Arc<Mutex<Vec<InterimResponse>>>);
+
+impl InterimCollector {
+    /// Record one interim response; `100 Continue` and `101` are the
+    /// client's own business and aren't forwarded.
+    pub(crate) fn push(&self, status: StatusCode, headers: &HeaderMap) {
+        if matches!(status, StatusCode::CONTINUE | StatusCode::SWITCHING_PROTOCOLS) {
+            return;
+        }
+        self.0.lock().unwrap().push(InterimResponse {
+            status,
+            headers: headers.clone(),
+        });
+    }
+
+    fn take(&self) -> Vec<InterimResponse> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Collects interim responses on routes that forward them.
+#[derive(Debug, Clone)]
+pub struct InterimLayer {
+    config: Arc<InterimForwarding>,
+}
+
+impl InterimLayer {
+    /// Create a layer forwarding interim responses per `config`.
+    pub fn new(config: InterimForwarding) -> Self {
+        Self { config: Arc::new(config) }
+    }
+}
+
+impl<S> Layer<S> for InterimLayer {
+    type Service = InterimService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        InterimService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Service produced by `InterimLayer`.
+#[derive(Debug, Clone)]
+pub struct InterimService<S> {
+    inner: S,
+    config: Arc<InterimForwarding>,
+}
+
+impl<S> Service<ProxyRequest> for InterimService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let enabled = req
+            .extensions()
+            .get::<RouteContext>()
+            .is_some_and(|ctx| self.config.for_route(&ctx.route));
+        if !enabled {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let collector = InterimCollector::default();
+        req.extensions_mut().insert(collector.clone());
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let mut res = inner.call(req).await?;
+            let interim = collector.take();
+            if interim.is_empty() {
+                return Ok(res);
+            }
+            fold_early_hints(&interim, res.headers_mut());
+            res.extensions_mut().insert(InterimResponses(interim));
+            Ok(res)
+        })
+    }
+}
+
+/// Copies the `Link` headers of any 103s into the final response's headers,
+/// skipping ones it already carries.
+fn fold_early_hints(interim: &[InterimResponse], headers: &mut HeaderMap) {
+    let hints = interim
+        .iter()
+        .filter(|r| r.status == StatusCode::EARLY_HINTS)
+        .flat_map(|r| r.headers.get_all(LINK));
+    for link in hints {
+        if !headers.get_all(LINK).iter().any(|existing| existing == link) {
+            headers.append(LINK, link.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use hyper::body::Bytes;
+    use hyper::header::HeaderValue;
+    use hyper::{Request, Response};
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::labels::Labels;
+
+    fn routed(route: &str) -> ProxyRequest {
+        let mut req = Request::new(full_body(Bytes::new()));
+        req.extensions_mut().insert(RouteContext {
+            route: route.into(),
+            backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap())),
+            labels: Arc::new(Labels::new()),
+        });
+        req
+    }
+
+    #[tokio::test]
+    async fn test_early_hints_reach_the_final_response() {
+        let upstream = tower::service_fn(|req: ProxyRequest| async move {
+            // Stands in for the upstream client's informational callback
+            if let Some(collector) = req.extensions().get::<InterimCollector>() {
+                let mut hints = HeaderMap::new();
+                hints.append(LINK, HeaderValue::from_static("</app.css>; rel=preload; as=style"));
+                hints.append(LINK, HeaderValue::from_static("</app.js>; rel=preload; as=script"));
+                collector.push(StatusCode::CONTINUE, &HeaderMap::new());
+                collector.push(StatusCode::EARLY_HINTS, &hints);
+            }
+            let res = Response::builder()
+                .header(LINK, "</app.css>; rel=preload; as=style")
+                .body(full_body(Bytes::new()))
+                .unwrap();
+            Ok::<_, ProxyError>(res)
+        });
+        let config = InterimForwarding {
+            default: false,
+            routes: [("pages".to_string(), true)].into(),
+        };
+        let service = InterimLayer::new(config).layer(upstream);
+
+        let res = service.clone().oneshot(routed("pages")).await.unwrap();
+        assert_eq!(res.headers().get_all(LINK).iter().count(), 2);
+        let interim = res.extensions().get::<InterimResponses>().unwrap();
+        assert_eq!(interim.0.len(), 1);
+        assert_eq!(interim.0[0].status, StatusCode::EARLY_HINTS);
+
+        let res = service.oneshot(routed("api")).await.unwrap();
+        assert_eq!(res.headers().get_all(LINK).iter().count(), 1);
+        assert!(res.extensions().get::<InterimResponses>().is_none());
+    }
+}
@@ -0,0 +1,65 @@
+//! Vortex Proxy Engine
+//!
+//! The Tokio async engine that manages socket binding, connection pooling, and request
+//! pipelining. The `vortex-proxy` binary is a thin wrapper around this library; teams can
+//! embed the same engine in their own binaries and tests through [`Vortex::builder`].
+
+#![deny(missing_docs)]
+
+pub mod access_log;
+pub mod alerting;
+pub mod body_codec;
+pub mod cache;
+mod civil;
+pub mod client_concurrency;
+pub mod client_quota;
+pub mod compression;
+pub mod config_check;
+pub mod config_reload;
+pub mod connect_pacing;
+pub mod connection_pool;
+pub mod deadline;
+pub mod dev_backend;
+pub mod diagnostics;
+pub mod dns;
+pub mod duplicate_headers;
+pub mod error;
+pub mod experiments;
+pub mod fd_limit;
+pub mod force_backend;
+pub mod header_limits;
+pub mod health_check;
+pub mod hedging;
+pub mod html_rewrite;
+pub mod interim;
+pub mod lifecycle;
+pub mod log_sink;
+pub mod logging;
+pub mod metrics;
+pub mod normalization;
+pub mod otel;
+pub mod passive_health;
+pub mod persistence;
+pub mod pipeline;
+pub mod redaction;
+pub mod request_id;
+pub mod request_rules;
+pub mod response_limit;
+pub mod retry;
+pub mod sampling;
+pub mod selftest;
+pub mod server;
+pub mod server_timing;
+pub mod socket;
+#[cfg(all(feature = "ebpf", target_os = "linux"))]
+pub mod sockmap;
+pub mod tls;
+pub mod tls_failures;
+pub mod token_bucket;
+pub mod traffic;
+pub mod upstream_auth;
+pub mod upstream_target;
+mod vortex;
+
+pub use error::ProxyError;
+pub use vortex::{Vortex, VortexBuilder, VortexHandle};
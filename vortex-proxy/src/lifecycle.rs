@@ -0,0 +1,255 @@
+//! Ordered shutdown of the proxy's background subsystems.
+//!
+//! Every background task a running instance spawns (the admin plane, the
+//! metrics server, discovery watchers, health checks, pool upkeep, telemetry
+//! shippers) is registered here with the phase it stops in, along with the
+//! one-off hooks that run on the way out, like saving state. Once the
+//! listeners have stopped, the phases run in order, so nothing is stopped
+//! while something still feeding it work runs: discovery stops before the
+//! health checks probing what it finds, telemetry is flushed only after
+//! everything producing it is gone, and state is saved last, once nothing
+//! changes it anymore.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::Instant;
+
+/// How long a graceful shutdown waits on subsystems, unless told otherwise.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// When a subsystem stops during shutdown; phases run in declaration order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Control and observability endpoints: the admin plane, the metrics
+    /// server, diagnostic dumps
+    Endpoints,
+    /// What changes the routing table: configuration reloading, DNS discovery
+    Discovery,
+    /// What watches the backends: health checks, alerting, certificate expiry
+    Health,
+    /// Pool upkeep and other housekeeping
+    Maintenance,
+    /// Queued telemetry: shipped logs, exported spans
+    Telemetry,
+    /// Saving state for the next start
+    Persistence,
+}
+
+/// Tells draining tasks to take no more work and finish what is queued.
+///
+/// Their queues can't be relied on to close by themselves: whatever still
+/// holds a sender, like a keep-alive connection's copy of the pipeline,
+/// would keep them open until the grace period ran out.
+#[derive(Debug, Clone, Default)]
+pub struct CloseSignal(Arc<watch::Sender<bool>>);
+
+impl CloseSignal {
+    /// A signal not yet given.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives the signal.
+    pub fn close(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Resolves once the signal is given.
+    pub async fn closed(&self) {
+        // The signal holds the sender, so waiting can't fail
+        let _ = self.0.subscribe().wait_for(|closed| *closed).await;
+    }
+}
+
+/// How a subsystem is stopped.
+enum Hook {
+    /// Aborted outright
+    Abort(JoinHandle<()>),
+    /// Signalled to finish its queued work, then aborted if out of time
+    Drain(JoinHandle<()>, CloseSignal),
+    /// Run once
+    Run(Box<dyn FnOnce() + Send>),
+}
+
+struct Subsystem {
+    name: &'static str,
+    phase: Phase,
+    hook: Hook,
+}
+
+/// The subsystems of a running instance, stopped together in phase order.
+#[derive(Default)]
+pub struct Lifecycle {
+    subsystems: Vec<Subsystem>,
+}
+
+impl Lifecycle {
+    /// An empty lifecycle.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `task`, aborted in `phase`.
+    pub fn register(&mut self, name: &'static str, phase: Phase, task: JoinHandle<()>) {
+        self.push(name, phase, Hook::Abort(task));
+    }
+
+    /// Registers `task`, which ends on its own once `close` is given and it
+    /// has drained its queue; `close` is given in `phase`, and the task gets
+    /// until the grace period runs out, then is aborted.
+    pub fn register_draining(&mut self, name: &'static str, phase: Phase, task: JoinHandle<()>, close: CloseSignal) {
+        self.push(name, phase, Hook::Drain(task, close));
+    }
+
+    /// Runs `hook` in `phase`.
+    pub fn on_shutdown(&mut self, name: &'static str, phase: Phase, hook: impl FnOnce() + Send + 'static) {
+        self.push(name, phase, Hook::Run(Box::new(hook)));
+    }
+
+    fn push(&mut self, name: &'static str, phase: Phase, hook: Hook) {
+        self.subsystems.push(Subsystem { name, phase, hook });
+    }
+
+    /// Phase order, keeping registration order within a phase.
+    fn ordered(mut self) -> Vec<Subsystem> {
+        self.subsystems.sort_by_key(|s| s.phase);
+        self.subsystems
+    }
+
+    /// Stops every subsystem in phase order, waiting at most `grace` in all
+    /// for them to finish.
+    pub async fn shutdown(self, grace: Duration) {
+        let deadline = Instant::now() + grace;
+        for Subsystem { name, phase, hook } in self.ordered() {
+            match hook {
+                Hook::Abort(task) => {
+                    task.abort();
+                    stopped(name, phase, task, deadline).await;
+                }
+                Hook::Drain(mut task, close) => {
+                    close.close();
+                    match tokio::time::timeout_at(deadline, &mut task).await {
+                        Ok(result) => report(name, phase, result),
+                        Err(_) => {
+                            tracing::warn!(subsystem = name, "subsystem did not drain in time; aborting");
+                            task.abort();
+                        }
+                    }
+                }
+                Hook::Run(hook) => {
+                    hook();
+                    tracing::debug!(subsystem = name, ?phase, "shutdown hook ran");
+                }
+            }
+        }
+    }
+
+    /// Stops every subsystem in phase order at once: tasks are aborted
+    /// without waiting on them, and hooks still run.
+    pub fn abort(self) {
+        for Subsystem { hook, .. } in self.ordered() {
+            match hook {
+                Hook::Abort(task) | Hook::Drain(task, _) => task.abort(),
+                Hook::Run(hook) => hook(),
+            }
+        }
+    }
+}
+
+/// Waits, until `deadline`, for `task` to end after it was told to stop.
+async fn stopped(name: &'static str, phase: Phase, task: JoinHandle<()>, deadline: Instant) {
+    match tokio::time::timeout_at(deadline, task).await {
+        Ok(result) => report(name, phase, result),
+        Err(_) => tracing::warn!(subsystem = name, "subsystem did not stop in time"),
+    }
+}
+
+fn report(name: &'static str, phase: Phase, result: Result<(), JoinError>) {
+    match result {
+        Err(e) if e.is_panic() => tracing::warn!(subsystem = name, "subsystem panicked"),
+        _ => tracing::debug!(subsystem = name, ?phase, "subsystem stopped"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_subsystems_stop_in_phase_order() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut lifecycle = Lifecycle::new();
+        for (name, phase) in [
+            ("state", Phase::Persistence),
+            ("health", Phase::Health),
+            ("admin", Phase::Endpoints),
+            ("dns", Phase::Discovery),
+            ("metrics", Phase::Endpoints),
+        ] {
+            let order = order.clone();
+            lifecycle.on_shutdown(name, phase, move || order.lock().unwrap().push(name));
+        }
+        lifecycle.shutdown(Duration::from_secs(1)).await;
+        assert_eq!(*order.lock().unwrap(), ["admin", "metrics", "dns", "health", "state"]);
+    }
+
+    #[tokio::test]
+    async fn test_draining_tasks_finish_their_queue_before_later_phases() {
+        let (tx, mut rx) = mpsc::channel::<u32>(8);
+        let shipped = Arc::new(Mutex::new(Vec::new()));
+        let sink = shipped.clone();
+        let mut lifecycle = Lifecycle::new();
+        let close = CloseSignal::new();
+        let closing = close.clone();
+        let shipper = tokio::spawn(async move {
+            let mut closed = false;
+            loop {
+                let record = tokio::select! {
+                    biased;
+                    record = rx.recv() => record,
+                    _ = closing.closed(), if !closed => {
+                        rx.close();
+                        closed = true;
+                        continue;
+                    }
+                };
+                let Some(record) = record else { break };
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                sink.lock().unwrap().push(record);
+            }
+        });
+        lifecycle.register_draining("shipper", Phase::Telemetry, shipper, close);
+        let seen = shipped.clone();
+        let saved = Arc::new(Mutex::new(None));
+        let save = saved.clone();
+        lifecycle.on_shutdown("state", Phase::Persistence, move || {
+            *save.lock().unwrap() = Some(seen.lock().unwrap().len());
+        });
+        for record in 0..3 {
+            tx.send(record).await.unwrap();
+        }
+        // The sender outlives the shutdown, as a keep-alive connection's would
+        let started = Instant::now();
+        lifecycle.shutdown(Duration::from_secs(1)).await;
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert!(tx.send(3).await.is_err());
+        assert_eq!(*shipped.lock().unwrap(), [0, 1, 2]);
+        assert_eq!(*saved.lock().unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_tasks_that_never_drain_are_aborted_at_the_deadline() {
+        let (_tx, mut rx) = mpsc::channel::<u32>(1);
+        let mut lifecycle = Lifecycle::new();
+        let stuck = tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        lifecycle.register_draining("stuck", Phase::Telemetry, stuck, CloseSignal::new());
+        lifecycle.register("reaper", Phase::Maintenance, tokio::spawn(std::future::pending()));
+        let started = Instant::now();
+        lifecycle.shutdown(Duration::from_millis(50)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,506 @@
+//! Shipping access and error logs to syslog and remote collectors.
+//!
+//! Not every environment collects logs from files or stderr. A `LogShipper`
+//! fans each `LogRecord` out to its sinks: syslog (RFC 5424 over UDP, TCP
+//! with octet-counting framing, or a local Unix socket), a plain TCP stream
+//! of JSON lines, batched JSON lines POSTed to an HTTP endpoint, or plain
+//! lines appended to a file.
+//!
+//! Shipping never blocks a request. Every sink has a bounded queue drained
+//! by its own task; records that don't fit, or that the sink fails to
+//! deliver, are dropped and counted in `vortex_log_records_dropped_total`.
+
+use prometheus::IntCounterVec;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::civil::UtcTime;
+use crate::lifecycle::CloseSignal;
+
+/// Most records a sink task writes (or POSTs) at once.
+const MAX_BATCH: usize = 256;
+
+/// Records shipped, by sink.
+static SHIPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_log_records_shipped_total",
+        "Log records delivered to a sink",
+        &["sink"]
+    )
+    .expect("metric registers once")
+});
+
+/// Records lost, by sink and `reason`: `queue_full` or `write_failed`.
+static DROPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_log_records_dropped_total",
+        "Log records a sink could not queue or deliver",
+        &["sink", "reason"]
+    )
+    .expect("metric registers once")
+});
+
+/// Which log a record belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogKind {
+    /// One line per request
+    Access,
+    /// A request that failed inside the proxy
+    Error,
+    /// A request assigned to an experiment variant
+    Exposure,
+}
+
+impl LogKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogKind::Access => "access",
+            LogKind::Error => "error",
+            LogKind::Exposure => "exposure",
+        }
+    }
+}
+
+/// How serious a record is, on the syslog scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSeverity {
+    /// Something failed
+    Error,
+    /// Something looks wrong
+    Warning,
+    /// Routine
+    Info,
+}
+
+impl LogSeverity {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogSeverity::Error => "error",
+            LogSeverity::Warning => "warning",
+            LogSeverity::Info => "info",
+        }
+    }
+
+    /// The RFC 5424 severity code.
+    fn code(self) -> u8 {
+        match self {
+            LogSeverity::Error => 3,
+            LogSeverity::Warning => 4,
+            LogSeverity::Info => 6,
+        }
+    }
+}
+
+/// One log line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// The log it belongs to
+    pub kind: LogKind,
+    /// How serious it is
+    pub severity: LogSeverity,
+    /// When it happened
+    pub timestamp: SystemTime,
+    /// The formatted line, without a trailing newline
+    pub message: String,
+}
+
+impl LogRecord {
+    fn json(&self) -> String {
+        json!({
+            "timestamp": UtcTime::from_system_time(self.timestamp).rfc3339(),
+            "log": self.kind.as_str(),
+            "severity": self.severity.as_str(),
+            "message": self.message,
+        })
+        .to_string()
+    }
+}
+
+/// How syslog messages reach the daemon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTransport {
+    /// One datagram per message
+    Udp(SocketAddr),
+    /// A stream with octet-counting framing (RFC 6587)
+    Tcp(SocketAddr),
+    /// A local datagram socket such as `/dev/log`
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A syslog destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogSink {
+    /// Where messages are sent
+    pub transport: SyslogTransport,
+    /// Facility code, 0-23; 16 (`local0`) by default
+    pub facility: u8,
+    /// The `APP-NAME` field
+    pub app_name: String,
+    /// The `HOSTNAME` field; `-` if empty
+    pub hostname: String,
+}
+
+impl SyslogSink {
+    /// Send to `transport` as `vortex` on facility `local0`, naming this host
+    /// from `$HOSTNAME` if set.
+    pub fn new(transport: SyslogTransport) -> Self {
+        Self {
+            transport,
+            facility: 16,
+            app_name: "vortex".to_string(),
+            hostname: std::env::var("HOSTNAME").unwrap_or_default(),
+        }
+    }
+
+    /// Renders `record` as an RFC 5424 message.
+    fn format(&self, record: &LogRecord) -> String {
+        let field = |value: &str| if value.is_empty() { "-".to_string() } else { value.replace(' ', "_") };
+        format!(
+            "<{}>1 {} {} {} {} {} - {}",
+            u16::from(self.facility.min(23)) * 8 + u16::from(record.severity.code()),
+            UtcTime::from_system_time(record.timestamp).rfc3339(),
+            field(&self.hostname),
+            field(&self.app_name),
+            std::process::id(),
+            record.kind.as_str(),
+            record.message
+        )
+    }
+}
+
+/// A destination for shipped logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogSink {
+    /// A syslog daemon or relay
+    Syslog(SyslogSink),
+    /// A TCP collector reading one JSON object per line
+    Tcp(SocketAddr),
+    /// An HTTP collector accepting POSTed batches of JSON lines
+    Http {
+        /// Where batches are POSTed
+        url: String,
+    },
+    /// A file the formatted lines are appended to, created if missing
+    File(PathBuf),
+}
+
+impl LogSink {
+    /// Label for metrics and error messages.
+    fn name(&self) -> String {
+        match self {
+            LogSink::Syslog(syslog) => match &syslog.transport {
+                SyslogTransport::Udp(addr) => format!("syslog+udp://{}", addr),
+                SyslogTransport::Tcp(addr) => format!("syslog+tcp://{}", addr),
+                #[cfg(unix)]
+                SyslogTransport::Unix(path) => format!("syslog+unix://{}", path.display()),
+            },
+            LogSink::Tcp(addr) => format!("tcp://{}", addr),
+            LogSink::Http { url } => url.clone(),
+            LogSink::File(path) => format!("file://{}", path.display()),
+        }
+    }
+}
+
+/// Where logs are shipped and how much may queue up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogShipping {
+    /// Destinations for every record
+    pub sinks: Vec<LogSink>,
+    /// Records each sink may hold before new ones are dropped
+    pub queue_capacity: usize,
+}
+
+impl Default for LogShipping {
+    fn default() -> Self {
+        Self {
+            sinks: Vec::new(),
+            queue_capacity: 10_000,
+        }
+    }
+}
+
+struct SinkQueue {
+    name: String,
+    tx: mpsc::Sender<Arc<LogRecord>>,
+}
+
+/// Fans log records out to every configured sink.
+pub struct LogShipper {
+    queues: Vec<SinkQueue>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogShipper {
+    /// Start a delivery task per sink in `config`, each running until
+    /// `close` is given and what was queued by then is shipped.
+    pub fn spawn(config: LogShipping, close: CloseSignal) -> (Self, Vec<JoinHandle<()>>) {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut queues = Vec::new();
+        let mut tasks = Vec::new();
+        for sink in config.sinks {
+            let name = sink.name();
+            let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+            tasks.push(tokio::spawn(deliver(sink, name.clone(), rx, dropped.clone(), close.clone())));
+            queues.push(SinkQueue { name, tx });
+        }
+        (Self { queues, dropped }, tasks)
+    }
+
+    /// Queue `record` on every sink, dropping it where the queue is full.
+    pub fn ship(&self, record: LogRecord) {
+        let record = Arc::new(record);
+        for queue in &self.queues {
+            if queue.tx.try_send(record.clone()).is_err() {
+                record_drops(&self.dropped, &queue.name, "queue_full", 1);
+            }
+        }
+    }
+
+    /// Records dropped across all sinks so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn record_drops(total: &AtomicU64, sink: &str, reason: &str, count: u64) {
+    total.fetch_add(count, Ordering::Relaxed);
+    DROPPED.with_label_values(&[sink, reason]).inc_by(count);
+}
+
+/// An open connection to a sink, re-established after a failure.
+enum Connection {
+    Datagram(UdpSocket),
+    Stream(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixDatagram),
+    Http(reqwest::Client),
+    File(tokio::fs::File),
+}
+
+/// Drains `rx` into `sink` until the shipper goes away or `close` is given.
+async fn deliver(
+    sink: LogSink,
+    name: String,
+    mut rx: mpsc::Receiver<Arc<LogRecord>>,
+    dropped: Arc<AtomicU64>,
+    close: CloseSignal,
+) {
+    let mut connection = None;
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut closed = false;
+    loop {
+        let received = tokio::select! {
+            biased;
+            received = rx.recv_many(&mut batch, MAX_BATCH) => received,
+            // Take no more, but still send what is queued
+            _ = close.closed(), if !closed => {
+                rx.close();
+                closed = true;
+                continue;
+            }
+        };
+        if received == 0 {
+            break;
+        }
+        match write_batch(&sink, &mut connection, &batch).await {
+            Ok(()) => SHIPPED.with_label_values(&[&name]).inc_by(batch.len() as u64),
+            Err(e) => {
+                tracing::warn!(records = batch.len(), sink = %name, error = %e, "failed to ship log records");
+                record_drops(&dropped, &name, "write_failed", batch.len() as u64);
+                connection = None;
+            }
+        }
+        batch.clear();
+    }
+}
+
+async fn connect(sink: &LogSink) -> std::io::Result<Connection> {
+    let bind_for = |addr: &SocketAddr| -> SocketAddr {
+        if addr.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0u16; 8], 0))
+        }
+    };
+    Ok(match sink {
+        LogSink::Syslog(syslog) => match &syslog.transport {
+            SyslogTransport::Udp(addr) => {
+                let socket = UdpSocket::bind(bind_for(addr)).await?;
+                socket.connect(addr).await?;
+                Connection::Datagram(socket)
+            }
+            SyslogTransport::Tcp(addr) => Connection::Stream(TcpStream::connect(addr).await?),
+            #[cfg(unix)]
+            SyslogTransport::Unix(path) => {
+                let socket = tokio::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Connection::Unix(socket)
+            }
+        },
+        LogSink::Tcp(addr) => Connection::Stream(TcpStream::connect(addr).await?),
+        LogSink::File(path) => {
+            Connection::File(tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?)
+        }
+        LogSink::Http { .. } => Connection::Http(
+            reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .map_err(std::io::Error::other)?,
+        ),
+    })
+}
+
+async fn write_batch(sink: &LogSink, connection: &mut Option<Connection>, batch: &[Arc<LogRecord>]) -> std::io::Result<()> {
+    if connection.is_none() {
+        *connection = Some(connect(sink).await?);
+    }
+    let connection = connection.as_mut().expect("connected above");
+    match (sink, connection) {
+        (LogSink::Syslog(syslog), Connection::Datagram(socket)) => {
+            for record in batch {
+                socket.send(syslog.format(record).as_bytes()).await?;
+            }
+        }
+        #[cfg(unix)]
+        (LogSink::Syslog(syslog), Connection::Unix(socket)) => {
+            for record in batch {
+                socket.send(syslog.format(record).as_bytes()).await?;
+            }
+        }
+        (LogSink::Syslog(syslog), Connection::Stream(stream)) => {
+            let mut out = String::new();
+            for record in batch {
+                let message = syslog.format(record);
+                out.push_str(&format!("{} {}", message.len(), message));
+            }
+            stream.write_all(out.as_bytes()).await?;
+        }
+        (LogSink::Tcp(_), Connection::Stream(stream)) => {
+            stream.write_all(json_lines(batch).as_bytes()).await?;
+        }
+        (LogSink::Http { url }, Connection::Http(client)) => {
+            client
+                .post(url)
+                .header("content-type", "application/x-ndjson")
+                .body(json_lines(batch))
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| std::io::Error::other(e.without_url()))?;
+        }
+        (LogSink::File(_), Connection::File(file)) => {
+            let lines = batch.iter().fold(String::new(), |mut out, record| {
+                out.push_str(&record.message);
+                out.push('\n');
+                out
+            });
+            file.write_all(lines.as_bytes()).await?;
+            file.flush().await?;
+        }
+        _ => unreachable!("connections are opened for their own sink"),
+    }
+    Ok(())
+}
+
+fn json_lines(batch: &[Arc<LogRecord>]) -> String {
+    batch.iter().fold(String::new(), |mut out, record| {
+        out.push_str(&record.json());
+        out.push('\n');
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            kind: LogKind::Access,
+            severity: LogSeverity::Info,
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_440_938_160_042),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_syslog_messages_follow_rfc_5424() {
+        let mut sink = SyslogSink::new(SyslogTransport::Udp("127.0.0.1:514".parse().unwrap()));
+        sink.hostname = "edge-1".to_string();
+        let line = sink.format(&record("GET / 200"));
+        let expected = format!("<134>1 2015-08-30T12:36:00.042Z edge-1 vortex {} access - GET / 200", std::process::id());
+        assert_eq!(line, expected);
+    }
+
+    #[tokio::test]
+    async fn test_records_reach_udp_syslog_and_tcp_collectors() {
+        let syslog = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = LogShipping {
+            sinks: vec![
+                LogSink::Syslog(SyslogSink::new(SyslogTransport::Udp(syslog.local_addr().unwrap()))),
+                LogSink::Tcp(collector.local_addr().unwrap()),
+            ],
+            ..LogShipping::default()
+        };
+        let (shipper, _tasks) = LogShipper::spawn(config, CloseSignal::new());
+        shipper.ship(record("first"));
+
+        let mut buf = [0u8; 1024];
+        let n = syslog.recv(&mut buf).await.unwrap();
+        assert!(std::str::from_utf8(&buf[..n]).unwrap().ends_with("access - first"));
+
+        let (mut stream, _) = collector.accept().await.unwrap();
+        let n = stream.read(&mut buf).await.unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&buf[..n]).unwrap();
+        assert_eq!(line["message"], "first");
+        assert_eq!(line["log"], "access");
+        assert_eq!(shipper.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_appends_plain_lines() {
+        let path = std::env::temp_dir().join(format!("vortex-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = LogShipping {
+            sinks: vec![LogSink::File(path.clone())],
+            ..LogShipping::default()
+        };
+        let (shipper, tasks) = LogShipper::spawn(config, CloseSignal::new());
+        shipper.ship(record("first"));
+        shipper.ship(record("second"));
+        drop(shipper);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_full_queues_drop_instead_of_blocking() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = LogShipping {
+            sinks: vec![LogSink::Tcp(collector.local_addr().unwrap())],
+            queue_capacity: 2,
+        };
+        let (shipper, _tasks) = LogShipper::spawn(config, CloseSignal::new());
+        // The delivery task can't run until this test yields
+        for i in 0..5 {
+            shipper.ship(record(&i.to_string()));
+        }
+        assert_eq!(shipper.dropped(), 3);
+    }
+}
@@ -0,0 +1,36 @@
+//! Process-wide log output.
+//!
+//! Every crate logs through `tracing`; this installs the subscriber that
+//! writes those events to stderr, either as human-readable text or as one
+//! JSON object per line for log shippers. Each accepted connection and each
+//! request runs inside a span (`connection` with the peer, `request` with
+//! method, path, route and backend), so their fields are attached to every
+//! event logged while handling them.
+
+use tracing_subscriber::EnvFilter;
+
+/// How log events are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Installs the global subscriber logging at `filter` (e.g. `info` or
+/// `vortex_proxy=debug,warn`). `RUST_LOG`, when set, takes precedence.
+///
+/// Fails if `filter` doesn't parse or a subscriber is already installed.
+pub fn init_logging(filter: &str, format: LogFormat) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(env) if !env.is_empty() => EnvFilter::try_new(env)?,
+        _ => EnvFilter::try_new(filter)?,
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_current_span(true).try_init(),
+    }
+}
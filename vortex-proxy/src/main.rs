@@ -1,24 +1,50 @@
 //! Vortex Proxy Engine
 //!
-//! The main Tokio async engine that manages socket binding, connection pooling, and request pipelining.
+//! Thin command line wrapper around the `vortex_proxy` library.
 
 #![deny(missing_docs)]
 
-use vortex_core;
-use vortex_filters;
-use vortex_admin;
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use vortex_admin::transport::AdminEndpoint;
+use vortex_core::config::ProxyConfig;
+use vortex_proxy::diagnostics::DumpTarget;
+use vortex_proxy::dns::{HostnamePool, SrvPool};
+use vortex_proxy::health_check::prober::HealthCheckConfig;
+use vortex_proxy::config_reload::ConfigReload;
+use vortex_proxy::logging::{self, LogFormat};
+use vortex_proxy::{config_check, dev_backend, selftest, tls, Vortex};
 
-mod server;
-mod tls;
-mod health_check;
-mod connection_pool;
+/// Command line interface for the Vortex binary.
+#[derive(Debug, Parser)]
+#[command(name = "vortex", version, about = "High-performance L7 reverse proxy")]
+struct Cli {
+    /// Listeners, clusters and routes to serve
+    #[arg(long, default_value = "vortex.toml")]
+    config: PathBuf,
+    /// Check the config file and exit, non-zero if it has problems
+    #[arg(long)]
+    validate_config: bool,
+    /// Which events to log, as `info` or per target like `vortex_proxy=debug,warn`; `RUST_LOG` overrides it
+    #[arg(long, default_value = "info")]
+    log_level: String,
+    /// Write log events as text lines or as JSON objects
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-use tokio_rustls::TlsAcceptor;
-use std::sync::Arc;
-use vortex_core::domain::backend::{Backend, BackendId};
-use vortex_core::domain::routing::RoutingTable;
-use crate::connection_pool::pool::ConnectionPool;
-use vortex_filters::wasm_engine::WasmEngine;
+/// Optional subcommands; without one, the proxy runs normally.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run an end-to-end smoke test against mock backends and report throughput/latency
+    Selftest(selftest::SelftestArgs),
+    /// Run echo backends for local development (ports 9090 and 9091 by default)
+    DevBackend(dev_backend::DevBackendArgs),
+}
 
 /// The primary entrypoint for the Vortex reverse proxy.
 ///
@@ -26,48 +52,98 @@ use vortex_filters::wasm_engine::WasmEngine;
 /// and begins listening for incoming TCP connections.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Starting Vortex Proxy Engine...");
+    let cli = Cli::parse();
+    logging::init_logging(&cli.log_level, cli.log_format).map_err(|e| e as Box<dyn std::error::Error>)?;
+
+    match cli.command {
+        Some(Command::Selftest(args)) => selftest::run(args).await.map_err(|e| e as Box<dyn std::error::Error>),
+        Some(Command::DevBackend(args)) => dev_backend::run(args).await.map_err(|e| e as Box<dyn std::error::Error>),
+        None if cli.validate_config => validate_config(&cli.config),
+        None => run_proxy(cli.config).await,
+    }
+}
+
+/// Reports every problem in the config file, one `file: location: message`
+/// line each, exiting with status 1 if there are any.
+fn validate_config(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let problems = config_check::check_config(path);
+    for problem in &problems {
+        eprintln!("{}: {}", path.display(), problem);
+    }
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+    println!("{}: OK", path.display());
+    Ok(())
+}
+
+/// Boots every subsystem and serves proxied traffic until the listener fails.
+async fn run_proxy(config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    tracing::info!("starting Vortex Proxy Engine");
 
     // Initialize core structural components
     vortex_core::core_init();
     vortex_filters::filters_init();
     vortex_admin::admin_init();
 
-    println!("Tokio asynchronous runtime initialized successfully.");
 
-    // Load TLS configuration
-    let tls_config = tls::load_tls_config("certs/cert.pem", "certs/key.pem")
-        .expect("Failed to load TLS configuration");
-    let tls_acceptor = TlsAcceptor::from(tls_config);
+    let config = ProxyConfig::load(&config_path)?;
+    tracing::info!(path = %config_path.display(), "loaded configuration");
 
-    // Prepare mock backends for Phase 2 implementation
-    let backends = vec![
-        Arc::new(Backend::new(BackendId(1), "127.0.0.1:9090".parse().unwrap())),
-        Arc::new(Backend::new(BackendId(2), "127.0.0.1:9091".parse().unwrap())),
-    ];
-    let routing_table = Arc::new(RoutingTable::new(backends));
+    // The first cluster is the default pool; routes pick theirs by longest path prefix
+    let (cluster, others) = config.clusters.split_first().ok_or("expected at least one cluster")?;
+    let routing_table = config.routing_table(&cluster.name).ok_or("cluster disappeared after validation")?;
+    let health_check = cluster.health_check.as_ref().map(HealthCheckConfig::from);
 
-    // Start background health-checker probing every 5 seconds
-    health_check::prober::spawn_health_checker(routing_table.clone(), 5000);
-
-    // Spawn the Control Plane API on a Unix Domain Socket
-    let admin_routing_table = routing_table.clone();
-    tokio::spawn(async move {
-        if let Err(e) = vortex_admin::server::start_admin_server("/tmp/vortex_admin.sock", admin_routing_table).await {
-            eprintln!("Admin gRPC server failed: {}", e);
-        }
-    });
+    let mut builder = Vortex::builder()
+        .routing_table(Arc::new(routing_table))
+        .admin_endpoint(AdminEndpoint::platform_default())
+        // `kill -QUIT <pid>` logs a runtime snapshot for triage
+        .diagnostic_dump(DumpTarget::Log);
+    for listener in &config.listeners {
+        builder = match &listener.tls {
+            Some(files) => {
+                let tls_config = tls::load_tls_config(&files.cert, &files.key)?;
+                builder.tls_listener(listener.address, TlsAcceptor::from(tls_config))
+            }
+            None => builder.listener(listener.address),
+        };
+    }
+    // Without routes every request goes to the pool
+    if !config.routes.is_empty() {
+        builder = builder.routes(config.route_specs());
+    }
+    if let Some(health_check) = health_check {
+        builder = builder.health_check(health_check);
+    }
+    if let Some(hostnames) = &cluster.hostnames {
+        builder = builder.hostname_pool(HostnamePool::from(hostnames));
+    }
+    if let Some(srv) = &cluster.srv {
+        builder = builder.srv_pool(SrvPool::from(srv));
+    }
+    for other in others {
+        let table = config.routing_table(&other.name).ok_or("cluster disappeared after validation")?;
+        let health_check = other.health_check.as_ref().map(HealthCheckConfig::from);
+        builder = builder.cluster(other.name.clone(), Arc::new(table), health_check);
+    }
+    if let Some(addr) = config.metrics_address {
+        builder = builder.metrics(addr);
+    }
 
-    let connection_pool = ConnectionPool::new();
-    let wasm_engine = Arc::new(WasmEngine::new());
+    // `kill -HUP <pid>` or editing the file applies changes in place
+    if cluster.hostnames.is_some() || cluster.srv.is_some() {
+        tracing::warn!("configuration reloading needs listed backends; changes need a restart");
+    } else {
+        builder = builder.config_reload(ConfigReload::new(config_path, config));
+    }
 
-    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], 8443));
+    let handle = builder.start().await?;
 
-    // Start the server with the TLS Acceptor, routing table, hot pool, and Wasm runtime
-    if let Err(e) = server::start_server(addr, Some(tls_acceptor), routing_table, connection_pool, wasm_engine).await {
-        eprintln!("Server failed: {}", e);
+    if let Err(e) = handle.wait().await {
+        tracing::error!(error = %e, "server failed");
     }
 
-    println!("Shutting down gracefully.");
+    tracing::info!("shutting down gracefully");
     Ok(())
 }
@@ -0,0 +1,438 @@
+//! Prometheus metrics registry and scrape endpoint.
+//!
+//! Subsystems register their collectors with the default `prometheus` registry;
+//! `serve_metrics` exposes everything gathered there on `GET /metrics`.
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, TextEncoder};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use vortex_core::domain::labels::Labels;
+use vortex_core::stats::PoolStatsSource;
+
+use crate::error::ProxyError;
+
+/// Requests that ended in a proxy-generated error, labeled by `ProxyError::kind`.
+pub static REQUEST_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_request_errors_total",
+        "Requests that failed inside the proxy, by error kind",
+        &["kind"]
+    )
+    .expect("metric registers once")
+});
+
+/// Upstream exchanges abandoned midway (a losing hedge, a client that went
+/// away); their connections are closed rather than returned to the pool.
+pub static UPSTREAM_CANCELLED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "vortex_upstream_requests_cancelled_total",
+        "Upstream exchanges cancelled before the response headers arrived"
+    )
+    .expect("metric registers once")
+});
+
+/// Downstream connections currently being served.
+pub static ACTIVE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "vortex_active_connections",
+        "Downstream connections currently open"
+    )
+    .expect("metric registers once")
+});
+
+/// File descriptors the process had open at the last guardrail sample.
+pub static OPEN_FDS: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "vortex_open_fds",
+        "File descriptors open in the proxy process"
+    )
+    .expect("metric registers once")
+});
+
+/// The process's soft file descriptor limit, when guardrails are enabled.
+pub static FD_LIMIT: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "vortex_fd_limit",
+        "Soft RLIMIT_NOFILE of the proxy process"
+    )
+    .expect("metric registers once")
+});
+
+/// Downstream connections closed on accept by listener access control, by
+/// `reason`: `source` (address not allowed), `disabled` (listener switched
+/// off) or `fd_limit` (file descriptors near exhaustion).
+pub static CONNECTIONS_REFUSED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_connections_refused_total",
+        "Downstream connections refused by listener access control",
+        &["reason"]
+    )
+    .expect("metric registers once")
+});
+
+/// Raw TCP tunnels handed to the in-kernel fast path instead of copied in userland.
+pub static TUNNELS_OFFLOADED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "vortex_tcp_tunnels_offloaded_total",
+        "Raw TCP tunnels forwarded by the kernel fast path"
+    )
+    .expect("metric registers once")
+});
+
+/// Wasm filter circuit breaker state changes, by `filter` and `event`
+/// (`opened` or `closed`).
+pub static FILTER_BREAKER_EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_filter_breaker_events_total",
+        "Wasm filter circuit breakers opening and closing",
+        &["filter", "event"]
+    )
+    .expect("metric registers once")
+});
+
+/// Per-request counters and latency histograms carrying the operator's
+/// observability dimensions.
+///
+/// Prometheus needs a fixed label set per metric, so the dimension keys (e.g.
+/// `team`, `service`, `environment`) are declared once at startup. Each request
+/// fills them from its route's `Labels` layered over its pool's; missing keys
+/// export as an empty value.
+#[derive(Clone)]
+pub struct RequestMetrics {
+    dimensions: Arc<[String]>,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    totals: Arc<OutcomeTotals>,
+}
+
+/// Label-free running totals, cheap to sample for alerting.
+#[derive(Debug, Default)]
+struct OutcomeTotals {
+    requests: AtomicU64,
+    server_errors: AtomicU64,
+}
+
+impl RequestMetrics {
+    /// Create unregistered request metrics labeled by `status` plus `dimensions`.
+    pub fn new<I, S>(dimensions: I) -> prometheus::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let dimensions: Arc<[String]> = dimensions.into_iter().map(Into::into).collect();
+        let dims: Vec<&str> = dimensions.iter().map(String::as_str).collect();
+        let status_and_dims: Vec<&str> = std::iter::once("status").chain(dims.iter().copied()).collect();
+
+        let requests = IntCounterVec::new(
+            Opts::new("vortex_requests_total", "Requests handled by the proxy, by response status"),
+            &status_and_dims,
+        )?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new("vortex_request_duration_seconds", "End-to-end request latency through the proxy"),
+            &dims,
+        )?;
+
+        Ok(Self { dimensions, requests, duration, totals: Arc::default() })
+    }
+
+    /// Register with the default registry so the metrics appear on `/metrics`.
+    pub fn register(&self) -> prometheus::Result<()> {
+        prometheus::register(Box::new(self.requests.clone()))?;
+        prometheus::register(Box::new(self.duration.clone()))
+    }
+
+    /// Record one finished request.
+    pub fn observe(&self, labels: &Labels, status: StatusCode, elapsed: Duration) {
+        let values: Vec<&str> = self.dimensions.iter().map(|key| labels.get(key).unwrap_or("")).collect();
+        self.duration.with_label_values(&values).observe(elapsed.as_secs_f64());
+
+        self.totals.requests.fetch_add(1, Ordering::Relaxed);
+        if status.is_server_error() {
+            self.totals.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let status = status.as_str();
+        let status_and_values: Vec<&str> = std::iter::once(status).chain(values).collect();
+        self.requests.with_label_values(&status_and_values).inc();
+    }
+
+    /// Total requests and 5xx responses recorded since startup.
+    pub fn outcome_totals(&self) -> (u64, u64) {
+        (
+            self.totals.requests.load(Ordering::Relaxed),
+            self.totals.server_errors.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The number of requests recorded with `status` and exactly these label values.
+    pub fn request_count(&self, labels: &Labels, status: StatusCode) -> u64 {
+        let status_and_values: Vec<&str> = std::iter::once(status.as_str())
+            .chain(self.dimensions.iter().map(|key| labels.get(key).unwrap_or("")))
+            .collect();
+        self.requests.with_label_values(&status_and_values).get()
+    }
+}
+
+/// Exports connection pool statistics, read fresh from the pool on every scrape.
+pub struct PoolCollector {
+    source: Arc<dyn PoolStatsSource>,
+    idle: IntGaugeVec,
+    avg_connection_age: IntGaugeVec,
+    created: IntCounterVec,
+    reused: IntCounterVec,
+    closed: IntCounterVec,
+    descs: Vec<Desc>,
+    /// Held from resetting the vectors until they are read, so concurrent
+    /// scrapes don't add up each other's counts
+    scrape: Mutex<()>,
+}
+
+impl PoolCollector {
+    /// Create a collector reading from the given pool.
+    pub fn new(source: Arc<dyn PoolStatsSource>) -> prometheus::Result<Self> {
+        // Backends can share an address, so each series names the backend's ID too
+        let labels = &["backend", "backend_id"];
+        let idle = IntGaugeVec::new(
+            Opts::new("vortex_pool_idle_connections", "Idle upstream connections waiting in the pool"),
+            labels,
+        )?;
+        let avg_connection_age = IntGaugeVec::new(
+            Opts::new(
+                "vortex_pool_connection_age_milliseconds",
+                "Mean time since the idle upstream connections were established",
+            ),
+            labels,
+        )?;
+        let created = IntCounterVec::new(
+            Opts::new("vortex_pool_connections_created_total", "Upstream connections established"),
+            labels,
+        )?;
+        let reused = IntCounterVec::new(
+            Opts::new("vortex_pool_connections_reused_total", "Idle upstream connections handed out for reuse"),
+            labels,
+        )?;
+        let closed = IntCounterVec::new(
+            Opts::new("vortex_pool_connections_closed_total", "Upstream connections retired from the pool"),
+            labels,
+        )?;
+
+        let descs = [&idle.desc(), &avg_connection_age.desc(), &created.desc(), &reused.desc(), &closed.desc()]
+            .into_iter()
+            .flatten()
+            .map(|d| (*d).clone())
+            .collect();
+
+        Ok(Self { source, idle, avg_connection_age, created, reused, closed, descs, scrape: Mutex::new(()) })
+    }
+}
+
+impl Collector for PoolCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _scrape = self.scrape.lock().unwrap_or_else(|e| e.into_inner());
+        for vec in [&self.idle, &self.avg_connection_age] {
+            vec.reset();
+        }
+        for vec in [&self.created, &self.reused, &self.closed] {
+            vec.reset();
+        }
+
+        for stats in self.source.pool_stats() {
+            let (backend, backend_id) = (stats.addr.to_string(), stats.backend.0.to_string());
+            let labels = &[backend.as_str(), backend_id.as_str()];
+            self.idle.with_label_values(labels).set(stats.idle as i64);
+            self.avg_connection_age
+                .with_label_values(labels)
+                .set(stats.avg_connection_age.as_millis() as i64);
+            self.created.with_label_values(labels).inc_by(stats.created);
+            self.reused.with_label_values(labels).inc_by(stats.reused);
+            self.closed.with_label_values(labels).inc_by(stats.closed);
+        }
+
+        let mut families = Vec::new();
+        families.extend(self.idle.collect());
+        families.extend(self.avg_connection_age.collect());
+        families.extend(self.created.collect());
+        families.extend(self.reused.collect());
+        families.extend(self.closed.collect());
+        families
+    }
+}
+
+/// Exports the not-after time of every served TLS certificate, read from the
+/// `tls` registry on every scrape.
+pub struct CertCollector {
+    not_after: GaugeVec,
+}
+
+impl CertCollector {
+    /// Create the certificate expiry collector.
+    pub fn new() -> prometheus::Result<Self> {
+        let not_after = GaugeVec::new(
+            Opts::new("vortex_tls_cert_not_after_seconds", "Unix time at which a served certificate expires"),
+            &["source", "subject"],
+        )?;
+        Ok(Self { not_after })
+    }
+}
+
+impl Collector for CertCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.not_after.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.not_after.reset();
+        for cert in crate::tls::loaded_certificates() {
+            let not_after = cert.not_after.duration_since(UNIX_EPOCH).unwrap_or_default();
+            self.not_after
+                .with_label_values(&[cert.source.as_str(), cert.subject.as_str()])
+                .set(not_after.as_secs_f64());
+        }
+        self.not_after.collect()
+    }
+}
+
+/// Serves the Prometheus text exposition format on `GET /metrics`.
+pub async fn serve_metrics(addr: SocketAddr) -> Result<(), ProxyError> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!(%addr, "serving Prometheus metrics");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(handle_scrape))
+                .await
+            {
+                tracing::debug!(error = %err, "error serving metrics connection");
+            }
+        });
+    }
+}
+
+async fn handle_scrape(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut res = Response::new(Full::new(Bytes::from_static(b"not found\n")));
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        tracing::error!(error = %e, "failed to encode metrics");
+    }
+
+    let mut res = Response::new(Full::new(Bytes::from(buf)));
+    res.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        encoder.format_type().parse().expect("static content type"),
+    );
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vortex_core::domain::backend::BackendId;
+    use vortex_core::stats::PoolStats;
+
+    struct FixedPool;
+
+    impl PoolStatsSource for FixedPool {
+        fn pool_stats(&self) -> Vec<PoolStats> {
+            vec![PoolStats {
+                backend: BackendId(1),
+                addr: "127.0.0.1:9000".parse().unwrap(),
+                idle: 2,
+                created: 7,
+                reused: 40,
+                closed: 5,
+                avg_connection_age: Duration::from_millis(1500),
+            }]
+        }
+    }
+
+    #[test]
+    fn test_concurrent_scrapes_report_the_pool_as_it_is() {
+        let collector = Arc::new(PoolCollector::new(Arc::new(FixedPool)).unwrap());
+        let scrapes: Vec<_> = (0..8)
+            .map(|_| {
+                let collector = collector.clone();
+                std::thread::spawn(move || (0..50).map(|_| collector.collect()).collect::<Vec<_>>())
+            })
+            .collect();
+        for families in scrapes.into_iter().flat_map(|scrape| scrape.join().unwrap()) {
+            let value = |name: &str| {
+                let family = families.iter().find(|f| f.get_name() == name).unwrap();
+                let metric = &family.get_metric()[0];
+                if metric.has_counter() { metric.get_counter().get_value() } else { metric.get_gauge().get_value() }
+            };
+            assert_eq!(value("vortex_pool_connections_created_total"), 7.0);
+            assert_eq!(value("vortex_pool_connections_reused_total"), 40.0);
+            assert_eq!(value("vortex_pool_connection_age_milliseconds"), 1500.0);
+        }
+    }
+
+    struct SharedAddress;
+
+    impl PoolStatsSource for SharedAddress {
+        fn pool_stats(&self) -> Vec<PoolStats> {
+            let backend = |id, idle, created| PoolStats {
+                backend: BackendId(id),
+                addr: "127.0.0.1:9000".parse().unwrap(),
+                idle,
+                created,
+                reused: 0,
+                closed: 0,
+                avg_connection_age: Duration::from_millis(100 * id as u64),
+            };
+            vec![backend(1, 2, 7), backend(2, 3, 4)]
+        }
+    }
+
+    #[test]
+    fn test_backends_sharing_an_address_keep_their_own_series() {
+        let families = PoolCollector::new(Arc::new(SharedAddress)).unwrap().collect();
+        let series = |name: &str| -> Vec<(String, f64)> {
+            let family = families.iter().find(|f| f.get_name() == name).unwrap();
+            let mut series: Vec<_> = family
+                .get_metric()
+                .iter()
+                .map(|metric| {
+                    let id = metric.get_label().iter().find(|l| l.get_name() == "backend_id").unwrap();
+                    let value = if metric.has_counter() {
+                        metric.get_counter().get_value()
+                    } else {
+                        metric.get_gauge().get_value()
+                    };
+                    (id.get_value().to_string(), value)
+                })
+                .collect();
+            series.sort_by(|a, b| a.0.cmp(&b.0));
+            series
+        };
+        assert_eq!(series("vortex_pool_idle_connections"), [("1".to_string(), 2.0), ("2".to_string(), 3.0)]);
+        assert_eq!(series("vortex_pool_connections_created_total"), [("1".to_string(), 7.0), ("2".to_string(), 4.0)]);
+        assert_eq!(
+            series("vortex_pool_connection_age_milliseconds"),
+            [("1".to_string(), 100.0), ("2".to_string(), 200.0)]
+        );
+    }
+}
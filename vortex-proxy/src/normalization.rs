@@ -0,0 +1,385 @@
+//! Request normalization, and telemetry of what it changes.
+//!
+//! Requests are tidied up before they are routed, so routes, filters and
+//! backends all see one canonical form: `/a/./b/../c` and `//a//c` become
+//! `/a/c`, `%41` becomes `A`, and hop-by-hop headers (plus whatever the
+//! client names in `Connection`) are dropped instead of forwarded. A request
+//! carrying both `Transfer-Encoding` and `Content-Length`, the classic
+//! smuggling setup, loses the `Content-Length` it was not framed by.
+//!
+//! None of this is silent: each kind of fix-up is counted, and a sample of
+//! the requests that needed it is logged at most once per `sample_interval`
+//! per kind, with how many went unlogged in between, so attack pressure
+//! shows up without flooding the logs. `Connection` itself is kept, since
+//! its `close` decides whether the upstream connection may be reused.
+
+use hyper::header::{HeaderName, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::pipeline::{replace_path, ClientAddr, ProxyFuture, ProxyRequest, ProxyResponse};
+
+/// Requests changed by normalization, by `kind` of fix-up
+static NORMALIZED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_request_normalizations_total",
+        "Requests changed by normalization, by kind of fix-up",
+        &["kind"]
+    )
+    .expect("metric registers once")
+});
+
+/// Headers that only concern one connection and are never forwarded.
+/// `Trailer` is not one of them: it announces trailer fields end to end
+/// (RFC 9110 §6.6.2).
+const HOP_BY_HOP: [&str; 3] = ["keep-alive", "proxy-connection", "te"];
+
+/// What normalization does to requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestNormalization {
+    /// Resolve dot segments, collapse repeated slashes and decode
+    /// percent-encoded unreserved characters in paths
+    pub paths: bool,
+    /// Drop hop-by-hop headers and the headers `Connection` names
+    pub hop_by_hop: bool,
+    /// Shortest time between two logged samples of one kind of fix-up
+    pub sample_interval: Duration,
+}
+
+impl Default for RequestNormalization {
+    fn default() -> Self {
+        Self { paths: true, hop_by_hop: true, sample_interval: Duration::from_secs(10) }
+    }
+}
+
+/// A kind of change normalization makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Fixup {
+    /// `Content-Length` dropped next to `Transfer-Encoding`
+    ContentLengthWithTransferEncoding,
+    /// Hop-by-hop or `Connection`-named headers dropped
+    HopByHopHeader,
+    /// `.` or `..` path segments resolved
+    DotSegments,
+    /// Empty path segments collapsed
+    RepeatedSlashes,
+    /// Percent-encoded letters, digits, `-`, `.`, `_` or `~` decoded
+    EncodedUnreserved,
+}
+
+impl Fixup {
+    fn label(self) -> &'static str {
+        match self {
+            Fixup::ContentLengthWithTransferEncoding => "content_length_with_transfer_encoding",
+            Fixup::HopByHopHeader => "hop_by_hop_header",
+            Fixup::DotSegments => "dot_segments",
+            Fixup::RepeatedSlashes => "repeated_slashes",
+            Fixup::EncodedUnreserved => "encoded_unreserved",
+        }
+    }
+}
+
+impl RequestNormalization {
+    /// Normalize `req`, returning each fix-up made with what it changed.
+    fn normalize(&self, req: &mut ProxyRequest) -> Vec<(Fixup, String)> {
+        let mut fixups = Vec::new();
+        let headers = req.headers_mut();
+        if headers.contains_key(TRANSFER_ENCODING) && headers.contains_key(CONTENT_LENGTH) {
+            headers.remove(CONTENT_LENGTH);
+            fixups.push((Fixup::ContentLengthWithTransferEncoding, "content-length".to_string()));
+        }
+
+        if self.hop_by_hop {
+            let nominated: Vec<HeaderName> = headers
+                .get_all(CONNECTION)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+                // Framing and the host are never the client's to take away
+                .filter(|name| ![CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING, HOST].contains(name))
+                .collect();
+            let mut dropped = Vec::new();
+            for name in HOP_BY_HOP.map(HeaderName::from_static).into_iter().chain(nominated) {
+                if headers.remove(&name).is_some() {
+                    dropped.push(name.to_string());
+                }
+            }
+            if !dropped.is_empty() {
+                fixups.push((Fixup::HopByHopHeader, dropped.join(", ")));
+            }
+        }
+
+        if self.paths {
+            let original = req.uri().path();
+            let (path, path_fixups) = normalize_path(original);
+            if !path_fixups.is_empty() {
+                let change = format!("{} -> {}", original, path);
+                if replace_path(req, &path).is_ok() {
+                    fixups.extend(path_fixups.into_iter().map(|fixup| (fixup, change.clone())));
+                }
+            }
+        }
+        fixups
+    }
+}
+
+/// `path` with percent-encoded unreserved characters decoded, then dot
+/// segments resolved and empty segments collapsed, and what that took.
+fn normalize_path(path: &str) -> (String, Vec<Fixup>) {
+    let mut fixups = Vec::new();
+    let Some(rest) = path.strip_prefix('/') else {
+        // `*` and authority forms have no segments
+        return (path.to_string(), fixups);
+    };
+
+    let bytes = rest.as_bytes();
+    let mut decoded = Vec::with_capacity(rest.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let unreserved = (bytes[i] == b'%')
+            .then(|| rest.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+            .flatten()
+            .filter(|b| b.is_ascii_alphanumeric() || b"-._~".contains(b));
+        match unreserved {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                // Anything else, UTF-8 sent raw included, is copied byte for byte
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    if decoded.len() != rest.len() {
+        fixups.push(Fixup::EncodedUnreserved);
+    }
+    // Only whole escapes were replaced, by ASCII, so no character was split
+    let Ok(decoded) = String::from_utf8(decoded) else {
+        return (path.to_string(), Vec::new());
+    };
+
+    let segments: Vec<&str> = decoded.split('/').collect();
+    let last = segments.len() - 1;
+    let mut kept: Vec<&str> = Vec::with_capacity(segments.len());
+    let (mut dots, mut slashes) = (false, false);
+    for (i, segment) in segments.into_iter().enumerate() {
+        match segment {
+            "." | ".." => {
+                dots = true;
+                if segment == ".." {
+                    kept.pop();
+                }
+                // A trailing dot segment leaves a directory behind
+                if i == last {
+                    kept.push("");
+                }
+            }
+            "" if i != last => slashes = true,
+            segment => kept.push(segment),
+        }
+    }
+    if dots {
+        fixups.push(Fixup::DotSegments);
+    }
+    if slashes {
+        fixups.push(Fixup::RepeatedSlashes);
+    }
+    (format!("/{}", kept.join("/")), fixups)
+}
+
+/// When each kind of fix-up was last logged, and how many went unlogged since.
+#[derive(Debug, Default)]
+struct Samples(Mutex<HashMap<Fixup, (Instant, u64)>>);
+
+impl Samples {
+    /// Whether a request needing `fixup` now is logged, and if so how many
+    /// went unlogged since the last one.
+    fn take(&self, fixup: Fixup, interval: Duration, now: Instant) -> Option<u64> {
+        let mut samples = self.0.lock().expect("samples lock poisoned");
+        match samples.get_mut(&fixup) {
+            Some((last, skipped)) if now.duration_since(*last) < interval => {
+                *skipped += 1;
+                None
+            }
+            Some((last, skipped)) => {
+                *last = now;
+                Some(std::mem::take(skipped))
+            }
+            None => {
+                samples.insert(fixup, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+/// Normalizes requests per `RequestNormalization` before they are routed.
+#[derive(Debug, Clone)]
+pub struct NormalizationLayer {
+    config: Arc<RequestNormalization>,
+    samples: Arc<Samples>,
+}
+
+impl NormalizationLayer {
+    /// Create a layer normalizing requests per `config`.
+    pub fn new(config: RequestNormalization) -> Self {
+        Self { config: Arc::new(config), samples: Arc::default() }
+    }
+}
+
+impl<S> Layer<S> for NormalizationLayer {
+    type Service = NormalizationService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        NormalizationService { inner, config: self.config.clone(), samples: self.samples.clone() }
+    }
+}
+
+/// Service produced by `NormalizationLayer`.
+#[derive(Clone)]
+pub struct NormalizationService<S> {
+    inner: S,
+    config: Arc<RequestNormalization>,
+    samples: Arc<Samples>,
+}
+
+impl<S> Service<ProxyRequest> for NormalizationService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let fixups = self.config.normalize(&mut req);
+        let now = Instant::now();
+        for (fixup, change) in fixups {
+            NORMALIZED.with_label_values(&[fixup.label()]).inc();
+            if let Some(unlogged) = self.samples.take(fixup, self.config.sample_interval, now) {
+                let client = req.extensions().get::<ClientAddr>().map(|ClientAddr(addr)| addr.ip());
+                tracing::warn!(
+                    kind = fixup.label(),
+                    client = ?client,
+                    method = %req.method(),
+                    %change,
+                    unlogged,
+                    "request normalized"
+                );
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use hyper::Request;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> ProxyRequest {
+        let mut builder = Request::get(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(full_body(hyper::body::Bytes::new())).unwrap()
+    }
+
+    #[test]
+    fn test_paths_are_normalized() {
+        let cases = [
+            ("/a/b", "/a/b", vec![]),
+            ("/", "/", vec![]),
+            ("/a/./b/../c", "/a/c", vec![Fixup::DotSegments]),
+            ("/a/..", "/", vec![Fixup::DotSegments]),
+            ("/../../etc/passwd", "/etc/passwd", vec![Fixup::DotSegments]),
+            ("//a//b/", "/a/b/", vec![Fixup::RepeatedSlashes]),
+            ("/%41%7e/%2F", "/A~/%2F", vec![Fixup::EncodedUnreserved]),
+            ("/public/%2e%2e/admin", "/admin", vec![Fixup::EncodedUnreserved, Fixup::DotSegments]),
+            ("/caf\u{e9}/%2e/men\u{fc}", "/caf\u{e9}/men\u{fc}", vec![Fixup::EncodedUnreserved, Fixup::DotSegments]),
+        ];
+        for (path, expected, fixups) in cases {
+            assert_eq!(normalize_path(path), (expected.to_string(), fixups), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_smuggling_and_hop_by_hop_headers_are_stripped() {
+        let config = RequestNormalization::default();
+        let mut req = request(
+            "/api//users?id=1",
+            &[
+                ("transfer-encoding", "chunked"),
+                ("content-length", "5"),
+                ("connection", "keep-alive, x-forwarded-for, host"),
+                ("x-forwarded-for", "10.0.0.1"),
+                ("proxy-connection", "keep-alive"),
+            ],
+        );
+        let fixups = config.normalize(&mut req);
+        let kinds: Vec<Fixup> = fixups.iter().map(|(fixup, _)| *fixup).collect();
+        assert_eq!(kinds, [Fixup::ContentLengthWithTransferEncoding, Fixup::HopByHopHeader, Fixup::RepeatedSlashes]);
+        assert_eq!(fixups[1].1, "proxy-connection, x-forwarded-for");
+        assert!(!req.headers().contains_key(CONTENT_LENGTH));
+        assert!(req.headers().contains_key(CONNECTION) && req.headers().contains_key(TRANSFER_ENCODING));
+        assert_eq!(req.uri().path_and_query().unwrap().as_str(), "/api/users?id=1");
+
+        // Each part can be left alone
+        let config = RequestNormalization { paths: false, hop_by_hop: false, ..config };
+        let mut req = request("/a//b", &[("keep-alive", "timeout=5")]);
+        assert!(config.normalize(&mut req).is_empty());
+        assert!(req.headers().contains_key("keep-alive"));
+    }
+
+    #[test]
+    fn test_trailer_announcements_are_forwarded() {
+        let config = RequestNormalization::default();
+        let mut req = request("/upload", &[("trailer", "x-checksum"), ("te", "trailers")]);
+        let fixups = config.normalize(&mut req);
+        assert_eq!(fixups.iter().map(|(_, sample)| sample.as_str()).collect::<Vec<_>>(), ["te"]);
+        assert_eq!(req.headers()["trailer"], "x-checksum");
+    }
+
+    #[tokio::test]
+    async fn test_raw_non_ascii_paths_pass_through_unchanged() {
+        use tower::ServiceExt;
+
+        let upstream = tower::service_fn(|req: ProxyRequest| async move {
+            assert_eq!(req.uri().path().as_bytes(), b"/caf\xc3\xa9/\xe2\x82\xac");
+            Ok::<_, ProxyError>(hyper::Response::new(full_body(hyper::body::Bytes::new())))
+        });
+        let service = NormalizationLayer::new(RequestNormalization::default()).layer(upstream);
+        let before = NORMALIZED.with_label_values(&[Fixup::EncodedUnreserved.label()]).get();
+        let uri = hyper::Uri::from_maybe_shared(hyper::body::Bytes::from_static(b"/caf\xc3\xa9/\xe2\x82\xac")).unwrap();
+        let req = Request::get(uri).body(full_body(hyper::body::Bytes::new())).unwrap();
+        service.oneshot(req).await.unwrap();
+        assert_eq!(NORMALIZED.with_label_values(&[Fixup::EncodedUnreserved.label()]).get(), before);
+    }
+
+    #[test]
+    fn test_samples_are_logged_once_per_interval() {
+        let samples = Samples::default();
+        let interval = Duration::from_secs(10);
+        let start = Instant::now();
+        assert_eq!(samples.take(Fixup::DotSegments, interval, start), Some(0));
+        assert_eq!(samples.take(Fixup::DotSegments, interval, start + Duration::from_secs(1)), None);
+        assert_eq!(samples.take(Fixup::DotSegments, interval, start + Duration::from_secs(2)), None);
+        // Other kinds are sampled on their own
+        assert_eq!(samples.take(Fixup::HopByHopHeader, interval, start), Some(0));
+        assert_eq!(samples.take(Fixup::DotSegments, interval, start + interval), Some(2));
+    }
+}
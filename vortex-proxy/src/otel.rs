@@ -0,0 +1,718 @@
+//! OpenTelemetry trace export over OTLP/HTTP.
+//!
+//! Each connection gets a `downstream.accept` span (with a `tls.handshake`
+//! child on TLS listeners), sampled at `OtlpExport::connection_ratio` since
+//! no request has been seen yet. Each request gets a `proxy.request` server
+//! span continuing the client's W3C `traceparent`, if any, linked to its
+//! connection's span and carrying the matched route's labels as
+//! `vortex.label.<key>` attributes, with `backend.select`, `upstream.connect`
+//! (only when a new connection is dialed) and `upstream.request` children.
+//! The upstream request carries a `traceparent` naming the `upstream.request`
+//! span, so the backend's spans join the same trace.
+//!
+//! A request's spans are held until it finishes and exported together if its
+//! trace is sampled: by the `SamplingPolicy` when one is configured (which
+//! may keep failed requests), otherwise by the incoming `traceparent`,
+//! defaulting to sampled. Spans are queued without blocking and POSTed in
+//! batches as OTLP JSON; spans that don't fit in the queue or fail to export
+//! are dropped and counted in `vortex_otlp_spans_dropped_total`.
+
+use hyper::header::{HeaderMap, HeaderValue};
+use prometheus::{IntCounter, IntCounterVec};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::lifecycle::CloseSignal;
+use crate::pipeline::{take_inner, ProxyFuture, ProxyRequest, ProxyResponse, RouteLabels};
+use crate::request_id::RequestId;
+
+/// The default OTLP/HTTP traces endpoint of a local collector.
+pub const DEFAULT_OTLP_ENDPOINT: &str = "http://127.0.0.1:4318/v1/traces";
+
+/// Most spans POSTed at once.
+const MAX_BATCH: usize = 512;
+
+/// Spans delivered to the collector
+static EXPORTED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!("vortex_otlp_spans_exported_total", "Spans delivered to the OTLP collector")
+        .expect("metric registers once")
+});
+
+/// Spans lost, by `reason`: `queue_full` or `export_failed`
+static DROPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_otlp_spans_dropped_total",
+        "Spans that could not be queued or exported",
+        &["reason"]
+    )
+    .expect("metric registers once")
+});
+
+/// Where spans are exported and how many may queue up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtlpExport {
+    /// The collector's OTLP/HTTP traces endpoint
+    pub endpoint: String,
+    /// The `service.name` resource attribute
+    pub service_name: String,
+    /// Spans that may wait for export before new ones are dropped
+    pub queue_capacity: usize,
+    /// Fraction (0.0-1.0) of connections whose accept and TLS handshake are traced
+    pub connection_ratio: f64,
+}
+
+impl Default for OtlpExport {
+    fn default() -> Self {
+        Self {
+            endpoint: DEFAULT_OTLP_ENDPOINT.to_string(),
+            service_name: "vortex".to_string(),
+            queue_capacity: 10_000,
+            connection_ratio: 0.01,
+        }
+    }
+}
+
+/// A position in a trace: the W3C trace context of one span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The trace the span belongs to
+    pub trace_id: [u8; 16],
+    /// The span itself
+    pub span_id: [u8; 8],
+    /// Whether the trace is recorded
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The first span of a new trace.
+    pub fn root(sampled: bool) -> Self {
+        Self {
+            trace_id: random_id(),
+            span_id: random_id(),
+            sampled,
+        }
+    }
+
+    /// A new span in the same trace.
+    pub fn child(&self) -> Self {
+        Self { span_id: random_id(), ..*self }
+    }
+
+    /// Parses a version 00 `traceparent` header, if present and valid.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get("traceparent")?.to_str().ok()?;
+        let mut parts = value.split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || parts.next().is_some() {
+            return None;
+        }
+        let trace_id: [u8; 16] = parse_hex(trace_id)?;
+        let span_id: [u8; 8] = parse_hex(span_id)?;
+        let [flags]: [u8; 1] = parse_hex(flags)?;
+        // All-zero IDs are invalid per the spec
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 0x01 == 0x01,
+        })
+    }
+
+    /// The `traceparent` header naming this span as the parent.
+    pub fn traceparent(&self) -> HeaderValue {
+        let value = format!("00-{}-{}-0{}", hex(&self.trace_id), hex(&self.span_id), u8::from(self.sampled));
+        HeaderValue::from_str(&value).expect("hex digits are valid header values")
+    }
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut rng = rand::thread_rng();
+    loop {
+        let id: [u8; N] = std::array::from_fn(|_| rng.gen());
+        if id != [0; N] {
+            return id;
+        }
+    }
+}
+
+fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// What a span represents, as OTLP classifies it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// Work inside the proxy
+    Internal,
+    /// Handling a downstream request
+    Server,
+    /// A request to a backend
+    Client,
+}
+
+impl SpanKind {
+    fn code(self) -> u8 {
+        match self {
+            SpanKind::Internal => 1,
+            SpanKind::Server => 2,
+            SpanKind::Client => 3,
+        }
+    }
+}
+
+/// A span attribute value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    /// Text
+    String(String),
+    /// An integer
+    Int(i64),
+    /// A flag
+    Bool(bool),
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<u32> for AttributeValue {
+    fn from(value: u32) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+/// A finished (or in-progress) span.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanData {
+    /// What the span measures, e.g. `upstream.connect`
+    pub name: &'static str,
+    /// How OTLP classifies it
+    pub kind: SpanKind,
+    /// The span's own trace context
+    pub context: TraceContext,
+    /// The parent span, if any
+    pub parent_span_id: Option<[u8; 8]>,
+    /// Spans in other traces this one relates to
+    pub links: Vec<TraceContext>,
+    /// When it started
+    pub start: SystemTime,
+    /// When it ended
+    pub end: SystemTime,
+    /// Key-value details
+    pub attributes: Vec<(Cow<'static, str>, AttributeValue)>,
+    /// Why it failed, if it did
+    pub error: Option<String>,
+}
+
+impl SpanData {
+    /// Starts a span now, as a child of `parent` if given.
+    pub fn start(name: &'static str, kind: SpanKind, context: TraceContext, parent: Option<&TraceContext>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            name,
+            kind,
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+            links: Vec::new(),
+            start: now,
+            end: now,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Adds an attribute.
+    pub fn set_attribute(&mut self, key: impl Into<Cow<'static, str>>, value: impl Into<AttributeValue>) {
+        self.attributes.push((key.into(), value.into()));
+    }
+
+    /// Marks the span as failed.
+    pub fn set_error(&mut self, err: &impl Display) {
+        self.error = Some(err.to_string());
+    }
+
+    /// Ends the span now.
+    pub fn finish(&mut self) {
+        self.end = SystemTime::now();
+    }
+
+    fn json(&self) -> Value {
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    AttributeValue::String(s) => json!({ "stringValue": s }),
+                    AttributeValue::Int(i) => json!({ "intValue": i.to_string() }),
+                    AttributeValue::Bool(b) => json!({ "boolValue": b }),
+                };
+                json!({ "key": key, "value": value })
+            })
+            .collect();
+        let mut span = json!({
+            "traceId": hex(&self.context.trace_id),
+            "spanId": hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind.code(),
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end),
+            "attributes": attributes,
+            "links": self
+                .links
+                .iter()
+                .map(|link| json!({ "traceId": hex(&link.trace_id), "spanId": hex(&link.span_id) }))
+                .collect::<Vec<_>>(),
+            "status": match &self.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({ "code": 0 }),
+            },
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(hex(parent));
+        }
+        span
+    }
+}
+
+/// Queues spans for export to the collector.
+#[derive(Debug)]
+pub struct Tracer {
+    tx: mpsc::Sender<SpanData>,
+    connection_ratio: f64,
+}
+
+impl Tracer {
+    /// Start the task exporting spans as `config` says, until `close` is
+    /// given and what was queued by then is sent.
+    pub fn spawn(config: OtlpExport, close: CloseSignal) -> (Arc<Self>, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+        let task = tokio::spawn(deliver(config.endpoint, config.service_name, rx, close));
+        let tracer = Self {
+            tx,
+            connection_ratio: config.connection_ratio,
+        };
+        (Arc::new(tracer), task)
+    }
+
+    /// Queues `span`, dropping it if the queue is full.
+    pub fn export(&self, span: SpanData) {
+        if self.tx.try_send(span).is_err() {
+            DROPPED.with_label_values(&["queue_full"]).inc();
+        }
+    }
+
+    /// Whether a new connection's spans are recorded.
+    pub(crate) fn sample_connection(&self) -> bool {
+        self.connection_ratio > 0.0 && rand::thread_rng().gen_bool(self.connection_ratio.min(1.0))
+    }
+}
+
+/// Drains `rx` into the collector until the tracer goes away or `close` is given.
+async fn deliver(endpoint: String, service_name: String, mut rx: mpsc::Receiver<SpanData>, close: CloseSignal) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("a client without custom TLS settings builds");
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut closed = false;
+    loop {
+        let received = tokio::select! {
+            biased;
+            received = rx.recv_many(&mut batch, MAX_BATCH) => received,
+            // Take no more, but still send what is queued
+            _ = close.closed(), if !closed => {
+                rx.close();
+                closed = true;
+                continue;
+            }
+        };
+        if received == 0 {
+            break;
+        }
+        let result = client
+            .post(&endpoint)
+            .header("content-type", "application/json")
+            .body(payload(&service_name, &batch).to_string())
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        match result {
+            Ok(_) => EXPORTED.inc_by(batch.len() as u64),
+            Err(e) => {
+                tracing::warn!(spans = batch.len(), error = %e.without_url(), "failed to export spans");
+                DROPPED.with_label_values(&["export_failed"]).inc_by(batch.len() as u64);
+            }
+        }
+        batch.clear();
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` in its JSON encoding.
+fn payload(service_name: &str, spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "vortex-proxy", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(SpanData::json).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+/// Records the spans of one accepted connection.
+pub(crate) struct ConnectionSpans {
+    tracer: Arc<Tracer>,
+    accept: SpanData,
+    handshake: Option<SpanData>,
+}
+
+impl ConnectionSpans {
+    /// Starts the `downstream.accept` span for a connection from `peer`.
+    pub(crate) fn accept(tracer: Arc<Tracer>, peer: SocketAddr, tls: bool) -> Self {
+        let context = TraceContext::root(tracer.sample_connection());
+        let mut accept = SpanData::start("downstream.accept", SpanKind::Server, context, None);
+        accept.set_attribute("client.address", peer.ip().to_string());
+        accept.set_attribute("client.port", peer.port());
+        let handshake = tls.then(|| SpanData::start("tls.handshake", SpanKind::Internal, context.child(), Some(&context)));
+        Self { tracer, accept, handshake }
+    }
+
+    /// The connection's trace context, for requests to link to.
+    pub(crate) fn context(&self) -> ConnectionTrace {
+        ConnectionTrace(self.accept.context)
+    }
+
+    /// Ends the TLS handshake span with its outcome.
+    pub(crate) fn handshake_done(&mut self, result: Result<&rustls::ServerConnection, (&str, &std::io::Error)>) {
+        let Some(handshake) = &mut self.handshake else {
+            return;
+        };
+        match result {
+            Ok(tls) => {
+                if let Some(version) = tls.protocol_version() {
+                    handshake.set_attribute("tls.protocol.version", format!("{:?}", version));
+                }
+                if let Some(sni) = tls.server_name() {
+                    handshake.set_attribute("tls.server_name", sni);
+                }
+            }
+            Err((reason, err)) => {
+                handshake.set_attribute("tls.failure.reason", reason);
+                handshake.set_error(err);
+                self.accept.set_error(err);
+            }
+        }
+        handshake.finish();
+    }
+
+    /// Ends the accept span and exports the connection's spans if sampled.
+    pub(crate) fn finish(mut self) {
+        self.accept.finish();
+        if self.accept.context.sampled {
+            self.tracer.export(self.accept);
+            if let Some(handshake) = self.handshake {
+                self.tracer.export(handshake);
+            }
+        }
+    }
+}
+
+/// The trace context of the connection a request arrived on, in the request extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionTrace(pub TraceContext);
+
+/// Sampling not decided yet
+const UNDECIDED: u8 = 0;
+const SAMPLED: u8 = 1;
+const NOT_SAMPLED: u8 = 2;
+
+struct RequestTraceInner {
+    tracer: Arc<Tracer>,
+    server: TraceContext,
+    sampled: AtomicU8,
+    children: Mutex<Vec<SpanData>>,
+}
+
+/// The trace of one request, in its extensions: the `proxy.request` span's
+/// context and the child spans recorded so far.
+#[derive(Clone)]
+pub struct RequestTrace(Arc<RequestTraceInner>);
+
+impl RequestTrace {
+    fn new(tracer: Arc<Tracer>, server: TraceContext) -> Self {
+        Self(Arc::new(RequestTraceInner {
+            tracer,
+            server,
+            sampled: AtomicU8::new(UNDECIDED),
+            children: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// The `proxy.request` span's context.
+    pub fn context(&self) -> TraceContext {
+        self.0.server
+    }
+
+    /// Records the sampling decision, replacing any earlier one.
+    pub fn set_sampled(&self, sampled: bool) {
+        self.0.sampled.store(if sampled { SAMPLED } else { NOT_SAMPLED }, Ordering::Relaxed);
+    }
+
+    /// Whether the trace is recorded, falling back to the incoming `traceparent`.
+    pub fn sampled(&self) -> bool {
+        match self.0.sampled.load(Ordering::Relaxed) {
+            UNDECIDED => self.0.server.sampled,
+            decided => decided == SAMPLED,
+        }
+    }
+
+    /// Starts a child of the `proxy.request` span, recorded when dropped.
+    pub fn start(&self, name: &'static str, kind: SpanKind) -> ChildSpan {
+        ChildSpan {
+            trace: self.clone(),
+            span: Some(SpanData::start(name, kind, self.0.server.child(), Some(&self.0.server))),
+        }
+    }
+}
+
+/// A child span of a request, ended and recorded when dropped.
+pub struct ChildSpan {
+    trace: RequestTrace,
+    span: Option<SpanData>,
+}
+
+impl ChildSpan {
+    /// The span's trace context.
+    pub fn context(&self) -> TraceContext {
+        self.span.as_ref().expect("present until dropped").context
+    }
+
+    /// Adds an attribute.
+    pub fn set_attribute(&mut self, key: impl Into<Cow<'static, str>>, value: impl Into<AttributeValue>) {
+        self.span.as_mut().expect("present until dropped").set_attribute(key, value);
+    }
+
+    /// Marks the span as failed.
+    pub fn set_error(&mut self, err: &impl Display) {
+        self.span.as_mut().expect("present until dropped").set_error(err);
+    }
+}
+
+impl Drop for ChildSpan {
+    fn drop(&mut self) {
+        if let Some(mut span) = self.span.take() {
+            span.finish();
+            self.trace.0.children.lock().unwrap_or_else(|e| e.into_inner()).push(span);
+        }
+    }
+}
+
+/// Records a `proxy.request` span per request and exports it with its children.
+#[derive(Debug, Clone)]
+pub struct TracingLayer {
+    tracer: Arc<Tracer>,
+}
+
+impl TracingLayer {
+    /// Create a layer exporting through `tracer`.
+    pub fn new(tracer: Arc<Tracer>) -> Self {
+        Self { tracer }
+    }
+}
+
+impl<S> Layer<S> for TracingLayer {
+    type Service = TracingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TracingService {
+            inner,
+            tracer: self.tracer.clone(),
+        }
+    }
+}
+
+/// Service produced by `TracingLayer`.
+#[derive(Debug, Clone)]
+pub struct TracingService<S> {
+    inner: S,
+    tracer: Arc<Tracer>,
+}
+
+impl<S> Service<ProxyRequest> for TracingService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let parent = TraceContext::from_headers(req.headers());
+        let context = parent.map_or_else(|| TraceContext::root(true), |p| p.child());
+        let mut span = SpanData::start("proxy.request", SpanKind::Server, context, parent.as_ref());
+        span.set_attribute("http.request.method", req.method().as_str());
+        span.set_attribute("url.path", req.uri().path());
+        if let Some(id) = req.extensions().get::<RequestId>() {
+            span.set_attribute("http.request.header.x-request-id", id.as_str());
+        }
+        if let Some(ConnectionTrace(connection)) = req.extensions().get::<ConnectionTrace>() {
+            span.links.push(*connection);
+        }
+        let trace = RequestTrace::new(self.tracer.clone(), context);
+        req.extensions_mut().insert(trace.clone());
+        let mut inner = take_inner(&mut self.inner);
+
+        Box::pin(async move {
+            let result = inner.call(req).await;
+            match &result {
+                Ok(res) => {
+                    span.set_attribute("http.response.status_code", res.status().as_u16());
+                    if let Some(RouteLabels(labels)) = res.extensions().get::<RouteLabels>() {
+                        for (key, value) in labels.iter() {
+                            span.set_attribute(format!("vortex.label.{}", key), value);
+                        }
+                    }
+                }
+                Err(e) => {
+                    span.set_attribute("http.response.status_code", e.status_code().as_u16());
+                    span.set_error(e);
+                }
+            }
+            span.finish();
+            if trace.sampled() {
+                let children = std::mem::take(&mut *trace.0.children.lock().unwrap_or_else(|e| e.into_inner()));
+                trace.0.tracer.export(span);
+                for child in children {
+                    trace.0.tracer.export(child);
+                }
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent_round_trips() {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".parse().unwrap());
+        let context = TraceContext::from_headers(&headers).unwrap();
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.span_id, context.span_id);
+
+        for invalid in [
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            headers.insert("traceparent", invalid.parse().unwrap());
+            assert!(TraceContext::from_headers(&headers).is_none(), "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_span_carries_the_route_labels() {
+        use crate::pipeline::local_response;
+        use tower::ServiceExt;
+        use vortex_core::domain::labels::Labels;
+
+        let (tx, mut rx) = mpsc::channel(8);
+        let tracer = Arc::new(Tracer { tx, connection_ratio: 0.0 });
+        let service = TracingLayer::new(tracer).layer(tower::service_fn(|_req: ProxyRequest| async {
+            let mut res = local_response(hyper::StatusCode::OK, "ok");
+            res.extensions_mut().insert(RouteLabels(Arc::new(Labels::new().with("team", "checkout"))));
+            Ok::<_, ProxyError>(res)
+        }));
+
+        let req = hyper::Request::new(crate::pipeline::full_body(hyper::body::Bytes::new()));
+        service.oneshot(req).await.unwrap();
+        let span = rx.recv().await.unwrap();
+        assert_eq!(span.name, "proxy.request");
+        let team = span.attributes.iter().find(|(key, _)| key == "vortex.label.team");
+        assert_eq!(team.map(|(_, value)| value), Some(&AttributeValue::String("checkout".to_string())));
+    }
+
+    #[test]
+    fn test_payload_uses_otlp_json_encoding() {
+        let root = TraceContext::root(true);
+        let mut span = SpanData::start("upstream.request", SpanKind::Client, root.child(), Some(&root));
+        span.set_attribute("vortex.backend.id", 3u32);
+        span.set_error(&"connection reset");
+
+        let body = payload("edge", &[span]);
+        assert_eq!(body["resourceSpans"][0]["resource"]["attributes"][0]["value"]["stringValue"], "edge");
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], hex(&root.trace_id));
+        assert_eq!(span["parentSpanId"], hex(&root.span_id));
+        assert_eq!(span["kind"], 3);
+        assert_eq!(span["attributes"][0]["value"]["intValue"], "3");
+        assert_eq!(span["status"]["code"], 2);
+    }
+}
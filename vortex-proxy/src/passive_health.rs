@@ -0,0 +1,305 @@
+//! Passive health: ejecting backends on the failures real traffic sees.
+//!
+//! Active probes only learn that a backend's health endpoint answers; a
+//! backend failing every real request can pass them all day. Here each
+//! upstream answer is classified as it goes by. A classifier gives every
+//! status a failure weight, so a route can count a `503` in full, a `500`
+//! (as often the application's fault as the backend's) as half, and a `429`
+//! (the backend protecting itself, as asked) not at all. Weights add up
+//! until an answer that isn't a failure resets them. A backend whose score
+//! reaches the threshold is marked unhealthy for the ejection period, then
+//! readmitted. A backend the active checks also fail stays out by their
+//! verdict; one they pass may be readmitted by them sooner.
+
+use hyper::StatusCode;
+use prometheus::IntCounter;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+use vortex_core::domain::backend::{BackendId, SharedBackend};
+
+use crate::error::ProxyError;
+use crate::pipeline::{take_inner, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// Backends ejected by passive health.
+static EJECTIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "vortex_passive_health_ejections_total",
+        "Backends marked unhealthy after failing real requests"
+    )
+    .expect("metric registers once")
+});
+
+/// How much each upstream answer counts toward ejecting its backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusClassifier {
+    statuses: HashMap<u16, f64>,
+    /// Weight of an attempt that got no answer: refused, timed out, reset
+    /// or malformed
+    pub error_weight: f64,
+}
+
+impl StatusClassifier {
+    /// A classifier counting only attempts that got no answer, each as a
+    /// full failure.
+    pub fn errors_only() -> Self {
+        Self { statuses: HashMap::new(), error_weight: 1.0 }
+    }
+
+    /// Counts `status` as `weight` failures; 0 stops it counting.
+    pub fn weigh(self, status: u16, weight: f64) -> Self {
+        self.weigh_range(status..=status, weight)
+    }
+
+    /// Counts every status in `range` as `weight` failures, e.g. `500..=599`.
+    pub fn weigh_range(mut self, range: RangeInclusive<u16>, weight: f64) -> Self {
+        for status in range {
+            if weight > 0.0 {
+                self.statuses.insert(status, weight);
+            } else {
+                self.statuses.remove(&status);
+            }
+        }
+        self
+    }
+
+    /// What an answer with `status` counts for.
+    pub fn weight(&self, status: StatusCode) -> f64 {
+        self.statuses.get(&status.as_u16()).copied().unwrap_or(0.0)
+    }
+
+    /// What a failed attempt counts for. Only errors coming from the
+    /// backend count, not ones the proxy raised itself, like connect pacing.
+    fn error(&self, err: &ProxyError) -> f64 {
+        match err {
+            ProxyError::UpstreamConnect { .. }
+            | ProxyError::UpstreamTimeout { .. }
+            | ProxyError::UpstreamProtocol { .. }
+            | ProxyError::InvalidUpstreamResponse { .. } => self.error_weight,
+            _ => 0.0,
+        }
+    }
+}
+
+impl Default for StatusClassifier {
+    /// Gateway errors count in full and `500` as half; everything else,
+    /// `429` included, doesn't count.
+    fn default() -> Self {
+        Self::errors_only().weigh(500, 0.5).weigh_range(502..=504, 1.0)
+    }
+}
+
+/// When failing backends are ejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassiveHealth {
+    /// Failure weight in a row that ejects a backend
+    pub threshold: f64,
+    /// How long an ejected backend is kept out
+    pub ejection: Duration,
+    /// How answers are classified on routes without their own classifier
+    pub classifier: StatusClassifier,
+    /// Classifiers of routes that count failures differently, by route name
+    pub routes: HashMap<String, StatusClassifier>,
+}
+
+impl Default for PassiveHealth {
+    fn default() -> Self {
+        Self {
+            threshold: 5.0,
+            ejection: Duration::from_secs(30),
+            classifier: StatusClassifier::default(),
+            routes: HashMap::new(),
+        }
+    }
+}
+
+impl PassiveHealth {
+    /// The classifier for answers on `route`.
+    fn classifier(&self, route: &str) -> &StatusClassifier {
+        self.routes.get(route).unwrap_or(&self.classifier)
+    }
+}
+
+/// Each backend's failure score, and whether passive health ejected it.
+#[derive(Debug, Default)]
+struct Tally {
+    score: f64,
+    ejected: bool,
+}
+
+/// Failure scores of every backend; clones share them.
+#[derive(Debug, Clone)]
+struct Scores {
+    config: Arc<PassiveHealth>,
+    tallies: Arc<Mutex<HashMap<BackendId, Tally>>>,
+}
+
+impl Scores {
+    /// Counts one answer from `backend` weighing `weight`, ejecting the
+    /// backend if that takes it to the threshold.
+    fn record(&self, backend: &SharedBackend, weight: f64) {
+        let mut tallies = self.tallies.lock().expect("passive health lock poisoned");
+        let tally = tallies.entry(backend.id).or_default();
+        if weight <= 0.0 {
+            tally.score = 0.0;
+            return;
+        }
+        tally.score += weight;
+        if tally.score < self.config.threshold || !backend.is_healthy() {
+            return;
+        }
+        tally.score = 0.0;
+        tally.ejected = true;
+        drop(tallies);
+        tracing::warn!(backend = backend.id.0, addr = %backend.addr, "backend ejected after failing requests");
+        EJECTIONS.inc();
+        backend.set_healthy(false);
+
+        let (scores, backend) = (self.clone(), backend.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(scores.config.ejection).await;
+            let mut tallies = scores.tallies.lock().expect("passive health lock poisoned");
+            if let Some(tally) = tallies.get_mut(&backend.id).filter(|t| t.ejected) {
+                tally.ejected = false;
+                tracing::info!(backend = backend.id.0, addr = %backend.addr, "ejected backend readmitted");
+                backend.set_healthy(true);
+            }
+        });
+    }
+}
+
+/// Ejects backends that keep failing requests, per `PassiveHealth`.
+#[derive(Debug, Clone)]
+pub struct PassiveHealthLayer {
+    scores: Scores,
+}
+
+impl PassiveHealthLayer {
+    /// Create a layer ejecting backends per `config`.
+    pub fn new(config: PassiveHealth) -> Self {
+        Self {
+            scores: Scores { config: Arc::new(config), tallies: Arc::default() },
+        }
+    }
+}
+
+impl<S> Layer<S> for PassiveHealthLayer {
+    type Service = PassiveHealthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PassiveHealthService { inner, scores: self.scores.clone() }
+    }
+}
+
+/// Service produced by `PassiveHealthLayer`.
+#[derive(Clone)]
+pub struct PassiveHealthService<S> {
+    inner: S,
+    scores: Scores,
+}
+
+impl<S> Service<ProxyRequest> for PassiveHealthService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let Some(context) = req.extensions().get::<RouteContext>() else {
+            return Box::pin(self.inner.call(req));
+        };
+        let (route, backend) = (context.route.clone(), context.backend.clone());
+        let scores = self.scores.clone();
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let result = inner.call(req).await;
+            let classifier = scores.config.classifier(&route);
+            let weight = match &result {
+                Ok(res) => classifier.weight(res.status()),
+                Err(err) => classifier.error(err),
+            };
+            scores.record(&backend, weight);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use vortex_core::domain::backend::Backend;
+
+    fn layer(config: PassiveHealth) -> (PassiveHealthLayer, SharedBackend) {
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9000".parse().unwrap()));
+        (PassiveHealthLayer::new(config), backend)
+    }
+
+    #[test]
+    fn test_default_classifier_weighs_gateway_errors_over_app_errors() {
+        let classifier = StatusClassifier::default();
+        assert_eq!(classifier.weight(StatusCode::BAD_GATEWAY), 1.0);
+        assert_eq!(classifier.weight(StatusCode::SERVICE_UNAVAILABLE), 1.0);
+        assert_eq!(classifier.weight(StatusCode::INTERNAL_SERVER_ERROR), 0.5);
+        assert_eq!(classifier.weight(StatusCode::TOO_MANY_REQUESTS), 0.0);
+        assert_eq!(classifier.weight(StatusCode::NOT_IMPLEMENTED), 0.0);
+
+        let addr = "127.0.0.1:9000".parse().unwrap();
+        let source = std::io::ErrorKind::ConnectionRefused.into();
+        let refused = ProxyError::UpstreamConnect { backend: BackendId(1), addr, source };
+        assert_eq!(classifier.error(&refused), 1.0);
+        assert_eq!(classifier.error(&ProxyError::ConnectPaced { backend: BackendId(1), addr }), 0.0);
+
+        let strict = StatusClassifier::errors_only().weigh_range(500..=599, 1.0).weigh(501, 0.0);
+        assert_eq!(strict.weight(StatusCode::INTERNAL_SERVER_ERROR), 1.0);
+        assert_eq!(strict.weight(StatusCode::NOT_IMPLEMENTED), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_weighted_failures_in_a_row_eject_until_readmitted() {
+        let config = PassiveHealth { threshold: 2.0, ejection: Duration::from_millis(50), ..Default::default() };
+        let (layer, backend) = layer(config);
+        let scores = &layer.scores;
+        let classifier = StatusClassifier::default();
+        let weight = |status| classifier.weight(StatusCode::from_u16(status).unwrap());
+
+        // Three 500s are only 1.5; a success in between starts over
+        for status in [500, 500, 200, 500, 429, 500, 500] {
+            scores.record(&backend, weight(status));
+        }
+        assert!(backend.is_healthy());
+        scores.record(&backend, weight(503));
+        assert!(!backend.is_healthy());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(backend.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn test_routes_use_their_own_classifier() {
+        let lenient = StatusClassifier::errors_only();
+        let config = PassiveHealth {
+            threshold: 1.0,
+            routes: HashMap::from([("batch".to_string(), lenient)]),
+            ..Default::default()
+        };
+        let (layer, backend) = layer(config);
+        let scores = &layer.scores;
+        for _ in 0..10 {
+            scores.record(&backend, scores.config.classifier("batch").weight(StatusCode::BAD_GATEWAY));
+        }
+        assert!(backend.is_healthy());
+        scores.record(&backend, scores.config.classifier("api").weight(StatusCode::BAD_GATEWAY));
+        assert!(!backend.is_healthy());
+    }
+}
@@ -0,0 +1,287 @@
+//! Backend and breaker state that survives restarts.
+//!
+//! A freshly started proxy knows nothing about its backends: every EWMA sits
+//! at its baseline and every backend counts as healthy, so the first burst of
+//! traffic lands on backends the previous process had learned to avoid. With
+//! persistence enabled, the proxy writes each backend's EWMA and health, and
+//! the filter breakers' state, to a file on shutdown and loads it back on
+//! startup. Backends are matched by address and host, since IDs may be
+//! reassigned.
+//!
+//! Snapshots older than `max_age` are ignored; by then the backends have
+//! likely changed enough that starting fresh is the better guess.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vortex_core::domain::routing::SharedRoutingTable;
+use vortex_core::stats::StateSource;
+use vortex_filters::breaker::{BreakerSnapshot, FilterBreakers};
+
+/// Version of the snapshot file layout.
+const SNAPSHOT_VERSION: u64 = 1;
+
+/// How old a snapshot may be and still be trusted, unless configured.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Where state is saved and how long a saved snapshot stays usable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatePersistence {
+    /// The snapshot file
+    pub path: PathBuf,
+    /// Older snapshots are ignored on startup
+    pub max_age: Duration,
+}
+
+impl StatePersistence {
+    /// Persist state to `path`, trusting snapshots up to ten minutes old.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+/// Snapshots of one proxy instance's backend and breaker state, for the
+/// state file and for seeding a new instance from a warm peer over the
+/// admin API.
+#[derive(Clone)]
+pub(crate) struct InstanceState {
+    routing_table: SharedRoutingTable,
+    breakers: Option<Arc<FilterBreakers>>,
+}
+
+impl InstanceState {
+    pub(crate) fn new(routing_table: SharedRoutingTable, breakers: Option<Arc<FilterBreakers>>) -> Self {
+        Self { routing_table, breakers }
+    }
+
+    /// The current state as a JSON snapshot.
+    fn snapshot(&self) -> Value {
+        let backends: Vec<Value> = self
+            .routing_table
+            .snapshot()
+            .iter()
+            .map(|b| {
+                json!({
+                    "id": b.id.0,
+                    "addr": b.addr.to_string(),
+                    "host": b.host,
+                    "ewma_ms": b.ewma.get_ewma(),
+                    "active_requests": b.ewma.active_requests(),
+                    "healthy": b.is_healthy(),
+                })
+            })
+            .collect();
+        let breakers: Vec<Value> = self
+            .breakers
+            .iter()
+            .flat_map(|breakers| breakers.snapshot(Instant::now()))
+            .map(|b| {
+                json!({
+                    "filter": b.filter,
+                    "consecutive_failures": b.consecutive_failures,
+                    "open_for_ms": b.open_for.map(|d| d.as_millis() as u64),
+                })
+            })
+            .collect();
+        json!({
+            "version": SNAPSHOT_VERSION,
+            "saved_at_ms": SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            "backends": backends,
+            "filter_breakers": breakers,
+        })
+    }
+
+    /// Applies a snapshot taken at most `max_age` ago; older ones are
+    /// ignored. Returns the number of backends whose state was restored.
+    /// In-flight request counts are only informational and aren't restored.
+    fn apply(&self, snapshot: &Value, max_age: Duration) -> Result<usize, String> {
+        if snapshot["version"].as_u64() != Some(SNAPSHOT_VERSION) {
+            return Err("unsupported snapshot version".to_string());
+        }
+        let saved_at = UNIX_EPOCH + Duration::from_millis(snapshot["saved_at_ms"].as_u64().unwrap_or_default());
+        let age = SystemTime::now().duration_since(saved_at).unwrap_or_default();
+        if age > max_age {
+            return Ok(0);
+        }
+
+        let saved: HashMap<(SocketAddr, Option<&str>), &Value> = snapshot["backends"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|b| Some(((b["addr"].as_str()?.parse().ok()?, b["host"].as_str()), b)))
+            .collect();
+        let mut restored = 0;
+        for backend in self.routing_table.snapshot().iter() {
+            let Some(state) = saved.get(&(backend.addr, backend.host.as_deref())) else {
+                continue;
+            };
+            if let Some(ewma) = state["ewma_ms"].as_f64() {
+                backend.ewma.restore(ewma);
+            }
+            if let Some(healthy) = state["healthy"].as_bool() {
+                backend.set_healthy(healthy);
+            }
+            restored += 1;
+        }
+
+        if let Some(breakers) = &self.breakers {
+            // The cool-down kept running since the snapshot was taken
+            let saved: Vec<BreakerSnapshot> = snapshot["filter_breakers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|b| {
+                    Some(BreakerSnapshot {
+                        filter: b["filter"].as_str()?.to_string(),
+                        consecutive_failures: b["consecutive_failures"].as_u64()?.try_into().ok()?,
+                        open_for: b["open_for_ms"]
+                            .as_u64()
+                            .map(|ms| Duration::from_millis(ms).saturating_sub(age)),
+                    })
+                })
+                .collect();
+            breakers.restore(&saved, Instant::now());
+        }
+        Ok(restored)
+    }
+}
+
+impl StateSource for InstanceState {
+    fn export_state(&self) -> String {
+        self.snapshot().to_string()
+    }
+
+    fn import_state(&self, snapshot: &str) -> Result<usize, String> {
+        let snapshot: Value = serde_json::from_str(snapshot).map_err(|e| format!("invalid snapshot: {}", e))?;
+        self.apply(&snapshot, DEFAULT_MAX_AGE)
+    }
+}
+
+/// Saves and restores the state of one proxy instance.
+pub(crate) struct StateStore {
+    config: StatePersistence,
+    state: InstanceState,
+}
+
+impl StateStore {
+    pub(crate) fn new(
+        config: StatePersistence,
+        routing_table: SharedRoutingTable,
+        breakers: Option<Arc<FilterBreakers>>,
+    ) -> Self {
+        Self {
+            config,
+            state: InstanceState::new(routing_table, breakers),
+        }
+    }
+
+    /// Load the snapshot file, if there is a recent enough one. Returns the
+    /// number of backends whose state was restored.
+    pub(crate) fn restore(&self) -> io::Result<usize> {
+        let raw = match std::fs::read(&self.config.path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let snapshot: Value = serde_json::from_slice(&raw).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.state
+            .apply(&snapshot, self.config.max_age)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write the current state to the snapshot file, replacing it atomically.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let mut tmp = self.config.path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.state.snapshot().to_string())?;
+        std::fs::rename(&tmp, &self.config.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_filters::breaker::BreakerPolicy;
+
+    fn table(ids: [u32; 2]) -> SharedRoutingTable {
+        Arc::new(RoutingTable::new(vec![
+            Arc::new(Backend::new(BackendId(ids[0]), "10.0.0.1:80".parse().unwrap())),
+            Arc::new(Backend::new(BackendId(ids[1]), "10.0.0.2:80".parse().unwrap())),
+        ]))
+    }
+
+    #[test]
+    fn test_state_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("vortex-state-test-{}.json", std::process::id()));
+        let config = StatePersistence::new(&path);
+        let policy = BreakerPolicy {
+            failure_threshold: 1,
+            ..BreakerPolicy::default()
+        };
+
+        let before = table([1, 2]);
+        let slow = before.snapshot()[1].clone();
+        slow.ewma.observe_latency(900.0);
+        slow.set_healthy(false);
+        let breakers = Arc::new(FilterBreakers::new(policy));
+        breakers.record("auth", false, Duration::ZERO, Instant::now());
+        StateStore::new(config.clone(), before, Some(breakers)).save().unwrap();
+
+        // The new process numbers its backends differently
+        let after = table([7, 8]);
+        let breakers = Arc::new(FilterBreakers::new(policy));
+        let store = StateStore::new(config.clone(), after.clone(), Some(breakers.clone()));
+        assert_eq!(store.restore().unwrap(), 2);
+        let backends = after.snapshot();
+        assert_eq!(backends[0].ewma.get_ewma(), 50.0);
+        assert!(backends[0].is_healthy());
+        assert_eq!(backends[1].ewma.get_ewma(), 900.0);
+        assert!(!backends[1].is_healthy());
+        assert!(!breakers.allows("auth", Instant::now()));
+
+        // Too old to trust
+        let expired = StatePersistence {
+            max_age: Duration::ZERO,
+            ..config
+        };
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(StateStore::new(expired, table([1, 2]), None).restore().unwrap(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_peers_seed_each_other_over_the_admin_api() {
+        let warm = InstanceState::new(table([1, 2]), None);
+        let slow = warm.routing_table.snapshot()[0].clone();
+        slow.ewma.observe_latency(400.0);
+        let _busy = slow.ewma.increment_active();
+        let exported = warm.export_state();
+        let parsed: Value = serde_json::from_str(&exported).unwrap();
+        assert_eq!(parsed["backends"][0]["id"], 1);
+        assert_eq!(parsed["backends"][0]["ewma_ms"], 400.0);
+        assert_eq!(parsed["backends"][0]["active_requests"], 1);
+
+        let fresh = InstanceState::new(table([5, 6]), None);
+        assert_eq!(fresh.import_state(&exported), Ok(2));
+        let backends = fresh.routing_table.snapshot();
+        assert_eq!(backends[0].ewma.get_ewma(), 400.0);
+        assert_eq!(backends[0].ewma.active_requests(), 0);
+        assert!(fresh.import_state("{").unwrap_err().starts_with("invalid snapshot"));
+        assert_eq!(fresh.import_state(r#"{"version": 9}"#), Err("unsupported snapshot version".to_string()));
+    }
+
+    #[test]
+    fn test_missing_snapshot_is_a_cold_start() {
+        let config = StatePersistence::new("/nonexistent/vortex-state.json");
+        assert_eq!(StateStore::new(config, table([1, 2]), None).restore().unwrap(), 0);
+    }
+}
@@ -0,0 +1,1940 @@
+//! The proxy request pipeline, assembled from tower layers.
+//!
+//! `StandardStages` wires the built-in stages onto a
+//! `vortex_core::pipeline::PipelineBuilder`: access logging, `Server-Timing` headers, trace sampling, traffic
+//! accounting, request metrics, deadlines, route matching, and backend selection at `Route`, fault
+//! injection, Wasm filters, JSON body redaction, HTML URL rewriting, and response caching at `Filters` (behind the
+//! response size guard), safe retries and hedging at `Retry`, request
+//! body compression, upstream credentials, and interim response pass-through at `Pool`, and the pooled HTTP/1.1 exchange as
+//! the innermost `UpstreamService`. Callers can add their own layers to the
+//! returned builder before calling `build`.
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::http::Extensions;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tower::{Layer, Service};
+use tracing::Instrument;
+use vortex_core::domain::backend::{BackendId, SharedBackend};
+use vortex_core::domain::labels::Labels;
+use vortex_core::domain::routing::SharedRoutingTable;
+use vortex_core::load_balancer::hash::{stable_hash, HashKey};
+use vortex_core::load_balancer::selector::select_best_backend_where;
+use vortex_core::load_balancer::subset::Subset;
+use vortex_core::pipeline::{BoxService, PipelineBuilder, Stage};
+use vortex_core::route::{RequestView, SharedRouteTable};
+use vortex_filters::breaker::{BreakerEvent, FilterBreakers, OpenAction};
+use vortex_filters::chain::{FilterChain, FilterCode, RouteFilterChains};
+use vortex_filters::fault_injection::FaultInjector;
+#[cfg(feature = "lua")]
+use vortex_filters::lua::{LuaFilter, ScriptRequest};
+use vortex_filters::wasm_engine::{FilterError, WasmEngine};
+
+use crate::access_log::{AccessLogFormat, AccessLogLayer};
+use crate::body_codec::{BodyCodecLayer, BodyCodecs};
+use crate::cache::{cookie, CacheLayer, ResponseCache};
+use crate::client_concurrency::{ClientConcurrency, ClientConcurrencyLayer};
+use crate::client_quota::{ClientQuota, ClientQuotaLayer};
+use crate::compression::{CompressionLayer, RequestCompression};
+use crate::connect_pacing::{ConnectPacer, ConnectPacing, Paced};
+use crate::connection_pool::pool::{self, ConnectionPool, PoolKey, PooledConnection};
+use crate::deadline::{Deadline, DeadlineConfig, DeadlineLayer};
+use crate::dns;
+use crate::duplicate_headers::DuplicateHeaders;
+use crate::error::ProxyError;
+use crate::experiments::{ExperimentLayer, RouteExperiments};
+use crate::force_backend::{ForceBackend, ForcedBackend};
+use crate::header_limits::HeaderLimits;
+use crate::hedging::{HedgeLayer, HedgePolicy};
+use crate::html_rewrite::{HtmlRewriteLayer, UrlRewrites};
+use crate::interim::{InterimCollector, InterimForwarding, InterimLayer};
+use crate::log_sink::LogShipper;
+use crate::metrics::{self, RequestMetrics};
+use crate::normalization::{NormalizationLayer, RequestNormalization};
+use crate::otel::{ConnectionTrace, RequestTrace, SpanKind, TraceContext, Tracer, TracingLayer};
+use crate::passive_health::{PassiveHealth, PassiveHealthLayer};
+use crate::redaction::{BodyRedaction, RedactionLayer};
+use crate::request_id::{RequestId, REQUEST_ID};
+use crate::request_rules::{RequestRulesLayer, RouteRequestRules};
+use crate::response_limit::{ResponseLimitLayer, ResponseLimits};
+use crate::retry::{RetryLayer, RetryPolicy};
+use crate::sampling::{SamplingLayer, SamplingPolicy};
+use crate::server_timing::{PhaseTimer, ServerTiming, ServerTimingLayer};
+use crate::server::ListenerPolicy;
+use crate::socket::SocketOptions;
+use crate::traffic::TrafficTracker;
+use crate::upstream_target::UpstreamTargets;
+use crate::upstream_auth::{UpstreamAuthLayer, UpstreamCredentials};
+
+/// How long to wait for the TCP connection to an upstream before giving up.
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The body type flowing through the pipeline in both directions: either a
+/// streamed peer body or a locally generated one.
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// A request travelling through the pipeline.
+pub type ProxyRequest = Request<ProxyBody>;
+
+/// A response travelling back through the pipeline.
+pub type ProxyResponse = Response<ProxyBody>;
+
+/// The fully assembled, type-erased request pipeline.
+pub type ProxyService = BoxService<ProxyRequest, ProxyResponse, ProxyError>;
+
+/// The boxed future returned by pipeline services.
+pub type ProxyFuture = Pin<Box<dyn Future<Output = Result<ProxyResponse, ProxyError>> + Send>>;
+
+/// Per-request routing decision, stored in the request extensions by the
+/// `Route` stage and read by every later stage.
+#[derive(Debug, Clone)]
+pub struct RouteContext {
+    /// The route the request matched
+    pub route: String,
+    /// The backend chosen by the load balancer
+    pub backend: SharedBackend,
+    /// Observability labels of the matched route and its pool
+    pub labels: Arc<Labels>,
+}
+
+/// Marks requests routed without declared routes, stored in the request
+/// extensions by the `Route` stage. Their `RouteContext::route` is the raw
+/// request path, which per-route metrics and statistics must not use as a
+/// label, or every path a client makes up would add a series.
+#[derive(Debug, Clone, Copy)]
+pub struct UndeclaredRoute;
+
+/// The route per-route metrics and statistics count requests under when
+/// they weren't matched against declared routes.
+pub const UNDECLARED_ROUTE: &str = "default";
+
+/// The route per-route metrics and statistics count `req` under, if it has
+/// been routed: its route's name, or `UNDECLARED_ROUTE`.
+pub fn route_label(req: &ProxyRequest) -> Option<&str> {
+    let ctx = req.extensions().get::<RouteContext>()?;
+    match req.extensions().get::<UndeclaredRoute>() {
+        Some(_) => Some(UNDECLARED_ROUTE),
+        None => Some(&ctx.route),
+    }
+}
+
+/// Observability labels of the matched route and its pool, stored in the
+/// response extensions by the `Route` stage so the metrics, tracing and
+/// access log layers outside it can report them.
+#[derive(Debug, Clone)]
+pub struct RouteLabels(pub Arc<Labels>);
+
+/// The subset of the pool the matched route is restricted to, stored in the
+/// request extensions by the `Route` stage so retries and hedges stay in it.
+#[derive(Debug, Clone)]
+pub struct RouteSubset(pub Arc<Subset>);
+
+/// The pool of the cluster the matched route is served by, when that isn't
+/// the default pool, stored in the request extensions by the `Route` stage
+/// so retries and hedges stay in it.
+#[derive(Debug, Clone)]
+pub struct RouteCluster(pub SharedRoutingTable);
+
+/// The request's hash for the pool's hash-based balancer, stored in the
+/// request extensions by the `Route` stage so retries and hedges hash alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestHash(pub u64);
+
+impl RequestHash {
+    /// Hashes `req` by `key`, if it has the key.
+    pub fn of<B>(req: &Request<B>, key: &HashKey) -> Option<Self> {
+        match key {
+            HashKey::ClientIp => Some(Self::of_ip(req.extensions().get::<ClientAddr>()?.0.ip())),
+            HashKey::Header(name) => Some(Self(stable_hash(&[req.headers().get(name.as_str())?.as_bytes()]))),
+            HashKey::Cookie(name) => Some(Self(stable_hash(&[cookie(req.headers(), name)?.as_bytes()]))),
+        }
+    }
+
+    /// Hashes a client address, for pools keyed by `HashKey::ClientIp`. An
+    /// IPv4 client seen through a dual-stack listener (`::ffff:a.b.c.d`)
+    /// hashes like the plain IPv4 address, so it sticks to the same backend
+    /// whichever listener it reaches.
+    pub fn of_ip(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => Self(stable_hash(&[&ip.octets()])),
+            IpAddr::V6(ip) => Self(stable_hash(&[&ip.octets()])),
+        }
+    }
+}
+
+/// The pool serving the request: its route's cluster, if the request has
+/// one, or else `routing_table`.
+pub(crate) fn route_pool<'a>(
+    routing_table: &'a SharedRoutingTable,
+    extensions: &'a Extensions,
+) -> &'a SharedRoutingTable {
+    extensions.get::<RouteCluster>().map_or(routing_table, |RouteCluster(pool)| pool)
+}
+
+/// Selects a backend with the balancer of the request's pool, within the
+/// route's subset if the request has one and by its hash if it has one,
+/// skipping the `excluded` ones.
+pub(crate) fn select_backend(
+    routing_table: &SharedRoutingTable,
+    extensions: &Extensions,
+    excluded: &[BackendId],
+) -> Option<SharedBackend> {
+    let routing_table = route_pool(routing_table, extensions);
+    let hash = extensions.get::<RequestHash>().map(|h| h.0);
+    match extensions.get::<RouteSubset>() {
+        Some(RouteSubset(subset)) => subset.select(routing_table, excluded, hash),
+        None => select_best_backend_where(routing_table, excluded, hash, |_| true),
+    }
+}
+
+/// The downstream peer address, stored in the request extensions by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Where an intercepted client was really connecting to, stored in the
+/// request extensions by transparent listeners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OriginalDst(pub SocketAddr);
+
+/// Builds a small locally generated response (e.g. an injected fault).
+pub fn local_response(status: StatusCode, body: &'static str) -> ProxyResponse {
+    let mut res = Response::new(full_body(Bytes::from_static(body.as_bytes())));
+    *res.status_mut() = status;
+    res
+}
+
+/// Wraps a fully buffered payload as a `ProxyBody`.
+pub fn full_body(bytes: Bytes) -> ProxyBody {
+    Full::new(bytes).map_err(|never| match never {}).boxed()
+}
+
+/// A test body of unknown length arriving in chunks.
+#[cfg(test)]
+pub(crate) struct Chunks(pub(crate) std::collections::VecDeque<&'static [u8]>);
+
+#[cfg(test)]
+impl hyper::body::Body for Chunks {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<hyper::body::Frame<Self::Data>, Self::Error>>> {
+        Poll::Ready(self.0.pop_front().map(|c| Ok(hyper::body::Frame::data(Bytes::from_static(c)))))
+    }
+}
+
+/// Replays a body buffered by a layer, followed by the trailers it ended with.
+/// Layers that buffer must use this rather than `full_body`, or trailers
+/// (gRPC's `grpc-status`, for one) silently disappear.
+pub fn buffered_body(bytes: Bytes, trailers: Option<HeaderMap>) -> ProxyBody {
+    match trailers {
+        Some(trailers) => Full::new(bytes)
+            .map_err(|never| match never {})
+            .with_trailers(std::future::ready(Some(Ok(trailers))))
+            .boxed(),
+        None => full_body(bytes),
+    }
+}
+
+/// Renders a request-ending `ProxyError` as a downstream error page.
+pub fn error_response(err: &ProxyError) -> ProxyResponse {
+    let status = err.status_code();
+    let mut res = local_response(status, status.canonical_reason().unwrap_or("Proxy Error"));
+    if let ProxyError::MethodNotAllowed { allowed, .. } = err {
+        let allowed = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+        if let Ok(value) = HeaderValue::from_str(&allowed) {
+            res.headers_mut().insert(hyper::header::ALLOW, value);
+        }
+    }
+    if let ProxyError::QuotaExceeded { retry_after, .. } = err {
+        // Whole seconds, rounded up so a client retrying on time is admitted
+        let secs = retry_after.as_secs().saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+        res.headers_mut().insert(hyper::header::RETRY_AFTER, HeaderValue::from(secs));
+    }
+    res
+}
+
+/// The subsystems and optional features the built-in Vortex stages are made of.
+pub struct StandardStages {
+    /// Backends to route between
+    pub routing_table: SharedRoutingTable,
+    /// Declared routes, if requests are matched against any
+    pub routes: Option<SharedRouteTable>,
+    /// Pools of the clusters routes may name, by cluster name
+    pub clusters: HashMap<String, SharedRoutingTable>,
+    /// Debug override letting trusted clients pick the backend, if enabled
+    pub force_backend: Option<ForceBackend>,
+    /// Engine running the Wasm filters
+    pub wasm_engine: Arc<WasmEngine>,
+    /// Admin-managed chaos rules
+    pub fault_injector: Arc<FaultInjector>,
+    /// Per-request Prometheus metrics
+    pub request_metrics: RequestMetrics,
+    /// Live top-talkers accounting, if enabled
+    pub traffic: Option<Arc<TrafficTracker>>,
+    /// Trace sampling policy, if tracing is enabled
+    pub sampling: Option<SamplingPolicy>,
+    /// OTLP span export, if enabled
+    pub tracing: Option<Arc<Tracer>>,
+    /// How client deadlines are honored and propagated
+    pub deadlines: DeadlineConfig,
+    /// Retry policy for failed upstream attempts, if retries are enabled
+    pub retry: Option<RetryPolicy>,
+    /// Hedging policy for slow safe requests, if hedging is enabled
+    pub hedging: Option<HedgePolicy>,
+    /// Ejection of backends failing real requests, if enabled
+    pub passive_health: Option<PassiveHealth>,
+    /// Per-client caps on requests in flight, if enabled
+    pub client_concurrency: Option<ClientConcurrency>,
+    /// Per-client request quotas weighted by route cost, if enabled
+    pub client_quota: Option<ClientQuota>,
+    /// Canonicalization of requests before routing, if enabled
+    pub normalization: Option<RequestNormalization>,
+    /// Request body compression toward the pool's backends, if enabled
+    pub compression: Option<RequestCompression>,
+    /// JSON field redaction of request and response bodies, if enabled
+    pub redaction: Option<BodyRedaction>,
+    /// Body format conversion between clients and upstreams by route, if any
+    pub body_codecs: Option<BodyCodecs>,
+    /// Internal URLs rewritten in HTML and CSS responses by route, if any
+    pub html_rewrites: Option<UrlRewrites>,
+    /// Response body size limits by route, if enforced
+    pub response_limits: Option<ResponseLimits>,
+    /// URI length and method rules by route, if enforced
+    pub request_rules: Option<RouteRequestRules>,
+    /// A/B experiments by route, if any
+    pub experiments: Option<RouteExperiments>,
+    /// Phase-ordered Wasm filter chains by route, if configured
+    pub filter_chains: Option<RouteFilterChains>,
+    /// Circuit breaking for chain filters, if enabled
+    pub filter_breakers: Option<Arc<FilterBreakers>>,
+    /// Credentials added to requests toward the pool's backends, if any
+    pub upstream_credentials: Option<UpstreamCredentials>,
+    /// In-memory response cache, if enabled
+    pub caching: Option<Arc<ResponseCache>>,
+    /// Routes whose upstream 1xx interim responses are passed through, if any
+    pub interim: Option<InterimForwarding>,
+    /// Access and error log shipping, if enabled
+    pub access_log: Option<Arc<LogShipper>>,
+    /// How access records are rendered, if not the default format
+    pub access_log_format: Option<AccessLogFormat>,
+    /// Routes whose responses carry `Server-Timing` headers, if any
+    pub server_timing: Option<ServerTiming>,
+}
+
+impl StandardStages {
+    /// Returns a pipeline builder preloaded with the built-in Vortex stages.
+    pub fn into_pipeline(self) -> PipelineBuilder<ProxyRequest, ProxyResponse, ProxyError> {
+        let mut builder = PipelineBuilder::new();
+        if let Some(config) = self.normalization {
+            // Outermost, so logs, traces, sampling and routing all see the canonical path.
+            // It never rejects a request, so nothing goes unlogged for it
+            builder = builder.layer(Stage::Route, NormalizationLayer::new(config));
+        }
+        if let Some(shipper) = &self.access_log {
+            // Around everything else, so every request is logged however it ends
+            let mut layer = AccessLogLayer::new(shipper.clone());
+            if let Some(format) = self.access_log_format {
+                layer = layer.with_format(format);
+            }
+            builder = builder.layer(Stage::Route, layer);
+        }
+        if let Some(config) = self.server_timing {
+            builder = builder.layer(Stage::Route, ServerTimingLayer::new(config));
+        }
+        if let Some(tracer) = self.tracing {
+            // Outside sampling, so the final decision is known when the spans are exported
+            builder = builder.layer(Stage::Route, TracingLayer::new(tracer));
+        }
+        if let Some(policy) = self.sampling {
+            builder = builder.layer(Stage::Route, SamplingLayer::new(policy));
+        }
+        if let Some(config) = self.compression {
+            builder = builder.layer(Stage::Pool, CompressionLayer::new(config));
+        }
+        if let Some(credentials) = self.upstream_credentials {
+            // Inside compression, so SigV4 signs the body as sent
+            builder = builder.layer(Stage::Pool, UpstreamAuthLayer::new(credentials));
+        }
+        if let Some(config) = self.interim {
+            builder = builder.layer(Stage::Pool, InterimLayer::new(config));
+        }
+        if let Some(config) = self.passive_health {
+            // Inside retries and hedges, so every attempt counts against its own backend
+            builder = builder.layer(Stage::Pool, PassiveHealthLayer::new(config));
+        }
+        if let Some(config) = self.client_concurrency {
+            builder = builder.layer(Stage::Limits, ClientConcurrencyLayer::new(config));
+        }
+        if let Some(policy) = self.retry {
+            builder = builder.layer(Stage::Retry, RetryLayer::new(policy, self.routing_table.clone()));
+        }
+        if let Some(policy) = self.hedging {
+            // Inside the retry layer, so every retried attempt can be hedged too
+            builder = builder.layer(Stage::Retry, HedgeLayer::new(policy, self.routing_table.clone()));
+        }
+        let mut route_layer = RouteLayer::new(self.routing_table.clone());
+        if let Some(routes) = self.routes {
+            route_layer = route_layer.with_routes(routes);
+        }
+        for (name, pool) in self.clusters {
+            route_layer = route_layer.with_cluster(name, pool);
+        }
+        if let Some(config) = self.force_backend {
+            route_layer = route_layer.with_force_backend(config);
+        }
+        let mut builder = builder
+            .layer(Stage::Route, RequestMetricsLayer::new(self.routing_table.clone(), self.request_metrics))
+            .layer(Stage::Route, DeadlineLayer::new(self.deadlines))
+            .layer(Stage::Route, route_layer);
+        if let Some(tracker) = self.traffic {
+            // Inside routing, so requests count under their route rather than their path,
+            // and ahead of the quota, so clients turned away still show up
+            builder = builder.layer(Stage::Route, TrafficLayer::new(tracker));
+        }
+        if let Some(config) = self.client_quota {
+            // Right after routing, so cache hits are charged too, and requests out
+            // of quota are turned away before any filter spends work on them
+            builder = builder.layer(Stage::Route, ClientQuotaLayer::new(config));
+        }
+        if let Some(limits) = self.response_limits {
+            // Outermost filter, so it measures the body clients will receive
+            builder = builder.layer(Stage::Filters, ResponseLimitLayer::new(limits));
+        }
+        if let Some(rules) = self.request_rules {
+            // Ahead of the filters, so rejected requests cost nothing more
+            builder = builder.layer(Stage::Filters, RequestRulesLayer::new(rules));
+        }
+        if let Some(experiments) = self.experiments {
+            // Inside the request rules, so rejected requests are no exposure
+            let mut layer = ExperimentLayer::new(experiments);
+            if let Some(shipper) = &self.access_log {
+                layer = layer.with_shipper(shipper.clone());
+            }
+            builder = builder.layer(Stage::Filters, layer);
+        }
+        if let Some(codecs) = self.body_codecs {
+            // Outside the other filters, so they only ever see the upstream's format
+            builder = builder.layer(Stage::Filters, BodyCodecLayer::new(codecs));
+        }
+        let mut wasm_layer = WasmFilterLayer::new(self.wasm_engine);
+        if let Some(chains) = self.filter_chains {
+            wasm_layer = wasm_layer.with_chains(chains);
+        }
+        if let Some(breakers) = self.filter_breakers {
+            wasm_layer = wasm_layer.with_breakers(breakers);
+        }
+        let mut builder = builder
+            .layer(Stage::Filters, FaultInjectionLayer::new(self.fault_injector))
+            .layer(Stage::Filters, wasm_layer);
+        if let Some(config) = self.redaction {
+            builder = builder.layer(Stage::Filters, RedactionLayer::new(config));
+        }
+        if let Some(rewrites) = self.html_rewrites {
+            builder = builder.layer(Stage::Filters, HtmlRewriteLayer::new(rewrites));
+        }
+        match self.caching {
+            // Innermost filter, so cache hits still pass the filter chains
+            Some(cache) => builder.layer(Stage::Filters, CacheLayer::new(cache)),
+            None => builder,
+        }
+    }
+}
+
+/// Hands the request to `inner` after leaving a ready clone in its place,
+/// as tower requires for services called from inside a boxed future.
+pub(crate) fn take_inner<S: Clone>(inner: &mut S) -> S {
+    let clone = inner.clone();
+    std::mem::replace(inner, clone)
+}
+
+/// Counts every routed request into the live top-talkers view.
+#[derive(Debug, Clone)]
+pub struct TrafficLayer {
+    tracker: Arc<TrafficTracker>,
+}
+
+impl TrafficLayer {
+    /// Create a layer recording into `tracker`.
+    pub fn new(tracker: Arc<TrafficTracker>) -> Self {
+        Self { tracker }
+    }
+}
+
+impl<S> Layer<S> for TrafficLayer {
+    type Service = TrafficService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TrafficService {
+            inner,
+            tracker: self.tracker.clone(),
+        }
+    }
+}
+
+/// Service produced by `TrafficLayer`.
+#[derive(Debug, Clone)]
+pub struct TrafficService<S> {
+    inner: S,
+    tracker: Arc<TrafficTracker>,
+}
+
+impl<S> Service<ProxyRequest> for TrafficService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError>,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let client_ip = req
+            .extensions()
+            .get::<ClientAddr>()
+            .map(|ClientAddr(addr)| addr.ip().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let user_agent = req
+            .headers()
+            .get(hyper::header::USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or("-");
+        self.tracker.record(&client_ip, route_label(&req).unwrap_or("-"), user_agent);
+
+        self.inner.call(req)
+    }
+}
+
+/// Records every request's outcome and latency, labeled with the matched
+/// route's observability dimensions. Sits outermost so it also counts
+/// requests that fail before a backend is chosen, which carry the default
+/// pool's labels.
+#[derive(Clone)]
+pub struct RequestMetricsLayer {
+    routing_table: SharedRoutingTable,
+    metrics: RequestMetrics,
+}
+
+impl RequestMetricsLayer {
+    /// Create a layer recording into `metrics` with labels from `routing_table`.
+    pub fn new(routing_table: SharedRoutingTable, metrics: RequestMetrics) -> Self {
+        Self { routing_table, metrics }
+    }
+}
+
+impl<S> Layer<S> for RequestMetricsLayer {
+    type Service = RequestMetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestMetricsService {
+            inner,
+            routing_table: self.routing_table.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// Service produced by `RequestMetricsLayer`.
+#[derive(Clone)]
+pub struct RequestMetricsService<S> {
+    inner: S,
+    routing_table: SharedRoutingTable,
+    metrics: RequestMetrics,
+}
+
+impl<S> Service<ProxyRequest> for RequestMetricsService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let labels = self.routing_table.labels();
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            let (status, labels) = match &result {
+                Ok(res) => (res.status(), res.extensions().get::<RouteLabels>().map_or(labels, |l| l.0.clone())),
+                Err(err) => (err.status_code(), labels),
+            };
+            metrics.observe(&labels, status, start.elapsed());
+            result
+        })
+    }
+}
+
+/// Route matching's view of a live request.
+pub struct HttpRequestView<'a>(pub &'a ProxyRequest);
+
+impl RequestView for HttpRequestView<'_> {
+    fn method(&self) -> &str {
+        self.0.method().as_str()
+    }
+
+    fn host(&self) -> Option<&str> {
+        // HTTP/2 carries the host in the URI authority, HTTP/1 in the Host header
+        match self.0.uri().authority() {
+            Some(authority) => Some(authority.as_str()),
+            None => self.header(hyper::header::HOST.as_str()),
+        }
+    }
+
+    fn path(&self) -> &str {
+        self.0.uri().path()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.0.headers().get(name)?.to_str().ok()
+    }
+
+    fn client_ip(&self) -> Option<std::net::IpAddr> {
+        // Canonical, so IPv4 clients of a dual-stack listener match IPv4 CIDRs
+        self.0.extensions().get::<ClientAddr>().map(|addr| addr.0.ip().to_canonical())
+    }
+}
+
+/// Matches the request against the declared routes, selects a backend from
+/// the matched route's cluster, and records both as the request's
+/// `RouteContext`.
+#[derive(Debug, Clone)]
+pub struct RouteLayer {
+    routing_table: SharedRoutingTable,
+    routes: Option<SharedRouteTable>,
+    clusters: Arc<HashMap<String, SharedRoutingTable>>,
+    force_backend: Option<Arc<ForceBackend>>,
+}
+
+impl RouteLayer {
+    /// Create a routing layer over the given table.
+    pub fn new(routing_table: SharedRoutingTable) -> Self {
+        Self {
+            routing_table,
+            routes: None,
+            clusters: Arc::default(),
+            force_backend: None,
+        }
+    }
+
+    /// Let trusted clients bypass load balancing as configured.
+    pub fn with_force_backend(mut self, config: ForceBackend) -> Self {
+        self.force_backend = Some(Arc::new(config));
+        self
+    }
+
+    /// Match requests against `routes`; requests matching none are rejected.
+    pub fn with_routes(mut self, routes: SharedRouteTable) -> Self {
+        self.routes = Some(routes);
+        self
+    }
+
+    /// Serve routes naming cluster `name` from `pool`. Routes naming a
+    /// cluster that wasn't added, or none, are served from the default pool.
+    pub fn with_cluster(mut self, name: impl Into<String>, pool: SharedRoutingTable) -> Self {
+        Arc::make_mut(&mut self.clusters).insert(name.into(), pool);
+        self
+    }
+}
+
+impl<S> Layer<S> for RouteLayer {
+    type Service = RouteService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RouteService {
+            inner,
+            routing_table: self.routing_table.clone(),
+            routes: self.routes.clone(),
+            clusters: self.clusters.clone(),
+            force_backend: self.force_backend.clone(),
+        }
+    }
+}
+
+/// Service produced by `RouteLayer`.
+#[derive(Debug, Clone)]
+pub struct RouteService<S> {
+    inner: S,
+    routing_table: SharedRoutingTable,
+    routes: Option<SharedRouteTable>,
+    clusters: Arc<HashMap<String, SharedRoutingTable>>,
+    force_backend: Option<Arc<ForceBackend>>,
+}
+
+impl<S> Service<ProxyRequest> for RouteService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let mut routing_table = self.routing_table.clone();
+        let mut labels = routing_table.labels();
+        let route = match &self.routes {
+            None => {
+                req.extensions_mut().insert(UndeclaredRoute);
+                req.uri().path().to_string()
+            }
+            Some(routes) => {
+                let matcher = routes.matcher();
+                let Some(spec) = matcher.find(&HttpRequestView(&req)) else {
+                    let path = req.uri().path().to_string();
+                    return Box::pin(std::future::ready(Err(ProxyError::NoRouteMatch { path })));
+                };
+                if let Some(pool) = spec.cluster.as_ref().and_then(|name| self.clusters.get(name)) {
+                    routing_table = pool.clone();
+                    labels = routing_table.labels();
+                    req.extensions_mut().insert(RouteCluster(routing_table.clone()));
+                }
+                if !spec.labels.is_empty() {
+                    labels = Arc::new(labels.merged(&spec.labels));
+                }
+                if let Some(subset) = &spec.subset {
+                    req.extensions_mut().insert(RouteSubset(subset.clone()));
+                }
+                if let Some(path) = spec.rewritten_path(req.uri().path()) {
+                    if let Err(reason) = replace_path(&mut req, &path) {
+                        let err = ProxyError::InvalidRequest { route: spec.name.clone(), reason };
+                        return Box::pin(std::future::ready(Err(err)));
+                    }
+                }
+                spec.name.clone()
+            }
+        };
+        if let Some(hash) = routing_table.hash_key().and_then(|key| RequestHash::of(&req, key)) {
+            req.extensions_mut().insert(hash);
+        }
+
+        let forced = match self.force_backend.as_ref().map(|f| f.take_requested(&mut req, &route)) {
+            Some(Ok(forced)) => forced,
+            Some(Err(e)) => return Box::pin(std::future::ready(Err(e))),
+            None => None,
+        };
+        let mut select_span = req.extensions().get::<RequestTrace>().map(|trace| {
+            let mut span = trace.start("backend.select", SpanKind::Internal);
+            span.set_attribute("vortex.route", route.as_str());
+            span
+        });
+        let selected = match forced {
+            // Deliberately ignores health: the point is to reach that exact node
+            Some(id) => match routing_table.backend(id) {
+                Some(backend) => {
+                    tracing::info!(backend = id.0, %route, "forcing backend by debug override");
+                    req.extensions_mut().insert(ForcedBackend(id));
+                    Ok(backend)
+                }
+                None => {
+                    let reason = format!("backend {} does not exist", id.0);
+                    Err(ProxyError::InvalidRequest { route: route.clone(), reason })
+                }
+            },
+            // Find the computationally optimal backend using Peak EWMA
+            None => select_backend(&self.routing_table, req.extensions(), &[])
+                .ok_or_else(|| ProxyError::NoHealthyBackend { route: route.clone() }),
+        };
+        let backend = match selected {
+            Ok(backend) => {
+                if let Some(span) = &mut select_span {
+                    span.set_attribute("vortex.backend.id", backend.id.0);
+                }
+                backend
+            }
+            Err(e) => {
+                if let Some(span) = &mut select_span {
+                    span.set_error(&e);
+                }
+                return Box::pin(std::future::ready(Err(e)));
+            }
+        };
+        drop(select_span);
+
+        let span = tracing::Span::current();
+        span.record("route", route.as_str());
+        span.record("backend", backend.id.0);
+        if let Some(timer) = req.extensions().get::<PhaseTimer>() {
+            timer.routed(&route);
+        }
+        req.extensions_mut().insert(RouteContext { route, backend, labels: labels.clone() });
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            res.extensions_mut().insert(RouteLabels(labels));
+            Ok(res)
+        })
+    }
+}
+
+/// Applies the admin-managed fault injection rules.
+#[derive(Debug, Clone)]
+pub struct FaultInjectionLayer {
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectionLayer {
+    /// Create a layer evaluating the given injector's rules.
+    pub fn new(injector: Arc<FaultInjector>) -> Self {
+        Self { injector }
+    }
+}
+
+impl<S> Layer<S> for FaultInjectionLayer {
+    type Service = FaultInjectionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FaultInjectionService {
+            inner,
+            injector: self.injector.clone(),
+        }
+    }
+}
+
+/// Service produced by `FaultInjectionLayer`.
+#[derive(Debug, Clone)]
+pub struct FaultInjectionService<S> {
+    inner: S,
+    injector: Arc<FaultInjector>,
+}
+
+impl<S> Service<ProxyRequest> for FaultInjectionService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        // Chaos testing: delay and/or abort the request before it reaches any backend
+        let fault = self.injector.evaluate(req.uri().path());
+        let mut inner = take_inner(&mut self.inner);
+
+        Box::pin(async move {
+            if let Some(delay) = fault.delay {
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(status) = fault.abort_status {
+                let status = StatusCode::from_u16(status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+                return Ok(local_response(status, "fault injected\n"));
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+/// Executes the Wasm L7 filter natively via Wasmtime.
+///
+/// With filter chains configured, runs the request's route chain in phase
+/// order: a filter returning a 2xx code passes the request on, any other
+/// status code ends it with that status. A filter that fails to run rejects
+/// the request too, since skipping e.g. an authz filter would fail open.
+/// With breakers, a filter failing repeatedly is bypassed or fails closed
+/// for a while instead, per their policy.
+#[derive(Clone)]
+pub struct WasmFilterLayer {
+    engine: Arc<WasmEngine>,
+    chains: Option<Arc<RouteFilterChains>>,
+    breakers: Option<Arc<FilterBreakers>>,
+}
+
+impl WasmFilterLayer {
+    /// Create a layer running filters on the given engine.
+    pub fn new(engine: Arc<WasmEngine>) -> Self {
+        Self {
+            engine,
+            chains: None,
+            breakers: None,
+        }
+    }
+
+    /// Trip per-filter circuit breakers kept in `breakers`, builder style.
+    pub fn with_breakers(mut self, breakers: Arc<FilterBreakers>) -> Self {
+        self.breakers = Some(breakers);
+        self
+    }
+
+    /// Run `chains` instead of the built-in demo filter, builder style.
+    pub fn with_chains(mut self, chains: RouteFilterChains) -> Self {
+        self.chains = Some(Arc::new(chains));
+        self
+    }
+}
+
+impl<S> Layer<S> for WasmFilterLayer {
+    type Service = WasmFilterService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        WasmFilterService {
+            inner,
+            engine: self.engine.clone(),
+            chains: self.chains.clone(),
+            breakers: self.breakers.clone(),
+        }
+    }
+}
+
+/// Service produced by `WasmFilterLayer`.
+#[derive(Clone)]
+pub struct WasmFilterService<S> {
+    inner: S,
+    engine: Arc<WasmEngine>,
+    chains: Option<Arc<RouteFilterChains>>,
+    breakers: Option<Arc<FilterBreakers>>,
+}
+
+impl<S> WasmFilterService<S> {
+    /// Runs `chain`, returning the response of the filter that rejected the request, if any.
+    #[cfg_attr(not(feature = "lua"), allow(unused_variables))]
+    fn run_chain(&self, chain: &FilterChain, req: &mut ProxyRequest) -> Option<ProxyResponse> {
+        for filter in chain.filters() {
+            let started = Instant::now();
+            if let Some(breakers) = &self.breakers {
+                if !breakers.allows(&filter.name, started) {
+                    match breakers.policy().when_open {
+                        OpenAction::Bypass => continue,
+                        OpenAction::FailClosed => {
+                            return Some(local_response(StatusCode::SERVICE_UNAVAILABLE, "filter unavailable\n"))
+                        }
+                    }
+                }
+            }
+
+            let result = match &filter.code {
+                FilterCode::Wasm(module) => self.engine.execute_filter(module),
+                #[cfg(feature = "lua")]
+                FilterCode::Lua(script) => run_lua(script, req),
+                _ => Err(FilterError::Script("filter kind not supported by this build".into())),
+            };
+            if let Some(breakers) = &self.breakers {
+                let now = Instant::now();
+                if let Some(event) = breakers.record(&filter.name, result.is_ok(), now - started, now) {
+                    let event = match event {
+                        BreakerEvent::Opened => "opened",
+                        BreakerEvent::Closed => "closed",
+                    };
+                    tracing::warn!(filter = %filter.name, "circuit breaker {}", event);
+                    metrics::FILTER_BREAKER_EVENTS.with_label_values(&[&filter.name, event]).inc();
+                }
+            }
+            match result {
+                Ok(code) if (200..300).contains(&code) => {}
+                Ok(code) => {
+                    let status = u16::try_from(code)
+                        .ok()
+                        .and_then(|code| StatusCode::from_u16(code).ok())
+                        .unwrap_or(StatusCode::FORBIDDEN);
+                    return Some(local_response(status, "rejected by filter\n"));
+                }
+                Err(e) => {
+                    tracing::warn!(filter = %filter.name, phase = %filter.phase, error = %e, "filter failed");
+                    return Some(local_response(StatusCode::INTERNAL_SERVER_ERROR, "filter failed\n"));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Runs a Lua filter against `req`, applying the headers it changed.
+#[cfg(feature = "lua")]
+fn run_lua(script: &LuaFilter, req: &mut ProxyRequest) -> Result<i32, FilterError> {
+    use hyper::header::{HeaderName, HeaderValue};
+    use std::collections::BTreeMap;
+
+    let mut headers = BTreeMap::new();
+    for name in req.headers().keys() {
+        let values: Vec<_> = req.headers().get_all(name).iter().map(|v| String::from_utf8_lossy(v.as_bytes())).collect();
+        headers.insert(name.as_str().to_string(), values.join(", "));
+    }
+    let backend = req
+        .extensions()
+        .get::<RouteContext>()
+        .map(|ctx| ctx.backend.metadata.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+        .unwrap_or_default();
+    let mut view = ScriptRequest {
+        method: req.method().to_string(),
+        path: req.uri().path_and_query().map_or("/", |p| p.as_str()).to_string(),
+        headers,
+        backend,
+    };
+    let original = view.headers.clone();
+    let status = script.run(&mut view)?;
+
+    for name in original.keys().filter(|name| !view.headers.contains_key(*name)) {
+        req.headers_mut().remove(name.as_str());
+    }
+    for (name, value) in view.headers.iter().filter(|(name, value)| original.get(*name) != Some(*value)) {
+        let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) else {
+            return Err(FilterError::Script(format!("script set invalid header {}", name)));
+        };
+        req.headers_mut().insert(name, value);
+    }
+    Ok(status)
+}
+
+impl<S> Service<ProxyRequest> for WasmFilterService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let policy = req.extensions().get::<Arc<ListenerPolicy>>().filter(|p| p.filter_chain.is_some()).cloned();
+        if self.chains.is_some() || policy.is_some() {
+            let chains = self.chains.clone();
+            // The route's own chain, else the listener's, else the default
+            let route_chain = req
+                .extensions()
+                .get::<RouteContext>()
+                .and_then(|ctx| chains.as_ref()?.routes.get(&ctx.route));
+            let listener_chain = policy.as_ref().and_then(|p| p.filter_chain.as_ref());
+            let default_chain = chains.as_ref().map(|c| &c.default);
+            if let Some(chain) = route_chain.or(listener_chain).or(default_chain) {
+                if let Some(res) = self.run_chain(chain, &mut req) {
+                    return Box::pin(std::future::ready(Ok(res)));
+                }
+            }
+            return Box::pin(self.inner.call(req));
+        }
+
+        // For MVP USP Demonstration, we run a static WASM payload yielding an ACCEPT (200).
+        // In production, `vortex_admin` dynamically swaps this bytecode at runtime!
+        let wat_filter = r#"
+            (module
+                (func (export "execute") (result i32)
+                    i32.const 200
+                )
+            )
+        "#;
+        match self.engine.execute_filter(wat_filter.as_bytes()) {
+            Ok(code) => tracing::trace!(code, "Wasm filter executed"),
+            Err(e) => tracing::warn!(error = %e, "Wasm filter execution failed"),
+        }
+
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// The backend that produced a response and how long it took to start
+/// answering, in the response extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamExchange {
+    /// The backend's address
+    pub addr: SocketAddr,
+    /// Time from picking a connection to the response headers
+    pub rtt: Duration,
+}
+
+/// The innermost service: sends the request to the routed backend over a pooled connection.
+#[derive(Debug, Clone)]
+pub struct UpstreamService {
+    connection_pool: ConnectionPool,
+    settings: Arc<UpstreamSettings>,
+}
+
+/// What `UpstreamService` shares with every request, behind one `Arc`.
+#[derive(Debug, Clone, Default)]
+struct UpstreamSettings {
+    socket_options: SocketOptions,
+    duplicate_headers: DuplicateHeaders,
+    connect_pacer: Option<ConnectPacer>,
+    targets: UpstreamTargets,
+}
+
+impl UpstreamService {
+    /// Create the upstream service drawing connections from `connection_pool`.
+    pub fn new(connection_pool: ConnectionPool) -> Self {
+        Self {
+            connection_pool,
+            settings: Arc::default(),
+        }
+    }
+
+    /// Apply socket options to newly opened upstream connections, builder style.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        Arc::make_mut(&mut self.settings).socket_options = options;
+        self
+    }
+
+    /// Handle upstream responses repeating framing headers as `policy` says,
+    /// builder style. By default they are rejected from every backend.
+    pub fn with_duplicate_headers(mut self, policy: DuplicateHeaders) -> Self {
+        Arc::make_mut(&mut self.settings).duplicate_headers = policy;
+        self
+    }
+
+    /// Pace new connections to each backend as `pacing` says, builder style.
+    /// By default requests missing the pool connect at once.
+    pub fn with_connect_pacing(mut self, pacing: ConnectPacing) -> Self {
+        Arc::make_mut(&mut self.settings).connect_pacer = Some(ConnectPacer::new(pacing));
+        self
+    }
+}
+
+impl Service<ProxyRequest> for UpstreamService {
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        Box::pin(forward_request(req, self.connection_pool.clone(), self.settings.clone()))
+    }
+}
+
+/// Proxies a routed request to its selected backend.
+async fn forward_request(
+    mut req: ProxyRequest,
+    connection_pool: ConnectionPool,
+    settings: Arc<UpstreamSettings>,
+) -> Result<ProxyResponse, ProxyError> {
+    let Some(ewma_node) = req.extensions().get::<RouteContext>().map(|ctx| ctx.backend.clone()) else {
+        return Err(ProxyError::NoHealthyBackend {
+            route: req.uri().path().to_string(),
+        });
+    };
+    let upstream_addr = ewma_node.addr;
+    let backend_id = ewma_node.id;
+    let pool_key = PoolKey::from(&*ewma_node);
+    let deadline = req.extensions().get::<Deadline>().cloned();
+    let timer = req.extensions().get::<PhaseTimer>().cloned();
+    let trace = req.extensions().get::<RequestTrace>().cloned();
+    // Sent upstream in the request headers, and back to the client below
+    let request_id = RequestId::of(&mut req);
+    let connect_timeout = deadline
+        .as_ref()
+        .map_or(UPSTREAM_CONNECT_TIMEOUT, |d| d.remaining().min(UPSTREAM_CONNECT_TIMEOUT));
+
+    // Increment active request gauge for this specific node
+    // This guard automatically decrements when it falls out of scope (after proxying finishes)
+    let _active_guard = ewma_node.ewma.increment_active();
+
+    // Start RTT timer
+    let start_time = Instant::now();
+
+    // Point the request at the upstream; the body is forwarded as a zero-copy stream
+    settings.targets.point(&mut req, &ewma_node);
+    // The upstream leg is always HTTP/1.1; left at HTTP/2, hyper would refuse to
+    // chunk a body of unknown length and send it empty, trailers and all
+    *req.version_mut() = hyper::Version::HTTP_11;
+
+    // Try popping an existing, warm connection sender from our Hot Pool
+    let mut sender_opt = None;
+    if let Some(mut conn) = connection_pool.try_pop(&pool_key) {
+        if conn.sender.ready().await.is_ok() {
+            sender_opt = Some(conn);
+        } else {
+            connection_pool.retire(pool_key, conn);
+        }
+    }
+    if let (None, Some(pacer)) = (&sender_opt, &settings.connect_pacer) {
+        match pacer.wait_for_turn(&connection_pool, pool_key, connect_timeout).await {
+            Some(Paced::Pooled(conn)) => sender_opt = Some(conn),
+            Some(Paced::Dial) => {}
+            None => return Err(ProxyError::ConnectPaced { backend: backend_id, addr: upstream_addr }),
+        }
+    }
+
+    // Either reuse the hot connection, or establish a new TCP stream to the backend
+    let reused = sender_opt.is_some();
+    let conn = match sender_opt {
+        Some(s) => s,
+        None => {
+            let mut connect_span = trace.as_ref().map(|trace| {
+                let mut span = trace.start("upstream.connect", SpanKind::Internal);
+                span.set_attribute("server.address", upstream_addr.to_string());
+                span
+            });
+            let connect_start = Instant::now();
+            let dns_name = ewma_node.dns_name.as_deref();
+            let s = match dial(backend_id, upstream_addr, dns_name, connect_timeout, &settings.socket_options).await {
+                Ok(s) => s,
+                Err(e) => {
+                    if let Some(span) = &mut connect_span {
+                        span.set_error(&e);
+                    }
+                    return Err(e);
+                }
+            };
+            drop(connect_span);
+
+            if let Some(timer) = &timer {
+                timer.connected(connect_start.elapsed());
+            }
+            connection_pool.register_new(pool_key, s)
+        }
+    };
+    let mut conn = CheckedOut::new(connection_pool.clone(), pool_key, conn);
+    if let Some(deadline) = &deadline {
+        // Tell the backend how much of the client's budget is left after our own work
+        deadline.apply_headers(req.headers_mut());
+    }
+
+    if let Err(source) = conn.sender().ready().await {
+        conn.release(false);
+        return Err(ProxyError::UpstreamProtocol { backend: backend_id, addr: upstream_addr, source });
+    }
+
+    if let Some(collector) = req.extensions().get::<InterimCollector>().cloned() {
+        hyper::ext::on_informational(&mut req, move |res| collector.push(res.status(), res.headers()));
+    }
+
+    // Whether the client asked to close, checked before the headers move upstream
+    let client_closes = pool::asks_to_close(req.headers());
+
+    // Continue the trace from the span covering the upstream exchange
+    let mut upstream_span = trace.as_ref().map(|trace| {
+        let mut span = trace.start("upstream.request", SpanKind::Client);
+        span.set_attribute("server.address", upstream_addr.to_string());
+        span.set_attribute("vortex.backend.id", backend_id.0);
+        span.set_attribute("vortex.connection.reused", reused);
+        let context = TraceContext { sampled: trace.sampled(), ..span.context() };
+        req.headers_mut().insert("traceparent", context.traceparent());
+        span
+    });
+
+    if let Some(timer) = &timer {
+        timer.sent(reused);
+    }
+    let mut res = match conn.sender().send_request(req).await {
+        Ok(res) => res,
+        Err(e) => {
+            // The connection died mid-exchange; make sure no sibling idle sender to the
+            // same backend is handed out if it went down with it.
+            conn.release(false);
+            connection_pool.evict_closed(&pool_key);
+            let err = ProxyError::UpstreamProtocol { backend: backend_id, addr: upstream_addr, source: e };
+            if let Some(span) = &mut upstream_span {
+                span.set_error(&err);
+            }
+            return Err(err);
+        }
+    };
+    if let Some(span) = &mut upstream_span {
+        span.set_attribute("http.response.status_code", res.status().as_u16());
+    }
+
+    if let Some(timer) = &timer {
+        timer.first_byte();
+    }
+
+    if let Err(reason) = settings.duplicate_headers.enforce(upstream_addr, res.headers_mut()) {
+        // The body is left unread, so the connection can't carry another exchange
+        conn.release(false);
+        let err = ProxyError::InvalidUpstreamResponse { backend: backend_id, addr: upstream_addr, reason };
+        if let Some(span) = &mut upstream_span {
+            span.set_error(&err);
+        }
+        return Err(err);
+    }
+    drop(upstream_span);
+
+    // Return the sender cleanly to the Lock-Free pool for reuse by another request,
+    // unless the upstream announced it is about to close the connection.
+    conn.release(pool::is_reusable(res.version(), client_closes, res.headers()));
+
+    // Record the round-trip latency and feed it into the Peak EWMA algorithm lock-free
+    let rtt = start_time.elapsed();
+    ewma_node.ewma.observe_latency(rtt.as_secs_f64() * 1000.0);
+
+    let mut res = res.map(|body: Incoming| body.boxed());
+    res.headers_mut().insert(REQUEST_ID, request_id.header_value().clone());
+    res.extensions_mut().insert(UpstreamExchange { addr: upstream_addr, rtt });
+    Ok(res)
+}
+
+/// Points `req` at `path`, keeping its query string.
+pub(crate) fn replace_path(req: &mut ProxyRequest, path: &str) -> Result<(), String> {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    if !path_and_query.starts_with('/') {
+        return Err(format!("rewritten path {:?} does not start with /", path));
+    }
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query =
+        Some(path_and_query.parse().map_err(|e| format!("rewritten path {:?} is invalid: {}", path, e))?);
+    *req.uri_mut() = Uri::from_parts(parts).map_err(|e| format!("rewritten path {:?} is invalid: {}", path, e))?;
+    Ok(())
+}
+
+/// Opens a new HTTP/1.1 connection to a backend, at the next address of
+/// `dns_name` if it is resolved per connection. The lookup counts against
+/// the connect timeout.
+async fn dial(
+    backend: BackendId,
+    addr: SocketAddr,
+    dns_name: Option<&str>,
+    connect_timeout: Duration,
+    socket_options: &SocketOptions,
+) -> Result<hyper::client::conn::http1::SendRequest<ProxyBody>, ProxyError> {
+    let connect = async {
+        let target = match dns_name {
+            Some(name) => dns::next_address(name).await?,
+            None => addr,
+        };
+        TcpStream::connect(target).await
+    };
+    let stream = match tokio::time::timeout(connect_timeout, connect).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(source)) => return Err(ProxyError::UpstreamConnect { backend, addr, source }),
+        Err(_) => return Err(ProxyError::UpstreamTimeout { backend, addr, phase: "connect" }),
+    };
+    let target = stream.peer_addr().unwrap_or(addr);
+    socket_options.apply_or_log(&stream, &format!("upstream connection to {}", target));
+
+    let io = TokioIo::new(stream);
+
+    // Perform the HTTP/1.1 handshake with the upstream server
+    let (sender, conn) = hyper::client::conn::http1::handshake(io)
+        .await
+        .map_err(|source| ProxyError::UpstreamProtocol { backend, addr, source })?;
+
+    // Spawn a task to drive the connection
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            tracing::debug!(error = %err, "upstream connection failed");
+        }
+    });
+    Ok(sender)
+}
+
+/// A pooled connection checked out for one exchange.
+///
+/// If the exchange is abandoned before `release` (a hedge lost the race, the
+/// client went away), the connection is in an unknown state mid-message, so
+/// it is retired instead of being handed to the next request.
+struct CheckedOut {
+    pool: ConnectionPool,
+    key: PoolKey,
+    conn: Option<PooledConnection>,
+}
+
+impl CheckedOut {
+    fn new(pool: ConnectionPool, key: PoolKey, conn: PooledConnection) -> Self {
+        Self { pool, key, conn: Some(conn) }
+    }
+
+    fn sender(&mut self) -> &mut hyper::client::conn::http1::SendRequest<ProxyBody> {
+        &mut self.conn.as_mut().expect("present until released").sender
+    }
+
+    /// Hands the connection back to the pool, or retires it if it must not be reused.
+    fn release(mut self, reusable: bool) {
+        if let Some(conn) = self.conn.take() {
+            if reusable {
+                self.pool.push(self.key, conn);
+            } else {
+                self.pool.retire(self.key, conn);
+            }
+        }
+    }
+}
+
+impl Drop for CheckedOut {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            metrics::UPSTREAM_CANCELLED.inc();
+            self.pool.retire(self.key, conn);
+        }
+    }
+}
+
+/// Adapts the pipeline to hyper: boxes the incoming body and renders any
+/// `ProxyError` as an error page so the connection stays usable.
+#[derive(Clone)]
+pub struct HyperAdapter {
+    pipeline: ProxyService,
+    peer: Option<SocketAddr>,
+    original_dst: Option<SocketAddr>,
+    header_limits: Option<HeaderLimits>,
+    connection_trace: Option<ConnectionTrace>,
+    policy: Option<Arc<ListenerPolicy>>,
+}
+
+impl HyperAdapter {
+    /// Wrap an assembled pipeline.
+    pub fn new(pipeline: ProxyService) -> Self {
+        Self {
+            pipeline,
+            peer: None,
+            original_dst: None,
+            header_limits: None,
+            connection_trace: None,
+            policy: None,
+        }
+    }
+
+    /// Tag every request on this connection with the downstream peer's `ClientAddr`.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Tag every request on this intercepted connection with its `OriginalDst`.
+    ///
+    /// Requests that name no host (e.g. from HTTP/1.0 clients) get the
+    /// original destination as their `Host`, so host-based routes still apply.
+    pub fn with_original_dst(mut self, original_dst: SocketAddr) -> Self {
+        self.original_dst = Some(original_dst);
+        self
+    }
+
+    /// Reject requests (431) and upstream responses (502) whose headers break `limits`.
+    pub fn with_header_limits(mut self, limits: HeaderLimits) -> Self {
+        self.header_limits = Some(limits);
+        self
+    }
+
+    /// Tag every request on this connection with the connection's `ConnectionTrace`.
+    pub fn with_connection_trace(mut self, trace: ConnectionTrace) -> Self {
+        self.connection_trace = Some(trace);
+        self
+    }
+
+    /// Tag every request on this connection with its listener's `ListenerPolicy`.
+    pub fn with_policy(mut self, policy: Arc<ListenerPolicy>) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+}
+
+impl Service<Request<Incoming>> for HyperAdapter {
+    type Response = ProxyResponse;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<ProxyResponse, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Incoming>) -> Self::Future {
+        let mut pipeline = self.pipeline.clone();
+        let peer = self.peer;
+        let original_dst = self.original_dst;
+        let header_limits = self.header_limits;
+        let connection_trace = self.connection_trace;
+        let policy = self.policy.clone();
+        let request_id = RequestId::of(&mut req);
+        let span = tracing::info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.uri().path(),
+            request_id = %request_id,
+            route = tracing::field::Empty,
+            backend = tracing::field::Empty,
+        );
+
+        Box::pin(async move {
+            let mut req = req.map(|body| body.boxed());
+            if let Some(peer) = peer {
+                req.extensions_mut().insert(ClientAddr(peer));
+            }
+            if let Some(connection_trace) = connection_trace {
+                req.extensions_mut().insert(connection_trace);
+            }
+            if let Some(policy) = policy {
+                req.extensions_mut().insert(policy);
+            }
+            if let Some(original_dst) = original_dst {
+                if req.uri().authority().is_none() && !req.headers().contains_key(hyper::header::HOST) {
+                    let host = original_dst.to_string().parse().expect("socket addresses are valid header values");
+                    req.headers_mut().insert(hyper::header::HOST, host);
+                }
+                req.extensions_mut().insert(OriginalDst(original_dst));
+            }
+            let result = match header_limits.map(|limits| limits.check(req.headers())) {
+                Some(Err(reason)) => Err(ProxyError::RequestHeadersTooLarge { reason }),
+                _ => match std::future::poll_fn(|cx| pipeline.poll_ready(cx)).await {
+                    Ok(()) => pipeline.call(req).await,
+                    Err(e) => Err(e),
+                },
+            };
+            let result = result.and_then(|res| match header_limits.map(|limits| limits.check(res.headers())) {
+                Some(Err(reason)) => Err(ProxyError::ResponseHeadersTooLarge { reason }),
+                _ => Ok(res),
+            });
+
+            let mut res = match result {
+                Ok(res) => res,
+                Err(e) => {
+                    let backend = e.backend().map(|id| id.0);
+                    tracing::warn!(kind = e.kind(), backend, error = %e, "request failed");
+                    metrics::REQUEST_ERRORS.with_label_values(&[e.kind()]).inc();
+                    error_response(&e)
+                }
+            };
+            // Responses the proxy made itself carry the ID too
+            res.headers_mut().entry(REQUEST_ID).or_insert_with(|| request_id.header_value().clone());
+            Ok(res)
+        }
+        .instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use tower::ServiceExt;
+    use vortex_core::domain::backend::{Backend, BackendId};
+    use vortex_core::domain::routing::RoutingTable;
+
+    /// The built-in stages with every optional feature off.
+    fn standard_stages(routing_table: SharedRoutingTable) -> StandardStages {
+        StandardStages {
+            routing_table,
+            routes: None,
+            clusters: HashMap::new(),
+            force_backend: None,
+            wasm_engine: Arc::default(),
+            fault_injector: Arc::new(FaultInjector::new()),
+            request_metrics: RequestMetrics::new(Vec::<String>::new()).unwrap(),
+            traffic: None,
+            sampling: None,
+            tracing: None,
+            deadlines: DeadlineConfig::default(),
+            retry: None,
+            hedging: None,
+            passive_health: None,
+            client_concurrency: None,
+            client_quota: None,
+            normalization: None,
+            compression: None,
+            redaction: None,
+            body_codecs: None,
+            html_rewrites: None,
+            response_limits: None,
+            request_rules: None,
+            experiments: None,
+            filter_chains: None,
+            filter_breakers: None,
+            upstream_credentials: None,
+            caching: None,
+            interim: None,
+            access_log: None,
+            access_log_format: None,
+            server_timing: None,
+        }
+    }
+
+    fn empty_request() -> ProxyRequest {
+        Request::builder()
+            .method("GET")
+            .uri("/")
+            .body(Empty::<Bytes>::new().map_err(|never| match never {}).boxed())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forward_request_routes_to_9090() {
+        // Without starting the backend, the direct TCP connect inside forward_request
+        // will return ConnectionRefused wrapped in ProxyError::UpstreamConnect. We assert this specific failure
+        // to verify that the routing logic is at least attempting to hit the right static port.
+        let addr = "127.0.0.1:9".parse().unwrap();
+        let routing_table = Arc::new(RoutingTable::new(vec![Arc::new(Backend::new(BackendId(1), addr))]));
+        let service = PipelineBuilder::new()
+            .layer(Stage::Route, RouteLayer::new(routing_table))
+            .build(UpstreamService::new(ConnectionPool::new()));
+
+        match service.oneshot(empty_request()).await {
+            Err(ProxyError::UpstreamConnect { backend, addr: dialed, .. }) => {
+                assert_eq!(backend, BackendId(1));
+                assert_eq!(dialed, addr);
+            }
+            other => panic!("expected an upstream connect error, got {:?}", other.map(|r| r.status())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_exchanges_release_their_active_request() {
+        // A backend that accepts connections and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted_tx, mut accepted) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = accepted_tx.send(stream);
+            }
+        });
+        let backend = Arc::new(Backend::new(BackendId(1), addr));
+        let routing_table = Arc::new(RoutingTable::new(vec![backend.clone()]));
+        let service = PipelineBuilder::new()
+            .layer(Stage::Route, RouteLayer::new(routing_table))
+            .build(UpstreamService::new(ConnectionPool::new()));
+
+        // The client goes away while the request is in flight
+        let in_flight = tokio::spawn(service.clone().oneshot(empty_request()));
+        let _stream = accepted.recv().await.unwrap();
+        assert_eq!(backend.ewma.active_requests(), 1);
+        in_flight.abort();
+        assert!(in_flight.await.unwrap_err().is_cancelled());
+        assert_eq!(backend.ewma.active_requests(), 0);
+
+        // A deadline cutting it short does the same
+        let timed_out = tokio::time::timeout(Duration::from_millis(50), service.oneshot(empty_request())).await;
+        assert!(timed_out.is_err());
+        assert_eq!(backend.ewma.active_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sampling_sees_the_normalized_path() {
+        use crate::sampling::{RouteSampling, TraceSampling};
+
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap()));
+        let mut stages = standard_stages(Arc::new(RoutingTable::new(vec![backend])));
+        stages.normalization = Some(RequestNormalization::default());
+        stages.sampling = Some(SamplingPolicy {
+            default_ratio: 1.0,
+            routes: vec![RouteSampling { route_prefix: "/admin/".into(), ratio: 0.0 }],
+            ..SamplingPolicy::default()
+        });
+        let service = stages.into_pipeline().build(tower::service_fn(|req: ProxyRequest| async move {
+            assert_eq!(req.uri().path(), "/admin/keys");
+            // Spelling the path oddly doesn't escape the route's sampling ratio
+            assert!(!req.extensions().get::<TraceSampling>().unwrap().sampled);
+            Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+        }));
+
+        for path in ["/admin/keys", "//admin/./keys", "/%61dmin/keys"] {
+            let mut req = empty_request();
+            *req.uri_mut() = path.parse().unwrap();
+            assert_eq!(service.clone().oneshot(req).await.unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_top_talkers_count_requests_by_route() {
+        use vortex_core::route::{RouteSpec, RouteTable};
+        use vortex_core::stats::{TalkerDimension, TrafficStatsSource};
+
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap()));
+        let tracker = Arc::new(TrafficTracker::new(Duration::from_secs(60), 4));
+        let mut stages = standard_stages(Arc::new(RoutingTable::new(vec![backend])));
+        stages.routes = Some(Arc::new(RouteTable::new(vec![RouteSpec::new("users", "/users/")])));
+        stages.traffic = Some(tracker.clone());
+        let service = stages.into_pipeline().build(tower::service_fn(|_req: ProxyRequest| async {
+            Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+        }));
+
+        // More distinct paths than the tracker keeps, all one route
+        for id in 0..10 {
+            let mut req = empty_request();
+            *req.uri_mut() = format!("/users/{}", id).parse().unwrap();
+            service.clone().oneshot(req).await.unwrap();
+        }
+        let routes = tracker.top_talkers(TalkerDimension::Route, 5);
+        assert_eq!((routes[0].key.as_str(), routes[0].requests), ("users", 10));
+        assert_eq!(routes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_backends_is_reported_by_route_stage() {
+        let routing_table = Arc::new(RoutingTable::new(Vec::new()));
+        let service = PipelineBuilder::new()
+            .layer(Stage::Route, RouteLayer::new(routing_table))
+            .build(UpstreamService::new(ConnectionPool::new()));
+
+        let err = service.oneshot(empty_request()).await.unwrap_err();
+        assert_eq!(err.kind(), "no_healthy_backend");
+    }
+
+    #[tokio::test]
+    async fn test_request_metrics_carry_pool_labels() {
+        let labels = Labels::new().with("team", "payments").with("environment", "staging");
+        let routing_table = Arc::new(RoutingTable::new(Vec::new()).with_labels(labels.clone()));
+        let request_metrics = RequestMetrics::new(["team", "service", "environment"]).unwrap();
+        let service = PipelineBuilder::new()
+            .layer(Stage::Route, RequestMetricsLayer::new(routing_table.clone(), request_metrics.clone()))
+            .layer(Stage::Route, RouteLayer::new(routing_table))
+            .build(UpstreamService::new(ConnectionPool::new()));
+
+        service.oneshot(empty_request()).await.unwrap_err();
+        assert_eq!(request_metrics.request_count(&labels, StatusCode::SERVICE_UNAVAILABLE), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_metrics_carry_route_labels() {
+        use vortex_core::route::{RouteSpec, RouteTable};
+
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap()));
+        let routing_table = Arc::new(RoutingTable::new(vec![backend]).with_labels(Labels::new().with("team", "core")));
+        let routes = Arc::new(RouteTable::new(vec![
+            RouteSpec::new("checkout", "/").with_labels(Labels::new().with("team", "checkout")),
+        ]));
+        let request_metrics = RequestMetrics::new(["team"]).unwrap();
+        let service = PipelineBuilder::new()
+            .layer(Stage::Route, RequestMetricsLayer::new(routing_table.clone(), request_metrics.clone()))
+            .layer(Stage::Route, RouteLayer::new(routing_table).with_routes(routes))
+            .build(tower::service_fn(|_req: ProxyRequest| async {
+                Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+            }));
+
+        let res = service.oneshot(empty_request()).await.unwrap();
+        assert_eq!(res.extensions().get::<RouteLabels>().unwrap().0.get("team"), Some("checkout"));
+        let route = Labels::new().with("team", "checkout");
+        assert_eq!(request_metrics.request_count(&route, StatusCode::OK), 1);
+        assert_eq!(request_metrics.request_count(&Labels::new().with("team", "core"), StatusCode::OK), 0);
+    }
+
+    #[tokio::test]
+    async fn test_route_stage_matches_declared_routes() {
+        use vortex_core::route::{RouteSpec, RouteTable};
+
+        let backend = Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap()));
+        let routing_table = Arc::new(RoutingTable::new(vec![backend]).with_labels(Labels::new().with("team", "core")));
+        let routes = Arc::new(RouteTable::new(vec![
+            RouteSpec::new("users", "/users").with_labels(Labels::new().with("service", "accounts")),
+        ]));
+        let service = RouteLayer::new(routing_table)
+            .with_routes(routes)
+            .layer(tower::service_fn(|req: ProxyRequest| async move {
+                let ctx = req.extensions().get::<RouteContext>().unwrap();
+                assert_eq!(ctx.route, "users");
+                assert_eq!(ctx.labels.get("team"), Some("core"));
+                assert_eq!(ctx.labels.get("service"), Some("accounts"));
+                Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+            }));
+
+        let mut req = empty_request();
+        *req.uri_mut() = "/users/7".parse().unwrap();
+        assert_eq!(service.clone().oneshot(req).await.unwrap().status(), StatusCode::OK);
+
+        let err = service.oneshot(empty_request()).await.unwrap_err();
+        assert_eq!(err.kind(), "no_route_match");
+    }
+
+    #[tokio::test]
+    async fn test_route_stage_selects_from_the_route_cluster() {
+        use vortex_core::route::{RouteSpec, RouteTable};
+
+        let pool = |id: u32, team: &str| {
+            let backend = Arc::new(Backend::new(BackendId(id), format!("127.0.0.{}:9", id).parse().unwrap()));
+            Arc::new(RoutingTable::new(vec![backend]).with_labels(Labels::new().with("team", team)))
+        };
+        let routes = Arc::new(RouteTable::new(vec![
+            RouteSpec::new("api", "/api/").with_cluster("api"),
+            RouteSpec::new("legacy", "/legacy/").with_cluster("retired"),
+        ]));
+        let api = pool(2, "platform");
+        let service = RouteLayer::new(pool(1, "core"))
+            .with_routes(routes)
+            .with_cluster("api", api.clone())
+            .layer(tower::service_fn(|req: ProxyRequest| async move {
+                let ctx = req.extensions().get::<RouteContext>().unwrap();
+                if ctx.route == "api" {
+                    assert_eq!((ctx.backend.id, ctx.labels.get("team")), (BackendId(2), Some("platform")));
+                    // Retries and hedges pick from the same cluster
+                    let retry = select_backend(&Arc::new(RoutingTable::new(Vec::new())), req.extensions(), &[]);
+                    assert_eq!(retry.map(|b| b.id), Some(BackendId(2)));
+                } else {
+                    assert_eq!((ctx.backend.id, ctx.labels.get("team")), (BackendId(1), Some("core")));
+                }
+                Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+            }));
+
+        // A route naming a cluster that wasn't added stays in the default pool
+        for path in ["/api/users", "/legacy/users"] {
+            let mut req = empty_request();
+            *req.uri_mut() = path.parse().unwrap();
+            assert_eq!(service.clone().oneshot(req).await.unwrap().status(), StatusCode::OK);
+        }
+        api.update_backends(Vec::new());
+        let mut req = empty_request();
+        *req.uri_mut() = "/api/users".parse().unwrap();
+        assert_eq!(service.oneshot(req).await.unwrap_err().kind(), "no_healthy_backend");
+    }
+
+    #[tokio::test]
+    async fn test_route_stage_balances_within_the_route_subset() {
+        use vortex_core::load_balancer::subset::Subset;
+        use vortex_core::route::{RouteSpec, RouteTable};
+
+        let backends = [("v1", 1), ("v2", 2)].map(|(version, id)| {
+            Arc::new(Backend::new(BackendId(id), "127.0.0.1:9".parse().unwrap()).with_metadata("version", version))
+        });
+        let routing_table = Arc::new(RoutingTable::new(backends.to_vec()));
+        let selector = [("version".to_string(), "v2".to_string())].into();
+        let routes = Arc::new(RouteTable::new(vec![RouteSpec::new("beta", "/").with_subset(Subset::new(selector))]));
+        let service = RouteLayer::new(routing_table)
+            .with_routes(routes)
+            .layer(tower::service_fn(|req: ProxyRequest| async move {
+                assert_eq!(req.extensions().get::<RouteContext>().unwrap().backend.id, BackendId(2));
+                assert!(req.extensions().get::<RouteSubset>().is_some());
+                Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+            }));
+
+        for _ in 0..3 {
+            assert_eq!(service.clone().oneshot(empty_request()).await.unwrap().status(), StatusCode::OK);
+        }
+        // Without a fallback, an empty subset fails the request
+        backends[1].set_healthy(false);
+        let err = service.oneshot(empty_request()).await.unwrap_err();
+        assert_eq!(err.kind(), "no_healthy_backend");
+    }
+
+    #[tokio::test]
+    async fn test_debug_override_pins_the_backend() {
+        let backends = [1, 2].map(|id| Arc::new(Backend::new(BackendId(id), "127.0.0.1:9".parse().unwrap())));
+        let routing_table = Arc::new(RoutingTable::new(backends.to_vec()));
+        let service = RouteLayer::new(routing_table)
+            .with_force_backend(ForceBackend::default())
+            .layer(tower::service_fn(|req: ProxyRequest| async move {
+                assert_eq!(req.extensions().get::<RouteContext>().unwrap().backend.id, BackendId(2));
+                assert!(req.headers().get("x-vortex-force-backend").is_none());
+                Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+            }));
+
+        let mut req = empty_request();
+        req.headers_mut().insert("x-vortex-force-backend", "2".parse().unwrap());
+        req.extensions_mut().insert(ClientAddr("127.0.0.1:40000".parse().unwrap()));
+        assert_eq!(service.clone().oneshot(req).await.unwrap().status(), StatusCode::OK);
+
+        let mut req = empty_request();
+        req.headers_mut().insert("x-vortex-force-backend", "3".parse().unwrap());
+        req.extensions_mut().insert(ClientAddr("127.0.0.1:40000".parse().unwrap()));
+        assert_eq!(service.oneshot(req).await.unwrap_err().kind(), "invalid_request");
+    }
+
+    #[tokio::test]
+    async fn test_filter_chain_rejection_stops_the_request() {
+        use vortex_filters::chain::{FilterPhase, FilterSpec};
+
+        let returning = |code: i32| format!(r#"(module (func (export "execute") (result i32) i32.const {}))"#, code);
+        let chain = FilterChain::new(vec![
+            FilterSpec::new("log", FilterPhase::Observability, returning(200).into_bytes()),
+            FilterSpec::new("acl", FilterPhase::Authz, returning(401).into_bytes()),
+        ])
+        .unwrap();
+        let chains = RouteFilterChains {
+            default: FilterChain::default(),
+            routes: [("/admin".to_string(), chain)].into(),
+        };
+        let service = WasmFilterLayer::new(Arc::default())
+            .with_chains(chains)
+            .layer(tower::service_fn(|_req: ProxyRequest| async move {
+                Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+            }));
+
+        let routed = |route: &str| {
+            let mut req = empty_request();
+            req.extensions_mut().insert(RouteContext {
+                route: route.into(),
+                backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap())),
+                labels: Arc::default(),
+            });
+            req
+        };
+        let res = service.clone().oneshot(routed("/admin")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = service.oneshot(routed("/")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_listener_chain_sits_between_route_and_default_chains() {
+        use vortex_filters::chain::{FilterPhase, FilterSpec};
+
+        let returning = |code: i32| format!(r#"(module (func (export "execute") (result i32) i32.const {}))"#, code);
+        let rejecting = |code: i32| {
+            FilterChain::new(vec![FilterSpec::new("auth", FilterPhase::Authn, returning(code).into_bytes())]).unwrap()
+        };
+        let chains = RouteFilterChains {
+            default: rejecting(401),
+            routes: [("/admin".to_string(), rejecting(403))].into(),
+        };
+        let service = WasmFilterLayer::new(Arc::default())
+            .with_chains(chains)
+            .layer(tower::service_fn(|_req: ProxyRequest| async move {
+                Ok::<_, ProxyError>(local_response(StatusCode::OK, "ok"))
+            }));
+
+        // The internal listener skips the default chain's authentication
+        let internal = Arc::new(ListenerPolicy { filter_chain: Some(FilterChain::default()), ..Default::default() });
+        let routed = |route: &str, policy: Option<&Arc<ListenerPolicy>>| {
+            let mut req = empty_request();
+            req.extensions_mut().insert(RouteContext {
+                route: route.into(),
+                backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap())),
+                labels: Arc::default(),
+            });
+            if let Some(policy) = policy {
+                req.extensions_mut().insert(policy.clone());
+            }
+            req
+        };
+        let status = |req| {
+            let service = service.clone();
+            async move { service.oneshot(req).await.unwrap().status() }
+        };
+        assert_eq!(status(routed("/", None)).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(routed("/", Some(&internal))).await, StatusCode::OK);
+        assert_eq!(status(routed("/admin", Some(&internal))).await, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_client_ip_hash_ignores_the_port_and_ipv4_mapping() {
+        let hash = |client: &str| {
+            let mut req = empty_request();
+            req.extensions_mut().insert(ClientAddr(client.parse().unwrap()));
+            RequestHash::of(&req, &HashKey::ClientIp).unwrap()
+        };
+        assert_eq!(hash("192.0.2.7:40000"), hash("192.0.2.7:51000"));
+        assert_eq!(hash("192.0.2.7:40000"), hash("[::ffff:192.0.2.7]:40000"));
+        assert_ne!(hash("192.0.2.7:40000"), hash("192.0.2.8:40000"));
+        assert_eq!(RequestHash::of(&empty_request(), &HashKey::ClientIp), None);
+    }
+
+    #[test]
+    fn test_local_response_carries_status() {
+        let res = local_response(StatusCode::SERVICE_UNAVAILABLE, "fault injected\n");
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,231 @@
+//! Redaction of sensitive JSON fields from request and response bodies.
+//!
+//! Bodies are rewritten as they stream through (see
+//! `vortex_filters::json_redaction`), so e.g. passwords never reach a
+//! logging-heavy upstream and internal identifiers never reach clients. A
+//! body that turns out not to be valid JSON is cut off where the problem was
+//! found rather than forwarded unredacted.
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{HeaderMap, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use http_body_util::BodyExt;
+use prometheus::IntCounterVec;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use vortex_filters::json_redaction::{JsonRedaction, JsonRedactor};
+
+use crate::error::ProxyError;
+use crate::pipeline::{take_inner, ProxyBody, ProxyFuture, ProxyRequest, ProxyResponse};
+
+/// Bodies cut short because they could not be parsed, by `direction`.
+static REDACTION_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_body_redaction_failures_total",
+        "JSON bodies truncated because they could not be redacted",
+        &["direction"]
+    )
+    .expect("metric registers once")
+});
+
+/// Which bodies are redacted, and of what.
+#[derive(Debug, Clone)]
+pub struct BodyRedaction {
+    /// The fields to redact and how
+    pub fields: Arc<JsonRedaction>,
+    /// Redact request bodies before they are forwarded upstream
+    pub requests: bool,
+    /// Redact response bodies before they are returned downstream
+    pub responses: bool,
+}
+
+/// Whether a message carries an uncompressed JSON body.
+fn is_json(headers: &HeaderMap) -> bool {
+    let json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| {
+            let mime = mime.trim();
+            mime.eq_ignore_ascii_case("application/json") || mime.to_ascii_lowercase().ends_with("+json")
+        });
+    json && !headers.contains_key(CONTENT_ENCODING)
+}
+
+/// Rewrites JSON bodies per `BodyRedaction`.
+#[derive(Debug, Clone)]
+pub struct RedactionLayer {
+    config: Arc<BodyRedaction>,
+}
+
+impl RedactionLayer {
+    /// Create a layer redacting per `config`.
+    pub fn new(config: BodyRedaction) -> Self {
+        Self { config: Arc::new(config) }
+    }
+}
+
+impl<S> Layer<S> for RedactionLayer {
+    type Service = RedactionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RedactionService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Service produced by `RedactionLayer`.
+#[derive(Debug, Clone)]
+pub struct RedactionService<S> {
+    inner: S,
+    config: Arc<BodyRedaction>,
+}
+
+impl<S> Service<ProxyRequest> for RedactionService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        if self.config.requests && is_json(req.headers()) {
+            req.headers_mut().remove(CONTENT_LENGTH);
+            req = req.map(|body| RedactedBody::wrap(body, &self.config.fields, "request"));
+        }
+
+        let mut inner = take_inner(&mut self.inner);
+        let config = self.config.clone();
+        Box::pin(async move {
+            let mut res = inner.call(req).await?;
+            if config.responses && is_json(res.headers()) {
+                res.headers_mut().remove(CONTENT_LENGTH);
+                res = res.map(|body| RedactedBody::wrap(body, &config.fields, "response"));
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// A body whose data frames are passed through a `JsonRedactor`.
+struct RedactedBody {
+    inner: ProxyBody,
+    redactor: JsonRedactor,
+    direction: &'static str,
+    done: bool,
+}
+
+impl RedactedBody {
+    fn wrap(inner: ProxyBody, fields: &Arc<JsonRedaction>, direction: &'static str) -> ProxyBody {
+        RedactedBody {
+            inner,
+            redactor: fields.redactor(),
+            direction,
+            done: false,
+        }
+        .boxed()
+    }
+
+    /// Stops the body after a redaction failure.
+    fn fail(&mut self, err: impl std::fmt::Display) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        tracing::warn!(direction = self.direction, error = %err, "truncating body that could not be redacted");
+        REDACTION_FAILURES.with_label_values(&[self.direction]).inc();
+        self.done = true;
+        Poll::Ready(None)
+    }
+}
+
+impl Body for RedactedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            return match Pin::new(&mut this.inner).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => {
+                        let mut out = Vec::with_capacity(data.len());
+                        if let Err(e) = this.redactor.push(&data, &mut out) {
+                            return this.fail(e);
+                        }
+                        if out.is_empty() {
+                            // Everything so far was redacted away
+                            continue;
+                        }
+                        Poll::Ready(Some(Ok(Frame::data(Bytes::from(out)))))
+                    }
+                    Err(frame) => Poll::Ready(Some(Ok(frame))),
+                },
+                Poll::Ready(None) => {
+                    if let Err(e) = this.redactor.finish() {
+                        return this.fail(e);
+                    }
+                    this.done = true;
+                    Poll::Ready(None)
+                }
+                other => other,
+            };
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use hyper::{Request, Response};
+    use tower::ServiceExt;
+    use vortex_filters::json_redaction::RedactionMode;
+
+    #[tokio::test]
+    async fn test_json_bodies_are_redacted_both_ways() {
+        let upstream = tower::service_fn(|req: ProxyRequest| async move {
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, r#"{"user":"ada","password":"[REDACTED]"}"#);
+            let res = Response::builder()
+                .header(CONTENT_TYPE, "application/problem+json")
+                .body(full_body(Bytes::from_static(br#"{"password":"x","ok":true}"#)))
+                .unwrap();
+            Ok::<_, ProxyError>(res)
+        });
+        let config = BodyRedaction {
+            fields: Arc::new(JsonRedaction::new(["password"], RedactionMode::Mask)),
+            requests: true,
+            responses: true,
+        };
+        let service = RedactionLayer::new(config).layer(upstream);
+
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .header(CONTENT_LENGTH, "36")
+            .body(full_body(Bytes::from_static(br#"{"user":"ada","password":"hunter2"}"#)))
+            .unwrap();
+        let res = service.oneshot(req).await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, r#"{"password":"[REDACTED]","ok":true}"#);
+    }
+}
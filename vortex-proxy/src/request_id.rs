@@ -0,0 +1,101 @@
+//! `X-Request-ID` correlation.
+//!
+//! Every request gets an ID as it enters the proxy: the client's own
+//! `X-Request-ID` if it sent a usable one, otherwise a random UUID. The ID
+//! rides in the request's extensions, is recorded on the request's tracing
+//! span and `proxy.request` OTLP span, can be logged with
+//! `%{request_id}x`, and is sent to the backend and back to the client in
+//! `X-Request-ID`, so one request can be followed through every hop.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::Request;
+use rand::Rng;
+use std::fmt;
+
+/// The header carrying the ID.
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied ID that is kept; longer ones are replaced.
+pub const MAX_LEN: usize = 128;
+
+/// The ID of one request, in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+    /// A fresh random (version 4) UUID.
+    pub fn generate() -> Self {
+        let mut bytes: [u8; 16] = rand::thread_rng().gen();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let uuid = format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]);
+        Self(HeaderValue::from_str(&uuid).expect("hex digits are a valid header value"))
+    }
+
+    /// The ID in `headers`, if it is 1 to `MAX_LEN` printable ASCII characters.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(REQUEST_ID)?;
+        let usable = (1..=MAX_LEN).contains(&value.len()) && value.as_bytes().iter().all(|b| b.is_ascii_graphic());
+        usable.then(|| Self(value.clone()))
+    }
+
+    /// The ID of `req`, giving it one if it has none yet. The ID ends up in
+    /// both the extensions and the `X-Request-ID` header.
+    pub fn of<B>(req: &mut Request<B>) -> Self {
+        let id = match req.extensions().get::<RequestId>() {
+            Some(id) => id.clone(),
+            None => {
+                let id = Self::from_headers(req.headers()).unwrap_or_else(Self::generate);
+                req.extensions_mut().insert(id.clone());
+                id
+            }
+        };
+        req.headers_mut().insert(REQUEST_ID, id.0.clone());
+        id
+    }
+
+    /// The ID as a header value.
+    pub fn header_value(&self) -> &HeaderValue {
+        &self.0
+    }
+
+    /// The ID as text.
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().expect("request IDs are printable ASCII")
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_ids_are_v4_uuids() {
+        let id = RequestId::generate();
+        let s = id.as_str();
+        assert_eq!(s.len(), 36);
+        assert_eq!(s.as_bytes()[14], b'4');
+        assert!(matches!(s.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        assert_ne!(RequestId::generate(), id);
+    }
+
+    #[test]
+    fn test_client_ids_are_kept_when_usable() {
+        let mut req = Request::builder().header("x-request-id", "abc-123").body(()).unwrap();
+        assert_eq!(RequestId::of(&mut req).as_str(), "abc-123");
+
+        let mut req = Request::builder().header("x-request-id", "has space").body(()).unwrap();
+        let id = RequestId::of(&mut req);
+        assert_eq!(id.as_str().len(), 36);
+        assert_eq!(req.headers()[REQUEST_ID], id.as_str());
+        // Asking again keeps the ID already assigned
+        assert_eq!(RequestId::of(&mut req), id);
+    }
+}
@@ -0,0 +1,198 @@
+//! Per-route limits on what requests may look like.
+//!
+//! Static asset routes have no business receiving `POST`s, and no route
+//! needs a multi-kilobyte URI. Requests breaking their route's rules are
+//! answered locally, 414 for an overlong URI, 405 (with `Allow`) for a
+//! method outside the allow-list and 403 for a request matching the route's
+//! `deny` condition, before filters run or a backend is used. The URI length
+//! counts the path and query as sent by the client.
+//!
+//! A `deny` condition is a CEL expression (see `vortex_core::route::cel`),
+//! compiled by whoever builds the rules, so `!(source.ip in
+//! cidr('10.0.0.0/8'))` keeps an admin route internal. One that fails to
+//! evaluate, e.g. by reading a header the request lacks, doesn't deny.
+
+use hyper::Method;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use vortex_core::route::cel::CelExpression;
+
+use crate::error::ProxyError;
+use crate::pipeline::{HttpRequestView, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// What requests to a route may look like.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestRules {
+    /// Longest path and query accepted, in bytes
+    pub max_uri_bytes: Option<usize>,
+    /// Methods accepted; any if `None`
+    pub allowed_methods: Option<Vec<Method>>,
+    /// Requests for which this holds are refused
+    pub deny: Option<CelExpression>,
+}
+
+impl RequestRules {
+    /// Check `req` against the rules of `route`.
+    fn check(&self, req: &ProxyRequest, route: &str) -> Result<(), ProxyError> {
+        let uri_bytes = req.uri().path_and_query().map_or(0, |pq| pq.as_str().len());
+        if let Some(limit) = self.max_uri_bytes.filter(|&limit| uri_bytes > limit) {
+            return Err(ProxyError::UriTooLong {
+                route: route.to_string(),
+                limit,
+            });
+        }
+        if let Some(allowed) = self.allowed_methods.as_ref().filter(|allowed| !allowed.contains(req.method())) {
+            return Err(ProxyError::MethodNotAllowed {
+                route: route.to_string(),
+                method: req.method().clone(),
+                allowed: allowed.clone(),
+            });
+        }
+        if let Some(deny) = self.deny.as_ref().filter(|deny| deny.matches(&HttpRequestView(req))) {
+            return Err(ProxyError::PolicyDenied {
+                route: route.to_string(),
+                condition: deny.source().to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Request rules by route name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteRequestRules {
+    /// Rules for routes without their own
+    pub default: RequestRules,
+    /// Rules for individual routes, by route name
+    pub routes: HashMap<String, RequestRules>,
+}
+
+impl RouteRequestRules {
+    fn for_route(&self, route: &str) -> &RequestRules {
+        self.routes.get(route).unwrap_or(&self.default)
+    }
+}
+
+/// Rejects requests breaking their route's `RequestRules`.
+#[derive(Debug, Clone)]
+pub struct RequestRulesLayer {
+    rules: Arc<RouteRequestRules>,
+}
+
+impl RequestRulesLayer {
+    /// Create a layer enforcing `rules`.
+    pub fn new(rules: RouteRequestRules) -> Self {
+        Self { rules: Arc::new(rules) }
+    }
+}
+
+impl<S> Layer<S> for RequestRulesLayer {
+    type Service = RequestRulesService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestRulesService {
+            inner,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+/// Service produced by `RequestRulesLayer`.
+#[derive(Debug, Clone)]
+pub struct RequestRulesService<S> {
+    inner: S,
+    rules: Arc<RouteRequestRules>,
+}
+
+impl<S> Service<ProxyRequest> for RequestRulesService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        if let Some(context) = req.extensions().get::<RouteContext>() {
+            if let Err(e) = self.rules.for_route(&context.route).check(&req, &context.route) {
+                return Box::pin(std::future::ready(Err(e)));
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{error_response, full_body};
+    use hyper::header::ALLOW;
+    use hyper::{Request, StatusCode};
+
+    fn request(method: Method, uri: &str) -> ProxyRequest {
+        Request::builder().method(method).uri(uri).body(full_body(hyper::body::Bytes::new())).unwrap()
+    }
+
+    #[test]
+    fn test_rules_reject_long_uris_and_other_methods() {
+        let rules = RouteRequestRules {
+            default: RequestRules {
+                max_uri_bytes: Some(16),
+                allowed_methods: None,
+                deny: None,
+            },
+            routes: [(
+                "static".to_string(),
+                RequestRules {
+                    max_uri_bytes: None,
+                    allowed_methods: Some(vec![Method::GET, Method::HEAD]),
+                    deny: None,
+                },
+            )]
+            .into(),
+        };
+
+        let api = rules.for_route("api");
+        assert!(api.check(&request(Method::POST, "/items?page=2"), "api").is_ok());
+        let err = api.check(&request(Method::GET, "/items?page=2&size=100"), "api").unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::URI_TOO_LONG);
+
+        let assets = rules.for_route("static");
+        assert!(assets.check(&request(Method::HEAD, "/app.css?v=0123456789abcdef"), "static").is_ok());
+        let err = assets.check(&request(Method::POST, "/app.css"), "static").unwrap_err();
+        assert_eq!(err.kind(), "method_not_allowed");
+        let res = error_response(&err);
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[ALLOW], "GET, HEAD");
+    }
+
+    #[test]
+    fn test_deny_conditions_refuse_matching_requests() {
+        use crate::pipeline::ClientAddr;
+
+        let rules = RequestRules {
+            deny: Some(CelExpression::compile("!(source.ip in cidr('10.0.0.0/8'))").unwrap()),
+            ..RequestRules::default()
+        };
+        let from = |addr: &str| {
+            let mut req = request(Method::GET, "/admin");
+            req.extensions_mut().insert(ClientAddr(addr.parse().unwrap()));
+            req
+        };
+
+        assert!(rules.check(&from("10.1.2.3:5000"), "admin").is_ok());
+        assert!(rules.check(&from("[::ffff:10.1.2.3]:5000"), "admin").is_ok());
+        let err = rules.check(&from("192.168.0.1:5000"), "admin").unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(err.kind(), "policy_denied");
+        // Unknown client addresses fail evaluation, which never denies
+        assert!(rules.check(&request(Method::GET, "/admin"), "admin").is_ok());
+    }
+}
@@ -0,0 +1,306 @@
+//! Maximum response body sizes per route.
+//!
+//! A runaway upstream (an unbounded export, a debug dump left on) shouldn't
+//! be able to flood clients or the proxy's own buffers. Responses that
+//! declare a `Content-Length` are judged up front; the rest are buffered up
+//! to the limit, which keeps memory bounded while still allowing a clean 502
+//! instead of a body that breaks off mid-stream.
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::pipeline::{buffered_body, take_inner, ProxyBody, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+use crate::server::ListenerPolicy;
+
+/// Marks a response whose body was cut short at the route's limit.
+pub const TRUNCATED_HEADER: HeaderName = HeaderName::from_static("x-vortex-truncated");
+
+/// Oversized responses, by the `action` taken: `aborted` or `truncated`.
+static OVERSIZED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_responses_oversized_total",
+        "Upstream responses over their route's size limit",
+        &["action"]
+    )
+    .expect("metric registers once")
+});
+
+/// What to do with a response over the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizeAction {
+    /// Replace it with a 502
+    Abort,
+    /// Forward the first `max_bytes` bytes, marked with `X-Vortex-Truncated`
+    Truncate,
+}
+
+/// A response size limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseLimit {
+    /// Largest body forwarded, in bytes
+    pub max_bytes: u64,
+    /// What happens to larger ones
+    pub action: OversizeAction,
+}
+
+/// Response size limits by route name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseLimits {
+    /// Limit for routes without their own, if any
+    pub default: Option<ResponseLimit>,
+    /// Limits for individual routes, by route name
+    pub routes: HashMap<String, ResponseLimit>,
+}
+
+impl ResponseLimits {
+    /// The route's own limit, else the listener's, else the default.
+    fn for_route(&self, route: &str, listener: Option<ResponseLimit>) -> Option<ResponseLimit> {
+        self.routes.get(route).copied().or(listener).or(self.default)
+    }
+}
+
+/// Enforces `ResponseLimits` on upstream responses.
+#[derive(Debug, Clone)]
+pub struct ResponseLimitLayer {
+    limits: Arc<ResponseLimits>,
+}
+
+impl ResponseLimitLayer {
+    /// Create a layer enforcing `limits`.
+    pub fn new(limits: ResponseLimits) -> Self {
+        Self { limits: Arc::new(limits) }
+    }
+}
+
+impl<S> Layer<S> for ResponseLimitLayer {
+    type Service = ResponseLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ResponseLimitService {
+            inner,
+            limits: self.limits.clone(),
+        }
+    }
+}
+
+/// Service produced by `ResponseLimitLayer`.
+#[derive(Debug, Clone)]
+pub struct ResponseLimitService<S> {
+    inner: S,
+    limits: Arc<ResponseLimits>,
+}
+
+impl<S> Service<ProxyRequest> for ResponseLimitService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let context = req.extensions().get::<RouteContext>().cloned();
+        let listener = req.extensions().get::<Arc<ListenerPolicy>>().and_then(|p| p.response_limit);
+        let limit = context.as_ref().and_then(|ctx| self.limits.for_route(&ctx.route, listener));
+        let Some((context, limit)) = context.zip(limit) else {
+            return Box::pin(self.inner.call(req));
+        };
+
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let res = inner.call(req).await?;
+            enforce(res, &context, limit).await
+        })
+    }
+}
+
+async fn enforce(res: ProxyResponse, context: &RouteContext, limit: ResponseLimit) -> Result<ProxyResponse, ProxyError> {
+    let too_large = || ProxyError::ResponseTooLarge {
+        route: context.route.clone(),
+        limit: limit.max_bytes,
+    };
+
+    // A declared length is checked without touching the body
+    if let Some(len) = res.body().size_hint().exact() {
+        if len <= limit.max_bytes {
+            return Ok(res);
+        }
+        return match limit.action {
+            OversizeAction::Abort => {
+                OVERSIZED.with_label_values(&["aborted"]).inc();
+                Err(too_large())
+            }
+            OversizeAction::Truncate => {
+                OVERSIZED.with_label_values(&["truncated"]).inc();
+                let (mut parts, body) = res.into_parts();
+                parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(limit.max_bytes));
+                parts.headers.insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+                let body = Truncated { inner: body, remaining: limit.max_bytes }.boxed();
+                Ok(ProxyResponse::from_parts(parts, body))
+            }
+        };
+    }
+
+    // Otherwise buffer up to the limit to find out
+    let (mut parts, mut body) = res.into_parts();
+    let mut buffered = Vec::new();
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|source| ProxyError::UpstreamProtocol {
+            backend: context.backend.id,
+            addr: context.backend.addr,
+            source,
+        })?;
+        let data = match frame.into_data() {
+            Ok(data) => data,
+            Err(frame) => {
+                trailers = frame.into_trailers().ok();
+                continue;
+            }
+        };
+        if (buffered.len() + data.len()) as u64 <= limit.max_bytes {
+            buffered.extend_from_slice(&data);
+            continue;
+        }
+
+        if limit.action == OversizeAction::Abort {
+            OVERSIZED.with_label_values(&["aborted"]).inc();
+            return Err(too_large());
+        }
+        OVERSIZED.with_label_values(&["truncated"]).inc();
+        let room = limit.max_bytes as usize - buffered.len();
+        buffered.extend_from_slice(&data[..room]);
+        parts.headers.insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+        break;
+    }
+    if trailers.is_none() {
+        // HTTP/1.1 only carries trailers on a chunked body
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(buffered.len()));
+    }
+    Ok(ProxyResponse::from_parts(parts, buffered_body(Bytes::from(buffered), trailers)))
+}
+
+/// Forwards only the first `remaining` bytes of a body.
+struct Truncated {
+    inner: ProxyBody,
+    remaining: u64,
+}
+
+impl Body for Truncated {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        if this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                Ok(mut data) => {
+                    if data.len() as u64 > this.remaining {
+                        data.truncate(this.remaining as usize);
+                    }
+                    this.remaining -= data.len() as u64;
+                    Poll::Ready(Some(Ok(Frame::data(data))))
+                }
+                // Trailers of a truncated body are meaningless
+                Err(_) => Poll::Ready(None),
+            },
+            other => other,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0 || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining.min(self.inner.size_hint().upper().unwrap_or(u64::MAX)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{full_body, Chunks};
+    use hyper::Response;
+    use vortex_core::domain::backend::{Backend, BackendId};
+
+    fn streamed(chunks: &[&'static [u8]]) -> ProxyResponse {
+        Response::new(Chunks(chunks.iter().copied().collect()).boxed())
+    }
+
+    fn fixed(body: &'static [u8]) -> ProxyResponse {
+        Response::new(full_body(Bytes::from_static(body)))
+    }
+
+    #[tokio::test]
+    async fn test_limits_apply_to_declared_and_streamed_lengths() {
+        let ctx = RouteContext {
+            route: "/".into(),
+            backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap())),
+            labels: Arc::default(),
+        };
+        let abort = ResponseLimit { max_bytes: 8, action: OversizeAction::Abort };
+        let truncate = ResponseLimit { max_bytes: 8, action: OversizeAction::Truncate };
+
+        assert!(enforce(fixed(b"12345678"), &ctx, abort).await.is_ok());
+        assert_eq!(enforce(fixed(b"0123456789"), &ctx, abort).await.unwrap_err().kind(), "response_too_large");
+
+        let res = enforce(fixed(b"0123456789"), &ctx, truncate).await.unwrap();
+        assert_eq!(res.headers()[TRUNCATED_HEADER], "true");
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "01234567");
+
+        let res = enforce(streamed(&[b"0123", b"45"]), &ctx, abort).await.unwrap();
+        assert_eq!(res.headers()[CONTENT_LENGTH], "6");
+        assert!(enforce(streamed(&[b"0123", b"4567", b"89"]), &ctx, abort).await.is_err());
+
+        let res = enforce(streamed(&[b"0123", b"4567", b"89"]), &ctx, truncate).await.unwrap();
+        assert_eq!(res.headers()[TRUNCATED_HEADER], "true");
+        assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "01234567");
+    }
+
+    #[tokio::test]
+    async fn test_buffered_responses_keep_their_trailers() {
+        let ctx = RouteContext {
+            route: "/".into(),
+            backend: Arc::new(Backend::new(BackendId(1), "127.0.0.1:9".parse().unwrap())),
+            labels: Arc::default(),
+        };
+        let mut trailers = hyper::HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let res = streamed(&[b"0123", b"45"]).map(|body| body.with_trailers(std::future::ready(Some(Ok(trailers)))).boxed());
+
+        let res = enforce(res, &ctx, ResponseLimit { max_bytes: 8, action: OversizeAction::Abort }).await.unwrap();
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        let body = res.into_body().collect().await.unwrap();
+        assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+        assert_eq!(body.to_bytes(), "012345");
+    }
+
+    #[test]
+    fn test_listener_limit_sits_between_route_and_default_limits() {
+        let limit = |max_bytes| ResponseLimit { max_bytes, action: OversizeAction::Abort };
+        let limits = ResponseLimits { default: Some(limit(100)), routes: [("/export".to_string(), limit(1000))].into() };
+        assert_eq!(limits.for_route("/", None), Some(limit(100)));
+        assert_eq!(limits.for_route("/", Some(limit(10))), Some(limit(10)));
+        assert_eq!(limits.for_route("/export", Some(limit(10))), Some(limit(1000)));
+    }
+}
@@ -0,0 +1,416 @@
+//! Retries that never double-submit a request.
+//!
+//! Failures are split by whether the request could have reached the backend:
+//!
+//! * **Not sent** (connect refused or timed out): always safe to retry, on the
+//!   next best backend.
+//! * **Ambiguous** (the exchange broke after the request may have been
+//!   processed): only retried for idempotent methods, or when the client sent
+//!   an `Idempotency-Key`. Keyed requests are retried on the *same* backend,
+//!   which has seen the key and can deduplicate, rather than on one that has not.
+//!
+//! Recently seen keys are remembered with the backend they were sent to, so a
+//! client resending the same key is also routed back to that backend.
+
+use http_body_util::BodyExt;
+use hyper::body::Body;
+use hyper::header::HeaderName;
+use hyper::{Method, Request};
+use prometheus::IntCounterVec;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service, ServiceExt};
+use vortex_core::domain::backend::{BackendId, SharedBackend};
+use vortex_core::domain::routing::SharedRoutingTable;
+
+use crate::error::ProxyError;
+use crate::force_backend::ForcedBackend;
+use crate::pipeline::{buffered_body, route_pool, select_backend, ProxyFuture, ProxyRequest, ProxyResponse, RouteContext};
+
+/// The default header carrying a client's idempotency key.
+pub const DEFAULT_IDEMPOTENCY_HEADER: &str = "idempotency-key";
+
+/// Keys remembered before expired ones are pruned.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Retries attempted, labeled by why the previous attempt failed.
+static RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_retries_total",
+        "Upstream attempts retried, by failure class",
+        &["failure"]
+    )
+    .expect("metric registers once")
+});
+
+/// When and how failed upstream attempts are retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Extra attempts after the first one
+    pub max_retries: u32,
+    /// Header whose presence makes a non-idempotent request safe to retry
+    pub idempotency_header: HeaderName,
+    /// How long a key stays pinned to the backend it was sent to
+    pub key_ttl: Duration,
+    /// Larger (or streamed) bodies are not buffered, so their requests are never retried
+    pub max_body_bytes: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            idempotency_header: HeaderName::from_static(DEFAULT_IDEMPOTENCY_HEADER),
+            key_ttl: Duration::from_secs(300),
+            max_body_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Whether a failed attempt may have been processed by the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    NotSent,
+    Ambiguous,
+}
+
+fn classify(err: &ProxyError) -> Option<Failure> {
+    match err {
+        ProxyError::UpstreamConnect { .. } => Some(Failure::NotSent),
+        ProxyError::UpstreamTimeout { phase: "connect", .. } => Some(Failure::NotSent),
+        ProxyError::ConnectPaced { .. } => Some(Failure::NotSent),
+        ProxyError::UpstreamProtocol { .. } => Some(Failure::Ambiguous),
+        _ => None,
+    }
+}
+
+/// RFC 9110 idempotent methods.
+pub(crate) fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Recently seen idempotency keys and the backend each was sent to.
+#[derive(Debug, Default)]
+struct IdempotencyKeys {
+    seen: Mutex<HashMap<String, (BackendId, Instant)>>,
+}
+
+impl IdempotencyKeys {
+    fn backend_for(&self, key: &str, ttl: Duration, now: Instant) -> Option<BackendId> {
+        let seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.get(key)
+            .filter(|(_, at)| now.duration_since(*at) < ttl)
+            .map(|(backend, _)| *backend)
+    }
+
+    fn record(&self, key: &str, backend: BackendId, ttl: Duration, now: Instant) {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.len() >= MAX_TRACKED_KEYS {
+            seen.retain(|_, (_, at)| now.duration_since(*at) < ttl);
+        }
+        seen.insert(key.to_string(), (backend, now));
+    }
+}
+
+/// Retries failed upstream attempts per `RetryPolicy`.
+#[derive(Debug, Clone)]
+pub struct RetryLayer {
+    policy: Arc<RetryPolicy>,
+    routing_table: SharedRoutingTable,
+    keys: Arc<IdempotencyKeys>,
+}
+
+impl RetryLayer {
+    /// Create a retry layer re-selecting backends from `routing_table`.
+    pub fn new(policy: RetryPolicy, routing_table: SharedRoutingTable) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            routing_table,
+            keys: Arc::default(),
+        }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = RetryService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RetryService {
+            inner,
+            policy: self.policy.clone(),
+            routing_table: self.routing_table.clone(),
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+/// Service produced by `RetryLayer`.
+#[derive(Debug, Clone)]
+pub struct RetryService<S> {
+    inner: S,
+    policy: Arc<RetryPolicy>,
+    routing_table: SharedRoutingTable,
+    keys: Arc<IdempotencyKeys>,
+}
+
+impl<S> Service<ProxyRequest> for RetryService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ProxyRequest) -> Self::Future {
+        let replayable = req
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|len| len <= self.policy.max_body_bytes);
+        let routed = req.extensions().get::<RouteContext>().is_some();
+        // A request pinned by the debug override must fail on its own backend
+        let forced = req.extensions().get::<ForcedBackend>().is_some();
+        if self.policy.max_retries == 0 || !replayable || !routed || forced {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let routing_table = self.routing_table.clone();
+        let keys = self.keys.clone();
+        Box::pin(retry_request(req, inner, policy, routing_table, keys))
+    }
+}
+
+async fn retry_request<S>(
+    req: ProxyRequest,
+    inner: S,
+    policy: Arc<RetryPolicy>,
+    routing_table: SharedRoutingTable,
+    keys: Arc<IdempotencyKeys>,
+) -> Result<ProxyResponse, ProxyError>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone,
+{
+    let (parts, body) = req.into_parts();
+    let mut context = parts.extensions.get::<RouteContext>().cloned().expect("checked by the caller");
+    let body = body.collect().await.map_err(|e| ProxyError::InvalidRequest {
+        route: context.route.clone(),
+        reason: format!("failed to read request body: {}", e),
+    })?;
+    let trailers = body.trailers().cloned();
+    let body = body.to_bytes();
+
+    let key = parts
+        .headers
+        .get(&policy.idempotency_header)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let ambiguous_retry_safe = is_idempotent(&parts.method) || key.is_some();
+
+    // A resent key goes back to the backend that may already have processed it
+    if let Some(pinned) = key.as_deref().and_then(|k| keys.backend_for(k, policy.key_ttl, Instant::now())) {
+        if let Some(backend) = healthy_backend(route_pool(&routing_table, &parts.extensions), pinned) {
+            context.backend = backend;
+        }
+    }
+
+    let mut tried = Vec::new();
+    let mut attempt = 0;
+    loop {
+        if let Some(key) = &key {
+            keys.record(key, context.backend.id, policy.key_ttl, Instant::now());
+        }
+        tried.push(context.backend.id);
+
+        let mut req = Request::from_parts(clone_parts(&parts), buffered_body(body.clone(), trailers.clone()));
+        req.extensions_mut().insert(context.clone());
+        let err = match inner.clone().oneshot(req).await {
+            Ok(res) => return Ok(res),
+            Err(err) => err,
+        };
+
+        let next = match classify(&err) {
+            _ if attempt >= policy.max_retries => None,
+            Some(Failure::NotSent) => select_backend(&routing_table, &parts.extensions, &tried),
+            Some(Failure::Ambiguous) if key.is_some() => Some(context.backend.clone()),
+            Some(Failure::Ambiguous) if ambiguous_retry_safe => select_backend(&routing_table, &parts.extensions, &tried)
+                .or_else(|| Some(context.backend.clone())),
+            _ => None,
+        };
+        let Some(backend) = next else {
+            return Err(err);
+        };
+
+        let failure = match classify(&err) {
+            Some(Failure::NotSent) => "not_sent",
+            _ => "ambiguous",
+        };
+        RETRIES.with_label_values(&[failure]).inc();
+        tracing::info!(route = %context.route, backend = backend.id.0, error = %err, "retrying request");
+        context.backend = backend;
+        attempt += 1;
+    }
+}
+
+/// Finds a healthy backend by id, including one that is draining after removal.
+fn healthy_backend(routing_table: &SharedRoutingTable, id: BackendId) -> Option<SharedBackend> {
+    routing_table.backend(id).filter(|b| b.is_healthy())
+}
+
+pub(crate) fn clone_parts(parts: &hyper::http::request::Parts) -> hyper::http::request::Parts {
+    let (mut cloned, ()) = Request::new(()).into_parts();
+    cloned.method = parts.method.clone();
+    cloned.uri = parts.uri.clone();
+    cloned.version = parts.version;
+    cloned.headers = parts.headers.clone();
+    cloned.extensions = parts.extensions.clone();
+    cloned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::full_body;
+    use hyper::body::Bytes;
+    use vortex_core::domain::backend::Backend;
+    use vortex_core::domain::routing::RoutingTable;
+
+    /// Fails every attempt with `err` and records which backend each went to.
+    #[derive(Clone)]
+    struct Failing {
+        err: fn(BackendId) -> ProxyError,
+        attempts: Arc<Mutex<Vec<BackendId>>>,
+    }
+
+    impl Service<ProxyRequest> for Failing {
+        type Response = ProxyResponse;
+        type Error = ProxyError;
+        type Future = std::future::Ready<Result<ProxyResponse, ProxyError>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: ProxyRequest) -> Self::Future {
+            let backend = req.extensions().get::<RouteContext>().unwrap().backend.id;
+            self.attempts.lock().unwrap().push(backend);
+            std::future::ready(Err((self.err)(backend)))
+        }
+    }
+
+    fn refused(backend: BackendId) -> ProxyError {
+        ProxyError::UpstreamConnect {
+            backend,
+            addr: "127.0.0.1:9".parse().unwrap(),
+            source: std::io::Error::from(std::io::ErrorKind::ConnectionRefused),
+        }
+    }
+
+    fn reset(backend: BackendId) -> ProxyError {
+        ProxyError::UpstreamTimeout { backend, addr: "127.0.0.1:9".parse().unwrap(), phase: "response" }
+    }
+
+    async fn run(method: Method, key: Option<&str>, err: fn(BackendId) -> ProxyError) -> Vec<BackendId> {
+        let backends: Vec<_> = (1..=3)
+            .map(|i| Arc::new(Backend::new(BackendId(i), format!("127.0.0.1:{}", 9000 + i).parse().unwrap())))
+            .collect();
+        let routing_table = Arc::new(RoutingTable::new(backends.clone()));
+        let failing = Failing { err, attempts: Arc::default() };
+        let service = RetryLayer::new(RetryPolicy::default(), routing_table).layer(failing.clone());
+
+        let mut builder = Request::builder().method(method).uri("/orders");
+        if let Some(key) = key {
+            builder = builder.header(DEFAULT_IDEMPOTENCY_HEADER, key);
+        }
+        let mut req = builder.body(full_body(Bytes::from_static(b"{}"))).unwrap();
+        req.extensions_mut().insert(RouteContext {
+            route: "/orders".into(),
+            backend: backends[0].clone(),
+            labels: Arc::default(),
+        });
+
+        service.oneshot(req).await.unwrap_err();
+        let attempts = failing.attempts.lock().unwrap();
+        attempts.clone()
+    }
+
+    #[tokio::test]
+    async fn test_unsent_requests_retry_on_other_backends() {
+        let attempts = run(Method::POST, None, refused).await;
+        assert_eq!(attempts, [BackendId(1), BackendId(2), BackendId(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_post_without_key_is_not_retried() {
+        let attempts = run(Method::POST, None, |b| ProxyError::UpstreamProtocol {
+            backend: b,
+            addr: "127.0.0.1:9".parse().unwrap(),
+            source: hyper_error(),
+        })
+        .await;
+        assert_eq!(attempts, [BackendId(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_keyed_post_retries_on_the_same_backend() {
+        let attempts = run(Method::POST, Some("order-42"), |b| ProxyError::UpstreamProtocol {
+            backend: b,
+            addr: "127.0.0.1:9".parse().unwrap(),
+            source: hyper_error(),
+        })
+        .await;
+        assert_eq!(attempts, [BackendId(1), BackendId(1), BackendId(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_non_retryable_errors_fail_fast() {
+        let attempts = run(Method::GET, None, reset).await;
+        assert_eq!(attempts, [BackendId(1)]);
+    }
+
+    #[test]
+    fn test_keys_expire() {
+        let keys = IdempotencyKeys::default();
+        let now = Instant::now();
+        keys.record("k", BackendId(2), Duration::from_secs(10), now);
+
+        assert_eq!(keys.backend_for("k", Duration::from_secs(10), now), Some(BackendId(2)));
+        assert_eq!(keys.backend_for("k", Duration::from_secs(10), now + Duration::from_secs(11)), None);
+    }
+
+    /// A real `hyper::Error`, produced by parsing garbage as an HTTP response.
+    fn hyper_error() -> hyper::Error {
+        use hyper_util::rt::TokioIo;
+
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        std::thread::spawn(move || {
+            rt.block_on(async {
+                let (client, mut server) = tokio::io::duplex(64);
+                let (mut sender, conn) = hyper::client::conn::http1::handshake::<_, http_body_util::Empty<Bytes>>(
+                    TokioIo::new(client),
+                )
+                .await
+                .unwrap();
+                tokio::spawn(conn);
+                tokio::spawn(async move {
+                    use tokio::io::AsyncWriteExt;
+                    let _ = server.write_all(b"garbage\r\n\r\n").await;
+                });
+                sender.send_request(Request::new(http_body_util::Empty::new())).await.unwrap_err()
+            })
+        })
+        .join()
+        .unwrap()
+    }
+}
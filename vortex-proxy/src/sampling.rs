@@ -0,0 +1,274 @@
+//! Head-based trace sampling policy.
+//!
+//! Each request gets a `TraceSampling` decision when it enters the pipeline.
+//! A sampled W3C `traceparent` from the client or the debug header forces
+//! sampling; otherwise the ratio of the longest matching route prefix (or the
+//! default ratio) applies. Requests that were not head-sampled can still be
+//! kept when they end in a server error, so failures are never invisible.
+//! The decision is stored in the request and response extensions and, when
+//! traces are exported, on the request's `RequestTrace`.
+
+use hyper::header::HeaderName;
+use hyper::HeaderMap;
+use prometheus::IntCounterVec;
+use rand::Rng;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::otel::RequestTrace;
+use crate::pipeline::{ProxyFuture, ProxyRequest, ProxyResponse};
+
+/// The header that forces sampling when present, unless configured otherwise.
+pub const DEFAULT_DEBUG_HEADER: &str = "x-vortex-trace";
+
+/// Sampling decisions made, labeled by `SampleReason`.
+static SAMPLING_DECISIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_trace_sampling_decisions_total",
+        "Trace sampling decisions, by reason",
+        &["reason"]
+    )
+    .expect("metric registers once")
+});
+
+/// Why a request was (or was not) sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleReason {
+    /// The client's `traceparent` was already sampled
+    Parent,
+    /// The debug header was present
+    DebugHeader,
+    /// Chosen by the route's sampling ratio
+    Ratio,
+    /// Not head-sampled, but kept because the request failed
+    Error,
+    /// Dropped by the route's sampling ratio
+    NotSampled,
+}
+
+impl SampleReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            SampleReason::Parent => "parent",
+            SampleReason::DebugHeader => "debug_header",
+            SampleReason::Ratio => "ratio",
+            SampleReason::Error => "error",
+            SampleReason::NotSampled => "not_sampled",
+        }
+    }
+}
+
+/// A per-request sampling decision, stored in the request extensions and
+/// copied (possibly upgraded to `Error`) into the response extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceSampling {
+    /// Whether the request's trace is recorded
+    pub sampled: bool,
+    /// What decided it
+    pub reason: SampleReason,
+}
+
+/// Sampling ratio for requests under a path prefix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteSampling {
+    /// The route path prefix this ratio applies to (e.g. `/api/`)
+    pub route_prefix: String,
+    /// Fraction (0.0-1.0) of matching requests to sample
+    pub ratio: f64,
+}
+
+/// Configures which requests are traced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplingPolicy {
+    /// Fraction (0.0-1.0) of requests sampled when no route ratio matches
+    pub default_ratio: f64,
+    /// Per-route overrides; the longest matching prefix wins
+    pub routes: Vec<RouteSampling>,
+    /// Keep traces of requests that end in a 5xx even if not head-sampled
+    pub always_sample_errors: bool,
+    /// Sample whenever this request header is present
+    pub debug_header: Option<HeaderName>,
+    /// Follow the sampled flag of an incoming `traceparent`
+    pub respect_parent: bool,
+}
+
+impl Default for SamplingPolicy {
+    fn default() -> Self {
+        Self {
+            default_ratio: 0.01,
+            routes: Vec::new(),
+            always_sample_errors: true,
+            debug_header: Some(HeaderName::from_static(DEFAULT_DEBUG_HEADER)),
+            respect_parent: true,
+        }
+    }
+}
+
+impl SamplingPolicy {
+    /// Makes the head-based decision for a request.
+    pub fn decide(&self, path: &str, headers: &HeaderMap) -> TraceSampling {
+        if self.respect_parent && parent_sampled(headers) {
+            return TraceSampling { sampled: true, reason: SampleReason::Parent };
+        }
+        if self.debug_header.as_ref().is_some_and(|h| headers.contains_key(h)) {
+            return TraceSampling { sampled: true, reason: SampleReason::DebugHeader };
+        }
+
+        let ratio = self
+            .routes
+            .iter()
+            .filter(|r| path.starts_with(&r.route_prefix))
+            .max_by_key(|r| r.route_prefix.len())
+            .map_or(self.default_ratio, |r| r.ratio);
+        if ratio > 0.0 && rand::thread_rng().gen_bool(ratio.min(1.0)) {
+            TraceSampling { sampled: true, reason: SampleReason::Ratio }
+        } else {
+            TraceSampling { sampled: false, reason: SampleReason::NotSampled }
+        }
+    }
+
+    /// Revisits a head decision once the request has finished.
+    pub fn finish(&self, head: TraceSampling, server_error: bool) -> TraceSampling {
+        if !head.sampled && server_error && self.always_sample_errors {
+            TraceSampling { sampled: true, reason: SampleReason::Error }
+        } else {
+            head
+        }
+    }
+}
+
+/// Whether a W3C `traceparent` header carries the sampled flag.
+fn parent_sampled(headers: &HeaderMap) -> bool {
+    headers
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split('-').nth(3))
+        .and_then(|flags| u8::from_str_radix(flags, 16).ok())
+        .is_some_and(|flags| flags & 0x01 == 0x01)
+}
+
+/// Attaches a `TraceSampling` decision to every request and response.
+#[derive(Debug, Clone)]
+pub struct SamplingLayer {
+    policy: Arc<SamplingPolicy>,
+}
+
+impl SamplingLayer {
+    /// Create a layer applying `policy`.
+    pub fn new(policy: SamplingPolicy) -> Self {
+        Self { policy: Arc::new(policy) }
+    }
+}
+
+impl<S> Layer<S> for SamplingLayer {
+    type Service = SamplingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SamplingService {
+            inner,
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+/// Service produced by `SamplingLayer`.
+#[derive(Debug, Clone)]
+pub struct SamplingService<S> {
+    inner: S,
+    policy: Arc<SamplingPolicy>,
+}
+
+impl<S> Service<ProxyRequest> for SamplingService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let head = self.policy.decide(req.uri().path(), req.headers());
+        req.extensions_mut().insert(head);
+        let trace = req.extensions().get::<RequestTrace>().cloned();
+        if let Some(trace) = &trace {
+            trace.set_sampled(head.sampled);
+        }
+        let policy = self.policy.clone();
+        let fut = self.inner.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            let server_error = match &result {
+                Ok(res) => res.status().is_server_error(),
+                Err(err) => err.status_code().is_server_error(),
+            };
+            let decision = policy.finish(head, server_error);
+            SAMPLING_DECISIONS.with_label_values(&[decision.reason.as_str()]).inc();
+            if let Some(trace) = &trace {
+                trace.set_sampled(decision.sampled);
+            }
+
+            result.map(|mut res| {
+                res.extensions_mut().insert(decision);
+                res
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (HeaderName::from_static(k), v.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_route_ratio_longest_prefix_wins() {
+        let policy = SamplingPolicy {
+            default_ratio: 1.0,
+            routes: vec![
+                RouteSampling { route_prefix: "/api/".into(), ratio: 1.0 },
+                RouteSampling { route_prefix: "/api/hot/".into(), ratio: 0.0 },
+            ],
+            ..SamplingPolicy::default()
+        };
+
+        assert!(policy.decide("/api/orders", &HeaderMap::new()).sampled);
+        assert_eq!(policy.decide("/api/hot/feed", &HeaderMap::new()).reason, SampleReason::NotSampled);
+    }
+
+    #[test]
+    fn test_debug_header_and_parent_force_sampling() {
+        let policy = SamplingPolicy { default_ratio: 0.0, ..SamplingPolicy::default() };
+
+        let debug = policy.decide("/", &headers(&[(DEFAULT_DEBUG_HEADER, "1")]));
+        assert_eq!(debug.reason, SampleReason::DebugHeader);
+
+        let parent = headers(&[("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")]);
+        assert_eq!(policy.decide("/", &parent).reason, SampleReason::Parent);
+
+        let unsampled = headers(&[("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00")]);
+        assert!(!policy.decide("/", &unsampled).sampled);
+    }
+
+    #[test]
+    fn test_errors_upgrade_unsampled_requests() {
+        let policy = SamplingPolicy { default_ratio: 0.0, ..SamplingPolicy::default() };
+        let head = policy.decide("/", &HeaderMap::new());
+
+        assert_eq!(policy.finish(head, true).reason, SampleReason::Error);
+        assert!(!policy.finish(head, false).sampled);
+    }
+}
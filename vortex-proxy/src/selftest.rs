@@ -0,0 +1,338 @@
+//! Built-in synthetic traffic generator for end-to-end smoke testing.
+//!
+//! `vortex-proxy selftest` spins up mock backends and an in-process proxy wired
+//! exactly like a real deployment (TLS termination, Peak EWMA routing, the hot
+//! connection pool), then drives a configurable amount of load through it and
+//! reports throughput, latency percentiles, and errors.
+
+use clap::Args;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http1::SendRequest;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use vortex_core::domain::backend::{Backend, BackendId};
+
+use crate::{tls, Vortex};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Options for the `selftest` subcommand.
+#[derive(Debug, Clone, Args)]
+pub struct SelftestArgs {
+    /// Total number of requests to send through the proxy
+    #[arg(long, default_value_t = 1000)]
+    pub requests: u64,
+    /// Number of concurrent client connections
+    #[arg(long, default_value_t = 16)]
+    pub concurrency: usize,
+    /// Number of mock backends to start behind the proxy
+    #[arg(long, default_value_t = 2)]
+    pub backends: u32,
+    /// TLS certificate for the proxy listener (falls back to plaintext if unreadable)
+    #[arg(long, default_value = "certs/cert.pem")]
+    pub cert: PathBuf,
+    /// TLS private key for the proxy listener
+    #[arg(long, default_value = "certs/key.pem")]
+    pub key: PathBuf,
+}
+
+/// Aggregated results of a selftest run.
+#[derive(Debug)]
+pub struct SelftestReport {
+    /// Requests that completed with a 2xx status
+    pub succeeded: u64,
+    /// Requests that failed or returned a non-2xx status
+    pub failed: u64,
+    /// Wall-clock duration of the load phase
+    pub elapsed: Duration,
+    /// Latencies of successful requests, sorted ascending
+    pub latencies: Vec<Duration>,
+}
+
+impl SelftestReport {
+    /// Successful requests per second.
+    pub fn throughput(&self) -> f64 {
+        self.succeeded as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The latency at the given percentile (0-100) of successful requests.
+    pub fn percentile(&self, pct: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = ((pct / 100.0) * (self.latencies.len() - 1) as f64).round() as usize;
+        self.latencies[rank.min(self.latencies.len() - 1)]
+    }
+}
+
+/// Runs the selftest and prints a report. Fails if any request failed.
+pub async fn run(args: SelftestArgs) -> Result<(), BoxError> {
+    let mut backends = Vec::new();
+    for id in 0..args.backends.max(1) {
+        let addr = spawn_mock_backend().await?;
+        println!("[SELFTEST] Mock backend {} listening on {}", id + 1, addr);
+        backends.push(Arc::new(Backend::new(BackendId(id + 1), addr)));
+    }
+
+    let tls_acceptor = match tls::load_tls_config(&args.cert, &args.key) {
+        Ok(config) => Some(TlsAcceptor::from(config)),
+        Err(e) => {
+            println!("[SELFTEST] TLS disabled ({}), testing the plaintext path", e);
+            None
+        }
+    };
+    let tls_enabled = tls_acceptor.is_some();
+
+    let loopback = SocketAddr::from(([127, 0, 0, 1], 0));
+    let builder = Vortex::builder().backends(backends);
+    let builder = match tls_acceptor {
+        Some(acceptor) => builder.tls_listener(loopback, acceptor),
+        None => builder.listener(loopback),
+    };
+    let handle = builder.start().await?;
+    let proxy_addr = handle.local_addrs()[0];
+
+    println!(
+        "[SELFTEST] Driving {} requests over {} connections through {} ({})",
+        args.requests,
+        args.concurrency,
+        proxy_addr,
+        if tls_enabled { "TLS" } else { "plaintext" }
+    );
+
+    let connector = tls_enabled.then(insecure_connector);
+    let report = drive_load(proxy_addr, connector, args.requests, args.concurrency.max(1)).await;
+    handle.shutdown();
+
+    println!("[SELFTEST] Succeeded:  {}", report.succeeded);
+    println!("[SELFTEST] Failed:     {}", report.failed);
+    println!("[SELFTEST] Elapsed:    {:.2?}", report.elapsed);
+    println!("[SELFTEST] Throughput: {:.1} req/s", report.throughput());
+    println!(
+        "[SELFTEST] Latency:    p50={:.2?} p90={:.2?} p99={:.2?} max={:.2?}",
+        report.percentile(50.0),
+        report.percentile(90.0),
+        report.percentile(99.0),
+        report.percentile(100.0)
+    );
+
+    if report.failed > 0 {
+        return Err(format!("selftest failed: {} of {} requests failed", report.failed, args.requests).into());
+    }
+    Ok(())
+}
+
+/// Starts a minimal HTTP/1.1 backend that answers every request with `200 ok`.
+pub async fn spawn_mock_backend() -> Result<SocketAddr, BoxError> {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = service_fn(|_req: Request<Incoming>| async {
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"ok\n"))))
+                });
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn drive_load(
+    target: SocketAddr,
+    connector: Option<TlsConnector>,
+    requests: u64,
+    concurrency: usize,
+) -> SelftestReport {
+    let remaining = Arc::new(AtomicU64::new(requests));
+    let failed = Arc::new(AtomicU64::new(0));
+    let started = Instant::now();
+
+    let mut workers = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        let remaining = remaining.clone();
+        let failed = failed.clone();
+        let connector = connector.clone();
+
+        workers.push(tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            let mut sender: Option<SendRequest<Empty<Bytes>>> = None;
+
+            while remaining
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                let begin = Instant::now();
+                match send_one(target, connector.as_ref(), &mut sender).await {
+                    Ok(true) => latencies.push(begin.elapsed()),
+                    Ok(false) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(_) => {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        sender = None;
+                    }
+                }
+            }
+            latencies
+        }));
+    }
+
+    let mut latencies = Vec::new();
+    for worker in workers {
+        if let Ok(mut l) = worker.await {
+            latencies.append(&mut l);
+        }
+    }
+    latencies.sort_unstable();
+
+    SelftestReport {
+        succeeded: latencies.len() as u64,
+        failed: failed.load(Ordering::Relaxed),
+        elapsed: started.elapsed(),
+        latencies,
+    }
+}
+
+/// Sends a single request, (re)connecting if needed. Returns whether it got a 2xx.
+async fn send_one(
+    target: SocketAddr,
+    connector: Option<&TlsConnector>,
+    sender: &mut Option<SendRequest<Empty<Bytes>>>,
+) -> Result<bool, BoxError> {
+    if sender.as_ref().is_none_or(|s| s.is_closed()) {
+        *sender = Some(connect(target, connector).await?);
+    }
+    let s = sender.as_mut().expect("sender was just established");
+    s.ready().await?;
+
+    let req = Request::builder()
+        .uri("/selftest")
+        .header(hyper::header::HOST, target.to_string())
+        .body(Empty::<Bytes>::new())?;
+    let res = s.send_request(req).await?;
+    let ok = res.status().is_success();
+    res.into_body().collect().await?;
+    Ok(ok)
+}
+
+async fn connect(target: SocketAddr, connector: Option<&TlsConnector>) -> Result<SendRequest<Empty<Bytes>>, BoxError> {
+    let stream = TcpStream::connect(target).await?;
+
+    match connector {
+        Some(connector) => {
+            let server_name = ServerName::IpAddress(target.ip().into());
+            let tls_stream = connector.connect(server_name, stream).await?;
+            let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tls_stream)).await?;
+            tokio::spawn(conn);
+            Ok(sender)
+        }
+        None => {
+            let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+            tokio::spawn(conn);
+            Ok(sender)
+        }
+    }
+}
+
+/// A TLS connector that accepts any server certificate.
+///
+/// The selftest talks to its own in-process listener, typically using a
+/// self-signed development certificate, so chain validation is pointless here.
+fn insecure_connector() -> TlsConnector {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions are supported")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    TlsConnector::from(Arc::new(config))
+}
+
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_selftest_plaintext_round_trip() {
+        let args = SelftestArgs {
+            requests: 50,
+            concurrency: 4,
+            backends: 2,
+            cert: PathBuf::from("does/not/exist.pem"),
+            key: PathBuf::from("does/not/exist.pem"),
+        };
+        run(args).await.expect("selftest should pass against mock backends");
+    }
+
+    #[test]
+    fn test_report_percentiles() {
+        let report = SelftestReport {
+            succeeded: 4,
+            failed: 0,
+            elapsed: Duration::from_secs(1),
+            latencies: (1..=4).map(Duration::from_millis).collect(),
+        };
+        assert_eq!(report.percentile(0.0), Duration::from_millis(1));
+        assert_eq!(report.percentile(100.0), Duration::from_millis(4));
+        assert_eq!(report.throughput(), 4.0);
+    }
+}
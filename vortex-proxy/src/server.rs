@@ -1,202 +1,495 @@
 //! Server module for handling incoming connections and HTTP parsing.
+//!
+//! Plaintext listeners can optionally sniff the first bytes of each connection
+//! so one port serves HTTP/1, HTTP/2 prior-knowledge (h2c), and raw TCP
+//! clients side by side, e.g. while migrating a service between protocols.
 
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use hyper::{Request, Response};
-use hyper::body::Incoming;
-use hyper_util::rt::TokioIo;
+use hyper::server::conn::{http1, http2};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::service::TowerToHyperService;
+use ipnet::IpNet;
 use tokio_rustls::TlsAcceptor;
-use std::net::SocketAddr;
+use tracing::Instrument;
+use vortex_filters::chain::FilterChain;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use vortex_core::domain::routing::SharedRoutingTable;
-use crate::connection_pool::pool::ConnectionPool;
-use vortex_core::load_balancer::selector::select_best_backend;
-use vortex_filters::wasm_engine::WasmEngine;
-use std::sync::Arc;
-use std::time::Instant;
+use vortex_core::load_balancer::hash::HashKey;
+use vortex_core::load_balancer::selector::select_best_backend_where;
+use crate::access_log::AccessLogFormat;
+use crate::dns;
+use crate::error::ProxyError;
+use crate::fd_limit::{self, FdMonitor};
+use crate::header_limits::HeaderLimits;
+use crate::metrics;
+use crate::otel::{ConnectionSpans, Tracer};
+use crate::pipeline::{HyperAdapter, ProxyService, RequestHash};
+use crate::response_limit::ResponseLimit;
+use crate::socket::{self, SocketOptions};
+use crate::tls_failures;
+use crate::token_bucket::TokenBuckets;
+
+/// The client connection preface of HTTP/2 with prior knowledge (RFC 9113 §3.4).
+const H2C_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How long to wait for a client's first bytes before treating it as raw TCP
+/// (server-speaks-first protocols never send any).
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to wait for the TCP connection to a tunnelled backend.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to pause accepting after running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a refused client has to finish sending its request.
+const REFUSAL_LINGER: Duration = Duration::from_secs(1);
+
+/// Written to plaintext clients turned away because descriptors are running out.
+const OVERLOADED_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// Completes when an offloaded tunnel has closed.
+pub type OffloadedTunnel = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+/// An in-kernel fast path for raw TCP tunnels, such as an eBPF sockmap
+/// program redirecting bytes between the two sockets (experimental).
+///
+/// Once the backend is chosen there is nothing left for userland to decide,
+/// so an offload may take over the byte shuffling entirely. On Linux, the
+/// `ebpf` feature provides one in `sockmap::SockmapOffload`.
+pub trait TunnelOffload: Send + Sync {
+    /// Take over forwarding between `client` and `upstream`, or hand both
+    /// streams back to fall back to copying in userland (e.g. when the
+    /// program failed to load or the sockmap is full).
+    fn offload(&self, client: TcpStream, upstream: TcpStream) -> Result<OffloadedTunnel, (TcpStream, TcpStream)>;
+}
+
+/// Which protocols a plaintext listener detects besides HTTP/1.
+///
+/// With the default (nothing enabled) connections are served as HTTP/1
+/// without peeking.
+#[derive(Clone, Default)]
+pub struct ProtocolSniffing {
+    /// Serve HTTP/2 prior-knowledge (h2c) clients
+    pub h2c: bool,
+    /// Tunnel connections that are not HTTP to a backend from this table
+    pub tcp_fallback: Option<SharedRoutingTable>,
+    /// Forward tunnelled bytes through this fast path when it accepts them
+    pub tcp_offload: Option<Arc<dyn TunnelOffload>>,
+}
+
+impl ProtocolSniffing {
+    fn enabled(&self) -> bool {
+        self.h2c || self.tcp_fallback.is_some()
+    }
+}
+
+/// Who may connect to a listener.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerAccess {
+    /// Source networks allowed to connect; empty allows everyone
+    pub allowed_sources: Vec<IpNet>,
+    /// Runtime switch: while it reads `false`, new connections are refused
+    pub enabled: Option<Arc<AtomicBool>>,
+    /// Descriptor usage; while it is past the high-water mark, new
+    /// connections are refused with a 503
+    pub fd_monitor: Option<Arc<FdMonitor>>,
+    /// New connections allowed per source IP; connections past it are
+    /// closed before any TLS handshake work is done
+    pub connection_rate: Option<Arc<TokenBuckets<IpAddr>>>,
+}
+
+impl ListenerAccess {
+    /// Why a connection from `peer` must be refused, if it must.
+    fn refusal(&self, peer: SocketAddr) -> Option<&'static str> {
+        if self.enabled.as_ref().is_some_and(|enabled| !enabled.load(Ordering::Relaxed)) {
+            return Some("disabled");
+        }
+        let allowed = self.allowed_sources.is_empty()
+            || self.allowed_sources.iter().any(|net| net.contains(&peer.ip()));
+        if !allowed {
+            return Some("source");
+        }
+        let limited = self.connection_rate.as_ref().is_some_and(|rate| rate.try_take(peer.ip(), Instant::now()).is_err());
+        limited.then_some("rate")
+    }
+}
+
+/// Request handling that differs by listener, e.g. an internal listener
+/// that skips the authentication and WAF filters external traffic runs.
+///
+/// Each setting sits between the proxy-wide default and the route's own:
+/// routes with their own filter chain or response limit keep it, and the
+/// rest take the listener's before falling back to the proxy-wide one.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerPolicy {
+    /// Filter chain for routes without their own, instead of the default chain
+    pub filter_chain: Option<FilterChain>,
+    /// How this listener's requests are access logged, if not the proxy-wide format
+    pub access_log_format: Option<Arc<AccessLogFormat>>,
+    /// Response size limit for routes without their own, instead of the default limit
+    pub response_limit: Option<ResponseLimit>,
+}
+
+/// Per-listener connection settings.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerConfig {
+    /// Socket options applied to accepted connections
+    pub socket: SocketOptions,
+    /// Who may connect
+    pub access: ListenerAccess,
+    /// Header limits for requests and their responses, if enforced
+    pub header_limits: Option<HeaderLimits>,
+    /// Exports accept and TLS handshake spans, if tracing is enabled
+    pub tracer: Option<Arc<Tracer>>,
+    /// Filter chain, logging and limit overrides for requests on this listener, if any
+    pub policy: Option<Arc<ListenerPolicy>>,
+}
+
+/// The protocol a plaintext client is speaking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Http1,
+    H2c,
+    Tcp,
+}
+
+/// Classifies a connection from the bytes peeked so far, or `None` if more are needed.
+fn classify_preface(peeked: &[u8]) -> Option<Protocol> {
+    let n = peeked.len().min(H2C_PREFACE.len());
+    if peeked[..n] == H2C_PREFACE[..n] {
+        return (n == H2C_PREFACE.len()).then_some(Protocol::H2c);
+    }
+
+    // An HTTP/1 request line starts with a method token followed by a space
+    match peeked.iter().position(|b| *b == b' ') {
+        Some(0) => Some(Protocol::Tcp),
+        Some(end) if peeked[..end].iter().all(u8::is_ascii_uppercase) => Some(Protocol::Http1),
+        Some(_) => Some(Protocol::Tcp),
+        None if peeked.iter().all(u8::is_ascii_uppercase) && peeked.len() < 16 => None,
+        None => Some(Protocol::Tcp),
+    }
+}
 
-// A generic boxed error type
-type BoxError = Box<dyn std::error::Error + Send + Sync>;
+/// Peeks at the client's first bytes until its protocol is known.
+async fn sniff(stream: &TcpStream) -> Protocol {
+    let mut buf = [0u8; H2C_PREFACE.len()];
+    let peek = async {
+        loop {
+            let n = match stream.peek(&mut buf).await {
+                Ok(0) | Err(_) => return Protocol::Http1,
+                Ok(n) => n,
+            };
+            if let Some(protocol) = classify_preface(&buf[..n]) {
+                return protocol;
+            }
+            // Wait briefly for the rest of the preface to arrive
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    };
+    tokio::time::timeout(SNIFF_TIMEOUT, peek).await.unwrap_or(Protocol::Tcp)
+}
 
 /// Starts the proxy server on the given address.
 pub async fn start_server(
     addr: SocketAddr,
     tls_acceptor: Option<TlsAcceptor>,
-    routing_table: SharedRoutingTable,
-    connection_pool: ConnectionPool,
-    wasm_engine: Arc<WasmEngine>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    pipeline: ProxyService,
+) -> Result<(), ProxyError> {
     let listener = TcpListener::bind(addr).await?;
-    println!("Listening on {}", addr);
+    tracing::info!(%addr, "listening");
+
+    serve_listener(listener, tls_acceptor, pipeline, ProtocolSniffing::default(), ListenerConfig::default()).await
+}
+
+/// Serves proxied traffic on an already bound listener.
+///
+/// `sniffing` only applies to plaintext listeners.
+pub async fn serve_listener(
+    listener: TcpListener,
+    tls_acceptor: Option<TlsAcceptor>,
+    pipeline: ProxyService,
+    sniffing: ProtocolSniffing,
+    config: ListenerConfig,
+) -> Result<(), ProxyError> {
+    let mut h1 = http1::Builder::new();
+    let mut h2 = http2::Builder::new(TokioExecutor::new());
+    if let Some(limits) = config.header_limits {
+        // Leave hyper's parsing limits just above ours so our check reports
+        // violations; HTTP/2 counts 32 bytes of overhead per header
+        h1.max_headers(limits.max_count + 1);
+        let list_size = limits.max_total_bytes + 32 * (limits.max_count + 1);
+        h2.max_header_list_size(u32::try_from(list_size).unwrap_or(u32::MAX));
+    }
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let routing_table = routing_table.clone();
-        let connection_pool = connection_pool.clone();
-        let wasm_engine = wasm_engine.clone();
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) if fd_limit::is_exhaustion(&e) => {
+                // The connection stays in the backlog; retry once some close
+                tracing::error!(error = %e, "out of file descriptors accepting connections");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if config.access.fd_monitor.as_ref().is_some_and(|monitor| monitor.saturated()) {
+            metrics::CONNECTIONS_REFUSED.with_label_values(&["fd_limit"]).inc();
+            if tls_acceptor.is_none() {
+                tokio::task::spawn(refuse_overloaded(stream));
+            }
+            continue;
+        }
+        if let Some(reason) = config.access.refusal(peer) {
+            metrics::CONNECTIONS_REFUSED.with_label_values(&[reason]).inc();
+            drop(stream);
+            continue;
+        }
+        config.socket.apply_or_log(&stream, &format!("connection from {}", peer));
+        let mut adapter = HyperAdapter::new(pipeline.clone()).with_peer(peer);
+        if let Some(limits) = config.header_limits {
+            adapter = adapter.with_header_limits(limits);
+        }
+        if let Some(policy) = &config.policy {
+            adapter = adapter.with_policy(policy.clone());
+        }
+        if config.socket.transparent {
+            match socket::original_dst(&stream) {
+                Ok(original_dst) => adapter = adapter.with_original_dst(original_dst),
+                Err(e) => tracing::warn!(%peer, error = %e, "failed to read the original destination"),
+            }
+        }
+        let open = ConnectionGauge::open();
 
+        let connection_spans = config
+            .tracer
+            .clone()
+            .map(|tracer| ConnectionSpans::accept(tracer, peer, tls_acceptor.is_some()));
+        if let Some(spans) = &connection_spans {
+            adapter = adapter.with_connection_trace(spans.context());
+        }
+        let service = TowerToHyperService::new(adapter);
+        let span = tracing::info_span!("connection", %peer, tls = tls_acceptor.is_some());
+        let (h1, h2) = (h1.clone(), h2.clone());
         if let Some(acceptor) = &tls_acceptor {
             let acceptor = acceptor.clone();
             tokio::task::spawn(async move {
+                let _open = open;
                 match acceptor.accept(stream).await {
                     Ok(tls_stream) => {
+                        if let Some(mut spans) = connection_spans {
+                            spans.handshake_done(Ok(tls_stream.get_ref().1));
+                            spans.finish();
+                        }
                         let io = TokioIo::new(tls_stream);
-                        let routers_request = routing_table.clone();
-                        let pool_request = connection_pool.clone();
-                        let wasm_request = wasm_engine.clone();
-                        if let Err(err) = http1::Builder::new()
-                            .serve_connection(io, service_fn(move |req| forward_request(req, routers_request.clone(), pool_request.clone(), wasm_request.clone())))
-                            .await
-                        {
-                            eprintln!("Error serving connection: {:?}", err);
+                        if let Err(err) = h1.serve_connection(io, service).await {
+                            tracing::debug!(error = %err, "error serving connection");
+                        }
+                    }
+                    Err(e) => {
+                        let reason = tls_failures::record_handshake_failure(peer, &e);
+                        if let Some(mut spans) = connection_spans {
+                            spans.handshake_done(Err((reason.as_str(), &e)));
+                            spans.finish();
                         }
                     }
-                    Err(e) => eprintln!("TLS Handshake failed: {}", e),
                 }
-            });
+            }
+            .instrument(span));
         } else {
             // Unencrypted fallback
-            let io = TokioIo::new(stream);
-            let routers_request = routing_table.clone();
-            let pool_request = connection_pool.clone();
-            let wasm_request = wasm_engine.clone();
+            if let Some(spans) = connection_spans {
+                spans.finish();
+            }
+            let sniffing = sniffing.clone();
             tokio::task::spawn(async move {
-                if let Err(err) = http1::Builder::new()
-                    .serve_connection(io, service_fn(move |req| forward_request(req, routers_request.clone(), pool_request.clone(), wasm_request.clone())))
-                    .await
-                {
-                    eprintln!("Error serving connection: {:?}", err);
+                let _open = open;
+                let protocol = if sniffing.enabled() { sniff(&stream).await } else { Protocol::Http1 };
+
+                match (protocol, sniffing.tcp_fallback) {
+                    (Protocol::H2c, _) if sniffing.h2c => {
+                        let io = TokioIo::new(stream);
+                        if let Err(err) = h2.serve_connection(io, service).await {
+                            tracing::debug!(error = %err, "error serving h2c connection");
+                        }
+                    }
+                    (Protocol::Tcp, Some(routing_table)) => {
+                        tunnel_tcp(stream, peer, routing_table, sniffing.tcp_offload).await
+                    }
+                    _ => {
+                        let io = TokioIo::new(stream);
+                        if let Err(err) = h1.serve_connection(io, service).await {
+                            tracing::debug!(error = %err, "error serving connection");
+                        }
+                    }
                 }
-            });
+            }
+            .instrument(span));
         }
     }
 }
 
-/// Handles incoming HTTP requests and proxies them to a healthy backend.
-async fn forward_request(
-    mut req: Request<Incoming>,
+/// Splices a non-HTTP client onto the best backend byte for byte.
+async fn tunnel_tcp(
+    client: TcpStream,
+    peer: SocketAddr,
     routing_table: SharedRoutingTable,
-    connection_pool: ConnectionPool,
-    wasm_engine: Arc<WasmEngine>,
-) -> Result<Response<Incoming>, BoxError> {
-    println!("Proxying request: {} {}", req.method(), req.uri());
-
-    // 0. Execute Wasm L7 Filter (e.g., Auth, Rate Limit) natively via Wasmtime
-    // For MVP USP Demonstration, we run a static WASM payload yielding an ACCEPT (200).
-    // In production, `vortex_admin` dynamically swaps this bytecode at runtime!
-    let wat_filter = r#"
-        (module
-            (func (export "execute") (result i32)
-                i32.const 200
-            )
-        )
-    "#;
-    match wasm_engine.execute_filter(wat_filter.as_bytes()) {
-        Ok(code) => println!("Wasm Filter executed natively across FFI boundary. Exit Code: {}", code),
-        Err(e) => eprintln!("Wasm Filter execution failed: {}", e),
-    }
-
-    // 1. Find the computationally optimal backend using Peak EWMA
-    let upstream_backend = select_best_backend(&routing_table);
+    offload: Option<Arc<dyn TunnelOffload>>,
+) {
+    // Raw TCP has no headers or cookies; only the client's address can be hashed
+    let hash = matches!(routing_table.hash_key(), Some(HashKey::ClientIp)).then(|| RequestHash::of_ip(peer.ip()).0);
+    let Some(backend) = select_best_backend_where(&routing_table, &[], hash, |_| true) else {
+        tracing::warn!(%peer, "{} (raw TCP)", ProxyError::NoHealthyBackend { route: "tcp".to_string() });
+        return;
+    };
+    let _active_guard = backend.ewma.increment_active();
 
-    let (upstream_addr, ewma_node) = match upstream_backend {
-        Some(backend) => (backend.addr, backend.clone()),
-        None => {
-            eprintln!("No healthy backends available!");
-            return Err(Box::from("No healthy backends available"));
+    let connect = async {
+        match &backend.dns_name {
+            Some(name) => TcpStream::connect(dns::next_address(name).await?).await,
+            None => TcpStream::connect(backend.addr).await,
+        }
+    };
+    let upstream = match tokio::time::timeout(TCP_CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(source)) => {
+            tracing::warn!("{}", ProxyError::UpstreamConnect { backend: backend.id, addr: backend.addr, source });
+            return;
+        }
+        Err(_) => {
+            tracing::warn!("{}", ProxyError::UpstreamTimeout { backend: backend.id, addr: backend.addr, phase: "connect" });
+            return;
         }
     };
 
-    // Increment active request gauge for this specific node
-    // This guard automatically decrements when it falls out of scope (after proxying finishes)
-    let _active_guard = ewma_node.ewma.increment_active();
+    if let Err(err) = splice(client, upstream, offload).await {
+        tracing::debug!(%peer, backend = %backend.addr, error = %err, "raw TCP tunnel failed");
+    }
+}
+
+/// Forwards bytes both ways until either side closes, in the kernel if the
+/// offload accepts the pair and in userland otherwise.
+async fn splice(client: TcpStream, upstream: TcpStream, offload: Option<Arc<dyn TunnelOffload>>) -> io::Result<()> {
+    let (mut client, mut upstream) = match offload {
+        Some(offload) => match offload.offload(client, upstream) {
+            Ok(tunnel) => {
+                metrics::TUNNELS_OFFLOADED.inc();
+                return tunnel.await;
+            }
+            Err(streams) => streams,
+        },
+        None => (client, upstream),
+    };
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await.map(|_| ())
+}
 
-    // Start RTT timer
-    let start_time = Instant::now();
+/// Answers a plaintext client with a 503 and closes the connection.
+///
+/// The request is read and discarded for a moment first: closing a socket
+/// with unread bytes resets it, and the client may never see the response.
+async fn refuse_overloaded(mut stream: TcpStream) {
+    let _ = stream.write_all(OVERLOADED_RESPONSE).await;
+    let _ = stream.shutdown().await;
+    let mut discard = [0u8; 1024];
+    let _ = tokio::time::timeout(REFUSAL_LINGER, async {
+        while matches!(stream.read(&mut discard).await, Ok(n) if n > 0) {}
+    })
+    .await;
+}
 
-    // 2. Try popping an existing, warm connection sender from our Hot Pool
-    let mut sender_opt = None;
-    if let Some(mut s) = connection_pool.try_pop(&upstream_addr) {
-        if s.ready().await.is_ok() {
-            sender_opt = Some(s);
-        }
+/// Counts a downstream connection in `metrics::ACTIVE_CONNECTIONS` until dropped.
+struct ConnectionGauge;
+
+impl ConnectionGauge {
+    fn open() -> Self {
+        metrics::ACTIVE_CONNECTIONS.inc();
+        ConnectionGauge
     }
+}
 
-    // 3. Either reuse the hot connection, or establish a new TCP stream to the backend
-    let mut sender = match sender_opt {
-        Some(s) => s,
-        None => {
-            let stream = match TcpStream::connect(upstream_addr).await {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Failed to connect to backend: {}", e);
-                    return Err(Box::new(e));
-                }
-            };
+impl Drop for ConnectionGauge {
+    fn drop(&mut self) {
+        metrics::ACTIVE_CONNECTIONS.dec();
+    }
+}
 
-            let io = TokioIo::new(stream);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // Perform the HTTP/1.1 handshake with the upstream server
-            let (s, conn) = match hyper::client::conn::http1::handshake(io).await {
-                Ok(handshake) => handshake,
-                Err(e) => {
-                    eprintln!("Failed HTTP handshake with backend: {}", e);
-                    return Err(Box::new(e));
-                }
-            };
+    #[test]
+    fn test_classify_preface() {
+        assert_eq!(classify_preface(b"GET / HTTP/1.1\r\n"), Some(Protocol::Http1));
+        assert_eq!(classify_preface(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), Some(Protocol::H2c));
+        assert_eq!(classify_preface(b"\x16\x03\x01\x02\x00"), Some(Protocol::Tcp));
+        assert_eq!(classify_preface(b"hello world"), Some(Protocol::Tcp));
 
-            // Spawn a task to drive the connection
-            tokio::task::spawn(async move {
-                if let Err(err) = conn.await {
-                    eprintln!("Connection failed: {:?}", err);
-                }
-            });
+        // Partial prefaces wait for more bytes
+        assert_eq!(classify_preface(b"PRI * HT"), None);
+        assert_eq!(classify_preface(b"POS"), None);
+    }
 
-            s
-        }
-    };
+    #[test]
+    fn test_listener_access() {
+        let enabled = Arc::new(AtomicBool::new(true));
+        let access = ListenerAccess {
+            allowed_sources: vec!["10.0.0.0/8".parse().unwrap()],
+            enabled: Some(enabled.clone()),
+            fd_monitor: None,
+            connection_rate: None,
+        };
+        assert_eq!(access.refusal("10.1.2.3:4000".parse().unwrap()), None);
+        assert_eq!(access.refusal("203.0.113.9:4000".parse().unwrap()), Some("source"));
 
-    // 4. Forward the original request directly with zero-copy stream
-    let uri_string = format!("http://{}{}", upstream_addr, req.uri().path_and_query().map(|x| x.as_str()).unwrap_or("/"));
-    *req.uri_mut() = uri_string.parse().unwrap();
-    req.headers_mut().insert(hyper::header::HOST, upstream_addr.to_string().parse().unwrap());
+        enabled.store(false, Ordering::Relaxed);
+        assert_eq!(access.refusal("10.1.2.3:4000".parse().unwrap()), Some("disabled"));
 
-    if sender.ready().await.is_err() {
-        return Err(Box::from("Failed to prepare connection sender"));
+        assert_eq!(ListenerAccess::default().refusal("203.0.113.9:4000".parse().unwrap()), None);
+
+        // Each source gets its own burst
+        let access = ListenerAccess { connection_rate: Some(Arc::new(TokenBuckets::new(1.0, 2))), ..Default::default() };
+        assert_eq!(access.refusal("203.0.113.9:4000".parse().unwrap()), None);
+        assert_eq!(access.refusal("203.0.113.9:4001".parse().unwrap()), None);
+        assert_eq!(access.refusal("203.0.113.9:4002".parse().unwrap()), Some("rate"));
+        assert_eq!(access.refusal("198.51.100.7:4000".parse().unwrap()), None);
     }
 
-    let res = sender.send_request(req).await?;
+    /// Refuses every tunnel, like an offload whose program failed to load.
+    struct Refusing;
 
-    // Return the sender cleanly to the Lock-Free pool for reuse by another request
-    connection_pool.push(upstream_addr, sender);
+    impl TunnelOffload for Refusing {
+        fn offload(&self, client: TcpStream, upstream: TcpStream) -> Result<OffloadedTunnel, (TcpStream, TcpStream)> {
+            Err((client, upstream))
+        }
+    }
 
-    // Record the round-trip latency and feed it into the Peak EWMA algorithm lock-free
-    let rtt_ms = start_time.elapsed().as_secs_f64() * 1000.0;
-    ewma_node.ewma.observe_latency(rtt_ms);
+    #[tokio::test]
+    async fn test_refused_offload_falls_back_to_userland() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    Ok(res)
-}
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut downstream = TcpStream::connect(addr).await.unwrap();
+        let (client, _) = listener.accept().await.unwrap();
+        let upstream = TcpStream::connect(addr).await.unwrap();
+        let (mut backend, _) = listener.accept().await.unwrap();
 
-#[cfg(test)]
-mod tests {
-    use hyper::Request;
-    use http_body_util::{BodyExt, Empty};
-    use hyper::body::Bytes;
+        let tunnel = tokio::spawn(splice(client, upstream, Some(Arc::new(Refusing))));
+        downstream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        backend.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
 
-    #[tokio::test]
-    async fn test_forward_request_routes_to_9090() {
-        // Without starting the backend, the direct TCP connect inside forward_request
-        // will return ConnectionRefused wrapped in BoxError. We assert this specific failure
-        // to verify that the routing logic is at least attempting to hit the right static port.
-
-        let _req = Request::builder()
-            .method("GET")
-            .uri("/")
-            .body(Empty::<Bytes>::new().map_err(|never| match never {}).boxed())
-            .unwrap();
-
-        // This isn't a direct test since signatures expect Incoming, but we can verify the core logic via types.
-        // For Phase 1, we acknowledge the proxy architecture is wired.
-        assert!(true);
+        drop(downstream);
+        drop(backend);
+        tunnel.await.unwrap().unwrap();
     }
 }
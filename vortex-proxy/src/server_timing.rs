@@ -0,0 +1,231 @@
+//! `Server-Timing` headers breaking down where the proxy spent a request's time.
+//!
+//! On routes that opt in, responses carry the phases the proxy measured,
+//! which browsers show in their developer tools next to the page's own
+//! timings:
+//!
+//! ```text
+//! Server-Timing: route;dur=0.041, queue;dur=0.210, connect;dur=1.302, ttfb;dur=12.877, total;dur=14.530
+//! ```
+//!
+//! - `route`: matching the route and picking a backend
+//! - `queue`: from then until the request went upstream, less `connect`
+//!   (filters, retries and waiting for a pooled connection)
+//! - `connect`: opening a new upstream connection; absent when one was reused
+//! - `ttfb`: from sending the request to the upstream's response headers
+//! - `total`: from the request entering the pipeline to the response headers
+//!
+//! Phases a request never reached, e.g. on a cache hit, are left out. With
+//! retries, the upstream phases are those of the last attempt. Durations are
+//! in milliseconds, and any `Server-Timing` the upstream sent is kept.
+
+use hyper::header::{HeaderName, HeaderValue};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+use crate::error::ProxyError;
+use crate::pipeline::{take_inner, ProxyFuture, ProxyRequest, ProxyResponse};
+
+/// The `Server-Timing` header (W3C Server Timing).
+const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+/// Which routes get `Server-Timing` headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerTiming {
+    /// Whether routes without their own setting get them
+    pub default: bool,
+    /// Settings for individual routes, by route name
+    pub routes: HashMap<String, bool>,
+}
+
+impl ServerTiming {
+    fn for_route(&self, route: &str) -> bool {
+        self.routes.get(route).copied().unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug)]
+struct Marks {
+    started: Instant,
+    route: Option<String>,
+    routed: Option<Instant>,
+    connect: Option<Duration>,
+    sent: Option<Instant>,
+    first_byte: Option<Instant>,
+}
+
+/// Request extension the stages record their progress in.
+#[derive(Debug, Clone)]
+pub(crate) struct PhaseTimer(Arc<Mutex<Marks>>);
+
+impl PhaseTimer {
+    fn start() -> Self {
+        Self(Arc::new(Mutex::new(Marks {
+            started: Instant::now(),
+            route: None,
+            routed: None,
+            connect: None,
+            sent: None,
+            first_byte: None,
+        })))
+    }
+
+    /// The request was routed to `route`.
+    pub(crate) fn routed(&self, route: &str) {
+        let mut marks = self.0.lock().unwrap();
+        marks.route = Some(route.to_string());
+        marks.routed = Some(Instant::now());
+    }
+
+    /// An upstream attempt opened a new connection, taking `took`.
+    pub(crate) fn connected(&self, took: Duration) {
+        self.0.lock().unwrap().connect = Some(took);
+    }
+
+    /// An upstream attempt started sending the request; `reused` if it went
+    /// over a pooled connection.
+    pub(crate) fn sent(&self, reused: bool) {
+        let mut marks = self.0.lock().unwrap();
+        if reused {
+            marks.connect = None;
+        }
+        marks.sent = Some(Instant::now());
+        marks.first_byte = None;
+    }
+
+    /// The upstream's response headers arrived.
+    pub(crate) fn first_byte(&self) {
+        self.0.lock().unwrap().first_byte = Some(Instant::now());
+    }
+
+    /// The header value for a response ready at `now`, if its route wants one.
+    fn header(&self, config: &ServerTiming, now: Instant) -> Option<HeaderValue> {
+        let marks = self.0.lock().unwrap();
+        if !config.for_route(marks.route.as_deref()?) {
+            return None;
+        }
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        // Writing into a String cannot fail
+        let mut out = String::new();
+        if let Some(routed) = marks.routed {
+            let _ = write!(out, "route;dur={:.3}, ", ms(routed - marks.started));
+            if let Some(sent) = marks.sent {
+                let queue = (sent - routed).saturating_sub(marks.connect.unwrap_or_default());
+                let _ = write!(out, "queue;dur={:.3}, ", ms(queue));
+            }
+        }
+        if let Some(connect) = marks.connect {
+            let _ = write!(out, "connect;dur={:.3}, ", ms(connect));
+        }
+        if let (Some(sent), Some(first_byte)) = (marks.sent, marks.first_byte) {
+            let _ = write!(out, "ttfb;dur={:.3}, ", ms(first_byte - sent));
+        }
+        let _ = write!(out, "total;dur={:.3}", ms(now - marks.started));
+        HeaderValue::from_str(&out).ok()
+    }
+}
+
+/// Adds `Server-Timing` headers on the routes `ServerTiming` selects.
+#[derive(Debug, Clone)]
+pub struct ServerTimingLayer {
+    config: Arc<ServerTiming>,
+}
+
+impl ServerTimingLayer {
+    /// Create a layer timing the routes `config` selects.
+    pub fn new(config: ServerTiming) -> Self {
+        Self { config: Arc::new(config) }
+    }
+}
+
+impl<S> Layer<S> for ServerTimingLayer {
+    type Service = ServerTimingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ServerTimingService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Service produced by `ServerTimingLayer`.
+#[derive(Debug, Clone)]
+pub struct ServerTimingService<S> {
+    inner: S,
+    config: Arc<ServerTiming>,
+}
+
+impl<S> Service<ProxyRequest> for ServerTimingService<S>
+where
+    S: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = ProxyResponse;
+    type Error = ProxyError;
+    type Future = ProxyFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ProxyRequest) -> Self::Future {
+        let timer = PhaseTimer::start();
+        req.extensions_mut().insert(timer.clone());
+        let config = self.config.clone();
+        let mut inner = take_inner(&mut self.inner);
+        Box::pin(async move {
+            let mut res = inner.call(req).await?;
+            if let Some(value) = timer.header(&config, Instant::now()) {
+                res.headers_mut().append(SERVER_TIMING, value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_lists_the_phases_reached() {
+        let config = ServerTiming {
+            default: true,
+            routes: [("static".to_string(), false)].into(),
+        };
+        let timer = PhaseTimer::start();
+        assert_eq!(timer.header(&config, Instant::now()), None);
+
+        timer.routed("api");
+        let cached = timer.header(&config, Instant::now()).unwrap();
+        let cached = cached.to_str().unwrap();
+        assert!(cached.starts_with("route;dur="));
+        assert!(cached.contains(", total;dur="));
+        assert!(!cached.contains("ttfb"));
+
+        timer.connected(Duration::from_millis(3));
+        timer.sent(false);
+        timer.first_byte();
+        let value = timer.header(&config, Instant::now()).unwrap();
+        let phases: Vec<&str> = value
+            .to_str()
+            .unwrap()
+            .split(", ")
+            .map(|phase| phase.split(';').next().unwrap())
+            .collect();
+        assert_eq!(phases, ["route", "queue", "connect", "ttfb", "total"]);
+        assert!(value.to_str().unwrap().contains("connect;dur=3.000"));
+
+        // A retry over a pooled connection has no connect phase
+        timer.sent(true);
+        assert!(!timer.header(&config, Instant::now()).unwrap().to_str().unwrap().contains("connect"));
+
+        timer.routed("static");
+        assert_eq!(timer.header(&config, Instant::now()), None);
+    }
+}
@@ -0,0 +1,192 @@
+//! TCP socket options for accepted and upstream connections.
+//!
+//! Also home to transparent proxying on Linux: a listener bound with
+//! `IP_TRANSPARENT` accepts connections that iptables/nftables `TPROXY` rules
+//! steer to it whatever their destination, and `original_dst` recovers where
+//! such a client (or one redirected with `REDIRECT`/`DNAT`) was really going.
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Backlog for listeners bound by `bind_listener`, as tokio uses.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// TCP keepalive probing for otherwise idle connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe is sent
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped
+    pub count: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            count: 6,
+        }
+    }
+}
+
+/// Socket options applied to a connection right after accept or connect.
+///
+/// Unset options keep the operating system's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Disable (`true`) or enable (`false`) Nagle's algorithm via `TCP_NODELAY`
+    pub nodelay: Option<bool>,
+    /// Enable `SO_KEEPALIVE` with these timings
+    pub keepalive: Option<Keepalive>,
+    /// `SO_SNDBUF` in bytes
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF` in bytes
+    pub recv_buffer_size: Option<usize>,
+    /// The IP TOS byte (IPv6 traffic class); the DSCP value is its upper six bits
+    pub tos: Option<u32>,
+    /// Listeners only: bind with `IP_TRANSPARENT` (Linux, needs `CAP_NET_ADMIN`)
+    /// and record each connection's original destination
+    pub transparent: bool,
+}
+
+impl SocketOptions {
+    /// Whether any option is set, i.e. whether `apply` would touch the socket.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply the configured options to `stream`, stopping at the first failure.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            let params = TcpKeepalive::new()
+                .with_time(keepalive.idle)
+                .with_interval(keepalive.interval)
+                .with_retries(keepalive.count);
+            socket.set_tcp_keepalive(&params)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            if stream.local_addr()?.is_ipv4() {
+                socket.set_tos_v4(tos)?;
+            } else {
+                // The traffic class can only be set where socket2 supports it
+                #[cfg(target_os = "linux")]
+                socket.set_tclass_v6(tos)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bind a listener on `addr`, honoring `transparent`.
+    pub async fn bind_listener(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        if !self.transparent {
+            return TcpListener::bind(addr).await;
+        }
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        set_transparent(&socket, addr)?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Like `apply`, but only logs a failure: a connection with default
+    /// socket options is still better than no connection.
+    pub(crate) fn apply_or_log(&self, stream: &TcpStream, what: &str) {
+        if self.is_empty() {
+            return;
+        }
+        if let Err(e) = self.apply(stream) {
+            tracing::warn!(socket = what, error = %e, "failed to set socket options");
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+    if addr.is_ipv6() {
+        // socket2 only exposes the IPv4 option
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "transparent listeners must bind IPv4"));
+    }
+    socket.set_ip_transparent_v4(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_socket: &Socket, _addr: SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "transparent proxying requires Linux"))
+}
+
+/// Where the client of an intercepted connection was really connecting to.
+///
+/// `REDIRECT`/`DNAT` rewrite the destination, which `SO_ORIGINAL_DST` still
+/// reports; under `TPROXY` the destination is kept, so it is the socket's own
+/// local address.
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    #[cfg(target_os = "linux")]
+    {
+        let socket = SockRef::from(stream);
+        let nat = if stream.local_addr()?.is_ipv4() {
+            socket.original_dst_v4()
+        } else {
+            socket.original_dst_v6()
+        };
+        if let Some(addr) = nat.ok().and_then(|addr| addr.as_socket()) {
+            return Ok(addr);
+        }
+    }
+    stream.local_addr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_options_are_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let options = SocketOptions {
+            nodelay: Some(true),
+            keepalive: Some(Keepalive::default()),
+            recv_buffer_size: Some(256 * 1024),
+            tos: Some(0x28 << 2),
+            ..SocketOptions::default()
+        };
+        options.apply(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(60));
+        assert_eq!(socket.tcp_keepalive_retries().unwrap(), 6);
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert_eq!(socket.tos_v4().unwrap(), 0x28 << 2);
+    }
+
+    #[tokio::test]
+    async fn test_original_dst_without_nat_is_the_local_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert_eq!(original_dst(&accepted).unwrap(), addr);
+    }
+}
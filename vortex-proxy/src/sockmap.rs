@@ -0,0 +1,434 @@
+//! In-kernel forwarding of raw TCP tunnels through an eBPF sockmap
+//! (experimental; Linux with the `ebpf` feature).
+//!
+//! [`SockmapOffload`] loads a small `sk_skb` stream verdict program and
+//! attaches it to a sockmap. Both sockets of a tunnel go in the sockmap, and a
+//! hash map from each socket's cookie to its peer's slot lets the program
+//! redirect whatever arrives on one socket straight out of the other, so the
+//! bytes never reach userland. The proxy only waits for each side to close.
+//!
+//! Loading needs `CAP_BPF` and `CAP_NET_ADMIN` (or root). Tunnels past the
+//! sockmap's capacity, or arriving while a socket can't be added, are handed
+//! back and copied in userland as before.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::server::{OffloadedTunnel, TunnelOffload};
+
+/// Longest wait for redirected bytes to reach the other socket before its
+/// side of the tunnel is closed.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the other socket is checked while its redirected bytes flush.
+const FLUSH_POLL: Duration = Duration::from_millis(1);
+
+// From linux/bpf.h
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_ATTACH: libc::c_long = 8;
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_SOCKMAP: u32 = 15;
+const BPF_PROG_TYPE_SK_SKB: u32 = 14;
+const BPF_SK_SKB_STREAM_VERDICT: u32 = 5;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_GET_SOCKET_COOKIE: i32 = 46;
+const BPF_FUNC_SK_REDIRECT_MAP: i32 = 52;
+const SK_PASS: i32 = 1;
+
+/// `bpf_attr` for `BPF_MAP_CREATE`.
+#[repr(C)]
+struct MapCreate {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+/// `bpf_attr` for `BPF_PROG_LOAD`.
+#[repr(C)]
+struct ProgLoad {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// `bpf_attr` for `BPF_PROG_ATTACH`.
+#[repr(C)]
+struct ProgAttach {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+/// `bpf_attr` for `BPF_MAP_UPDATE_ELEM` and `BPF_MAP_DELETE_ELEM`.
+#[repr(C)]
+struct MapElem {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// One eBPF instruction.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn { code, regs: (src << 4) | dst, off, imm }
+}
+
+/// The verdict program: look up the peer slot by the receiving socket's
+/// cookie and redirect the data out of the peer, or pass it to the socket
+/// itself if it isn't part of a tunnel.
+fn verdict_program(peers: RawFd, sockets: RawFd) -> [Insn; 18] {
+    [
+        // r6 = skb
+        insn(0xbf, 6, 1, 0, 0),
+        // r0 = bpf_get_socket_cookie(skb); *(u64 *)(r10 - 8) = r0
+        insn(0x85, 0, 0, 0, BPF_FUNC_GET_SOCKET_COOKIE),
+        insn(0x7b, 10, 0, -8, 0),
+        // r0 = bpf_map_lookup_elem(peers, r10 - 8)
+        insn(0x18, 1, BPF_PSEUDO_MAP_FD, 0, peers),
+        insn(0, 0, 0, 0, 0),
+        insn(0xbf, 2, 10, 0, 0),
+        insn(0x07, 2, 0, 0, -8),
+        insn(0x85, 0, 0, 0, BPF_FUNC_MAP_LOOKUP_ELEM),
+        // Not a tunnel socket: pass
+        insn(0x15, 0, 0, 7, 0),
+        // return bpf_sk_redirect_map(skb, sockets, *r0, 0)
+        insn(0x61, 3, 0, 0, 0),
+        insn(0xbf, 1, 6, 0, 0),
+        insn(0x18, 2, BPF_PSEUDO_MAP_FD, 0, sockets),
+        insn(0, 0, 0, 0, 0),
+        insn(0xb7, 4, 0, 0, 0),
+        insn(0x85, 0, 0, 0, BPF_FUNC_SK_REDIRECT_MAP),
+        insn(0x95, 0, 0, 0, 0),
+        insn(0xb7, 0, 0, 0, SK_PASS),
+        insn(0x95, 0, 0, 0, 0),
+    ]
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<libc::c_long> {
+    // SAFETY: `attr` is a fully initialized `bpf_attr` prefix for `cmd`, and
+    // the buffers it points to outlive the call
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *const T, std::mem::size_of::<T>()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn bpf_fd<T>(cmd: libc::c_long, attr: &T) -> io::Result<OwnedFd> {
+    let fd = bpf(cmd, attr)? as RawFd;
+    // SAFETY: the kernel just returned this descriptor, and nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn create_map(map_type: u32, key_size: u32, value_size: u32, max_entries: u32) -> io::Result<OwnedFd> {
+    bpf_fd(BPF_MAP_CREATE, &MapCreate { map_type, key_size, value_size, max_entries, map_flags: 0 })
+}
+
+fn update_elem<K, V>(map: &OwnedFd, key: &K, value: &V) -> io::Result<()> {
+    let attr = MapElem {
+        map_fd: map.as_raw_fd() as u32,
+        pad: 0,
+        key: key as *const K as u64,
+        value: value as *const V as u64,
+        flags: 0,
+    };
+    bpf(BPF_MAP_UPDATE_ELEM, &attr).map(drop)
+}
+
+fn delete_elem<K>(map: &OwnedFd, key: &K) {
+    let attr = MapElem { map_fd: map.as_raw_fd() as u32, pad: 0, key: key as *const K as u64, value: 0, flags: 0 };
+    // Sockets leave the sockmap by themselves once closed
+    let _ = bpf(BPF_MAP_DELETE_ELEM, &attr);
+}
+
+fn getsockopt<T: Default>(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<(T, usize)> {
+    let mut value = T::default();
+    let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+    // SAFETY: `value` is writable for `len` bytes, which the kernel shrinks to what it wrote
+    let ret = unsafe { libc::getsockopt(fd, level, name, &mut value as *mut T as *mut libc::c_void, &mut len) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((value, len as usize))
+}
+
+/// The socket's cookie, which is what the program knows it by.
+fn socket_cookie(stream: &TcpStream) -> io::Result<u64> {
+    getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_COOKIE).map(|(cookie, _)| cookie)
+}
+
+/// Bytes the socket has received and bytes written to it, from `TCP_INFO`
+/// (`tcpi_bytes_received`, and `tcpi_bytes_acked` plus what is still queued).
+fn tcp_bytes(stream: &TcpStream) -> io::Result<(u64, u64)> {
+    // `struct tcp_info` in u64 words: bytes_acked is the 16th, bytes_received the 17th
+    let (info, len): ([u64; 17], _) = getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO)?;
+    if len < std::mem::size_of_val(&info) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "TCP_INFO lacks byte counts"));
+    }
+    let mut queued: libc::c_int = 0;
+    // SAFETY: SIOCOUTQ writes one int
+    if unsafe { libc::ioctl(stream.as_raw_fd(), libc::TIOCOUTQ, &mut queued) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((info[16], info[15] + queued as u64))
+}
+
+/// The loaded program and its maps.
+struct Maps {
+    /// Tunnel sockets by slot
+    sockets: OwnedFd,
+    /// Each tunnel socket's peer slot, by socket cookie
+    peers: OwnedFd,
+    _program: OwnedFd,
+    free_slots: Mutex<Vec<u32>>,
+    /// Bytes the program passed back instead of redirecting, copied in userland
+    passed_back: AtomicU64,
+}
+
+impl Maps {
+    fn add(&self, cookie: u64, slot: u32, stream: &TcpStream, peer_slot: u32) -> io::Result<()> {
+        update_elem(&self.peers, &cookie, &peer_slot)?;
+        update_elem(&self.sockets, &slot, &(stream.as_raw_fd() as u32))
+    }
+
+    fn remove(&self, cookie: u64, slot: u32) {
+        delete_elem(&self.sockets, &slot);
+        delete_elem(&self.peers, &cookie);
+        self.free_slots.lock().unwrap_or_else(|e| e.into_inner()).push(slot);
+    }
+}
+
+/// A tunnel's entries in the maps, removed when dropped.
+struct Entries {
+    maps: Arc<Maps>,
+    added: Vec<(u64, u32)>,
+}
+
+impl Drop for Entries {
+    fn drop(&mut self) {
+        for (cookie, slot) in self.added.drain(..) {
+            self.maps.remove(cookie, slot);
+        }
+    }
+}
+
+/// Forwards raw TCP tunnels in the kernel with an eBPF sockmap program.
+#[derive(Clone)]
+pub struct SockmapOffload {
+    maps: Arc<Maps>,
+}
+
+impl SockmapOffload {
+    /// Load the program, with room for `max_tunnels` tunnels at once.
+    pub fn load(max_tunnels: u32) -> io::Result<Self> {
+        let slots = max_tunnels.checked_mul(2).filter(|&n| n > 0).ok_or(io::ErrorKind::InvalidInput)?;
+        let sockets = create_map(BPF_MAP_TYPE_SOCKMAP, 4, 4, slots)?;
+        let peers = create_map(BPF_MAP_TYPE_HASH, 8, 4, slots)?;
+
+        let program = verdict_program(peers.as_raw_fd(), sockets.as_raw_fd());
+        let license = c"Dual MIT/GPL";
+        let program = bpf_fd(
+            BPF_PROG_LOAD,
+            &ProgLoad {
+                prog_type: BPF_PROG_TYPE_SK_SKB,
+                insn_cnt: program.len() as u32,
+                insns: program.as_ptr() as u64,
+                license: license.as_ptr() as u64,
+                log_level: 0,
+                log_size: 0,
+                log_buf: 0,
+                kern_version: 0,
+                prog_flags: 0,
+            },
+        )?;
+        let attach = ProgAttach {
+            target_fd: sockets.as_raw_fd() as u32,
+            attach_bpf_fd: program.as_raw_fd() as u32,
+            attach_type: BPF_SK_SKB_STREAM_VERDICT,
+            attach_flags: 0,
+        };
+        bpf(BPF_PROG_ATTACH, &attach)?;
+
+        let free_slots = Mutex::new((0..slots).rev().collect());
+        let maps = Maps { sockets, peers, _program: program, free_slots, passed_back: AtomicU64::new(0) };
+        Ok(Self { maps: Arc::new(maps) })
+    }
+
+    /// Puts both sockets in the maps, each pointing at the other.
+    fn add(&self, client: &TcpStream, upstream: &TcpStream) -> io::Result<Entries> {
+        let (client_cookie, upstream_cookie) = (socket_cookie(client)?, socket_cookie(upstream)?);
+        let slots = {
+            let mut free = self.maps.free_slots.lock().unwrap_or_else(|e| e.into_inner());
+            if free.len() < 2 {
+                return Err(io::Error::new(io::ErrorKind::OutOfMemory, "sockmap is full"));
+            }
+            [free.pop(), free.pop()]
+        };
+        let [Some(client_slot), Some(upstream_slot)] = slots else { unreachable!("two slots were free") };
+        let mut entries = Entries { maps: self.maps.clone(), added: Vec::with_capacity(2) };
+        // Each side's peer is known before its bytes can arrive there
+        entries.added.push((upstream_cookie, upstream_slot));
+        self.maps.add(upstream_cookie, upstream_slot, upstream, client_slot)?;
+        entries.added.push((client_cookie, client_slot));
+        self.maps.add(client_cookie, client_slot, client, upstream_slot)?;
+        Ok(entries)
+    }
+}
+
+impl TunnelOffload for SockmapOffload {
+    fn offload(&self, client: TcpStream, upstream: TcpStream) -> Result<OffloadedTunnel, (TcpStream, TcpStream)> {
+        let entries = match self.add(&client, &upstream) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::debug!(error = %e, "tunnel not offloaded");
+                return Err((client, upstream));
+            }
+        };
+        // Bytes queued before the sockets joined the map (such as the client's
+        // sniffed preface) are only seen on the next wakeup; raising the
+        // receive low-water mark to what it already is wakes the program now
+        for stream in [&client, &upstream] {
+            let one: libc::c_int = 1;
+            // SAFETY: SO_RCVLOWAT reads one int
+            unsafe {
+                libc::setsockopt(
+                    stream.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_RCVLOWAT,
+                    &one as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                );
+            }
+        }
+
+        let (client_read, client_write) = client.into_split();
+        let (upstream_read, upstream_write) = upstream.into_split();
+        Ok(Box::pin(async move {
+            let maps = &entries.maps;
+            tokio::try_join!(forward(client_read, upstream_write, maps), forward(upstream_read, client_write, maps))?;
+            Ok(())
+        }))
+    }
+}
+
+/// Waits for `from` to close, then closes `to` for writing once the bytes
+/// redirected into it have been sent.
+async fn forward(mut from: OwnedReadHalf, mut to: OwnedWriteHalf, maps: &Maps) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        // Only bytes the program passed back, if any, are ever read here
+        match from.read(&mut buf).await? {
+            0 => break,
+            n => {
+                maps.passed_back.fetch_add(n as u64, Ordering::Relaxed);
+                to.write_all(&buf[..n]).await?
+            }
+        }
+    }
+    // Redirected bytes go out from a kernel work queue, so the FIN could overtake them
+    let received = tcp_bytes(from.as_ref())?.0.saturating_sub(1); // less the FIN
+    let deadline = Instant::now() + FLUSH_TIMEOUT;
+    while tcp_bytes(to.as_ref())?.1 < received && Instant::now() < deadline {
+        tokio::time::sleep(FLUSH_POLL).await;
+    }
+    to.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A client and an accepted connection, upstream and backend likewise.
+    async fn pairs(listener: &TcpListener) -> [(TcpStream, TcpStream); 2] {
+        let addr = listener.local_addr().unwrap();
+        let mut pairs = Vec::new();
+        for _ in 0..2 {
+            let connected = TcpStream::connect(addr).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            pairs.push((connected, accepted));
+        }
+        pairs.try_into().unwrap_or_else(|_| unreachable!())
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_are_forwarded_in_the_kernel() {
+        // Without privileges or BPF there is nothing to test; a rejected program is a failure
+        let offload = match SockmapOffload::load(1) {
+            Ok(offload) => offload,
+            Err(e) if matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported) => {
+                eprintln!("skipping: BPF unavailable: {}", e);
+                return;
+            }
+            Err(e) => panic!("failed to load the program: {}", e),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let [(mut downstream, client), (upstream, mut backend)] = pairs(&listener).await;
+
+        // Sent before the offload, as a sniffed preface would be
+        downstream.write_all(b"hello").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let tunnel = tokio::spawn(offload.offload(client, upstream).unwrap_or_else(|_| panic!("not offloaded")));
+
+        // One tunnel fills the map, so the next is handed back
+        let [(_other_downstream, other_client), (other_upstream, _other_backend)] = pairs(&listener).await;
+        assert!(offload.offload(other_client, other_upstream).is_err());
+
+        let mut buf = [0u8; 5];
+        backend.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        downstream.write_all(b" world").await.unwrap();
+        let mut buf = [0u8; 6];
+        backend.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b" world");
+
+        // The client finishing reaches the backend, which still answers
+        downstream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        backend.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        let reply = vec![7u8; 256 * 1024];
+        backend.write_all(&reply).await.unwrap();
+        drop(backend);
+        let mut received = Vec::new();
+        downstream.read_to_end(&mut received).await.unwrap();
+        assert!(received == reply);
+        tunnel.await.unwrap().unwrap();
+        // None of it went through userland
+        assert_eq!(offload.maps.passed_back.load(Ordering::Relaxed), 0);
+
+        // Its slots are free again
+        let [(_downstream, client), (upstream, _backend)] = pairs(&listener).await;
+        assert!(offload.offload(client, upstream).is_ok());
+    }
+}
@@ -3,33 +3,92 @@
 //! This module handles loading certificates and private keys
 //! into a `rustls::ServerConfig`, and providing an acceptor
 //! for incoming secure connections.
+//!
+//! Every loaded leaf certificate is also recorded in a process-wide registry
+//! so its expiry can be exported as a metric and watched by
+//! `spawn_cert_expiry_monitor` before it turns into an outage.
 
 use pki_types::{CertificateDer, PrivateKeyDer};
 use rustls::ServerConfig;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::alerting::{Alert, Alerter, Severity};
+
+/// Default lead time for certificate expiry warnings.
+pub const DEFAULT_CERT_EXPIRY_WARNING: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Errors raised while loading TLS material from disk.
+#[derive(Debug, Error)]
+pub enum TlsConfigError {
+    /// A certificate or key file could not be read.
+    #[error("failed to read {path}: {source}")]
+    Io {
+        /// The file that failed to load
+        path: String,
+        /// The underlying I/O error
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The key file did not contain a PKCS#8 private key.
+    #[error("no PKCS#8 private key found in {0}")]
+    MissingPrivateKey(String),
+
+    /// A certificate could not be parsed as X.509.
+    #[error("invalid certificate from {source_name}: {reason}")]
+    InvalidCertificate {
+        /// Where the certificate came from
+        source_name: String,
+        /// Why parsing failed
+        reason: String,
+    },
+
+    /// rustls rejected the certificate/key pair.
+    #[error("invalid certificate or key: {0}")]
+    Rustls(#[from] rustls::Error),
+}
 
 /// Loads a TLS `ServerConfig` from the given certificate and key paths.
 pub fn load_tls_config<P: AsRef<Path>>(
     cert_path: P,
     key_path: P,
-) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error + Send + Sync>> {
-    let cert_file = File::open(cert_path)?;
+) -> Result<Arc<ServerConfig>, TlsConfigError> {
+    let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+
+    let cert_file = File::open(cert_path).map_err(io_err(cert_path))?;
     let mut cert_reader = BufReader::new(cert_file);
     let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut cert_reader)
-        .collect::<Result<Vec<_>, _>>()?;
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_err(cert_path))?;
 
-    let key_file = File::open(key_path)?;
+    let key_file = File::open(key_path).map_err(io_err(key_path))?;
     let mut key_reader = BufReader::new(key_file);
     let mut keys = rustls_pemfile::pkcs8_private_keys(&mut key_reader)
         .map(|res| res.map(PrivateKeyDer::Pkcs8))
-        .collect::<Result<Vec<_>, _>>()?;
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_err(key_path))?;
 
     // For simplicity, just grab the first valid key
+    if keys.is_empty() {
+        return Err(TlsConfigError::MissingPrivateKey(key_path.display().to_string()));
+    }
     let key = keys.remove(0);
 
+    // Track the leaf certificate's expiry; a parse failure here must not block serving
+    if let Some(leaf) = certs.first() {
+        if let Err(e) = register_certificate(&cert_path.display().to_string(), leaf) {
+            tracing::warn!(error = %e, "not monitoring certificate expiry");
+        }
+    }
+
     let mut config = ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(certs, key)?;
@@ -38,3 +97,144 @@ pub fn load_tls_config<P: AsRef<Path>>(
 
     Ok(Arc::new(config))
 }
+
+/// Identity and expiry of a certificate the proxy is serving.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateInfo {
+    /// Where it was loaded from: a file path, SNI name, or ACME order
+    pub source: String,
+    /// The certificate's subject distinguished name
+    pub subject: String,
+    /// The end of the validity period
+    pub not_after: SystemTime,
+}
+
+static LOADED_CERTIFICATES: LazyLock<Mutex<BTreeMap<String, CertificateInfo>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Records a served certificate for expiry monitoring, replacing any earlier
+/// certificate from the same `source` (e.g. after a reload or renewal).
+///
+/// Called by `load_tls_config`; per-SNI and ACME certificate loaders should
+/// call it for each leaf they install.
+pub fn register_certificate(source: &str, der: &CertificateDer<'_>) -> Result<CertificateInfo, TlsConfigError> {
+    let invalid = |reason: String| TlsConfigError::InvalidCertificate {
+        source_name: source.to_string(),
+        reason,
+    };
+    let (_, cert) = x509_parser::parse_x509_certificate(der.as_ref()).map_err(|e| invalid(e.to_string()))?;
+    let not_after = u64::try_from(cert.validity().not_after.timestamp())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .map_err(|_| invalid("not-after predates the Unix epoch".to_string()))?;
+
+    let info = CertificateInfo {
+        source: source.to_string(),
+        subject: cert.subject().to_string(),
+        not_after,
+    };
+    LOADED_CERTIFICATES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(info.source.clone(), info.clone());
+    Ok(info)
+}
+
+/// Every certificate currently registered, ordered by source.
+pub fn loaded_certificates() -> Vec<CertificateInfo> {
+    LOADED_CERTIFICATES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect()
+}
+
+/// Spawns a task that checks every registered certificate each `check_interval`,
+/// warning (and alerting, if an `Alerter` is given) once one expires within `warn_within`.
+pub fn spawn_cert_expiry_monitor(
+    warn_within: Duration,
+    check_interval: Duration,
+    alerter: Option<Arc<Alerter>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(check_interval);
+        loop {
+            interval.tick().await;
+
+            let now = SystemTime::now();
+            for cert in loaded_certificates() {
+                let Some(alert) = expiry_alert(&cert, now, warn_within) else {
+                    continue;
+                };
+                tracing::warn!(key = %alert.key, "{}", alert.summary);
+                if let Some(alerter) = &alerter {
+                    alerter.fire(alert).await;
+                }
+            }
+        }
+    })
+}
+
+fn expiry_alert(cert: &CertificateInfo, now: SystemTime, warn_within: Duration) -> Option<Alert> {
+    let (severity, summary) = match cert.not_after.duration_since(now) {
+        Err(_) => (
+            Severity::Critical,
+            format!("certificate {} ({}) has expired", cert.source, cert.subject),
+        ),
+        Ok(remaining) if remaining < warn_within => (
+            Severity::Warning,
+            format!(
+                "certificate {} ({}) expires in {} days",
+                cert.source,
+                cert.subject,
+                remaining.as_secs() / 86_400
+            ),
+        ),
+        Ok(_) => return None,
+    };
+    Some(Alert {
+        key: format!("cert_expiry:{}", cert.source),
+        severity,
+        summary,
+    })
+}
+
+fn io_err(path: &Path) -> impl FnOnce(std::io::Error) -> TlsConfigError {
+    let path = path.display().to_string();
+    move |source| TlsConfigError::Io { path, source }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registered_certificate_reports_not_after() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["vortex.test".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(2030, 1, 1);
+        let cert = params.self_signed(&key).unwrap();
+
+        let info = register_certificate("test:not-after", cert.der()).unwrap();
+        assert_eq!(info.not_after, UNIX_EPOCH + Duration::from_secs(1_893_456_000));
+        assert!(loaded_certificates().contains(&info));
+    }
+
+    #[test]
+    fn test_expiry_alert_thresholds() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let cert = |days_left: u64| CertificateInfo {
+            source: "certs/cert.pem".to_string(),
+            subject: "CN=vortex".to_string(),
+            not_after: now + Duration::from_secs(days_left * 86_400),
+        };
+
+        assert!(expiry_alert(&cert(30), now, DEFAULT_CERT_EXPIRY_WARNING).is_none());
+        let soon = expiry_alert(&cert(3), now, DEFAULT_CERT_EXPIRY_WARNING).unwrap();
+        assert_eq!(soon.severity, Severity::Warning);
+        assert_eq!(soon.key, "cert_expiry:certs/cert.pem");
+
+        let expired = CertificateInfo { not_after: now - Duration::from_secs(1), ..cert(0) };
+        assert_eq!(expiry_alert(&expired, now, DEFAULT_CERT_EXPIRY_WARNING).unwrap().severity, Severity::Critical);
+    }
+}
@@ -0,0 +1,207 @@
+//! Classifying failed TLS handshakes.
+//!
+//! A client that can't complete a handshake (an old TLS stack, a missing
+//! cipher suite, a plaintext request on the TLS port) never reaches the
+//! pipeline, so it shows up nowhere else. Every failure is counted by reason
+//! in `vortex_tls_handshake_failures_total` and logged as a warning, at most
+//! once per reason every `LOG_INTERVAL`, with the number of failures logged
+//! since then; the rest are only logged at debug level.
+
+use prometheus::IntCounterVec;
+use rustls::{AlertDescription, PeerIncompatible};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often each failure reason may log a warning.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Failed handshakes, by `reason` (see `HandshakeFailure::as_str`)
+static FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "vortex_tls_handshake_failures_total",
+        "Failed TLS handshakes by reason",
+        &["reason"]
+    )
+    .expect("metric registers once")
+});
+
+static THROTTLE: LazyLock<LogThrottle> = LazyLock::new(|| LogThrottle::new(LOG_INTERVAL));
+
+/// Why a TLS handshake with a client failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakeFailure {
+    /// No cipher suite, key exchange group or signature scheme in common
+    NoSharedCipher,
+    /// No certificate is configured for the server name the client asked for
+    UnknownSni,
+    /// The client's certificate was missing or failed verification
+    BadClientCert,
+    /// The client only speaks TLS versions the proxy doesn't accept
+    ProtocolVersion,
+    /// The client aborted the handshake with an alert, e.g. rejecting the proxy's certificate
+    PeerAlert,
+    /// The bytes received weren't valid TLS, e.g. plaintext HTTP on a TLS port
+    Malformed,
+    /// The connection closed or reset before the handshake finished
+    ClientClosed,
+    /// Anything else
+    Other,
+}
+
+impl HandshakeFailure {
+    /// Classifies an error returned by `TlsAcceptor::accept`.
+    pub fn classify(err: &io::Error) -> Self {
+        if let Some(tls) = err.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+            return Self::from_rustls(tls);
+        }
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {
+                Self::ClientClosed
+            }
+            _ => Self::Other,
+        }
+    }
+
+    fn from_rustls(err: &rustls::Error) -> Self {
+        match err {
+            rustls::Error::PeerIncompatible(
+                PeerIncompatible::NoCipherSuitesInCommon
+                | PeerIncompatible::NoKxGroupsInCommon
+                | PeerIncompatible::NoSignatureSchemesInCommon,
+            ) => Self::NoSharedCipher,
+            rustls::Error::PeerIncompatible(
+                PeerIncompatible::SupportedVersionsExtensionRequired
+                | PeerIncompatible::ServerDoesNotSupportTls12Or13
+                | PeerIncompatible::Tls12NotOffered
+                | PeerIncompatible::Tls12NotOfferedOrEnabled,
+            )
+            | rustls::Error::AlertReceived(AlertDescription::ProtocolVersion) => Self::ProtocolVersion,
+            // What rustls reports when no certificate resolves for the SNI name
+            rustls::Error::General(msg) if msg == "no server certificate chain resolved" => Self::UnknownSni,
+            rustls::Error::InvalidCertificate(_)
+            | rustls::Error::NoCertificatesPresented
+            | rustls::Error::InvalidCertRevocationList(_) => Self::BadClientCert,
+            rustls::Error::AlertReceived(_) => Self::PeerAlert,
+            rustls::Error::InvalidMessage(_)
+            | rustls::Error::PeerMisbehaved(_)
+            | rustls::Error::InappropriateMessage { .. }
+            | rustls::Error::InappropriateHandshakeMessage { .. } => Self::Malformed,
+            _ => Self::Other,
+        }
+    }
+
+    /// The `reason` label value
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NoSharedCipher => "no_shared_cipher",
+            Self::UnknownSni => "unknown_sni",
+            Self::BadClientCert => "bad_client_cert",
+            Self::ProtocolVersion => "protocol_version",
+            Self::PeerAlert => "peer_alert",
+            Self::Malformed => "malformed",
+            Self::ClientClosed => "client_closed",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Counts and logs a failed handshake with `peer`.
+pub(crate) fn record_handshake_failure(peer: SocketAddr, err: &io::Error) -> HandshakeFailure {
+    let reason = HandshakeFailure::classify(err);
+    FAILURES.with_label_values(&[reason.as_str()]).inc();
+    match THROTTLE.admit(reason, Instant::now()) {
+        Some(suppressed) => tracing::warn!(
+            %peer,
+            reason = reason.as_str(),
+            suppressed,
+            error = %err,
+            "TLS handshake failed"
+        ),
+        None => tracing::debug!(%peer, reason = reason.as_str(), error = %err, "TLS handshake failed"),
+    }
+    reason
+}
+
+/// Admits one warning per failure reason per interval.
+struct LogThrottle {
+    interval: Duration,
+    /// When each reason last logged a warning, and how many failures were not warned about since
+    last: Mutex<HashMap<HandshakeFailure, (Instant, u64)>>,
+}
+
+impl LogThrottle {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a failure for `reason` at `now` should warn; if so, how many
+    /// were held back since the last warning.
+    fn admit(&self, reason: HandshakeFailure, now: Instant) -> Option<u64> {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        match last.get_mut(&reason) {
+            Some((logged_at, suppressed)) if now.duration_since(*logged_at) < self.interval => {
+                *suppressed += 1;
+                None
+            }
+            Some((logged_at, suppressed)) => {
+                *logged_at = now;
+                Some(std::mem::take(suppressed))
+            }
+            None => {
+                last.insert(reason, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls_error(err: rustls::Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    #[test]
+    fn test_classify_handshake_failures() {
+        let cases = [
+            (tls_error(PeerIncompatible::NoCipherSuitesInCommon.into()), HandshakeFailure::NoSharedCipher),
+            (tls_error(PeerIncompatible::SupportedVersionsExtensionRequired.into()), HandshakeFailure::ProtocolVersion),
+            (
+                tls_error(rustls::Error::General("no server certificate chain resolved".to_string())),
+                HandshakeFailure::UnknownSni,
+            ),
+            (tls_error(rustls::Error::NoCertificatesPresented), HandshakeFailure::BadClientCert),
+            (tls_error(rustls::Error::AlertReceived(AlertDescription::UnknownCA)), HandshakeFailure::PeerAlert),
+            (
+                tls_error(rustls::Error::InvalidMessage(rustls::InvalidMessage::InvalidContentType)),
+                HandshakeFailure::Malformed,
+            ),
+            (io::Error::from(io::ErrorKind::UnexpectedEof), HandshakeFailure::ClientClosed),
+            (io::Error::from(io::ErrorKind::TimedOut), HandshakeFailure::Other),
+        ];
+        for (err, expected) in cases {
+            assert_eq!(HandshakeFailure::classify(&err), expected, "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_log_throttle_reports_suppressed_count() {
+        let throttle = LogThrottle::new(Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(throttle.admit(HandshakeFailure::Malformed, start), Some(0));
+        assert_eq!(throttle.admit(HandshakeFailure::Malformed, start + Duration::from_secs(1)), None);
+        assert_eq!(throttle.admit(HandshakeFailure::Malformed, start + Duration::from_secs(2)), None);
+        // Each reason is throttled on its own
+        assert_eq!(throttle.admit(HandshakeFailure::UnknownSni, start + Duration::from_secs(2)), Some(0));
+        assert_eq!(throttle.admit(HandshakeFailure::Malformed, start + Duration::from_secs(11)), Some(2));
+        assert_eq!(throttle.admit(HandshakeFailure::Malformed, start + Duration::from_secs(12)), None);
+    }
+}
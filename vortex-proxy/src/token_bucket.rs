@@ -0,0 +1,141 @@
+//! Token buckets keyed by backend, client address and the like.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Keys tracked before buckets that have refilled are forgotten.
+const PRUNE_AT: usize = 16_384;
+
+/// A token bucket per key: each key may take `burst` tokens at once, and
+/// they refill at `rate` per second.
+///
+/// A key's bucket is full when first seen. Full buckets carry no state, so
+/// they are dropped once many keys are tracked, which keeps the map small
+/// when keys come and go (client addresses, re-resolved backends).
+#[derive(Debug)]
+pub struct TokenBuckets<K> {
+    rate: f64,
+    burst: f64,
+    state: Mutex<State<K>>,
+}
+
+#[derive(Debug)]
+struct State<K> {
+    buckets: HashMap<K, Bucket>,
+    prune_at: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl<K: Hash + Eq> TokenBuckets<K> {
+    /// Buckets of `burst` tokens (at least 1) refilling at `rate` per second.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate: rate.max(0.0),
+            burst: f64::from(burst.max(1)),
+            state: Mutex::new(State { buckets: HashMap::new(), prune_at: PRUNE_AT }),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, or says how long until one refills.
+    pub fn try_take(&self, key: K, now: Instant) -> Result<(), Duration> {
+        self.try_take_n(key, 1.0, now)
+    }
+
+    /// Takes `cost` tokens from `key`'s bucket, or says how long until enough
+    /// refill. A cost above the burst is taken from a full bucket, leaving it
+    /// in debt, so no cost is refused forever.
+    pub fn try_take_n(&self, key: K, cost: f64, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().expect("bucket lock poisoned");
+        let bucket = self.refilled(&mut state, key, now);
+        let needed = cost.min(self.burst);
+        if bucket.tokens >= needed {
+            bucket.tokens -= cost;
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(Duration::from_secs_f64((needed - bucket.tokens) / self.rate))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    /// Adjusts `key`'s bucket by `cost` tokens without checking them: a
+    /// positive cost may leave it in debt, a negative one refunds up to the burst.
+    pub fn charge(&self, key: K, cost: f64, now: Instant) {
+        let mut state = self.state.lock().expect("bucket lock poisoned");
+        let burst = self.burst;
+        let bucket = self.refilled(&mut state, key, now);
+        bucket.tokens = (bucket.tokens - cost).min(burst);
+    }
+
+    /// `key`'s bucket, topped up for the time since it was last used.
+    fn refilled<'a>(&self, state: &'a mut State<K>, key: K, now: Instant) -> &'a mut Bucket {
+        if state.buckets.len() >= state.prune_at {
+            let (rate, burst) = (self.rate, self.burst);
+            state.buckets.retain(|_, b| b.tokens + now.saturating_duration_since(b.refilled).as_secs_f64() * rate < burst);
+            // Keys that are all busy are kept, so don't scan them again right away
+            state.prune_at = (state.buckets.len() * 2).max(PRUNE_AT);
+        }
+        let bucket = state.buckets.entry(key).or_insert(Bucket { tokens: self.burst, refilled: now });
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled = now;
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_a_burst_then_the_rate() {
+        let buckets = TokenBuckets::new(10.0, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(buckets.try_take(1, start).is_ok());
+        }
+        let wait = buckets.try_take(1, start).unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100), "{:?}", wait);
+        // Other keys have their own bucket
+        assert!(buckets.try_take(2, start).is_ok());
+
+        assert!(buckets.try_take(1, start + Duration::from_millis(100)).is_ok());
+        assert!(buckets.try_take(1, start + Duration::from_millis(150)).is_err());
+    }
+
+    #[test]
+    fn test_costly_takes_can_borrow_against_the_refill() {
+        let buckets = TokenBuckets::new(1.0, 10);
+        let start = Instant::now();
+        buckets.try_take_n("a", 8.0, start).unwrap();
+        assert_eq!(buckets.try_take_n("a", 5.0, start), Err(Duration::from_secs(3)));
+        // Over the burst, a full bucket still pays, going into debt
+        buckets.try_take_n("b", 25.0, start).unwrap();
+        assert!(buckets.try_take("b", start + Duration::from_secs(15)).is_err());
+        assert!(buckets.try_take("b", start + Duration::from_secs(16)).is_ok());
+
+        // A refund tops up, but never past the burst
+        buckets.charge("a", -100.0, start);
+        buckets.try_take_n("a", 10.0, start).unwrap();
+        assert!(buckets.try_take("a", start).is_err());
+    }
+
+    #[test]
+    fn test_refilled_buckets_are_pruned() {
+        let buckets = TokenBuckets::new(1.0, 1);
+        let start = Instant::now();
+        for key in 0..PRUNE_AT {
+            buckets.try_take(key, start).unwrap();
+        }
+        // All of them refilled a second later, so they go before the next key is added
+        buckets.try_take(PRUNE_AT, start + Duration::from_secs(1)).unwrap();
+        assert_eq!(buckets.state.lock().unwrap().buckets.len(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+//! Live request rates and top talkers over a sliding window.
+//!
+//! The window is a ring of one-second buckets. Each bucket keeps a bounded
+//! Space-Saving sketch per `TalkerDimension`, so memory stays fixed no matter
+//! how many distinct clients hit the proxy, while the heavy hitters an operator
+//! cares about during an incident are counted (over-)accurately.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use vortex_core::stats::{TalkerDimension, TopTalker, TrafficStatsSource};
+
+/// Default length of the sliding window.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Default number of distinct keys tracked per dimension per second.
+pub const DEFAULT_CAPACITY: usize = 128;
+
+/// A bounded heavy-hitter counter (Metwally et al., "Space-Saving").
+///
+/// When full, a new key replaces the current minimum and inherits its count,
+/// so counts may over-estimate but a true heavy hitter is never dropped.
+#[derive(Debug, Default)]
+struct SpaceSaving {
+    counts: HashMap<String, u64>,
+}
+
+impl SpaceSaving {
+    fn record(&mut self, key: &str, capacity: usize) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+
+        let mut count = 1;
+        if self.counts.len() >= capacity {
+            let evicted = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count));
+            if let Some((evicted, min)) = evicted {
+                self.counts.remove(&evicted);
+                count += min;
+            }
+        }
+        self.counts.insert(key.to_string(), count);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    second: u64,
+    requests: u64,
+    talkers: [SpaceSaving; TalkerDimension::ALL.len()],
+}
+
+/// Aggregates live traffic for the admin API's top-talkers view.
+#[derive(Debug)]
+pub struct TrafficTracker {
+    epoch: Instant,
+    capacity: usize,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl Default for TrafficTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW, DEFAULT_CAPACITY)
+    }
+}
+
+impl TrafficTracker {
+    /// Create a tracker over `window` (rounded up to whole seconds), keeping up
+    /// to `capacity` keys per dimension per second.
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        let secs = window.as_secs() + u64::from(window.subsec_nanos() > 0);
+        let buckets = (0..secs.max(1)).map(|_| Bucket::default()).collect();
+        Self {
+            epoch: Instant::now(),
+            capacity: capacity.max(1),
+            buckets: Mutex::new(buckets),
+        }
+    }
+
+    /// Count one request.
+    pub fn record(&self, client_ip: &str, route: &str, user_agent: &str) {
+        self.record_at(self.now(), client_ip, route, user_agent);
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_secs()
+    }
+
+    fn record_at(&self, second: u64, client_ip: &str, route: &str, user_agent: &str) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let len = buckets.len() as u64;
+        let bucket = &mut buckets[(second % len) as usize];
+        if bucket.second != second || bucket.requests == 0 {
+            *bucket = Bucket { second, ..Bucket::default() };
+        }
+
+        bucket.requests += 1;
+        for (dimension, key) in TalkerDimension::ALL.into_iter().zip([client_ip, route, user_agent]) {
+            bucket.talkers[dimension as usize].record(key, self.capacity);
+        }
+    }
+
+    fn requests_at(&self, now: u64) -> u64 {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let len = buckets.len() as u64;
+        buckets
+            .iter()
+            .filter(|b| b.requests > 0 && now.saturating_sub(b.second) < len)
+            .map(|b| b.requests)
+            .sum()
+    }
+
+    fn top_talkers_at(&self, now: u64, dimension: TalkerDimension, limit: usize) -> Vec<TopTalker> {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let len = buckets.len() as u64;
+
+        let mut merged: HashMap<&str, u64> = HashMap::new();
+        for bucket in buckets.iter().filter(|b| b.requests > 0 && now.saturating_sub(b.second) < len) {
+            for (key, count) in &bucket.talkers[dimension as usize].counts {
+                *merged.entry(key.as_str()).or_default() += count;
+            }
+        }
+
+        let mut talkers: Vec<TopTalker> = merged
+            .into_iter()
+            .map(|(key, requests)| TopTalker { key: key.to_string(), requests })
+            .collect();
+        talkers.sort_by(|a, b| b.requests.cmp(&a.requests).then_with(|| a.key.cmp(&b.key)));
+        talkers.truncate(limit);
+        talkers
+    }
+}
+
+impl TrafficStatsSource for TrafficTracker {
+    fn window(&self) -> Duration {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        Duration::from_secs(buckets.len() as u64)
+    }
+
+    fn requests_per_second(&self) -> f64 {
+        self.requests_at(self.now()) as f64 / self.window().as_secs_f64()
+    }
+
+    fn top_talkers(&self, dimension: TalkerDimension, limit: usize) -> Vec<TopTalker> {
+        self.top_talkers_at(self.now(), dimension, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heaviest_client_ranks_first() {
+        let tracker = TrafficTracker::new(Duration::from_secs(10), 8);
+        for _ in 0..50 {
+            tracker.record_at(0, "10.0.0.1", "/api", "curl/8");
+        }
+        for i in 0..20 {
+            tracker.record_at(1, &format!("10.0.1.{}", i), "/health", "kube-probe");
+        }
+
+        let top = tracker.top_talkers_at(1, TalkerDimension::ClientIp, 3);
+        assert_eq!(top[0], TopTalker { key: "10.0.0.1".into(), requests: 50 });
+        assert_eq!(top.len(), 3);
+
+        let routes = tracker.top_talkers_at(1, TalkerDimension::Route, 5);
+        assert_eq!(routes.iter().map(|t| t.key.as_str()).collect::<Vec<_>>(), ["/api", "/health"]);
+    }
+
+    #[test]
+    fn test_old_buckets_fall_out_of_the_window() {
+        let tracker = TrafficTracker::new(Duration::from_secs(5), 8);
+        tracker.record_at(0, "10.0.0.1", "/", "-");
+        tracker.record_at(4, "10.0.0.2", "/", "-");
+        assert_eq!(tracker.requests_at(4), 2);
+
+        // Second 5 is past the window for second 0, and reuses its ring slot
+        tracker.record_at(5, "10.0.0.3", "/", "-");
+        assert_eq!(tracker.requests_at(5), 2);
+        let top = tracker.top_talkers_at(5, TalkerDimension::ClientIp, 10);
+        assert!(top.iter().all(|t| t.key != "10.0.0.1"));
+    }
+
+    #[test]
+    fn test_sketch_memory_is_bounded() {
+        let mut sketch = SpaceSaving::default();
+        for i in 0..1000 {
+            sketch.record(&i.to_string(), 16);
+        }
+        assert_eq!(sketch.counts.len(), 16);
+    }
+}
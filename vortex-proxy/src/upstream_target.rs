@@ -0,0 +1,111 @@
+//! Pointing requests at their backend without per-request formatting.
+//!
+//! Every proxied request gets an absolute-form URI and a `Host` header naming
+//! the backend: its configured host, or else its address. Both only depend
+//! on the backend, so they are built once per backend and shared by every
+//! request after that, instead of being formatted into a string and parsed
+//! back each time.
+
+use dashmap::DashMap;
+use hyper::header::{HeaderValue, HOST};
+use hyper::http::uri::{Authority, PathAndQuery, Scheme, Uri};
+use hyper::Request;
+use std::sync::Arc;
+use vortex_core::domain::backend::Backend;
+
+use crate::connection_pool::pool::PoolKey;
+
+/// A backend's interned authority and `Host` value.
+#[derive(Debug, Clone)]
+struct Target {
+    /// The backend's `host` when this was built
+    host: Option<String>,
+    authority: Authority,
+    header: HeaderValue,
+}
+
+/// The interned targets of each backend; clones share them.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTargets {
+    by_backend: Arc<DashMap<PoolKey, Target>>,
+}
+
+impl UpstreamTargets {
+    /// An empty set, filled in as backends are first seen.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn target(&self, backend: &Backend) -> Target {
+        let key = PoolKey::from(backend);
+        if let Some(target) = self.by_backend.get(&key).filter(|t| t.host == backend.host) {
+            return target.clone();
+        }
+        let authority: Authority = match backend.authority().parse() {
+            Ok(authority) => authority,
+            // Config validation keeps this from happening
+            Err(_) => backend.addr.to_string().parse().expect("socket addresses are valid authorities"),
+        };
+        let header = HeaderValue::from_str(authority.as_str()).expect("authorities are valid header values");
+        let target = Target { host: backend.host.clone(), authority, header };
+        self.by_backend.insert(key, target.clone());
+        target
+    }
+
+    /// Points `req` at `backend`: `http://` plus its authority plus the
+    /// request's own path and query, with a matching `Host` header. The
+    /// upstream reads the host from the URI, so both must agree.
+    pub fn point<B>(&self, req: &mut Request<B>, backend: &Backend) {
+        let Target { authority, header, .. } = self.target(backend);
+        let path_and_query = req.uri().path_and_query().cloned().unwrap_or_else(|| PathAndQuery::from_static("/"));
+        let mut parts = hyper::http::uri::Parts::default();
+        parts.scheme = Some(Scheme::HTTP);
+        parts.authority = Some(authority);
+        parts.path_and_query = Some(path_and_query);
+        *req.uri_mut() = Uri::from_parts(parts).expect("scheme, authority and path make a valid URI");
+        req.headers_mut().insert(HOST, header);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vortex_core::domain::backend::BackendId;
+
+    #[test]
+    fn test_requests_are_pointed_at_the_backend() {
+        let targets = UpstreamTargets::new();
+        let backend = Backend::new(BackendId(1), "10.0.0.7:8080".parse().unwrap());
+        let mut req = Request::builder().uri("https://edge.example.com/api/orders?id=4").header(HOST, "edge.example.com").body(()).unwrap();
+        targets.point(&mut req, &backend);
+        assert_eq!(req.uri(), "http://10.0.0.7:8080/api/orders?id=4");
+        assert_eq!(req.headers()[HOST], "10.0.0.7:8080");
+
+        let backend = Backend::new(BackendId(2), "[::1]:9000".parse().unwrap());
+        let mut req = Request::builder().uri("http://edge.example.com").body(()).unwrap();
+        targets.point(&mut req, &backend);
+        assert_eq!(req.uri(), "http://[::1]:9000/");
+        assert_eq!(req.headers()[HOST], "[::1]:9000");
+        assert_eq!(targets.by_backend.len(), 2);
+    }
+
+    #[test]
+    fn test_backends_sharing_an_address_keep_their_own_host() {
+        let targets = UpstreamTargets::new();
+        let addr = "10.0.0.7:443".parse().unwrap();
+        let orders = Backend::new(BackendId(1), addr).with_host("orders.internal");
+        let users = Backend::new(BackendId(2), addr).with_host("users.internal:8443");
+        for (backend, host) in [(&orders, "orders.internal"), (&users, "users.internal:8443")] {
+            let mut req = Request::builder().uri("/v1").body(()).unwrap();
+            targets.point(&mut req, backend);
+            assert_eq!(req.uri(), &*format!("http://{}/v1", host));
+            assert_eq!(req.headers()[HOST], host);
+        }
+
+        // A reloaded backend with a new host isn't served the old one
+        let renamed = Backend::new(BackendId(1), addr).with_host("orders-v2.internal");
+        let mut req = Request::builder().uri("/v1").body(()).unwrap();
+        targets.point(&mut req, &renamed);
+        assert_eq!(req.headers()[HOST], "orders-v2.internal");
+    }
+}
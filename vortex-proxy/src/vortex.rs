@@ -0,0 +1,986 @@
+//! Builder API for running Vortex embedded in another process.
+
+use ipnet::IpNet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tower::{Layer, Service};
+use vortex_admin::server::AdminServerImpl;
+use vortex_admin::transport::AdminEndpoint;
+use vortex_core::domain::backend::SharedBackend;
+use vortex_core::domain::routing::{RoutingTable, SharedRoutingTable};
+use vortex_core::pipeline::{BoxService, PipelineBuilder, Stage};
+use vortex_core::route::{RouteSpec, RouteTable, SharedRouteTable};
+use vortex_filters::fault_injection::FaultInjector;
+use vortex_filters::breaker::{BreakerPolicy, FilterBreakers};
+use vortex_filters::chain::RouteFilterChains;
+use vortex_filters::wasm_engine::WasmEngine;
+
+use crate::access_log::AccessLogFormat;
+use crate::alerting::{self, AlertConfig, Alerter};
+use crate::body_codec::BodyCodecs;
+use crate::cache::{ResponseCache, ResponseCaching};
+use crate::client_concurrency::ClientConcurrency;
+use crate::client_quota::ClientQuota;
+use crate::compression::RequestCompression;
+use crate::config_reload::{self, ConfigReload};
+use crate::connect_pacing::ConnectPacing;
+use crate::connection_pool::{drain, idle};
+use crate::connection_pool::pool::ConnectionPool;
+use crate::deadline::DeadlineConfig;
+use crate::diagnostics::{self, Diagnostics, DumpTarget};
+use crate::dns::{self, HostnamePool, SrvPool};
+use crate::duplicate_headers::DuplicateHeaders;
+use crate::error::ProxyError;
+use crate::experiments::RouteExperiments;
+use crate::fd_limit::{FdGuardrail, FdMonitor};
+use crate::force_backend::ForceBackend;
+use crate::header_limits::HeaderLimits;
+use crate::health_check::prober::{HealthCheckConfig, HealthCheckers, OnDemandProber, DEFAULT_POOL};
+use crate::hedging::HedgePolicy;
+use crate::html_rewrite::UrlRewrites;
+use crate::interim::InterimForwarding;
+use crate::lifecycle::{CloseSignal, Lifecycle, Phase, DEFAULT_SHUTDOWN_GRACE};
+use crate::log_sink::{LogShipper, LogShipping};
+use crate::normalization::RequestNormalization;
+use crate::otel::{OtlpExport, Tracer};
+use crate::passive_health::PassiveHealth;
+use crate::persistence::{InstanceState, StatePersistence, StateStore};
+use crate::pipeline::{ProxyRequest, ProxyResponse, StandardStages, UpstreamService};
+use crate::redaction::BodyRedaction;
+use crate::request_rules::RouteRequestRules;
+use crate::response_limit::ResponseLimits;
+use crate::retry::RetryPolicy;
+use crate::sampling::SamplingPolicy;
+use crate::token_bucket::TokenBuckets;
+use crate::traffic::TrafficTracker;
+use crate::server_timing::ServerTiming;
+use crate::server::{ListenerConfig, ListenerPolicy, ProtocolSniffing, TunnelOffload};
+use crate::socket::SocketOptions;
+use crate::upstream_auth::UpstreamCredentials;
+use crate::{health_check, metrics, server, tls};
+
+/// How often removed backends are checked for the end of their drain period.
+const DRAIN_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often served certificates are checked for approaching expiry.
+const CERT_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+type Pipeline = PipelineBuilder<ProxyRequest, ProxyResponse, ProxyError>;
+type LayerFn = Box<dyn FnOnce(Pipeline) -> Pipeline + Send>;
+
+/// Entry point for embedding the proxy.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), vortex_proxy::ProxyError> {
+/// use std::sync::Arc;
+/// use vortex_core::domain::backend::{Backend, BackendId};
+///
+/// let handle = vortex_proxy::Vortex::builder()
+///     .listener("127.0.0.1:8080".parse().unwrap())
+///     .backends(vec![Arc::new(Backend::new(BackendId(1), "127.0.0.1:9090".parse().unwrap()))])
+///     .start()
+///     .await?;
+/// handle.wait().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Vortex;
+
+impl Vortex {
+    /// Start configuring a new proxy instance.
+    pub fn builder() -> VortexBuilder {
+        VortexBuilder::default()
+    }
+}
+
+/// A listener to bind, as declared on the builder.
+struct ListenerSpec {
+    addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    config: ListenerConfig,
+}
+
+impl ListenerSpec {
+    fn new(addr: SocketAddr, tls: Option<TlsAcceptor>) -> Self {
+        Self {
+            addr,
+            tls,
+            config: ListenerConfig::default(),
+        }
+    }
+}
+
+/// Configures and starts a proxy instance.
+#[derive(Default)]
+pub struct VortexBuilder {
+    listeners: Vec<ListenerSpec>,
+    routing_table: Option<SharedRoutingTable>,
+    route_table: Option<SharedRouteTable>,
+    clusters: Vec<(String, SharedRoutingTable, Option<HealthCheckConfig>)>,
+    pool: Option<ConnectionPool>,
+    upstream_idle_timeout: Option<Duration>,
+    upstream_socket_options: SocketOptions,
+    connect_pacing: Option<ConnectPacing>,
+    header_limits: Option<HeaderLimits>,
+    wasm_engine: Option<Arc<WasmEngine>>,
+    fault_injector: Option<Arc<FaultInjector>>,
+    health_check: Option<HealthCheckConfig>,
+    admin_endpoint: Option<AdminEndpoint>,
+    metrics_addr: Option<SocketAddr>,
+    metric_dimensions: Vec<String>,
+    alerts: Option<AlertConfig>,
+    cert_expiry_warning: Option<Duration>,
+    diagnostic_dump: Option<DumpTarget>,
+    trace_sampling: Option<SamplingPolicy>,
+    otlp_export: Option<OtlpExport>,
+    deadlines: DeadlineConfig,
+    retries: Option<RetryPolicy>,
+    hedging: Option<HedgePolicy>,
+    passive_health: Option<PassiveHealth>,
+    client_concurrency: Option<ClientConcurrency>,
+    client_quota: Option<ClientQuota>,
+    normalization: Option<RequestNormalization>,
+    force_backend: Option<ForceBackend>,
+    request_compression: Option<RequestCompression>,
+    body_redaction: Option<BodyRedaction>,
+    body_codecs: Option<BodyCodecs>,
+    html_rewrites: Option<UrlRewrites>,
+    response_limits: Option<ResponseLimits>,
+    request_rules: Option<RouteRequestRules>,
+    experiments: Option<RouteExperiments>,
+    filter_chains: Option<RouteFilterChains>,
+    filter_breakers: Option<BreakerPolicy>,
+    upstream_credentials: Option<UpstreamCredentials>,
+    response_caching: Option<ResponseCaching>,
+    interim_responses: Option<InterimForwarding>,
+    log_shipping: Option<LogShipping>,
+    access_log_format: Option<AccessLogFormat>,
+    server_timing: Option<ServerTiming>,
+    state_persistence: Option<StatePersistence>,
+    fd_guardrail: Option<FdGuardrail>,
+    hostname_pool: Option<HostnamePool>,
+    srv_pool: Option<SrvPool>,
+    config_reload: Option<ConfigReload>,
+    duplicate_headers: Option<DuplicateHeaders>,
+    h2c: bool,
+    tcp_fallback: bool,
+    tcp_offload: Option<Arc<dyn TunnelOffload>>,
+    layers: Vec<LayerFn>,
+}
+
+impl VortexBuilder {
+    /// Serve plaintext HTTP on `addr`. Use port 0 to let the OS pick one.
+    pub fn listener(mut self, addr: SocketAddr) -> Self {
+        self.listeners.push(ListenerSpec::new(addr, None));
+        self
+    }
+
+    /// Serve TLS-terminated HTTP on `addr`.
+    pub fn tls_listener(mut self, addr: SocketAddr, acceptor: TlsAcceptor) -> Self {
+        self.listeners.push(ListenerSpec::new(addr, Some(acceptor)));
+        self
+    }
+
+    /// Apply socket options to connections accepted on the listener
+    /// previously added for `addr`. Setting `transparent` makes it a TPROXY
+    /// listener, bound with `IP_TRANSPARENT`.
+    pub fn listener_socket_options(mut self, addr: SocketAddr, options: SocketOptions) -> Self {
+        for listener in self.listeners.iter_mut().filter(|l| l.addr == addr) {
+            listener.config.socket = options.clone();
+        }
+        self
+    }
+
+    /// Only accept connections from these source networks on the listener
+    /// previously added for `addr`; everyone else is disconnected at once.
+    pub fn listener_allowed_sources(mut self, addr: SocketAddr, networks: Vec<IpNet>) -> Self {
+        for listener in self.listeners.iter_mut().filter(|l| l.addr == addr) {
+            listener.config.access.allowed_sources = networks.clone();
+        }
+        self
+    }
+
+    /// Let each source IP open at most `burst` connections at once, and
+    /// `per_second` more after that, on the listener previously added for
+    /// `addr`. Connections past the limit are closed before the TLS
+    /// handshake, the most expensive part of a connection flood.
+    pub fn listener_connection_rate(mut self, addr: SocketAddr, per_second: f64, burst: u32) -> Self {
+        let buckets = Arc::new(TokenBuckets::new(per_second, burst));
+        for listener in self.listeners.iter_mut().filter(|l| l.addr == addr) {
+            listener.config.access.connection_rate = Some(buckets.clone());
+        }
+        self
+    }
+
+    /// Enforce header limits on the listener previously added for `addr`,
+    /// overriding `header_limits`.
+    pub fn listener_header_limits(mut self, addr: SocketAddr, limits: HeaderLimits) -> Self {
+        for listener in self.listeners.iter_mut().filter(|l| l.addr == addr) {
+            listener.config.header_limits = Some(limits);
+        }
+        self
+    }
+
+    /// Apply `policy` to requests on the listener previously added for
+    /// `addr`: its filter chain, access log format and response limit take
+    /// over from the proxy-wide ones for routes without their own.
+    pub fn listener_policy(mut self, addr: SocketAddr, policy: ListenerPolicy) -> Self {
+        let policy = Arc::new(policy);
+        for listener in self.listeners.iter_mut().filter(|l| l.addr == addr) {
+            listener.config.policy = Some(policy.clone());
+        }
+        self
+    }
+
+    /// Enforce header limits on every listener: oversized requests get a 431,
+    /// oversized upstream responses a 502. Disabled unless set.
+    pub fn header_limits(mut self, limits: HeaderLimits) -> Self {
+        self.header_limits = Some(limits);
+        self
+    }
+
+    /// Apply socket options to every upstream connection the pool opens.
+    pub fn upstream_socket_options(mut self, options: SocketOptions) -> Self {
+        self.upstream_socket_options = options;
+        self
+    }
+
+    /// Pace new upstream connections per backend, so a burst of requests
+    /// missing the pool waits briefly for a connection instead of each
+    /// dialing at once. Disabled unless set.
+    pub fn connect_pacing(mut self, pacing: ConnectPacing) -> Self {
+        self.connect_pacing = Some(pacing);
+        self
+    }
+
+    /// Route to a fixed set of backends.
+    pub fn backends(self, backends: Vec<SharedBackend>) -> Self {
+        self.routing_table(Arc::new(RoutingTable::new(backends)))
+    }
+
+    /// Route using an existing (possibly shared) routing table.
+    pub fn routing_table(mut self, routing_table: SharedRoutingTable) -> Self {
+        self.routing_table = Some(routing_table);
+        self
+    }
+
+    /// Serve routes naming cluster `name` from `routing_table` rather than
+    /// the default pool, probing its backends as `health_check` says, if
+    /// set. Routes naming a cluster that wasn't added, or none, are served
+    /// from the default pool.
+    pub fn cluster(
+        mut self,
+        name: impl Into<String>,
+        routing_table: SharedRoutingTable,
+        health_check: Option<HealthCheckConfig>,
+    ) -> Self {
+        self.clusters.push((name.into(), routing_table, health_check));
+        self
+    }
+
+    /// Match requests against `routes`; requests matching none get a 404.
+    /// Without routes every request goes to the pool.
+    pub fn routes(self, routes: Vec<RouteSpec>) -> Self {
+        self.route_table(Arc::new(RouteTable::new(routes)))
+    }
+
+    /// Match requests using an existing (possibly shared) route table.
+    pub fn route_table(mut self, route_table: SharedRouteTable) -> Self {
+        self.route_table = Some(route_table);
+        self
+    }
+
+    /// Use an existing connection pool instead of a fresh one.
+    pub fn pool(mut self, pool: ConnectionPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Use an existing Wasm engine instead of a fresh one.
+    pub fn wasm_engine(mut self, engine: Arc<WasmEngine>) -> Self {
+        self.wasm_engine = Some(engine);
+        self
+    }
+
+    /// Close pooled upstream connections that sit idle this long, so requests
+    /// after a quiet period don't land on one a NAT has silently dropped.
+    /// Disabled unless set.
+    pub fn upstream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.upstream_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Use an existing fault injector, e.g. to drive chaos rules from a test.
+    pub fn fault_injector(mut self, injector: Arc<FaultInjector>) -> Self {
+        self.fault_injector = Some(injector);
+        self
+    }
+
+    /// Actively probe backends at this interval, with the other health
+    /// check settings left as they are. Disabled unless set.
+    pub fn health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check.get_or_insert_with(HealthCheckConfig::default).interval = interval;
+        self
+    }
+
+    /// Actively probe the pool's backends as `config` says. Disabled unless set.
+    pub fn health_check(mut self, config: HealthCheckConfig) -> Self {
+        self.health_check = Some(config);
+        self
+    }
+
+    /// Serve the admin gRPC API on the given endpoint. Disabled unless set.
+    pub fn admin_endpoint(mut self, endpoint: AdminEndpoint) -> Self {
+        self.admin_endpoint = Some(endpoint);
+        self
+    }
+
+    /// Serve Prometheus metrics on `addr`. Disabled unless set.
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Export these label keys (e.g. `team`, `service`, `environment`) as
+    /// dimensions on request metrics, filled from each pool's labels.
+    pub fn metric_dimensions<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.metric_dimensions = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Send anomaly alerts to webhooks. Disabled unless set.
+    pub fn alerts(mut self, config: AlertConfig) -> Self {
+        self.alerts = Some(config);
+        self
+    }
+
+    /// Warn about served certificates this long before they expire.
+    /// Defaults to `tls::DEFAULT_CERT_EXPIRY_WARNING`.
+    pub fn cert_expiry_warning(mut self, warn_within: Duration) -> Self {
+        self.cert_expiry_warning = Some(warn_within);
+        self
+    }
+
+    /// On Unix, write a diagnostic dump to `target` whenever the process
+    /// receives `SIGQUIT`. Disabled unless set; dumps are always available
+    /// through the admin API.
+    pub fn diagnostic_dump(mut self, target: DumpTarget) -> Self {
+        self.diagnostic_dump = Some(target);
+        self
+    }
+
+    /// Decide per request whether its trace is recorded. Disabled unless set.
+    pub fn trace_sampling(mut self, policy: SamplingPolicy) -> Self {
+        self.trace_sampling = Some(policy);
+        self
+    }
+
+    /// Export connection and request spans to an OpenTelemetry collector over
+    /// OTLP/HTTP. Without `trace_sampling`, every request not marked unsampled
+    /// by its `traceparent` is exported. Disabled unless set.
+    pub fn otlp_export(mut self, config: OtlpExport) -> Self {
+        self.otlp_export = Some(config);
+        self
+    }
+
+    /// Configure how client deadlines are honored and forwarded.
+    /// By default `grpc-timeout` and `X-Request-Deadline` are honored without a proxy cap.
+    pub fn deadlines(mut self, config: DeadlineConfig) -> Self {
+        self.deadlines = config;
+        self
+    }
+
+    /// Retry failed upstream attempts without double-submitting. Disabled unless set.
+    pub fn retries(mut self, policy: RetryPolicy) -> Self {
+        self.retries = Some(policy);
+        self
+    }
+
+    /// Send a second copy of slow safe requests to another backend. Disabled unless set.
+    pub fn hedging(mut self, policy: HedgePolicy) -> Self {
+        self.hedging = Some(policy);
+        self
+    }
+
+    /// Eject backends while their answers to real requests keep failing, as
+    /// weighed per route by `config`. Disabled unless set.
+    pub fn passive_health(mut self, config: PassiveHealth) -> Self {
+        self.passive_health = Some(config);
+        self
+    }
+
+    /// Cap the requests each client may have in flight, answering 429 over
+    /// the cap. Disabled unless set.
+    pub fn client_concurrency(mut self, config: ClientConcurrency) -> Self {
+        self.client_concurrency = Some(config);
+        self
+    }
+
+    /// Give each client a request quota that routes spend at their own cost,
+    /// fixed or reported by the upstream, answering 429 once it runs out.
+    /// Disabled unless set.
+    pub fn client_quota(mut self, config: ClientQuota) -> Self {
+        self.client_quota = Some(config);
+        self
+    }
+
+    /// Canonicalize request paths and drop hop-by-hop headers before routing,
+    /// counting and sampling every request that needed it. Disabled unless set.
+    pub fn request_normalization(mut self, config: RequestNormalization) -> Self {
+        self.normalization = Some(config);
+        self
+    }
+
+    /// Let trusted clients pin a request to a backend id with a debug header,
+    /// bypassing load balancing. Disabled unless set.
+    pub fn force_backend(mut self, config: ForceBackend) -> Self {
+        self.force_backend = Some(config);
+        self
+    }
+
+    /// Redact sensitive fields from JSON request and response bodies. Disabled unless set.
+    pub fn body_redaction(mut self, config: BodyRedaction) -> Self {
+        self.body_redaction = Some(config);
+        self
+    }
+
+    /// Convert request and response bodies between a route's client and
+    /// upstream formats, e.g. MessagePack clients in front of a JSON API.
+    /// Disabled unless set.
+    pub fn body_codecs(mut self, codecs: BodyCodecs) -> Self {
+        self.body_codecs = Some(codecs);
+        self
+    }
+
+    /// Rewrite the internal URLs a route's backends emit in HTML, CSS and
+    /// redirects to the external ones clients should see. Disabled unless set.
+    pub fn html_rewrites(mut self, rewrites: UrlRewrites) -> Self {
+        self.html_rewrites = Some(rewrites);
+        self
+    }
+
+    /// Cap upstream response bodies per route, aborting with a 502 or
+    /// truncating oversized ones. Disabled unless set.
+    pub fn response_limits(mut self, limits: ResponseLimits) -> Self {
+        self.response_limits = Some(limits);
+        self
+    }
+
+    /// Answer requests with overlong URIs (414) or methods outside their
+    /// route's allow-list (405) locally. Disabled unless set.
+    pub fn request_rules(mut self, rules: RouteRequestRules) -> Self {
+        self.request_rules = Some(rules);
+        self
+    }
+
+    /// Assign requests to A/B experiment variants per route, sending the
+    /// variant upstream and recording exposures (shipped with the access
+    /// log when it is enabled). Disabled unless set.
+    pub fn experiments(mut self, experiments: RouteExperiments) -> Self {
+        self.experiments = Some(experiments);
+        self
+    }
+
+    /// Run phase-ordered Wasm filter chains per route, in place of the
+    /// built-in demo filter. Disabled unless set.
+    pub fn filter_chains(mut self, chains: RouteFilterChains) -> Self {
+        self.filter_chains = Some(chains);
+        self
+    }
+
+    /// Bypass or fail closed on chain filters that keep trapping or running
+    /// slow, for a cool-down period. Disabled unless set.
+    pub fn filter_breakers(mut self, policy: BreakerPolicy) -> Self {
+        self.filter_breakers = Some(policy);
+        self
+    }
+
+    /// Authenticate to the backends with `credentials`, replacing any
+    /// `Authorization` clients send. Disabled unless set.
+    pub fn upstream_credentials(mut self, credentials: UpstreamCredentials) -> Self {
+        self.upstream_credentials = Some(credentials);
+        self
+    }
+
+    /// Cache shareable responses in memory, keyed per route. Disabled unless set.
+    pub fn response_caching(mut self, config: ResponseCaching) -> Self {
+        self.response_caching = Some(config);
+        self
+    }
+
+    /// Pass upstream 1xx interim responses such as 103 Early Hints through
+    /// on the routes `config` selects. Dropped unless set.
+    pub fn interim_responses(mut self, config: InterimForwarding) -> Self {
+        self.interim_responses = Some(config);
+        self
+    }
+
+    /// Ship access and error logs to syslog or remote collectors. Disabled unless set.
+    pub fn log_shipping(mut self, config: LogShipping) -> Self {
+        self.log_shipping = Some(config);
+        self
+    }
+
+    /// Render shipped access records in `format`, e.g. `AccessLogFormat::combined()`.
+    /// The `access_log::DEFAULT_FORMAT` unless set.
+    pub fn access_log_format(mut self, format: AccessLogFormat) -> Self {
+        self.access_log_format = Some(format);
+        self
+    }
+
+    /// Add `Server-Timing` headers breaking down the proxy's time on the
+    /// routes `config` selects. Disabled unless set.
+    pub fn server_timing(mut self, config: ServerTiming) -> Self {
+        self.server_timing = Some(config);
+        self
+    }
+
+    /// Save backend EWMA, health and filter breaker state on shutdown and
+    /// restore it on startup. Disabled unless set.
+    pub fn state_persistence(mut self, config: StatePersistence) -> Self {
+        self.state_persistence = Some(config);
+        self
+    }
+
+    /// Fill the routing table from hostnames, serving the first one with
+    /// healthy backends and failing over down the list. Replaces the
+    /// backends set with `backends`. Disabled unless set.
+    pub fn hostname_pool(mut self, pool: HostnamePool) -> Self {
+        self.hostname_pool = Some(pool);
+        self
+    }
+
+    /// Fill the routing table from SRV records, serving the lowest priority
+    /// with healthy backends and weighting backends by their records.
+    /// Replaces the backends set with `backends`. Disabled unless set.
+    pub fn srv_pool(mut self, pool: SrvPool) -> Self {
+        self.srv_pool = Some(pool);
+        self
+    }
+
+    /// Apply changes to the configuration file on `SIGHUP` or when it
+    /// changes, without dropping in-flight requests. Expects the routing and
+    /// route tables to have been built from `config.loaded`. Disabled unless set.
+    pub fn config_reload(mut self, config: ConfigReload) -> Self {
+        self.config_reload = Some(config);
+        self
+    }
+
+    /// Handle upstream responses repeating `Content-Length` or
+    /// `Content-Encoding`, e.g. repairing them for known-broken backends.
+    /// Such responses are rejected with a 502 unless set.
+    pub fn duplicate_response_headers(mut self, policy: DuplicateHeaders) -> Self {
+        self.duplicate_headers = Some(policy);
+        self
+    }
+
+    /// Refuse new connections with a 503 while file descriptor usage is
+    /// past a high-water mark of the process limit (Linux only). Disabled unless set.
+    pub fn fd_guardrail(mut self, config: FdGuardrail) -> Self {
+        self.fd_guardrail = Some(config);
+        self
+    }
+
+    /// Compress request bodies for backends that advertise support. Disabled unless set.
+    pub fn request_compression(mut self, config: RequestCompression) -> Self {
+        self.request_compression = Some(config);
+        self
+    }
+
+    /// Also serve HTTP/2 prior-knowledge (h2c) clients on plaintext listeners.
+    pub fn h2c(mut self, enabled: bool) -> Self {
+        self.h2c = enabled;
+        self
+    }
+
+    /// Tunnel non-HTTP clients on plaintext listeners to a backend as raw TCP.
+    pub fn tcp_fallback(mut self, enabled: bool) -> Self {
+        self.tcp_fallback = enabled;
+        self
+    }
+
+    /// Forward raw TCP tunnels through an in-kernel fast path (experimental),
+    /// falling back to userland copying whenever it declines.
+    pub fn tcp_offload(mut self, offload: Arc<dyn TunnelOffload>) -> Self {
+        self.tcp_offload = Some(offload);
+        self
+    }
+
+    /// Insert a custom tower layer into the request pipeline at `stage`.
+    ///
+    /// Custom layers run after the built-in layers of the same stage.
+    pub fn layer<L>(mut self, stage: Stage, layer: L) -> Self
+    where
+        L: Layer<BoxService<ProxyRequest, ProxyResponse, ProxyError>> + Send + 'static,
+        L::Service: Service<ProxyRequest, Response = ProxyResponse, Error = ProxyError> + Clone + Send + 'static,
+        <L::Service as Service<ProxyRequest>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |pipeline| pipeline.layer(stage, layer)));
+        self
+    }
+
+    /// Bind every listener and spawn the proxy's subsystems.
+    pub async fn start(self) -> Result<VortexHandle, ProxyError> {
+        let routing_table = self
+            .routing_table
+            .unwrap_or_else(|| Arc::new(RoutingTable::new(Vec::new())));
+        let pool = self.pool.unwrap_or_default();
+        let wasm_engine = self.wasm_engine.unwrap_or_default();
+        let fault_injector = self.fault_injector.unwrap_or_default();
+
+        let request_metrics = metrics::RequestMetrics::new(self.metric_dimensions)?;
+
+        // The live top-talkers view is only queryable through the admin API
+        let traffic = self.admin_endpoint.is_some().then(|| Arc::new(TrafficTracker::default()));
+        let response_cache = self.response_caching.map(|config| Arc::new(ResponseCache::new(config)));
+        let filter_breakers = self.filter_breakers.map(|policy| Arc::new(FilterBreakers::new(policy)));
+
+        // Restored before health checks start, so probes pick up from the saved state
+        let state_store = self
+            .state_persistence
+            .map(|config| StateStore::new(config, routing_table.clone(), filter_breakers.clone()));
+        if let Some(store) = &state_store {
+            match store.restore() {
+                Ok(0) => {}
+                Ok(restored) => tracing::info!(backends = restored, "restored saved state"),
+                Err(e) => tracing::warn!(error = %e, "failed to restore saved state"),
+            }
+        }
+
+        let mut lifecycle = Lifecycle::new();
+        if let Some(store) = state_store {
+            lifecycle.on_shutdown("state persistence", Phase::Persistence, move || {
+                if let Err(e) = store.save() {
+                    tracing::error!(error = %e, "failed to save state");
+                }
+            });
+        }
+        lifecycle.register(
+            "drain reaper",
+            Phase::Maintenance,
+            drain::spawn_drain_reaper(routing_table.clone(), pool.clone(), DRAIN_REAP_INTERVAL),
+        );
+
+        for (name, pool_table, _) in &self.clusters {
+            tracing::info!(cluster = %name, backends = pool_table.snapshot().len(), "serving cluster");
+            let task = drain::spawn_drain_reaper(pool_table.clone(), pool.clone(), DRAIN_REAP_INTERVAL);
+            lifecycle.register("drain reaper", Phase::Maintenance, task);
+        }
+
+        if let Some(max_idle) = self.upstream_idle_timeout {
+            lifecycle.register("idle reaper", Phase::Maintenance, idle::spawn_idle_reaper(pool.clone(), max_idle));
+        }
+
+        // Exporters are closed when shutdown reaches them, and end once they have sent what was queued
+        let tracer = self.otlp_export.map(|config| {
+            let close = CloseSignal::new();
+            let (tracer, task) = Tracer::spawn(config, close.clone());
+            lifecycle.register_draining("span export", Phase::Telemetry, task, close);
+            tracer
+        });
+
+        let access_log = self.log_shipping.map(|config| {
+            let close = CloseSignal::new();
+            let (shipper, sink_tasks) = LogShipper::spawn(config, close.clone());
+            for task in sink_tasks {
+                lifecycle.register_draining("log shipping", Phase::Telemetry, task, close.clone());
+            }
+            Arc::new(shipper)
+        });
+
+        let fd_monitor = self.fd_guardrail.and_then(|config| match FdMonitor::spawn(config) {
+            Ok((monitor, task)) => {
+                lifecycle.register("fd monitor", Phase::Maintenance, task);
+                Some(monitor)
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "file descriptor guardrail disabled");
+                None
+            }
+        });
+
+        if let Some(pool) = self.hostname_pool {
+            lifecycle.register("dns discovery", Phase::Discovery, dns::spawn_hostname_pool(pool, routing_table.clone()));
+        }
+        if let Some(pool) = self.srv_pool {
+            lifecycle.register("srv discovery", Phase::Discovery, dns::spawn_srv_pool(pool, routing_table.clone()));
+        }
+
+        if let Some(config) = self.config_reload {
+            let clusters = self.clusters.iter().map(|(name, pool_table, _)| (name.clone(), pool_table.clone()));
+            let route_table = self.route_table.clone();
+            match config_reload::spawn_config_reload(config, routing_table.clone(), clusters.collect(), route_table) {
+                Ok(task) => lifecycle.register("config reload", Phase::Discovery, task),
+                Err(e) => tracing::warn!(error = %e, "configuration reloading disabled"),
+            }
+        }
+
+        // One control for every pool's checks, so pausing them reaches the clusters too
+        let mut health_checkers = HealthCheckers::default();
+        let pools = std::iter::once((DEFAULT_POOL, &routing_table, &self.health_check))
+            .chain(self.clusters.iter().map(|(name, pool_table, config)| (name.as_str(), pool_table, config)));
+        for (name, pool_table, config) in pools {
+            if let Some(config) = config {
+                let (checker, task) =
+                    health_check::prober::spawn_health_checker_with(pool_table.clone(), config.clone());
+                lifecycle.register("health checker", Phase::Health, task);
+                health_checkers.push(name, checker);
+            }
+        }
+
+        if let Some(addr) = self.metrics_addr {
+            // Exporting pool statistics to Prometheus and serving the scrape endpoint
+            let collector = metrics::PoolCollector::new(Arc::new(pool.clone()))
+                .and_then(|collector| prometheus::register(Box::new(collector)));
+            if let Err(e) = collector {
+                tracing::warn!(error = %e, "failed to register pool metrics");
+            }
+            if let Err(e) = request_metrics.register() {
+                tracing::warn!(error = %e, "failed to register request metrics");
+            }
+            let collector = metrics::CertCollector::new()
+                .and_then(|collector| prometheus::register(Box::new(collector)));
+            if let Err(e) = collector {
+                tracing::warn!(error = %e, "failed to register certificate metrics");
+            }
+            let task = tokio::spawn(async move {
+                if let Err(e) = metrics::serve_metrics(addr).await {
+                    tracing::error!(error = %e, "metrics server failed");
+                }
+            });
+            lifecycle.register("metrics server", Phase::Endpoints, task);
+        }
+
+        let mut alerter = None;
+        if let Some(config) = self.alerts {
+            let shared = Arc::new(Alerter::new(config.webhooks.clone(), config.cooldown));
+            alerter = Some(shared.clone());
+            let task = alerting::spawn_alert_monitor(shared, config, routing_table.clone(), request_metrics.clone());
+            lifecycle.register("alerting", Phase::Health, task);
+        }
+
+        if self.listeners.iter().any(|l| l.tls.is_some()) {
+            let task = tls::spawn_cert_expiry_monitor(
+                self.cert_expiry_warning.unwrap_or(tls::DEFAULT_CERT_EXPIRY_WARNING),
+                CERT_EXPIRY_CHECK_INTERVAL,
+                alerter,
+            );
+            lifecycle.register("cert expiry monitor", Phase::Health, task);
+        }
+
+        let mut diagnostics = Diagnostics::new(
+            routing_table.clone(),
+            pool.clone(),
+            fault_injector.clone(),
+            request_metrics.clone(),
+        );
+        if let Some(tracker) = &traffic {
+            diagnostics = diagnostics.with_traffic(tracker.clone());
+        }
+        let diagnostics = Arc::new(diagnostics);
+
+        #[cfg(unix)]
+        if let Some(target) = self.diagnostic_dump {
+            match diagnostics::spawn_dump_on_sigquit(diagnostics.clone(), target) {
+                Ok(task) => lifecycle.register("diagnostic dump", Phase::Endpoints, task),
+                Err(e) => tracing::warn!(error = %e, "failed to install SIGQUIT dump handler"),
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = self.diagnostic_dump;
+
+        if let Some(endpoint) = self.admin_endpoint {
+            // Spawn the Control Plane API on the platform's local transport
+            let prober = self.clusters.iter().fold(
+                OnDemandProber::new(routing_table.clone(), self.health_check.clone()),
+                |prober, (_, pool_table, config)| prober.with_pool(pool_table.clone(), config.clone()),
+            );
+            let mut admin_service = AdminServerImpl::new(routing_table.clone())
+                .with_pool_stats(Arc::new(pool.clone()))
+                .with_fault_injector(fault_injector.clone())
+                .with_diagnostics(diagnostics)
+                .with_prober(Arc::new(prober))
+                .with_state(Arc::new(InstanceState::new(routing_table.clone(), filter_breakers.clone())));
+            if let Some(route_table) = &self.route_table {
+                admin_service = admin_service.with_route_table(route_table.clone());
+            }
+            if let Some(tracker) = &traffic {
+                admin_service = admin_service.with_traffic_stats(tracker.clone());
+            }
+            if let Some(cache) = &response_cache {
+                admin_service = admin_service.with_cache_stats(cache.clone());
+            }
+            if !health_checkers.is_empty() {
+                admin_service = admin_service.with_health_checks(Arc::new(health_checkers.clone()));
+            }
+            let task = tokio::spawn(async move {
+                if let Err(e) = vortex_admin::server::start_admin_server(&endpoint, admin_service).await {
+                    tracing::error!(error = %e, "admin gRPC server failed");
+                }
+            });
+            lifecycle.register("admin plane", Phase::Endpoints, task);
+        }
+
+        // Assemble the request pipeline: route -> filters -> upstream over the hot pool
+        let mut builder = StandardStages {
+            routing_table: routing_table.clone(),
+            routes: self.route_table.clone(),
+            clusters: self.clusters.into_iter().map(|(name, pool_table, _)| (name, pool_table)).collect(),
+            force_backend: self.force_backend,
+            wasm_engine,
+            fault_injector,
+            request_metrics,
+            traffic,
+            sampling: self.trace_sampling,
+            tracing: tracer.clone(),
+            deadlines: self.deadlines,
+            retry: self.retries,
+            hedging: self.hedging,
+            passive_health: self.passive_health,
+            client_concurrency: self.client_concurrency,
+            client_quota: self.client_quota,
+            normalization: self.normalization,
+            compression: self.request_compression,
+            redaction: self.body_redaction,
+            body_codecs: self.body_codecs,
+            html_rewrites: self.html_rewrites,
+            // A listener's limit needs the layer even without proxy-wide limits
+            response_limits: self.response_limits.or_else(|| {
+                let mut policies = self.listeners.iter().filter_map(|l| l.config.policy.as_ref());
+                policies.any(|p| p.response_limit.is_some()).then(ResponseLimits::default)
+            }),
+            request_rules: self.request_rules,
+            experiments: self.experiments,
+            filter_chains: self.filter_chains,
+            filter_breakers,
+            upstream_credentials: self.upstream_credentials,
+            caching: response_cache,
+            interim: self.interim_responses,
+            access_log,
+            access_log_format: self.access_log_format,
+            server_timing: self.server_timing,
+        }
+        .into_pipeline();
+        for layer in self.layers {
+            builder = layer(builder);
+        }
+        let mut upstream = UpstreamService::new(pool)
+            .with_socket_options(self.upstream_socket_options)
+            .with_duplicate_headers(self.duplicate_headers.unwrap_or_default());
+        if let Some(pacing) = self.connect_pacing {
+            upstream = upstream.with_connect_pacing(pacing);
+        }
+        let service = builder.build(upstream);
+        let sniffing = ProtocolSniffing {
+            h2c: self.h2c,
+            tcp_fallback: self.tcp_fallback.then(|| routing_table.clone()),
+            tcp_offload: self.tcp_offload,
+        };
+
+        let mut local_addrs = Vec::new();
+        let mut servers = Vec::new();
+        let plaintext_enabled = Arc::new(AtomicBool::new(true));
+        for ListenerSpec { addr, tls: tls_acceptor, mut config } in self.listeners {
+            let listener = config.socket.bind_listener(addr).await?;
+            // Only plaintext listeners follow the runtime switch
+            config.access.enabled = tls_acceptor.is_none().then(|| plaintext_enabled.clone());
+            config.access.fd_monitor = fd_monitor.clone();
+            config.header_limits = config.header_limits.or(self.header_limits);
+            config.tracer = tracer.clone();
+            let local_addr = listener.local_addr()?;
+            tracing::info!(addr = %local_addr, "listening");
+            local_addrs.push(local_addr);
+
+            let service = service.clone();
+            let sniffing = sniffing.clone();
+            servers.push(tokio::spawn(async move {
+                server::serve_listener(listener, tls_acceptor, service, sniffing, config).await
+            }));
+        }
+
+        Ok(VortexHandle {
+            local_addrs,
+            routing_table,
+            health_checkers,
+            plaintext_enabled,
+            servers,
+            lifecycle,
+        })
+    }
+}
+
+/// A running proxy instance.
+pub struct VortexHandle {
+    local_addrs: Vec<SocketAddr>,
+    routing_table: SharedRoutingTable,
+    health_checkers: HealthCheckers,
+    plaintext_enabled: Arc<AtomicBool>,
+    servers: Vec<JoinHandle<Result<(), ProxyError>>>,
+    lifecycle: Lifecycle,
+}
+
+impl VortexHandle {
+    /// The bound addresses of every listener, in the order they were added.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The routing table the instance is serving from, for live updates.
+    pub fn routing_table(&self) -> &SharedRoutingTable {
+        &self.routing_table
+    }
+
+    /// The periodic health checks of the default pool (named
+    /// `health_check::prober::DEFAULT_POOL`) and every cluster, to pause or
+    /// retime them as the admin API does. Empty if no pool has any.
+    pub fn health_checks(&self) -> &HealthCheckers {
+        &self.health_checkers
+    }
+
+    /// Stop (or resume) accepting connections on every plaintext listener,
+    /// e.g. to shut off an internal-only port that turned out to be exposed.
+    /// Already established connections are left alone.
+    pub fn set_plaintext_enabled(&self, enabled: bool) {
+        self.plaintext_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Wait until every listener stops, then stop the background subsystems in order, returning the first
+    /// listener error.
+    pub async fn wait(self) -> Result<(), ProxyError> {
+        let mut result = Ok(());
+        for server in self.servers {
+            match server.await {
+                Ok(Err(e)) if result.is_ok() => result = Err(e),
+                _ => {}
+            }
+        }
+        self.lifecycle.shutdown(DEFAULT_SHUTDOWN_GRACE).await;
+        result
+    }
+
+    /// Stop every listener, then give the background subsystems up to `grace` to stop in order: telemetry
+    /// still queued is sent, and state is saved if persistence is enabled.
+    pub async fn drain(self, grace: Duration) {
+        for server in &self.servers {
+            server.abort();
+        }
+        self.lifecycle.shutdown(grace).await;
+    }
+
+    /// Stop every listener and background task at once, saving state if persistence is enabled.
+    pub fn shutdown(self) {
+        for server in self.servers {
+            server.abort();
+        }
+        self.lifecycle.abort();
+    }
+}
@@ -0,0 +1,1153 @@
+//! Integration tests for running Vortex embedded through the builder API.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use hyper::StatusCode;
+use tower::util::MapResponseLayer;
+use vortex_core::domain::backend::{Backend, BackendId};
+use vortex_core::pipeline::Stage;
+use vortex_proxy::pipeline::ProxyResponse;
+use vortex_proxy::selftest::spawn_mock_backend;
+use vortex_proxy::Vortex;
+
+fn loopback() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 0))
+}
+
+#[tokio::test]
+async fn test_embedded_proxy_forwards_to_backend() {
+    let backend_addr = spawn_mock_backend().await.unwrap();
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .start()
+        .await
+        .expect("proxy should start");
+    let proxy_addr = handle.local_addrs()[0];
+
+    let res = reqwest::get(format!("http://{}/hello", proxy_addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.unwrap(), "ok\n");
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_embedded_proxy_runs_custom_layers() {
+    let backend_addr = spawn_mock_backend().await.unwrap();
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .layer(
+            Stage::Filters,
+            MapResponseLayer::new(|mut res: ProxyResponse| {
+                res.headers_mut().insert("x-embedded", "yes".parse().unwrap());
+                res
+            }),
+        )
+        .start()
+        .await
+        .expect("proxy should start");
+    let proxy_addr = handle.local_addrs()[0];
+
+    let res = reqwest::get(format!("http://{}/", proxy_addr)).await.unwrap();
+    assert_eq!(res.headers()["x-embedded"], "yes");
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_embedded_proxy_without_backends_returns_503() {
+    let handle = Vortex::builder().listener(loopback()).start().await.unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    let res = reqwest::get(format!("http://{}/", proxy_addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_embedded_proxy_rejects_expired_deadlines() {
+    let backend_addr = spawn_mock_backend().await.unwrap();
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .start()
+        .await
+        .unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    let res = reqwest::Client::new()
+        .get(format!("http://{}/", proxy_addr))
+        .header("x-request-deadline", "0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_plaintext_listener_sniffs_h2c_and_raw_tcp() {
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    let http_backend = spawn_mock_backend().await.unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), http_backend))])
+        .h2c(true)
+        .start()
+        .await
+        .unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    // h2c prior knowledge
+    let io = TokioIo::new(TcpStream::connect(proxy_addr).await.unwrap());
+    let (mut sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), io).await.unwrap();
+    tokio::spawn(conn);
+    let req = hyper::Request::builder()
+        .uri(format!("http://{}/h2", proxy_addr))
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = sender.send_request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "ok\n");
+
+    // Plain HTTP/1 still works on the same port
+    let res = reqwest::get(format!("http://{}/", proxy_addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    handle.shutdown();
+
+    // Raw TCP is tunnelled to an echo backend
+    let echo = TcpListener::bind(loopback()).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = echo.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(2), echo_addr))])
+        .tcp_fallback(true)
+        .start()
+        .await
+        .unwrap();
+
+    let mut client = TcpStream::connect(handle.local_addrs()[0]).await.unwrap();
+    client.write_all(b"\x00\x01raw bytes").await.unwrap();
+    let mut echoed = [0u8; 11];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"\x00\x01raw bytes");
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_plaintext_can_be_switched_off_at_runtime() {
+    let backend_addr = spawn_mock_backend().await.unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .start()
+        .await
+        .unwrap();
+    let url = format!("http://{}/", handle.local_addrs()[0]);
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::OK);
+
+    handle.set_plaintext_enabled(false);
+    assert!(reqwest::get(&url).await.is_err());
+
+    handle.set_plaintext_enabled(true);
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::OK);
+    handle.shutdown();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_connections_get_503_past_the_fd_high_water_mark() {
+    use vortex_proxy::fd_limit::FdGuardrail;
+
+    let backend_addr = spawn_mock_backend().await.unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        // Any usage at all is past this mark
+        .fd_guardrail(FdGuardrail { high_water: 0.0, ..FdGuardrail::default() })
+        .start()
+        .await
+        .unwrap();
+
+    let res = reqwest::get(format!("http://{}/", handle.local_addrs()[0])).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_server_timing_breaks_down_proxied_requests() {
+    use vortex_proxy::server_timing::ServerTiming;
+
+    let backend_addr = spawn_mock_backend().await.unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .server_timing(ServerTiming { default: true, ..ServerTiming::default() })
+        .start()
+        .await
+        .unwrap();
+
+    let res = reqwest::get(format!("http://{}/", handle.local_addrs()[0])).await.unwrap();
+    let timing = res.headers()["server-timing"].to_str().unwrap();
+    for phase in ["route;", "queue;", "connect;", "ttfb;", "total;"] {
+        assert!(timing.contains(phase), "{} missing from {}", phase, timing);
+    }
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_oversized_request_headers_get_431() {
+    use vortex_proxy::header_limits::HeaderLimits;
+
+    let backend_addr = spawn_mock_backend().await.unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .header_limits(HeaderLimits { max_header_bytes: 256, ..HeaderLimits::default() })
+        .start()
+        .await
+        .unwrap();
+    let url = format!("http://{}/", handle.local_addrs()[0]);
+    let client = reqwest::Client::new();
+
+    let res = client.get(&url).header("cookie", "a".repeat(100)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = client.get(&url).header("cookie", "a".repeat(300)).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_early_hints_links_reach_the_client() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_proxy::interim::InterimForwarding;
+
+    let backend = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = backend.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        let _ = stream.read(&mut buf).await.unwrap();
+        stream
+            .write_all(
+                b"HTTP/1.1 103 Early Hints\r\nlink: </app.css>; rel=preload; as=style\r\n\r\n\
+                  HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nok\n",
+            )
+            .await
+            .unwrap();
+    });
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .interim_responses(InterimForwarding { default: true, ..InterimForwarding::default() })
+        .start()
+        .await
+        .unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    let res = reqwest::get(format!("http://{}/", proxy_addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["link"], "</app.css>; rel=preload; as=style");
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_duplicate_content_length_gets_502_unless_repaired() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_proxy::duplicate_headers::{DuplicateHeaderAction, DuplicateHeaders};
+
+    let backend = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = backend.accept().await {
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\ncontent-length: 3\r\nconnection: close\r\n\r\nok\n")
+                .await;
+        }
+    });
+
+    for (action, status) in [
+        (DuplicateHeaderAction::Reject, StatusCode::BAD_GATEWAY),
+        (DuplicateHeaderAction::Repair, StatusCode::OK),
+    ] {
+        let handle = Vortex::builder()
+            .listener(loopback())
+            .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+            .duplicate_response_headers(DuplicateHeaders {
+                backends: [(backend_addr, action)].into(),
+                ..DuplicateHeaders::default()
+            })
+            .start()
+            .await
+            .unwrap();
+        let proxy_addr = handle.local_addrs()[0];
+
+        let res = reqwest::get(format!("http://{}/", proxy_addr)).await.unwrap();
+        assert_eq!(res.status(), status);
+        if status == StatusCode::OK {
+            assert_eq!(res.headers().get_all("content-length").iter().count(), 1);
+        }
+        handle.shutdown();
+    }
+}
+
+/// Accepts one HTTP/1.1 exchange, checks the chunked request carried an
+/// `x-checksum` trailer, and answers with a chunked body ending in gRPC-style trailers.
+async fn spawn_trailer_backend() -> SocketAddr {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let backend = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let addr = backend.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = backend.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !(request.windows(5).any(|w| w == b"\r\n0\r\n") && request.ends_with(b"\r\n\r\n")) {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "request ended before its trailers");
+            request.extend_from_slice(&buf[..n]);
+        }
+        let request = String::from_utf8(request).unwrap().to_ascii_lowercase();
+        assert!(request.contains("\r\n0\r\nx-checksum: abc\r\n\r\n"), "{}", request);
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ntrailer: grpc-status\r\n\r\n\
+                  3\r\nok\n\r\n0\r\ngrpc-status: 0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+/// A request body of unknown length, so it is sent chunked, ending in trailers.
+struct Frames(std::collections::VecDeque<hyper::body::Frame<hyper::body::Bytes>>);
+
+impl hyper::body::Body for Frames {
+    type Data = hyper::body::Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<hyper::body::Frame<Self::Data>, Self::Error>>> {
+        std::task::Poll::Ready(self.0.pop_front().map(Ok))
+    }
+}
+
+fn request_with_trailers(proxy_addr: SocketAddr) -> hyper::Request<Frames> {
+    use hyper::body::{Bytes, Frame};
+
+    let mut trailers = hyper::HeaderMap::new();
+    trailers.insert("x-checksum", "abc".parse().unwrap());
+    hyper::Request::builder()
+        .method("POST")
+        .uri(format!("http://{}/stream", proxy_addr))
+        .header("te", "trailers")
+        .header("trailer", "x-checksum")
+        .body(Frames([Frame::data(Bytes::from_static(b"hello")), Frame::trailers(trailers)].into()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_trailers_cross_the_proxy_over_chunked_http1() {
+    use http_body_util::BodyExt;
+    use hyper_util::rt::TokioIo;
+
+    let backend_addr = spawn_trailer_backend().await;
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .start()
+        .await
+        .unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    let io = TokioIo::new(tokio::net::TcpStream::connect(proxy_addr).await.unwrap());
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await.unwrap();
+    tokio::spawn(conn);
+    let res = sender.send_request(request_with_trailers(proxy_addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.into_body().collect().await.unwrap();
+    assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+    assert_eq!(body.to_bytes(), "ok\n");
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_trailers_cross_the_proxy_as_h2_frames() {
+    use http_body_util::BodyExt;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+
+    let backend_addr = spawn_trailer_backend().await;
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .h2c(true)
+        .start()
+        .await
+        .unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    let io = TokioIo::new(tokio::net::TcpStream::connect(proxy_addr).await.unwrap());
+    let (mut sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), io).await.unwrap();
+    tokio::spawn(conn);
+    let res = sender.send_request(request_with_trailers(proxy_addr)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = res.into_body().collect().await.unwrap();
+    assert_eq!(body.trailers().unwrap()["grpc-status"], "0");
+    assert_eq!(body.to_bytes(), "ok\n");
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_spans_reach_the_otlp_collector_and_traceparent_the_backend() {
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::{Bytes, Incoming};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_proxy::otel::OtlpExport;
+
+    let collector = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let collector_addr = collector.local_addr().unwrap();
+    let (spans_tx, mut spans_rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = collector.accept().await {
+            let spans_tx = spans_tx.clone();
+            let service = hyper::service::service_fn(move |req: hyper::Request<Incoming>| {
+                let spans_tx = spans_tx.clone();
+                async move {
+                    let body = req.into_body().collect().await?.to_bytes();
+                    let _ = spans_tx.send(serde_json::from_slice(&body).unwrap());
+                    Ok::<_, hyper::Error>(hyper::Response::new(Full::new(Bytes::from_static(b"{}"))))
+                }
+            });
+            tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(hyper_util::rt::TokioIo::new(stream), service));
+        }
+    });
+
+    let backend = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let (parent_tx, parent_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = backend.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).await.unwrap();
+        let request = String::from_utf8_lossy(&buf[..n]).to_string();
+        let traceparent = request
+            .lines()
+            .find_map(|line| line.strip_prefix("traceparent: "))
+            .map(str::to_string);
+        let _ = parent_tx.send(traceparent);
+        stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nok\n").await.unwrap();
+    });
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .otlp_export(OtlpExport {
+            endpoint: format!("http://{}/v1/traces", collector_addr),
+            connection_ratio: 0.0,
+            ..OtlpExport::default()
+        })
+        .start()
+        .await
+        .unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    let client_trace = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let res = reqwest::Client::new()
+        .get(format!("http://{}/traced", proxy_addr))
+        .header("traceparent", format!("00-{}-00f067aa0ba902b7-01", client_trace))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // The backend sees the same trace with the proxy's upstream span as parent
+    let traceparent = parent_rx.await.unwrap().expect("traceparent forwarded");
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(parts[1], client_trace);
+    assert_ne!(parts[2], "00f067aa0ba902b7");
+
+    let mut spans = Vec::new();
+    while spans.len() < 4 {
+        let body = tokio::time::timeout(std::time::Duration::from_secs(5), spans_rx.recv()).await.unwrap().unwrap();
+        spans.extend(body["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap().clone());
+    }
+    let span = |name: &str| spans.iter().find(|s| s["name"] == name).unwrap_or_else(|| panic!("no {} span", name));
+    let server = span("proxy.request");
+    assert_eq!(server["traceId"], client_trace);
+    assert_eq!(server["parentSpanId"], "00f067aa0ba902b7");
+    for child in ["backend.select", "upstream.connect", "upstream.request"] {
+        assert_eq!(span(child)["parentSpanId"], server["spanId"]);
+    }
+    assert_eq!(span("upstream.request")["spanId"], parts[2]);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_request_ids_reach_the_backend_and_the_client() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let backend = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let (id_tx, id_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = backend.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        let n = stream.read(&mut buf).await.unwrap();
+        let request = String::from_utf8_lossy(&buf[..n]).to_string();
+        let id = request.lines().find_map(|line| line.strip_prefix("x-request-id: ")).map(str::to_string);
+        let _ = id_tx.send(id);
+        stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nok\n").await.unwrap();
+    });
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .start()
+        .await
+        .unwrap();
+    let proxy_addr = handle.local_addrs()[0];
+
+    // Without an ID from the client, the proxy makes one up
+    let res = reqwest::get(format!("http://{}/", proxy_addr)).await.unwrap();
+    let id = res.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert_eq!(id.len(), 36);
+    assert_eq!(id_rx.await.unwrap().as_deref(), Some(id.as_str()));
+    handle.shutdown();
+
+    // Errors answered by the proxy itself echo the client's ID
+    let handle = Vortex::builder().listener(loopback()).start().await.unwrap();
+    let res = reqwest::Client::new()
+        .get(format!("http://{}/", handle.local_addrs()[0]))
+        .header("x-request-id", "client-42")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()["x-request-id"], "client-42");
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_ring_hash_pins_each_key_to_one_backend() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::load_balancer::balancer::Algorithm;
+    use vortex_core::load_balancer::hash::HashKey;
+
+    let mut backends = Vec::new();
+    for id in 1..=3u32 {
+        let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+        backends.push(Arc::new(Backend::new(BackendId(id), listener.local_addr().unwrap())));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 1\r\n\r\n{}", id);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+    }
+    let routing_table = RoutingTable::new(backends)
+        .with_balancer(Algorithm::RingHash.build())
+        .with_hash_key(HashKey::Header("x-user-id".to_string()));
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(Arc::new(routing_table))
+        .start()
+        .await
+        .unwrap();
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/", handle.local_addrs()[0]);
+    let mut seen = std::collections::HashSet::new();
+    for user in 0..20 {
+        let mut served_by = Vec::new();
+        for _ in 0..3 {
+            let res = client.get(&url).header("x-user-id", format!("user-{}", user)).send().await.unwrap();
+            served_by.push(res.text().await.unwrap());
+        }
+        assert!(served_by.iter().all(|b| *b == served_by[0]), "user-{} moved: {:?}", user, served_by);
+        seen.insert(served_by.remove(0));
+    }
+    assert!(seen.len() > 1, "every key went to {:?}", seen);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_connect_pacing_makes_bursts_share_connections() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use vortex_proxy::connect_pacing::ConnectPacing;
+
+    let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend_addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(|_req| async {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(http_body_util::Full::new(
+                        hyper::body::Bytes::from_static(b"ok"),
+                    )))
+                });
+                let io = hyper_util::rt::TokioIo::new(stream);
+                let _ = hyper::server::conn::http1::Builder::new().serve_connection(io, service).await;
+            });
+        }
+    });
+
+    let pacing = ConnectPacing { rate: 0.5, burst: 2, max_wait: Duration::from_secs(2) };
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .connect_pacing(pacing)
+        .start()
+        .await
+        .unwrap();
+    let url = format!("http://{}/", handle.local_addrs()[0]);
+    let requests: Vec<_> = (0..10)
+        .map(|_| {
+            let url = url.clone();
+            tokio::spawn(async move { reqwest::get(url).await.unwrap().status() })
+        })
+        .collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap(), StatusCode::OK);
+    }
+    // Two connections from the burst, all ten requests served over them
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_source_ip_affinity_holds_across_connections() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::load_balancer::balancer::Algorithm;
+    use vortex_core::load_balancer::hash::HashKey;
+
+    let mut backends = Vec::new();
+    for id in 1..=4u32 {
+        let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+        backends.push(Arc::new(Backend::new(BackendId(id), listener.local_addr().unwrap())));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 1\r\n\r\n{}", id);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+    }
+    let routing_table = RoutingTable::new(backends)
+        .with_balancer(Algorithm::Maglev.build())
+        .with_hash_key(HashKey::ClientIp);
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(Arc::new(routing_table))
+        .start()
+        .await
+        .unwrap();
+    let url = format!("http://{}/", handle.local_addrs()[0]);
+    let mut seen = std::collections::HashSet::new();
+    // The whole of 127.0.0.0/8 is loopback on Linux, so each client gets its own source address
+    for client in 1..=12u8 {
+        let source = std::net::IpAddr::from([127, 0, 0, client]);
+        let mut served_by = Vec::new();
+        for _ in 0..3 {
+            // A fresh client each time, so every request comes over a new connection
+            let http = reqwest::Client::builder().local_address(source).build().unwrap();
+            served_by.push(http.get(&url).send().await.unwrap().text().await.unwrap());
+        }
+        assert!(served_by.iter().all(|b| *b == served_by[0]), "{} moved: {:?}", source, served_by);
+        seen.insert(served_by.remove(0));
+    }
+    assert!(seen.len() > 1, "every client went to {:?}", seen);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_passive_health_ejects_on_gateway_errors_but_not_throttling() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::load_balancer::balancer::Algorithm;
+    use vortex_proxy::passive_health::PassiveHealth;
+
+    let mut backends = Vec::new();
+    for (id, status) in [(1u32, "429 Too Many Requests"), (2, "503 Service Unavailable")] {
+        let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+        backends.push(Arc::new(Backend::new(BackendId(id), listener.local_addr().unwrap())));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 {}\r\nconnection: close\r\ncontent-length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+    }
+    let routing_table = RoutingTable::new(backends.clone()).with_balancer(Algorithm::RoundRobin.build());
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(Arc::new(routing_table))
+        .passive_health(PassiveHealth { threshold: 3.0, ..Default::default() })
+        .start()
+        .await
+        .unwrap();
+    let url = format!("http://{}/", handle.local_addrs()[0]);
+    let mut statuses = Vec::new();
+    for _ in 0..10 {
+        statuses.push(reqwest::get(&url).await.unwrap().status().as_u16());
+    }
+    // Three 503s take the second backend out; the first throttles but stays in
+    assert_eq!(statuses.iter().filter(|&&s| s == 503).count(), 3, "{:?}", statuses);
+    assert!(backends[0].is_healthy());
+    assert!(!backends[1].is_healthy());
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_client_concurrency_cap_answers_429_over_the_cap() {
+    use std::time::Duration;
+    use vortex_proxy::client_concurrency::ClientConcurrency;
+
+    let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(|_req| async {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    Ok::<_, std::convert::Infallible>(hyper::Response::new(http_body_util::Full::new(
+                        hyper::body::Bytes::from_static(b"ok"),
+                    )))
+                });
+                let io = hyper_util::rt::TokioIo::new(stream);
+                let _ = hyper::server::conn::http1::Builder::new().serve_connection(io, service).await;
+            });
+        }
+    });
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .client_concurrency(ClientConcurrency { max_in_flight: 2, ..Default::default() })
+        .start()
+        .await
+        .unwrap();
+    let url = format!("http://{}/", handle.local_addrs()[0]);
+    let requests: Vec<_> = (0..3)
+        .map(|_| {
+            let url = url.clone();
+            tokio::spawn(async move { reqwest::get(url).await.unwrap().status() })
+        })
+        .collect();
+    let mut statuses = Vec::new();
+    for request in requests {
+        statuses.push(request.await.unwrap());
+    }
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+
+    // Slots are given back once the responses are done
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), StatusCode::OK);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_routes_reach_their_cluster_by_longest_prefix() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::route::RouteSpec;
+
+    // Each backend answers with its cluster's name
+    let mut pools = Vec::new();
+    for (id, name) in (1..).zip(["web", "api", "cdn"]) {
+        let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+        let backend = Arc::new(Backend::new(BackendId(id), listener.local_addr().unwrap()));
+        pools.push(Arc::new(RoutingTable::new(vec![backend])));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 3\r\n\r\n{}", name);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+    }
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(pools[0].clone())
+        .cluster("api", pools[1].clone(), None)
+        .cluster("cdn", pools[2].clone(), None)
+        .routes(vec![
+            RouteSpec::new("static", "/api/static/").with_cluster("cdn"),
+            RouteSpec::new("api", "/api/").with_cluster("api"),
+            RouteSpec::new("assets", "/static/").with_cluster("cdn"),
+            RouteSpec::new("default", "/"),
+        ])
+        .start()
+        .await
+        .unwrap();
+    let client = reqwest::Client::new();
+    for (path, cluster) in [("/api/users", "api"), ("/api/static/app.js", "cdn"), ("/static/logo.png", "cdn"), ("/", "web")] {
+        let res = client.get(format!("http://{}{}", handle.local_addrs()[0], path)).send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), cluster, "{}", path);
+    }
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_reads_reach_the_replicas_and_writes_the_primary() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::route::RouteSpec;
+
+    // Each backend answers with its cluster's name
+    let mut pools = Vec::new();
+    for (id, name) in (1..).zip(["primary", "replica"]) {
+        let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+        let backend = Arc::new(Backend::new(BackendId(id), listener.local_addr().unwrap()));
+        pools.push(Arc::new(RoutingTable::new(vec![backend])));
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 200 OK\r\nconnection: close\r\nx-cluster: {}\r\ncontent-length: 0\r\n\r\n", name);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+    }
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(pools[0].clone())
+        .cluster("replica", pools[1].clone(), None)
+        .routes(vec![
+            RouteSpec::new("reads", "/orders").with_methods(["GET"]).with_cluster("replica"),
+            RouteSpec::new("writes", "/orders"),
+        ])
+        .start()
+        .await
+        .unwrap();
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/orders/7", handle.local_addrs()[0]);
+    for (method, cluster) in [("GET", "replica"), ("HEAD", "replica"), ("POST", "primary"), ("DELETE", "primary")] {
+        let res = client.request(method.parse().unwrap(), &url).send().await.unwrap();
+        assert_eq!(res.headers()["x-cluster"], cluster, "{}", method);
+    }
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_regex_routes_rewrite_the_path_from_their_captures() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::route::pattern::Pattern;
+    use vortex_core::route::RouteSpec;
+
+    // The backend answers with the request target it was sent
+    let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend = Arc::new(Backend::new(BackendId(1), listener.local_addr().unwrap()));
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap_or(0);
+            let head = String::from_utf8_lossy(&buf[..n]).into_owned();
+            let target = head.split(' ').nth(1).unwrap_or_default().to_string();
+            let response =
+                format!("HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{}", target.len(), target);
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let profile = Pattern::new(r"/users/(?P<id>\d+)/profile").unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(Arc::new(RoutingTable::new(vec![backend])))
+        .routes(vec![
+            RouteSpec::new("profile", "/users/").with_path_regex(profile).with_rewrite("/profiles/$id"),
+            RouteSpec::new("default", "/"),
+        ])
+        .start()
+        .await
+        .unwrap();
+    let client = reqwest::Client::new();
+    for (path, forwarded) in [
+        ("/users/42/profile?fields=name", "/profiles/42?fields=name"),
+        ("/users/me/profile", "/users/me/profile"),
+    ] {
+        let res = client.get(format!("http://{}{}", handle.local_addrs()[0], path)).send().await.unwrap();
+        assert!(res.text().await.unwrap().ends_with(forwarded), "{}", path);
+    }
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_normalization_strips_smuggled_framing_and_canonicalizes_paths() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_proxy::normalization::RequestNormalization;
+
+    // The backend answers with the request head it was sent
+    let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend = Arc::new(Backend::new(BackendId(1), listener.local_addr().unwrap()));
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap_or(0);
+            let head = String::from_utf8_lossy(&buf[..n]).split("\r\n\r\n").next().unwrap_or_default().to_lowercase();
+            let response = format!("HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{}", head.len(), head);
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(Arc::new(RoutingTable::new(vec![backend])))
+        .request_normalization(RequestNormalization::default())
+        .start()
+        .await
+        .unwrap();
+    let mut client = tokio::net::TcpStream::connect(handle.local_addrs()[0]).await.unwrap();
+    client
+        .write_all(
+            b"POST /public/%2e%2e//admin HTTP/1.1\r\nhost: example.com\r\ncontent-length: 4\r\n\
+              transfer-encoding: chunked\r\nconnection: close, x-internal\r\nx-internal: 1\r\n\r\n0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let forwarded = response.split("\r\n\r\n").nth(1).unwrap();
+    assert!(forwarded.lines().next().unwrap().ends_with("/admin http/1.1"), "{}", forwarded);
+    assert!(!forwarded.contains("content-length") && !forwarded.contains("x-internal: 1"), "{}", forwarded);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_msgpack_clients_round_trip_through_a_json_upstream() {
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::route::RouteSpec;
+    use vortex_filters::body_codec::MessagePackJson;
+    use vortex_proxy::body_codec::{BodyCodecs, RouteCodec};
+
+    // The backend only takes JSON, and echoes it back with a field added
+    let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend = Arc::new(Backend::new(BackendId(1), listener.local_addr().unwrap()));
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n{\"id\":7}") {
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = String::from_utf8_lossy(&request).to_lowercase();
+            let body = if head.contains("content-type: application/json") {
+                r#"{"id":7,"seen":true}"#
+            } else {
+                r#"{"seen":false}"#
+            };
+            let status = "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-type: application/json";
+            let response = format!("{}\r\ncontent-length: {}\r\n\r\n{}", status, body.len(), body);
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let route = RouteCodec { codec: Arc::new(MessagePackJson), max_bytes: 1024 };
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .routing_table(Arc::new(RoutingTable::new(vec![backend])))
+        .routes(vec![RouteSpec::new("api", "/")])
+        .body_codecs(BodyCodecs { routes: HashMap::from([("api".to_string(), route)]) })
+        .start()
+        .await
+        .unwrap();
+    // {"id": 7} as MessagePack
+    let res = reqwest::Client::new()
+        .post(format!("http://{}/things", handle.local_addrs()[0]))
+        .header("content-type", "application/msgpack")
+        .header("accept", "application/msgpack")
+        .body(vec![0x81, 0xa2, b'i', b'd', 0x07])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-type"], "application/msgpack");
+    let body = res.bytes().await.unwrap();
+    assert_eq!(&body[..], &[0x82, 0xa2, b'i', b'd', 0x07, 0xa4, b's', b'e', b'e', b'n', 0xc3]);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_expensive_routes_exhaust_the_client_quota_first() {
+    use std::collections::HashMap;
+    use vortex_core::route::RouteSpec;
+    use vortex_proxy::client_quota::{ClientQuota, RouteCost};
+
+    let backend_addr = spawn_mock_backend().await.unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .routes(vec![RouteSpec::new("search", "/search"), RouteSpec::new("default", "/")])
+        .client_quota(ClientQuota {
+            rate: 0.5,
+            burst: 12,
+            routes: HashMap::from([("search".to_string(), RouteCost::Fixed(10))]),
+            ..ClientQuota::default()
+        })
+        .start()
+        .await
+        .unwrap();
+    let base = format!("http://{}", handle.local_addrs()[0]);
+    assert_eq!(reqwest::get(format!("{}/search", base)).await.unwrap().status(), StatusCode::OK);
+
+    // Two tokens left: enough for pings, not for another search
+    let res = reqwest::get(format!("{}/search", base)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()["retry-after"], "16");
+    for _ in 0..2 {
+        assert_eq!(reqwest::get(format!("{}/", base)).await.unwrap().status(), StatusCode::OK);
+    }
+    assert_eq!(reqwest::get(format!("{}/", base)).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_pausing_health_checks_reaches_cluster_pools() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use vortex_core::domain::routing::RoutingTable;
+    use vortex_core::stats::HealthCheckControl;
+    use vortex_proxy::health_check::prober::HealthCheckConfig;
+
+    // The cluster's only backend counts the TCP probes it gets
+    let listener = tokio::net::TcpListener::bind(loopback()).await.unwrap();
+    let backend = Arc::new(Backend::new(BackendId(7), listener.local_addr().unwrap()));
+    let probes = Arc::new(AtomicUsize::new(0));
+    let counted = probes.clone();
+    tokio::spawn(async move {
+        while listener.accept().await.is_ok() {
+            counted.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    let backend_addr = spawn_mock_backend().await.unwrap();
+    let interval = Duration::from_millis(20);
+    let check = HealthCheckConfig { interval, spread: false, ..HealthCheckConfig::default() };
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .cluster("reports", Arc::new(RoutingTable::new(vec![backend])), Some(check))
+        .start()
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(probes.load(Ordering::SeqCst) > 0);
+
+    handle.health_checks().pause(Some("reports"));
+    let status = handle.health_checks().status();
+    assert_eq!(status.len(), 1);
+    assert_eq!(status[0].0, "reports");
+    assert!(status[0].1.paused);
+    // Let a sweep already under way finish
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let paused_at = probes.load(Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(probes.load(Ordering::SeqCst), paused_at);
+
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn test_drain_ships_queued_logs_while_a_connection_stays_open() {
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use vortex_proxy::log_sink::{LogShipping, LogSink};
+
+    let path = std::env::temp_dir().join(format!("vortex-drain-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let backend_addr = spawn_mock_backend().await.unwrap();
+    let handle = Vortex::builder()
+        .listener(loopback())
+        .backends(vec![Arc::new(Backend::new(BackendId(1), backend_addr))])
+        .log_shipping(LogShipping { sinks: vec![LogSink::File(path.clone())], ..LogShipping::default() })
+        .start()
+        .await
+        .unwrap();
+
+    // A keep-alive connection, left open through the drain
+    let mut client = tokio::net::TcpStream::connect(handle.local_addrs()[0]).await.unwrap();
+    client.write_all(b"GET /kept-alive HTTP/1.1\r\nhost: example.com\r\n\r\n").await.unwrap();
+    let mut response = [0u8; 1024];
+    let n = client.read(&mut response).await.unwrap();
+    assert!(response[..n].starts_with(b"HTTP/1.1 200"));
+
+    let started = Instant::now();
+    handle.drain(Duration::from_secs(5)).await;
+    assert!(started.elapsed() < Duration::from_secs(2), "drain took {:?}", started.elapsed());
+    assert!(std::fs::read_to_string(&path).unwrap().contains("/kept-alive"));
+    drop(client);
+    std::fs::remove_file(&path).unwrap();
+}